@@ -0,0 +1,132 @@
+//! Benchmarks of the hot paths of the protocol. Run with `cargo bench --features bench`.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use discv5::{
+    enr::{CombinedKey, NodeId},
+    handler::bench::Peers,
+    kbucket::{ConnectionDirection, ConnectionState, KBucketsTable, Key, NodeStatus},
+    packet::Packet,
+    rpc::{Message, Request, RequestBody, RequestId},
+    test_utils::TestNetwork,
+    DefaultProtocolId, Enr,
+};
+use std::{hint::black_box, net::Ipv4Addr, time::Duration};
+
+/// A FINDNODE request, as the message most packets carry.
+fn findnode() -> Vec<u8> {
+    Message::Request(Request {
+        id: RequestId::random(),
+        body: RequestBody::FindNode {
+            distances: vec![254, 255, 256],
+        },
+    })
+    .encode()
+}
+
+fn packet_codec(c: &mut Criterion) {
+    let src_id = NodeId::random();
+    let dst_id = NodeId::random();
+    let packet = Packet::new_message(src_id, rand::random(), findnode());
+    let encoded = packet.clone().encode::<DefaultProtocolId>(&dst_id);
+
+    c.bench_function("packet/encode", |b| {
+        b.iter_batched(
+            || packet.clone(),
+            |packet| packet.encode::<DefaultProtocolId>(&dst_id),
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("packet/decode", |b| {
+        b.iter(|| Packet::decode::<DefaultProtocolId>(&dst_id, black_box(&encoded)).unwrap())
+    });
+}
+
+fn handshake(c: &mut Criterion) {
+    let peers = Peers::random();
+    let message = findnode();
+    c.bench_function("handshake", |b| {
+        b.iter(|| peers.handshake::<DefaultProtocolId>(&message))
+    });
+}
+
+fn session(c: &mut Criterion) {
+    let mut sessions = Peers::random().handshake::<DefaultProtocolId>(&[]);
+    let message = findnode();
+    let packet = sessions.encrypt::<DefaultProtocolId>(&message);
+
+    c.bench_function("session/encrypt", |b| {
+        b.iter(|| sessions.encrypt::<DefaultProtocolId>(black_box(&message)))
+    });
+    c.bench_function("session/decrypt", |b| {
+        b.iter(|| sessions.decrypt::<DefaultProtocolId>(black_box(&packet)))
+    });
+}
+
+fn kbucket_insertion(c: &mut Criterion) {
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(9000)
+        .build(&key)
+        .unwrap();
+    let status = NodeStatus {
+        state: ConnectionState::Connected,
+        direction: ConnectionDirection::Outgoing,
+    };
+    c.bench_function("kbucket/insert_1000", |b| {
+        b.iter_batched(
+            || {
+                (0..1000)
+                    .map(|_| Key::from(NodeId::random()))
+                    .collect::<Vec<_>>()
+            },
+            |keys| {
+                let table = KBucketsTable::<NodeId, Enr>::new(
+                    Key::from(NodeId::random()),
+                    Duration::from_secs(60),
+                    16,
+                    None,
+                    None,
+                );
+                for key in &keys {
+                    let _ = table.insert_or_update(key, enr.clone(), status);
+                }
+                table
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn query(c: &mut Criterion) {
+    // Timers elapse in virtual time, so that only the work of the lookups is measured.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    let network = runtime.block_on(async {
+        let network = TestNetwork::builder().nodes(32).build().await.unwrap();
+        network
+            .converge(16, Duration::from_secs(120))
+            .await
+            .unwrap();
+        network
+    });
+    c.bench_function("query/find_node_32_nodes", |b| {
+        b.iter(|| {
+            runtime
+                .block_on(network.node(0).find_node(NodeId::random()))
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    packet_codec,
+    handshake,
+    session,
+    kbucket_insertion,
+    query
+);
+criterion_main!(benches);
@@ -197,7 +197,7 @@ async fn main() {
                 }
                 match discv5_ev {
                     Event::Discovered(enr) => info!(%enr, "Enr discovered"),
-                    Event::NodeInserted { node_id, replaced: _ } => info!(%node_id, "Node inserted"),
+                    Event::NodeInserted { node_id, .. } => info!(%node_id, "Node inserted"),
                     Event::SessionEstablished(enr, _) => info!(%enr, "Session established"),
                     Event::SocketUpdated(addr) => info!(%addr, "Socket updated"),
                     Event::TalkRequest(_) => info!("Talk request received"),
@@ -0,0 +1,512 @@
+//! An admin JSON-RPC interface, letting operators drive discovery from scripts and dashboards.
+//!
+//! [`AdminRpcServer`] serves JSON-RPC 2.0 requests POSTed over HTTP to a loopback address. As any
+//! local process, and any web page open in a local browser, can reach a loopback address, requests
+//! have to carry the token the server is started with as an `Authorization: Bearer <token>` header,
+//! and requests with an `Origin` header, which browsers add, are refused. The parameters of all
+//! methods are passed by name:
+//!
+//! - `nodeInfo`: The local ENR, node id and sockets, and the size of the routing table.
+//! - `tableDump`: The entries of the routing table with their connection status.
+//! - `lookup`: Looks up the nodes closest to `target`, a hex node id, or to a random target if
+//!   omitted, and returns their ENRs.
+//! - `ban`, `unban`: Bans or lifts the ban of a `nodeId` and/or an `ip`. Bans last `duration`
+//!   seconds, or indefinitely if omitted.
+//! - `updateEnr`: Sets the UDP `socket` of the local ENR, or its TCP socket if `tcp` is true, or
+//!   sets an arbitrary `key` to the hex `value`. Returns the updated ENR.
+use crate::{packet::ProtocolIdentity, ConnectionDirection, Discv5};
+use enr::NodeId;
+use serde_json::{json, Value};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+use tracing::{debug, warn};
+
+/// The largest request body accepted.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// The largest request head, the request line and headers, accepted.
+const MAX_HEAD_SIZE: u64 = 8 * 1024;
+
+/// The time a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The JSON-RPC error codes used.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// A running admin interface. The server stops when this is dropped.
+pub struct AdminRpcServer {
+    local_addr: SocketAddr,
+    exit: Option<oneshot::Sender<()>>,
+}
+
+impl AdminRpcServer {
+    /// Serves the admin interface of `discv5` on `addr`, which must be a loopback address, to
+    /// requests authorized with `token`.
+    pub async fn start<P>(
+        discv5: Arc<Discv5<P>>,
+        addr: SocketAddr,
+        token: impl Into<String>,
+    ) -> io::Result<Self>
+    where
+        P: ProtocolIdentity + Send + Sync + 'static,
+    {
+        if !addr.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The admin interface may only listen on a loopback address",
+            ));
+        }
+        let token: Arc<str> = token.into().into();
+        if token.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The admin interface requires a token",
+            ));
+        }
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (exit, mut exit_recv) = oneshot::channel();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut exit_recv => return,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, peer)) => {
+                            let discv5 = discv5.clone();
+                            let token = token.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = serve_connection(stream, &discv5, &token).await {
+                                    debug!(error = %e, %peer, "Admin RPC connection failed");
+                                }
+                            });
+                        }
+                        Err(e) => warn!(error = %e, "Failed to accept admin RPC connection"),
+                    }
+                }
+            }
+        });
+
+        Ok(AdminRpcServer {
+            local_addr,
+            exit: Some(exit),
+        })
+    }
+
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for AdminRpcServer {
+    fn drop(&mut self) {
+        if let Some(exit) = self.exit.take() {
+            let _ = exit.send(());
+        }
+    }
+}
+
+/// The parts of an HTTP request the server looks at.
+struct HttpRequest {
+    authorization: Option<String>,
+    has_origin: bool,
+    /// The body, unless it is too large.
+    body: Option<Vec<u8>>,
+}
+
+/// Answers a single HTTP request and closes the connection.
+async fn serve_connection<P: ProtocolIdentity>(
+    stream: TcpStream,
+    discv5: &Discv5<P>,
+    token: &str,
+) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out reading the request",
+            ))
+        }
+    };
+    let Some(request) = request else {
+        return Ok(());
+    };
+
+    let expected = format!("Bearer {token}");
+    let authorized = request.authorization.is_some_and(|authorization| {
+        constant_time_eq(authorization.as_bytes(), expected.as_bytes())
+    });
+    let (status, response) = if request.has_origin {
+        (
+            "403 Forbidden",
+            error_response(
+                Value::Null,
+                INVALID_REQUEST,
+                "Cross-origin requests are refused",
+            ),
+        )
+    } else if !authorized {
+        (
+            "401 Unauthorized",
+            error_response(Value::Null, INVALID_REQUEST, "Missing or invalid token"),
+        )
+    } else {
+        let response = match request.body {
+            None => error_response(Value::Null, INVALID_REQUEST, "Request too large"),
+            Some(body) => match serde_json::from_slice(&body) {
+                Ok(request) => handle_request(discv5, request).await,
+                Err(e) => error_response(Value::Null, PARSE_ERROR, &e.to_string()),
+            },
+        };
+        ("200 OK", response)
+    };
+
+    let body = response.to_string();
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads the head of an HTTP request and its body unless it is too large. Returns None if the
+/// connection is closed before a request.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> io::Result<Option<HttpRequest>> {
+    let mut request = HttpRequest {
+        authorization: None,
+        has_origin: false,
+        body: None,
+    };
+    let mut content_length = 0;
+    let mut head_size = 0;
+    loop {
+        let mut line = String::new();
+        let read = (&mut *stream)
+            .take(MAX_HEAD_SIZE - head_size)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            if head_size == 0 {
+                return Ok(None);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request head too large or incomplete",
+            ));
+        }
+        head_size += read as u64;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().unwrap_or(0);
+            } else if name.eq_ignore_ascii_case("authorization") {
+                request.authorization = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("origin") {
+                request.has_origin = true;
+            }
+        }
+    }
+
+    if content_length <= MAX_REQUEST_SIZE {
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await?;
+        request.body = Some(body);
+    }
+    Ok(Some(request))
+}
+
+/// Compares the bytes in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Handles a JSON-RPC request, returning the response object.
+async fn handle_request<P: ProtocolIdentity>(discv5: &Discv5<P>, request: Value) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return error_response(id, INVALID_REQUEST, "Missing method");
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    match call(discv5, method, &params).await {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => error_response(id, code, &message),
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+async fn call<P: ProtocolIdentity>(
+    discv5: &Discv5<P>,
+    method: &str,
+    params: &Value,
+) -> Result<Value, (i64, String)> {
+    match method {
+        "nodeInfo" => {
+            let enr = discv5.local_enr();
+            Ok(json!({
+                "enr": enr.to_base64(),
+                "nodeId": hex::encode(enr.node_id().raw()),
+                "udp4": enr.udp4_socket().map(|socket| socket.to_string()),
+                "udp6": enr.udp6_socket().map(|socket| socket.to_string()),
+                "connectedPeers": discv5.connected_peers(),
+                "tableSize": discv5.table_entries_id().len(),
+            }))
+        }
+        "tableDump" => Ok(discv5
+            .table_entries()
+            .into_iter()
+            .map(|(node_id, enr, status)| {
+                json!({
+                    "nodeId": hex::encode(node_id.raw()),
+                    "enr": enr.to_base64(),
+                    "connected": status.is_connected(),
+                    "direction": match status.direction {
+                        ConnectionDirection::Incoming => "incoming",
+                        ConnectionDirection::Outgoing => "outgoing",
+                    },
+                })
+            })
+            .collect()),
+        "lookup" => {
+            let target = node_id_param(params, "target")?.unwrap_or_else(NodeId::random);
+            let enrs = discv5
+                .find_node(target)
+                .await
+                .map_err(|e| (SERVER_ERROR, format!("{e:?}")))?;
+            Ok(enrs.iter().map(|enr| enr.to_base64()).collect())
+        }
+        "ban" => {
+            let duration = match params.get("duration") {
+                None | Some(Value::Null) => None,
+                Some(duration) => Some(Duration::from_secs(
+                    duration.as_u64().ok_or_else(|| invalid_param("duration"))?,
+                )),
+            };
+            let (node_id, ip) = ban_target(params)?;
+            if let Some(node_id) = node_id {
+                discv5.ban_node(&node_id, duration);
+            }
+            if let Some(ip) = ip {
+                discv5.ban_ip(ip, duration);
+            }
+            Ok(Value::Bool(true))
+        }
+        "unban" => {
+            let (node_id, ip) = ban_target(params)?;
+            if let Some(node_id) = node_id {
+                discv5.ban_node_remove(&node_id);
+            }
+            if let Some(ip) = ip {
+                discv5.ban_ip_remove(&ip);
+            }
+            Ok(Value::Bool(true))
+        }
+        "updateEnr" => {
+            if let Some(socket) = params.get("socket") {
+                let socket = socket
+                    .as_str()
+                    .and_then(|socket| socket.parse().ok())
+                    .ok_or_else(|| invalid_param("socket"))?;
+                let tcp = params.get("tcp").and_then(Value::as_bool).unwrap_or(false);
+                discv5.update_local_enr_socket(socket, tcp);
+            } else {
+                let key = params
+                    .get("key")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| invalid_param("key"))?;
+                let value = params
+                    .get("value")
+                    .and_then(Value::as_str)
+                    .and_then(|value| hex::decode(value.trim_start_matches("0x")).ok())
+                    .ok_or_else(|| invalid_param("value"))?;
+                discv5
+                    .update_local_enr_bytes(key, &value)
+                    .map_err(|e| (SERVER_ERROR, format!("{e:?}")))?;
+            }
+            Ok(Value::String(discv5.local_enr().to_base64()))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("Unknown method {method}"))),
+    }
+}
+
+/// The node id and IP a ban applies to. At least one must be given.
+fn ban_target(params: &Value) -> Result<(Option<NodeId>, Option<IpAddr>), (i64, String)> {
+    let node_id = node_id_param(params, "nodeId")?;
+    let ip = match params.get("ip") {
+        None | Some(Value::Null) => None,
+        Some(ip) => Some(
+            ip.as_str()
+                .and_then(|ip| ip.parse().ok())
+                .ok_or_else(|| invalid_param("ip"))?,
+        ),
+    };
+    if node_id.is_none() && ip.is_none() {
+        return Err((INVALID_PARAMS, "Either nodeId or ip is required".into()));
+    }
+    Ok((node_id, ip))
+}
+
+fn node_id_param(params: &Value, name: &str) -> Result<Option<NodeId>, (i64, String)> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(node_id) => node_id
+            .as_str()
+            .and_then(|node_id| hex::decode(node_id.trim_start_matches("0x")).ok())
+            .and_then(|node_id| NodeId::parse(&node_id).ok())
+            .map(Some)
+            .ok_or_else(|| invalid_param(name)),
+    }
+}
+
+fn invalid_param(name: &str) -> (i64, String) {
+    (INVALID_PARAMS, format!("Invalid parameter {name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{discv5::PERMIT_BAN_LIST, ConfigBuilder, Enr, ListenConfig};
+    use enr::CombinedKey;
+    use std::net::Ipv4Addr;
+
+    const TOKEN: &str = "secret";
+
+    /// Sends the request with the extra header lines, returning the status line and the body.
+    async fn send(addr: SocketAddr, headers: &str, request: Value) -> (String, Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let body = request.to_string();
+        let head = format!(
+            "POST / HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n",
+            headers,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_owned();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    async fn post(addr: SocketAddr, request: Value) -> Value {
+        let headers = format!("Authorization: Bearer {TOKEN}\r\n");
+        let (status, body) = send(addr, &headers, request).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        body
+    }
+
+    async fn start_server(udp_port: u16) -> (Arc<Discv5>, Enr, AdminRpcServer) {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(udp_port)
+            .build(&key)
+            .unwrap();
+        let config = ConfigBuilder::new(ListenConfig::default()).build();
+        let discv5: Arc<Discv5> = Arc::new(Discv5::new(enr.clone(), key, config).unwrap());
+        let server = AdminRpcServer::start(discv5.clone(), "127.0.0.1:0".parse().unwrap(), TOKEN)
+            .await
+            .unwrap();
+        (discv5, enr, server)
+    }
+
+    #[tokio::test]
+    async fn test_admin_rpc() {
+        let (_discv5, enr, server) = start_server(9300).await;
+
+        let response = post(
+            server.local_addr(),
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "nodeInfo" }),
+        )
+        .await;
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["enr"], enr.to_base64());
+        assert_eq!(response["result"]["udp4"], "127.0.0.1:9300");
+
+        // Bans are global to the process, so a random node is banned rather than an IP other
+        // tests may use.
+        let node_id = NodeId::random();
+        let response = post(
+            server.local_addr(),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "ban", "params": { "nodeId": hex::encode(node_id.raw()) } }),
+        )
+        .await;
+        assert_eq!(response["result"], true);
+        assert!(PERMIT_BAN_LIST.read().ban_nodes().contains_key(&node_id));
+        let response = post(
+            server.local_addr(),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "unban", "params": { "nodeId": hex::encode(node_id.raw()) } }),
+        )
+        .await;
+        assert_eq!(response["result"], true);
+        assert!(!PERMIT_BAN_LIST.read().ban_nodes().contains_key(&node_id));
+
+        let response = post(
+            server.local_addr(),
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "ban", "params": {} }),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn test_admin_rpc_refuses_unauthorized_requests() {
+        let (_discv5, _enr, server) = start_server(9301).await;
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "nodeInfo" });
+
+        let (status, _) = send(server.local_addr(), "", request.clone()).await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        let (status, _) = send(
+            server.local_addr(),
+            "Authorization: Bearer wrong\r\n",
+            request.clone(),
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+        // Requests of web pages are refused even with the token.
+        let headers = format!("Origin: http://example.com\r\nAuthorization: Bearer {TOKEN}\r\n");
+        let (status, _) = send(server.local_addr(), &headers, request).await;
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+
+        // A request head without end is cut off.
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        let header = format!("X-Padding: {}\r\n", "a".repeat(1024));
+        let _ = stream.write_all(b"POST / HTTP/1.1\r\n").await;
+        for _ in 0..MAX_HEAD_SIZE / 1024 {
+            if stream.write_all(header.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+//! Structured security events for audit logs.
+//!
+//! Bans, packets dropped by the filter, forged or replayed handshakes and malformed packets are
+//! published as [`SecurityEvent`]s, each carrying the IP, node id and packet type involved where
+//! known, so that they can be fed to a SIEM rather than picked out of the debug logs. Subscribe
+//! with [`crate::Discv5::security_events`].
+//!
+//! NOTE: Like the permit and ban lists, the audit log is global to the process. A subscriber
+//! receives the events of every discv5 instance of the process.
+use crate::packet::PacketKind;
+use enr::NodeId;
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::broadcast;
+
+/// The number of events buffered per subscriber. A subscriber that falls further behind skips
+/// the oldest events.
+const AUDIT_LOG_CAPACITY: usize = 1024;
+
+lazy_static! {
+    pub(crate) static ref AUDIT_LOG: AuditLog = AuditLog::default();
+}
+
+/// A security relevant occurrence.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SecurityEvent {
+    /// When the event occurred.
+    pub timestamp: SystemTime,
+    pub kind: SecurityEventKind,
+    /// The IP of the peer, or the banned IP.
+    pub ip: Option<IpAddr>,
+    /// The node id of the peer, or the banned node.
+    pub node_id: Option<NodeId>,
+    /// The type of the packet that caused the event, if any.
+    pub packet_type: Option<PacketType>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum SecurityEventKind {
+    /// An IP has been banned, permanently if no duration is given.
+    IpBanned {
+        reason: BanReason,
+        duration: Option<Duration>,
+    },
+    /// A node has been banned, permanently if no duration is given.
+    NodeBanned {
+        reason: BanReason,
+        duration: Option<Duration>,
+    },
+    /// The packet filter dropped a packet.
+    PacketFiltered(FilterReason),
+    /// A handshake was signed with a key other than that of the node it claims to be from.
+    ForgedHandshake,
+    /// A packet referred to a message nonce or challenge we have no record of, as a replayed
+    /// packet does.
+    ReplayedNonce,
+    /// A message was received again within its session, as a duplicated or replayed packet is.
+    /// The message is dropped.
+    ReplayedMessage,
+    /// A packet could not be decoded.
+    MalformedPacket,
+    /// A peer answered a request with a response breaking the protocol.
+    InvalidResponse(ResponseViolation),
+}
+
+/// Why an IP or node was banned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum BanReason {
+    /// Banned by the application.
+    Manual,
+    /// The IP or node exceeded its request rate limit.
+    ExcessiveRequests,
+    /// The IP was used by more node ids than `filter_max_nodes_per_ip`.
+    TooManyNodes,
+    /// More nodes were banned on the IP than `filter_max_bans_per_ip`.
+    TooManyBannedNodes,
+    /// Enough trusted peers reported the IP or node. See [`crate::ConfigBuilder::ban_intel`].
+    PeerReport,
+    /// The node answered a request with a response breaking the protocol.
+    InvalidResponse(ResponseViolation),
+}
+
+/// How a response broke the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ResponseViolation {
+    /// A NODES response held ENRs at distances that were not requested.
+    UnrequestedDistance,
+    /// A request for the ENR of the peer was answered with other ENRs.
+    ForeignEnr,
+    /// A NODES response held an ENR with an invalid signature.
+    InvalidSignature,
+    /// The NODES responses to a request held more than `max_nodes_response` ENRs. The excess
+    /// ENRs are dropped.
+    ExcessNodes,
+    /// A NODES response announced more responses than are accepted. The excess responses are
+    /// dropped.
+    ExcessiveTotal,
+}
+
+impl ResponseViolation {
+    /// Whether the violation can only be explained by a faulty or malicious peer, and the peer is
+    /// banned. The other violations may stem from a peer configured with larger limits than ours.
+    pub fn is_severe(&self) -> bool {
+        matches!(
+            self,
+            ResponseViolation::UnrequestedDistance
+                | ResponseViolation::ForeignEnr
+                | ResponseViolation::InvalidSignature
+        )
+    }
+}
+
+/// Why the packet filter dropped a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum FilterReason {
+    /// The source IP is banned.
+    BannedIp,
+    /// The source node is banned.
+    BannedNode,
+    /// The IPv6 /64 prefix of the source exceeded its rate limit.
+    Ipv6PrefixLimit,
+    /// The packets of the IP version of the source exceeded their rate limit.
+    IpVersionLimit,
+    /// The total rate limit of unsolicited packets was exceeded.
+    TotalLimit,
+}
+
+/// The type of a discv5 packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum PacketType {
+    Message,
+    WhoAreYou,
+    Handshake,
+}
+
+impl From<&PacketKind> for PacketType {
+    fn from(kind: &PacketKind) -> Self {
+        match kind {
+            PacketKind::Message { .. } => PacketType::Message,
+            PacketKind::WhoAreYou { .. } => PacketType::WhoAreYou,
+            PacketKind::Handshake { .. } => PacketType::Handshake,
+        }
+    }
+}
+
+/// Publishes [`SecurityEvent`]s to the subscribers.
+pub(crate) struct AuditLog {
+    sender: broadcast::Sender<SecurityEvent>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(AUDIT_LOG_CAPACITY);
+        AuditLog { sender }
+    }
+}
+
+impl AuditLog {
+    pub fn subscribe(&self) -> broadcast::Receiver<SecurityEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes an event. Events are discarded if nobody has subscribed.
+    pub fn record(
+        &self,
+        kind: SecurityEventKind,
+        ip: Option<IpAddr>,
+        node_id: Option<NodeId>,
+        packet_type: Option<PacketType>,
+    ) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.sender.send(SecurityEvent {
+            timestamp: SystemTime::now(),
+            kind,
+            ip,
+            node_id,
+            packet_type,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_receive_events() {
+        let audit_log = AuditLog::default();
+        // Nothing is buffered without subscribers.
+        audit_log.record(SecurityEventKind::MalformedPacket, None, None, None);
+
+        let mut events = audit_log.subscribe();
+        let node_id = NodeId::random();
+        audit_log.record(
+            SecurityEventKind::ForgedHandshake,
+            Some([192, 0, 2, 1].into()),
+            Some(node_id),
+            Some(PacketType::Handshake),
+        );
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, SecurityEventKind::ForgedHandshake);
+        assert_eq!(event.node_id, Some(node_id));
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! Commands that start an ephemeral node, run a single operation against the network and print
+//! the result.
+use clap::{Args, Subcommand};
+use discv5::{
+    crawler::Reachability,
+    enr::{self, CombinedKey, NodeId},
+    service::Pong,
+    ConfigBuilder, CrawlConfig, CrawlSnapshot, Discv5, Enr, ListenConfig,
+};
+use std::{convert::TryFrom, error::Error, net::IpAddr, path::PathBuf};
+use tracing::info;
+
+use crate::key;
+
+/// The options of the ephemeral node running a command.
+#[derive(Args)]
+pub struct NodeArgs {
+    /// Specify a secp256k1 private key file (hex encoded) to use for the nodes identity. A new
+    /// key is generated if unset.
+    #[clap(long = "secp256k1-key-file")]
+    pub secp256k1_key_file: Option<PathBuf>,
+
+    /// The environment variable holding the passphrase of an encrypted key file. If set, the key file is read as a passphrase-encrypted key.
+    #[clap(long = "key-passphrase-env", requires = "secp256k1_key_file")]
+    pub key_passphrase_env: Option<String>,
+
+    /// Specifies the listening address of the node.
+    #[clap(long = "listen.ip", default_value = "0.0.0.0")]
+    pub listen_ip: IpAddr,
+
+    /// Specifies the listening UDP port of the node. A random port is used if unset.
+    #[clap(long = "listen.port", default_value = "0")]
+    pub listen_port: u16,
+
+    /// A base64 encoded ENR to bootstrap from. Can be given multiple times.
+    #[clap(long = "bootnode")]
+    pub bootnodes: Vec<Enr>,
+}
+
+#[derive(Subcommand)]
+pub enum ClientCommand {
+    /// Find the nodes closest to a node id
+    Lookup {
+        #[command(flatten)]
+        node: NodeArgs,
+        /// The hex encoded target node id
+        #[arg(value_parser = parse_node_id)]
+        node_id: NodeId,
+    },
+
+    /// Ping a node and print the address it observed for us
+    Ping {
+        #[command(flatten)]
+        node: NodeArgs,
+        /// The base64 encoded ENR of the node
+        enr: Enr,
+    },
+
+    /// Request the latest ENR of a node
+    RequestEnr {
+        #[command(flatten)]
+        node: NodeArgs,
+        /// The base64 encoded ENR of the node
+        enr: Enr,
+    },
+
+    /// Crawl the network reachable from the bootnodes
+    Crawl {
+        #[command(flatten)]
+        node: NodeArgs,
+        /// The maximum number of nodes contacted
+        #[arg(long, default_value = "10000")]
+        max_peers: usize,
+        /// The number of nodes crawled concurrently
+        #[arg(long, default_value = "16")]
+        concurrency: usize,
+    },
+}
+
+impl ClientCommand {
+    fn node(&self) -> &NodeArgs {
+        match self {
+            ClientCommand::Lookup { node, .. }
+            | ClientCommand::Ping { node, .. }
+            | ClientCommand::RequestEnr { node, .. }
+            | ClientCommand::Crawl { node, .. } => node,
+        }
+    }
+}
+
+pub async fn run(command: ClientCommand) -> Result<(), Box<dyn Error>> {
+    let discv5 = start(command.node()).await?;
+    match command {
+        ClientCommand::Lookup { node_id, .. } => {
+            for enr in lookup(&discv5, node_id).await? {
+                println!("{} {}", enr.node_id(), enr.to_base64());
+            }
+        }
+        ClientCommand::Ping { enr, .. } => {
+            let pong = ping(&discv5, enr).await?;
+            println!(
+                "enr-seq: {}, observed address: {}:{}",
+                pong.enr_seq, pong.ip, pong.port
+            );
+        }
+        ClientCommand::RequestEnr { enr, .. } => {
+            println!("{}", request_enr(&discv5, enr).await?.to_base64());
+        }
+        ClientCommand::Crawl {
+            max_peers,
+            concurrency,
+            ..
+        } => {
+            let snapshot = crawl(&discv5, max_peers, concurrency).await?;
+            for node in &snapshot.nodes {
+                println!(
+                    "{} {:?} {}",
+                    node.enr.node_id(),
+                    node.reachability,
+                    node.enr.to_base64()
+                );
+            }
+            info!(
+                "Found {} nodes in {:?}, {} reachable, {} unreachable, {} not contacted",
+                snapshot.nodes.len(),
+                snapshot.duration,
+                snapshot.count(Reachability::Reachable),
+                snapshot.count(Reachability::Unreachable),
+                snapshot.count(Reachability::NotContacted),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Parses a hex encoded node id, with or without a `0x` prefix.
+fn parse_node_id(node_id: &str) -> Result<NodeId, String> {
+    let bytes = hex::decode(node_id.trim_start_matches("0x"))
+        .map_err(|e| format!("Invalid hex encoding: {}", e))?;
+    let raw = <[u8; 32]>::try_from(bytes.as_slice())
+        .map_err(|_| format!("Invalid node id: expected 32 bytes, got {}", bytes.len()))?;
+    Ok(NodeId::new(&raw))
+}
+
+/// Creates and starts the ephemeral node and adds the bootnodes to its routing table. The node
+/// doesn't advertise an address, peers answer it at the address they observe.
+async fn start(args: &NodeArgs) -> Result<Discv5, Box<dyn Error>> {
+    let key = match (&args.secp256k1_key_file, &args.key_passphrase_env) {
+        (Some(path), Some(passphrase_env)) => {
+            key::read_encrypted_key_from_file(path, passphrase_env)?
+        }
+        (Some(path), None) => key::read_secp256k1_key_from_file(path)?,
+        (None, _) => CombinedKey::generate_secp256k1(),
+    };
+    let enr = enr::Builder::default().build(&key)?;
+    let config =
+        ConfigBuilder::new(ListenConfig::from_ip(args.listen_ip, args.listen_port)).build();
+    let mut discv5: Discv5 = Discv5::new(enr, key, config)?;
+    discv5
+        .start()
+        .await
+        .map_err(|e| format!("Failed to start discv5: {:?}", e))?;
+    info!(node_id = %discv5.local_enr().node_id(), "Started discv5");
+
+    for enr in &args.bootnodes {
+        discv5
+            .add_enr(enr.clone())
+            .map_err(|e| format!("Could not add bootnode {}: {}", enr.node_id(), e))?;
+    }
+    Ok(discv5)
+}
+
+async fn lookup(discv5: &Discv5, target: NodeId) -> Result<Vec<Enr>, Box<dyn Error>> {
+    Ok(discv5
+        .find_node(target)
+        .await
+        .map_err(|e| format!("Lookup failed: {:?}", e))?)
+}
+
+async fn ping(discv5: &Discv5, enr: Enr) -> Result<Pong, Box<dyn Error>> {
+    Ok(discv5
+        .send_ping(enr)
+        .await
+        .map_err(|e| format!("Ping failed: {:?}", e))?)
+}
+
+async fn request_enr(discv5: &Discv5, enr: Enr) -> Result<Enr, Box<dyn Error>> {
+    let mut nodes = discv5
+        .find_node_designated_peer(enr, vec![0])
+        .await
+        .map_err(|e| format!("ENR request failed: {:?}", e))?;
+    Ok(nodes.pop().ok_or("Peer returned no ENR")?)
+}
+
+async fn crawl(
+    discv5: &Discv5,
+    max_peers: usize,
+    concurrency: usize,
+) -> Result<CrawlSnapshot, Box<dyn Error>> {
+    Ok(discv5
+        .crawl(CrawlConfig {
+            max_peers,
+            concurrency,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| format!("Crawl failed: {:?}", e))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    async fn start_peer(port: u16) -> Discv5 {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(port)
+            .build(&key)
+            .unwrap();
+        let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+            ip: Ipv4Addr::LOCALHOST,
+            port,
+        })
+        .build();
+        let mut peer: Discv5 = Discv5::new(enr, key, config).unwrap();
+        peer.start().await.unwrap();
+        peer
+    }
+
+    fn node_args(bootnodes: Vec<Enr>) -> NodeArgs {
+        NodeArgs {
+            secp256k1_key_file: None,
+            key_passphrase_env: None,
+            listen_ip: Ipv4Addr::LOCALHOST.into(),
+            listen_port: 0,
+            bootnodes,
+        }
+    }
+
+    #[test]
+    fn test_parse_node_id() {
+        let node_id = NodeId::random();
+        let hex_id = hex::encode(node_id.raw());
+        assert_eq!(parse_node_id(&hex_id), Ok(node_id));
+        assert_eq!(parse_node_id(&format!("0x{}", hex_id)), Ok(node_id));
+        assert!(parse_node_id("0x1234").is_err());
+        assert!(parse_node_id("not hex").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let peer = start_peer(10196).await;
+        let peer_enr = peer.local_enr();
+        let discv5 = start(&node_args(vec![peer_enr.clone()])).await.unwrap();
+
+        let pong = ping(&discv5, peer_enr.clone()).await.unwrap();
+        assert_eq!(pong.enr_seq, peer_enr.seq());
+        assert_eq!(pong.ip, IpAddr::from(Ipv4Addr::LOCALHOST));
+
+        assert_eq!(
+            request_enr(&discv5, peer_enr.clone()).await.unwrap(),
+            peer_enr
+        );
+
+        let found = lookup(&discv5, NodeId::random()).await.unwrap();
+        assert!(found.iter().any(|enr| enr.node_id() == peer_enr.node_id()));
+
+        let snapshot = crawl(&discv5, 10, 2).await.unwrap();
+        assert_eq!(snapshot.count(Reachability::Reachable), 1);
+    }
+}
@@ -20,6 +20,9 @@ enum KeyActions {
         /// Output file path for the key
         #[arg(short, long, required = false)]
         file: Option<PathBuf>,
+        /// The environment variable holding a passphrase to encrypt the key file with
+        #[arg(long, requires = "file")]
+        passphrase_env: Option<String>,
     },
 }
 
@@ -42,6 +45,24 @@ pub fn read_secp256k1_key_from_file<P: AsRef<Path>>(
     result
 }
 
+/// Reads a key file encrypted with the passphrase held by the environment variable.
+pub fn read_encrypted_key_from_file<P: AsRef<Path>>(
+    path: P,
+    passphrase_env: &str,
+) -> Result<enr::CombinedKey, io::Error> {
+    let passphrase = read_passphrase(passphrase_env)?;
+    discv5::key_store::EncryptedKeyFile::new(path.as_ref()).load(passphrase.as_bytes())
+}
+
+fn read_passphrase(passphrase_env: &str) -> Result<String, io::Error> {
+    std::env::var(passphrase_env).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot read the passphrase from {passphrase_env}: {e}"),
+        )
+    })
+}
+
 pub fn write_secp256k1_key_to_file<P: AsRef<Path>>(
     path: P,
     key: &enr::CombinedKey,
@@ -69,7 +90,10 @@ pub fn write_secp256k1_key_to_file<P: AsRef<Path>>(
 
 pub fn run(key_cmd: KeyCommand) -> eyre::Result<()> {
     match key_cmd.action {
-        KeyActions::Generate { file } => {
+        KeyActions::Generate {
+            file,
+            passphrase_env,
+        } => {
             info!("Generating new secp256k1 key");
             let key = enr::CombinedKey::generate_secp256k1();
             let hex_key = match &key {
@@ -77,7 +101,13 @@ pub fn run(key_cmd: KeyCommand) -> eyre::Result<()> {
                 _ => unreachable!("We generated a secp256k1 key"),
             };
 
-            if let Some(path) = file {
+            if let (Some(path), Some(passphrase_env)) = (&file, passphrase_env) {
+                info!("Saving encrypted to {}", path.display());
+                let passphrase = read_passphrase(&passphrase_env)?;
+                discv5::key_store::EncryptedKeyFile::new(path)
+                    .store(&key, passphrase.as_bytes())?;
+                info!("Key saved successfully");
+            } else if let Some(path) = file {
                 info!("Saving in raw format to {}", path.display());
                 write_secp256k1_key_to_file(path, &key)?;
                 info!("Key saved successfully");
@@ -1,3 +1,4 @@
+mod client;
 mod key;
 mod server;
 mod utils;
@@ -5,11 +6,12 @@ mod utils;
 use std::error::Error;
 
 use clap::{Parser, Subcommand};
+use client::ClientCommand;
 use key::KeyCommand;
 use server::args::ServerArgs;
 use tracing::Level;
 
-/// CLI tool for discv5 node and secp256k1 key management
+/// CLI tool for discv5 node and secp256k1 key management, and for operating on discv5 networks
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
@@ -28,6 +30,9 @@ enum Commands {
 
     /// Manage secp256k1 keys
     Key(KeyCommand),
+
+    #[command(flatten)]
+    Client(ClientCommand),
 }
 
 #[tokio::main]
@@ -39,6 +44,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
     match cli.command {
         Commands::Server(server_cmd) => server::run(server_cmd).await?,
         Commands::Key(key_cmd) => key::run(key_cmd)?,
+        Commands::Client(client_cmd) => client::run(client_cmd).await?,
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_commands_parse() {
+        let enr = "enr:-IS4QHCYrYZbAKWCBRlAy5zzaDZXJBGkcnh4MHcBFZntXNFrdvJjX04jRzjzCBOonrkTfj499SZuOh8R33Ls8RRcy5wBgmlkgnY0gmlwhH8AAAGJc2VjcDI1NmsxoQPKY0yuDUmstAHYpMa2_oxVtw0RW_QAdpzBQA8yWM0xOIN1ZHCCdl8";
+        let cli = Cli::try_parse_from(["discv5-cli", "ping", "--bootnode", enr, enr]).unwrap();
+        match cli.command {
+            Commands::Client(ClientCommand::Ping { node, enr }) => {
+                assert_eq!(node.bootnodes, vec![enr.clone()]);
+                assert_eq!(node.listen_port, 0);
+            }
+            _ => panic!("Expected the ping command"),
+        }
+
+        let node_id = "0x".to_string() + &"ab".repeat(32);
+        let cli = Cli::try_parse_from(["discv5-cli", "lookup", node_id.as_str()]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Client(ClientCommand::Lookup { .. })
+        ));
+        assert!(Cli::try_parse_from(["discv5-cli", "lookup", "0x1234"]).is_err());
+
+        let cli = Cli::try_parse_from(["discv5-cli", "crawl", "--max-peers", "5"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Client(ClientCommand::Crawl { max_peers: 5, .. })
+        ));
+    }
+}
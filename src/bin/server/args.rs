@@ -11,6 +11,10 @@ pub struct ServerArgs {
     )]
     pub secp256k1_key_file: PathBuf,
 
+    /// The environment variable holding the passphrase of an encrypted key file. If set, the key file is read as a passphrase-encrypted key.
+    #[clap(long = "key-passphrase-env")]
+    pub key_passphrase_env: Option<String>,
+
     /// Specifies the listening address of the server.
     #[clap(long = "listen.ipv4", default_value = "0.0.0.0")]
     pub listen_ipv4: Ipv4Addr,
@@ -38,6 +38,12 @@ pub fn run(server: Arc<Discv5>) {
                         talk_request.node_id()
                     );
                 }
+                Some(Event::NatStatusChanged(nat_status)) => {
+                    info!(
+                        "The NAT status has been updated to ipv4: {:?}, ipv6: {:?}",
+                        nat_status.ipv4, nat_status.ipv6
+                    );
+                }
                 _ => {}
             }
         }
@@ -19,7 +19,12 @@ pub mod events;
 pub mod stats;
 
 pub async fn run(args: ServerArgs) -> Result<(), Box<dyn Error>> {
-    let key = key::read_secp256k1_key_from_file(&args.secp256k1_key_file)?;
+    let key = match &args.key_passphrase_env {
+        Some(passphrase_env) => {
+            key::read_encrypted_key_from_file(&args.secp256k1_key_file, passphrase_env)?
+        }
+        None => key::read_secp256k1_key_from_file(&args.secp256k1_key_file)?,
+    };
     let enr = enr::build(&args, &key)?;
 
     let enr_str: &'static str = Box::leak(enr.to_string().into_boxed_str());
@@ -0,0 +1,276 @@
+//! Tracks the bootnodes separately from the regular peers of the routing table.
+//!
+//! Bootnodes are the entry point to the network, so losing them when the table drains leaves the
+//! node isolated. They are retried with exponential backoff whenever we have no connected peers,
+//! and the packet filter of the instance lets them through even if they are banned.
+use crate::time::{Clock, Instant, SystemClock};
+use crate::Enr;
+use enr::NodeId;
+use parking_lot::RwLock;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+/// The delay before retrying a bootnode after its first failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The maximum delay between retries of a bootnode.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// The health of a bootnode, as reported by `Discv5::bootnodes`.
+#[derive(Debug, Clone)]
+pub struct BootnodeHealth {
+    /// The most recent ENR of the bootnode.
+    pub enr: Enr,
+    /// Whether the bootnode is currently connected in the routing table.
+    pub connected: bool,
+    /// The number of requests to the bootnode that have failed since it was last reachable.
+    pub consecutive_failures: u32,
+    /// The last time a session was established with the bootnode.
+    pub last_success: Option<Instant>,
+}
+
+struct Bootnode {
+    enr: Enr,
+    consecutive_failures: u32,
+    last_success: Option<Instant>,
+    /// The earliest time the bootnode is retried.
+    next_attempt: Instant,
+}
+
+/// The node ids and IPs of the bootnodes of an instance, which its packet filter lets through
+/// like those on the permit list. Unlike the permit list, they are not shared with the other
+/// instances of the process.
+#[derive(Debug, Default)]
+pub struct BootnodePermits {
+    pub nodes: HashSet<NodeId>,
+    pub ips: HashSet<IpAddr>,
+}
+
+/// The bootnodes of an instance, along with their failures and the backoff of their retries.
+pub struct Bootnodes {
+    nodes: HashMap<NodeId, Bootnode>,
+    /// The permits of the bootnodes, shared with the packet filter.
+    permits: Arc<RwLock<BootnodePermits>>,
+    /// The clock the backoff is measured by.
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Bootnodes {
+    fn default() -> Self {
+        Bootnodes::new(Arc::new(SystemClock))
+    }
+}
+
+impl Bootnodes {
+    /// Creates an empty set of bootnodes, backing off their retries as measured by `clock`.
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Bootnodes {
+            nodes: HashMap::new(),
+            permits: Default::default(),
+            clock,
+        }
+    }
+
+    /// The permits of the bootnodes, kept up to date as bootnodes are added and removed.
+    pub fn permits(&self) -> Arc<RwLock<BootnodePermits>> {
+        self.permits.clone()
+    }
+
+    /// Adds a bootnode. Adding a known bootnode updates its ENR.
+    pub fn insert(&mut self, enr: Enr) {
+        match self.nodes.get_mut(&enr.node_id()) {
+            Some(bootnode) => bootnode.enr = enr,
+            None => {
+                self.nodes.insert(
+                    enr.node_id(),
+                    Bootnode {
+                        enr,
+                        consecutive_failures: 0,
+                        last_success: None,
+                        next_attempt: self.clock.now(),
+                    },
+                );
+            }
+        }
+        self.update_permits();
+    }
+
+    /// Removes a bootnode, revoking its permits. Returns `true` if it was a bootnode.
+    pub fn remove(&mut self, node_id: &NodeId) -> bool {
+        if self.nodes.remove(node_id).is_none() {
+            return false;
+        }
+        self.update_permits();
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn contains(&self, node_id: &NodeId) -> bool {
+        self.nodes.contains_key(node_id)
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = &NodeId> {
+        self.nodes.keys()
+    }
+
+    /// Permits the node ids and the IPs of the current ENRs of the bootnodes.
+    fn update_permits(&mut self) {
+        let mut permits = self.permits.write();
+        permits.nodes = self.nodes.keys().copied().collect();
+        permits.ips = self
+            .nodes
+            .values()
+            .flat_map(|bootnode| {
+                [
+                    bootnode.enr.ip4().map(IpAddr::V4),
+                    bootnode.enr.ip6().map(IpAddr::V6),
+                ]
+            })
+            .flatten()
+            .collect();
+    }
+
+    /// Returns the bootnodes whose backoff has elapsed and schedules their next attempt.
+    pub fn due(&mut self, now: Instant) -> Vec<Enr> {
+        self.nodes
+            .values_mut()
+            .filter(|bootnode| bootnode.next_attempt <= now)
+            .map(|bootnode| {
+                bootnode.next_attempt = now + backoff(bootnode.consecutive_failures);
+                bootnode.enr.clone()
+            })
+            .collect()
+    }
+
+    /// A session was established with the node. If it is a bootnode, its failures are reset and
+    /// its ENR is updated.
+    pub fn success(&mut self, enr: &Enr) {
+        if let Some(bootnode) = self.nodes.get_mut(&enr.node_id()) {
+            bootnode.consecutive_failures = 0;
+            let now = self.clock.now();
+            bootnode.last_success = Some(now);
+            bootnode.next_attempt = now;
+            if enr.seq() > bootnode.enr.seq() {
+                bootnode.enr = enr.clone();
+                self.update_permits();
+            }
+        }
+    }
+
+    /// A request to the node failed. If it is a bootnode, its next retry is backed off further.
+    pub fn failure(&mut self, node_id: &NodeId) {
+        if let Some(bootnode) = self.nodes.get_mut(node_id) {
+            bootnode.consecutive_failures = bootnode.consecutive_failures.saturating_add(1);
+            bootnode.next_attempt = self.clock.now() + backoff(bootnode.consecutive_failures);
+        }
+    }
+
+    /// Reports the health of all bootnodes. `is_connected` reports whether a node is connected
+    /// in the routing table.
+    pub fn health(&self, mut is_connected: impl FnMut(&NodeId) -> bool) -> Vec<BootnodeHealth> {
+        self.nodes
+            .iter()
+            .map(|(node_id, bootnode)| BootnodeHealth {
+                enr: bootnode.enr.clone(),
+                connected: is_connected(node_id),
+                consecutive_failures: bootnode.consecutive_failures,
+                last_success: bootnode.last_success,
+            })
+            .collect()
+    }
+}
+
+/// The delay before the next attempt after the given number of consecutive failures.
+fn backoff(consecutive_failures: u32) -> Duration {
+    if consecutive_failures == 0 {
+        return INITIAL_BACKOFF;
+    }
+    INITIAL_BACKOFF
+        .saturating_mul(1 << consecutive_failures.min(16))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::ManualClock;
+    use enr::CombinedKey;
+
+    fn bootnode_enr(key: &CombinedKey) -> Enr {
+        Enr::builder()
+            .ip4("192.0.2.1".parse().unwrap())
+            .udp4(9000)
+            .build(key)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_backoff_and_recovery() {
+        let clock = ManualClock::default();
+        let mut bootnodes = Bootnodes::new(Arc::new(clock.clone()));
+        let enr = bootnode_enr(&CombinedKey::generate_secp256k1());
+        let node_id = enr.node_id();
+        bootnodes.insert(enr.clone());
+
+        let now = clock.now();
+        assert_eq!(bootnodes.due(now).len(), 1);
+        assert!(bootnodes.due(now).is_empty());
+
+        bootnodes.failure(&node_id);
+        bootnodes.failure(&node_id);
+        assert!(bootnodes.due(now + INITIAL_BACKOFF).is_empty());
+        clock.advance(MAX_BACKOFF);
+        assert_eq!(bootnodes.due(clock.now()).len(), 1);
+
+        bootnodes.success(&enr);
+        let health = bootnodes.health(|_| true);
+        assert_eq!(health[0].consecutive_failures, 0);
+        assert!(health[0].last_success.is_some());
+        assert_eq!(bootnodes.due(clock.now()).len(), 1);
+
+        assert!(bootnodes.remove(&node_id));
+        assert!(bootnodes.is_empty());
+    }
+
+    #[test]
+    fn test_bootnodes_are_permitted() {
+        let mut bootnodes = Bootnodes::default();
+        let permits = bootnodes.permits();
+        let key = CombinedKey::generate_secp256k1();
+        let enr = bootnode_enr(&key);
+        let node_id = enr.node_id();
+        let ip = IpAddr::V4(enr.ip4().unwrap());
+
+        bootnodes.insert(enr.clone());
+        assert!(permits.read().nodes.contains(&node_id));
+        assert!(permits.read().ips.contains(&ip));
+
+        // The permits follow the IPs of the bootnode's latest ENR.
+        let moved = Enr::builder()
+            .ip4("192.0.2.2".parse().unwrap())
+            .udp4(9000)
+            .seq(enr.seq() + 1)
+            .build(&key)
+            .unwrap();
+        bootnodes.success(&moved);
+        assert!(!permits.read().ips.contains(&ip));
+        assert!(permits
+            .read()
+            .ips
+            .contains(&IpAddr::V4(moved.ip4().unwrap())));
+
+        // Other instances are unaffected.
+        assert!(Bootnodes::default().permits().read().nodes.is_empty());
+
+        bootnodes.remove(&node_id);
+        assert!(permits.read().nodes.is_empty());
+        assert!(permits.read().ips.is_empty());
+    }
+}
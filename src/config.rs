@@ -2,10 +2,39 @@
 use cidr::Ipv4Cidr;
 
 use crate::{
-    kbucket::MAX_NODES_PER_BUCKET, socket::ListenConfig, Enr, Executor, PermitBanList, RateLimiter,
-    RateLimiterBuilder,
+    kbucket::{EvictionPolicy, MAX_NODES_PER_BUCKET},
+    service::{
+        BanIntelConfig, DialBackConfig, EclipseDetectionConfig, NodesSelection, QuerySeeding,
+        TopicConfig,
+    },
+    socket::{ListenConfig, TransportFactory},
+    time::{Clock, SystemClock},
+    DialPolicy, Enr, EnrAllowlist, Executor, ExternalAddressPolicy, PeerStore, PermitBanList,
+    RateLimiter, RateLimiterBuilder,
 };
-use std::time::Duration;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+/// The capacity and time to live of the NODES response cache set by
+/// [`ConfigBuilder::bootnode_mode`].
+const BOOTNODE_NODES_RESPONSE_CACHE: (usize, Duration) = (1024, Duration::from_secs(5));
+
+/// The session cache capacity set by [`ConfigBuilder::low_memory`].
+const LOW_MEMORY_SESSION_CACHE_CAPACITY: usize = 64;
+
+/// The WHOAREYOU challenges per IP set by [`ConfigBuilder::bootnode_mode`].
+const BOOTNODE_CHALLENGE_RATE_LIMIT: (u64, Duration) = (10, Duration::from_secs(1));
+
+/// The idle session cache capacity set by [`ConfigBuilder::bootnode_mode`].
+const BOOTNODE_IDLE_SESSION_CACHE_CAPACITY: usize = 20_000;
+
+/// The idle session cache capacity set by [`ConfigBuilder::low_memory`].
+const LOW_MEMORY_IDLE_SESSION_CACHE_CAPACITY: usize = 256;
+
+/// The event stream capacity set by [`ConfigBuilder::low_memory`].
+const LOW_MEMORY_EVENT_STREAM_CAPACITY: usize = 16;
+
+/// The peers tracked per query set by [`ConfigBuilder::low_memory`].
+const LOW_MEMORY_QUERY_PEER_LIMIT: usize = 2 * MAX_NODES_PER_BUCKET;
 
 /// Configuration parameters that define the performance of the discovery network.
 #[derive(Clone)]
@@ -34,22 +63,90 @@ pub struct Config {
     /// The session timeout for each node. Default: 1 day.
     pub session_timeout: Duration,
 
-    /// The maximum number of established sessions to maintain. Default: 1000.
+    /// The maximum number of established sessions to keep in full. Less recently used sessions
+    /// are demoted to the idle tier. Default: 1000.
     pub session_cache_capacity: usize,
 
-    /// Updates the local ENR IP and port based on PONG responses from peers. Default: true.
-    pub enr_update: bool,
+    /// The maximum number of idle sessions to maintain. Idle sessions only keep their keys,
+    /// message counter and the nonces checked for replays, dropping the keys of a previous
+    /// handshake and any pending ENR request. Default: 4000.
+    pub idle_session_cache_capacity: usize,
+
+    /// The fraction of both session cache capacities reserved for sessions with members of the
+    /// routing table and peers on the permit list. Other sessions can't evict these, but can't
+    /// use the reserved capacity either. The reserved peers are refreshed every few seconds.
+    /// Default: 0.0 (nothing reserved).
+    pub session_reserved_fraction: f64,
+
+    /// The time a session goes unused before it is demoted to the idle tier. Default: 5 minutes.
+    pub session_idle_timeout: Duration,
+
+    /// If set, sessions with peers that also advertise the resumption capability in their ENR
+    /// leave a resumption ticket behind, valid for this long. When either peer lost the session,
+    /// e.g. after a short disconnect, the next message re-establishes it with keys derived from
+    /// the previous session, without a WHOAREYOU exchange. This is not part of the specification;
+    /// peers without the capability always perform the handshake. Default: None.
+    pub session_resumption: Option<Duration>,
+
+    /// How the local ENR IP and port are updated, by default from the addresses peers report in
+    /// PONG responses. Default: `ExternalAddressPolicy::Votes`.
+    pub external_address_policy: ExternalAddressPolicy,
+
+    /// The UDP port advertised in the local ENR for IPv4, when it differs from the bound port, as
+    /// behind a static port forward. Only the IP reported by peers is then used when updating the
+    /// ENR, with this port. Default: None.
+    pub advertised_udp4_port: Option<u16>,
+
+    /// The UDP port advertised in the local ENR for IPv6. See `advertised_udp4_port`. Default:
+    /// None.
+    pub advertised_udp6_port: Option<u16>,
 
     /// The maximum number of nodes we return to a find nodes request. The default is 16.
     pub max_nodes_response: usize,
 
+    /// Selects the nodes returned to a find nodes request when more than `max_nodes_response`
+    /// match. Default: closest first.
+    pub nodes_selection: NodesSelection,
+
+    /// Caches the nodes returned for each set of requested distances, given as a
+    /// `(capacity, ttl)` pair, so that busy nodes don't walk the routing table for every FINDNODE
+    /// request. Responses served from the cache miss changes made to the routing table within
+    /// the last `ttl`. Default: None.
+    pub nodes_response_cache: Option<(usize, Duration)>,
+
+    /// Runs the node as a dedicated bootnode, which answers requests but sends no PINGs of its own
+    /// beyond the periodic liveness checks of the routing table. In particular, newly inserted
+    /// peers are not pinged to learn their ENR updates early and no PINGs are sent to gather IP
+    /// votes. Queries are only run when requested by the application. Default: false.
+    pub bootnode_mode: bool,
+
     /// The minimum number of peer's who agree on an external IP port before updating the
     /// local ENR. Default: 10.
     pub enr_peer_update_min: usize,
 
+    /// Weights the votes of peers on our external address by the quality of the peers, rather
+    /// than counting every PONG equally. A vote weighs one, plus one for a peer connected in the
+    /// routing table, one if our session with the peer is older than ten minutes and one if the
+    /// peer has answered our requests without failing since. The address with the greatest weight
+    /// of votes is advertised. Default: false.
+    pub weighted_ip_votes: bool,
+
+    /// The minimum number of distinct subnets, /24 for IPv4 and /48 for IPv6, the peers voting for
+    /// an external address must be in before it is advertised. Default: 1.
+    pub ip_vote_min_subnets: usize,
+
     /// The number of peers to request in parallel in a single query. Default: 3.
     pub query_parallelism: usize,
 
+    /// Decides which peers of the routing table a query starts from. Default: the peers closest
+    /// to the target.
+    pub query_seeding: QuerySeeding,
+
+    /// The maximum number of peers, and their ENRs, tracked by a single query. When more peers
+    /// are discovered, the furthest ones that have not been contacted are dropped. If set to None,
+    /// a query tracks every peer it discovers. Default: None.
+    pub query_peer_limit: Option<usize>,
+
     /// Limits the number of IP addresses from the same
     /// /24 subnet in the kbuckets table. This is to mitigate eclipse attacks. Default: false.
     pub ip_limit: bool,
@@ -58,17 +155,139 @@ pub struct Config {
     /// than the bucket size (16). By default this is disabled (set to the maximum bucket size, 16).
     pub incoming_bucket_limit: usize,
 
+    /// How full buckets of the routing table treat new nodes. Default:
+    /// `EvictionPolicy::LeastRecentlyConnected`.
+    pub eviction_policy: EvictionPolicy,
+
     /// A filter used to decide whether to insert nodes into our local routing table. Nodes can be
     /// excluded if they do not pass this filter. The default is to accept all nodes.
     pub table_filter: fn(&Enr) -> bool,
 
+    /// Restricts the nodes admitted to the routing table and to sessions to those whose ENR is
+    /// allowed, for permissioned networks. Unlike the `table_filter`, this also rejects handshakes
+    /// from nodes that are not allowed. Default: None (all nodes allowed).
+    pub enr_allowlist: Option<EnrAllowlist>,
+
     /// The time between pings to ensure connectivity amongst connected nodes. Default: 300
     /// seconds.
     pub ping_interval: Duration,
 
+    /// The number of successive failed requests after which a peer is considered dead, rather
+    /// than waiting for its next ping to fail. Its sessions are dropped, it is marked disconnected
+    /// in the routing table and [`crate::Event::PeerUnresponsive`] is emitted. Any response resets
+    /// the count. If set to None, peers are not tracked. Default: None.
+    pub max_successive_failures: Option<usize>,
+
+    /// A backend the routing table and the ban list are persisted to. Both are repopulated from
+    /// the store when the service starts, and saved to it every `peer_store_checkpoint_interval`
+    /// and on shutdown. Changes to the ban list are saved within seconds. Default: None.
+    pub peer_store: Option<Arc<dyn PeerStore>>,
+
+    /// The time between checkpoints of the routing table to the `peer_store`. Default: 5 minutes.
+    pub peer_store_checkpoint_interval: Duration,
+
+    /// The time between pings sent solely to keep NAT mappings open towards the peers registered
+    /// via `Discv5::add_nat_keepalive_peer`. Consumer NATs typically expire UDP mappings after
+    /// 30-60 seconds, which is much shorter than `ping_interval`. If set to None, no keepalive
+    /// pings are sent. Default: None.
+    pub nat_keepalive_interval: Option<Duration>,
+
+    /// The time between lookups of our own node id. These keep the buckets closest to us
+    /// accurate and, after an ENR change, spread the new record to the nodes that others ask
+    /// for us. A lookup is skipped while the previous one is still running. If set to None, no
+    /// self-lookups are made. Default: None.
+    pub self_lookup_interval: Option<Duration>,
+
+    /// Paces the PINGs that advertise a new local ENR sequence number to connected peers, given
+    /// as a `(fanout, interval)` pair: at most `fanout` peers are pinged every `interval` until
+    /// all connected peers have been informed. This applies to ENR changes made by the
+    /// application as well as socket updates from IP voting. If set to None, socket updates ping
+    /// all connected peers at once and application changes are only propagated by the regular
+    /// `ping_interval`. Default: None.
+    pub enr_readvertise: Option<(usize, Duration)>,
+
+    /// The maximum number of recently seen ENRs to retain outside of the routing table. Stored
+    /// ENRs can be looked up via `Discv5::find_stored_enr` and `Discv5::stored_enrs`. If set to
+    /// None, no ENRs are stored. Default: None.
+    pub enr_store_capacity: Option<usize>,
+
+    /// The time an ENR remains in the ENR store after it was last seen. Default: 1 hour.
+    pub enr_store_ttl: Duration,
+
     /// Reports all discovered ENR's when traversing the DHT to the event stream. Default true.
     pub report_discovered_peers: bool,
 
+    /// Remembers the sequence numbers of the reported discovered ENRs, given as a
+    /// `(capacity, ttl)` pair, so that an ENR returned by several queries is only reported again
+    /// once it has been updated. Set to None to report every discovered ENR.
+    /// Default: (1024, 10 minutes).
+    pub discovered_peers_dedup: Option<(usize, Duration)>,
+
+    /// Keeps the discovered ENRs that can't be contacted over the IP versions in use, which are
+    /// otherwise dropped, for applications that reach nodes over other transports. They are
+    /// appended to the results of `Discv5::find_node` and reported as
+    /// [`crate::Event::DiscoveredUnreachable`] rather than [`crate::Event::Discovered`]. They are
+    /// never added to the routing table nor contacted by queries. Default: false.
+    pub report_unreachable_enrs: bool,
+
+    /// The number of events buffered by the event stream before further events are dropped. If
+    /// set to None, 100 events are buffered when discovered peers are reported and 30 otherwise.
+    /// Default: None.
+    pub event_stream_capacity: Option<usize>,
+
+    /// The number of decoded packets buffered between the socket and the handler. Packets that
+    /// arrive while the queue is full are dropped, so that a flood is shed at the socket instead
+    /// of piling up in memory. Default: 128.
+    pub inbound_queue_capacity: usize,
+
+    /// The number of messages buffered from the service to the handler. The service does not wait
+    /// for the handler: while the queue is full, new requests fail with
+    /// `RequestError::ChannelFailed` and responses are dropped. Default: 1024.
+    pub handler_queue_capacity: usize,
+
+    /// The number of threads verifying handshakes and deriving session keys, so that the handler
+    /// keeps processing packets while many peers handshake at once. If set to 0, handshakes are
+    /// processed in the handler task. Default: 2.
+    pub handshake_workers: usize,
+
+    /// The maximum number of sessions being established at once. Requests to further peers
+    /// without a session wait until an establishment completes, those to peers on the permit list
+    /// first, then those of queries and the application, then maintenance traffic such as pings.
+    /// Waiting requests fail once their timeout passes. At most 1024 requests wait, and further
+    /// requests fail or make room by failing one of a lower priority. Default: None (unlimited).
+    pub max_session_establishments: Option<usize>,
+
+    /// Watches the routing table and lookups for signs of an eclipse attack, reporting them as
+    /// [`crate::Event::EclipseWarning`]. If set to None, no monitoring is done. Default: None.
+    pub eclipse_detection: Option<EclipseDetectionConfig>,
+
+    /// Exchanges signed reports of bans with the configured trusted peers over TALK, banning an
+    /// IP or node once enough of them report it. If set to None, ban reports are neither sent nor
+    /// accepted. Default: None.
+    pub ban_intel: Option<BanIntelConfig>,
+
+    /// Has connected peers ping us at a new external address agreed on by the votes of peers
+    /// before it is advertised, so that an address our packets appear to come from but which is
+    /// not reachable, such as behind a NAT without hairpinning, is not adopted. As the peers asked
+    /// have been contacted by us, this doesn't detect a NAT dropping packets of peers that haven't.
+    /// Dial-back requests of peers are only answered if this is set as well, at a public address
+    /// with the IP the peer sends from. If set to None, addresses are advertised as soon as the
+    /// votes agree. Default: None.
+    pub dial_back: Option<DialBackConfig>,
+
+    /// The TALK protocols whose responses re-establish the session with the requester if it has
+    /// expired by the time the application responds. The response is held while a handshake is
+    /// made and sent once the session is established, rather than dropped, so that the requester
+    /// doesn't have to retry. The requester's ENR must be known when the request is received,
+    /// otherwise the response is only sent if the session still exists. Default: empty.
+    pub reestablish_session_for_talk_responses: HashSet<Vec<u8>>,
+
+    /// Enables the advertisement of topics, both as a registrar holding the advertisements of
+    /// others and as a registrant placing our own. If set to None, registrations of others are
+    /// ignored and [`crate::Discv5::register_topic`] fails. Topic queries don't need it, and use
+    /// [`TopicConfig::default`] if it is not set. Default: None.
+    pub topics: Option<TopicConfig>,
+
     /// A set of configuration parameters for setting inbound request rate limits. See
     /// [`RateLimiterBuilder`] for options. This is only functional if the packet filter is
     /// enabled via the `enable_packet_filter` option. See the `Default` implementation for
@@ -85,15 +304,45 @@ pub struct Config {
     /// applicable if the `enable_packet_filter` option is set.
     pub filter_max_bans_per_ip: Option<usize>,
 
+    /// Caps the number of NODES and TALKRESP bytes sent to a single peer, given as a
+    /// `(max_bytes, interval)` pair. NODES responses that would exceed the cap are cut down to the
+    /// nodes that fit, TALKRESP responses are dropped. Unlike the inbound filter, this applies
+    /// regardless of the `enable_packet_filter` option and prevents peers from using us to amplify
+    /// traffic. Default: None (no limit).
+    pub outbound_response_limit: Option<(u64, Duration)>,
+
+    /// Caps the WHOAREYOU challenges sent to a single IP, given as a `(max_challenges, interval)`
+    /// pair. Packets that would call for further challenges are dropped, so that packets with a
+    /// spoofed source cannot make us send challenges to a victim at the rate they are sent to us.
+    /// Default: None (no limit).
+    pub challenge_rate_limit: Option<(u64, Duration)>,
+
+    /// The maximum number of WHOAREYOU challenges awaiting a handshake. Packets that would call
+    /// for further challenges are dropped, which bounds the memory spoofed packets can make us
+    /// spend. Default: 5000.
+    pub challenge_cache_capacity: usize,
+
+    /// The time a WHOAREYOU challenge awaits a handshake. Default: None (the request timeout).
+    pub challenge_ttl: Option<Duration>,
+
+    /// Whether to relay handshake initiations between peers as part of the NAT hole punching
+    /// extension. When enabled, peers we have sessions with can ask us to forward a RELAYINIT
+    /// to another of our peers so both sides can punch a hole in their NAT. Default: false.
+    pub enable_relay: bool,
+
     /// A set of lists that permit or ban IP's or NodeIds from the server. See
     /// `crate::PermitBanList`.
     pub permit_ban_list: PermitBanList,
 
-    /// Set the default duration for which nodes are banned for. This timeouts are checked every 5 minutes,
-    /// so the precision will be to the nearest 5 minutes. If set to `None`, bans from the filter
-    /// will last indefinitely. Default is 1 hour.
+    /// Set the default duration for which nodes are banned for. Bans are lifted as they expire,
+    /// measured against the `clock`. If set to `None`, bans from the filter will last
+    /// indefinitely. Default is 1 hour.
     pub ban_duration: Option<Duration>,
 
+    /// The interval at which unused sessions are demoted and the response and challenge limiters
+    /// are pruned. Bans are lifted as they expire, independently of it. Default: 5 minutes.
+    pub maintenance_interval: Duration,
+
     /// Auto-discovering our IP address, is only one part in discovering our NAT/firewall
     /// situation. We need to determine if we are behind a firewall that is preventing incoming
     /// connections (this is especially true for IPv6 where all connections will report the same
@@ -113,6 +362,24 @@ pub struct Config {
     /// Configuration for the sockets to listen on.
     pub listen_config: ListenConfig,
 
+    /// Creates the transports the node listens on in place of UDP sockets, such as an in-memory
+    /// network for simulations. Default: None.
+    pub transport_factory: Option<Arc<dyn TransportFactory>>,
+
+    /// The clock session, query, ban and IP vote expiries are measured against. Default:
+    /// [`SystemClock`].
+    pub clock: Arc<dyn Clock>,
+
+    /// When listening on both IPv4 and IPv6, decides which address is contacted for peers that
+    /// advertise both. Default: prefer IPv6.
+    pub dial_policy: DialPolicy,
+
+    /// Disables IPv4 entirely, for IPv6-only hosts. Packets from IPv4 and IPv4-mapped addresses
+    /// are dropped and never sent, such addresses are neither dialed nor advertised, and the IPv4
+    /// fields of the local ENR are removed. Requires a listen config without an IPv4 socket.
+    /// Default: false.
+    pub ipv6_only: bool,
+
     /// Lifts the restrictions on discovery table addition to nodes which have a differing
     /// source ip from their public advertised ip. Source ip addresses which are part of
     /// this cidr range will be added to discovery table
@@ -146,23 +413,68 @@ impl ConfigBuilder {
             request_retries: 1,
             session_timeout: Duration::from_secs(86400),
             session_cache_capacity: 1000,
-            enr_update: true,
+            idle_session_cache_capacity: 4000,
+            session_reserved_fraction: 0.0,
+            session_idle_timeout: Duration::from_secs(300),
+            session_resumption: None,
+            external_address_policy: ExternalAddressPolicy::Votes,
+            advertised_udp4_port: None,
+            advertised_udp6_port: None,
             max_nodes_response: 16,
+            nodes_selection: NodesSelection::default(),
+            nodes_response_cache: None,
+            bootnode_mode: false,
             enr_peer_update_min: 10,
+            weighted_ip_votes: false,
+            ip_vote_min_subnets: 1,
             query_parallelism: 3,
+            query_seeding: QuerySeeding::default(),
+            query_peer_limit: None,
             ip_limit: false,
             incoming_bucket_limit: MAX_NODES_PER_BUCKET,
+            eviction_policy: EvictionPolicy::default(),
             table_filter: |_| true,
+            enr_allowlist: None,
             ping_interval: Duration::from_secs(300),
+            max_successive_failures: None,
+            peer_store: None,
+            peer_store_checkpoint_interval: Duration::from_secs(300),
+            nat_keepalive_interval: None,
+            self_lookup_interval: None,
+            enr_readvertise: None,
+            enr_store_capacity: None,
+            enr_store_ttl: Duration::from_secs(3600),
             report_discovered_peers: true,
+            report_unreachable_enrs: false,
+            discovered_peers_dedup: Some((1024, Duration::from_secs(600))),
+            event_stream_capacity: None,
+            inbound_queue_capacity: 128,
+            handler_queue_capacity: 1024,
+            handshake_workers: 2,
+            max_session_establishments: None,
+            eclipse_detection: None,
+            ban_intel: None,
+            dial_back: None,
+            reestablish_session_for_talk_responses: HashSet::new(),
+            topics: None,
             filter_rate_limiter,
             filter_max_nodes_per_ip: Some(10),
             filter_max_bans_per_ip: Some(5),
+            outbound_response_limit: None,
+            challenge_rate_limit: None,
+            challenge_cache_capacity: 5000,
+            challenge_ttl: None,
+            enable_relay: false,
             permit_ban_list: PermitBanList::default(),
             ban_duration: Some(Duration::from_secs(3600)), // 1 hour
+            maintenance_interval: Duration::from_secs(300),
             auto_nat_listen_duration: Some(Duration::from_secs(300)), // 5 minutes
             executor: None,
             listen_config,
+            transport_factory: None,
+            clock: Arc::new(SystemClock),
+            dial_policy: DialPolicy::default(),
+            ipv6_only: false,
             allowed_cidr: None,
         };
 
@@ -214,15 +526,59 @@ impl ConfigBuilder {
         self
     }
 
-    /// The maximum number of established sessions to maintain.
+    /// The maximum number of established sessions to keep in full.
     pub fn session_cache_capacity(&mut self, capacity: usize) -> &mut Self {
         self.config.session_cache_capacity = capacity;
         self
     }
 
+    /// The maximum number of idle sessions to maintain.
+    pub fn idle_session_cache_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.idle_session_cache_capacity = capacity;
+        self
+    }
+
+    /// The fraction of the session cache reserved for table members and permitted peers.
+    pub fn session_reserved_fraction(&mut self, fraction: f64) -> &mut Self {
+        self.config.session_reserved_fraction = fraction;
+        self
+    }
+
+    /// The time a session goes unused before it is demoted to the idle tier.
+    pub fn session_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.session_idle_timeout = timeout;
+        self
+    }
+
+    /// Enables session resumption with peers advertising it, with tickets valid for `ttl`.
+    pub fn session_resumption(&mut self, ttl: Duration) -> &mut Self {
+        self.config.session_resumption = Some(ttl);
+        self
+    }
+
     /// Disables the auto-update of the local ENR IP and port based on PONG responses from peers.
     pub fn disable_enr_update(&mut self) -> &mut Self {
-        self.config.enr_update = false;
+        self.config.external_address_policy = ExternalAddressPolicy::Disabled;
+        self
+    }
+
+    /// Decides how the local ENR IP and port are updated. See [`ExternalAddressPolicy`].
+    pub fn external_address_policy(&mut self, policy: ExternalAddressPolicy) -> &mut Self {
+        self.config.external_address_policy = policy;
+        self
+    }
+
+    /// Advertises the given IPv4 UDP port in the local ENR instead of the port observed by peers.
+    /// Use this when the external port is known but differs from the bound port.
+    pub fn advertised_udp4_port(&mut self, port: u16) -> &mut Self {
+        self.config.advertised_udp4_port = Some(port);
+        self
+    }
+
+    /// Advertises the given IPv6 UDP port in the local ENR instead of the port observed by peers.
+    /// Use this when the external port is known but differs from the bound port.
+    pub fn advertised_udp6_port(&mut self, port: u16) -> &mut Self {
+        self.config.advertised_udp6_port = Some(port);
         self
     }
 
@@ -232,6 +588,56 @@ impl ConfigBuilder {
         self
     }
 
+    /// Selects the nodes returned to a find nodes request when more than the maximum match.
+    pub fn nodes_selection(&mut self, selection: NodesSelection) -> &mut Self {
+        self.config.nodes_selection = selection;
+        self
+    }
+
+    /// Caches the nodes returned for each set of requested distances for up to `ttl`. Set to
+    /// `None` to disable the cache.
+    pub fn nodes_response_cache(&mut self, cache: Option<(usize, Duration)>) -> &mut Self {
+        self.config.nodes_response_cache = cache;
+        self
+    }
+
+    /// Configures a dedicated bootnode. Besides setting `bootnode_mode`, this enables the packet
+    /// filter and the NODES response cache and stops the reporting of discovered peers, cutting
+    /// the memory and CPU spent per request. The idle session cache is raised to 20000 sessions,
+    /// as most peers of a bootnode only contact it once in a while, and at most 10 challenges per
+    /// second are sent to an IP. The individual options can be overridden afterwards.
+    pub fn bootnode_mode(&mut self) -> &mut Self {
+        self.config.bootnode_mode = true;
+        self.config.enable_packet_filter = true;
+        self.config.nodes_response_cache = Some(BOOTNODE_NODES_RESPONSE_CACHE);
+        self.config.idle_session_cache_capacity = BOOTNODE_IDLE_SESSION_CACHE_CAPACITY;
+        self.config.challenge_rate_limit = Some(BOOTNODE_CHALLENGE_RATE_LIMIT);
+        self.config.report_discovered_peers = false;
+        self
+    }
+
+    /// Caps the memory used by the node, for routers and single board computers. This shrinks the
+    /// session cache to 64 full and 256 idle sessions, buffers at most 16 events on the event
+    /// stream, limits each query to tracking 32 peers and stops the reporting of discovered peers.
+    /// The ENR store and the NODES response cache are disabled. The individual options can be
+    /// overridden afterwards.
+    ///
+    /// An idle node with an empty routing table allocates about 160 KiB, as buckets are only
+    /// allocated once they hold a node. On a simulated network of 64 nodes, after a few rounds of
+    /// lookups, a node allocates about 350 KiB with either config, most of it routing table
+    /// entries and sessions. The profile bounds what grows beyond that in large networks: the
+    /// session cache, which otherwise holds up to 5000 sessions, and the peers of each query.
+    pub fn low_memory(&mut self) -> &mut Self {
+        self.config.session_cache_capacity = LOW_MEMORY_SESSION_CACHE_CAPACITY;
+        self.config.idle_session_cache_capacity = LOW_MEMORY_IDLE_SESSION_CACHE_CAPACITY;
+        self.config.event_stream_capacity = Some(LOW_MEMORY_EVENT_STREAM_CAPACITY);
+        self.config.query_peer_limit = Some(LOW_MEMORY_QUERY_PEER_LIMIT);
+        self.config.report_discovered_peers = false;
+        self.config.enr_store_capacity = None;
+        self.config.nodes_response_cache = None;
+        self
+    }
+
     /// The minimum number of peer's who agree on an external IP port before updating the
     /// local ENR.
     pub fn enr_peer_update_min(&mut self, min: usize) -> &mut Self {
@@ -242,12 +648,36 @@ impl ConfigBuilder {
         self
     }
 
+    /// Weights the votes of peers on our external address by the quality of the peers.
+    pub fn weighted_ip_votes(&mut self, weighted: bool) -> &mut Self {
+        self.config.weighted_ip_votes = weighted;
+        self
+    }
+
+    /// The minimum number of distinct subnets the peers voting for an external address must be in.
+    pub fn ip_vote_min_subnets(&mut self, min_subnets: usize) -> &mut Self {
+        self.config.ip_vote_min_subnets = min_subnets;
+        self
+    }
+
     /// The number of peers to request in parallel in a single query.
     pub fn query_parallelism(&mut self, parallelism: usize) -> &mut Self {
         self.config.query_parallelism = parallelism;
         self
     }
 
+    /// Decides which peers of the routing table a query starts from.
+    pub fn query_seeding(&mut self, seeding: QuerySeeding) -> &mut Self {
+        self.config.query_seeding = seeding;
+        self
+    }
+
+    /// The maximum number of peers tracked by a single query. Set to `None` for no limit.
+    pub fn query_peer_limit(&mut self, limit: Option<usize>) -> &mut Self {
+        self.config.query_peer_limit = limit;
+        self
+    }
+
     /// Limits the number of IP addresses from the same
     /// /24 subnet in the kbuckets table. This is to mitigate eclipse attacks.
     pub fn ip_limit(&mut self) -> &mut Self {
@@ -262,6 +692,12 @@ impl ConfigBuilder {
         self
     }
 
+    /// Sets how full buckets of the routing table treat new nodes.
+    pub fn eviction_policy(&mut self, policy: EvictionPolicy) -> &mut Self {
+        self.config.eviction_policy = policy;
+        self
+    }
+
     /// A filter used to decide whether to insert nodes into our local routing table. Nodes can be
     /// excluded if they do not pass this filter.
     pub fn table_filter(&mut self, filter: fn(&Enr) -> bool) -> &mut Self {
@@ -269,18 +705,152 @@ impl ConfigBuilder {
         self
     }
 
+    /// Only admits nodes whose ENR is allowed to the routing table and to sessions.
+    pub fn enr_allowlist(&mut self, allowlist: EnrAllowlist) -> &mut Self {
+        self.config.enr_allowlist = Some(allowlist);
+        self
+    }
+
     /// The time between pings to ensure connectivity amongst connected nodes.
     pub fn ping_interval(&mut self, interval: Duration) -> &mut Self {
         self.config.ping_interval = interval;
         self
     }
 
+    /// Drops peers after the given number of successive failed requests.
+    pub fn max_successive_failures(&mut self, max: Option<usize>) -> &mut Self {
+        self.config.max_successive_failures = max;
+        self
+    }
+
+    /// Persists the routing table to the given store, such as a [`crate::FilePeerStore`].
+    pub fn peer_store(&mut self, peer_store: Arc<dyn PeerStore>) -> &mut Self {
+        self.config.peer_store = Some(peer_store);
+        self
+    }
+
+    /// The time between checkpoints of the routing table to the peer store.
+    pub fn peer_store_checkpoint_interval(&mut self, interval: Duration) -> &mut Self {
+        self.config.peer_store_checkpoint_interval = interval;
+        self
+    }
+
+    /// The time between pings sent to keep NAT mappings open towards the keepalive peers.
+    pub fn nat_keepalive_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.config.nat_keepalive_interval = interval;
+        self
+    }
+
+    /// The time between lookups of our own node id.
+    pub fn self_lookup_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.config.self_lookup_interval = interval;
+        self
+    }
+
+    /// Pings at most `fanout` connected peers every `interval` after the local ENR changes. Set
+    /// to `None` to ping all connected peers at once on socket updates only.
+    pub fn enr_readvertise(&mut self, readvertise: Option<(usize, Duration)>) -> &mut Self {
+        self.config.enr_readvertise = readvertise;
+        self
+    }
+
+    /// Retains up to `capacity` recently seen ENRs outside of the routing table.
+    pub fn enr_store_capacity(&mut self, capacity: Option<usize>) -> &mut Self {
+        self.config.enr_store_capacity = capacity;
+        self
+    }
+
+    /// The time an ENR remains in the ENR store after it was last seen.
+    pub fn enr_store_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.config.enr_store_ttl = ttl;
+        self
+    }
+
     /// Disables reporting of discovered peers through the event stream.
     pub fn disable_report_discovered_peers(&mut self) -> &mut Self {
         self.config.report_discovered_peers = false;
         self
     }
 
+    /// Remembers the reported discovered ENRs for up to `ttl`, so that each is only reported
+    /// again once updated. Set to `None` to report every discovered ENR.
+    pub fn discovered_peers_dedup(&mut self, dedup: Option<(usize, Duration)>) -> &mut Self {
+        self.config.discovered_peers_dedup = dedup;
+        self
+    }
+
+    /// Keeps discovered ENRs that can't be contacted, reporting their reachability.
+    pub fn report_unreachable_enrs(&mut self, report: bool) -> &mut Self {
+        self.config.report_unreachable_enrs = report;
+        self
+    }
+
+    /// The number of events buffered by the event stream.
+    pub fn event_stream_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.event_stream_capacity = Some(capacity);
+        self
+    }
+
+    /// The number of decoded packets buffered between the socket and the handler.
+    pub fn inbound_queue_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.inbound_queue_capacity = capacity;
+        self
+    }
+
+    /// The number of messages buffered from the service to the handler.
+    pub fn handler_queue_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.handler_queue_capacity = capacity;
+        self
+    }
+
+    /// The number of threads processing handshakes. Set to 0 to process them in the handler task.
+    pub fn handshake_workers(&mut self, workers: usize) -> &mut Self {
+        self.config.handshake_workers = workers;
+        self
+    }
+
+    /// Limits the number of sessions being established at once, queueing the further requests by
+    /// priority.
+    pub fn max_session_establishments(&mut self, max: Option<usize>) -> &mut Self {
+        self.config.max_session_establishments = max;
+        self
+    }
+
+    /// Enables the eclipse attack heuristics.
+    pub fn eclipse_detection(&mut self, config: Option<EclipseDetectionConfig>) -> &mut Self {
+        self.config.eclipse_detection = config;
+        self
+    }
+
+    /// Enables the exchange of ban reports with trusted peers.
+    pub fn ban_intel(&mut self, config: Option<BanIntelConfig>) -> &mut Self {
+        self.config.ban_intel = config;
+        self
+    }
+
+    /// Verifies new external addresses by having peers dial back before advertising them.
+    pub fn dial_back(&mut self, config: Option<DialBackConfig>) -> &mut Self {
+        self.config.dial_back = config;
+        self
+    }
+
+    /// Re-establishes expired sessions to deliver the responses of the TALK protocol.
+    pub fn reestablish_session_for_talk_responses(
+        &mut self,
+        protocol: impl Into<Vec<u8>>,
+    ) -> &mut Self {
+        self.config
+            .reestablish_session_for_talk_responses
+            .insert(protocol.into());
+        self
+    }
+
+    /// Enables the advertisement of topics.
+    pub fn topics(&mut self, config: Option<TopicConfig>) -> &mut Self {
+        self.config.topics = config;
+        self
+    }
+
     /// A rate limiter for limiting inbound requests.
     pub fn filter_rate_limiter(&mut self, rate_limiter: Option<RateLimiter>) -> &mut Self {
         self.config.filter_rate_limiter = rate_limiter;
@@ -301,6 +871,38 @@ impl ConfigBuilder {
         self
     }
 
+    /// Limits the NODES and TALKRESP bytes sent to any single peer to `max_bytes` per `interval`.
+    /// Set to `None` to disable the limit.
+    pub fn outbound_response_limit(&mut self, limit: Option<(u64, Duration)>) -> &mut Self {
+        self.config.outbound_response_limit = limit;
+        self
+    }
+
+    /// Limits the WHOAREYOU challenges sent to any single IP to `max_challenges` per `interval`.
+    /// Set to `None` to disable the limit.
+    pub fn challenge_rate_limit(&mut self, limit: Option<(u64, Duration)>) -> &mut Self {
+        self.config.challenge_rate_limit = limit;
+        self
+    }
+
+    /// The maximum number of WHOAREYOU challenges awaiting a handshake.
+    pub fn challenge_cache_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.challenge_cache_capacity = capacity;
+        self
+    }
+
+    /// The time a WHOAREYOU challenge awaits a handshake, if not the request timeout.
+    pub fn challenge_ttl(&mut self, ttl: Option<Duration>) -> &mut Self {
+        self.config.challenge_ttl = ttl;
+        self
+    }
+
+    /// Opts in to relaying handshake initiations between peers behind NATs.
+    pub fn enable_relay(&mut self) -> &mut Self {
+        self.config.enable_relay = true;
+        self
+    }
+
     /// A set of lists that permit or ban IP's or NodeIds from the server. See
     /// `crate::PermitBanList`.
     pub fn permit_ban_list(&mut self, list: PermitBanList) -> &mut Self {
@@ -308,14 +910,25 @@ impl ConfigBuilder {
         self
     }
 
-    /// Set the default duration for which nodes are banned for. This timeouts are checked every 5 minutes,
-    /// so the precision will be to the nearest 5 minutes. If set to `None`, bans from the filter
-    /// will last indefinitely. Default is 1 hour.
+    /// Set the default duration for which nodes are banned for. If set to `None`, bans from the
+    /// filter will last indefinitely. Default is 1 hour.
     pub fn ban_duration(&mut self, ban_duration: Option<Duration>) -> &mut Self {
         self.config.ban_duration = ban_duration;
         self
     }
 
+    /// Sets the interval at which unused sessions are demoted and limiters pruned.
+    pub fn maintenance_interval(&mut self, interval: Duration) -> &mut Self {
+        self.config.maintenance_interval = interval;
+        self
+    }
+
+    /// Sets the interval at which unused sessions are demoted and limiters pruned.
+    #[deprecated(note = "Bans are lifted as they expire. Use `maintenance_interval` instead.")]
+    pub fn ban_check_interval(&mut self, interval: Duration) -> &mut Self {
+        self.maintenance_interval(interval)
+    }
+
     /// Auto-discovering our IP address, is only one part in discovering our NAT/firewall
     /// situation. We need to determine if we are behind a firewall that is preventing incoming
     /// connections (this is especially true for IPv6 where all connections will report the same
@@ -346,6 +959,32 @@ impl ConfigBuilder {
         self
     }
 
+    /// Listens on transports created by the given factory rather than on UDP sockets. The
+    /// addresses of the `ListenConfig` are passed to the factory.
+    pub fn transport_factory(&mut self, transport_factory: Arc<dyn TransportFactory>) -> &mut Self {
+        self.config.transport_factory = Some(transport_factory);
+        self
+    }
+
+    /// Sets the clock session, query, ban and IP vote expiries are measured against.
+    pub fn clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.config.clock = clock;
+        self
+    }
+
+    /// Sets the policy for contacting peers that advertise both an IPv4 and an IPv6 address, when
+    /// listening on both.
+    pub fn dial_policy(&mut self, policy: DialPolicy) -> &mut Self {
+        self.config.dial_policy = policy;
+        self
+    }
+
+    /// Disables IPv4 entirely. The listen config must not include an IPv4 socket.
+    pub fn ipv6_only(&mut self) -> &mut Self {
+        self.config.ipv6_only = true;
+        self
+    }
+
     pub fn build(&mut self) -> Config {
         // If an executor is not provided, assume a current tokio runtime is running.
         if self.config.executor.is_none() {
@@ -353,6 +992,66 @@ impl ConfigBuilder {
         };
 
         assert!(self.config.incoming_bucket_limit <= MAX_NODES_PER_BUCKET);
+        if let Some(interval) = self.config.nat_keepalive_interval {
+            assert!(!interval.is_zero());
+        }
+        if let Some(interval) = self.config.self_lookup_interval {
+            assert!(!interval.is_zero());
+        }
+        if let Some((fanout, interval)) = self.config.enr_readvertise {
+            assert!(fanout > 0 && !interval.is_zero());
+        }
+        if let Some((max_bytes, interval)) = self.config.outbound_response_limit {
+            assert!(max_bytes > 0 && !interval.is_zero());
+        }
+        if let Some((max_challenges, interval)) = self.config.challenge_rate_limit {
+            assert!(max_challenges > 0 && !interval.is_zero());
+        }
+        assert!(self.config.challenge_cache_capacity > 0);
+        if let Some(ttl) = self.config.challenge_ttl {
+            assert!(!ttl.is_zero());
+        }
+        assert!(!self.config.peer_store_checkpoint_interval.is_zero());
+        if let Some(limit) = self.config.query_peer_limit {
+            assert!(limit >= MAX_NODES_PER_BUCKET);
+        }
+        if let Some(capacity) = self.config.event_stream_capacity {
+            assert!(capacity > 0);
+        }
+        assert!(self.config.session_cache_capacity > 0);
+        assert!((0.0..1.0).contains(&self.config.session_reserved_fraction));
+        assert!(self.config.inbound_queue_capacity > 0);
+        assert!(self.config.handler_queue_capacity > 0);
+        assert!(!self.config.maintenance_interval.is_zero());
+        assert!(self.config.max_session_establishments != Some(0));
+        assert!(self.config.max_successive_failures != Some(0));
+        if let Some((capacity, ttl)) = self.config.nodes_response_cache {
+            assert!(capacity > 0 && !ttl.is_zero());
+        }
+        if let Some(eclipse_detection) = &self.config.eclipse_detection {
+            assert!(!eclipse_detection.window.is_zero());
+            assert!(eclipse_detection.close_buckets > 0);
+        }
+        if let Some(ban_intel) = &self.config.ban_intel {
+            assert!(ban_intel.attenuation > 0.0);
+            assert!(!ban_intel.share_interval.is_zero());
+        }
+        if let Some(dial_back) = &self.config.dial_back {
+            assert!(dial_back.confirmations > 0);
+            assert!(dial_back.helpers >= dial_back.confirmations);
+        }
+        if let Some(topics) = &self.config.topics {
+            assert!(!topics.ad_lifetime.is_zero());
+            assert!(topics.table_capacity > 0);
+            assert!(topics.ads_per_topic > 0);
+            assert!(topics.ads_per_ip > 0);
+            assert!(topics.registrars > 0);
+            assert!(topics.registrations_per_refresh > 0);
+        }
+        assert!(
+            !self.config.ipv6_only || self.config.listen_config.socket_addrs().0.is_none(),
+            "An IPv6-only node cannot listen on IPv4"
+        );
 
         self.config.clone()
     }
@@ -369,17 +1068,74 @@ impl std::fmt::Debug for Config {
             .field("request_retries", &self.request_retries)
             .field("session_timeout", &self.session_timeout)
             .field("session_cache_capacity", &self.session_cache_capacity)
-            .field("enr_update", &self.enr_update)
+            .field(
+                "idle_session_cache_capacity",
+                &self.idle_session_cache_capacity,
+            )
+            .field("session_reserved_fraction", &self.session_reserved_fraction)
+            .field("session_idle_timeout", &self.session_idle_timeout)
+            .field("session_resumption", &self.session_resumption)
+            .field("external_address_policy", &self.external_address_policy)
+            .field("weighted_ip_votes", &self.weighted_ip_votes)
+            .field("ip_vote_min_subnets", &self.ip_vote_min_subnets)
+            .field("advertised_udp4_port", &self.advertised_udp4_port)
+            .field("advertised_udp6_port", &self.advertised_udp6_port)
+            .field("nodes_selection", &self.nodes_selection)
+            .field("nodes_response_cache", &self.nodes_response_cache)
+            .field("bootnode_mode", &self.bootnode_mode)
             .field("query_parallelism", &self.query_parallelism)
+            .field("query_seeding", &self.query_seeding)
             .field("report_discovered_peers", &self.report_discovered_peers)
+            .field("report_unreachable_enrs", &self.report_unreachable_enrs)
+            .field("discovered_peers_dedup", &self.discovered_peers_dedup)
+            .field("event_stream_capacity", &self.event_stream_capacity)
+            .field("inbound_queue_capacity", &self.inbound_queue_capacity)
+            .field("handler_queue_capacity", &self.handler_queue_capacity)
+            .field("handshake_workers", &self.handshake_workers)
+            .field(
+                "max_session_establishments",
+                &self.max_session_establishments,
+            )
+            .field("eclipse_detection", &self.eclipse_detection)
+            .field("ban_intel", &self.ban_intel)
+            .field("dial_back", &self.dial_back)
+            .field(
+                "reestablish_session_for_talk_responses",
+                &self.reestablish_session_for_talk_responses,
+            )
+            .field("topics", &self.topics)
+            .field("query_peer_limit", &self.query_peer_limit)
             .field("ip_limit", &self.ip_limit)
             .field("filter_max_nodes_per_ip", &self.filter_max_nodes_per_ip)
             .field("filter_max_bans_per_ip", &self.filter_max_bans_per_ip)
+            .field("outbound_response_limit", &self.outbound_response_limit)
+            .field("challenge_rate_limit", &self.challenge_rate_limit)
+            .field("challenge_cache_capacity", &self.challenge_cache_capacity)
+            .field("challenge_ttl", &self.challenge_ttl)
+            .field("enable_relay", &self.enable_relay)
             .field("ip_limit", &self.ip_limit)
             .field("incoming_bucket_limit", &self.incoming_bucket_limit)
+            .field("eviction_policy", &self.eviction_policy)
+            .field("enr_allowlist", &self.enr_allowlist)
             .field("ping_interval", &self.ping_interval)
+            .field("max_successive_failures", &self.max_successive_failures)
+            .field("peer_store", &self.peer_store.is_some())
+            .field(
+                "peer_store_checkpoint_interval",
+                &self.peer_store_checkpoint_interval,
+            )
+            .field("nat_keepalive_interval", &self.nat_keepalive_interval)
+            .field("self_lookup_interval", &self.self_lookup_interval)
+            .field("enr_readvertise", &self.enr_readvertise)
+            .field("enr_store_capacity", &self.enr_store_capacity)
+            .field("enr_store_ttl", &self.enr_store_ttl)
             .field("ban_duration", &self.ban_duration)
+            .field("maintenance_interval", &self.maintenance_interval)
             .field("listen_config", &self.listen_config)
+            .field("transport_factory", &self.transport_factory.is_some())
+            .field("clock", &self.clock)
+            .field("dial_policy", &self.dial_policy)
+            .field("ipv6_only", &self.ipv6_only)
             .finish()
     }
 }
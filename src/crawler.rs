@@ -0,0 +1,169 @@
+//! Crawling of the whole DHT, for network measurement.
+//!
+//! A crawl starts from the routing table and asks every node it learns of for the contents of its
+//! furthest buckets, which together cover the keyspace. The ENRs found are deduplicated, keeping
+//! the most recent record of each node, and every node contacted is recorded as reachable or not.
+//! See [`crate::Discv5::crawl`].
+use crate::{
+    time::{self, Instant},
+    Enr,
+};
+use enr::NodeId;
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    time::Duration,
+};
+
+/// Configures a crawl.
+#[derive(Debug, Clone)]
+pub struct CrawlConfig {
+    /// The maximum number of nodes contacted. Nodes discovered beyond this limit are still
+    /// reported, as not contacted. Default: 10,000.
+    pub max_peers: usize,
+    /// The number of the furthest buckets requested from each node, one request per bucket.
+    /// Buckets closer than about log2 of the network size are almost always empty. Default: 16.
+    pub buckets_per_peer: u64,
+    /// The number of nodes crawled concurrently. Default: 16.
+    pub concurrency: usize,
+}
+
+impl Default for CrawlConfig {
+    fn default() -> Self {
+        CrawlConfig {
+            max_peers: 10_000,
+            buckets_per_peer: 16,
+            concurrency: 16,
+        }
+    }
+}
+
+/// Whether a node responded during a crawl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reachability {
+    /// The node answered our requests.
+    Reachable,
+    /// The node did not answer our first request.
+    Unreachable,
+    /// The node was not contacted, because the crawl reached `max_peers`.
+    NotContacted,
+}
+
+/// A node found by a crawl.
+#[derive(Debug, Clone)]
+pub struct CrawledNode {
+    /// The most recent ENR of the node seen during the crawl.
+    pub enr: Enr,
+    pub reachability: Reachability,
+}
+
+/// The result of a crawl.
+#[derive(Debug, Clone)]
+pub struct CrawlSnapshot {
+    /// Every node found, each once.
+    pub nodes: Vec<CrawledNode>,
+    /// How long the crawl took.
+    pub duration: Duration,
+}
+
+impl CrawlSnapshot {
+    /// The number of nodes with the given reachability.
+    pub fn count(&self, reachability: Reachability) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| node.reachability == reachability)
+            .count()
+    }
+
+    /// The share of the contacted nodes that were reachable, or `None` if none were contacted.
+    pub fn reachable_ratio(&self) -> Option<f64> {
+        let reachable = self.count(Reachability::Reachable);
+        let contacted = reachable + self.count(Reachability::Unreachable);
+        (contacted > 0).then(|| reachable as f64 / contacted as f64)
+    }
+
+    /// Looks up a node of the snapshot.
+    pub fn get(&self, node_id: &NodeId) -> Option<&CrawledNode> {
+        self.nodes
+            .iter()
+            .find(|node| node.enr.node_id() == *node_id)
+    }
+}
+
+/// The distances requested from each node, furthest first.
+pub(crate) fn crawl_distances(config: &CrawlConfig) -> impl Iterator<Item = u64> {
+    let furthest = 256;
+    (furthest + 1 - config.buckets_per_peer.clamp(1, furthest)..=furthest).rev()
+}
+
+/// Tracks the progress of a crawl.
+pub(crate) struct Crawl {
+    started: Instant,
+    /// The nodes found so far, by id.
+    nodes: HashMap<NodeId, CrawledNode>,
+    /// The nodes yet to be contacted, in the order they were found.
+    queue: VecDeque<NodeId>,
+    contacted: usize,
+    max_peers: usize,
+}
+
+impl Crawl {
+    pub(crate) fn new(seeds: Vec<Enr>, max_peers: usize) -> Self {
+        let mut crawl = Crawl {
+            started: time::now(),
+            nodes: Default::default(),
+            queue: Default::default(),
+            contacted: 0,
+            max_peers,
+        };
+        for enr in seeds {
+            crawl.found(enr);
+        }
+        crawl
+    }
+
+    /// Records a discovered ENR, queueing the node if it is new.
+    pub(crate) fn found(&mut self, enr: Enr) {
+        match self.nodes.entry(enr.node_id()) {
+            Entry::Occupied(mut entry) => {
+                if entry.get().enr.seq() < enr.seq() {
+                    entry.get_mut().enr = enr;
+                }
+            }
+            Entry::Vacant(entry) => {
+                self.queue.push_back(enr.node_id());
+                entry.insert(CrawledNode {
+                    enr,
+                    reachability: Reachability::NotContacted,
+                });
+            }
+        }
+    }
+
+    /// The next node to contact, if any and the crawl hasn't reached its limit.
+    pub(crate) fn next_peer(&mut self) -> Option<Enr> {
+        if self.contacted >= self.max_peers {
+            return None;
+        }
+        let node_id = self.queue.pop_front()?;
+        self.contacted += 1;
+        self.nodes.get(&node_id).map(|node| node.enr.clone())
+    }
+
+    /// Records whether a contacted node responded.
+    pub(crate) fn contacted(&mut self, node_id: &NodeId, reachable: bool) {
+        if let Some(node) = self.nodes.get_mut(node_id) {
+            node.reachability = if reachable {
+                Reachability::Reachable
+            } else {
+                Reachability::Unreachable
+            };
+        }
+    }
+
+    pub(crate) fn finish(self) -> CrawlSnapshot {
+        CrawlSnapshot {
+            nodes: self.nodes.into_values().collect(),
+            duration: self.started.elapsed(),
+        }
+    }
+}
@@ -13,34 +13,65 @@
 //! The server can be shutdown using the [`Discv5::shutdown`] function.
 
 use crate::{
+    audit::{BanReason, SecurityEvent, SecurityEventKind, AUDIT_LOG},
+    bootnodes::{BootnodeHealth, Bootnodes},
+    crawler::{crawl_distances, Crawl, CrawlConfig, CrawlSnapshot},
+    enr_ext,
+    enr_store::EnrStore,
+    enr_watch::{EnrKeyChange, EnrWatch},
     error::{Error, QueryError, RequestError},
+    handler::OutgoingRequest,
+    ipmode::{is_ipv4_or_mapped, to_ipv4_mapped},
     kbucket::{
         self, ConnectionDirection, ConnectionState, FailureReason, InsertResult, KBucketsTable,
         NodeStatus, UpdateResult,
     },
+    key_store::EncryptedKeyFile,
+    network_size::{self, NetworkSizeEstimate, NETWORK_SIZE_LOOKUPS},
     node_info::NodeContact,
     packet::ProtocolIdentity,
-    service::{QueryKind, Service, ServiceRequest, TalkRequest},
-    Config, DefaultProtocolId, Enr, IpMode,
+    peer_store::{PeerStoreSnapshot, StateSnapshot},
+    rendezvous,
+    rpc::TopicHash,
+    service::{
+        EclipseWarning, InjectionOutcome, NatStatus, PeerSource, QueryKind, QueryTrace, Service,
+        ServiceRequest, TalkRequest,
+    },
+    talk::Talk,
+    Config, DefaultProtocolId, Enr, EnrReachability, FilterStats, IpMode,
 };
-use enr::{CombinedKey, EnrKey, Error as EnrError, NodeId};
+use enr::{CombinedKey, EnrKey, EnrPublicKey, Error as EnrError, NodeId};
+use futures::{future, stream::FuturesUnordered, Stream, StreamExt};
 use parking_lot::RwLock;
 use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
     future::Future,
+    io,
     marker::PhantomData,
     net::SocketAddr,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tracing::{debug, warn};
 
+#[cfg(feature = "dns")]
+use crate::{
+    dns::{self, DnsTreeUrl},
+    error::DnsError,
+};
+#[cfg(feature = "dns")]
+use tokio::sync::watch;
+
 #[cfg(feature = "libp2p")]
 use multiaddr::Multiaddr;
 
+#[cfg(feature = "test_utils")]
+use crate::handler::ChaosHook;
+
 // Create lazy static variable for the global permit/ban list
 use crate::{
-    metrics::{Metrics, METRICS},
+    metrics::{ChurnStats, Metrics, METRICS},
     service::Pong,
 };
 
@@ -52,32 +83,114 @@ lazy_static! {
 pub(crate) mod test;
 
 /// Events that can be produced by the `Discv5` event stream.
+///
+/// ENRs are shared with the routing table rather than copied into each event. They dereference to
+/// [`Enr`], can be borrowed as one with [`Event::enr`], and an owned copy can be taken with
+/// `Enr::clone(&enr)`.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[non_exhaustive]
 pub enum Event {
     /// A node has been discovered from a FINDNODES request.
     ///
     /// The ENR of the node is returned. Various properties can be derived from the ENR.
     /// This happen spontaneously through queries as nodes return ENR's. These ENR's are not
-    /// guaranteed to be live or contactable.
-    Discovered(Enr),
+    /// guaranteed to be live or contactable. An ENR is only reported again once it has been
+    /// updated, unless [`Config::discovered_peers_dedup`] is disabled.
+    Discovered(Arc<Enr>),
+    /// A node that can't be contacted over the IP versions in use has been discovered, reported
+    /// instead of [`Event::Discovered`] if [`Config::report_unreachable_enrs`] is enabled.
+    DiscoveredUnreachable {
+        enr: Arc<Enr>,
+        reachability: EnrReachability,
+    },
     /// A new node has been added to the routing table.
     NodeInserted {
         node_id: NodeId,
         replaced: Option<NodeId>,
+        /// Whether the node connected to us or we to it.
+        direction: ConnectionDirection,
     },
     /// An ENR doesn't verify against the observed socket and node ID of the peer.
     UnverifiableEnr {
-        enr: Enr,
+        enr: Arc<Enr>,
         socket: SocketAddr,
         node_id: NodeId,
     },
     /// A new session has been established with a node.
-    SessionEstablished(Enr, SocketAddr),
+    SessionEstablished(Arc<Enr>, SocketAddr),
+    /// A new session has been established with a node, reported right after
+    /// [`Event::SessionEstablished`] with what peer managers track about the peer.
+    PeerConnected {
+        enr: Arc<Enr>,
+        /// The socket the session is held over.
+        socket: SocketAddr,
+        /// Whether the node initiated the session or we did.
+        direction: ConnectionDirection,
+        /// Whether the node was not in the routing table when the session was established.
+        new_to_table: bool,
+    },
     /// Our local ENR IP address has been updated.
     SocketUpdated(SocketAddr),
     /// A node has initiated a talk request.
     TalkRequest(TalkRequest),
+    /// The classification of our NAT has changed. See [`Discv5::nat_status`].
+    NatStatusChanged(NatStatus),
+    /// The routing table or our lookups show signs of an eclipse attack. If the table reset is
+    /// enabled, the nodes removed from the routing table in response are listed.
+    EclipseWarning {
+        warning: EclipseWarning,
+        removed: Vec<NodeId>,
+    },
+    /// The verification of a peer passed to [`Discv5::inject_peer`] has finished.
+    PeerInjected {
+        node_id: NodeId,
+        source: PeerSource,
+        outcome: InjectionOutcome,
+    },
+    /// A peer failed [`Config::max_successive_failures`] requests in a row. Its sessions have
+    /// been dropped and it has been marked disconnected in the routing table.
+    PeerUnresponsive { node_id: NodeId, failures: usize },
+}
+
+impl Event {
+    /// The ENR carried by the event, if any, borrowed from the shared ENR. This reads the ENR of
+    /// any event without matching on how it is shared.
+    pub fn enr(&self) -> Option<&Enr> {
+        match self {
+            Event::Discovered(enr)
+            | Event::DiscoveredUnreachable { enr, .. }
+            | Event::UnverifiableEnr { enr, .. }
+            | Event::SessionEstablished(enr, _)
+            | Event::PeerConnected { enr, .. } => Some(enr),
+            _ => None,
+        }
+    }
+}
+
+/// A peer given by its ENR, or by its node id to be looked up among the known ENRs.
+#[derive(Debug, Clone)]
+pub enum PeerRef {
+    NodeId(NodeId),
+    Enr(Arc<Enr>),
+}
+
+impl From<NodeId> for PeerRef {
+    fn from(node_id: NodeId) -> Self {
+        PeerRef::NodeId(node_id)
+    }
+}
+
+impl From<Enr> for PeerRef {
+    fn from(enr: Enr) -> Self {
+        PeerRef::Enr(Arc::new(enr))
+    }
+}
+
+impl From<Arc<Enr>> for PeerRef {
+    fn from(enr: Arc<Enr>) -> Self {
+        PeerRef::Enr(enr)
+    }
 }
 
 /// The main Discv5 Service struct. This provides the user-level API for performing queries and
@@ -91,21 +204,36 @@ where
     service_channel: Option<mpsc::Sender<ServiceRequest>>,
     /// The exit channel to shutdown the underlying service.
     service_exit: Option<oneshot::Sender<()>>,
+    /// Signalled on shutdown to stop the background tasks outside the service.
+    #[cfg(feature = "dns")]
+    background_exit: watch::Sender<()>,
     /// The routing table of the discv5 service.
-    kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+    kbuckets: Arc<KBucketsTable<NodeId, Arc<Enr>>>,
     /// The local ENR of the server.
     local_enr: Arc<RwLock<Enr>>,
     /// The key associated with the local ENR, required for updating the local ENR.
     enr_key: Arc<RwLock<CombinedKey>>,
+    /// The subscriptions to fields of the local ENR and of the ENRs of the routing table.
+    enr_watch: Arc<EnrWatch>,
     // Type of socket we are using
     ip_mode: IpMode,
+    /// The NAT classification maintained by the service.
+    nat_status: Arc<RwLock<NatStatus>>,
+    /// Peers the service pings to keep our NAT mappings open.
+    nat_keepalive_peers: Arc<RwLock<HashSet<NodeId>>>,
+    /// Recently seen ENRs, if enabled.
+    enr_store: Option<Arc<RwLock<EnrStore>>>,
+    /// The bootnodes, tracked separately from the regular peers.
+    bootnodes: Arc<RwLock<Bootnodes>>,
+    /// Application data saved alongside the routing table in the peer store.
+    peer_store_metadata: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
     /// Phantom for the protocol id.
     _phantom: PhantomData<P>,
 }
 
 impl<P: ProtocolIdentity> Discv5<P> {
     pub fn new(
-        local_enr: Enr,
+        mut local_enr: Enr,
         enr_key: CombinedKey,
         mut config: Config,
     ) -> Result<Self, &'static str> {
@@ -114,6 +242,37 @@ impl<P: ProtocolIdentity> Discv5<P> {
             return Err("Provided keypair does not match the provided ENR");
         }
 
+        // An IPv6-only node advertises no IPv4 address.
+        if config.ipv6_only {
+            let mut ipv4_keys = vec!["ip", "udp", "tcp"];
+            if local_enr
+                .ip6()
+                .is_some_and(|ip| to_ipv4_mapped(&ip).is_some())
+            {
+                ipv4_keys.extend(["ip6", "udp6", "tcp6"]);
+            }
+            ipv4_keys.retain(|key| local_enr.get_raw_rlp(key).is_some());
+            if !ipv4_keys.is_empty() {
+                warn!(keys = ?ipv4_keys, "Removing IPv4 fields from the ENR of an IPv6-only node");
+                local_enr
+                    .remove_insert(
+                        ipv4_keys.into_iter(),
+                        std::iter::empty::<(&str, &[u8])>(),
+                        &enr_key,
+                    )
+                    .map_err(|_| "Failed to remove the IPv4 fields of the ENR")?;
+            }
+        }
+
+        // Peers only resume sessions with nodes advertising the capability.
+        if config.session_resumption.is_some()
+            && local_enr.get_raw_rlp(enr_ext::RESUMPTION_ENR_KEY).is_none()
+        {
+            local_enr
+                .insert(enr_ext::RESUMPTION_ENR_KEY, &1u8, &enr_key)
+                .map_err(|_| "Failed to advertise session resumption in the ENR")?;
+        }
+
         // If an executor is not provided, assume a current tokio runtime is running. If not panic.
         if config.executor.is_none() {
             config.executor = Some(Box::<crate::executor::TokioExecutor>::default());
@@ -124,8 +283,8 @@ impl<P: ProtocolIdentity> Discv5<P> {
         // may expose this functionality to the users if there is demand for it.
         let (table_filter, bucket_filter) = if config.ip_limit {
             (
-                Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Enr>>),
-                Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Enr>>),
+                Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Arc<Enr>>>),
+                Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Arc<Enr>>>),
             )
         } else {
             (None, None)
@@ -133,31 +292,69 @@ impl<P: ProtocolIdentity> Discv5<P> {
 
         let local_enr = Arc::new(RwLock::new(local_enr));
         let enr_key = Arc::new(RwLock::new(enr_key));
-        let kbuckets = Arc::new(RwLock::new(KBucketsTable::new(
+        let kbuckets = KBucketsTable::new(
             local_enr.read().node_id().into(),
             Duration::from_secs(60),
             config.incoming_bucket_limit,
             table_filter,
             bucket_filter,
-        )));
+        );
+        kbuckets.set_eviction_policy(config.eviction_policy);
+        let kbuckets = Arc::new(kbuckets);
 
         // Update the PermitBan list based on initial configuration
-        *PERMIT_BAN_LIST.write() = config.permit_ban_list.clone();
+        {
+            let mut permit_ban_list = PERMIT_BAN_LIST.write();
+            *permit_ban_list = config.permit_ban_list.clone();
+            // The expiries of the bans of the list come along with it, but may be due before
+            // those the handlers are waiting on.
+            crate::PermitBanList::notify_expiries();
+        }
 
         let ip_mode = IpMode::new_from_listen_config(&config.listen_config);
 
+        let enr_store = config
+            .enr_store_capacity
+            .map(|capacity| Arc::new(RwLock::new(EnrStore::new(capacity, config.enr_store_ttl))));
+        let bootnodes = Arc::new(RwLock::new(Bootnodes::new(config.clock.clone())));
+
         Ok(Discv5 {
             config,
             service_channel: None,
             service_exit: None,
+            #[cfg(feature = "dns")]
+            background_exit: watch::channel(()).0,
             kbuckets,
             local_enr,
             enr_key,
+            enr_watch: Default::default(),
             ip_mode,
+            nat_status: Default::default(),
+            nat_keepalive_peers: Default::default(),
+            enr_store,
+            bootnodes,
+            peer_store_metadata: Default::default(),
             _phantom: Default::default(),
         })
     }
 
+    /// Creates the server with the node key held by the encrypted key file, generating and
+    /// storing a new key if the file does not exist. The local ENR is built from `enr_builder` and
+    /// signed with the key. Fails with [`io::ErrorKind::InvalidData`] if the passphrase is wrong.
+    pub fn from_key_file(
+        key_file: &EncryptedKeyFile,
+        passphrase: &[u8],
+        mut enr_builder: enr::Builder<CombinedKey>,
+        config: Config,
+    ) -> io::Result<Self> {
+        let enr_key = key_file.load_or_generate(passphrase)?;
+        let local_enr = enr_builder
+            .build(&enr_key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Self::new(local_enr, enr_key, config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
     /// Starts the required tasks and begins listening on a given UDP SocketAddr.
     pub async fn start(&mut self) -> Result<(), Error> {
         if self.service_channel.is_some() {
@@ -165,11 +362,29 @@ impl<P: ProtocolIdentity> Discv5<P> {
             return Err(Error::ServiceAlreadyStarted);
         }
 
+        // repopulate the routing table and the ban list from the peer store
+        if let Some(peer_store) = self.config.peer_store.as_ref() {
+            match peer_store.load() {
+                Ok(snapshot) => {
+                    let total = snapshot.enrs.len();
+                    let inserted = self.restore_peers(snapshot);
+                    debug!(inserted, total, "Loaded ENRs from the peer store");
+                }
+                Err(error) => warn!(%error, "Failed to load the peer store"),
+            }
+        }
+
         // create the main service
         let (service_exit, service_channel) = Service::spawn::<P>(
             self.local_enr.clone(),
             self.enr_key.clone(),
+            self.enr_watch.clone(),
             self.kbuckets.clone(),
+            self.nat_status.clone(),
+            self.nat_keepalive_peers.clone(),
+            self.enr_store.clone(),
+            self.bootnodes.clone(),
+            self.peer_store_metadata.clone(),
             self.config.clone(),
         )
         .await?;
@@ -180,6 +395,8 @@ impl<P: ProtocolIdentity> Discv5<P> {
 
     /// Terminates the service.
     pub fn shutdown(&mut self) {
+        #[cfg(feature = "dns")]
+        self.background_exit.send_replace(());
         if let Some(exit) = self.service_exit.take() {
             if exit.send(()).is_err() {
                 debug!("Discv5 service already shutdown");
@@ -198,39 +415,71 @@ impl<P: ProtocolIdentity> Discv5<P> {
     /// operations involving one of these peers, without having to dial
     /// them upfront.
     pub fn add_enr(&self, enr: Enr) -> Result<(), &'static str> {
-        // only add ENR's that have a valid udp socket.
-        if self.ip_mode.get_contactable_addr(&enr).is_none() {
-            warn!("ENR attempted to be added without an UDP socket compatible with configured IpMode has been ignored.");
-            return Err("ENR has no compatible UDP socket to connect to");
-        }
+        insert_enr(&self.kbuckets, self.ip_mode, &self.config, enr)
+    }
 
-        if !(self.config.table_filter)(&enr) {
-            warn!("ENR attempted to be added which is banned by the configuration table filter.");
-            return Err("ENR banned by table filter");
-        }
+    /// Resolves the EIP-1459 DNS node list at `url` and adds its ENRs to the routing table, as
+    /// with [`Discv5::add_enr`]. The tree is re-resolved every `resync_interval` and ENRs from
+    /// newer versions of the tree are added. This runs in the background until the `Discv5`
+    /// instance is shut down or dropped.
+    #[cfg(feature = "dns")]
+    pub fn add_dns_tree(&self, url: &str, resync_interval: Duration) -> Result<(), DnsError> {
+        let url: DnsTreeUrl = url.parse()?;
+        let resolver = hickory_resolver::TokioAsyncResolver::tokio_from_system_conf()
+            .map_err(|e| DnsError::Resolver(e.to_string()))?;
+        let kbuckets = Arc::downgrade(&self.kbuckets);
+        let ip_mode = self.ip_mode;
+        let config = self.config.clone();
+        let exit = self.background_exit.subscribe();
+
+        self.config
+            .executor
+            .clone()
+            .expect("Executor must be present")
+            .spawn(Box::pin(async move {
+                let lookup = |name| dns::txt_lookup(&resolver, name);
+                let domain = url.domain().to_string();
+                dns::sync_tree(&url, lookup, resync_interval, exit, |enrs| {
+                    // The routing table is gone once the instance is dropped.
+                    let Some(kbuckets) = kbuckets.upgrade() else {
+                        return false;
+                    };
+                    for enr in enrs {
+                        if let Err(error) = insert_enr(&kbuckets, ip_mode, &config, enr) {
+                            debug!(domain, error, "ENR from DNS tree not added");
+                        }
+                    }
+                    true
+                })
+                .await;
+            }));
+        Ok(())
+    }
 
-        let key = kbucket::Key::from(enr.node_id());
+    /// Adds a bootnode. Bootnodes are added to the routing table like any other ENR, but are
+    /// also retried with backoff whenever we have no connected peers, and the packet filter of
+    /// this instance lets them through even if they are banned.
+    pub fn add_bootnode(&self, enr: Enr) -> Result<(), &'static str> {
+        self.add_enr(enr.clone())?;
+        self.bootnodes.write().insert(enr);
+        Ok(())
+    }
 
-        match self.kbuckets.write().insert_or_update(
-            &key,
-            enr,
-            NodeStatus {
-                state: ConnectionState::Disconnected,
-                direction: ConnectionDirection::Incoming,
-            },
-        ) {
-            InsertResult::Inserted
-            | InsertResult::Pending { .. }
-            | InsertResult::StatusUpdated { .. }
-            | InsertResult::ValueUpdated
-            | InsertResult::Updated { .. }
-            | InsertResult::UpdatedPending => Ok(()),
-            InsertResult::Failed(FailureReason::BucketFull) => Err("Table full"),
-            InsertResult::Failed(FailureReason::BucketFilter) => Err("Failed bucket filter"),
-            InsertResult::Failed(FailureReason::TableFilter) => Err("Failed table filter"),
-            InsertResult::Failed(FailureReason::InvalidSelfUpdate) => Err("Invalid self update"),
-            InsertResult::Failed(_) => Err("Failed to insert ENR"),
-        }
+    /// Stops treating the node as a bootnode. The node remains in the routing table as a regular
+    /// peer. Returns `true` if the node was a bootnode.
+    pub fn remove_bootnode(&self, node_id: &NodeId) -> bool {
+        self.bootnodes.write().remove(node_id)
+    }
+
+    /// Reports the health of the bootnodes.
+    pub fn bootnodes(&self) -> Vec<BootnodeHealth> {
+        let kbuckets = &self.kbuckets;
+        self.bootnodes.read().health(|node_id| {
+            matches!(
+                kbuckets.entry(&kbucket::Key::from(*node_id)),
+                kbucket::Entry::Present(_, status) if status.is_connected()
+            )
+        })
     }
 
     /// Removes a `node_id` from the routing table.
@@ -239,7 +488,7 @@ impl<P: ProtocolIdentity> Discv5<P> {
     /// table. Returns `true` if the node was in the table and `false` otherwise.
     pub fn remove_node(&self, node_id: &NodeId) -> bool {
         let key = &kbucket::Key::from(*node_id);
-        self.kbuckets.write().remove(key)
+        self.kbuckets.remove(key)
     }
 
     /// Returns a vector of closest nodes by the given distances.
@@ -255,11 +504,11 @@ impl<P: ProtocolIdentity> Discv5<P> {
         }
 
         if !distances.is_empty() {
-            let mut kbuckets = self.kbuckets.write();
+            let kbuckets = &self.kbuckets;
             for node in kbuckets
                 .nodes_by_distances(distances.as_slice(), self.config.max_nodes_response)
                 .into_iter()
-                .map(|entry| entry.node.value.clone())
+                .map(|entry| Enr::clone(&entry.node.value))
             {
                 nodes_to_send.push(node);
             }
@@ -280,7 +529,6 @@ impl<P: ProtocolIdentity> Discv5<P> {
         let key = &kbucket::Key::from(*node_id);
         !matches!(
             self.kbuckets
-                .write()
                 .update_node_status(key, ConnectionState::Disconnected, None),
             UpdateResult::Failed(_)
         )
@@ -289,7 +537,6 @@ impl<P: ProtocolIdentity> Discv5<P> {
     /// Returns the number of connected peers that exist in the routing table.
     pub fn connected_peers(&self) -> usize {
         self.kbuckets
-            .write()
             .iter()
             .filter(|entry| entry.status.is_connected())
             .count()
@@ -300,6 +547,54 @@ impl<P: ProtocolIdentity> Discv5<P> {
         Metrics::from(&METRICS)
     }
 
+    /// Returns the churn of the routing table, with the insertions, evictions and average peer
+    /// lifetime of each bucket, and the share of lookups that ran into unresponsive peers.
+    pub fn churn_stats(&self) -> ChurnStats {
+        let buckets = self.kbuckets.churn();
+        let mut total = kbucket::BucketChurn::default();
+        for (_, churn) in &buckets {
+            total += *churn;
+        }
+        ChurnStats {
+            buckets,
+            total,
+            lookups: METRICS.lookups.load(Ordering::Relaxed),
+            lookups_with_dead_peers: METRICS.lookups_with_dead_peers.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Subscribes to the security events of the process, such as bans and forged handshakes. See
+    /// [`crate::audit`].
+    pub fn security_events(&self) -> broadcast::Receiver<SecurityEvent> {
+        AUDIT_LOG.subscribe()
+    }
+
+    /// Subscribes to the changes of a field of the local ENR and of the ENRs of the routing
+    /// table, such as the `eth2` fork id. A change is reported when an updated record sets,
+    /// changes or removes the field. The key is watched until the receiver is dropped. See
+    /// [`crate::EnrKeyChange`].
+    pub fn watch_enr_key(&self, key: impl Into<String>) -> broadcast::Receiver<EnrKeyChange> {
+        self.enr_watch.watch(key.into())
+    }
+
+    /// Returns the NAT classification for each IP version, derived from the external sockets
+    /// our peers report for us. This is only updated under the `ExternalAddressPolicy::Votes` policy.
+    pub fn nat_status(&self) -> NatStatus {
+        *self.nat_status.read()
+    }
+
+    /// Registers a peer to be pinged every `nat_keepalive_interval`, keeping the NAT mapping
+    /// towards it open. This is useful for relays and other peers we must remain reachable by.
+    /// Only peers in the routing table are pinged.
+    pub fn add_nat_keepalive_peer(&self, node_id: NodeId) {
+        self.nat_keepalive_peers.write().insert(node_id);
+    }
+
+    /// Stops sending NAT keepalive pings to a peer.
+    pub fn remove_nat_keepalive_peer(&self, node_id: &NodeId) {
+        self.nat_keepalive_peers.write().remove(node_id);
+    }
+
     /// Exposes the raw reference to the underlying internal metrics.
     pub fn raw_metrics() -> &'static METRICS {
         &METRICS
@@ -317,26 +612,107 @@ impl<P: ProtocolIdentity> Discv5<P> {
         self.local_enr.clone()
     }
 
+    /// Returns the application data stored under `key` in the peer store.
+    pub fn peer_store_metadata(&self, key: &str) -> Option<Vec<u8>> {
+        self.peer_store_metadata.read().get(key).cloned()
+    }
+
+    /// Stores application data under `key` in the peer store. It is persisted at the next
+    /// checkpoint.
+    pub fn set_peer_store_metadata(&self, key: impl Into<String>, value: Vec<u8>) {
+        self.peer_store_metadata.write().insert(key.into(), value);
+    }
+
+    /// Takes a snapshot of the routing table, the active bans, the application metadata, the ENR
+    /// store and the metrics, to be restored with [`Discv5::import_state`], possibly on another
+    /// host. The routing table is kept after shutdown, so that the final state can be exported
+    /// once the service has stopped.
+    pub fn export_state(&self) -> StateSnapshot {
+        let mut peers = PeerStoreSnapshot {
+            enrs: self.table_entries_enr(),
+            metadata: self.peer_store_metadata.read().clone(),
+            ..Default::default()
+        };
+        peers.set_bans(&PERMIT_BAN_LIST.read(), &*self.config.clock);
+        let stored_enrs = self
+            .enr_store
+            .as_ref()
+            .map(|enr_store| enr_store.read().filter(|_| true))
+            .unwrap_or_default();
+        StateSnapshot {
+            peers,
+            stored_enrs,
+            metrics: self.metrics(),
+        }
+    }
+
+    /// Restores a snapshot taken by [`Discv5::export_state`]. The ENRs are added to the routing
+    /// table and the ENR store, bans that have not expired are reinstated and the application
+    /// metadata is replaced. Returns the number of ENRs added to the routing table.
+    pub fn import_state(&self, state: StateSnapshot) -> usize {
+        if let Some(enr_store) = self.enr_store.as_ref() {
+            let mut enr_store = enr_store.write();
+            for enr in state.stored_enrs {
+                enr_store.insert(enr);
+            }
+        }
+        self.restore_peers(state.peers)
+    }
+
+    /// Adds the ENRs of a peer store snapshot to the routing table and restores its bans and
+    /// metadata. Returns the number of ENRs added.
+    fn restore_peers(&self, snapshot: PeerStoreSnapshot) -> usize {
+        let inserted = snapshot
+            .enrs
+            .iter()
+            .filter(|&enr| {
+                insert_enr(&self.kbuckets, self.ip_mode, &self.config, enr.clone()).is_ok()
+            })
+            .count();
+        // bans that expired while we were offline are dropped
+        snapshot.restore_bans(&mut PERMIT_BAN_LIST.write(), &*self.config.clock);
+        *self.peer_store_metadata.write() = snapshot.metadata;
+        inserted
+    }
+
     /// Returns the routing table of the discv5 service
-    pub fn kbuckets(&self) -> KBucketsTable<NodeId, Enr> {
-        self.kbuckets.read().clone()
+    pub fn kbuckets(&self) -> KBucketsTable<NodeId, Arc<Enr>> {
+        KBucketsTable::clone(&self.kbuckets)
     }
 
     /// Returns an ENR if one is known for the given NodeId.
     pub fn find_enr(&self, node_id: &NodeId) -> Option<Enr> {
         // check if we know this node id in our routing table
         let key = kbucket::Key::from(*node_id);
-        if let kbucket::Entry::Present(entry, _) = self.kbuckets.write().entry(&key) {
-            return Some(entry.value().clone());
+        if let kbucket::Entry::Present(entry, _) = self.kbuckets.entry(&key) {
+            return Some(Enr::clone(entry.value()));
         }
         None
     }
 
+    /// Returns the most recent ENR seen for the given NodeId, whether or not the node is in the
+    /// routing table. Requires the ENR store to be enabled via `enr_store_capacity`.
+    pub fn find_stored_enr(&self, node_id: &NodeId) -> Option<Enr> {
+        self.enr_store
+            .as_ref()
+            .and_then(|enr_store| enr_store.read().get(node_id).cloned())
+    }
+
+    /// Returns all recently seen ENRs that match the predicate. Requires the ENR store to be
+    /// enabled via `enr_store_capacity`.
+    pub fn stored_enrs(&self, predicate: impl Fn(&Enr) -> bool) -> Vec<Enr> {
+        self.enr_store
+            .as_ref()
+            .map(|enr_store| enr_store.read().filter(predicate))
+            .unwrap_or_default()
+    }
+
     /// Sends a PING request to a node.
     pub fn send_ping(
         &self,
-        enr: Enr,
+        enr: impl Into<Arc<Enr>>,
     ) -> impl Future<Output = Result<Pong, RequestError>> + 'static {
+        let enr = enr.into();
         let (callback_send, callback_recv) = oneshot::channel();
         let channel = self.clone_channel();
 
@@ -361,17 +737,23 @@ impl<P: ProtocolIdentity> Discv5<P> {
     /// and block all incoming packets from the node until the timeout specified. Setting the
     /// timeout to `None` creates a permanent ban.
     pub fn ban_node(&self, node_id: &NodeId, duration_of_ban: Option<Duration>) {
-        let time_to_unban = duration_of_ban.map(|v| Instant::now() + v);
+        let time_to_unban = duration_of_ban.map(|v| self.config.clock.now() + v);
         self.remove_node(node_id);
-        PERMIT_BAN_LIST
-            .write()
-            .ban_nodes
-            .insert(*node_id, time_to_unban);
+        PERMIT_BAN_LIST.write().ban_node(*node_id, time_to_unban);
+        AUDIT_LOG.record(
+            SecurityEventKind::NodeBanned {
+                reason: BanReason::Manual,
+                duration: duration_of_ban,
+            },
+            None,
+            Some(*node_id),
+            None,
+        );
     }
 
     /// Removes a banned node from the banned list.
     pub fn ban_node_remove(&self, node_id: &NodeId) {
-        PERMIT_BAN_LIST.write().ban_nodes.remove(node_id);
+        PERMIT_BAN_LIST.write().unban_node(node_id);
     }
 
     /// Permits a node, allowing the node to bypass the packet filter.
@@ -386,13 +768,22 @@ impl<P: ProtocolIdentity> Discv5<P> {
 
     /// Bans an IP from the server.  This will block all incoming packets from the IP.
     pub fn ban_ip(&self, ip: std::net::IpAddr, duration_of_ban: Option<Duration>) {
-        let time_to_unban = duration_of_ban.map(|v| Instant::now() + v);
-        PERMIT_BAN_LIST.write().ban_ips.insert(ip, time_to_unban);
+        let time_to_unban = duration_of_ban.map(|v| self.config.clock.now() + v);
+        PERMIT_BAN_LIST.write().ban_ip(ip, time_to_unban);
+        AUDIT_LOG.record(
+            SecurityEventKind::IpBanned {
+                reason: BanReason::Manual,
+                duration: duration_of_ban,
+            },
+            Some(ip),
+            None,
+            None,
+        );
     }
 
     /// Removes a banned IP from the banned list.
     pub fn ban_ip_remove(&self, ip: &std::net::IpAddr) {
-        PERMIT_BAN_LIST.write().ban_ips.remove(ip);
+        PERMIT_BAN_LIST.write().unban_ip(ip);
     }
 
     /// Permits an IP, allowing the all packets from the IP to bypass the packet filter.
@@ -405,40 +796,44 @@ impl<P: ProtocolIdentity> Discv5<P> {
         PERMIT_BAN_LIST.write().permit_ips.remove(ip);
     }
 
-    /// Updates the local ENR TCP/UDP socket.
+    /// Updates the local ENR TCP/UDP socket. IPv4 addresses are refused by an IPv6-only node.
     pub fn update_local_enr_socket(&self, socket_addr: SocketAddr, is_tcp: bool) -> bool {
-        let mut local_enr = self.local_enr.write();
-        match (is_tcp, socket_addr) {
-            (false, SocketAddr::V4(specific_socket_addr)) => {
-                if Some(specific_socket_addr) != local_enr.udp4_socket() {
-                    return local_enr
-                        .set_udp_socket(socket_addr, &self.enr_key.read())
-                        .is_ok();
+        if self.config.ipv6_only && is_ipv4_or_mapped(&socket_addr) {
+            return false;
+        }
+        self.enr_watch.update(&self.local_enr, |local_enr| {
+            match (is_tcp, socket_addr) {
+                (false, SocketAddr::V4(specific_socket_addr)) => {
+                    if Some(specific_socket_addr) != local_enr.udp4_socket() {
+                        return local_enr
+                            .set_udp_socket(socket_addr, &self.enr_key.read())
+                            .is_ok();
+                    }
                 }
-            }
-            (true, SocketAddr::V4(specific_socket_addr)) => {
-                if Some(specific_socket_addr) != local_enr.tcp4_socket() {
-                    return local_enr
-                        .set_tcp_socket(socket_addr, &self.enr_key.read())
-                        .is_ok();
+                (true, SocketAddr::V4(specific_socket_addr)) => {
+                    if Some(specific_socket_addr) != local_enr.tcp4_socket() {
+                        return local_enr
+                            .set_tcp_socket(socket_addr, &self.enr_key.read())
+                            .is_ok();
+                    }
                 }
-            }
-            (false, SocketAddr::V6(specific_socket_addr)) => {
-                if Some(specific_socket_addr) != local_enr.udp6_socket() {
-                    return local_enr
-                        .set_udp_socket(socket_addr, &self.enr_key.read())
-                        .is_ok();
+                (false, SocketAddr::V6(specific_socket_addr)) => {
+                    if Some(specific_socket_addr) != local_enr.udp6_socket() {
+                        return local_enr
+                            .set_udp_socket(socket_addr, &self.enr_key.read())
+                            .is_ok();
+                    }
                 }
-            }
-            (true, SocketAddr::V6(specific_socket_addr)) => {
-                if Some(specific_socket_addr) != local_enr.tcp6_socket() {
-                    return local_enr
-                        .set_tcp_socket(socket_addr, &self.enr_key.read())
-                        .is_ok();
+                (true, SocketAddr::V6(specific_socket_addr)) => {
+                    if Some(specific_socket_addr) != local_enr.tcp6_socket() {
+                        return local_enr
+                            .set_tcp_socket(socket_addr, &self.enr_key.read())
+                            .is_ok();
+                    }
                 }
             }
-        }
-        false
+            false
+        })
     }
 
     /// Allows application layer to insert an arbitrary field into the local ENR.
@@ -447,16 +842,45 @@ impl<P: ProtocolIdentity> Discv5<P> {
         key: &str,
         value: &T,
     ) -> Result<Option<Vec<u8>>, EnrError> {
-        self.local_enr
-            .write()
-            .insert(key, value, &self.enr_key.read())
-            .map(|v| v.map(|v| v.to_vec()))
+        self.enr_watch.update(&self.local_enr, |local_enr| {
+            local_enr
+                .insert(key, value, &self.enr_key.read())
+                .map(|v| v.map(|v| v.to_vec()))
+        })
+    }
+
+    /// Sets the QUIC port of the local ENR for the given IP version.
+    pub fn update_local_enr_quic(&self, port: u16, is_ipv6: bool) -> Result<(), EnrError> {
+        let key = if is_ipv6 {
+            enr_ext::QUIC6_ENR_KEY
+        } else {
+            enr_ext::QUIC_ENR_KEY
+        };
+        self.enr_watch.update(&self.local_enr, |local_enr| {
+            local_enr
+                .insert(key, &port, &self.enr_key.read())
+                .map(|_| ())
+        })
+    }
+
+    /// Sets the `eth2` field of the local ENR to the given bytes.
+    pub fn update_local_enr_eth2(&self, eth2: &[u8]) -> Result<(), EnrError> {
+        self.update_local_enr_bytes(enr_ext::ETH2_ENR_KEY, eth2)
+    }
+
+    /// Sets an arbitrary field of the local ENR to the given bytes, encoded as an RLP byte
+    /// string. The field can be read back with [`crate::EnrExt::get_bytes`].
+    pub fn update_local_enr_bytes(&self, key: &str, value: &[u8]) -> Result<(), EnrError> {
+        self.enr_watch.update(&self.local_enr, |local_enr| {
+            local_enr
+                .insert(key, &value, &self.enr_key.read())
+                .map(|_| ())
+        })
     }
 
     /// Returns an iterator over all ENR node IDs of nodes currently contained in the routing table.
     pub fn table_entries_id(&self) -> Vec<NodeId> {
         self.kbuckets
-            .write()
             .iter()
             .map(|entry| *entry.node.key.preimage())
             .collect()
@@ -465,32 +889,30 @@ impl<P: ProtocolIdentity> Discv5<P> {
     /// Returns an iterator over all the ENR's of nodes currently contained in the routing table.
     pub fn table_entries_enr(&self) -> Vec<Enr> {
         self.kbuckets
-            .write()
             .iter()
-            .map(|entry| entry.node.value.clone())
+            .map(|entry| Enr::clone(&entry.node.value))
             .collect()
     }
 
     /// Returns an iterator over all the entries in the routing table.
     pub fn table_entries(&self) -> Vec<(NodeId, Enr, NodeStatus)> {
         self.kbuckets
-            .write()
             .iter()
             .map(|entry| {
                 (
                     *entry.node.key.preimage(),
-                    entry.node.value.clone(),
+                    Enr::clone(&entry.node.value),
                     entry.status,
                 )
             })
             .collect()
     }
 
-    /// Takes a closure parameterized by type `Arc<RwLock<KBucketsTable<NodeId, Enr>>>` as
-    /// parameter. Caution: caller is responsible of dropping a lock taken on the kbuckets. For
-    /// example, a read lock can be taken on the kbuckets to optimistically view the current keys
-    /// in the kbuckets (optimistic since it doesn't apply pending entries, which requires a write
-    /// lock).
+    /// Takes a closure parameterized by type `Arc<KBucketsTable<NodeId, Arc<Enr>>>` as
+    /// parameter. Caution: a [`kbucket::Entry`] keeps the bucket of its key locked until it is
+    /// dropped. For example, [`KBucketsTable::iter_ref`] can be used to optimistically view the
+    /// current keys in the kbuckets (optimistic since it doesn't apply pending entries, which
+    /// requires a write lock on each bucket).
     /// ```
     /// use std::str::FromStr;
     /// use discv5::{ConfigBuilder, Discv5, ListenConfig, Enr, enr::CombinedKey};
@@ -501,14 +923,13 @@ impl<P: ProtocolIdentity> Discv5<P> {
     /// let discv5: Discv5 = Discv5::new(enr, sk, config).unwrap();
     ///
     /// let entries =  discv5.with_kbuckets(|kbuckets| kbuckets
-    ///     .read()
     ///     .iter_ref()
     ///     .map(|entry| *entry.node.key.preimage())
     ///     .collect::<Vec<_>>());
     /// ```
     pub fn with_kbuckets<F, T>(&self, f: F) -> T
     where
-        F: FnOnce(&Arc<RwLock<KBucketsTable<NodeId, Enr>>>) -> T,
+        F: FnOnce(&Arc<KBucketsTable<NodeId, Arc<Enr>>>) -> T,
     {
         f(&self.kbuckets)
     }
@@ -526,6 +947,7 @@ impl<P: ProtocolIdentity> Discv5<P> {
         multiaddr: impl std::convert::TryInto<Multiaddr> + 'static,
     ) -> impl Future<Output = Result<Enr, RequestError>> + 'static {
         let channel = self.clone_channel();
+        let ipv6_only = self.config.ipv6_only;
 
         async move {
             let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
@@ -539,6 +961,11 @@ impl<P: ProtocolIdentity> Discv5<P> {
                 .map_err(|_| RequestError::InvalidMultiaddr("Could not convert to multiaddr"))?;
             let node_contact: NodeContact = NodeContact::try_from_multiaddr(multiaddr)
                 .map_err(RequestError::InvalidMultiaddr)?;
+            if ipv6_only && is_ipv4_or_mapped(&node_contact.socket_addr()) {
+                return Err(RequestError::InvalidMultiaddr(
+                    "An IPv6-only node cannot contact an IPv4 address",
+                ));
+            }
 
             let (callback_send, callback_recv) = oneshot::channel();
 
@@ -565,6 +992,7 @@ impl<P: ProtocolIdentity> Discv5<P> {
                     }
                     nodes
                         .pop()
+                        .map(Arc::unwrap_or_clone)
                         .ok_or(RequestError::InvalidEnr("Peer did not return an ENR"))
                 }
                 Err(err) => Err(err),
@@ -572,7 +1000,20 @@ impl<P: ProtocolIdentity> Discv5<P> {
         }
     }
 
+    /// Sends a TALK request to a node. The returned [`Talk`] request is configured with its
+    /// builder methods and sent when awaited, failing with a [`crate::TalkError`] that tells peers that
+    /// don't serve the protocol from timeouts and other failures.
+    pub fn talk(&self, enr: impl Into<Arc<Enr>>, protocol: Vec<u8>, payload: Vec<u8>) -> Talk {
+        let contact = NodeContact::try_from_enr_preferring(
+            enr,
+            self.ip_mode,
+            self.config.dial_policy.prefers_ipv6(),
+        );
+        Talk::new(self.clone_channel().ok(), contact, protocol, payload)
+    }
+
     /// Request a TALK message from a node, identified via the NodeContact.
+    #[deprecated(note = "Use `Discv5::talk`, which supports timeouts, retries and typed errors")]
     pub fn talk_req(
         &self,
         node_contact: NodeContact,
@@ -587,7 +1028,7 @@ impl<P: ProtocolIdentity> Discv5<P> {
         async move {
             let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
 
-            let event = ServiceRequest::Talk(node_contact, protocol, request, callback_send);
+            let event = ServiceRequest::Talk(node_contact, protocol, request, None, callback_send);
 
             // send the request
             channel
@@ -605,15 +1046,17 @@ impl<P: ProtocolIdentity> Discv5<P> {
     /// to the designated peer and wait for a response.
     pub fn find_node_designated_peer(
         &self,
-        enr: Enr,
+        enr: impl Into<Arc<Enr>>,
         distances: Vec<u64>,
     ) -> impl Future<Output = Result<Vec<Enr>, RequestError>> + 'static {
+        let enr = enr.into();
         let (callback_send, callback_recv) = oneshot::channel();
         let channel = self.clone_channel();
         let ip_mode = self.ip_mode;
+        let prefer_ipv6 = self.config.dial_policy.prefers_ipv6();
 
         async move {
-            let node_contact = NodeContact::try_from_enr(enr, ip_mode)?;
+            let node_contact = NodeContact::try_from_enr_preferring(enr, ip_mode, prefer_ipv6)?;
             let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
 
             let event = ServiceRequest::FindNodeDesignated(node_contact, distances, callback_send);
@@ -624,12 +1067,43 @@ impl<P: ProtocolIdentity> Discv5<P> {
                 .await
                 .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
             // await the response
-            callback_recv
+            let nodes = callback_recv
                 .await
-                .map_err(|e| RequestError::ChannelFailed(e.to_string()))?
+                .map_err(|e| RequestError::ChannelFailed(e.to_string()))??;
+            Ok(nodes.into_iter().map(Arc::unwrap_or_clone).collect())
         }
     }
 
+    /// Sends a single FINDNODE request for the given distances to one peer and returns its NODES
+    /// response, without running a query. A peer given by node id is looked up in the routing
+    /// table, then in the ENR store. Distances above 256 are rejected and duplicates are
+    /// removed.
+    pub fn find_node_at_distances(
+        &self,
+        peer: impl Into<PeerRef>,
+        mut distances: Vec<u64>,
+    ) -> impl Future<Output = Result<Vec<Enr>, RequestError>> + 'static {
+        distances.sort_unstable();
+        distances.dedup();
+        let enr = match peer.into() {
+            PeerRef::Enr(enr) => Ok(enr),
+            PeerRef::NodeId(node_id) => self
+                .find_enr(&node_id)
+                .or_else(|| self.find_stored_enr(&node_id))
+                .map(Arc::new)
+                .ok_or(RequestError::InvalidEnr("Unknown node")),
+        };
+        let request = match enr {
+            Ok(_) if distances.is_empty() || distances.iter().any(|d| *d > 256) => {
+                Err(RequestError::InvalidDistances)
+            }
+            Ok(enr) => Ok(self.find_node_designated_peer(enr, distances)),
+            Err(e) => Err(e),
+        };
+
+        async move { request?.await }
+    }
+
     /// Runs an iterative `FIND_NODE` request.
     ///
     /// This will return peers containing contactable nodes of the DHT closest to the
@@ -657,10 +1131,49 @@ impl<P: ProtocolIdentity> Discv5<P> {
 
             callback_recv
                 .await
+                .map(|enrs| enrs.into_iter().map(Arc::unwrap_or_clone).collect())
                 .map_err(|e| QueryError::ChannelFailed(e.to_string()))
         }
     }
 
+    /// Runs an iterative `FIND_NODE` request like [`Discv5::find_node`], also returning a trace of
+    /// the peers the lookup contacted. The trace can be serialized with the `serde` feature or
+    /// exported with [`QueryTrace::to_dot`] to debug slow or failing lookups.
+    pub fn find_node_traced(
+        &self,
+        target_node: NodeId,
+    ) -> impl Future<Output = Result<(Vec<Enr>, QueryTrace), QueryError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+            let (trace_send, trace_recv) = oneshot::channel();
+
+            let query_kind = QueryKind::TracedFindNode {
+                target_node,
+                trace: trace_send,
+            };
+
+            let event = ServiceRequest::StartQuery(query_kind, callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+
+            let enrs = callback_recv
+                .await
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))?;
+            // A query that never started, for lack of known peers, contacted no one.
+            let trace = trace_recv.await.unwrap_or(QueryTrace {
+                target: target_node,
+                duration: Duration::ZERO,
+                contacts: Vec::new(),
+            });
+            Ok((enrs.into_iter().map(Arc::unwrap_or_clone).collect(), trace))
+        }
+    }
+
     /// Starts a `FIND_NODE` request.
     ///
     /// This will return less than or equal to `num_nodes` ENRs which satisfy the
@@ -704,10 +1217,327 @@ impl<P: ProtocolIdentity> Discv5<P> {
 
             callback_recv
                 .await
+                .map(|enrs| enrs.into_iter().map(Arc::unwrap_or_clone).collect())
                 .map_err(|e| QueryError::ChannelFailed(e.to_string()))
         }
     }
 
+    /// Estimates the number of nodes of the network from the density of the nodes found by a few
+    /// lookups for targets spread across the id space. The lookups run in parallel. See
+    /// [`NetworkSizeEstimate`].
+    pub async fn estimate_network_size(&self) -> Result<NetworkSizeEstimate, QueryError> {
+        let local_id = self.local_enr().node_id();
+        let lookups = network_size::lookup_targets(NETWORK_SIZE_LOOKUPS)
+            .into_iter()
+            .map(|target| {
+                let lookup = self.find_node(target);
+                async move { lookup.await.map(|enrs| (target, enrs)) }
+            });
+        let mut found = HashSet::from([local_id]);
+        let mut estimates = Vec::new();
+        for (target, enrs) in future::try_join_all(lookups).await? {
+            let node_ids: Vec<NodeId> = enrs
+                .iter()
+                .map(Enr::node_id)
+                .filter(|node_id| *node_id != local_id)
+                .chain(std::iter::once(local_id))
+                .collect();
+            found.extend(node_ids.iter().copied());
+            estimates.push(network_size::lookup_estimate(&target, &node_ids));
+        }
+        Ok(network_size::combine_estimates(&estimates, &found))
+    }
+
+    /// Crawls the DHT, asking every node found for the contents of its furthest buckets, starting
+    /// from the routing table. Returns every node found, with whether it responded. See
+    /// [`CrawlConfig`] for the extent of the crawl.
+    pub async fn crawl(&self, config: CrawlConfig) -> Result<CrawlSnapshot, QueryError> {
+        self.clone_channel()
+            .map_err(|_| QueryError::ServiceNotStarted)?;
+        let local_id = self.local_enr().node_id();
+        let mut crawl = Crawl::new(self.table_entries_enr(), config.max_peers);
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            while in_flight.len() < config.concurrency.max(1) {
+                let Some(enr) = crawl.next_peer() else {
+                    break;
+                };
+                let node_id = enr.node_id();
+                let requests: Vec<_> = crawl_distances(&config)
+                    .map(|distance| self.find_node_designated_peer(enr.clone(), vec![distance]))
+                    .collect();
+                in_flight.push(async move {
+                    let mut reachable = false;
+                    let mut found = Vec::new();
+                    for request in requests {
+                        // Stop at the first failure, rather than wait for every remaining request
+                        // to a node that has gone away to time out.
+                        let Ok(enrs) = request.await else {
+                            break;
+                        };
+                        reachable = true;
+                        found.extend(enrs);
+                    }
+                    (node_id, reachable, found)
+                });
+            }
+
+            let Some((node_id, reachable, found)) = in_flight.next().await else {
+                break;
+            };
+            crawl.contacted(&node_id, reachable);
+            for enr in found {
+                if enr.node_id() != local_id {
+                    crawl.found(enr);
+                }
+            }
+        }
+        Ok(crawl.finish())
+    }
+
+    /// Verifies a peer learned out of band, such as from gossip or the operator's configuration,
+    /// the same way as a discovered peer: the peer is pinged, and inserted into the routing table
+    /// once it has proven to hold the key of its ENR and answers. Unlike [`Discv5::add_enr`], an
+    /// unresponsive peer never enters the table. The outcome is reported by
+    /// [`Event::PeerInjected`].
+    pub fn inject_peer(
+        &self,
+        enr: Enr,
+        source: PeerSource,
+    ) -> impl Future<Output = Result<(), Error>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            channel?
+                .send(ServiceRequest::InjectPeer(enr, source))
+                .await
+                .map_err(|_| Error::ServiceChannelClosed)
+        }
+    }
+
+    /// Advertises the local node for a topic at the nodes closest to the topic hash. The
+    /// advertisements are renewed until [`Discv5::unregister_topic`] is called. Registering a
+    /// topic again renews its advertisements right away, with the current local ENR. Fails unless
+    /// topic advertisement is enabled with [`crate::ConfigBuilder::topics`].
+    pub fn register_topic(
+        &self,
+        topic: TopicHash,
+    ) -> impl Future<Output = Result<(), Error>> + 'static {
+        let channel = self.clone_channel();
+        let enabled = self.config.topics.is_some();
+
+        async move {
+            if !enabled {
+                return Err(Error::Custom("Topic advertisement is not enabled"));
+            }
+            channel?
+                .send(ServiceRequest::RegisterTopic(topic))
+                .await
+                .map_err(|_| Error::ServiceChannelClosed)
+        }
+    }
+
+    /// Stops renewing the advertisements for a topic. Existing advertisements expire on their own.
+    pub fn unregister_topic(
+        &self,
+        topic: TopicHash,
+    ) -> impl Future<Output = Result<(), Error>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            channel?
+                .send(ServiceRequest::UnregisterTopic(topic))
+                .await
+                .map_err(|_| Error::ServiceChannelClosed)
+        }
+    }
+
+    /// Asks the nodes closest to the topic hash for the nodes advertised for a topic.
+    pub fn topic_query(
+        &self,
+        topic: TopicHash,
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let event = ServiceRequest::TopicQuery(topic, callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+
+            callback_recv
+                .await
+                .map(|enrs| enrs.into_iter().map(Arc::unwrap_or_clone).collect())
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
+        }
+    }
+
+    /// Advertises the local node as a provider of the named service, publishing `metadata` in the
+    /// local ENR. Advertising the service again updates the metadata and renews the
+    /// advertisements, so that the registrars hold the updated ENR. Fails without touching the
+    /// local ENR unless topic advertisement is enabled. See [`crate::rendezvous`].
+    pub fn advertise_service(
+        &self,
+        name: &str,
+        metadata: &[u8],
+    ) -> impl Future<Output = Result<(), Error>> + 'static {
+        let updated = if self.config.topics.is_some() {
+            self.update_local_enr_bytes(&rendezvous::service_enr_key(name), metadata)
+                .map_err(|e| Error::Error(e.to_string()))
+        } else {
+            Err(Error::Custom("Topic advertisement is not enabled"))
+        };
+        let registration = self.register_topic(rendezvous::service_topic(name));
+
+        async move {
+            updated?;
+            registration.await
+        }
+    }
+
+    /// Stops advertising the named service and removes its metadata from the local ENR.
+    pub fn stop_advertising_service(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<(), Error>> + 'static {
+        let key = rendezvous::service_enr_key(name);
+        let removed = self
+            .enr_watch
+            .update(&self.local_enr, |local_enr| {
+                local_enr.remove_insert(
+                    std::iter::once(&key),
+                    std::iter::empty::<(&str, &[u8])>(),
+                    &self.enr_key.read(),
+                )
+            })
+            .map(|_| ())
+            .map_err(|e| Error::Error(e.to_string()));
+        let unregistration = self.unregister_topic(rendezvous::service_topic(name));
+
+        async move {
+            removed?;
+            unregistration.await
+        }
+    }
+
+    /// Searches for the providers of the named service. Each provider is yielded once, and again
+    /// whenever its ENR is updated, and only if its ENR carries the metadata of the service. The
+    /// search is repeated periodically and the stream ends once the service is shut down.
+    pub fn find_service(&self, name: &str) -> impl Stream<Item = Enr> + 'static {
+        let channel = self.clone_channel().ok();
+        let topic = rendezvous::service_topic(name);
+        let providers = rendezvous::Providers::new(name.to_string());
+
+        futures::stream::unfold(
+            (channel, providers, VecDeque::<Enr>::new(), true),
+            move |(channel, mut providers, mut found, mut first)| async move {
+                loop {
+                    if let Some(enr) = found.pop_front() {
+                        return Some((enr, (channel, providers, found, first)));
+                    }
+                    if !first {
+                        tokio::time::sleep(rendezvous::SERVICE_QUERY_INTERVAL).await;
+                    }
+                    first = false;
+
+                    let (callback_send, callback_recv) = oneshot::channel();
+                    channel
+                        .as_ref()?
+                        .send(ServiceRequest::TopicQuery(topic, callback_send))
+                        .await
+                        .ok()?;
+                    let enrs = callback_recv.await.ok()?;
+                    found.extend(
+                        enrs.into_iter()
+                            .filter(|enr| providers.accept(enr))
+                            .map(Arc::unwrap_or_clone),
+                    );
+                }
+            },
+        )
+    }
+
+    /// Rotates the identity of the node to a new key of the same type. The local ENR is re-signed
+    /// with the key and the routing table is re-arranged around the new node id, dropping the
+    /// nodes that no longer fit.
+    ///
+    /// New sessions are established under the new identity. With a grace period, the sessions
+    /// established under the previous identity keep answering until it ends or the peer
+    /// handshakes with the new identity. Otherwise they are dropped right away.
+    pub async fn rotate_identity(
+        &self,
+        new_key: CombinedKey,
+        grace_period: Option<Duration>,
+    ) -> Result<(), Error> {
+        if new_key.public().enr_key() != self.enr_key.read().public().enr_key() {
+            return Err(Error::KeyTypeNotSupported(
+                "The new key must be of the same type as the current key",
+            ));
+        }
+        // The key is swapped while the ENR is locked, so that no update re-signs it with the
+        // previous key in between. The ENR is locked first, as by every other update.
+        let node_id = self
+            .enr_watch
+            .update(&self.local_enr, |local_enr| {
+                // Re-signing the record replaces its public key with that of the signing key.
+                local_enr
+                    .remove_insert(
+                        std::iter::empty::<&str>(),
+                        std::iter::empty::<(&str, &[u8])>(),
+                        &new_key,
+                    )
+                    .ok()?;
+                *self.enr_key.write() = new_key;
+                Some(local_enr.node_id())
+            })
+            .ok_or(Error::Custom("Could not sign the ENR with the new key"))?;
+
+        let dropped = self.kbuckets.set_local_key(node_id.into());
+        if !dropped.is_empty() {
+            debug!(
+                dropped = dropped.len(),
+                "Dropped the nodes that don't fit the routing table of the new identity"
+            );
+        }
+
+        // The handler only needs to switch identities while it is running.
+        if let Some(channel) = self.service_channel.as_ref() {
+            channel
+                .send(ServiceRequest::RotateIdentity(grace_period))
+                .await
+                .map_err(|_| Error::ServiceChannelClosed)?;
+        }
+        Ok(())
+    }
+
+    /// The requests that have not been answered yet: those awaiting a response, those waiting
+    /// for a handshake to complete and those queued for a session establishment. This helps to
+    /// find out what a lookup that appears to hang is waiting on.
+    pub async fn pending_requests(&self) -> Result<Vec<OutgoingRequest>, Error> {
+        let (callback_send, callback_recv) = oneshot::channel();
+        self.clone_channel()?
+            .send(ServiceRequest::PendingRequests(callback_send))
+            .await
+            .map_err(|_| Error::ServiceChannelClosed)?;
+        callback_recv.await.map_err(|_| Error::ServiceChannelClosed)
+    }
+
+    /// The state of the filter of unsolicited packets: the budgets left of the rate limits, the
+    /// senders closest to their limits and the packets recently dropped. This shows whether
+    /// legitimate traffic is being throttled before the limits are loosened.
+    pub async fn filter_stats(&self) -> Result<FilterStats, Error> {
+        let (callback_send, callback_recv) = oneshot::channel();
+        self.clone_channel()?
+            .send(ServiceRequest::FilterStats(callback_send))
+            .await
+            .map_err(|_| Error::ServiceChannelClosed)?;
+        callback_recv.await.map_err(|_| Error::ServiceChannelClosed)
+    }
+
     /// Creates an event stream channel which can be polled to receive Discv5 events.
     pub fn event_stream(
         &self,
@@ -739,8 +1569,97 @@ impl<P: ProtocolIdentity> Discv5<P> {
     }
 }
 
+/// Hooks perturbing a running node, so that tests can cover the paths recovering from lost
+/// sessions, unresponsive peers and undecryptable packets.
+#[cfg(feature = "test_utils")]
+impl<P: ProtocolIdentity> Discv5<P> {
+    /// Drops the sessions with a node without notifying it. The next packet exchanged with the
+    /// node fails to decrypt and a new handshake is performed.
+    pub async fn chaos_drop_session(&self, node_id: NodeId) -> Result<(), Error> {
+        self.send_chaos_hook(ChaosHook::DropSessions(Some(node_id)))
+            .await
+    }
+
+    /// Drops the sessions with all nodes without notifying them.
+    pub async fn chaos_drop_all_sessions(&self) -> Result<(), Error> {
+        self.send_chaos_hook(ChaosHook::DropSessions(None)).await
+    }
+
+    /// Corrupts the message nonce of the next packet sent to a node. The node fails to decrypt
+    /// the packet and challenges us to a new handshake.
+    pub async fn chaos_corrupt_next_nonce(&self, node_id: NodeId) -> Result<(), Error> {
+        self.send_chaos_hook(ChaosHook::CorruptNextNonce(node_id))
+            .await
+    }
+
+    /// Marks a routing table entry disconnected, as if it had stopped responding, and lets the
+    /// pending entry of its bucket, if any, replace it immediately. Returns `false` if the node
+    /// is not in the routing table.
+    pub fn chaos_expire_entry(&self, node_id: &NodeId) -> bool {
+        let key = kbucket::Key::from(*node_id);
+        !matches!(self.kbuckets.expire_node(&key), UpdateResult::Failed(_))
+    }
+
+    async fn send_chaos_hook(&self, hook: ChaosHook) -> Result<(), Error> {
+        self.clone_channel()?
+            .send(ServiceRequest::Chaos(hook))
+            .await
+            .map_err(|_| Error::ServiceChannelClosed)
+    }
+}
+
 impl<P: ProtocolIdentity> Drop for Discv5<P> {
     fn drop(&mut self) {
         self.shutdown();
     }
 }
+
+/// Adds an ENR to the routing table as a disconnected node, if it passes the contactability,
+/// table filter and allowlist checks.
+fn insert_enr(
+    kbuckets: &KBucketsTable<NodeId, Arc<Enr>>,
+    ip_mode: IpMode,
+    config: &Config,
+    enr: Enr,
+) -> Result<(), &'static str> {
+    // only add ENR's that have a valid udp socket.
+    if ip_mode.get_contactable_addr(&enr).is_none() {
+        warn!("ENR attempted to be added without an UDP socket compatible with configured IpMode has been ignored.");
+        return Err("ENR has no compatible UDP socket to connect to");
+    }
+
+    if !(config.table_filter)(&enr) {
+        warn!("ENR attempted to be added which is banned by the configuration table filter.");
+        return Err("ENR banned by table filter");
+    }
+
+    if let Some(allowlist) = config.enr_allowlist.as_ref() {
+        if !allowlist.allows(&enr) {
+            warn!("ENR attempted to be added which is not in the ENR allowlist.");
+            return Err("ENR not in allowlist");
+        }
+    }
+
+    let key = kbucket::Key::from(enr.node_id());
+
+    match kbuckets.insert_or_update(
+        &key,
+        Arc::new(enr),
+        NodeStatus {
+            state: ConnectionState::Disconnected,
+            direction: ConnectionDirection::Incoming,
+        },
+    ) {
+        InsertResult::Inserted
+        | InsertResult::Pending { .. }
+        | InsertResult::StatusUpdated { .. }
+        | InsertResult::ValueUpdated
+        | InsertResult::Updated { .. }
+        | InsertResult::UpdatedPending => Ok(()),
+        InsertResult::Failed(FailureReason::BucketFull) => Err("Table full"),
+        InsertResult::Failed(FailureReason::BucketFilter) => Err("Failed bucket filter"),
+        InsertResult::Failed(FailureReason::TableFilter) => Err("Failed table filter"),
+        InsertResult::Failed(FailureReason::InvalidSelfUpdate) => Err("Invalid self update"),
+        InsertResult::Failed(_) => Err("Failed to insert ENR"),
+    }
+}
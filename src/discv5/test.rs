@@ -21,13 +21,24 @@ fn update_enr<T: alloy_rlp::Encodable>(discv5: &mut Discv5, key: &str, value: &T
 
 #[allow(dead_code)]
 async fn build_nodes(n: usize, base_port: u16) -> Vec<Discv5> {
+    build_nodes_with(n, base_port, |_| {}).await
+}
+
+/// Build `n` swarms with the configuration adjusted by `configure`.
+async fn build_nodes_with(
+    n: usize,
+    base_port: u16,
+    configure: impl Fn(&mut ConfigBuilder),
+) -> Vec<Discv5> {
     let mut nodes = Vec::new();
     let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
 
     for port in base_port..base_port + n as u16 {
         let enr_key = CombinedKey::generate_secp256k1();
         let listen_config = ListenConfig::Ipv4 { ip, port };
-        let config = ConfigBuilder::new(listen_config).build();
+        let mut builder = ConfigBuilder::new(listen_config);
+        configure(&mut builder);
+        let config = builder.build();
 
         let enr = Enr::builder().ip4(ip).udp4(port).build(&enr_key).unwrap();
         // transport for building a swarm
@@ -764,7 +775,7 @@ async fn test_table_limits() {
         let _ = discv5.add_enr(enr.clone()); // we expect some of these to fail the filter.
     }
     // Number of entries should be `table_limit`, i.e one node got restricted
-    assert_eq!(discv5.kbuckets.read().iter_ref().count(), table_limit);
+    assert_eq!(discv5.kbuckets.iter_ref().count(), table_limit);
 }
 
 // Each bucket can have maximum 2 nodes in the same /24 subnet
@@ -816,5 +827,549 @@ async fn test_bucket_limits() {
     }
 
     // Number of entries should be equal to `bucket_limit`.
-    assert_eq!(discv5.kbuckets.read().iter_ref().count(), bucket_limit);
+    assert_eq!(discv5.kbuckets.iter_ref().count(), bucket_limit);
+}
+
+#[tokio::test]
+async fn test_topic_registration_and_query() {
+    init();
+    let nodes = build_nodes_with(3, 10100, |builder| {
+        builder.topics(Some(TopicConfig::default()));
+    })
+    .await;
+    // The advertiser and the searcher only know of the registrar.
+    let registrar = nodes[0].local_enr();
+    nodes[1].add_enr(registrar.clone()).unwrap();
+    nodes[2].add_enr(registrar).unwrap();
+
+    let topic = TopicHash::new("test");
+    nodes[1].register_topic(topic).await.unwrap();
+
+    let mut found = Vec::new();
+    for _ in 0..20 {
+        found = nodes[2].topic_query(topic).await.unwrap();
+        if !found.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let found: Vec<NodeId> = found.iter().map(|enr| enr.node_id()).collect();
+    assert_eq!(found, vec![nodes[1].local_enr().node_id()]);
+}
+
+#[tokio::test]
+async fn test_topics_are_opt_in() {
+    init();
+    let nodes = build_nodes(2, 10186).await;
+    nodes[1].add_enr(nodes[0].local_enr()).unwrap();
+    let topic = TopicHash::new("test");
+    assert!(nodes[1].register_topic(topic).await.is_err());
+    assert!(nodes[1].advertise_service("relay", b"v1").await.is_err());
+    assert!(crate::rendezvous::service_metadata(&nodes[1].local_enr(), "relay").is_none());
+    // Nodes without topics enabled can still search, and hold no advertisements.
+    assert_eq!(nodes[1].topic_query(topic).await.unwrap(), Vec::new());
+}
+
+#[tokio::test]
+async fn test_service_discovery() {
+    use futures::StreamExt;
+    init();
+    let nodes = build_nodes_with(3, 10110, |builder| {
+        builder.topics(Some(TopicConfig::default()));
+    })
+    .await;
+    let registrar = nodes[0].local_enr();
+    nodes[1].add_enr(registrar.clone()).unwrap();
+    nodes[2].add_enr(registrar).unwrap();
+
+    nodes[1].advertise_service("relay", b"v1").await.unwrap();
+    let topic = crate::rendezvous::service_topic("relay");
+    for _ in 0..20 {
+        if !nodes[2].topic_query(topic).await.unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    let mut providers = Box::pin(nodes[2].find_service("relay"));
+    let provider = tokio::time::timeout(std::time::Duration::from_secs(5), providers.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(provider.node_id(), nodes[1].local_enr().node_id());
+    assert_eq!(
+        crate::rendezvous::service_metadata(&provider, "relay"),
+        Some(b"v1".to_vec())
+    );
+}
+
+#[tokio::test]
+async fn test_inject_peer() {
+    init();
+    let nodes = build_nodes(2, 10120).await;
+    let mut events = nodes[0].event_stream().await.unwrap();
+    let peer = nodes[1].local_enr();
+    nodes[0]
+        .inject_peer(peer.clone(), PeerSource::Static)
+        .await
+        .unwrap();
+
+    let key = CombinedKey::generate_secp256k1();
+    let no_address = Enr::builder().build(&key).unwrap();
+    nodes[0]
+        .inject_peer(no_address.clone(), PeerSource::Gossip)
+        .await
+        .unwrap();
+
+    let mut outcomes = HashMap::new();
+    while outcomes.len() < 2 {
+        let event = tokio::time::timeout(std::time::Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        if let Event::PeerInjected {
+            node_id, outcome, ..
+        } = event
+        {
+            outcomes.insert(node_id, outcome);
+        }
+    }
+    assert_eq!(outcomes[&peer.node_id()], InjectionOutcome::Verified);
+    assert_eq!(
+        outcomes[&no_address.node_id()],
+        InjectionOutcome::NotContactable
+    );
+    assert!(nodes[0].find_enr(&peer.node_id()).is_some());
+}
+
+#[tokio::test]
+async fn test_find_node_at_distances() {
+    init();
+    let nodes = build_nodes(2, 10130).await;
+    let peer = nodes[1].local_enr();
+    nodes[0].add_enr(peer.clone()).unwrap();
+
+    let found = nodes[0]
+        .find_node_at_distances(peer.node_id(), vec![0, 0])
+        .await
+        .unwrap();
+    assert_eq!(found, vec![peer.clone()]);
+
+    assert_eq!(
+        nodes[0]
+            .find_node_at_distances(peer.node_id(), vec![257])
+            .await,
+        Err(RequestError::InvalidDistances)
+    );
+    assert!(nodes[0]
+        .find_node_at_distances(NodeId::random(), vec![256])
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_pre_opened_socket() {
+    init();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let socket = std::net::UdpSocket::bind((ip, 0)).unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let listen_config = ListenConfig::from_std_sockets([socket]).unwrap();
+    assert!(ListenConfig::from_std_sockets([]).is_err());
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder().ip4(ip).udp4(port).build(&enr_key).unwrap();
+    let config = ConfigBuilder::new(listen_config).build();
+    let mut node = Discv5::<DefaultProtocolId>::new(enr, enr_key, config).unwrap();
+    assert_eq!(node.ip_mode(), IpMode::Ip4);
+    node.start().await.unwrap();
+
+    let peer = build_nodes(1, 10140).await.pop().unwrap();
+    peer.add_enr(node.local_enr()).unwrap();
+    let found = peer
+        .find_node_at_distances(node.local_enr().node_id(), vec![0])
+        .await
+        .unwrap();
+    assert_eq!(found, vec![node.local_enr()]);
+}
+
+#[tokio::test]
+async fn test_export_and_import_state() {
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let build = |port: u16| {
+        let enr_key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder().ip4(ip).udp4(port).build(&enr_key).unwrap();
+        let config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port })
+            .enr_store_capacity(Some(16))
+            .build();
+        Discv5::<DefaultProtocolId>::new(enr, enr_key, config).unwrap()
+    };
+    let source = build(9600);
+    let target = build(9601);
+
+    let peers: Vec<Enr<CombinedKey>> = (0..3)
+        .map(|i| {
+            let key = CombinedKey::generate_secp256k1();
+            Enr::builder()
+                .ip4(Ipv4Addr::new(10, 0, 0, i))
+                .udp4(9000)
+                .build(&key)
+                .unwrap()
+        })
+        .collect();
+    for peer in &peers {
+        source.add_enr(peer.clone()).unwrap();
+        source
+            .enr_store
+            .as_ref()
+            .unwrap()
+            .write()
+            .insert(peer.clone());
+    }
+    source.set_peer_store_metadata("fork", vec![1, 2]);
+
+    let state = source.export_state();
+    assert_eq!(state.peers.enrs.len(), peers.len());
+    assert_eq!(state.stored_enrs.len(), peers.len());
+
+    assert_eq!(target.import_state(state), peers.len());
+    for peer in &peers {
+        assert_eq!(target.find_enr(&peer.node_id()).as_ref(), Some(peer));
+        assert_eq!(target.find_stored_enr(&peer.node_id()).as_ref(), Some(peer));
+    }
+    assert_eq!(target.peer_store_metadata("fork"), Some(vec![1, 2]));
+}
+
+#[tokio::test]
+async fn test_ipv6_only_advertises_no_ipv4() {
+    let ip6 = Ipv6Addr::LOCALHOST;
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(9700)
+        .ip6(ip6)
+        .udp6(9700)
+        .build(&enr_key)
+        .unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv6 { ip: ip6, port: 0 })
+        .ipv6_only()
+        .build();
+    let node = Discv5::<DefaultProtocolId>::new(enr, enr_key, config).unwrap();
+
+    let local_enr = node.local_enr();
+    assert_eq!(local_enr.udp4_socket(), None);
+    assert_eq!(
+        local_enr.udp6_socket().map(|socket| *socket.ip()),
+        Some(ip6)
+    );
+    assert!(!node.update_local_enr_socket("127.0.0.1:9701".parse().unwrap(), false));
+    assert!(!node.update_local_enr_socket("[::ffff:127.0.0.1]:9701".parse().unwrap(), false));
+
+    // A peer advertising only an IPv4 address is not contactable.
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(9702)
+        .build(&peer_key)
+        .unwrap();
+    assert!(node.add_enr(peer).is_err());
+}
+
+#[tokio::test]
+async fn test_pending_requests() {
+    init();
+    let nodes = build_nodes(1, 10150).await;
+    let key = CombinedKey::generate_secp256k1();
+    let silent = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(10151)
+        .build(&key)
+        .unwrap();
+    let ping = tokio::spawn(nodes[0].send_ping(silent.clone()));
+
+    let mut pending = Vec::new();
+    for _ in 0..50 {
+        pending = nodes[0].pending_requests().await.unwrap();
+        if !pending.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].node_id, silent.node_id());
+    assert!(matches!(
+        pending[0].request,
+        crate::rpc::RequestBody::Ping { .. }
+    ));
+    assert_eq!(pending[0].state, OutgoingRequestState::Sent);
+    ping.abort();
+}
+
+#[tokio::test]
+async fn test_rotate_identity() {
+    init();
+    let nodes = build_nodes(2, 10160).await;
+    let old_enr = nodes[0].local_enr();
+    nodes[1].send_ping(old_enr.clone()).await.unwrap();
+
+    nodes[0]
+        .rotate_identity(
+            CombinedKey::generate_secp256k1(),
+            Some(std::time::Duration::from_secs(5)),
+        )
+        .await
+        .unwrap();
+    let new_enr = nodes[0].local_enr();
+    assert_ne!(new_enr.node_id(), old_enr.node_id());
+    assert!(new_enr.seq() > old_enr.seq());
+    assert_eq!(new_enr.udp4_socket(), old_enr.udp4_socket());
+
+    // The session established under the old identity keeps answering during the grace period,
+    // while the new identity can be contacted right away.
+    nodes[1].send_ping(old_enr).await.unwrap();
+    nodes[1].send_ping(new_enr).await.unwrap();
+
+    assert!(matches!(
+        nodes[0]
+            .rotate_identity(CombinedKey::generate_ed25519(), None)
+            .await,
+        Err(Error::KeyTypeNotSupported(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_event_enr() {
+    init();
+    let nodes = build_nodes(2, 10197).await;
+    let mut events = nodes[1].event_stream().await.unwrap();
+    nodes[0].send_ping(nodes[1].local_enr()).await.unwrap();
+
+    let event = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            if let Some(event @ Event::SessionEstablished(..)) = events.recv().await {
+                return event;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(event.enr(), Some(&nodes[0].local_enr()));
+    assert_eq!(
+        Event::SocketUpdated(nodes[0].local_enr().udp4_socket().unwrap().into()).enr(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_talk() {
+    init();
+    let mut nodes = build_nodes(2, 10170).await;
+    let peer = nodes[1].local_enr();
+    let mut events = nodes[1].event_stream().await.unwrap();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if let Event::TalkRequest(request) = event {
+                // Requests of other protocols are answered with an empty response on drop.
+                if request.protocol() == b"echo" {
+                    let body = request.body().to_vec();
+                    request.respond(body).unwrap();
+                }
+            }
+        }
+    });
+
+    let node = nodes.remove(0);
+    assert_eq!(
+        node.talk(peer.clone(), b"echo".to_vec(), b"hello".to_vec())
+            .await,
+        Ok(b"hello".to_vec())
+    );
+    assert_eq!(
+        node.talk(peer.clone(), b"other".to_vec(), b"hello".to_vec())
+            .await,
+        Err(TalkError::EmptyResponse)
+    );
+    assert_eq!(
+        node.talk(peer, b"other".to_vec(), b"hello".to_vec())
+            .allow_empty_response()
+            .await,
+        Ok(Vec::new())
+    );
+
+    let key = CombinedKey::generate_secp256k1();
+    let unreachable = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(10179)
+        .build(&key)
+        .unwrap();
+    // The handler fails each attempt once its timeout elapses, well before the request timeout
+    // of the configuration.
+    let started = std::time::Instant::now();
+    assert_eq!(
+        node.talk(unreachable, b"echo".to_vec(), Vec::new())
+            .with_timeout(std::time::Duration::from_millis(100))
+            .with_retries(1)
+            .await,
+        Err(TalkError::Timeout)
+    );
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    assert!(node.pending_requests().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_filter_stats() {
+    init();
+    let ip = Ipv4Addr::LOCALHOST;
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder().ip4(ip).udp4(10180).build(&enr_key).unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port: 10180 })
+        .enable_packet_filter()
+        .build();
+    let mut node: Discv5 = Discv5::new(enr.clone(), enr_key, config).unwrap();
+    node.start().await.unwrap();
+    let peer = build_nodes(1, 10181).await.remove(0);
+
+    peer.send_ping(enr).await.unwrap();
+
+    let stats = node.filter_stats().await.unwrap();
+    assert!(stats.enabled);
+    let rate_limiter = stats.rate_limiter.unwrap();
+    assert!(rate_limiter.total.remaining < rate_limiter.total.capacity);
+    assert!(rate_limiter
+        .top_talkers
+        .iter()
+        .any(|(talker, _)| *talker == Talker::Ip(ip.into())));
+    assert!(stats.recent_drops.is_empty());
+}
+
+#[tokio::test]
+async fn test_short_ban_expires_on_time() {
+    init();
+    let node = build_nodes(1, 10182).await.remove(0);
+    let ip = "192.0.2.50".parse().unwrap();
+    node.ban_ip(ip, Some(std::time::Duration::from_millis(200)));
+    assert!(crate::discv5::PERMIT_BAN_LIST
+        .read()
+        .ban_ips()
+        .contains_key(&ip));
+
+    // Lifted well before the interval of the housekeeping of the handler.
+    tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+    assert!(!crate::discv5::PERMIT_BAN_LIST
+        .read()
+        .ban_ips()
+        .contains_key(&ip));
+}
+
+#[tokio::test]
+async fn test_dial_back() {
+    init();
+    let ip = Ipv4Addr::LOCALHOST;
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder().ip4(ip).udp4(10183).build(&enr_key).unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port: 10183 })
+        .dial_back(Some(DialBackConfig::default()))
+        .request_timeout(std::time::Duration::from_millis(200))
+        .request_retries(0)
+        .build();
+    let mut helper: Discv5 = Discv5::new(enr.clone(), enr_key, config).unwrap();
+    helper.start().await.unwrap();
+    let requester = build_nodes(1, 10184).await.remove(0);
+    helper.add_enr(requester.local_enr()).unwrap();
+
+    // The candidate is encoded as the octets of the IP followed by the port. Peers are only
+    // dialed back at public addresses, so the loopback address is refused with an empty response.
+    let candidate = [&ip.octets()[..], &10184u16.to_be_bytes()[..]].concat();
+    assert_eq!(
+        requester
+            .talk(enr.clone(), b"dialback".to_vec(), candidate)
+            .await,
+        Err(TalkError::EmptyResponse)
+    );
+    // As are addresses of other hosts.
+    let candidate = [&[1, 2, 3, 4][..], &10184u16.to_be_bytes()[..]].concat();
+    assert_eq!(
+        requester.talk(enr, b"dialback".to_vec(), candidate).await,
+        Err(TalkError::EmptyResponse)
+    );
+}
+
+#[tokio::test]
+async fn test_from_key_file() {
+    init();
+
+    let path = std::env::temp_dir().join(format!("discv5-key-{}", rand::random::<u64>()));
+    let key_file = key_store::EncryptedKeyFile::new(&path).iterations(10);
+    let config = || ConfigBuilder::new(ListenConfig::default()).build();
+    let enr_builder = || {
+        let mut builder = Enr::builder();
+        builder.ip4(Ipv4Addr::LOCALHOST).udp4(10188);
+        builder
+    };
+
+    // The key is generated on first use and reloaded afterwards.
+    let discv5: Discv5 =
+        Discv5::from_key_file(&key_file, b"passphrase", enr_builder(), config()).unwrap();
+    let node_id = discv5.local_enr().node_id();
+    let discv5: Discv5 =
+        Discv5::from_key_file(&key_file, b"passphrase", enr_builder(), config()).unwrap();
+    assert_eq!(discv5.local_enr().node_id(), node_id);
+    assert_eq!(discv5.local_enr().udp4(), Some(10188));
+
+    assert!(matches!(
+        Discv5::<DefaultProtocolId>::from_key_file(&key_file, b"wrong", enr_builder(), config()),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_self_lookup_finds_peers_of_known_nodes() {
+    init();
+    let node = build_nodes_with(1, 10200, |builder| {
+        builder.self_lookup_interval(Some(std::time::Duration::from_millis(200)));
+    })
+    .await
+    .remove(0);
+    let node_key = Key::from(node.local_enr().node_id());
+    // The peer only returns nodes at the distances of the lookup target, our own node id.
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer_id = Key::from(NodeId::from(peer_key.public()));
+    let distance = peer_id.log2_distance(&node_key);
+    let far_key = loop {
+        let key = CombinedKey::generate_secp256k1();
+        if peer_id.log2_distance(&Key::from(NodeId::from(key.public()))) == distance {
+            break key;
+        }
+    };
+    let peers = build_nodes_from_keypairs(vec![peer_key, far_key], 10201).await;
+    // Only the nodes of the chain know each other, no lookups are started by the test.
+    node.add_enr(peers[0].local_enr()).unwrap();
+    peers[0].add_enr(peers[1].local_enr()).unwrap();
+
+    let target = peers[1].local_enr().node_id();
+    let found = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while !node.table_entries_id().contains(&target) {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(found.is_ok(), "The self-lookup didn't find the far node");
+}
+
+#[tokio::test]
+async fn test_fixed_external_address_is_revoked_without_incoming_connections() {
+    init();
+    let fixed = "192.0.2.1:30303".parse::<std::net::SocketAddrV4>().unwrap();
+    let node = build_nodes_with(1, 10203, |builder| {
+        builder
+            .external_address_policy(ExternalAddressPolicy::Fixed(fixed.into()))
+            .auto_nat_listen_duration(Some(std::time::Duration::from_millis(200)));
+    })
+    .await
+    .remove(0);
+    assert_eq!(node.local_enr().udp4_socket(), Some(fixed));
+
+    // No peer reaches us at the fixed address.
+    tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+    assert_eq!(node.local_enr().udp4_socket(), None);
 }
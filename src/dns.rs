@@ -0,0 +1,495 @@
+//! Resolution of [EIP-1459](https://eips.ethereum.org/EIPS/eip-1459) DNS node lists.
+//!
+//! A node list is a merkle tree of ENRs published as TXT records under a domain and signed by a
+//! known secp256k1 key. It is referenced by a URL of the form `enrtree://<key>@<domain>`, where
+//! `<key>` is the base32 encoded compressed public key of the signer.
+//!
+//! Resolution starts from the root record at the domain itself, which is verified against the
+//! public key in the URL. Every other record is requested by the base32 encoded hash of its
+//! content, so once the root is verified, the whole tree is authenticated. Only the ENR subtree
+//! is resolved. Links to other trees are not followed. Leaves that are not valid ENRs are skipped,
+//! so that a single bad entry does not keep the rest of the tree from being used.
+//!
+//! Discovered ENRs are added to the routing table via [`crate::Discv5::add_dns_tree`].
+use crate::{error::DnsError, Enr};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use enr::k256::ecdsa::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+use hickory_resolver::TokioAsyncResolver;
+use sha3::{Digest, Keccak256};
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+const LINK_PREFIX: &str = "enrtree://";
+const ROOT_PREFIX: &str = "enrtree-root:v1";
+const BRANCH_PREFIX: &str = "enrtree-branch:";
+const ENR_PREFIX: &str = "enr:";
+
+/// The maximum number of records resolved for a single tree. This bounds the work a malicious or
+/// misconfigured tree can cause.
+const MAX_TREE_RECORDS: usize = 10_000;
+
+/// The RFC 4648 base32 alphabet used for public keys and record hashes.
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A parsed `enrtree://` URL.
+#[derive(Debug, Clone)]
+pub struct DnsTreeUrl {
+    /// The key the tree root must be signed with.
+    public_key: VerifyingKey,
+    /// The domain the tree is published under.
+    domain: String,
+}
+
+impl DnsTreeUrl {
+    /// The domain the tree is published under.
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+}
+
+impl FromStr for DnsTreeUrl {
+    type Err = DnsError;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let invalid = || DnsError::InvalidUrl(url.to_string());
+        let (key, domain) = url
+            .strip_prefix(LINK_PREFIX)
+            .and_then(|rest| rest.split_once('@'))
+            .ok_or_else(invalid)?;
+        if domain.is_empty() {
+            return Err(invalid());
+        }
+        let public_key = base32_decode(key)
+            .and_then(|key| VerifyingKey::from_sec1_bytes(&key).ok())
+            .ok_or_else(invalid)?;
+        Ok(DnsTreeUrl {
+            public_key,
+            domain: domain.to_string(),
+        })
+    }
+}
+
+/// The verified root record of a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeRoot {
+    /// The hash of the root of the ENR subtree.
+    pub enr_root: String,
+    /// The hash of the root of the link subtree.
+    pub link_root: String,
+    /// The sequence number of the tree, incremented by the publisher on every update.
+    pub seq: u64,
+}
+
+impl TreeRoot {
+    /// Parses a root record and verifies its signature against the given key.
+    fn parse(record: &str, public_key: &VerifyingKey) -> Result<Self, DnsError> {
+        let invalid = || DnsError::InvalidRoot(record.to_string());
+        let (signed, signature) = record.split_once(" sig=").ok_or_else(invalid)?;
+
+        let mut fields = signed.split(' ');
+        if fields.next() != Some(ROOT_PREFIX) {
+            return Err(invalid());
+        }
+        let mut field = |name: &str| {
+            fields
+                .next()
+                .and_then(|field| field.strip_prefix(name))
+                .ok_or_else(invalid)
+        };
+        let enr_root = field("e=")?.to_string();
+        let link_root = field("l=")?.to_string();
+        let seq = field("seq=")?.parse().map_err(|_| invalid())?;
+
+        // The signature is 65 bytes, `r || s || v`. The recovery id is not needed, as the key is
+        // known.
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature.trim_end_matches('='))
+            .map_err(|_| DnsError::InvalidSignature)?;
+        if signature.len() != 65 {
+            return Err(DnsError::InvalidSignature);
+        }
+        let signature =
+            Signature::from_slice(&signature[..64]).map_err(|_| DnsError::InvalidSignature)?;
+        let signature = signature.normalize_s().unwrap_or(signature);
+        public_key
+            .verify_prehash(&Keccak256::digest(signed.as_bytes()), &signature)
+            .map_err(|_| DnsError::InvalidSignature)?;
+
+        Ok(TreeRoot {
+            enr_root,
+            link_root,
+            seq,
+        })
+    }
+}
+
+/// Resolves the ENRs of the tree at `url`, using `lookup` to fetch the TXT record of a domain
+/// name.
+///
+/// If `known_seq` matches the sequence number of the tree root, the tree is unchanged and
+/// `Ok(None)` is returned without resolving the remaining records.
+pub async fn resolve_tree<F, Fut>(
+    url: &DnsTreeUrl,
+    lookup: F,
+    known_seq: Option<u64>,
+) -> Result<Option<(TreeRoot, Vec<Enr>)>, DnsError>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<String, DnsError>>,
+{
+    let root = TreeRoot::parse(&lookup(url.domain.clone()).await?, &url.public_key)?;
+    if known_seq == Some(root.seq) {
+        return Ok(None);
+    }
+
+    let mut enrs = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending = VecDeque::from([root.enr_root.clone()]);
+    while let Some(hash) = pending.pop_front() {
+        if !seen.insert(hash.to_ascii_uppercase()) {
+            continue;
+        }
+        if seen.len() > MAX_TREE_RECORDS {
+            return Err(DnsError::TooManyRecords);
+        }
+
+        let record = lookup(format!("{}.{}", hash, url.domain)).await?;
+        if !hash.eq_ignore_ascii_case(&record_hash(&record)) {
+            return Err(DnsError::HashMismatch(hash));
+        }
+
+        if let Some(children) = record.strip_prefix(BRANCH_PREFIX) {
+            pending.extend(
+                children
+                    .split(',')
+                    .filter(|child| !child.is_empty())
+                    .map(str::to_string),
+            );
+        } else {
+            match record
+                .strip_prefix(ENR_PREFIX)
+                .and_then(|_| record.parse::<Enr>().ok())
+            {
+                Some(enr) => enrs.push(enr),
+                None => warn!(
+                    domain = url.domain,
+                    record, "Skipping invalid DNS tree leaf"
+                ),
+            }
+        }
+    }
+
+    Ok(Some((root, enrs)))
+}
+
+/// Resolves the tree at `url` every `resync_interval` until `exit` is signalled or its sender is
+/// dropped. After every attempt, `add` is passed the ENRs of a newer version of the tree, or none
+/// if the tree is unchanged or could not be resolved. The sync stops once `add` returns false.
+pub(crate) async fn sync_tree<F, Fut>(
+    url: &DnsTreeUrl,
+    lookup: F,
+    resync_interval: Duration,
+    mut exit: watch::Receiver<()>,
+    mut add: impl FnMut(Vec<Enr>) -> bool,
+) where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<String, DnsError>>,
+{
+    let mut resync = tokio::time::interval(resync_interval);
+    let mut known_seq = None;
+    loop {
+        tokio::select! {
+            biased;
+            _ = exit.changed() => return,
+            _ = resync.tick() => {}
+        }
+        let resolved = tokio::select! {
+            biased;
+            _ = exit.changed() => return,
+            resolved = resolve_tree(url, &lookup, known_seq) => resolved,
+        };
+        let enrs = match resolved {
+            Ok(Some((root, enrs))) => {
+                debug!(
+                    domain = url.domain(),
+                    seq = root.seq,
+                    enrs = enrs.len(),
+                    "Resolved DNS tree"
+                );
+                known_seq = Some(root.seq);
+                enrs
+            }
+            Ok(None) => Vec::new(),
+            Err(error) => {
+                warn!(domain = url.domain(), %error, "Failed to resolve DNS tree");
+                Vec::new()
+            }
+        };
+        if !add(enrs) {
+            return;
+        }
+    }
+}
+
+/// Fetches the TXT record of `name`. Records split into multiple strings are concatenated.
+pub async fn txt_lookup(resolver: &TokioAsyncResolver, name: String) -> Result<String, DnsError> {
+    let lookup = resolver
+        .txt_lookup(name.as_str())
+        .await
+        .map_err(|e| DnsError::Lookup(e.to_string()))?;
+    lookup
+        .iter()
+        .next()
+        .map(|txt| {
+            txt.txt_data()
+                .iter()
+                .map(|data| String::from_utf8_lossy(data))
+                .collect()
+        })
+        .ok_or(DnsError::Lookup(format!("No TXT record at {name}")))
+}
+
+/// The subdomain a record is published under: the base32 encoding of the first 16 bytes of the
+/// keccak256 hash of its content.
+fn record_hash(record: &str) -> String {
+    base32_encode(&Keccak256::digest(record.as_bytes())[..16])
+}
+
+/// Encodes bytes as unpadded base32.
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[usize::from((buffer >> bits) & 0x1f)] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[usize::from((buffer << (5 - bits)) & 0x1f)] as char);
+    }
+    encoded
+}
+
+/// Decodes unpadded base32, ignoring case. Returns `None` on invalid characters.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::{k256::ecdsa::SigningKey, CombinedKey};
+    use std::collections::HashMap;
+
+    /// Publishes a tree of the given ENRs under `domain`, returning its URL and records.
+    fn publish(
+        signer: &SigningKey,
+        domain: &str,
+        enrs: &[Enr],
+        seq: u64,
+    ) -> (String, HashMap<String, String>) {
+        let leaves: Vec<String> = enrs.iter().map(Enr::to_base64).collect();
+        publish_leaves(signer, domain, &leaves, seq)
+    }
+
+    /// Publishes a tree of the given leaf records under `domain`, returning its URL and records.
+    fn publish_leaves(
+        signer: &SigningKey,
+        domain: &str,
+        leaves: &[String],
+        seq: u64,
+    ) -> (String, HashMap<String, String>) {
+        let mut records = HashMap::new();
+        let mut publish_record = |record: String| {
+            let hash = record_hash(&record);
+            records.insert(format!("{hash}.{domain}"), record);
+            hash
+        };
+
+        let leaves: Vec<String> = leaves
+            .iter()
+            .map(|leaf| publish_record(leaf.clone()))
+            .collect();
+        let enr_root = publish_record(format!("{BRANCH_PREFIX}{}", leaves.join(",")));
+        let link_root = publish_record(BRANCH_PREFIX.to_string());
+
+        let signed = format!("{ROOT_PREFIX} e={enr_root} l={link_root} seq={seq}");
+        let (signature, recovery_id) = signer
+            .sign_prehash_recoverable(&Keccak256::digest(signed.as_bytes()))
+            .unwrap();
+        let mut signature = signature.to_vec();
+        signature.push(recovery_id.to_byte());
+        records.insert(
+            domain.to_string(),
+            format!("{signed} sig={}", URL_SAFE_NO_PAD.encode(signature)),
+        );
+
+        let public_key = signer.verifying_key().to_encoded_point(true);
+        let url = format!(
+            "{LINK_PREFIX}{}@{domain}",
+            base32_encode(public_key.as_bytes())
+        );
+        (url, records)
+    }
+
+    fn random_enr(port: u16) -> Enr {
+        let key = CombinedKey::generate_secp256k1();
+        Enr::builder()
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(port)
+            .build(&key)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_base32_roundtrip() {
+        let data: Vec<u8> = (0..=32).collect();
+        for len in 0..data.len() {
+            let encoded = base32_encode(&data[..len]);
+            assert_eq!(base32_decode(&encoded).unwrap(), &data[..len]);
+            assert_eq!(
+                base32_decode(&encoded.to_ascii_lowercase()).unwrap(),
+                &data[..len]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_tree() {
+        let signer = SigningKey::random(&mut rand::thread_rng());
+        let enrs = vec![random_enr(9000), random_enr(9001), random_enr(9002)];
+        let (url, records) = publish(&signer, "nodes.example.org", &enrs, 3);
+        let url: DnsTreeUrl = url.parse().unwrap();
+
+        let lookup = |name: String| {
+            let record = records.get(&name).cloned().ok_or(DnsError::Lookup(name));
+            async move { record }
+        };
+
+        let (root, resolved) = resolve_tree(&url, &lookup, None).await.unwrap().unwrap();
+        assert_eq!(root.seq, 3);
+        assert_eq!(resolved, enrs);
+
+        // An unchanged tree is not resolved again.
+        assert!(resolve_tree(&url, &lookup, Some(3))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reject_invalid_tree() {
+        let signer = SigningKey::random(&mut rand::thread_rng());
+        let other_signer = SigningKey::random(&mut rand::thread_rng());
+        let enrs = vec![random_enr(9000)];
+        let (url, _) = publish(&signer, "nodes.example.org", &enrs, 1);
+        let url: DnsTreeUrl = url.parse().unwrap();
+
+        // A tree signed by another key is rejected.
+        let (_, forged) = publish(&other_signer, "nodes.example.org", &enrs, 1);
+        let lookup = |name: String| {
+            let record = forged.get(&name).cloned().ok_or(DnsError::Lookup(name));
+            async move { record }
+        };
+        assert_eq!(
+            resolve_tree(&url, &lookup, None).await.unwrap_err(),
+            DnsError::InvalidSignature
+        );
+
+        // A record that does not match its hash is rejected.
+        let (_, mut records) = publish(&signer, "nodes.example.org", &enrs, 1);
+        let leaf = records
+            .values_mut()
+            .find(|record| record.starts_with(ENR_PREFIX))
+            .unwrap();
+        *leaf = random_enr(9001).to_base64();
+        let lookup = |name: String| {
+            let record = records.get(&name).cloned().ok_or(DnsError::Lookup(name));
+            async move { record }
+        };
+        assert!(matches!(
+            resolve_tree(&url, &lookup, None).await,
+            Err(DnsError::HashMismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_skip_invalid_leaves() {
+        let signer = SigningKey::random(&mut rand::thread_rng());
+        let enrs = vec![random_enr(9000), random_enr(9001)];
+        let leaves = vec![
+            enrs[0].to_base64(),
+            "enr:invalid".to_string(),
+            "unknown:record".to_string(),
+            enrs[1].to_base64(),
+        ];
+        let (url, records) = publish_leaves(&signer, "nodes.example.org", &leaves, 1);
+        let url: DnsTreeUrl = url.parse().unwrap();
+        let lookup = |name: String| {
+            let record = records.get(&name).cloned().ok_or(DnsError::Lookup(name));
+            async move { record }
+        };
+
+        let (_, resolved) = resolve_tree(&url, &lookup, None).await.unwrap().unwrap();
+        assert_eq!(resolved, enrs);
+    }
+
+    #[tokio::test]
+    async fn test_sync_tree_stops() {
+        let signer = SigningKey::random(&mut rand::thread_rng());
+        let enrs = vec![random_enr(9000)];
+        let (url, records) = publish(&signer, "nodes.example.org", &enrs, 1);
+        let url: DnsTreeUrl = url.parse().unwrap();
+        let lookup = |name: String| {
+            let record = records.get(&name).cloned().ok_or(DnsError::Lookup(name));
+            async move { record }
+        };
+        let interval = Duration::from_millis(10);
+
+        // The sync stops once the ENRs can't be added anymore.
+        let (_exit, exit_recv) = watch::channel(());
+        let mut added = Vec::new();
+        let mut attempts = 0;
+        sync_tree(&url, &lookup, interval, exit_recv, |enrs| {
+            added.extend(enrs);
+            attempts += 1;
+            attempts < 3
+        })
+        .await;
+        // The tree is only added again once it changes.
+        assert_eq!(added, enrs);
+        assert_eq!(attempts, 3);
+
+        // And once the exit is signalled, or its sender dropped.
+        let (exit, exit_recv) = watch::channel(());
+        exit.send_replace(());
+        sync_tree(&url, &lookup, interval, exit_recv, |_| true).await;
+        let (exit, exit_recv) = watch::channel(());
+        drop(exit);
+        sync_tree(&url, &lookup, interval, exit_recv, |_| true).await;
+    }
+}
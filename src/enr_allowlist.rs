@@ -0,0 +1,67 @@
+//! Restricts the nodes we admit to the routing table and establish sessions with, for
+//! permissioned networks.
+use crate::Enr;
+use enr::{CombinedPublicKey, NodeId};
+use std::{collections::HashSet, fmt, sync::Arc};
+
+/// The set of ENRs admitted to the routing table and to sessions. ENRs that are not allowed are
+/// never inserted into the table, and handshakes with their nodes are rejected.
+#[derive(Clone)]
+pub enum EnrAllowlist {
+    /// Only ENRs signed by one of these keys are allowed. The keys are stored as the node ids
+    /// derived from them, as a valid ENR is always signed by the key its node id is derived from.
+    Keys(HashSet<NodeId>),
+    /// Only ENRs for which the callback returns `true` are allowed.
+    Callback(Arc<dyn Fn(&Enr) -> bool + Send + Sync>),
+}
+
+impl EnrAllowlist {
+    /// Allows the ENRs signed by any of the given public keys.
+    pub fn from_public_keys(public_keys: impl IntoIterator<Item = CombinedPublicKey>) -> Self {
+        EnrAllowlist::Keys(public_keys.into_iter().map(NodeId::from).collect())
+    }
+
+    /// Allows the ENRs for which the callback returns `true`.
+    pub fn from_callback(callback: impl Fn(&Enr) -> bool + Send + Sync + 'static) -> Self {
+        EnrAllowlist::Callback(Arc::new(callback))
+    }
+
+    /// Returns whether the ENR is allowed.
+    pub fn allows(&self, enr: &Enr) -> bool {
+        match self {
+            EnrAllowlist::Keys(node_ids) => node_ids.contains(&enr.node_id()),
+            EnrAllowlist::Callback(callback) => callback(enr),
+        }
+    }
+}
+
+impl fmt::Debug for EnrAllowlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnrAllowlist::Keys(node_ids) => f.debug_tuple("Keys").field(node_ids).finish(),
+            EnrAllowlist::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::{CombinedKey, EnrKey};
+
+    #[test]
+    fn test_allowlist() {
+        let allowed_key = CombinedKey::generate_secp256k1();
+        let other_key = CombinedKey::generate_secp256k1();
+        let allowed = Enr::builder().build(&allowed_key).unwrap();
+        let other = Enr::builder().udp4(9000).build(&other_key).unwrap();
+
+        let allowlist = EnrAllowlist::from_public_keys([allowed_key.public()]);
+        assert!(allowlist.allows(&allowed));
+        assert!(!allowlist.allows(&other));
+
+        let allowlist = EnrAllowlist::from_callback(|enr| enr.udp4().is_some());
+        assert!(!allowlist.allows(&allowed));
+        assert!(allowlist.allows(&other));
+    }
+}
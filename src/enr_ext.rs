@@ -0,0 +1,261 @@
+//! Typed accessors for ENR fields that are commonly used by discv5 consumers but are not part of
+//! the core ENR specification.
+//!
+//! The [`EnrExt`] trait decodes these fields from any [`Enr`]. The corresponding setters for the
+//! local ENR are provided by [`crate::Discv5`].
+//!
+//! With the `libp2p` feature, ENRs can also be converted to libp2p `PeerId`s and `Multiaddr`s.
+use crate::Enr;
+use alloy_rlp::Bytes;
+
+#[cfg(feature = "libp2p")]
+use enr::{CombinedPublicKey, EnrPublicKey, NodeId};
+#[cfg(feature = "libp2p")]
+use libp2p_identity::{ed25519, secp256k1, KeyType, PeerId, PublicKey};
+#[cfg(feature = "libp2p")]
+use multiaddr::{Multiaddr, Protocol};
+
+/// The ENR key of the IPv4 QUIC port.
+pub const QUIC_ENR_KEY: &str = "quic";
+/// The ENR key of the IPv6 QUIC port.
+pub const QUIC6_ENR_KEY: &str = "quic6";
+/// The ENR key of the Ethereum consensus layer fork id.
+pub const ETH2_ENR_KEY: &str = "eth2";
+/// The ENR key of the Ethereum execution layer fork id.
+pub const ETH_ENR_KEY: &str = "eth";
+/// The ENR key advertising support for session resumption, see
+/// [`crate::Config::session_resumption`]. Specific to this implementation.
+pub const RESUMPTION_ENR_KEY: &str = "resume";
+
+/// Typed getters for common ENR fields. Fields that are absent or fail to decode are returned as
+/// `None`.
+pub trait EnrExt {
+    /// The TCP port, preferring the IPv4 port if both are set.
+    fn tcp_port(&self) -> Option<u16>;
+
+    /// The UDP port, preferring the IPv4 port if both are set.
+    fn udp_port(&self) -> Option<u16>;
+
+    /// The IPv4 QUIC port.
+    fn quic4(&self) -> Option<u16>;
+
+    /// The IPv6 QUIC port.
+    fn quic6(&self) -> Option<u16>;
+
+    /// The QUIC port, preferring the IPv4 port if both are set.
+    fn quic_port(&self) -> Option<u16>;
+
+    /// The raw bytes of the `eth2` field.
+    fn eth2(&self) -> Option<Vec<u8>>;
+
+    /// Decodes an arbitrary field stored as an RLP byte string.
+    fn get_bytes(&self, key: &str) -> Option<Vec<u8>>;
+
+    /// The libp2p `PeerId` of the node, derived from the ENR's public key.
+    #[cfg(feature = "libp2p")]
+    fn peer_id(&self) -> PeerId;
+
+    /// The discv5 UDP addresses of the node, each ending in its `/p2p` component.
+    #[cfg(feature = "libp2p")]
+    fn multiaddr_udp(&self) -> Vec<Multiaddr>;
+
+    /// The libp2p transport addresses of the node, TCP and QUIC, each ending in its `/p2p`
+    /// component. These are the addresses a libp2p swarm can dial.
+    #[cfg(feature = "libp2p")]
+    fn multiaddr_libp2p(&self) -> Vec<Multiaddr>;
+}
+
+impl EnrExt for Enr {
+    fn tcp_port(&self) -> Option<u16> {
+        self.tcp4().or_else(|| self.tcp6())
+    }
+
+    fn udp_port(&self) -> Option<u16> {
+        self.udp4().or_else(|| self.udp6())
+    }
+
+    fn quic4(&self) -> Option<u16> {
+        self.get_decodable(QUIC_ENR_KEY).and_then(Result::ok)
+    }
+
+    fn quic6(&self) -> Option<u16> {
+        self.get_decodable(QUIC6_ENR_KEY).and_then(Result::ok)
+    }
+
+    fn quic_port(&self) -> Option<u16> {
+        self.quic4().or_else(|| self.quic6())
+    }
+
+    fn eth2(&self) -> Option<Vec<u8>> {
+        self.get_bytes(ETH2_ENR_KEY)
+    }
+
+    fn get_bytes(&self, key: &str) -> Option<Vec<u8>> {
+        self.get_decodable::<Bytes>(key)
+            .and_then(Result::ok)
+            .map(|bytes| bytes.to_vec())
+    }
+
+    #[cfg(feature = "libp2p")]
+    fn peer_id(&self) -> PeerId {
+        libp2p_public_key(&self.public_key()).to_peer_id()
+    }
+
+    #[cfg(feature = "libp2p")]
+    fn multiaddr_udp(&self) -> Vec<Multiaddr> {
+        let peer_id = self.peer_id();
+        let mut multiaddrs = Vec::new();
+        if let Some(socket) = self.udp4_socket() {
+            multiaddrs.push(
+                Multiaddr::from(*socket.ip())
+                    .with(Protocol::Udp(socket.port()))
+                    .with(Protocol::P2p(peer_id)),
+            );
+        }
+        if let Some(socket) = self.udp6_socket() {
+            multiaddrs.push(
+                Multiaddr::from(*socket.ip())
+                    .with(Protocol::Udp(socket.port()))
+                    .with(Protocol::P2p(peer_id)),
+            );
+        }
+        multiaddrs
+    }
+
+    #[cfg(feature = "libp2p")]
+    fn multiaddr_libp2p(&self) -> Vec<Multiaddr> {
+        let peer_id = self.peer_id();
+        let mut multiaddrs = Vec::new();
+        if let Some(ip) = self.ip4() {
+            if let Some(tcp) = self.tcp4() {
+                multiaddrs.push(Multiaddr::from(ip).with(Protocol::Tcp(tcp)));
+            }
+            if let Some(quic) = self.quic4() {
+                multiaddrs.push(
+                    Multiaddr::from(ip)
+                        .with(Protocol::Udp(quic))
+                        .with(Protocol::QuicV1),
+                );
+            }
+        }
+        if let Some(ip) = self.ip6() {
+            if let Some(tcp) = self.tcp6() {
+                multiaddrs.push(Multiaddr::from(ip).with(Protocol::Tcp(tcp)));
+            }
+            if let Some(quic) = self.quic6() {
+                multiaddrs.push(
+                    Multiaddr::from(ip)
+                        .with(Protocol::Udp(quic))
+                        .with(Protocol::QuicV1),
+                );
+            }
+        }
+        multiaddrs
+            .into_iter()
+            .map(|multiaddr| multiaddr.with(Protocol::P2p(peer_id)))
+            .collect()
+    }
+}
+
+/// Converts an ENR public key to the equivalent libp2p public key.
+#[cfg(feature = "libp2p")]
+pub fn libp2p_public_key(public_key: &CombinedPublicKey) -> PublicKey {
+    match public_key {
+        CombinedPublicKey::Secp256k1(_) => {
+            secp256k1::PublicKey::try_from_bytes(&public_key.encode())
+                .expect("Libp2p key conversion, always valid")
+                .into()
+        }
+        CombinedPublicKey::Ed25519(_) => ed25519::PublicKey::try_from_bytes(&public_key.encode())
+            .expect("Libp2p key conversion, always valid")
+            .into(),
+    }
+}
+
+/// Recovers the ENR public key from a libp2p `PeerId`. Only `secp256k1` and `ed25519` keys, which
+/// are inlined in their peer ids, are supported.
+#[cfg(feature = "libp2p")]
+pub fn public_key_from_peer_id(peer_id: &PeerId) -> Result<CombinedPublicKey, &'static str> {
+    let pk = PublicKey::try_decode_protobuf(&peer_id.to_bytes()[2..])
+        .map_err(|_| "Invalid public key")?;
+    match pk.key_type() {
+        KeyType::Secp256k1 => Ok(enr::k256::ecdsa::VerifyingKey::from_sec1_bytes(
+            &pk.try_into_secp256k1()
+                .expect("Must be secp256k1")
+                .to_bytes_uncompressed(),
+        )
+        .expect("Libp2p key conversion, always valid")
+        .into()),
+        KeyType::Ed25519 => Ok(enr::ed25519_dalek::VerifyingKey::from_bytes(
+            &pk.try_into_ed25519().expect("Must be ed25519").to_bytes(),
+        )
+        .expect("Libp2p key conversion, always valid")
+        .into()),
+        _ => Err("The key type is not supported"),
+    }
+}
+
+/// The discv5 `NodeId` of the node with the given libp2p `PeerId`.
+#[cfg(feature = "libp2p")]
+pub fn peer_id_to_node_id(peer_id: &PeerId) -> Result<NodeId, &'static str> {
+    public_key_from_peer_id(peer_id).map(NodeId::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::CombinedKey;
+
+    #[test]
+    fn test_typed_fields() {
+        let key = CombinedKey::generate_secp256k1();
+        let mut enr = Enr::builder().udp6(9000).tcp4(9001).build(&key).unwrap();
+        assert_eq!(enr.udp_port(), Some(9000));
+        assert_eq!(enr.tcp_port(), Some(9001));
+        assert_eq!(enr.quic_port(), None);
+        assert_eq!(enr.eth2(), None);
+
+        enr.insert(QUIC6_ENR_KEY, &9002u16, &key).unwrap();
+        enr.insert(ETH2_ENR_KEY, &[1u8, 2, 3, 4].as_slice(), &key)
+            .unwrap();
+        assert_eq!(enr.quic4(), None);
+        assert_eq!(enr.quic_port(), Some(9002));
+        assert_eq!(enr.eth2(), Some(vec![1, 2, 3, 4]));
+    }
+
+    #[cfg(feature = "libp2p")]
+    #[test]
+    fn test_libp2p_conversions() {
+        for key in [
+            CombinedKey::generate_secp256k1(),
+            CombinedKey::generate_ed25519(),
+        ] {
+            let mut enr = Enr::builder()
+                .ip4("10.0.0.1".parse().unwrap())
+                .udp4(9000)
+                .tcp4(9001)
+                .build(&key)
+                .unwrap();
+            enr.insert(QUIC_ENR_KEY, &9002u16, &key).unwrap();
+
+            let peer_id = enr.peer_id();
+            assert_eq!(peer_id_to_node_id(&peer_id), Ok(enr.node_id()));
+
+            let udp: Vec<String> = enr.multiaddr_udp().iter().map(|m| m.to_string()).collect();
+            assert_eq!(udp, vec![format!("/ip4/10.0.0.1/udp/9000/p2p/{peer_id}")]);
+
+            let libp2p: Vec<String> = enr
+                .multiaddr_libp2p()
+                .iter()
+                .map(|m| m.to_string())
+                .collect();
+            assert_eq!(
+                libp2p,
+                vec![
+                    format!("/ip4/10.0.0.1/tcp/9001/p2p/{peer_id}"),
+                    format!("/ip4/10.0.0.1/udp/9002/quic-v1/p2p/{peer_id}"),
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! A store of recently seen ENRs, independent of the routing table.
+//!
+//! The routing table only holds a bounded number of nodes per bucket and evicts nodes as they
+//! become unresponsive. The [`EnrStore`] retains every ENR we have seen, from NODES responses and
+//! established sessions, for a configurable duration. This lets applications look up what we
+//! know about a node after it has left the routing table.
+use crate::{lru_time_cache::LruTimeCache, Enr};
+use enr::NodeId;
+use std::{sync::Arc, time::Duration};
+
+/// The most recent ENRs of the nodes we have heard of, bounded in size and age.
+pub struct EnrStore {
+    /// The stored ENRs, shared with the routing table. The least recently seen ENR is evicted
+    /// when the store is full.
+    enrs: LruTimeCache<NodeId, Arc<Enr>>,
+}
+
+impl EnrStore {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        EnrStore {
+            enrs: LruTimeCache::new(ttl, Some(capacity)),
+        }
+    }
+
+    /// Records that an ENR has been seen. A stored ENR is only replaced by one with an equal or
+    /// greater sequence number, but seeing an older record still refreshes its TTL.
+    pub fn insert(&mut self, enr: impl Into<Arc<Enr>>) {
+        let enr = enr.into();
+        let node_id = enr.node_id();
+        match self.enrs.get_mut(&node_id) {
+            Some(stored) => {
+                if stored.seq() <= enr.seq() {
+                    *stored = enr;
+                }
+            }
+            None => self.enrs.insert(node_id, enr),
+        }
+    }
+
+    /// Returns the stored ENR of a node, if it has not expired.
+    pub fn get(&self, node_id: &NodeId) -> Option<&Enr> {
+        self.enrs.peek(node_id).map(|enr| &**enr)
+    }
+
+    /// Returns all stored ENRs that match the predicate.
+    pub fn filter(&self, predicate: impl Fn(&Enr) -> bool) -> Vec<Enr> {
+        self.enrs
+            .iter()
+            .map(|(_, enr)| &**enr)
+            .filter(|enr| predicate(enr))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::CombinedKey;
+
+    #[test]
+    fn keeps_highest_sequence_number() {
+        let key = CombinedKey::generate_secp256k1();
+        let mut enr = Enr::builder().build(&key).unwrap();
+        let old_enr = enr.clone();
+        enr.set_udp4(9000, &key).unwrap();
+
+        let mut store = EnrStore::new(10, Duration::from_secs(10));
+        store.insert(enr.clone());
+        store.insert(old_enr);
+
+        assert_eq!(store.get(&enr.node_id()), Some(&enr));
+    }
+
+    #[test]
+    fn filters_by_predicate() {
+        let mut store = EnrStore::new(10, Duration::from_secs(10));
+        for port in 9000..9004 {
+            let key = CombinedKey::generate_secp256k1();
+            let enr = Enr::builder().udp4(port).build(&key).unwrap();
+            store.insert(enr);
+        }
+
+        let found = store.filter(|enr| enr.udp4() >= Some(9002));
+        assert_eq!(found.len(), 2);
+    }
+}
@@ -0,0 +1,125 @@
+//! Subscriptions to individual fields of ENRs.
+//!
+//! Applications tracking a field such as the `eth2` fork id would otherwise have to compare full
+//! ENRs on every discovery event. Watching a key with [`crate::Discv5::watch_enr_key`] reports
+//! each change of that field in the local ENR and in the ENRs of the routing table, as an
+//! [`EnrKeyChange`].
+use crate::Enr;
+use enr::NodeId;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+
+/// The number of changes buffered per subscriber. A subscriber that falls further behind skips
+/// the oldest changes.
+const ENR_WATCH_CAPACITY: usize = 256;
+
+/// A watched field of an ENR has been set, changed or removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnrKeyChange {
+    /// The node whose ENR changed, which is the local node for changes of the local ENR.
+    pub node_id: NodeId,
+    /// The watched key.
+    pub key: String,
+    /// The RLP encoded value of the field in the updated ENR, or `None` if it was removed.
+    pub value: Option<Vec<u8>>,
+    /// The sequence number of the updated ENR.
+    pub seq: u64,
+}
+
+/// Publishes the changes of the watched ENR keys to their subscribers.
+#[derive(Default)]
+pub struct EnrWatch {
+    watched: RwLock<HashMap<String, broadcast::Sender<EnrKeyChange>>>,
+}
+
+impl EnrWatch {
+    /// Subscribes to the changes of the key. The key is watched until every subscriber has been
+    /// dropped.
+    pub fn watch(&self, key: String) -> broadcast::Receiver<EnrKeyChange> {
+        let mut watched = self.watched.write();
+        watched.retain(|_, sender| sender.receiver_count() > 0);
+        watched
+            .entry(key)
+            .or_insert_with(|| broadcast::channel(ENR_WATCH_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Whether any key is watched.
+    pub fn is_watching(&self) -> bool {
+        self.watched
+            .read()
+            .values()
+            .any(|sender| sender.receiver_count() > 0)
+    }
+
+    /// Reports the changes of the watched keys from the `previous` to the `current` ENR of a node.
+    /// Nothing is reported unless `current` is the newer record.
+    pub fn compare(&self, previous: &Enr, current: &Enr) {
+        if current.seq() <= previous.seq() {
+            return;
+        }
+        for (key, sender) in self.watched.read().iter() {
+            let value = current.get_raw_rlp(key.as_bytes());
+            if sender.receiver_count() == 0 || previous.get_raw_rlp(key.as_bytes()) == value {
+                continue;
+            }
+            let _ = sender.send(EnrKeyChange {
+                node_id: current.node_id(),
+                key: key.clone(),
+                value: value.map(<[u8]>::to_vec),
+                seq: current.seq(),
+            });
+        }
+    }
+
+    /// Applies `update` to the ENR, reporting the changes of the watched keys.
+    pub fn update<T>(&self, enr: &RwLock<Enr>, update: impl FnOnce(&mut Enr) -> T) -> T {
+        let mut enr = enr.write();
+        let previous = self.is_watching().then(|| enr.clone());
+        let result = update(&mut enr);
+        if let Some(previous) = previous {
+            self.compare(&previous, &enr);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::CombinedKey;
+
+    #[test]
+    fn reports_changes_of_watched_keys() {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = RwLock::new(Enr::builder().build(&key).unwrap());
+        let watch = EnrWatch::default();
+        let mut eth2 = watch.watch("eth2".into());
+
+        watch.update(&enr, |enr| enr.insert("other", &1u8, &key).unwrap());
+        watch.update(&enr, |enr| enr.insert("eth2", &2u8, &key).unwrap());
+        // Setting the same value again changes nothing.
+        watch.update(&enr, |enr| enr.insert("eth2", &2u8, &key).unwrap());
+        watch.update(&enr, |enr| {
+            enr.remove_insert(
+                std::iter::once("eth2"),
+                std::iter::empty::<(&str, &[u8])>(),
+                &key,
+            )
+            .unwrap()
+        });
+
+        let set = eth2.try_recv().unwrap();
+        assert_eq!(set.node_id, enr.read().node_id());
+        assert_eq!(set.value, Some(vec![2]));
+        assert_eq!(set.seq, 3);
+        let removed = eth2.try_recv().unwrap();
+        assert_eq!(removed.value, None);
+        assert_eq!(removed.seq, enr.read().seq());
+        assert!(eth2.try_recv().is_err());
+
+        drop(eth2);
+        assert!(!watch.is_watching());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{handler::Challenge, node_info::NonContactable};
 use alloy_rlp::Error as DecoderError;
+use enr::NodeId;
 use std::fmt;
 
 #[derive(Debug)]
@@ -75,6 +76,8 @@ pub enum PacketError {
 pub enum ResponseError {
     /// The channel used to send the response has already been closed.
     ChannelClosed,
+    /// The handler's queue was full and the response was dropped.
+    QueueFull,
 }
 
 impl fmt::Display for ResponseError {
@@ -83,12 +86,38 @@ impl fmt::Display for ResponseError {
             ResponseError::ChannelClosed => {
                 write!(f, "response channel has already been closed")
             }
+            ResponseError::QueueFull => {
+                write!(f, "response dropped as the handler queue is full")
+            }
         }
     }
 }
 
 impl std::error::Error for ResponseError {}
 
+/// The broad cause of a [`RequestError`] or [`QueryError`], to decide whether to retry and whom
+/// to blame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The request was invalid or the local node is not in a state to send it. Retrying does not
+    /// help until the caller fixes the cause.
+    LocalMisconfiguration,
+    /// The network or the local node was temporarily unable to carry the request. Retrying may
+    /// succeed.
+    TransientNetwork,
+    /// The peer sent something it should not have, such as an ENR it is not allowed to use.
+    PeerMisbehavior,
+    /// The peer sent packets that do not follow the protocol.
+    ProtocolViolation,
+}
+
+impl ErrorCategory {
+    /// Whether retrying the same request may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorCategory::TransientNetwork)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RequestError {
     /// The request timed out.
@@ -101,16 +130,34 @@ pub enum RequestError {
     ChannelFailed(String),
     /// An invalid ENR was provided.
     InvalidEnr(&'static str),
-    /// The remote's ENR is invalid.
-    InvalidRemoteEnr,
-    /// The remote returned and invalid packet.
-    InvalidRemotePacket,
+    /// The ENR of the remote is invalid or not allowed.
+    InvalidRemoteEnr(NodeId),
+    /// The remote returned an invalid packet.
+    InvalidRemotePacket(NodeId),
     /// Failed attempting to encrypt the request.
     EncryptionFailed(String),
+    /// The keys of a session with the remote could not be generated in answer to its challenge.
+    SessionGenerationFailed(String),
     /// The multiaddr provided is invalid.
     InvalidMultiaddr(&'static str),
     /// Failure generating random numbers during request.
     EntropyFailure(&'static str),
+    /// The requested distances are empty or above 256.
+    InvalidDistances,
+}
+
+/// The failure of a TALK request made with [`crate::Discv5::talk`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TalkError {
+    /// The peer answered with an empty response. Peers answer requests of protocols they don't
+    /// serve with an empty response, so this usually means the protocol is unknown to the peer.
+    /// Protocols with empty responses of their own can accept them with
+    /// [`crate::Talk::allow_empty_response`].
+    EmptyResponse,
+    /// The peer didn't respond in time.
+    Timeout,
+    /// The request failed.
+    Request(RequestError),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -127,6 +174,83 @@ pub enum QueryError {
     InvalidMultiaddr(String),
 }
 
+impl RequestError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            RequestError::Timeout
+            | RequestError::ChannelFailed(_)
+            | RequestError::EncryptionFailed(_) => ErrorCategory::TransientNetwork,
+            RequestError::ServiceNotStarted
+            | RequestError::SelfRequest
+            | RequestError::InvalidEnr(_)
+            | RequestError::SessionGenerationFailed(_)
+            | RequestError::InvalidMultiaddr(_)
+            | RequestError::EntropyFailure(_)
+            | RequestError::InvalidDistances => ErrorCategory::LocalMisconfiguration,
+            RequestError::InvalidRemoteEnr(_) => ErrorCategory::PeerMisbehavior,
+            RequestError::InvalidRemotePacket(_) => ErrorCategory::ProtocolViolation,
+        }
+    }
+
+    /// Whether retrying the same request may succeed.
+    pub fn is_retryable(&self) -> bool {
+        self.category().is_retryable()
+    }
+
+    /// The peer at fault, for errors caused by the peer.
+    pub fn peer(&self) -> Option<NodeId> {
+        match self {
+            RequestError::InvalidRemoteEnr(node_id)
+            | RequestError::InvalidRemotePacket(node_id) => Some(*node_id),
+            _ => None,
+        }
+    }
+
+    /// Whether the peer failed the request, by not responding in time or by misbehaving, rather
+    /// than the request failing locally.
+    pub fn is_peer_failure(&self) -> bool {
+        matches!(self, RequestError::Timeout) || self.peer().is_some()
+    }
+}
+
+impl TalkError {
+    /// Whether retrying the same request may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TalkError::EmptyResponse => false,
+            TalkError::Timeout => true,
+            TalkError::Request(error) => error.is_retryable(),
+        }
+    }
+}
+
+impl From<RequestError> for TalkError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::Timeout => TalkError::Timeout,
+            error => TalkError::Request(error),
+        }
+    }
+}
+
+impl QueryError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            QueryError::ChannelFailed(_) | QueryError::EncryptionFailed(_) => {
+                ErrorCategory::TransientNetwork
+            }
+            QueryError::ServiceNotStarted
+            | QueryError::InvalidEnr(_)
+            | QueryError::InvalidMultiaddr(_) => ErrorCategory::LocalMisconfiguration,
+        }
+    }
+
+    /// Whether retrying the same query may succeed.
+    pub fn is_retryable(&self) -> bool {
+        self.category().is_retryable()
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{self:?}")
@@ -139,6 +263,12 @@ impl fmt::Display for RequestError {
     }
 }
 
+impl fmt::Display for TalkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
 impl fmt::Display for QueryError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{self:?}")
@@ -150,3 +280,173 @@ impl From<NonContactable> for RequestError {
         RequestError::InvalidEnr("ENR is not contactable")
     }
 }
+
+#[cfg(feature = "dns")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Errors that can occur when resolving an EIP-1459 DNS node list.
+pub enum DnsError {
+    /// The `enrtree://` URL is malformed or contains an invalid public key.
+    InvalidUrl(String),
+    /// The root record is malformed.
+    InvalidRoot(String),
+    /// The root record is not signed by the key in the tree URL.
+    InvalidSignature,
+    /// A record in the tree is malformed.
+    InvalidRecord(String),
+    /// A record does not match the hash it was requested by.
+    HashMismatch(String),
+    /// The tree exceeds the maximum number of records resolved per tree.
+    TooManyRecords,
+    /// The TXT record could not be resolved.
+    Lookup(String),
+    /// The system DNS resolver could not be configured.
+    Resolver(String),
+}
+
+#[cfg(feature = "dns")]
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::InvalidUrl(url) => write!(f, "invalid tree URL: {url}"),
+            DnsError::InvalidRoot(record) => write!(f, "invalid tree root: {record}"),
+            DnsError::InvalidSignature => write!(f, "invalid tree root signature"),
+            DnsError::InvalidRecord(record) => write!(f, "invalid tree record: {record}"),
+            DnsError::HashMismatch(hash) => write!(f, "record does not match hash {hash}"),
+            DnsError::TooManyRecords => write!(f, "tree has too many records"),
+            DnsError::Lookup(e) => write!(f, "TXT lookup failed: {e}"),
+            DnsError::Resolver(e) => write!(f, "failed to configure DNS resolver: {e}"),
+        }
+    }
+}
+
+#[cfg(feature = "dns")]
+impl std::error::Error for DnsError {}
+
+#[cfg(feature = "test_vectors")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Errors that can occur when decoding a hex test vector.
+pub enum VectorError {
+    /// The vector is not valid hex.
+    InvalidHex(String),
+    /// The packet could not be decoded.
+    Packet(PacketError),
+    /// The message could not be decrypted with the given key.
+    Decryption(String),
+    /// The message could not be decoded.
+    Message(String),
+}
+
+#[cfg(feature = "test_vectors")]
+impl fmt::Display for VectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VectorError::InvalidHex(e) => write!(f, "invalid hex: {e}"),
+            VectorError::Packet(e) => write!(f, "invalid packet: {e:?}"),
+            VectorError::Decryption(e) => write!(f, "message decryption failed: {e}"),
+            VectorError::Message(e) => write!(f, "invalid message: {e}"),
+        }
+    }
+}
+
+#[cfg(feature = "test_vectors")]
+impl std::error::Error for VectorError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_error_categories() {
+        let peer = NodeId::random();
+        let cases = [
+            (RequestError::Timeout, ErrorCategory::TransientNetwork),
+            (
+                RequestError::ChannelFailed(String::new()),
+                ErrorCategory::TransientNetwork,
+            ),
+            (
+                RequestError::EncryptionFailed(String::new()),
+                ErrorCategory::TransientNetwork,
+            ),
+            (
+                RequestError::ServiceNotStarted,
+                ErrorCategory::LocalMisconfiguration,
+            ),
+            (
+                RequestError::SelfRequest,
+                ErrorCategory::LocalMisconfiguration,
+            ),
+            (
+                RequestError::InvalidEnr(""),
+                ErrorCategory::LocalMisconfiguration,
+            ),
+            (
+                RequestError::SessionGenerationFailed(String::new()),
+                ErrorCategory::LocalMisconfiguration,
+            ),
+            (
+                RequestError::InvalidMultiaddr(""),
+                ErrorCategory::LocalMisconfiguration,
+            ),
+            (
+                RequestError::EntropyFailure(""),
+                ErrorCategory::LocalMisconfiguration,
+            ),
+            (
+                RequestError::InvalidDistances,
+                ErrorCategory::LocalMisconfiguration,
+            ),
+            (
+                RequestError::InvalidRemoteEnr(peer),
+                ErrorCategory::PeerMisbehavior,
+            ),
+            (
+                RequestError::InvalidRemotePacket(peer),
+                ErrorCategory::ProtocolViolation,
+            ),
+        ];
+        for (error, category) in cases {
+            assert_eq!(error.category(), category, "{}", error);
+            assert_eq!(
+                error.is_retryable(),
+                category == ErrorCategory::TransientNetwork,
+                "{}",
+                error
+            );
+            let blamed = matches!(
+                category,
+                ErrorCategory::PeerMisbehavior | ErrorCategory::ProtocolViolation
+            );
+            assert_eq!(error.peer(), blamed.then_some(peer), "{}", error);
+        }
+    }
+
+    #[test]
+    fn query_error_categories() {
+        let cases = [
+            (
+                QueryError::ChannelFailed(String::new()),
+                ErrorCategory::TransientNetwork,
+            ),
+            (
+                QueryError::EncryptionFailed(String::new()),
+                ErrorCategory::TransientNetwork,
+            ),
+            (
+                QueryError::ServiceNotStarted,
+                ErrorCategory::LocalMisconfiguration,
+            ),
+            (
+                QueryError::InvalidEnr(String::new()),
+                ErrorCategory::LocalMisconfiguration,
+            ),
+            (
+                QueryError::InvalidMultiaddr(String::new()),
+                ErrorCategory::LocalMisconfiguration,
+            ),
+        ];
+        for (error, category) in cases {
+            assert_eq!(error.category(), category, "{:?}", error);
+        }
+    }
+}
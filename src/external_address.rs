@@ -0,0 +1,52 @@
+//! The choice of the external address advertised in the local ENR.
+//!
+//! By default the address is learnt from the PONG responses of peers, and the address reported by
+//! a majority of them is advertised. Nodes behind a load balancer or an anycast address may
+//! receive and send from different hosts, so that peers disagree on the address of the node and
+//! no majority ever forms. Such nodes can advertise a fixed address, or decide on the reported
+//! addresses with a callback of their own.
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+/// Decides how the IP and port of the local ENR are updated.
+#[derive(Clone, Default)]
+pub enum ExternalAddressPolicy {
+    /// The address reported by a majority of the peers is advertised.
+    #[default]
+    Votes,
+    /// The address is advertised when the node starts and never updated. The address of the
+    /// other IP version is left as the ENR was built with. Like a voted address, it is revoked if
+    /// no incoming connections arrive within `Config::auto_nat_listen_duration`.
+    Fixed(SocketAddr),
+    /// An address reported by a peer is advertised if the callback accepts it. The callback is
+    /// given the address with the advertised port of its IP version applied, and only addresses
+    /// that differ from the advertised one.
+    Custom(Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>),
+    /// The address is never updated.
+    Disabled,
+}
+
+impl ExternalAddressPolicy {
+    /// Advertises the reported addresses the callback accepts.
+    pub fn from_callback(callback: impl Fn(SocketAddr) -> bool + Send + Sync + 'static) -> Self {
+        ExternalAddressPolicy::Custom(Arc::new(callback))
+    }
+
+    /// Whether the addresses reported by peers are considered.
+    pub(crate) fn updates_from_peers(&self) -> bool {
+        matches!(
+            self,
+            ExternalAddressPolicy::Votes | ExternalAddressPolicy::Custom(_)
+        )
+    }
+}
+
+impl fmt::Debug for ExternalAddressPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExternalAddressPolicy::Votes => f.write_str("Votes"),
+            ExternalAddressPolicy::Fixed(socket) => f.debug_tuple("Fixed").field(socket).finish(),
+            ExternalAddressPolicy::Custom(_) => f.write_str("Custom"),
+            ExternalAddressPolicy::Disabled => f.write_str("Disabled"),
+        }
+    }
+}
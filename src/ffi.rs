@@ -0,0 +1,473 @@
+//! A C ABI for embedding discv5 in applications written in other languages.
+//!
+//! A node is created with [`discv5_new`], which returns an opaque handle owning the node and the
+//! tokio runtime driving it. All functions taking strings expect NUL-terminated UTF-8, and strings
+//! returned by the library must be released with [`discv5_string_free`]. Functions return a
+//! [`Discv5Status`], `Discv5Status::Ok` on success.
+//!
+//! Callbacks run on the threads of the node's runtime. From within a callback, the node may be
+//! stopped and freed, but not started or looked up with, as these block on the runtime; such calls
+//! fail with `Discv5Status::ServiceError`. No panic unwinds out of the library.
+//!
+//! The library is built for linking with, for instance:
+//!
+//! ```text
+//! cargo rustc --release --features ffi --lib --crate-type cdylib
+//! ```
+use crate::{ConfigBuilder, Discv5, Enr, Event, ListenConfig};
+use enr::{CombinedKey, NodeId};
+use std::{
+    ffi::{c_void, CStr, CString},
+    net::SocketAddr,
+    os::raw::c_char,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+use tokio::{runtime::Runtime, task::JoinHandle};
+use tracing::warn;
+
+/// The outcome of a call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discv5Status {
+    Ok = 0,
+    /// A pointer was null, or a string was not valid UTF-8 or could not be parsed.
+    InvalidArgument = 1,
+    /// The service is not running, or failed to start, or the call would block the thread of a
+    /// callback.
+    ServiceError = 2,
+    /// The lookup failed.
+    QueryError = 3,
+}
+
+/// The kind of an event passed to a [`Discv5EventCallback`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discv5EventKind {
+    /// A node was discovered. The data is its base64 ENR.
+    Discovered = 0,
+    /// A node was added to the routing table. The data is its hex node id.
+    NodeInserted = 1,
+    /// A session was established with a node. The data is its base64 ENR.
+    SessionEstablished = 2,
+    /// Our ENR was updated with a new socket. The data is the socket, as `ip:port`.
+    SocketUpdated = 3,
+}
+
+/// Receives the events of a node. The data string is only valid for the duration of the call.
+/// Called from the threads of the node's runtime. Passed as a nullable function pointer.
+pub type Discv5EventCallback =
+    extern "C" fn(user_data: *mut c_void, kind: Discv5EventKind, data: *const c_char);
+
+/// Receives each ENR found by a lookup, in base64. The string is only valid for the duration of
+/// the call. Passed as a nullable function pointer.
+pub type Discv5EnrCallback = extern "C" fn(user_data: *mut c_void, enr: *const c_char);
+
+/// An embedded node and the runtime driving it.
+pub struct Discv5Handle {
+    runtime: Runtime,
+    discv5: Discv5,
+    /// The task passing events to the event callback, if one is set.
+    events: Option<JoinHandle<()>>,
+}
+
+/// Application data passed back to a callback.
+struct UserData(*mut c_void);
+
+// The application is responsible for the user data being usable from the runtime's threads.
+unsafe impl Send for UserData {}
+
+/// Creates a node listening on `listen_addr`, an `ip:port` string, with the hex encoded
+/// secp256k1 secret key `secret_key`. If the listening IP is specified, the local ENR advertises
+/// it. Returns null on failure.
+///
+/// # Safety
+///
+/// Both arguments must be null or valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn discv5_new(
+    secret_key: *const c_char,
+    listen_addr: *const c_char,
+) -> *mut Discv5Handle {
+    let (Some(secret_key), Some(listen_addr)) = (to_str(secret_key), to_str(listen_addr)) else {
+        return ptr::null_mut();
+    };
+    let Some(mut secret_key) = hex::decode(secret_key).ok() else {
+        return ptr::null_mut();
+    };
+    let Ok(key) = CombinedKey::secp256k1_from_bytes(&mut secret_key) else {
+        return ptr::null_mut();
+    };
+    let Ok(listen_addr) = listen_addr.parse::<SocketAddr>() else {
+        return ptr::null_mut();
+    };
+
+    let mut builder = Enr::builder();
+    if !listen_addr.ip().is_unspecified() {
+        builder.ip(listen_addr.ip());
+        match listen_addr {
+            SocketAddr::V4(_) => builder.udp4(listen_addr.port()),
+            SocketAddr::V6(_) => builder.udp6(listen_addr.port()),
+        };
+    }
+    let Ok(enr) = builder.build(&key) else {
+        return ptr::null_mut();
+    };
+    let Ok(runtime) = Runtime::new() else {
+        return ptr::null_mut();
+    };
+    let config = ConfigBuilder::new(ListenConfig::from(listen_addr)).build();
+    // The service's timers must be created within the runtime.
+    let discv5 = {
+        let _guard = runtime.enter();
+        Discv5::new(enr, key, config)
+    };
+    match discv5 {
+        Ok(discv5) => Box::into_raw(Box::new(Discv5Handle {
+            runtime,
+            discv5,
+            events: None,
+        })),
+        Err(e) => {
+            warn!(error = e, "Failed to create discv5 node");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Starts the node.
+///
+/// # Safety
+///
+/// `handle` must be a handle returned by [`discv5_new`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn discv5_start(handle: *mut Discv5Handle) -> Discv5Status {
+    let Some(handle) = handle.as_mut() else {
+        return Discv5Status::InvalidArgument;
+    };
+    catch_panic(|| match handle.runtime.block_on(handle.discv5.start()) {
+        Ok(()) => Discv5Status::Ok,
+        Err(e) => {
+            warn!(error = ?e, "Failed to start discv5 node");
+            Discv5Status::ServiceError
+        }
+    })
+}
+
+/// Stops the node. It may be started again.
+///
+/// # Safety
+///
+/// `handle` must be a handle returned by [`discv5_new`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn discv5_stop(handle: *mut Discv5Handle) -> Discv5Status {
+    let Some(handle) = handle.as_mut() else {
+        return Discv5Status::InvalidArgument;
+    };
+    catch_panic(|| {
+        let _guard = handle.runtime.enter();
+        handle.discv5.shutdown();
+        Discv5Status::Ok
+    })
+}
+
+/// Stops the node and releases the handle. Called from within a callback, the runtime is shut
+/// down without waiting for the callback to return.
+///
+/// # Safety
+///
+/// `handle` must be null or a handle returned by [`discv5_new`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn discv5_free(handle: *mut Discv5Handle) {
+    if handle.is_null() {
+        return;
+    }
+    let Discv5Handle {
+        runtime,
+        discv5,
+        events,
+    } = *Box::from_raw(handle);
+    catch_panic(|| {
+        {
+            let _guard = runtime.enter();
+            if let Some(events) = events {
+                events.abort();
+            }
+            drop(discv5);
+        }
+        // A runtime can't be dropped on one of its own threads.
+        if tokio::runtime::Handle::try_current().is_ok() {
+            runtime.shutdown_background();
+        } else {
+            drop(runtime);
+        }
+        Discv5Status::Ok
+    });
+}
+
+/// Adds a node, given as a base64 ENR, to the routing table.
+///
+/// # Safety
+///
+/// `handle` must be a handle returned by [`discv5_new`] and not yet freed, and `enr` a valid
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn discv5_add_enr(
+    handle: *mut Discv5Handle,
+    enr: *const c_char,
+) -> Discv5Status {
+    let (Some(handle), Some(enr)) = (handle.as_ref(), to_str(enr)) else {
+        return Discv5Status::InvalidArgument;
+    };
+    let Ok(enr) = enr.parse::<Enr>() else {
+        return Discv5Status::InvalidArgument;
+    };
+    match handle.discv5.add_enr(enr) {
+        Ok(()) => Discv5Status::Ok,
+        Err(e) => {
+            warn!(error = e, "Failed to add ENR");
+            Discv5Status::InvalidArgument
+        }
+    }
+}
+
+/// Returns the base64 ENR of the node, to be released with [`discv5_string_free`]. Returns null
+/// if the handle is null.
+///
+/// # Safety
+///
+/// `handle` must be null or a handle returned by [`discv5_new`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn discv5_local_enr(handle: *const Discv5Handle) -> *mut c_char {
+    match handle.as_ref() {
+        Some(handle) => to_c_string(handle.discv5.local_enr().to_base64()),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Looks up the nodes closest to the 32 byte `target` and passes each found ENR to `callback`.
+/// Blocks until the lookup has completed.
+///
+/// # Safety
+///
+/// `handle` must be a handle returned by [`discv5_new`] and not yet freed, and `target` must
+/// point to 32 readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn discv5_find_node(
+    handle: *mut Discv5Handle,
+    target: *const u8,
+    callback: Option<Discv5EnrCallback>,
+    user_data: *mut c_void,
+) -> Discv5Status {
+    let (Some(handle), Some(callback)) = (handle.as_ref(), callback) else {
+        return Discv5Status::InvalidArgument;
+    };
+    if target.is_null() {
+        return Discv5Status::InvalidArgument;
+    }
+    let target = NodeId::new(&*(target as *const [u8; 32]));
+    catch_panic(
+        || match handle.runtime.block_on(handle.discv5.find_node(target)) {
+            Ok(enrs) => {
+                for enr in enrs {
+                    let enr = CString::new(enr.to_base64()).expect("base64 has no NUL bytes");
+                    callback(user_data, enr.as_ptr());
+                }
+                Discv5Status::Ok
+            }
+            Err(e) => {
+                warn!(error = ?e, "Lookup failed");
+                Discv5Status::QueryError
+            }
+        },
+    )
+}
+
+/// Passes the events of the started node to `callback`, replacing any previous callback, or stops
+/// passing events if `callback` is null. Events are delivered until the node is stopped.
+///
+/// # Safety
+///
+/// `handle` must be a handle returned by [`discv5_new`] and not yet freed. `user_data` must be
+/// usable from any thread while the node runs.
+#[no_mangle]
+pub unsafe extern "C" fn discv5_set_event_callback(
+    handle: *mut Discv5Handle,
+    callback: Option<Discv5EventCallback>,
+    user_data: *mut c_void,
+) -> Discv5Status {
+    let Some(handle) = handle.as_mut() else {
+        return Discv5Status::InvalidArgument;
+    };
+    if let Some(events) = handle.events.take() {
+        events.abort();
+    }
+    let Some(callback) = callback else {
+        return Discv5Status::Ok;
+    };
+    catch_panic(
+        || match handle.runtime.block_on(handle.discv5.event_stream()) {
+            Ok(events) => {
+                let user_data = UserData(user_data);
+                handle.events = Some(
+                    handle
+                        .runtime
+                        .spawn(forward_events(events, callback, user_data)),
+                );
+                Discv5Status::Ok
+            }
+            Err(_) => Discv5Status::ServiceError,
+        },
+    )
+}
+
+/// Passes the events to the callback until the stream ends.
+async fn forward_events(
+    mut events: tokio::sync::mpsc::Receiver<Event>,
+    callback: Discv5EventCallback,
+    user_data: UserData,
+) {
+    while let Some(event) = events.recv().await {
+        let (kind, data) = match event {
+            Event::Discovered(enr) => (Discv5EventKind::Discovered, enr.to_base64()),
+            Event::NodeInserted { node_id, .. } => {
+                (Discv5EventKind::NodeInserted, hex::encode(node_id.raw()))
+            }
+            Event::SessionEstablished(enr, _) => {
+                (Discv5EventKind::SessionEstablished, enr.to_base64())
+            }
+            Event::SocketUpdated(socket) => (Discv5EventKind::SocketUpdated, socket.to_string()),
+            _ => continue,
+        };
+        let data = CString::new(data).expect("Event data has no NUL bytes");
+        callback(user_data.0, kind, data.as_ptr());
+    }
+}
+
+/// Releases a string returned by the library.
+///
+/// # Safety
+///
+/// `string` must be null or a string returned by the library and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn discv5_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Borrows a C string as UTF-8.
+unsafe fn to_str<'a>(string: *const c_char) -> Option<&'a str> {
+    if string.is_null() {
+        return None;
+    }
+    CStr::from_ptr(string).to_str().ok()
+}
+
+/// Runs `f`, failing with `Discv5Status::ServiceError` if it panics, as panics must not unwind into
+/// the application. Blocking on the runtime panics on the thread of a callback.
+fn catch_panic(f: impl FnOnce() -> Discv5Status) -> Discv5Status {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(Discv5Status::ServiceError)
+}
+
+fn to_c_string(string: String) -> *mut c_char {
+    CString::new(string).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_ffi_lifecycle() {
+        let secret_key =
+            CString::new(hex::encode(CombinedKey::generate_secp256k1().encode())).unwrap();
+        let listen_addr = CString::new("127.0.0.1:0").unwrap();
+        unsafe {
+            let handle = discv5_new(secret_key.as_ptr(), listen_addr.as_ptr());
+            assert!(!handle.is_null());
+            assert_eq!(discv5_start(handle), Discv5Status::Ok);
+
+            let enr = discv5_local_enr(handle);
+            assert!(CStr::from_ptr(enr).to_str().unwrap().parse::<Enr>().is_ok());
+            // A node can't be added to its own routing table.
+            assert_eq!(discv5_add_enr(handle, enr), Discv5Status::InvalidArgument);
+            discv5_string_free(enr);
+
+            assert_eq!(discv5_stop(handle), Discv5Status::Ok);
+            discv5_free(handle);
+        }
+    }
+
+    extern "C" fn count_event(user_data: *mut c_void, _: Discv5EventKind, _: *const c_char) {
+        let count = unsafe { &*(user_data as *const AtomicUsize) };
+        count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn ignore_enr(_: *mut c_void, _: *const c_char) {}
+
+    #[test]
+    fn test_ffi_callbacks() {
+        let new_node = |port: u16| {
+            let secret_key =
+                CString::new(hex::encode(CombinedKey::generate_secp256k1().encode())).unwrap();
+            let listen_addr = CString::new(format!("127.0.0.1:{port}")).unwrap();
+            unsafe {
+                let handle = discv5_new(secret_key.as_ptr(), listen_addr.as_ptr());
+                assert_eq!(discv5_start(handle), Discv5Status::Ok);
+                handle
+            }
+        };
+        let (node, peer) = (new_node(10190), new_node(10191));
+        let (replaced, current) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let user_data = |count: &AtomicUsize| count as *const AtomicUsize as *mut c_void;
+        let target = [0u8; 32];
+        unsafe {
+            assert_eq!(
+                discv5_find_node(node, target.as_ptr(), None, ptr::null_mut()),
+                Discv5Status::InvalidArgument
+            );
+
+            // Only the latest callback receives events.
+            let callback = Some(count_event as Discv5EventCallback);
+            for count in [&replaced, &current] {
+                assert_eq!(
+                    discv5_set_event_callback(node, callback, user_data(count)),
+                    Discv5Status::Ok
+                );
+            }
+            let enr = discv5_local_enr(peer);
+            assert_eq!(discv5_add_enr(node, enr), Discv5Status::Ok);
+            discv5_string_free(enr);
+            assert_eq!(
+                discv5_find_node(node, target.as_ptr(), Some(ignore_enr), ptr::null_mut()),
+                Discv5Status::Ok
+            );
+            for _ in 0..100 {
+                if current.load(Ordering::SeqCst) > 0 {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            assert!(current.load(Ordering::SeqCst) > 0);
+            assert_eq!(replaced.load(Ordering::SeqCst), 0);
+
+            // Calls blocking on the runtime fail rather than panic on the threads of a runtime,
+            // such as those of callbacks.
+            let runtime = Runtime::new().unwrap();
+            assert_eq!(
+                runtime.block_on(async { discv5_start(node) }),
+                Discv5Status::ServiceError
+            );
+            // While the node may be freed there.
+            runtime.block_on(async { discv5_free(peer) });
+
+            assert_eq!(
+                discv5_set_event_callback(node, None, ptr::null_mut()),
+                Discv5Status::Ok
+            );
+            discv5_free(node);
+        }
+    }
+}
@@ -24,12 +24,31 @@ impl ActiveRequests {
     /// Insert a new request into the active requests mapping.
     pub fn insert(&mut self, node_address: NodeAddress, request_call: RequestCall) {
         let nonce = *request_call.packet().message_nonce();
+        let remaining_time = request_call.remaining_time();
         self.active_requests_mapping
             .entry(node_address.clone())
             .or_default()
             .push(request_call);
-        self.active_requests_nonce_mapping
-            .insert(nonce, node_address);
+        self.insert_nonce(nonce, node_address, remaining_time);
+    }
+
+    /// Maps the nonce to the node address, expiring after the time left for the request if it
+    /// has a timeout of its own.
+    fn insert_nonce(
+        &mut self,
+        nonce: MessageNonce,
+        node_address: NodeAddress,
+        remaining_time: Option<Duration>,
+    ) {
+        match remaining_time {
+            Some(remaining_time) => {
+                self.active_requests_nonce_mapping
+                    .insert_at(nonce, node_address, remaining_time)
+            }
+            None => self
+                .active_requests_nonce_mapping
+                .insert(nonce, node_address),
+        }
     }
 
     /// Update the underlying packet for the request via message nonce.
@@ -43,10 +62,8 @@ impl ActiveRequests {
                 return;
             };
 
-        self.active_requests_nonce_mapping
-            .insert(new_packet.header.message_nonce, node_address.clone());
-
-        match self.active_requests_mapping.entry(node_address) {
+        let new_nonce = new_packet.header.message_nonce;
+        let remaining_time = match self.active_requests_mapping.entry(node_address.clone()) {
             Entry::Occupied(mut requests) => {
                 let maybe_request_call = requests
                     .get_mut()
@@ -55,16 +72,37 @@ impl ActiveRequests {
 
                 if let Some(request_call) = maybe_request_call {
                     request_call.update_packet(new_packet);
+                    request_call.remaining_time()
                 } else {
                     debug_unreachable!("expected to find request call in active_requests_mapping");
                     error!("expected to find request call in active_requests_mapping");
+                    None
                 }
             }
             Entry::Vacant(_) => {
                 debug_unreachable!("expected to find node address in active_requests_mapping");
                 error!("expected to find node address in active_requests_mapping");
+                None
             }
-        }
+        };
+        self.insert_nonce(new_nonce, node_address, remaining_time);
+    }
+
+    /// The number of nodes with which one of the requests is establishing a session.
+    pub fn num_initiating_sessions(&self) -> usize {
+        self.active_requests_mapping
+            .values()
+            .filter(|requests| requests.iter().any(RequestCall::initiating_session))
+            .count()
+    }
+
+    /// Iterates over the requests awaiting a response.
+    pub fn iter(&self) -> impl Iterator<Item = (&NodeAddress, &RequestCall)> {
+        self.active_requests_mapping
+            .iter()
+            .flat_map(|(node_address, requests)| {
+                requests.iter().map(move |req| (node_address, req))
+            })
     }
 
     pub fn get(&self, node_address: &NodeAddress) -> Option<&Vec<RequestCall>> {
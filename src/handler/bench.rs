@@ -0,0 +1,120 @@
+//! Entry points into the handshake and session encryption for the benchmarks of `benches/`,
+//! enabled by the `bench` feature. Not part of the stable API.
+use super::*;
+use session::Session;
+
+/// Two nodes to establish sessions between.
+pub struct Peers {
+    initiator: (Arc<RwLock<CombinedKey>>, Enr),
+    responder: (Arc<RwLock<CombinedKey>>, Enr),
+}
+
+/// The two ends of an established session.
+pub struct SessionPair {
+    /// The session of the node that initiated the handshake.
+    initiator: Session,
+    initiator_id: NodeId,
+    /// The session of the node that challenged it.
+    responder: Session,
+}
+
+impl Peers {
+    /// Two nodes with random secp256k1 keys.
+    pub fn random() -> Self {
+        let node = |port: u16| {
+            let key = CombinedKey::generate_secp256k1();
+            let enr = Enr::builder()
+                .ip4(std::net::Ipv4Addr::LOCALHOST)
+                .udp4(port)
+                .build(&key)
+                .expect("Valid ENR");
+            (Arc::new(RwLock::new(key)), enr)
+        };
+        Peers {
+            initiator: node(9000),
+            responder: node(9001),
+        }
+    }
+
+    /// Runs a handshake as the handler does: the responder challenges the initiator with a
+    /// WHOAREYOU, the initiator answers with a signed handshake packet carrying `message`, and the
+    /// responder verifies it and decrypts the message.
+    pub fn handshake<P: ProtocolIdentity>(&self, message: &[u8]) -> SessionPair {
+        let (initiator_key, initiator_enr) = &self.initiator;
+        let (responder_key, responder_enr) = &self.responder;
+        let initiator_id = initiator_enr.node_id();
+        let responder_id = responder_enr.node_id();
+
+        let whoareyou = Packet::new_whoareyou(rand::random(), rand::random(), 0);
+        let challenge_data =
+            ChallengeData::try_from(whoareyou.authenticated_data::<P>().as_slice())
+                .expect("Must be the correct challenge size");
+
+        let (packet, initiator) = Session::encrypt_with_header::<P>(
+            &NodeContact::try_from_enr(responder_enr.clone(), Default::default())
+                .expect("Contactable ENR"),
+            initiator_key.clone(),
+            Some(initiator_enr.clone()),
+            &initiator_id,
+            &challenge_data,
+            message,
+        )
+        .expect("Handshake packet");
+
+        let PacketKind::Handshake {
+            id_nonce_sig,
+            ephem_pubkey,
+            enr_record,
+            ..
+        } = &packet.header.kind
+        else {
+            unreachable!("The initiator sends a handshake packet")
+        };
+        let (mut responder, _) = Session::establish_from_challenge(
+            responder_key.clone(),
+            &responder_id,
+            &initiator_id,
+            Challenge {
+                data: challenge_data,
+                remote_enr: None,
+            },
+            id_nonce_sig,
+            ephem_pubkey,
+            enr_record.clone(),
+        )
+        .expect("Valid handshake");
+        responder
+            .decrypt_message(
+                *packet.message_nonce(),
+                &packet.message,
+                &packet.authenticated_data::<P>(),
+            )
+            .expect("Message of the handshake");
+
+        SessionPair {
+            initiator,
+            initiator_id,
+            responder,
+        }
+    }
+}
+
+impl SessionPair {
+    /// Encrypts a message from the initiator to the responder.
+    pub fn encrypt<P: ProtocolIdentity>(&mut self, message: &[u8]) -> Packet {
+        self.initiator
+            .encrypt_message::<P>(self.initiator_id, message)
+            .expect("Message counter not exhausted")
+    }
+
+    /// Decrypts a message of the initiator as the responder.
+    pub fn decrypt<P: ProtocolIdentity>(&mut self, packet: &Packet) -> Vec<u8> {
+        self.responder
+            .decrypt_message(
+                *packet.message_nonce(),
+                &packet.message,
+                &packet.authenticated_data::<P>(),
+            )
+            .expect("Encrypted by the initiator")
+    }
+}
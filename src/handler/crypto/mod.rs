@@ -36,6 +36,7 @@ const INFO_LENGTH: usize = 26 + 2 * NODE_ID_LENGTH;
 const KEY_LENGTH: usize = 16;
 const KEY_AGREEMENT_STRING: &str = "discovery v5 key agreement";
 const ID_SIGNATURE_TEXT: &str = "discovery v5 identity proof";
+const RESUMPTION_STRING: &str = "discovery v5 resumption";
 
 type Key = [u8; KEY_LENGTH];
 
@@ -92,6 +93,18 @@ fn derive_key(
     Ok((initiator_key, recipient_key))
 }
 
+/// Derives the key of a resumed session from a key of the session it resumes. Both peers derive
+/// the same key from the same key, so the keys of a direction still match after resumption, while
+/// the keys of the previous session can't be recovered from the new ones.
+pub(crate) fn derive_resumption_key(key: &Key) -> Key {
+    let hk = Hkdf::<Sha256>::new(None, key);
+    let mut resumption_key: Key = Default::default();
+    // A single key is well within the output length of HKDF-SHA256.
+    hk.expand(RESUMPTION_STRING.as_bytes(), &mut resumption_key)
+        .expect("Valid output length");
+    resumption_key
+}
+
 /// Derives the session keys for a public key type that matches the local keypair.
 pub(crate) fn derive_keys_from_pubkey(
     local_key: &CombinedKey,
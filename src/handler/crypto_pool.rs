@@ -0,0 +1,172 @@
+//! A small pool of workers processing handshakes off the handler task.
+//!
+//! Verifying the signature of a handshake and deriving its session keys costs far more than
+//! decrypting a message. When many peers handshake at once, doing this inline in the handler
+//! delays every other packet queued behind them. The pool runs these steps on a bounded number of
+//! blocking threads and hands the outcomes back to the handler, which completes the handshakes in
+//! its own task.
+use super::{session::Session, Challenge, RequestCall};
+use crate::{
+    error::Error,
+    node_info::NodeAddress,
+    packet::{ChallengeData, MessageNonce, Packet, ProtocolIdentity},
+    Enr,
+};
+use enr::{CombinedKey, NodeId};
+use parking_lot::RwLock;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::error;
+
+/// The number of processed handshakes buffered for the handler.
+const OUTCOME_CHANNEL_CAPACITY: usize = 64;
+
+/// A handshake packet received in response to one of our challenges.
+pub(crate) struct InboundHandshake {
+    pub node_address: NodeAddress,
+    pub message_nonce: MessageNonce,
+    pub id_nonce_sig: Vec<u8>,
+    pub ephem_pubkey: Vec<u8>,
+    pub enr_record: Option<Enr>,
+    /// The message carried by the handshake packet.
+    pub message: Vec<u8>,
+    pub authenticated_data: Vec<u8>,
+}
+
+/// The cryptographic work of a handshake.
+pub(crate) enum HandshakeJob {
+    /// Verifies a handshake answering our challenge and derives the session keys.
+    Verify {
+        handshake: Box<InboundHandshake>,
+        challenge: Challenge,
+    },
+    /// Answers a challenge to one of our requests with a signed handshake packet.
+    Answer {
+        request_call: Box<RequestCall>,
+        updated_enr: Option<Enr>,
+        challenge_data: ChallengeData,
+    },
+}
+
+/// The result of a [`HandshakeJob`], returned with the state needed to complete the handshake.
+pub(crate) enum HandshakeOutcome {
+    Verified {
+        handshake: Box<InboundHandshake>,
+        result: Result<(Session, Enr), Error>,
+    },
+    Answered {
+        request_call: Box<RequestCall>,
+        result: Result<(Packet, Session), Error>,
+    },
+}
+
+impl HandshakeOutcome {
+    /// The peer the handshake is with.
+    pub fn node_address(&self) -> NodeAddress {
+        match self {
+            HandshakeOutcome::Verified { handshake, .. } => handshake.node_address.clone(),
+            HandshakeOutcome::Answered { request_call, .. } => {
+                request_call.contact().node_address()
+            }
+        }
+    }
+}
+
+impl HandshakeJob {
+    fn run<P: ProtocolIdentity>(
+        self,
+        key: Arc<RwLock<CombinedKey>>,
+        node_id: &NodeId,
+    ) -> HandshakeOutcome {
+        match self {
+            HandshakeJob::Verify {
+                mut handshake,
+                challenge,
+            } => {
+                let result = Session::establish_from_challenge(
+                    key,
+                    node_id,
+                    &handshake.node_address.node_id,
+                    challenge,
+                    &handshake.id_nonce_sig,
+                    &handshake.ephem_pubkey,
+                    handshake.enr_record.take(),
+                );
+                HandshakeOutcome::Verified { handshake, result }
+            }
+            HandshakeJob::Answer {
+                request_call,
+                updated_enr,
+                challenge_data,
+            } => {
+                let result = Session::encrypt_with_header::<P>(
+                    request_call.contact(),
+                    key,
+                    updated_enr,
+                    node_id,
+                    &challenge_data,
+                    &request_call.encode(),
+                );
+                HandshakeOutcome::Answered {
+                    request_call,
+                    result,
+                }
+            }
+        }
+    }
+}
+
+/// Runs [`HandshakeJob`]s on a bounded number of blocking threads.
+pub(crate) struct CryptoPool {
+    key: Arc<RwLock<CombinedKey>>,
+    node_id: NodeId,
+    /// Limits the number of jobs running at once. If None, jobs are run inline.
+    workers: Option<Arc<Semaphore>>,
+    outcome_send: mpsc::Sender<HandshakeOutcome>,
+    /// The outcomes of the jobs run by the workers.
+    pub outcomes: mpsc::Receiver<HandshakeOutcome>,
+}
+
+impl CryptoPool {
+    /// Creates a pool with `workers` threads. With no workers, jobs are run by the caller.
+    pub fn new(workers: usize, key: Arc<RwLock<CombinedKey>>, node_id: NodeId) -> Self {
+        let (outcome_send, outcomes) = mpsc::channel(OUTCOME_CHANNEL_CAPACITY);
+        CryptoPool {
+            key,
+            node_id,
+            workers: (workers > 0).then(|| Arc::new(Semaphore::new(workers))),
+            outcome_send,
+            outcomes,
+        }
+    }
+
+    /// Sets the local node id handshakes are verified and answered for, after the identity has
+    /// been rotated.
+    pub fn set_node_id(&mut self, node_id: NodeId) {
+        self.node_id = node_id;
+    }
+
+    /// Processes a job. Without workers the outcome is returned right away, otherwise it is
+    /// delivered on `outcomes` once a worker has run the job.
+    pub fn process<P: ProtocolIdentity>(&self, job: HandshakeJob) -> Option<HandshakeOutcome> {
+        let Some(workers) = self.workers.clone() else {
+            return Some(job.run::<P>(self.key.clone(), &self.node_id));
+        };
+        let key = self.key.clone();
+        let node_id = self.node_id;
+        let outcome_send = self.outcome_send.clone();
+        tokio::spawn(async move {
+            let Ok(_permit) = workers.acquire_owned().await else {
+                return;
+            };
+            match tokio::task::spawn_blocking(move || job.run::<P>(key, &node_id)).await {
+                Ok(outcome) => {
+                    // The handler has shut down if the channel is closed.
+                    let _ = outcome_send.send(outcome).await;
+                }
+                Err(e) => error!(error = %e, "Handshake worker failed"),
+            }
+        });
+        None
+    }
+}
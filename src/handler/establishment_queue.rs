@@ -0,0 +1,193 @@
+//! Requests waiting for a session to be established.
+//!
+//! Establishing a session costs a handshake, so the handler may limit how many are in progress at
+//! once. Requests to peers without a session wait in this queue in the meantime, and are started
+//! by their [`RequestPriority`], so that a burst of maintenance traffic doesn't delay the peers
+//! queries are waiting on.
+use crate::time::Instant;
+use std::collections::VecDeque;
+
+/// The priority of a request to a peer without a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestPriority {
+    /// Maintenance of the routing table, such as pings and ENR refreshes.
+    Maintenance,
+    /// Requests of queries and of the application.
+    Query,
+    /// Requests to peers on the permit list.
+    Pinned,
+}
+
+impl RequestPriority {
+    /// All priorities, from the highest to the lowest.
+    const DESCENDING: [RequestPriority; 3] = [
+        RequestPriority::Pinned,
+        RequestPriority::Query,
+        RequestPriority::Maintenance,
+    ];
+}
+
+/// The maximum number of requests waiting for a session establishment.
+pub(crate) const ESTABLISHMENT_QUEUE_CAPACITY: usize = 1024;
+
+/// A queued request and the time it fails if it is still queued.
+struct Queued<T> {
+    request: T,
+    deadline: Instant,
+}
+
+/// Queues requests by priority, keeping the order of requests of the same priority. The queue is
+/// bounded, and requests that wait past their deadline are removed.
+pub(crate) struct EstablishmentQueue<T> {
+    pinned: VecDeque<Queued<T>>,
+    query: VecDeque<Queued<T>>,
+    maintenance: VecDeque<Queued<T>>,
+    capacity: usize,
+}
+
+impl<T> EstablishmentQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        EstablishmentQueue {
+            pinned: VecDeque::new(),
+            query: VecDeque::new(),
+            maintenance: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn queue(&mut self, priority: RequestPriority) -> &mut VecDeque<Queued<T>> {
+        match priority {
+            RequestPriority::Pinned => &mut self.pinned,
+            RequestPriority::Query => &mut self.query,
+            RequestPriority::Maintenance => &mut self.maintenance,
+        }
+    }
+
+    /// Queues a request behind those of the same or a higher priority until its deadline. If the
+    /// queue is full, the latest request of the lowest priority below that of the request makes
+    /// room for it, or else the request itself is refused. Returns the request dropped, if any.
+    pub fn push(&mut self, priority: RequestPriority, request: T, deadline: Instant) -> Option<T> {
+        let dropped = if self.len() < self.capacity {
+            None
+        } else {
+            let lowest = RequestPriority::DESCENDING
+                .iter()
+                .rev()
+                .take_while(|lower| **lower < priority)
+                .find_map(|lower| self.queue(*lower).pop_back());
+            match lowest {
+                Some(queued) => Some(queued.request),
+                None => return Some(request),
+            }
+        };
+        self.queue(priority).push_back(Queued { request, deadline });
+        dropped
+    }
+
+    /// Takes the request of the highest priority that has waited the longest.
+    pub fn pop(&mut self) -> Option<T> {
+        RequestPriority::DESCENDING
+            .iter()
+            .find_map(|priority| self.queue(*priority).pop_front())
+            .map(|queued| queued.request)
+    }
+
+    /// Removes the requests whose deadline has passed.
+    pub fn remove_expired(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+        for priority in RequestPriority::DESCENDING {
+            let queue = self.queue(priority);
+            let mut i = 0;
+            while i < queue.len() {
+                if queue[i].deadline <= now {
+                    expired.extend(queue.remove(i).map(|queued| queued.request));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        expired
+    }
+
+    /// The earliest deadline of the queued requests.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.entries().map(|queued| queued.deadline).min()
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &Queued<T>> {
+        self.pinned
+            .iter()
+            .chain(self.query.iter())
+            .chain(self.maintenance.iter())
+    }
+
+    /// Iterates over the queued requests, the next to be popped first.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries().map(|queued| &queued.request)
+    }
+
+    /// The number of queued requests.
+    pub fn len(&self) -> usize {
+        self.pinned.len() + self.query.len() + self.maintenance.len()
+    }
+
+    /// Whether no request is queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn pops_by_priority_then_age() {
+        let mut queue = EstablishmentQueue::new(ESTABLISHMENT_QUEUE_CAPACITY);
+        let deadline = Instant::now();
+        queue.push(RequestPriority::Maintenance, 1, deadline);
+        queue.push(RequestPriority::Query, 2, deadline);
+        queue.push(RequestPriority::Maintenance, 3, deadline);
+        queue.push(RequestPriority::Pinned, 4, deadline);
+        queue.push(RequestPriority::Query, 5, deadline);
+        assert_eq!(queue.len(), 5);
+
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(popped, vec![4, 2, 5, 1, 3]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn full_queue_drops_the_latest_lower_priority_request() {
+        let mut queue = EstablishmentQueue::new(3);
+        let deadline = Instant::now();
+        assert_eq!(queue.push(RequestPriority::Maintenance, 1, deadline), None);
+        assert_eq!(queue.push(RequestPriority::Maintenance, 2, deadline), None);
+        assert_eq!(queue.push(RequestPriority::Query, 3, deadline), None);
+
+        assert_eq!(
+            queue.push(RequestPriority::Maintenance, 4, deadline),
+            Some(4)
+        );
+        assert_eq!(queue.push(RequestPriority::Pinned, 5, deadline), Some(2));
+        assert_eq!(queue.push(RequestPriority::Query, 6, deadline), Some(1));
+        assert_eq!(queue.push(RequestPriority::Query, 7, deadline), Some(7));
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[test]
+    fn expired_requests_are_removed() {
+        let mut queue = EstablishmentQueue::new(ESTABLISHMENT_QUEUE_CAPACITY);
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        queue.push(RequestPriority::Query, 1, later);
+        queue.push(RequestPriority::Query, 2, now);
+        queue.push(RequestPriority::Maintenance, 3, now);
+        assert_eq!(queue.next_deadline(), Some(now));
+
+        assert_eq!(queue.remove_expired(now), vec![2, 3]);
+        assert_eq!(queue.next_deadline(), Some(later));
+        assert_eq!(queue.iter().collect::<Vec<_>>(), vec![&1]);
+    }
+}
@@ -26,16 +26,21 @@
 //! Responses from the application layer can be made via the receive channel using a [`HandlerIn`].
 //! Messages from a node on the network come by [`Socket`] and get the form of a [`HandlerOut`]
 //! and can be forwarded to the application layer via the send channel.
+use crate::time::{self, Clock, Instant};
 use crate::{
+    audit::{PacketType, ResponseViolation, SecurityEventKind, AUDIT_LOG},
+    bootnodes::BootnodePermits,
     config::Config,
     discv5::PERMIT_BAN_LIST,
+    enr_ext::RESUMPTION_ENR_KEY,
     error::{Error, RequestError},
     packet::{ChallengeData, IdNonce, MessageNonce, Packet, PacketKind, ProtocolIdentity},
-    rpc::{Message, Request, RequestBody, RequestId, Response, ResponseBody},
+    rpc::{Message, Notification, Request, RequestBody, RequestId, Response, ResponseBody},
     socket,
-    socket::{FilterConfig, Socket},
-    Enr,
+    socket::{FilterConfig, FilterStats, Limiter, LocalNodeIds, Quota, Socket},
+    Enr, EnrAllowlist, PermitBanList,
 };
+use alloy_rlp::Error as DecoderError;
 use cidr::Ipv4Cidr;
 use delay_map::HashMapDelay;
 use enr::{CombinedKey, NodeId};
@@ -44,39 +49,51 @@ use more_asserts::debug_unreachable;
 use parking_lot::RwLock;
 use smallvec::SmallVec;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryFrom,
     default::Default,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
     sync::{atomic::Ordering, Arc},
     task::{Context, Poll},
-    time::{Duration, Instant},
+    time::Duration,
 };
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, trace, warn};
 
 mod active_requests;
-mod crypto;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub(crate) mod crypto;
+mod crypto_pool;
+mod establishment_queue;
 mod request_call;
 mod session;
+mod session_cache;
 mod tests;
 
 pub use crate::node_info::{NodeAddress, NodeContact};
 
 use crate::metrics::METRICS;
 
-use crate::{lru_time_cache::LruTimeCache, socket::ListenConfig};
+use crate::lru_time_cache::LruTimeCache;
 use active_requests::ActiveRequests;
+use crypto_pool::{CryptoPool, HandshakeJob, HandshakeOutcome, InboundHandshake};
+pub(crate) use establishment_queue::RequestPriority;
+use establishment_queue::{EstablishmentQueue, ESTABLISHMENT_QUEUE_CAPACITY};
 use request_call::RequestCall;
-use session::Session;
+use session::{Keys, Session};
+use session_cache::SessionCache;
 
-// The time interval to check banned peer timeouts and unban peers when the timeout has elapsed (in
-// seconds).
-const BANNED_NODES_CHECK: u64 = 300; // Check every 5 minutes.
+/// The maximum number of messages from a peer held back while one of its handshakes is processed.
+const MAX_MESSAGES_DURING_HANDSHAKE: usize = 16;
+
+/// The RELAYMSG notifications from a single relay that are answered with a WHOAREYOU, given as a
+/// `(max_notifications, interval)` pair. Bounds the challenges a relay can make us send.
+const RELAY_RATE_LIMIT: (u64, Duration) = (10, Duration::from_secs(60));
 
 /// Messages sent from the application layer to `Handler`.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum HandlerIn {
     /// A Request to send to a `NodeContact` has been received from the application layer. A
@@ -91,7 +108,11 @@ pub enum HandlerIn {
     ///
     /// Note: To update an ENR for an unknown node, we request a FINDNODE with distance 0 to the
     /// `NodeContact` we know of.
-    Request(NodeContact, Box<Request>),
+    ///
+    /// The priority orders the requests that wait for a session to be established, if the number
+    /// of concurrent establishments is limited. A timeout, if given, fails the request once it
+    /// elapses, in place of the request timeout and retries of the configuration.
+    Request(NodeContact, Box<Request>, RequestPriority, Option<Duration>),
 
     /// A Response to send to a particular node to answer a HandlerOut::Request has been
     /// received from the application layer.
@@ -100,11 +121,80 @@ pub enum HandlerIn {
     /// response back to the `NodeAddress` from which the request was received.
     Response(NodeAddress, Box<Response>),
 
+    /// A Response to send to a node, like `Response`, that re-establishes the session with the
+    /// node if there is none. The response is held until the session is established, and dropped
+    /// if the handshake fails.
+    ResponseEstablishingSession(NodeContact, Box<Response>),
+
     /// A Random packet has been received and we have requested the application layer to inform
     /// us what the highest known ENR is for this node.
     /// The `WhoAreYouRef` is sent out in the `HandlerOut::WhoAreYou` event and should
     /// be returned here to submit the application's response.
     WhoAreYou(WhoAreYouRef, Option<Enr>),
+
+    /// The application layer considers a node dead. Its sessions are dropped, so that the next
+    /// request to the node starts a new handshake.
+    RemoveSessions(NodeId),
+
+    /// The nodes whose sessions are kept in the reserved part of the session cache, see
+    /// [`crate::Config::session_reserved_fraction`].
+    ReservedNodes(HashSet<NodeId>),
+
+    /// Reports the requests awaiting a response, a session or a session establishment slot.
+    PendingRequests(oneshot::Sender<Vec<OutgoingRequest>>),
+
+    /// Requests the state of the filter of unsolicited packets.
+    FilterStats(oneshot::Sender<FilterStats>),
+
+    /// The local ENR has been re-signed with a new key. New sessions are established under the
+    /// new identity, while those established under the previous one are kept for the grace
+    /// period, if any, and dropped otherwise.
+    RotateIdentity(Option<Duration>),
+
+    /// Perturbs the sessions of the handler, to exercise its recovery paths in tests.
+    #[cfg(feature = "test_utils")]
+    Chaos(ChaosHook),
+}
+
+/// A request that has not been answered yet, as reported by
+/// [`crate::Discv5::pending_requests`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutgoingRequest {
+    /// The node the request is addressed to.
+    pub node_id: NodeId,
+    /// The socket the request is sent to.
+    pub socket_addr: SocketAddr,
+    /// The request.
+    pub request: RequestBody,
+    /// How far the request has progressed.
+    pub state: OutgoingRequestState,
+    /// The time since the request was first sent, or queued if it has not been sent yet.
+    pub age: Duration,
+    /// The number of further transmissions before the request times out.
+    pub retries_remaining: u8,
+}
+
+/// How far an [`OutgoingRequest`] has progressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutgoingRequestState {
+    /// The request has been sent and awaits a response.
+    Sent,
+    /// The request waits for the handshake with the node to complete.
+    AwaitingSession,
+    /// The request waits for one of the sessions being established to complete, see
+    /// [`crate::Config::max_session_establishments`].
+    Queued,
+}
+
+/// A perturbation of the state of a running node.
+#[cfg(feature = "test_utils")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChaosHook {
+    /// Drops the sessions with a node, or with all nodes if `None`, without notifying them.
+    DropSessions(Option<NodeId>),
+    /// Corrupts the message nonce of the next packet sent to a node, so that the node fails to
+    /// decrypt it.
+    CorruptNextNonce(NodeId),
 }
 
 /// Messages sent between a node on the network and `Handler`.
@@ -115,13 +205,14 @@ pub enum HandlerOut {
     /// A session is only considered established once we have received a signed ENR from the
     /// node and either the observed `SocketAddr` matches the one declared in the ENR or the
     /// ENR declares no `SocketAddr`.
-    Established(Enr, SocketAddr, ConnectionDirection),
+    Established(Arc<Enr>, SocketAddr, ConnectionDirection),
 
     /// A Request has been received from a node on the network.
     Request(NodeAddress, Box<Request>),
 
-    /// A Response has been received from a node on the network.
-    Response(NodeAddress, Box<Response>),
+    /// A Response has been received from a node on the network, along with the round trip time
+    /// of the request if it could be measured.
+    Response(NodeAddress, Box<Response>, Option<Duration>),
 
     /// An unknown source has requested information from us. Return the reference with the known
     /// ENR of this node (if known). See the `HandlerIn::WhoAreYou` variant.
@@ -136,14 +227,19 @@ pub enum HandlerOut {
     ///
     /// These peers are denied sessions.
     UnverifiableEnr {
-        enr: Enr,
+        enr: Arc<Enr>,
         socket: SocketAddr,
         node_id: NodeId,
     },
+
+    /// A peer with an established session sent a response breaking the protocol, which could not
+    /// be matched to its request.
+    InvalidResponse(NodeAddress, ResponseViolation),
 }
 
 /// How we connected to the node.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConnectionDirection {
     /// The node contacted us.
     Incoming,
@@ -174,11 +270,24 @@ enum HandlerReqId {
     External(RequestId),
 }
 
+/// Displays the request id on the wire, which is the id the service logs the request under.
+impl std::fmt::Display for HandlerReqId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandlerReqId::Internal(id) | HandlerReqId::External(id) => id.fmt(f),
+        }
+    }
+}
+
 /// A request queued for sending.
 struct PendingRequest {
     contact: NodeContact,
     request_id: HandlerReqId,
     request: RequestBody,
+    /// The timeout of the request, if it isn't that of the configuration.
+    timeout: Option<Duration>,
+    /// When the request was queued.
+    queued_at: Instant,
 }
 
 impl From<&HandlerReqId> for RequestId {
@@ -194,25 +303,42 @@ impl From<&HandlerReqId> for RequestId {
 pub struct Handler {
     /// Configuration for the discv5 service.
     request_retries: u8,
-    /// The local node id to save unnecessary read locks on the ENR. The NodeID should not change
-    /// during the operation of the server.
+    /// The timeout of a request, which requests waiting for a session establishment also fail
+    /// after unless they have their own.
+    request_timeout: Duration,
+    /// The local node id to save unnecessary read locks on the ENR. It only changes when the
+    /// identity is rotated.
     node_id: NodeId,
+    /// The node ids the recv handler decodes packets for, updated when the identity is rotated.
+    local_node_ids: Arc<RwLock<LocalNodeIds>>,
+    /// The identity retired by the last rotation, while its sessions remain in use.
+    retired_identity: Option<RetiredIdentity>,
     /// The local ENR.
     enr: Arc<RwLock<Enr>>,
-    /// The key to sign the ENR and set up encrypted communication with peers.
-    key: Arc<RwLock<CombinedKey>>,
     /// Active requests that are awaiting a response.
     active_requests: ActiveRequests,
     /// The expected responses by SocketAddr which allows packets to pass the underlying filter.
     filter_expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
     /// Requests awaiting a handshake completion.
     pending_requests: HashMap<NodeAddress, Vec<PendingRequest>>,
+    /// Responses awaiting a handshake completion, to peers whose session expired before they
+    /// were answered.
+    held_responses: HashMap<NodeAddress, Vec<Response>>,
     /// Currently in-progress outbound handshakes (WHOAREYOU packets) with peers.
     active_challenges: HashMapDelay<NodeAddress, Challenge>,
+    /// The maximum number of WHOAREYOU challenges awaiting a handshake. Challenges expire, so
+    /// this bounds the rate of challenges as well as the memory they hold.
+    max_active_challenges: usize,
     /// Established sessions with peers.
-    sessions: LruTimeCache<NodeAddress, Session>,
+    sessions: SessionCache,
+    /// The tickets to resume the last session with peers supporting session resumption, if
+    /// enabled.
+    resumption_tickets: Option<LruTimeCache<NodeAddress, ResumptionTicket>>,
+    /// The sessions we resumed from a ticket, until the peer proves it holds the ticket too by
+    /// sending a message encrypted with its keys.
+    resuming_sessions: HashMap<NodeAddress, (Session, Arc<Enr>)>,
     /// The channel to receive messages from the application layer.
-    service_recv: mpsc::UnboundedReceiver<HandlerIn>,
+    service_recv: mpsc::Receiver<HandlerIn>,
     /// The channel to send messages to the application layer.
     service_send: mpsc::Sender<HandlerOut>,
     /// The listening sockets to filter out any attempted requests to self.
@@ -223,24 +349,88 @@ pub struct Handler {
     exit: oneshot::Receiver<()>,
     /// Permitted discovery table additions cidr for non-advertise-ip matching source addresses
     allowed_cidr: Option<Ipv4Cidr>,
+    /// Limits the NODES and TALKRESP bytes sent to each peer, if configured.
+    response_limiter: Option<Limiter<NodeId>>,
+    /// Limits the WHOAREYOU challenges sent to each IP, if configured.
+    challenge_limiter: Option<Limiter<IpAddr>>,
+    /// The time the handler was created, used as the reference point for the response limiter.
+    init_time: Instant,
+    /// Whether we forward RELAYINIT notifications to our peers.
+    enable_relay: bool,
+    /// The peer that most recently sent us each node's ENR. These peers are asked to relay our
+    /// handshake initiation if the node does not respond.
+    relays: LruTimeCache<NodeId, NodeAddress>,
+    /// Limits the RELAYMSG notifications acted upon from each relay.
+    relay_limiter: Limiter<NodeId>,
+    /// If set, sessions are only established with nodes whose ENR is allowed.
+    enr_allowlist: Option<EnrAllowlist>,
+    /// Verifies and answers handshakes.
+    crypto_pool: CryptoPool,
+    /// Peers with handshakes in the crypto pool.
+    handshakes_in_progress: HashMap<NodeAddress, HandshakeInProgress>,
+    /// The maximum number of sessions being established at once, if limited.
+    max_session_establishments: Option<usize>,
+    /// Requests waiting for one of the sessions being established to complete.
+    establishment_queue: EstablishmentQueue<(NodeContact, Request, Option<Duration>, Instant)>,
+    /// The requests answered by an identical request to the same node, by the id of the request
+    /// that is sent.
+    coalesced_requests: HashMap<RequestId, Vec<RequestId>>,
+    /// The clock ban expiries are measured against.
+    clock: Arc<dyn Clock>,
+    /// The interval at which unused sessions are demoted and the limiters pruned.
+    maintenance_interval: Duration,
+}
+
+/// An identity replaced by a rotation, whose sessions keep answering until the grace period
+/// ends.
+struct RetiredIdentity {
+    /// The node id of the retired identity.
+    node_id: NodeId,
+    /// The peers whose sessions were established under the retired identity and have not been
+    /// re-established since.
+    sessions: HashSet<NodeAddress>,
+    /// Fires at the end of the grace period.
+    expiry: Pin<Box<tokio::time::Sleep>>,
+}
+
+/// The keys to resume a session with a peer without a handshake.
+struct ResumptionTicket {
+    /// The keys of the resumed session.
+    keys: Keys,
+    /// The ENR of the peer, reported when the session is resumed.
+    enr: Arc<Enr>,
+}
+
+/// The handshakes with a peer that are being processed by the crypto pool.
+#[derive(Default)]
+struct HandshakeInProgress {
+    /// The number of handshakes being processed.
+    jobs: usize,
+    /// The messages received from the peer in the meantime, processed once the handshakes
+    /// complete so that they are handled in the order they would be without the pool.
+    messages: Vec<(MessageNonce, Vec<u8>, Vec<u8>)>,
 }
 
 type HandlerReturn = (
     oneshot::Sender<()>,
-    mpsc::UnboundedSender<HandlerIn>,
+    mpsc::Sender<HandlerIn>,
     mpsc::Receiver<HandlerOut>,
 );
 
 impl Handler {
-    /// A new Session service which instantiates the UDP socket send/recv tasks.
+    /// A new Session service which instantiates the UDP socket send/recv tasks. The packet filter
+    /// lets the bootnodes of `bootnode_permits` through.
     pub async fn spawn<P: ProtocolIdentity>(
         enr: Arc<RwLock<Enr>>,
         key: Arc<RwLock<CombinedKey>>,
+        bootnode_permits: Arc<RwLock<BootnodePermits>>,
         config: Config,
     ) -> Result<HandlerReturn, std::io::Error> {
         let (exit_sender, exit) = oneshot::channel();
-        // create the channels to send/receive messages from the application
-        let (handler_send, service_recv) = mpsc::unbounded_channel();
+        // create the channels to send/receive messages from the application. The handler waits
+        // for space in the service's queue, which in turn stops it from reading the socket's
+        // queue. The service never waits for the handler, so the two can't deadlock.
+        let (handler_send, service_recv) = mpsc::channel(config.handler_queue_capacity);
         let (service_send, handler_recv) = mpsc::channel(50);
 
         // Creates a SocketConfig to pass to the underlying UDP socket tasks.
@@ -250,6 +440,7 @@ impl Handler {
 
         // The local node id
         let node_id = enr.read().node_id();
+        let local_node_ids = Arc::new(RwLock::new(LocalNodeIds::new(node_id)));
 
         // enable the packet filter if required
         let filter_config = FilterConfig {
@@ -257,32 +448,45 @@ impl Handler {
             rate_limiter: config.filter_rate_limiter.clone(),
             max_nodes_per_ip: config.filter_max_nodes_per_ip,
             max_bans_per_ip: config.filter_max_bans_per_ip,
+            clock: config.clock.clone(),
+            bootnode_permits,
         };
 
         let mut listen_sockets = SmallVec::default();
-        match config.listen_config {
-            ListenConfig::Ipv4 { ip, port } => listen_sockets.push((ip, port).into()),
-            ListenConfig::Ipv6 { ip, port } => listen_sockets.push((ip, port).into()),
-            ListenConfig::DualStack {
-                ipv4,
-                ipv4_port,
-                ipv6,
-                ipv6_port,
-            } => {
-                listen_sockets.push((ipv4, ipv4_port).into());
-                listen_sockets.push((ipv6, ipv6_port).into());
-            }
-        };
+        let (ipv4, ipv6) = config.listen_config.socket_addrs();
+        listen_sockets.extend(ipv4.map(SocketAddr::V4));
+        listen_sockets.extend(ipv6.map(SocketAddr::V6));
 
         let socket_config = socket::SocketConfig {
             executor: config.executor.clone().expect("Executor must exist"),
             filter_config,
             listen_config: config.listen_config.clone(),
-            local_node_id: node_id,
+            local_node_ids: local_node_ids.clone(),
             expected_responses: filter_expected_responses.clone(),
             ban_duration: config.ban_duration,
+            transport_factory: config.transport_factory.clone(),
+            inbound_queue_capacity: config.inbound_queue_capacity,
+            ipv6_only: config.ipv6_only,
         };
 
+        let response_limiter = config.outbound_response_limit.map(|(max_bytes, interval)| {
+            Limiter::from_quota(Quota::n_every(max_bytes, interval))
+                .expect("The outbound response limit is validated by the config")
+        });
+
+        let challenge_limiter = config
+            .challenge_rate_limit
+            .map(|(max_challenges, interval)| {
+                Limiter::from_quota(Quota::n_every(max_challenges, interval))
+                    .expect("The challenge rate limit is validated by the config")
+            });
+
+        let (max_notifications, interval) = RELAY_RATE_LIMIT;
+        let relay_limiter = Limiter::from_quota(Quota::n_every(max_notifications, interval))
+            .expect("The relay rate limit is valid");
+
+        let crypto_pool = CryptoPool::new(config.handshake_workers, key, node_id);
+
         // Attempt to bind to the socket before spinning up the send/recv tasks.
         let socket = Socket::new::<P>(socket_config).await?;
         config
@@ -292,23 +496,55 @@ impl Handler {
             .spawn(Box::pin(async move {
                 let mut handler = Handler {
                     request_retries: config.request_retries,
+                    request_timeout: config.request_timeout,
                     node_id,
+                    local_node_ids,
+                    retired_identity: None,
                     enr,
-                    key,
                     active_requests: ActiveRequests::new(config.request_timeout),
                     pending_requests: HashMap::new(),
+                    held_responses: HashMap::new(),
                     filter_expected_responses,
-                    sessions: LruTimeCache::new(
+                    sessions: SessionCache::new(
+                        config.session_cache_capacity,
+                        config.idle_session_cache_capacity,
+                        config.session_reserved_fraction,
+                        config.session_idle_timeout,
                         config.session_timeout,
-                        Some(config.session_cache_capacity),
+                        config.clock.clone(),
+                    ),
+                    resumption_tickets: config
+                        .session_resumption
+                        .map(|ttl| LruTimeCache::new(ttl, Some(config.session_cache_capacity))),
+                    resuming_sessions: HashMap::new(),
+                    active_challenges: HashMapDelay::new(
+                        config.challenge_ttl.unwrap_or(config.request_timeout),
                     ),
-                    active_challenges: HashMapDelay::new(config.request_timeout),
+                    max_active_challenges: config.challenge_cache_capacity,
                     service_recv,
                     service_send,
                     listen_sockets,
                     socket,
                     exit,
                     allowed_cidr: config.allowed_cidr,
+                    response_limiter,
+                    challenge_limiter,
+                    init_time: time::now(),
+                    enable_relay: config.enable_relay,
+                    // A relay is only useful while we hold a session with it.
+                    relays: LruTimeCache::new(
+                        config.session_timeout,
+                        Some(config.session_cache_capacity),
+                    ),
+                    relay_limiter,
+                    enr_allowlist: config.enr_allowlist.clone(),
+                    crypto_pool,
+                    handshakes_in_progress: HashMap::new(),
+                    max_session_establishments: config.max_session_establishments,
+                    establishment_queue: EstablishmentQueue::new(ESTABLISHMENT_QUEUE_CAPACITY),
+                    coalesced_requests: HashMap::new(),
+                    clock: config.clock.clone(),
+                    maintenance_interval: config.maintenance_interval,
                 };
                 debug!("Handler Starting");
                 handler.start::<P>().await;
@@ -319,41 +555,105 @@ impl Handler {
 
     /// The main execution loop for the handler.
     async fn start<P: ProtocolIdentity>(&mut self) {
-        let mut banned_nodes_check = tokio::time::interval(Duration::from_secs(BANNED_NODES_CHECK));
+        let mut maintenance = tokio::time::interval(self.maintenance_interval);
+        // Bans are lifted as they expire. The timer is reset when a ban is added, as it may
+        // expire before the next one.
+        let mut ban_expiries = PermitBanList::subscribe_expiries();
+        let mut next_ban_expiry = self.next_ban_expiry();
 
         loop {
+            METRICS.set_queue_depth(&METRICS.service_queue_depth, &self.service_send);
+            METRICS
+                .active_challenges
+                .store(self.active_challenges.len(), Ordering::Relaxed);
+            METRICS
+                .queued_requests
+                .store(self.establishment_queue.len(), Ordering::Relaxed);
             tokio::select! {
                 Some(handler_request) = self.service_recv.recv() => {
                     match handler_request {
-                        HandlerIn::Request(contact, request) => {
-                            let Request { id, body: request } = *request;
-                            if let Err(request_error) =  self.send_request::<P>(contact, HandlerReqId::External(id.clone()), request).await {
-                                // If the sending failed report to the application
-                                if let Err(e) = self.service_send.send(HandlerOut::RequestFailed(id, request_error)).await {
-                                    warn!(error = %e, "Failed to inform that request failed")
-                                }
+                        HandlerIn::Request(contact, request, priority, timeout) => {
+                            if let Some(sent_id) = self.coalescable_request(&contact, &request) {
+                                trace!(%sent_id, request_id = %request.id, "Coalescing duplicate request");
+                                METRICS.coalesced_requests.fetch_add(1, Ordering::Relaxed);
+                                self.coalesced_requests.entry(sent_id).or_default().push(request.id);
+                            } else if self.is_establishment_limited(&contact.node_address()) {
+                                trace!(node_address = %contact.node_address(), ?priority, "Request queued for session establishment");
+                                self.queue_request(contact, *request, priority, timeout).await;
+                            } else {
+                                self.send_external_request::<P>(contact, *request, timeout).await;
                             }
                         }
                         HandlerIn::Response(dst, response) => self.send_response::<P>(dst, *response).await,
+                        HandlerIn::ResponseEstablishingSession(contact, response) => self.send_response_establishing_session::<P>(contact, *response).await,
                         HandlerIn::WhoAreYou(wru_ref, enr) => self.send_challenge::<P>(wru_ref, enr).await,
+                        HandlerIn::RemoveSessions(node_id) => self.remove_sessions(&node_id),
+                        HandlerIn::ReservedNodes(nodes) => self.sessions.set_reserved_nodes(nodes),
+                        HandlerIn::PendingRequests(callback) => {
+                            if callback.send(self.outgoing_requests()).is_err() {
+                                debug!("Failed to return the pending requests");
+                            }
+                        }
+                        HandlerIn::FilterStats(callback) => {
+                            if self.socket.filter_stats.try_send(callback).is_err() {
+                                debug!("Failed to request the state of the filter");
+                            }
+                        }
+                        HandlerIn::RotateIdentity(grace_period) => self.rotate_identity(grace_period),
+                        #[cfg(feature = "test_utils")]
+                        HandlerIn::Chaos(hook) => self.apply_chaos_hook(hook),
                     }
                 }
                 Some(inbound_packet) = self.socket.recv.recv() => {
                     self.process_inbound_packet::<P>(inbound_packet).await;
                 }
+                _ = Handler::retired_identity_expiry(&mut self.retired_identity) => {
+                    self.drop_retired_identity();
+                }
+                Some(outcome) = self.crypto_pool.outcomes.recv() => {
+                    self.handle_handshake_outcome::<P>(outcome).await;
+                }
                 Some(Ok((node_address, active_request))) = self.active_requests.next() => {
-                    self.handle_request_timeout(node_address, active_request).await;
+                    self.handle_request_timeout::<P>(node_address, active_request).await;
                 }
                 Some(Ok((node_address, _challenge))) = self.active_challenges.next() => {
                     // A challenge has expired. There could be pending requests awaiting this
                     // challenge. We process them here
                     self.send_pending_requests::<P>(&node_address).await;
+                    // Responses held for the session are dropped unless one of the pending
+                    // requests starts it.
+                    if !self.is_awaiting_session_to_be_established(&node_address) {
+                        self.held_responses.remove(&node_address);
+                    }
+                }
+                _ = Handler::ban_expiry(&mut next_ban_expiry) => {
+                    self.unban_nodes_check();
+                    next_ban_expiry = self.next_ban_expiry();
+                }
+                Ok(()) = ban_expiries.changed() => {
+                    next_ban_expiry = self.next_ban_expiry();
+                }
+                _ = Handler::queue_expiry(self.establishment_queue.next_deadline()) => {
+                    self.expire_queued_requests().await;
+                }
+                _ = maintenance.tick() => {
+                    // Demote and expire unused sessions.
+                    self.update_session_metrics();
+                    let now = time::now().saturating_duration_since(self.init_time);
+                    if let Some(limiter) = self.response_limiter.as_mut() {
+                        limiter.prune(now);
+                    }
+                    if let Some(limiter) = self.challenge_limiter.as_mut() {
+                        limiter.prune(now);
+                    }
+                    self.relay_limiter.prune(now);
                 }
-                _ = banned_nodes_check.tick() => self.unban_nodes_check(), // Unban nodes that are past the timeout
                 _ = &mut self.exit => {
                     return;
                 }
             }
+            // Completed or failed establishments make room for queued requests.
+            self.send_queued_requests::<P>().await;
         }
     }
 
@@ -362,6 +662,20 @@ impl Handler {
         &mut self,
         inbound_packet: socket::InboundPacket,
     ) {
+        // Packets to the retired identity are only accepted on the sessions established under it.
+        if inbound_packet.to_retired_identity
+            && !matches!(
+                inbound_packet.header.kind,
+                PacketKind::Message { src_id } if self.is_retired_session(&NodeAddress {
+                    socket_addr: inbound_packet.src_address,
+                    node_id: src_id,
+                })
+            )
+        {
+            trace!(src = %inbound_packet.src_address, "Dropped packet to the retired identity");
+            return;
+        }
+
         let message_nonce = inbound_packet.header.message_nonce;
         match inbound_packet.header.kind {
             PacketKind::WhoAreYou { enr_seq, .. } => {
@@ -382,31 +696,31 @@ impl Handler {
                 ephem_pubkey,
                 enr_record,
             } => {
-                let node_address = NodeAddress {
-                    socket_addr: inbound_packet.src_address,
-                    node_id: src_id,
-                };
-                self.handle_auth_message::<P>(
-                    node_address,
+                let handshake = InboundHandshake {
+                    node_address: NodeAddress {
+                        socket_addr: inbound_packet.src_address,
+                        node_id: src_id,
+                    },
                     message_nonce,
-                    &id_nonce_sig,
-                    &ephem_pubkey,
+                    id_nonce_sig,
+                    ephem_pubkey,
                     enr_record,
-                    &inbound_packet.message,
-                    &inbound_packet.authenticated_data, // This is required for authenticated data in decryption.
-                )
-                .await
+                    message: inbound_packet.message,
+                    authenticated_data: inbound_packet.authenticated_data, // This is required for authenticated data in decryption.
+                };
+                self.handle_auth_message::<P>(handshake).await
             }
             PacketKind::Message { src_id } => {
                 let node_address = NodeAddress {
                     socket_addr: inbound_packet.src_address,
                     node_id: src_id,
                 };
-                self.handle_message(
+                self.handle_message::<P>(
                     node_address,
                     message_nonce,
                     &inbound_packet.message,
                     &inbound_packet.authenticated_data,
+                    inbound_packet.received_at,
                 )
                 .await
             }
@@ -434,13 +748,36 @@ impl Handler {
     }
 
     /// A request has timed out.
-    async fn handle_request_timeout(
+    async fn handle_request_timeout<P: ProtocolIdentity>(
         &mut self,
         node_address: NodeAddress,
         mut request_call: RequestCall,
     ) {
-        if request_call.retries() >= self.request_retries {
-            trace!(%node_address, "Request timed out");
+        // A peer that doesn't answer the resumed session may have lost the ticket without
+        // noticing our request. The request is resent as a handshake initiation.
+        if self.resuming_sessions.remove(&node_address).is_some() {
+            debug!(%node_address, "Resumed session not answered, starting a handshake");
+            match Packet::new_random(&self.node_id) {
+                Ok(packet) => request_call.update_packet(packet),
+                Err(e) => warn!(error = ?e, "Failed to generate a random packet"),
+            }
+        }
+        // A request with a timeout of its own is not resent, it fails once the timeout elapses.
+        let capped = request_call.remaining_time().is_some();
+        if request_call.retries() >= self.request_retries
+            && !capped
+            && request_call.initiating_session()
+            && !request_call.relayed()
+            && self
+                .send_relay_init::<P>(&node_address, &request_call)
+                .await
+        {
+            // The node may be behind a NAT. Give it a chance to respond to the relayed
+            // handshake initiation before failing the request.
+            request_call.set_relayed();
+            self.active_requests.insert(node_address, request_call);
+        } else if request_call.retries() >= self.request_retries || capped {
+            trace!(%node_address, request_id = %request_call.id(), "Request timed out");
             // Remove the request from the awaiting packet_filter
             self.remove_expected_response(node_address.socket_addr);
             // The request has timed out. We keep any established session for future use.
@@ -451,21 +788,270 @@ impl Handler {
             trace!(
                 body = %request_call.body(),
                 %node_address,
+                request_id = %request_call.id(),
                 "Resending message",
             );
-            self.send(node_address.clone(), request_call.packet().clone())
-                .await;
+            self.send_request_packet(
+                node_address.clone(),
+                request_call.packet().clone(),
+                request_call.id(),
+            )
+            .await;
             request_call.increment_retries();
             self.active_requests.insert(node_address, request_call);
         }
     }
 
+    /// Asks the peer that informed us of the node to relay our handshake initiation, so that the
+    /// node can punch a hole in its NAT by sending us a WHOAREYOU. Returns whether a RELAYINIT
+    /// was sent.
+    async fn send_relay_init<P: ProtocolIdentity>(
+        &mut self,
+        node_address: &NodeAddress,
+        request_call: &RequestCall,
+    ) -> bool {
+        let relay = match self.relays.peek(&node_address.node_id) {
+            Some(relay) => relay.clone(),
+            None => return false,
+        };
+
+        // The target must be able to reach us on the same IP version we contacted it on.
+        let initiator_enr = self.enr.read().clone();
+        let contactable = match node_address.socket_addr {
+            SocketAddr::V4(_) => initiator_enr.udp4_socket().is_some(),
+            SocketAddr::V6(_) => initiator_enr.udp6_socket().is_some(),
+        };
+        if !contactable {
+            return false;
+        }
+
+        let notification = Notification::RelayInit {
+            initiator_enr,
+            target: node_address.node_id,
+            nonce: *request_call.packet().message_nonce(),
+        };
+        let src_id = self.session_src_id(&relay);
+        let packet = match self.sessions.get_mut(&relay) {
+            Some(session) => {
+                session.encrypt_message::<P>(src_id, &Message::Notification(notification).encode())
+            }
+            None => return false,
+        };
+
+        match packet {
+            Ok(packet) => {
+                debug!(%relay, target = %node_address, "Sending RELAYINIT");
+                self.send(relay, packet).await;
+                true
+            }
+            Err(e) => {
+                warn!(error = ?e, "Could not encrypt RELAYINIT");
+                false
+            }
+        }
+    }
+
+    /// Sends a `Request` of the application layer to a node, reporting a failure to send it.
+    async fn send_external_request<P: ProtocolIdentity>(
+        &mut self,
+        contact: NodeContact,
+        request: Request,
+        timeout: Option<Duration>,
+    ) {
+        let Request { id, body: request } = request;
+        if let Err(request_error) = self
+            .send_request::<P>(
+                contact,
+                HandlerReqId::External(id.clone()),
+                request,
+                timeout,
+            )
+            .await
+        {
+            // If the sending failed report to the application
+            self.report_failure(id, request_error).await;
+        }
+    }
+
+    /// Finds an identical FINDNODE request to the node that has not been answered yet, which the
+    /// request can share. Returns the id of that request.
+    fn coalescable_request(&self, contact: &NodeContact, request: &Request) -> Option<RequestId> {
+        if !matches!(request.body, RequestBody::FindNode { .. }) {
+            return None;
+        }
+        let node_address = contact.node_address();
+        // Requests that have received part of a multiple Nodes response can't be shared.
+        let active = self
+            .active_requests
+            .get(&node_address)
+            .into_iter()
+            .flatten()
+            .filter(|call| call.remaining_responses().is_none())
+            .map(|call| (call.id(), call.body()));
+        let pending = self
+            .pending_requests
+            .get(&node_address)
+            .into_iter()
+            .flatten()
+            .map(|pending| (&pending.request_id, &pending.request));
+        let sent = active.chain(pending).find_map(|(id, body)| match id {
+            HandlerReqId::External(id) if *body == request.body => Some(id.clone()),
+            _ => None,
+        });
+        sent.or_else(|| {
+            self.establishment_queue
+                .iter()
+                .find(|(queued, queued_request, ..)| {
+                    queued.node_address() == node_address && queued_request.body == request.body
+                })
+                .map(|(_, queued_request, ..)| queued_request.id.clone())
+        })
+    }
+
+    /// Reports a response to the service, along with a copy for each request coalesced into the
+    /// answered one. The coalesced requests are forgotten with the last response.
+    async fn report_response(
+        &mut self,
+        node_address: NodeAddress,
+        response: Response,
+        rtt: Option<Duration>,
+        last: bool,
+    ) {
+        let coalesced = if last {
+            self.coalesced_requests.remove(&response.id)
+        } else {
+            self.coalesced_requests.get(&response.id).cloned()
+        };
+        let copies: Vec<Response> = coalesced
+            .unwrap_or_default()
+            .into_iter()
+            .map(|id| Response {
+                id,
+                body: response.body.clone(),
+            })
+            .collect();
+        for response in std::iter::once(response).chain(copies) {
+            if let Err(e) = self
+                .service_send
+                .send(HandlerOut::Response(
+                    node_address.clone(),
+                    Box::new(response),
+                    rtt,
+                ))
+                .await
+            {
+                warn!(error = %e, "Failed to inform of response")
+            }
+        }
+    }
+
+    /// Reports the failure of a request to the service, along with the requests coalesced into
+    /// it.
+    async fn report_failure(&mut self, id: RequestId, error: RequestError) {
+        let coalesced = self.coalesced_requests.remove(&id).unwrap_or_default();
+        for id in std::iter::once(id).chain(coalesced) {
+            if let Err(e) = self
+                .service_send
+                .send(HandlerOut::RequestFailed(id, error.clone()))
+                .await
+            {
+                warn!(error = %e, "Failed to inform request failure")
+            }
+        }
+    }
+
+    /// Whether a request to the node would start a handshake while the maximum number of sessions
+    /// are being established.
+    fn is_establishment_limited(&mut self, node_address: &NodeAddress) -> bool {
+        let Some(max_establishments) = self.max_session_establishments else {
+            return false;
+        };
+        !self.sessions.contains(node_address)
+            && self.active_challenges.get(node_address).is_none()
+            && !self.handshakes_in_progress.contains_key(node_address)
+            && !self.is_initiating_session(node_address)
+            && self.active_requests.num_initiating_sessions() >= max_establishments
+    }
+
+    /// Queues a request until one of the sessions being established completes. It fails if it
+    /// is still queued after its timeout. If the queue is full, the request dropped to make room,
+    /// or else the request itself, fails.
+    async fn queue_request(
+        &mut self,
+        contact: NodeContact,
+        request: Request,
+        priority: RequestPriority,
+        timeout: Option<Duration>,
+    ) {
+        let queued_at = time::now();
+        let deadline = queued_at + timeout.unwrap_or(self.request_timeout);
+        if let Some((contact, dropped, ..)) = self.establishment_queue.push(
+            priority,
+            (contact, request, timeout, queued_at),
+            deadline,
+        ) {
+            debug!(node = %contact, request_id = %dropped.id, "Session establishment queue full, dropping request");
+            METRICS
+                .queued_requests_dropped
+                .fetch_add(1, Ordering::Relaxed);
+            self.report_failure(
+                dropped.id,
+                RequestError::ChannelFailed("Session establishment queue is full".into()),
+            )
+            .await;
+        }
+    }
+
+    /// Completes when the earliest queued request expires, never if no request is queued.
+    async fn queue_expiry(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => {
+                tokio::time::sleep_until(tokio::time::Instant::from_std(deadline)).await
+            }
+            None => future::pending().await,
+        }
+    }
+
+    /// Fails the queued requests that have waited past their timeout.
+    async fn expire_queued_requests(&mut self) {
+        for (contact, request, ..) in self.establishment_queue.remove_expired(time::now()) {
+            trace!(node = %contact, request_id = %request.id, "Queued request timed out");
+            METRICS
+                .queued_requests_dropped
+                .fetch_add(1, Ordering::Relaxed);
+            self.report_failure(request.id, RequestError::Timeout).await;
+        }
+    }
+
+    /// Sends the queued requests, the most important first, while fewer than the maximum number
+    /// of sessions are being established.
+    async fn send_queued_requests<P: ProtocolIdentity>(&mut self) {
+        while !self.establishment_queue.is_empty() {
+            if self
+                .max_session_establishments
+                .is_some_and(|max_establishments| {
+                    self.active_requests.num_initiating_sessions() >= max_establishments
+                })
+            {
+                return;
+            }
+            if let Some((contact, request, timeout, queued_at)) = self.establishment_queue.pop() {
+                // The time spent queued counts towards the timeout of the request.
+                let waited = time::now().saturating_duration_since(queued_at);
+                let timeout = timeout.map(|timeout| timeout.saturating_sub(waited));
+                self.send_external_request::<P>(contact, request, timeout)
+                    .await;
+            }
+        }
+    }
+
     /// Sends a `Request` to a node.
     async fn send_request<P: ProtocolIdentity>(
         &mut self,
         contact: NodeContact,
         request_id: HandlerReqId,
         request: RequestBody,
+        timeout: Option<Duration>,
     ) -> Result<(), RequestError> {
         let node_address = contact.node_address();
 
@@ -474,12 +1060,14 @@ impl Handler {
             return Err(RequestError::SelfRequest);
         }
 
-        // If there is already an active challenge (WHOAREYOU sent) for this node, or if we are
-        // awaiting a session with this node to be established, add the request to pending requests.
+        // If there is already an active challenge (WHOAREYOU sent) for this node, a handshake
+        // in progress, or if we are awaiting a session with this node to be established, add the
+        // request to pending requests.
         if self.active_challenges.get(&node_address).is_some()
+            || self.handshakes_in_progress.contains_key(&node_address)
             || self.is_awaiting_session_to_be_established(&node_address)
         {
-            trace!(%node_address, "Request queued for node");
+            trace!(%node_address, %request_id, "Request queued for node");
             self.pending_requests
                 .entry(node_address)
                 .or_default()
@@ -487,23 +1075,52 @@ impl Handler {
                     contact,
                     request_id,
                     request,
+                    timeout,
+                    queued_at: time::now(),
                 });
             return Ok(());
         }
 
+        // A session whose message counter is nearly exhausted is re-keyed by starting a new
+        // handshake. The session remains in use until the new keys are established. Sessions
+        // established under a retired identity are replaced the same way.
+        let exhausted = self
+            .sessions
+            .get_mut(&node_address)
+            .is_some_and(|session| session.needs_rekey());
+        let rekey = (exhausted || self.is_retired_session(&node_address))
+            && !self.is_initiating_session(&node_address);
+        if rekey && exhausted {
+            debug!(%node_address, "Message counter nearly exhausted, re-keying session");
+            METRICS.rekeys.fetch_add(1, Ordering::Relaxed);
+        }
+        let src_id = self.session_src_id(&node_address);
+        let message = || match &request_id {
+            HandlerReqId::Internal(id) | HandlerReqId::External(id) => Request {
+                id: id.clone(),
+                body: request.clone(),
+            }
+            .encode(),
+        };
+
         let (packet, initiating_session) = {
-            if let Some(session) = self.sessions.get_mut(&node_address) {
+            if let Some(session) = self.sessions.get_mut(&node_address).filter(|_| !rekey) {
                 // Encrypt the message and send
-                let request = match &request_id {
-                    HandlerReqId::Internal(id) | HandlerReqId::External(id) => Request {
-                        id: id.clone(),
-                        body: request.clone(),
-                    },
-                };
                 let packet = session
-                    .encrypt_message::<P>(self.node_id, &request.encode())
+                    .encrypt_message::<P>(src_id, &message())
                     .map_err(|e| RequestError::EncryptionFailed(format!("{e:?}")))?;
                 (packet, false)
+            } else if let Some((mut session, enr)) = self.take_resumption_ticket(&node_address) {
+                // Resume the session without a handshake. The session is established once the
+                // peer answers under its keys. A peer that no longer holds the ticket challenges
+                // the request as it would that of an expired session, starting a handshake.
+                debug!(%node_address, "Resuming session");
+                let packet = session
+                    .encrypt_message::<P>(src_id, &message())
+                    .map_err(|e| RequestError::EncryptionFailed(format!("{e:?}")))?;
+                self.resuming_sessions
+                    .insert(node_address.clone(), (session, enr));
+                (packet, true)
             } else {
                 // No session exists, start a new handshake initiating a new session
                 trace!(
@@ -516,16 +1133,21 @@ impl Handler {
             }
         };
 
-        let call = RequestCall::new(
+        let mut call = RequestCall::new(
             contact,
             packet.clone(),
             request_id,
             request,
             initiating_session,
         );
+        if let Some(timeout) = timeout {
+            call.set_timeout(timeout);
+        }
         // let the filter know we are expecting a response
         self.add_expected_response(node_address.socket_addr);
-        self.send(node_address.clone(), packet).await;
+        trace!(%node_address, request_id = %call.id(), body = %call.body(), "Sending request");
+        self.send_request_packet(node_address.clone(), packet, call.id())
+            .await;
 
         self.active_requests.insert(node_address, call);
         Ok(())
@@ -538,8 +1160,19 @@ impl Handler {
         response: Response,
     ) {
         // Check for an established session
-        let packet = if let Some(session) = self.sessions.get_mut(&node_address) {
-            session.encrypt_message::<P>(self.node_id, &response.encode())
+        let src_id = self.session_src_id(&node_address);
+        let packet = if self.sessions.contains(&node_address) {
+            // Prevent peers from using us as a bandwidth amplifier
+            let Some(message) = self.limit_response(&node_address.node_id, response) else {
+                return debug!(
+                    node = %node_address.node_id,
+                    "Outbound response limit reached. Dropping response",
+                );
+            };
+            let Some(session) = self.sessions.get_mut(&node_address) else {
+                return;
+            };
+            session.encrypt_message::<P>(src_id, &message)
         } else {
             // Either the session is being established or has expired. We simply drop the
             // response in this case.
@@ -556,6 +1189,86 @@ impl Handler {
         }
     }
 
+    /// Encodes the response if it is within the outbound response limit of the peer. A NODES
+    /// response over the limit is cut down to the nodes that fit, rather than dropped.
+    fn limit_response(&mut self, node_id: &NodeId, mut response: Response) -> Option<Vec<u8>> {
+        let limited = matches!(
+            response.body,
+            ResponseBody::Nodes { .. } | ResponseBody::Talk { .. }
+        );
+        let Some(limiter) = self.response_limiter.as_mut().filter(|_| limited) else {
+            return Some(response.encode());
+        };
+        let message = response.clone().encode();
+        let now = time::now().saturating_duration_since(self.init_time);
+        if limiter.allows(now, node_id, message.len() as u64).is_ok() {
+            return Some(message);
+        }
+        loop {
+            match &mut response.body {
+                ResponseBody::Nodes { nodes, .. } if nodes.len() > 1 => {
+                    nodes.pop();
+                }
+                _ => return None,
+            }
+            let message = response.clone().encode();
+            if limiter.allows(now, node_id, message.len() as u64).is_ok() {
+                trace!(%node_id, bytes = message.len(), "Truncated NODES response to the outbound response limit");
+                return Some(message);
+            }
+        }
+    }
+
+    /// Sends an RPC Response, establishing a session with the node first if there is none.
+    async fn send_response_establishing_session<P: ProtocolIdentity>(
+        &mut self,
+        contact: NodeContact,
+        response: Response,
+    ) {
+        let node_address = contact.node_address();
+        if self.sessions.contains(&node_address) {
+            return self.send_response::<P>(node_address, response).await;
+        }
+        let held = self.held_responses.entry(node_address.clone()).or_default();
+        if held.len() >= MAX_MESSAGES_DURING_HANDSHAKE {
+            return debug!(%node_address, "Too many responses awaiting a session. Dropping response");
+        }
+        trace!(%node_address, %response, "Response held until the session is established");
+        held.push(response);
+
+        // The response is sent with the session being established, if any. Otherwise a PING
+        // starts a handshake.
+        if self.active_challenges.get(&node_address).is_some()
+            || self.handshakes_in_progress.contains_key(&node_address)
+            || self.is_awaiting_session_to_be_established(&node_address)
+        {
+            return;
+        }
+        let request = RequestBody::Ping {
+            enr_seq: self.enr.read().seq(),
+        };
+        if let Err(e) = self
+            .send_request::<P>(
+                contact,
+                HandlerReqId::Internal(RequestId::random()),
+                request,
+                None,
+            )
+            .await
+        {
+            warn!(error = %e, "Failed to start a session for a response");
+            self.held_responses.remove(&node_address);
+        }
+    }
+
+    /// Sends the responses held until the session with the node was established.
+    async fn send_held_responses<P: ProtocolIdentity>(&mut self, node_address: &NodeAddress) {
+        for response in self.held_responses.remove(node_address).unwrap_or_default() {
+            self.send_response::<P>(node_address.clone(), response)
+                .await;
+        }
+    }
+
     /// This is called in response to a `HandlerOut::WhoAreYou` event. The applications finds the
     /// highest known ENR for a node then we respond to the node with a WHOAREYOU packet.
     async fn send_challenge<P: ProtocolIdentity>(
@@ -571,6 +1284,15 @@ impl Handler {
             return;
         }
 
+        // Bound the memory spoofed packets can make us spend on challenges.
+        if self.active_challenges.len() >= self.max_active_challenges {
+            debug!(%node_address, "Too many active challenges, not sending WHOAREYOU");
+            METRICS
+                .challenges_suppressed
+                .fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
         // NOTE: We do not check if we have an active session here. This was checked before
         // requesting the ENR from the service. It could be the case we have established a session
         // in the meantime, we allow this challenge to establish a second session in the event this
@@ -599,6 +1321,23 @@ impl Handler {
         );
     }
 
+    /// Whether the challenge rate limit allows a WHOAREYOU to be sent to the node's IP.
+    fn challenge_allowed(&mut self, node_address: &NodeAddress) -> bool {
+        let Some(limiter) = self.challenge_limiter.as_mut() else {
+            return true;
+        };
+        let ip = node_address.socket_addr.ip();
+        let now = time::now().saturating_duration_since(self.init_time);
+        if limiter.allows(now, &ip, 1).is_err() {
+            debug!(%ip, "Challenge rate limit exceeded, not sending WHOAREYOU");
+            METRICS
+                .challenges_suppressed
+                .fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
     /* Packet Handling */
 
     /// Handles a WHOAREYOU packet that was received from the network.
@@ -611,7 +1350,7 @@ impl Handler {
     ) {
         // Check that this challenge matches a known active request.
         // If this message passes all the requisite checks, a request call is returned.
-        let mut request_call = match self.active_requests.remove_by_nonce(&request_nonce) {
+        let request_call = match self.active_requests.remove_by_nonce(&request_nonce) {
             Some((node_address, request_call)) => {
                 // Verify that the src_addresses match
                 if node_address.socket_addr != src_address {
@@ -633,6 +1372,12 @@ impl Handler {
                     message_nonce = hex::encode(request_nonce),
                     "Received a WHOAREYOU packet that references an unknown or expired request."
                 );
+                AUDIT_LOG.record(
+                    SecurityEventKind::ReplayedNonce,
+                    Some(src_address.ip()),
+                    None,
+                    Some(PacketType::WhoAreYou),
+                );
                 return;
             }
         };
@@ -656,6 +1401,16 @@ impl Handler {
             "Received a WHOAREYOU packet response.",
         );
 
+        // A challenge to a resumed session means the peer doesn't hold the ticket. The session
+        // is established by the handshake instead.
+        if self
+            .resuming_sessions
+            .remove(&request_call.contact().node_address())
+            .is_some()
+        {
+            debug!(node = %request_call.contact(), "Session resumption rejected, starting a handshake");
+        }
+
         // We do not allow multiple WHOAREYOU packets for a single challenge request. If we have
         // already sent a WHOAREYOU ourselves, we drop sessions who send us a WHOAREYOU in
         // response.
@@ -664,8 +1419,8 @@ impl Handler {
                 node = %request_call.contact(),
                 "Authentication response already sent. Dropping session.",
             );
-            self.fail_request(request_call, RequestError::InvalidRemotePacket, true)
-                .await;
+            let error = RequestError::InvalidRemotePacket(request_call.contact().node_id());
+            self.fail_request(request_call, error, true).await;
             return;
         }
 
@@ -679,19 +1434,28 @@ impl Handler {
         };
 
         // Generate a new session and authentication packet
-        let (auth_packet, mut session) = match Session::encrypt_with_header::<P>(
-            request_call.contact(),
-            self.key.clone(),
+        let node_address = request_call.contact().node_address();
+        let job = HandshakeJob::Answer {
+            request_call: Box::new(request_call),
             updated_enr,
-            &self.node_id,
-            &challenge_data,
-            &request_call.encode(),
-        ) {
+            challenge_data,
+        };
+        self.process_handshake::<P>(node_address, job).await;
+    }
+
+    /// Sends the handshake answering a challenge to one of our requests, once generated by the
+    /// crypto pool.
+    async fn complete_challenge<P: ProtocolIdentity>(
+        &mut self,
+        mut request_call: RequestCall,
+        result: Result<(Packet, Session), Error>,
+    ) {
+        let (auth_packet, mut session) = match result {
             Ok(v) => v,
             Err(e) => {
                 error!(error = ?e, "Could not generate a session");
-                self.fail_request(request_call, RequestError::InvalidRemotePacket, true)
-                    .await;
+                let error = RequestError::SessionGenerationFailed(format!("{e:?}"));
+                self.fail_request(request_call, error, true).await;
                 return;
             }
         };
@@ -712,6 +1476,16 @@ impl Handler {
         let node_address = request_call.contact().node_address();
         let auth_message_nonce = auth_packet.header.message_nonce;
         match request_call.contact().enr() {
+            Some(enr) if !self.is_allowed(&enr) => {
+                debug!(%node_address, "Node is not in the ENR allowlist. Dropping session");
+                self.fail_request(
+                    request_call,
+                    RequestError::InvalidRemoteEnr(node_address.node_id),
+                    true,
+                )
+                .await;
+                return;
+            }
             Some(enr) => {
                 // NOTE: Here we decide if the session is outgoing or ingoing. The condition for an
                 // outgoing session is that we originally sent a RANDOM packet (signifying we did
@@ -726,16 +1500,19 @@ impl Handler {
                 // We already know the ENR. Send the handshake response packet
                 trace!(
                     %node_address,
-                    request_call.id = ?request_call.id(),
+                    request_id = %request_call.id(),
                     "Sending Authentication response to node",
                 );
                 request_call.update_packet(auth_packet.clone());
                 request_call.set_handshake_sent();
                 request_call.set_initiating_session(false);
+                let request_id = request_call.id().clone();
                 // Reinsert the request_call
                 self.insert_active_request(request_call);
                 // Send the actual packet to the send task.
-                self.send(node_address.clone(), auth_packet).await;
+                self.send_request_packet(node_address.clone(), auth_packet, &request_id)
+                    .await;
+                self.store_resumption_ticket(&node_address, &session, &enr);
 
                 // Notify the application that the session has been established
                 self.service_send
@@ -754,20 +1531,22 @@ impl Handler {
                 let contact = request_call.contact().clone();
                 trace!(
                     %node_address,
-                    request_call.id = ?request_call.id(),
+                    request_id = %request_call.id(),
                     "Sending Authentication response to node",
                 );
                 request_call.update_packet(auth_packet.clone());
                 request_call.set_handshake_sent();
+                let request_id = request_call.id().clone();
                 // Reinsert the request_call
                 self.insert_active_request(request_call);
-                self.send(node_address.clone(), auth_packet).await;
+                self.send_request_packet(node_address.clone(), auth_packet, &request_id)
+                    .await;
 
                 let id = RequestId::random();
                 let request = RequestBody::FindNode { distances: vec![0] };
                 session.awaiting_enr = Some(id.clone());
                 if let Err(e) = self
-                    .send_request::<P>(contact, HandlerReqId::Internal(id), request)
+                    .send_request::<P>(contact, HandlerReqId::Internal(id), request, None)
                     .await
                 {
                     warn!(error = %e, "Failed to send Enr request")
@@ -814,7 +1593,15 @@ impl Handler {
             }
     }
 
-    async fn notify_unverifiable_enr(&self, enr: Enr, socket: SocketAddr, node_id: NodeId) {
+    /// Returns whether sessions may be established with the node of this ENR.
+    fn is_allowed(&self, enr: &Enr) -> bool {
+        match &self.enr_allowlist {
+            Some(allowlist) => allowlist.allows(enr),
+            None => true,
+        }
+    }
+
+    async fn notify_unverifiable_enr(&self, enr: Arc<Enr>, socket: SocketAddr, node_id: NodeId) {
         self.service_send
             .send(HandlerOut::UnverifiableEnr {
                 enr,
@@ -826,112 +1613,201 @@ impl Handler {
     }
 
     /// Handle a message that contains an authentication header.
-    #[allow(clippy::too_many_arguments)]
-    async fn handle_auth_message<P: ProtocolIdentity>(
-        &mut self,
-        node_address: NodeAddress,
-        message_nonce: MessageNonce,
-        id_nonce_sig: &[u8],
-        ephem_pubkey: &[u8],
-        enr_record: Option<Enr>,
-        message: &[u8],
-        authenticated_data: &[u8],
-    ) {
+    async fn handle_auth_message<P: ProtocolIdentity>(&mut self, handshake: InboundHandshake) {
         // Needs to match an outgoing challenge packet (so we have the required nonce to be signed). If it doesn't we drop the packet.
         // This will lead to future outgoing challenges if they proceed to send further encrypted
         // packets.
+        let node_address = handshake.node_address.clone();
         trace!(
             from = %node_address,
             "Received an Authentication header message",
         );
 
         if let Some(challenge) = self.active_challenges.remove(&node_address) {
-            match Session::establish_from_challenge(
-                self.key.clone(),
-                &self.node_id,
-                &node_address.node_id,
+            let job = HandshakeJob::Verify {
+                handshake: Box::new(handshake),
                 challenge,
-                id_nonce_sig,
-                ephem_pubkey,
-                enr_record,
-            ) {
-                Ok((session, enr)) => {
-                    // Remove the expected response for the challenge.
-                    self.remove_expected_response(node_address.socket_addr);
-                    // Receiving an AuthResponse must give us an up-to-date view of the node ENR.
-                    // Verify the ENR is valid
-                    if self.verify_enr(&enr, &node_address) {
-                        // Session is valid
-                        // Notify the application
-                        // The session established here are from WHOAREYOU packets that we sent.
-                        // This occurs when a node established a connection with us.
-                        if let Err(e) = self
-                            .service_send
-                            .send(HandlerOut::Established(
-                                enr,
-                                node_address.socket_addr,
-                                ConnectionDirection::Incoming,
-                            ))
-                            .await
-                        {
-                            warn!(error = %e, "Failed to inform of established session")
-                        }
-                    } else {
-                        // IP's or NodeAddress don't match.
-                        //
-                        // We still handle the request, but we do not add the ENR to our routing
-                        // table or consider the ENR valid.
-                        debug!(
-                            udp4_socket = ?enr.udp4_socket(),
-                            udp6_socket = ?enr.udp6_socket(),
-                            expected = %node_address,
-                            "Session has invalid ENR",
-                        );
-
-                        // The ENR doesn't verify. Notify application.
-                        self.notify_unverifiable_enr(
+            };
+            self.process_handshake::<P>(node_address, job).await;
+        } else {
+            warn!(
+                node_id = %node_address.node_id, addr = %node_address.socket_addr,
+                "Received an authenticated header without a matching WHOAREYOU request",
+            );
+            AUDIT_LOG.record(
+                SecurityEventKind::ReplayedNonce,
+                Some(node_address.socket_addr.ip()),
+                Some(node_address.node_id),
+                Some(PacketType::Handshake),
+            );
+        }
+    }
+
+    /// Completes a handshake answering our challenge, once verified by the crypto pool.
+    async fn complete_auth_message<P: ProtocolIdentity>(
+        &mut self,
+        handshake: InboundHandshake,
+        result: Result<(Session, Enr), Error>,
+    ) {
+        let InboundHandshake {
+            node_address,
+            message_nonce,
+            message,
+            authenticated_data,
+            ..
+        } = handshake;
+        match result {
+            Ok((_, enr)) if !self.is_allowed(&enr) => {
+                debug!(%node_address, "Node is not in the ENR allowlist. Dropping session");
+                self.remove_expected_response(node_address.socket_addr);
+                self.fail_session(
+                    &node_address,
+                    RequestError::InvalidRemoteEnr(node_address.node_id),
+                    true,
+                )
+                .await;
+            }
+            Ok((session, enr)) => {
+                // Remove the expected response for the challenge.
+                self.remove_expected_response(node_address.socket_addr);
+                // Receiving an AuthResponse must give us an up-to-date view of the node ENR.
+                // Verify the ENR is valid
+                if self.verify_enr(&enr, &node_address) {
+                    let enr = Arc::new(enr);
+                    self.store_resumption_ticket(&node_address, &session, &enr);
+                    // Session is valid
+                    // Notify the application
+                    // The session established here are from WHOAREYOU packets that we sent.
+                    // This occurs when a node established a connection with us.
+                    if let Err(e) = self
+                        .service_send
+                        .send(HandlerOut::Established(
                             enr,
                             node_address.socket_addr,
-                            node_address.node_id,
-                        )
-                        .await;
+                            ConnectionDirection::Incoming,
+                        ))
+                        .await
+                    {
+                        warn!(error = %e, "Failed to inform of established session")
                     }
+                } else {
+                    // IP's or NodeAddress don't match.
+                    //
+                    // We still handle the request, but we do not add the ENR to our routing
+                    // table or consider the ENR valid.
+                    debug!(
+                        udp4_socket = ?enr.udp4_socket(),
+                        udp6_socket = ?enr.udp6_socket(),
+                        expected = %node_address,
+                        "Session has invalid ENR",
+                    );
 
-                    // When (re-)establishing a session from an outgoing challenge, we do not need
-                    // to filter out this request from active requests, so we do not pass
-                    // the message nonce on to `new_session`.
-                    self.new_session::<P>(node_address.clone(), session, None)
-                        .await;
-                    self.handle_message(
-                        node_address.clone(),
-                        message_nonce,
-                        message,
-                        authenticated_data,
+                    // The ENR doesn't verify. Notify application.
+                    self.notify_unverifiable_enr(
+                        Arc::new(enr),
+                        node_address.socket_addr,
+                        node_address.node_id,
                     )
                     .await;
                 }
-                Err(Error::InvalidChallengeSignature(challenge)) => {
-                    warn!(
-                        %node_address,
-                        "Authentication header contained invalid signature. Ignoring packet from node",
-                    );
-                    // insert back the challenge
-                    self.active_challenges.insert(node_address, challenge);
-                }
-                Err(e) => {
-                    warn!(
-                        error = ?e,
-                        "Invalid Authentication header. Dropping session",
-                    );
-                    self.fail_session(&node_address, RequestError::InvalidRemotePacket, true)
-                        .await;
-                }
+
+                // When (re-)establishing a session from an outgoing challenge, we do not need
+                // to filter out this request from active requests, so we do not pass
+                // the message nonce on to `new_session`.
+                self.new_session::<P>(node_address.clone(), session, None)
+                    .await;
+                self.handle_message::<P>(
+                    node_address.clone(),
+                    message_nonce,
+                    &message,
+                    &authenticated_data,
+                    time::now(),
+                )
+                .await;
+            }
+            Err(Error::InvalidChallengeSignature(challenge)) => {
+                warn!(
+                    %node_address,
+                    "Authentication header contained invalid signature. Ignoring packet from node",
+                );
+                AUDIT_LOG.record(
+                    SecurityEventKind::ForgedHandshake,
+                    Some(node_address.socket_addr.ip()),
+                    Some(node_address.node_id),
+                    Some(PacketType::Handshake),
+                );
+                // insert back the challenge
+                self.active_challenges.insert(node_address, challenge);
+            }
+            Err(e) => {
+                warn!(
+                    error = ?e,
+                    "Invalid Authentication header. Dropping session",
+                );
+                self.fail_session(
+                    &node_address,
+                    RequestError::InvalidRemotePacket(node_address.node_id),
+                    true,
+                )
+                .await;
             }
-        } else {
-            warn!(
-                node_id = %node_address.node_id, addr = %node_address.socket_addr,
-                "Received an authenticated header without a matching WHOAREYOU request",
-            );
+        }
+    }
+
+    /// Hands the cryptographic work of a handshake to the crypto pool. Without workers, the
+    /// handshake completes before this returns.
+    async fn process_handshake<P: ProtocolIdentity>(
+        &mut self,
+        node_address: NodeAddress,
+        job: HandshakeJob,
+    ) {
+        self.handshakes_in_progress
+            .entry(node_address)
+            .or_default()
+            .jobs += 1;
+        if let Some(outcome) = self.crypto_pool.process::<P>(job) {
+            self.handle_handshake_outcome::<P>(outcome).await;
+        }
+    }
+
+    /// Completes a handshake processed by the crypto pool. Once no handshake with the peer is in
+    /// progress, the messages and requests held back in the meantime are processed.
+    async fn handle_handshake_outcome<P: ProtocolIdentity>(&mut self, outcome: HandshakeOutcome) {
+        let node_address = outcome.node_address();
+        let held_back = match self.handshakes_in_progress.get_mut(&node_address) {
+            Some(in_progress) if in_progress.jobs > 1 => {
+                in_progress.jobs -= 1;
+                None
+            }
+            Some(_) => self
+                .handshakes_in_progress
+                .remove(&node_address)
+                .map(|in_progress| in_progress.messages),
+            None => None,
+        };
+
+        match outcome {
+            HandshakeOutcome::Verified { handshake, result } => {
+                self.complete_auth_message::<P>(*handshake, result).await
+            }
+            HandshakeOutcome::Answered {
+                request_call,
+                result,
+            } => self.complete_challenge::<P>(*request_call, result).await,
+        }
+
+        if let Some(messages) = held_back {
+            for (message_nonce, message, authenticated_data) in messages {
+                self.handle_message::<P>(
+                    node_address.clone(),
+                    message_nonce,
+                    &message,
+                    &authenticated_data,
+                    time::now(),
+                )
+                .await;
+            }
+            self.send_pending_requests::<P>(&node_address).await;
         }
     }
 
@@ -950,7 +1826,12 @@ impl Handler {
                 "Sending pending request",
             );
             if let Err(request_error) = self
-                .send_request::<P>(req.contact, req.request_id.clone(), req.request)
+                .send_request::<P>(
+                    req.contact,
+                    req.request_id.clone(),
+                    req.request,
+                    req.timeout,
+                )
                 .await
             {
                 warn!(error = %request_error, "Failed to send next pending request");
@@ -960,15 +1841,7 @@ impl Handler {
                         // An internal request could not be sent. For now we do nothing about
                         // this.
                     }
-                    HandlerReqId::External(id) => {
-                        if let Err(e) = self
-                            .service_send
-                            .send(HandlerOut::RequestFailed(id, request_error))
-                            .await
-                        {
-                            warn!(error = %e, "Failed to inform that request failed");
-                        }
-                    }
+                    HandlerReqId::External(id) => self.report_failure(id, request_error).await,
                 }
             }
         }
@@ -1009,10 +1882,14 @@ impl Handler {
                 if let Ok(new_packet) =
                     session.encrypt_message::<P>(self.node_id, &request_call.encode())
                 {
-                    packets.push((*request_call.packet().message_nonce(), new_packet));
+                    packets.push((
+                        *request_call.packet().message_nonce(),
+                        new_packet,
+                        request_call.id().clone(),
+                    ));
                 } else {
                     error!(
-                        id = ?request_call.id(),
+                        request_id = %request_call.id(),
                         "Failed to re-encrypt packet while replaying active request with id",
                     );
                 }
@@ -1025,31 +1902,95 @@ impl Handler {
             return;
         };
 
-        for (old_nonce, new_packet) in packets {
+        for (old_nonce, new_packet, request_id) in packets {
             self.active_requests
                 .update_packet(old_nonce, new_packet.clone());
-            self.send(node_address.clone(), new_packet).await;
+            self.send_request_packet(node_address.clone(), new_packet, &request_id)
+                .await;
         }
     }
 
     /// Handle a standard message that does not contain an authentication header.
     #[allow(clippy::single_match)]
-    async fn handle_message(
+    async fn handle_message<P: ProtocolIdentity>(
         &mut self,
         node_address: NodeAddress,
         message_nonce: MessageNonce,
         message: &[u8],
         authenticated_data: &[u8],
+        received_at: Instant,
     ) {
+        // Messages sent while a handshake is in progress may be encrypted with its keys.
+        if let Some(in_progress) = self.handshakes_in_progress.get_mut(&node_address) {
+            if in_progress.messages.len() < MAX_MESSAGES_DURING_HANDSHAKE {
+                in_progress.messages.push((
+                    message_nonce,
+                    message.to_vec(),
+                    authenticated_data.to_vec(),
+                ));
+            } else {
+                trace!(%node_address, "Dropping message received during handshake");
+            }
+            return;
+        }
+
+        // Without a session, the message may confirm the session we resumed, or resume the last
+        // one.
+        if let Some((session, enr)) =
+            self.resumed_session(&node_address, message_nonce, message, authenticated_data)
+        {
+            debug!(%node_address, "Peer confirmed resumed session");
+            self.session_resumed(&node_address, &session, enr, ConnectionDirection::Outgoing)
+                .await;
+            self.new_session::<P>(node_address.clone(), session, None)
+                .await;
+        } else if let Some((session, enr)) =
+            self.resumable_session(&node_address, message_nonce, message, authenticated_data)
+        {
+            debug!(%node_address, "Peer resumed session");
+            self.session_resumed(&node_address, &session, enr, ConnectionDirection::Incoming)
+                .await;
+            self.new_session::<P>(node_address.clone(), session, None)
+                .await;
+        }
+
         // check if we have an available session
         if let Some(session) = self.sessions.get_mut(&node_address) {
             // attempt to decrypt and process the message.
             let message = match session.decrypt_message(message_nonce, message, authenticated_data)
             {
+                Ok(_) if session.is_replay(&message_nonce) => {
+                    debug!(
+                        %node_address,
+                        message_nonce = hex::encode(message_nonce),
+                        "Dropping a replayed message",
+                    );
+                    METRICS.replayed_messages.fetch_add(1, Ordering::Relaxed);
+                    AUDIT_LOG.record(
+                        SecurityEventKind::ReplayedMessage,
+                        Some(node_address.socket_addr.ip()),
+                        Some(node_address.node_id),
+                        Some(PacketType::Message),
+                    );
+                    return;
+                }
                 Ok(m) => match Message::decode(&m) {
                     Ok(p) => p,
                     Err(e) => {
                         warn!(error = ?e, %node_address, "Failed to decode message");
+                        // The ENRs of NODES responses are verified as they are decoded.
+                        if matches!(e, DecoderError::Custom("Invalid Signature")) {
+                            if let Err(e) = self
+                                .service_send
+                                .send(HandlerOut::InvalidResponse(
+                                    node_address,
+                                    ResponseViolation::InvalidSignature,
+                                ))
+                                .await
+                            {
+                                warn!(error = %e, "Failed to inform of invalid response")
+                            }
+                        }
                         return;
                     }
                 },
@@ -1063,11 +2004,17 @@ impl Handler {
                         %node_address,
                         "Message from node is not encrypted with known session keys.",
                     );
-                    self.fail_session(&node_address, RequestError::InvalidRemotePacket, true)
-                        .await;
+                    self.fail_session(
+                        &node_address,
+                        RequestError::InvalidRemotePacket(node_address.node_id),
+                        true,
+                    )
+                    .await;
                     // If we haven't already sent a WhoAreYou,
                     // spawn a WHOAREYOU event to check for highest known ENR
-                    if self.active_challenges.get(&node_address).is_none() {
+                    if self.active_challenges.get(&node_address).is_none()
+                        && self.challenge_allowed(&node_address)
+                    {
                         let whoareyou_ref = WhoAreYouRef(node_address, message_nonce);
                         if let Err(e) = self
                             .service_send
@@ -1107,7 +2054,18 @@ impl Handler {
                                 ResponseBody::Nodes { mut nodes, .. } => {
                                     // Received the requested ENR
                                     if let Some(enr) = nodes.pop() {
-                                        if self.verify_enr(&enr, &node_address) {
+                                        if !self.is_allowed(&enr) {
+                                            debug!(%node_address, "Node is not in the ENR allowlist. Dropping session");
+                                            self.fail_session(
+                                                &node_address,
+                                                RequestError::InvalidRemoteEnr(
+                                                    node_address.node_id,
+                                                ),
+                                                true,
+                                            )
+                                            .await;
+                                            return;
+                                        } else if self.verify_enr(&enr, &node_address) {
                                             // Notify the application
                                             // This can occur when we try to dial a node without an
                                             // ENR. In this case we have attempted to establish the
@@ -1124,33 +2082,54 @@ impl Handler {
                                                 warn!(error = %e, "Failed to inform established outgoing connection")
                                             }
                                             return;
+                                        } else {
+                                            // The ENR doesn't verify. Notify application.
+                                            self.notify_unverifiable_enr(
+                                                enr,
+                                                node_address.socket_addr,
+                                                node_address.node_id,
+                                            )
+                                            .await;
                                         }
-
-                                        // The ENR doesn't verify. Notify application.
-                                        self.notify_unverifiable_enr(
-                                            enr,
-                                            node_address.socket_addr,
-                                            node_address.node_id,
-                                        )
-                                        .await;
                                     }
                                 }
                                 _ => {}
                             }
 
                             debug!("Session failed invalid ENR response");
-                            self.fail_session(&node_address, RequestError::InvalidRemoteEnr, true)
-                                .await;
+                            self.fail_session(
+                                &node_address,
+                                RequestError::InvalidRemoteEnr(node_address.node_id),
+                                true,
+                            )
+                            .await;
                             return;
                         }
                     }
+                    // Remember who told us about these nodes, in case we need a relay to reach
+                    // them.
+                    if let ResponseBody::Nodes { nodes, .. } = &response.body {
+                        for enr in nodes {
+                            if enr.node_id() != node_address.node_id {
+                                self.relays.insert(enr.node_id(), node_address.clone());
+                            }
+                        }
+                    }
                     // Handle standard responses
-                    self.handle_response(node_address, response).await;
+                    self.handle_response(node_address, response, received_at)
+                        .await;
+                }
+                Message::Notification(notification) => {
+                    self.handle_notification::<P>(node_address, notification)
+                        .await;
                 }
             }
         } else {
             // no session exists
             trace!(%node_address, "Received a message without a session.");
+            if !self.challenge_allowed(&node_address) {
+                return;
+            }
             trace!("Requesting a WHOAREYOU packet to be sent.");
             // spawn a WHOAREYOU event to check for highest known ENR
             let whoareyou_ref = WhoAreYouRef(node_address, message_nonce);
@@ -1167,14 +2146,121 @@ impl Handler {
         }
     }
 
+    /// Handles a NAT hole punching notification received within a session.
+    async fn handle_notification<P: ProtocolIdentity>(
+        &mut self,
+        node_address: NodeAddress,
+        notification: Notification,
+    ) {
+        match notification {
+            Notification::RelayInit {
+                initiator_enr,
+                target,
+                nonce,
+            } => {
+                if !self.enable_relay {
+                    trace!(%node_address, "Relaying is disabled. Ignoring RELAYINIT");
+                    return;
+                }
+                // Only the initiator may ask for its own handshake to be relayed.
+                if initiator_enr.node_id() != node_address.node_id {
+                    debug!(%node_address, "RELAYINIT initiator does not match the sender");
+                    return;
+                }
+                let target_address = match self
+                    .sessions
+                    .keys()
+                    .find(|address| address.node_id == target)
+                {
+                    Some(address) => address.clone(),
+                    None => {
+                        trace!(%target, "No session with RELAYINIT target");
+                        return;
+                    }
+                };
+                // The target punches towards the socket we observe the initiator at, which holds
+                // the NAT mapping the initiator's retried message will leave from.
+                let notification = Notification::RelayMsg {
+                    initiator_enr,
+                    initiator_socket: node_address.socket_addr,
+                    nonce,
+                };
+                let src_id = self.session_src_id(&target_address);
+                let packet = match self.sessions.get_mut(&target_address) {
+                    Some(session) => session.encrypt_message::<P>(
+                        src_id,
+                        &Message::Notification(notification).encode(),
+                    ),
+                    None => return,
+                };
+                match packet {
+                    Ok(packet) => {
+                        debug!(initiator = %node_address, target = %target_address, "Relaying RELAYMSG");
+                        self.send(target_address, packet).await;
+                    }
+                    Err(e) => warn!(error = ?e, "Could not encrypt RELAYMSG"),
+                }
+            }
+            Notification::RelayMsg {
+                initiator_enr,
+                initiator_socket,
+                nonce,
+            } => {
+                let initiator_address = NodeAddress {
+                    socket_addr: initiator_socket,
+                    node_id: initiator_enr.node_id(),
+                };
+                if self.sessions.contains(&initiator_address)
+                    || self.listen_sockets.contains(&initiator_address.socket_addr)
+                {
+                    trace!(initiator = %initiator_address, "Ignoring RELAYMSG");
+                    return;
+                }
+                let now = time::now().saturating_duration_since(self.init_time);
+                if self
+                    .relay_limiter
+                    .allows(now, &node_address.node_id, 1)
+                    .is_err()
+                {
+                    debug!(relay = %node_address, "Relay rate limit exceeded, ignoring RELAYMSG");
+                    return;
+                }
+                // Answering the initiator's timed out message with a WHOAREYOU opens our NAT to
+                // the initiator, who then completes the handshake as normal.
+                debug!(initiator = %initiator_address, relay = %node_address, "Received RELAYMSG");
+                self.send_challenge::<P>(
+                    WhoAreYouRef(initiator_address, nonce),
+                    Some(initiator_enr),
+                )
+                .await;
+            }
+        }
+    }
+
     /// Handles a response to a request. Re-inserts the request call if the response is a multiple
     /// Nodes response.
-    async fn handle_response(&mut self, node_address: NodeAddress, response: Response) {
+    async fn handle_response(
+        &mut self,
+        node_address: NodeAddress,
+        response: Response,
+        received_at: Instant,
+    ) {
         // Find a matching request, if any
         if let Some(mut request_call) = self
             .active_requests
             .remove_request(&node_address, &response.id)
         {
+            trace!(%node_address, request_id = %response.id, "Received response");
+            // Only the first of multiple Nodes responses measures the round trip.
+            let rtt = if request_call.remaining_responses_mut().is_none() {
+                request_call.round_trip_time(received_at)
+            } else {
+                None
+            };
+            if let Some(rtt) = rtt {
+                trace!(%node_address, ?rtt, "Measured round trip time");
+                METRICS.add_rtt_sample(rtt);
+            }
             // The response matches a request
             // Check to see if this is a Nodes response, in which case we may require to wait for
             // extra responses
@@ -1188,13 +2274,8 @@ impl Handler {
                             // add back the request and send the response
                             self.active_requests
                                 .insert(node_address.clone(), request_call);
-                            if let Err(e) = self
-                                .service_send
-                                .send(HandlerOut::Response(node_address, Box::new(response)))
-                                .await
-                            {
-                                warn!(error = %e, "Failed to inform of response")
-                            }
+                            self.report_response(node_address, response, rtt, false)
+                                .await;
                             return;
                         }
                     } else {
@@ -1203,13 +2284,8 @@ impl Handler {
                         // add back the request and send the response
                         self.active_requests
                             .insert(node_address.clone(), request_call);
-                        if let Err(e) = self
-                            .service_send
-                            .send(HandlerOut::Response(node_address, Box::new(response)))
-                            .await
-                        {
-                            warn!(error = %e, "Failed to inform of response")
-                        }
+                        self.report_response(node_address, response, rtt, false)
+                            .await;
                         return;
                     }
                 }
@@ -1218,21 +2294,18 @@ impl Handler {
             // Remove the expected response
             self.remove_expected_response(node_address.socket_addr);
 
-            // The request matches report the response
-            if let Err(e) = self
-                .service_send
-                .send(HandlerOut::Response(
-                    node_address.clone(),
-                    Box::new(response),
-                ))
-                .await
-            {
-                warn!(error = %e, "Failed to inform of response")
+            if let HandlerReqId::Internal(_) = request_call.id() {
+                // Do not report responses to requests belonging to the handler.
+                return;
             }
+
+            // The request matches report the response
+            self.report_response(node_address, response, rtt, true)
+                .await;
         } else {
             // This is likely a late response and we have already failed the request. These get
             // dropped here.
-            trace!(%node_address, "Late response from node");
+            trace!(%node_address, request_id = %response.id, "Late response from node");
         }
     }
 
@@ -1254,6 +2327,10 @@ impl Handler {
         // handshake to re-establish a session, if applicable.
         message_nonce: Option<MessageNonce>,
     ) {
+        // The session is now held under the current identity.
+        if let Some(retired) = self.retired_identity.as_mut() {
+            retired.sessions.remove(&node_address);
+        }
         if let Some(current_session) = self.sessions.get_mut(&node_address) {
             current_session.update(session);
             // If a session is re-established, due to a new handshake during an ongoing
@@ -1263,13 +2340,111 @@ impl Handler {
                 .await;
         } else {
             self.sessions.insert(node_address.clone(), session);
-            METRICS
-                .active_sessions
-                .store(self.sessions.len(), Ordering::Relaxed);
+            self.update_session_metrics();
             // We could have pending messages that were awaiting this session to be
             // established. If so process them.
             self.send_pending_requests::<P>(&node_address).await;
         }
+        self.send_held_responses::<P>(&node_address).await;
+    }
+
+    /// Stores the ticket to resume the session with the peer later, if both support resumption.
+    fn store_resumption_ticket(
+        &mut self,
+        node_address: &NodeAddress,
+        session: &Session,
+        enr: &Arc<Enr>,
+    ) {
+        let Some(tickets) = self.resumption_tickets.as_mut() else {
+            return;
+        };
+        if enr.get_raw_rlp(RESUMPTION_ENR_KEY).is_none() {
+            return;
+        }
+        tickets.insert(
+            node_address.clone(),
+            ResumptionTicket {
+                keys: session.resumption_keys(),
+                enr: enr.clone(),
+            },
+        );
+    }
+
+    /// Takes the ticket to resume the session with the peer, if there is no session to resume.
+    fn take_resumption_ticket(
+        &mut self,
+        node_address: &NodeAddress,
+    ) -> Option<(Session, Arc<Enr>)> {
+        let tickets = self.resumption_tickets.as_mut()?;
+        if self.sessions.contains(node_address) {
+            return None;
+        }
+        tickets.peek(node_address)?;
+        let ticket = tickets.remove(node_address)?;
+        Some((Session::new(ticket.keys), ticket.enr))
+    }
+
+    /// Takes the ticket to resume the session with the peer if there is no session and the
+    /// message is encrypted with the keys of the ticket.
+    fn resumable_session(
+        &mut self,
+        node_address: &NodeAddress,
+        message_nonce: MessageNonce,
+        message: &[u8],
+        authenticated_data: &[u8],
+    ) -> Option<(Session, Arc<Enr>)> {
+        let tickets = self.resumption_tickets.as_mut()?;
+        if self.sessions.contains(node_address) {
+            return None;
+        }
+        let ticket = tickets.peek(node_address)?;
+        Session::new(ticket.keys.clone())
+            .decrypt_message(message_nonce, message, authenticated_data)
+            .ok()?;
+        self.take_resumption_ticket(node_address)
+    }
+
+    /// Takes the session we resumed with the peer if there is no session and the message is
+    /// encrypted with its keys, proving that the peer holds the ticket too.
+    fn resumed_session(
+        &mut self,
+        node_address: &NodeAddress,
+        message_nonce: MessageNonce,
+        message: &[u8],
+        authenticated_data: &[u8],
+    ) -> Option<(Session, Arc<Enr>)> {
+        if self.sessions.contains(node_address) {
+            return None;
+        }
+        let (session, _) = self.resuming_sessions.get_mut(node_address)?;
+        session
+            .decrypt_message(message_nonce, message, authenticated_data)
+            .ok()?;
+        self.resuming_sessions.remove(node_address)
+    }
+
+    /// Reports a session resumed from a ticket as established, and stores the ticket to resume
+    /// it again.
+    async fn session_resumed(
+        &mut self,
+        node_address: &NodeAddress,
+        session: &Session,
+        enr: Arc<Enr>,
+        direction: ConnectionDirection,
+    ) {
+        METRICS.resumed_sessions.fetch_add(1, Ordering::Relaxed);
+        self.store_resumption_ticket(node_address, session, &enr);
+        if let Err(e) = self
+            .service_send
+            .send(HandlerOut::Established(
+                enr,
+                node_address.socket_addr,
+                direction,
+            ))
+            .await
+        {
+            warn!(error = %e, "Failed to inform of established session")
+        }
     }
 
     /// A request has failed.
@@ -1279,21 +2454,14 @@ impl Handler {
         error: RequestError,
         remove_session: bool,
     ) {
+        trace!(request_id = %request_call.id(), %error, "Request failed");
         // The Request has expired, remove the session.
         // Fail the current request
         match request_call.id() {
             HandlerReqId::Internal(_) => {
                 // Do not report failures on requests belonging to the handler.
             }
-            HandlerReqId::External(id) => {
-                if let Err(e) = self
-                    .service_send
-                    .send(HandlerOut::RequestFailed(id.clone(), error.clone()))
-                    .await
-                {
-                    warn!(error = %e, "Failed to inform request failure")
-                }
-            }
+            HandlerReqId::External(id) => self.report_failure(id.clone(), error.clone()).await,
         }
 
         let node_address = request_call.contact().node_address();
@@ -1301,6 +2469,165 @@ impl Handler {
             .await;
     }
 
+    /// Applies a perturbation requested by a test.
+    #[cfg(feature = "test_utils")]
+    fn apply_chaos_hook(&mut self, hook: ChaosHook) {
+        let node_id = match &hook {
+            ChaosHook::DropSessions(node_id) => *node_id,
+            ChaosHook::CorruptNextNonce(node_id) => Some(*node_id),
+        };
+        let addresses: Vec<NodeAddress> = self
+            .sessions
+            .keys()
+            .filter(|address| node_id.is_none() || node_id == Some(address.node_id))
+            .cloned()
+            .collect();
+        for address in addresses {
+            match hook {
+                ChaosHook::DropSessions(_) => {
+                    self.sessions.remove(&address);
+                }
+                ChaosHook::CorruptNextNonce(_) => {
+                    if let Some(session) = self.sessions.get_mut(&address) {
+                        session.corrupt_next_nonce = true;
+                    }
+                }
+            }
+        }
+        self.update_session_metrics();
+    }
+
+    /// The requests that have not been answered yet.
+    fn outgoing_requests(&self) -> Vec<OutgoingRequest> {
+        let now = time::now();
+        let sent = self
+            .active_requests
+            .iter()
+            .map(|(node_address, call)| OutgoingRequest {
+                node_id: node_address.node_id,
+                socket_addr: node_address.socket_addr,
+                request: call.body().clone(),
+                state: OutgoingRequestState::Sent,
+                age: now.saturating_duration_since(call.sent_at()),
+                retries_remaining: self.request_retries.saturating_sub(call.retries()),
+            });
+        let awaiting_session = self
+            .pending_requests
+            .iter()
+            .flat_map(|(node_address, requests)| {
+                requests.iter().map(move |pending| OutgoingRequest {
+                    node_id: node_address.node_id,
+                    socket_addr: node_address.socket_addr,
+                    request: pending.request.clone(),
+                    state: OutgoingRequestState::AwaitingSession,
+                    age: now.saturating_duration_since(pending.queued_at),
+                    retries_remaining: self.request_retries,
+                })
+            });
+        let queued = self
+            .establishment_queue
+            .iter()
+            .map(|(contact, request, _, queued_at)| OutgoingRequest {
+                node_id: contact.node_id(),
+                socket_addr: contact.socket_addr(),
+                request: request.body.clone(),
+                state: OutgoingRequestState::Queued,
+                age: now.saturating_duration_since(*queued_at),
+                retries_remaining: self.request_retries,
+            });
+        sent.chain(awaiting_session).chain(queued).collect()
+    }
+
+    /// Switches to the identity of the re-signed local ENR. With a grace period, the current
+    /// sessions are retired and keep answering until it ends. Otherwise they are dropped.
+    fn rotate_identity(&mut self, grace_period: Option<Duration>) {
+        let previous = self.node_id;
+        self.node_id = self.enr.read().node_id();
+        self.crypto_pool.set_node_id(self.node_id);
+        // Only the most recently retired identity is kept.
+        self.drop_retired_identity();
+        let sessions: HashSet<NodeAddress> = self.sessions.keys().cloned().collect();
+        match grace_period {
+            Some(grace_period) => {
+                self.retired_identity = Some(RetiredIdentity {
+                    node_id: previous,
+                    sessions,
+                    expiry: Box::pin(tokio::time::sleep(grace_period)),
+                });
+            }
+            None => {
+                for node_address in sessions {
+                    self.sessions.remove(&node_address);
+                }
+            }
+        }
+        *self.local_node_ids.write() = LocalNodeIds {
+            current: self.node_id,
+            retired: grace_period.map(|_| previous),
+        };
+        self.update_session_metrics();
+        info!(%previous, current = %self.node_id, ?grace_period, "Rotated the local identity");
+    }
+
+    /// Resolves at the end of the grace period of the retired identity, if any.
+    async fn retired_identity_expiry(retired: &mut Option<RetiredIdentity>) {
+        match retired {
+            Some(retired) => retired.expiry.as_mut().await,
+            None => future::pending().await,
+        }
+    }
+
+    /// Drops the retired identity along with the sessions established under it.
+    fn drop_retired_identity(&mut self) {
+        let Some(retired) = self.retired_identity.take() else {
+            return;
+        };
+        for node_address in &retired.sessions {
+            self.sessions.remove(node_address);
+        }
+        self.local_node_ids.write().retired = None;
+        self.update_session_metrics();
+    }
+
+    /// Whether the session with a peer was established under the retired identity.
+    fn is_retired_session(&self, node_address: &NodeAddress) -> bool {
+        self.retired_identity
+            .as_ref()
+            .is_some_and(|retired| retired.sessions.contains(node_address))
+    }
+
+    /// The node id the packets of the session with a peer are sent from.
+    fn session_src_id(&self, node_address: &NodeAddress) -> NodeId {
+        match &self.retired_identity {
+            Some(retired) if retired.sessions.contains(node_address) => retired.node_id,
+            _ => self.node_id,
+        }
+    }
+
+    /// Drops the sessions held with a node.
+    fn remove_sessions(&mut self, node_id: &NodeId) {
+        let addresses: Vec<NodeAddress> = self
+            .sessions
+            .keys()
+            .filter(|address| address.node_id == *node_id)
+            .cloned()
+            .collect();
+        for address in addresses {
+            self.sessions.remove(&address);
+        }
+        self.update_session_metrics();
+    }
+
+    /// Records the number of sessions in the metrics.
+    fn update_session_metrics(&mut self) {
+        METRICS
+            .active_sessions
+            .store(self.sessions.len(), Ordering::Relaxed);
+        METRICS
+            .idle_sessions
+            .store(self.sessions.idle_len(), Ordering::Relaxed);
+    }
+
     /// Removes a session, fails all of that session's active & pending requests, and updates associated metrics and fields.
     async fn fail_session(
         &mut self,
@@ -1310,9 +2637,11 @@ impl Handler {
     ) {
         if remove_session {
             self.sessions.remove(node_address);
-            METRICS
-                .active_sessions
-                .store(self.sessions.len(), Ordering::Relaxed);
+            self.update_session_metrics();
+        }
+        self.resuming_sessions.remove(node_address);
+        if let Some(held) = self.held_responses.remove(node_address) {
+            debug!(%node_address, responses = held.len(), "Session failed. Dropping held responses");
         }
         // fail all pending requests
         if let Some(to_remove) = self.pending_requests.remove(node_address) {
@@ -1321,15 +2650,7 @@ impl Handler {
                     HandlerReqId::Internal(_) => {
                         // Do not report failures on requests belonging to the handler.
                     }
-                    HandlerReqId::External(id) => {
-                        if let Err(e) = self
-                            .service_send
-                            .send(HandlerOut::RequestFailed(id, error.clone()))
-                            .await
-                        {
-                            warn!(error = %e, "Failed to inform request failure")
-                        }
-                    }
+                    HandlerReqId::External(id) => self.report_failure(id, error.clone()).await,
                 }
             }
         }
@@ -1343,15 +2664,7 @@ impl Handler {
                 HandlerReqId::Internal(_) => {
                     // Do not report failures on requests belonging to the handler.
                 }
-                HandlerReqId::External(id) => {
-                    if let Err(e) = self
-                        .service_send
-                        .send(HandlerOut::RequestFailed(id.clone(), error.clone()))
-                        .await
-                    {
-                        warn!(error = %e, "Failed to inform request failure")
-                    }
-                }
+                HandlerReqId::External(id) => self.report_failure(id.clone(), error.clone()).await,
             }
             self.remove_expected_response(node_address.socket_addr);
         }
@@ -1359,39 +2672,75 @@ impl Handler {
 
     /// Sends a packet to the send handler to be encoded and sent.
     async fn send(&mut self, node_address: NodeAddress, packet: Packet) {
-        let outbound_packet = socket::OutboundPacket {
+        self.send_outbound(socket::OutboundPacket {
             node_address,
             packet,
+            request_id: None,
+        })
+        .await
+    }
+
+    /// Sends a packet carrying a request, tagged with its id for the logs of the send handler.
+    async fn send_request_packet(
+        &mut self,
+        node_address: NodeAddress,
+        packet: Packet,
+        request_id: &HandlerReqId,
+    ) {
+        let request_id = match request_id {
+            HandlerReqId::Internal(id) | HandlerReqId::External(id) => id.clone(),
         };
+        self.send_outbound(socket::OutboundPacket {
+            node_address,
+            packet,
+            request_id: Some(request_id),
+        })
+        .await
+    }
+
+    async fn send_outbound(&mut self, outbound_packet: socket::OutboundPacket) {
         if let Err(e) = self.socket.send.send(outbound_packet).await {
             warn!(error = %e, "Failed to send outbound packet")
         }
     }
 
+    /// A timer for the earliest expiry of a ban, measured against the clock, if any ban expires.
+    fn next_ban_expiry(&self) -> Option<Pin<Box<tokio::time::Sleep>>> {
+        let expiry = PERMIT_BAN_LIST.read().next_expiry()?;
+        let remaining = expiry.saturating_duration_since(self.clock.now());
+        Some(Box::pin(tokio::time::sleep(remaining)))
+    }
+
+    /// Completes when the ban expiry timer fires, never if no ban expires.
+    async fn ban_expiry(timer: &mut Option<Pin<Box<tokio::time::Sleep>>>) {
+        match timer {
+            Some(timer) => timer.as_mut().await,
+            None => future::pending().await,
+        }
+    }
+
     /// Check if any banned nodes have served their time and unban them.
     fn unban_nodes_check(&self) {
         PERMIT_BAN_LIST
             .write()
-            .ban_ips
-            .retain(|_, time| time.is_none() || Some(Instant::now()) < *time);
-        PERMIT_BAN_LIST
-            .write()
-            .ban_nodes
-            .retain(|_, time| time.is_none() || Some(Instant::now()) < *time);
+            .remove_expired_bans(self.clock.now());
     }
 
     /// Returns whether a session with this node does not exist and a request that initiates
     /// a session has been sent.
     fn is_awaiting_session_to_be_established(&mut self, node_address: &NodeAddress) -> bool {
-        if self.sessions.get(node_address).is_some() {
+        if self.sessions.contains(node_address) {
             // session exists
             return false;
         }
 
-        if let Some(requests) = self.active_requests.get(node_address) {
-            requests.iter().any(|req| req.initiating_session())
-        } else {
-            false
-        }
+        self.is_initiating_session(node_address)
+    }
+
+    /// Whether one of the active requests to the node is initiating a handshake.
+    fn is_initiating_session(&self, node_address: &NodeAddress) -> bool {
+        self.active_requests
+            .get(node_address)
+            .is_some_and(|requests| requests.iter().any(|req| req.initiating_session()))
     }
 }
@@ -2,9 +2,11 @@ pub use crate::node_info::NodeContact;
 use crate::{
     packet::Packet,
     rpc::{Request, RequestBody},
+    time::{self, Instant},
 };
 
 use super::HandlerReqId;
+use std::time::Duration;
 
 /// A request to a node that we are waiting for a response.
 #[derive(Debug)]
@@ -26,6 +28,12 @@ pub(super) struct RequestCall {
     /// Signifies if we are initiating the session with a random packet. This is only used to
     /// determine the connection direction of the session.
     initiating_session: bool,
+    /// Whether the handshake initiation has been relayed to the target through another peer.
+    relayed: bool,
+    /// When the request was first sent.
+    sent_at: Instant,
+    /// When the request fails, if it has a timeout of its own.
+    deadline: Option<Instant>,
 }
 
 impl RequestCall {
@@ -45,6 +53,9 @@ impl RequestCall {
             retries: 1,
             remaining_responses: None,
             initiating_session,
+            relayed: false,
+            sent_at: time::now(),
+            deadline: None,
         }
     }
 
@@ -110,11 +121,54 @@ impl RequestCall {
         self.initiating_session
     }
 
+    /// Returns whether the handshake initiation has been relayed through another peer.
+    pub fn relayed(&self) -> bool {
+        self.relayed
+    }
+
+    /// Indicates the handshake initiation has been relayed through another peer.
+    pub fn set_relayed(&mut self) {
+        self.relayed = true;
+    }
+
+    /// When the request was first sent.
+    pub fn sent_at(&self) -> Instant {
+        self.sent_at
+    }
+
+    /// Fails the request once `timeout` has elapsed since it was first sent, rather than after
+    /// the request timeout and retries of the configuration.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.deadline = Some(self.sent_at + timeout);
+    }
+
+    /// The time left until the request fails, if it has a timeout of its own.
+    pub fn remaining_time(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(time::now()))
+    }
+
     /// Updates the underlying packet for the call.
     pub fn update_packet(&mut self, packet: Packet) {
         self.packet = packet;
     }
 
+    /// The round trip time of a response received at `received_at`. Following Karn's algorithm,
+    /// there is none if the request was resent or answered with a handshake, as the response can
+    /// not be matched to a single transmission.
+    pub fn round_trip_time(&self, received_at: Instant) -> Option<Duration> {
+        if self.retries > 1 || self.handshake_sent || self.initiating_session {
+            return None;
+        }
+        received_at.checked_duration_since(self.sent_at)
+    }
+
+    /// The number of responses still expected, once the first of multiple Nodes responses has
+    /// been received.
+    pub fn remaining_responses(&self) -> Option<u64> {
+        self.remaining_responses
+    }
+
     /// Gets a mutable reference to the remaining repsonses.
     pub fn remaining_responses_mut(&mut self) -> &mut Option<u64> {
         &mut self.remaining_responses
@@ -6,14 +6,26 @@ use crate::{
     },
 };
 use enr::{CombinedKey, NodeId};
+use std::collections::VecDeque;
 use zeroize::Zeroize;
 
-#[derive(Zeroize, PartialEq)]
+/// The message counter at which a session is re-keyed with a new handshake. This leaves room for
+/// the messages sent while the handshake completes, before the counter would wrap around.
+const REKEY_COUNTER: u32 = u32::MAX - (1 << 16);
+
+/// The number of most recent message nonces of a session checked for replays.
+const REPLAY_CACHE_CAPACITY: usize = 128;
+
+#[derive(Zeroize, PartialEq, Clone)]
+#[cfg_attr(test, derive(Default))]
 pub(crate) struct Keys {
     /// The encryption key.
     encryption_key: [u8; 16],
     /// The decryption key.
     decryption_key: [u8; 16],
+    /// Number of messages encrypted with these keys. Used to ensure the nonce used in message
+    /// encryption is always unique.
+    counter: u32,
 }
 
 /// A Session containing the encryption/decryption keys. These are kept individually for a given
@@ -33,9 +45,33 @@ pub(crate) struct Session {
     ///
     /// This field holds the request_id associated with the ENR request.
     pub awaiting_enr: Option<RequestId>,
-    /// Number of messages sent. Used to ensure the nonce used in message encryption is always
-    /// unique.
-    counter: u32,
+    /// The nonces of the most recent messages decrypted in this session, oldest first.
+    recent_nonces: VecDeque<MessageNonce>,
+    /// The nonces of `recent_nonces`, for lookups.
+    seen_nonces: HashSet<MessageNonce>,
+    /// Whether to corrupt the nonce of the next message, set by tests.
+    #[cfg(feature = "test_utils")]
+    pub corrupt_next_nonce: bool,
+}
+
+/// A session that has not been used for a while, reduced to what is needed to resume it: its
+/// keys, which carry the message counter, and its replay window. The keys of a previous handshake
+/// and any pending ENR request are dropped.
+pub(crate) struct IdleSession {
+    /// The keys of the session, along with its message counter.
+    keys: Keys,
+    /// The nonces of the most recent messages decrypted in the session, oldest first, so that
+    /// messages replayed from before the session went idle are still dropped once resumed.
+    recent_nonces: VecDeque<MessageNonce>,
+}
+
+impl From<IdleSession> for Session {
+    fn from(idle: IdleSession) -> Self {
+        let mut session = Session::new(idle.keys);
+        session.seen_nonces = idle.recent_nonces.iter().copied().collect();
+        session.recent_nonces = idle.recent_nonces;
+        session
+    }
 }
 
 impl Session {
@@ -44,10 +80,38 @@ impl Session {
             keys,
             old_keys: None,
             awaiting_enr: None,
+            recent_nonces: VecDeque::new(),
+            seen_nonces: HashSet::new(),
+            #[cfg(feature = "test_utils")]
+            corrupt_next_nonce: false,
+        }
+    }
+
+    /// Drops the state that is only needed while the session is in use. The message counter is
+    /// kept with the keys so that nonces are not reused once the session is resumed, and the
+    /// nonces checked for replays are kept so that the replay window survives the idle period.
+    pub fn into_idle(self) -> IdleSession {
+        IdleSession {
+            keys: self.keys,
+            recent_nonces: self.recent_nonces,
+        }
+    }
+
+    /// The keys of the next session with the peer, derived from the keys of this one so that the
+    /// peer derives the same, to resume without a handshake. See [`crate::Config::session_resumption`].
+    pub fn resumption_keys(&self) -> Keys {
+        Keys {
+            encryption_key: crypto::derive_resumption_key(&self.keys.encryption_key),
+            decryption_key: crypto::derive_resumption_key(&self.keys.decryption_key),
             counter: 0,
         }
     }
 
+    /// Whether the message counter is close to exhaustion and the session should be re-keyed.
+    pub fn needs_rekey(&self) -> bool {
+        self.keys.counter >= REKEY_COUNTER
+    }
+
     /// A new session has been established. Update this session based on the new session.
     pub fn update(&mut self, new_session: Session) {
         // Optimistically assume the new keys are canonical.
@@ -62,13 +126,18 @@ impl Session {
         src_id: NodeId,
         message: &[u8],
     ) -> Result<Packet, Error> {
-        self.counter += 1;
+        // Reusing a nonce would expose the key, so encryption fails once the counter is exhausted.
+        self.keys.counter = self
+            .keys
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| Error::EncryptionFail("Message counter exhausted".into()))?;
 
         // If the message nonce length is ever set below 4 bytes this will explode. The packet
         // size constants shouldn't be modified.
         let random_nonce: [u8; MESSAGE_NONCE_LENGTH - 4] = rand::random();
         let mut message_nonce: MessageNonce = [0u8; MESSAGE_NONCE_LENGTH];
-        message_nonce[..4].copy_from_slice(&self.counter.to_be_bytes());
+        message_nonce[..4].copy_from_slice(&self.keys.counter.to_be_bytes());
         message_nonce[4..].copy_from_slice(&random_nonce);
 
         // the authenticated data is the IV concatenated with the packet header
@@ -89,11 +158,20 @@ impl Session {
         )?;
 
         // construct a packet from the header and the cipher text
-        Ok(Packet {
+        #[allow(unused_mut)]
+        let mut packet = Packet {
             iv,
             header,
             message: cipher,
-        })
+        };
+
+        // Altering the nonce after encryption invalidates the authentication tag.
+        #[cfg(feature = "test_utils")]
+        if std::mem::take(&mut self.corrupt_next_nonce) {
+            packet.header.message_nonce[MESSAGE_NONCE_LENGTH - 1] ^= 0xff;
+        }
+
+        Ok(packet)
     }
 
     /// Decrypts an encrypted message. If a Session is already established, the original decryption
@@ -127,6 +205,22 @@ impl Session {
         result_canon
     }
 
+    /// Records the nonce of a decrypted message, returning whether a message with the same nonce
+    /// was decrypted before, as it is when the packet is duplicated by the network or replayed.
+    /// Only the most recent nonces are remembered.
+    pub(crate) fn is_replay(&mut self, message_nonce: &MessageNonce) -> bool {
+        if !self.seen_nonces.insert(*message_nonce) {
+            return true;
+        }
+        self.recent_nonces.push_back(*message_nonce);
+        if self.recent_nonces.len() > REPLAY_CACHE_CAPACITY {
+            if let Some(oldest) = self.recent_nonces.pop_front() {
+                self.seen_nonces.remove(&oldest);
+            }
+        }
+        false
+    }
+
     /* Session Helper Functions */
 
     /// Generates session keys from an authentication header. If the IP of the ENR does not match the
@@ -192,6 +286,7 @@ impl Session {
         let keys = Keys {
             encryption_key,
             decryption_key,
+            counter: 0,
         };
 
         // Takes ownership of the provided ENRs - Slightly annoying code duplication, but avoids
@@ -228,6 +323,7 @@ impl Session {
         let keys = Keys {
             encryption_key,
             decryption_key,
+            counter: 0,
         };
 
         // construct the nonce signature
@@ -265,3 +361,91 @@ impl Session {
         Ok((packet, session))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DefaultProtocolId;
+
+    #[test]
+    fn exhausted_counter_requires_rekey() {
+        let mut session = Session::new(Keys {
+            counter: REKEY_COUNTER - 1,
+            ..Default::default()
+        });
+        assert!(!session.needs_rekey());
+        session
+            .encrypt_message::<DefaultProtocolId>(NodeId::random(), b"ping")
+            .unwrap();
+        assert!(session.needs_rekey());
+
+        session.keys.counter = u32::MAX;
+        assert!(session
+            .encrypt_message::<DefaultProtocolId>(NodeId::random(), b"ping")
+            .is_err());
+
+        // New keys start with a fresh counter.
+        session.update(Session::new(Keys::default()));
+        assert!(!session.needs_rekey());
+    }
+
+    #[test]
+    fn repeated_nonce_is_a_replay() {
+        let mut session = Session::new(Keys::default());
+        let nonce = |n: usize| {
+            let mut nonce = [0u8; MESSAGE_NONCE_LENGTH];
+            nonce[..8].copy_from_slice(&(n as u64).to_be_bytes());
+            nonce
+        };
+        assert!(!session.is_replay(&nonce(0)));
+        assert!(session.is_replay(&nonce(0)));
+
+        // The oldest nonces are forgotten once the cache is full.
+        for n in 1..=REPLAY_CACHE_CAPACITY {
+            assert!(!session.is_replay(&nonce(n)));
+        }
+        assert!(session.is_replay(&nonce(REPLAY_CACHE_CAPACITY)));
+        assert!(!session.is_replay(&nonce(0)));
+    }
+
+    #[test]
+    fn idle_session_keeps_replay_window() {
+        let mut session = Session::new(Keys::default());
+        let nonce = [1u8; MESSAGE_NONCE_LENGTH];
+        assert!(!session.is_replay(&nonce));
+
+        let mut resumed = Session::from(session.into_idle());
+        assert!(resumed.is_replay(&nonce));
+        assert!(!resumed.is_replay(&[2u8; MESSAGE_NONCE_LENGTH]));
+    }
+
+    #[test]
+    fn resumed_sessions_share_keys() {
+        let (a, b): ([u8; 16], [u8; 16]) = (rand::random(), rand::random());
+        let session = |encryption_key, decryption_key| {
+            Session::new(Keys {
+                encryption_key,
+                decryption_key,
+                counter: 7,
+            })
+        };
+        let (initiator, responder) = (session(a, b), session(b, a));
+
+        let mut resumed_initiator = Session::new(initiator.resumption_keys());
+        let mut resumed_responder = Session::new(responder.resumption_keys());
+        assert!(resumed_initiator.keys.encryption_key != a);
+        assert_eq!(resumed_initiator.keys.counter, 0);
+
+        let packet = resumed_initiator
+            .encrypt_message::<DefaultProtocolId>(NodeId::random(), b"ping")
+            .unwrap();
+        let message = resumed_responder
+            .decrypt_message(
+                *packet.message_nonce(),
+                &packet.message,
+                &packet.authenticated_data::<DefaultProtocolId>(),
+            )
+            .unwrap();
+        assert_eq!(message, b"ping");
+    }
+}
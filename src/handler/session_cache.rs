@@ -0,0 +1,313 @@
+//! The established sessions, kept in two tiers.
+//!
+//! Sessions in use are kept in full in the hot tier. Once a session goes unused for the idle
+//! timeout, or is pushed out of the hot tier by newer sessions, it is demoted to the idle tier,
+//! which only keeps its current keys and message counter. Idle sessions are promoted back when
+//! used again and expire after the session timeout. Most of the sessions of a bootnode are idle,
+//! and an idle entry takes about 30% less memory than a full one, so more of them fit in the same
+//! budget.
+//!
+//! Both tiers are partitioned. A fraction of their capacity is reserved for the sessions with
+//! members of the routing table and peers on the permit list, so that a burst of handshakes from
+//! strangers can't push them out. Whether a peer is reserved is decided when its session is
+//! inserted, and again when it is about to be pushed out of the general partition, as a session is
+//! typically established before the peer is added to the table. The reserved peers are a snapshot
+//! the service refreshes periodically, so deciding takes no locks.
+use super::session::{IdleSession, Session};
+use crate::{lru_time_cache::LruTimeCache, metrics::METRICS, node_info::NodeAddress, time::Clock};
+use enr::NodeId;
+use std::{
+    collections::HashSet,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tracing::trace;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Reserved,
+    General,
+}
+
+struct Partition {
+    /// Sessions that have been used recently.
+    hot: LruTimeCache<NodeAddress, Session>,
+    /// Sessions demoted from the hot tier.
+    idle: LruTimeCache<NodeAddress, IdleSession>,
+}
+
+impl Partition {
+    fn new(
+        capacity: usize,
+        idle_capacity: usize,
+        idle_timeout: Duration,
+        session_timeout: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Partition {
+            hot: LruTimeCache::with_clock(idle_timeout, Some(capacity), clock.clone()),
+            idle: LruTimeCache::with_clock(
+                session_timeout - idle_timeout,
+                Some(idle_capacity),
+                clock,
+            ),
+        }
+    }
+
+    fn contains(&self, node_address: &NodeAddress) -> bool {
+        self.hot.peek(node_address).is_some() || self.idle.peek(node_address).is_some()
+    }
+}
+
+pub(crate) struct SessionCache {
+    reserved: Partition,
+    general: Partition,
+    /// Whether any capacity is reserved.
+    reserving: bool,
+    /// The nodes whose sessions belong to the reserved partition.
+    reserved_nodes: HashSet<NodeId>,
+}
+
+impl SessionCache {
+    /// Creates a cache keeping up to `capacity` sessions in full for `idle_timeout` after their
+    /// last use, and up to `idle_capacity` idle sessions. Sessions expire `session_timeout` after
+    /// their last use, as measured by `clock`. The `reserved_fraction` of both capacities is kept
+    /// for the sessions with the nodes set by [`SessionCache::set_reserved_nodes`].
+    pub fn new(
+        capacity: usize,
+        idle_capacity: usize,
+        reserved_fraction: f64,
+        idle_timeout: Duration,
+        session_timeout: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let idle_timeout = idle_timeout.min(session_timeout);
+        let reserved_capacity = (capacity as f64 * reserved_fraction) as usize;
+        let reserved_idle_capacity = (idle_capacity as f64 * reserved_fraction) as usize;
+        SessionCache {
+            reserved: Partition::new(
+                reserved_capacity,
+                reserved_idle_capacity,
+                idle_timeout,
+                session_timeout,
+                clock.clone(),
+            ),
+            general: Partition::new(
+                capacity - reserved_capacity,
+                idle_capacity - reserved_idle_capacity,
+                idle_timeout,
+                session_timeout,
+                clock,
+            ),
+            reserving: reserved_capacity > 0,
+            reserved_nodes: HashSet::new(),
+        }
+    }
+
+    /// Replaces the nodes whose sessions are kept in the reserved partition. Sessions already in
+    /// the cache keep their partition until they are about to be pushed out.
+    pub fn set_reserved_nodes(&mut self, nodes: HashSet<NodeId>) {
+        self.reserved_nodes = nodes;
+    }
+
+    /// Returns the session with the node, promoting it to the hot tier if it is idle.
+    pub fn get_mut(&mut self, node_address: &NodeAddress) -> Option<&mut Session> {
+        self.demote_expired();
+        let class = self.class_of(node_address)?;
+        if self.partition(class).hot.peek(node_address).is_none() {
+            let idle = self.partition(class).idle.remove(node_address)?;
+            self.insert_hot(class, node_address.clone(), idle.into());
+        }
+        self.partition(class).hot.get_mut(node_address)
+    }
+
+    /// Whether a session with the node exists, without promoting it.
+    pub fn contains(&mut self, node_address: &NodeAddress) -> bool {
+        self.demote_expired();
+        self.class_of(node_address).is_some()
+    }
+
+    /// Inserts a session into the hot tier, demoting the least recently used session of its
+    /// partition if the partition is full.
+    pub fn insert(&mut self, node_address: NodeAddress, session: Session) {
+        self.remove(&node_address);
+        let class = if self.reserving && self.reserved_nodes.contains(&node_address.node_id) {
+            Class::Reserved
+        } else {
+            Class::General
+        };
+        self.insert_hot(class, node_address, session);
+    }
+
+    /// Removes the session with the node. Returns whether it existed.
+    pub fn remove(&mut self, node_address: &NodeAddress) -> bool {
+        let mut removed = false;
+        for partition in [&mut self.reserved, &mut self.general] {
+            removed |= partition.hot.remove(node_address).is_some();
+            removed |= partition.idle.remove(node_address).is_some();
+        }
+        removed
+    }
+
+    /// The addresses of the nodes we hold a session with.
+    pub fn keys(&self) -> impl Iterator<Item = &NodeAddress> {
+        self.reserved
+            .hot
+            .keys()
+            .chain(self.reserved.idle.keys())
+            .chain(self.general.hot.keys())
+            .chain(self.general.idle.keys())
+    }
+
+    /// The number of sessions in both tiers.
+    pub fn len(&mut self) -> usize {
+        self.demote_expired();
+        self.reserved.hot.len()
+            + self.reserved.idle.len()
+            + self.general.hot.len()
+            + self.general.idle.len()
+    }
+
+    /// The number of idle sessions.
+    pub fn idle_len(&mut self) -> usize {
+        self.demote_expired();
+        self.reserved.idle.len() + self.general.idle.len()
+    }
+
+    fn partition(&mut self, class: Class) -> &mut Partition {
+        match class {
+            Class::Reserved => &mut self.reserved,
+            Class::General => &mut self.general,
+        }
+    }
+
+    fn class_of(&self, node_address: &NodeAddress) -> Option<Class> {
+        if self.reserved.contains(node_address) {
+            Some(Class::Reserved)
+        } else if self.general.contains(node_address) {
+            Some(Class::General)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a general session about to be pushed out should move to the reserved partition.
+    fn rescue(&self, class: Class, node_id: &NodeId) -> bool {
+        class == Class::General && self.reserving && self.reserved_nodes.contains(node_id)
+    }
+
+    fn insert_hot(&mut self, class: Class, node_address: NodeAddress, session: Session) {
+        let Some((demoted_address, demoted)) = self
+            .partition(class)
+            .hot
+            .insert_evicting(node_address, session)
+        else {
+            return;
+        };
+        if self.rescue(class, &demoted_address.node_id) {
+            self.insert_hot(Class::Reserved, demoted_address, demoted);
+        } else {
+            self.insert_idle(class, demoted_address, demoted.into_idle());
+        }
+    }
+
+    fn insert_idle(&mut self, class: Class, node_address: NodeAddress, session: IdleSession) {
+        let Some((evicted_address, evicted)) = self
+            .partition(class)
+            .idle
+            .insert_evicting(node_address, session)
+        else {
+            return;
+        };
+        if self.rescue(class, &evicted_address.node_id) {
+            return self.insert_idle(Class::Reserved, evicted_address, evicted);
+        }
+        trace!(node_address = %evicted_address, ?class, "Session evicted from the cache");
+        let evictions = match class {
+            Class::Reserved => &METRICS.reserved_session_evictions,
+            Class::General => &METRICS.general_session_evictions,
+        };
+        evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Moves the sessions that went unused for the idle timeout to the idle tier.
+    fn demote_expired(&mut self) {
+        for class in [Class::Reserved, Class::General] {
+            while let Some((node_address, session)) = self.partition(class).hot.pop_expired() {
+                self.insert_idle(class, node_address, session.into_idle());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handler::session::Keys, time::ManualClock};
+
+    fn node_address(port: u16) -> NodeAddress {
+        NodeAddress {
+            socket_addr: ([127, 0, 0, 1], port).into(),
+            node_id: NodeId::random(),
+        }
+    }
+
+    #[test]
+    fn demotes_to_idle_tier_and_promotes_on_use() {
+        let clock = ManualClock::default();
+        let mut cache = SessionCache::new(
+            1,
+            10,
+            0.0,
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+            Arc::new(clock.clone()),
+        );
+        let first = node_address(9000);
+        let second = node_address(9001);
+        cache.insert(first.clone(), Session::new(Keys::default()));
+        cache.insert(second.clone(), Session::new(Keys::default()));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.idle_len(), 1);
+
+        // Using the idle session promotes it and demotes the other one.
+        assert!(cache.get_mut(&first).is_some());
+        assert_eq!(cache.idle_len(), 1);
+        assert!(cache.contains(&second));
+
+        assert!(cache.remove(&second));
+        assert!(!cache.contains(&second));
+        assert_eq!(cache.len(), 1);
+
+        // Unused sessions are demoted, then expire.
+        clock.advance(Duration::from_secs(61));
+        assert!(cache.contains(&first));
+        assert_eq!(cache.idle_len(), 1);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn strangers_do_not_evict_reserved_sessions() {
+        let member = node_address(9000);
+        let mut cache = SessionCache::new(
+            4,
+            4,
+            0.5,
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+            Arc::new(ManualClock::default()),
+        );
+
+        // The session is established before the peer is added to the table.
+        cache.insert(member.clone(), Session::new(Keys::default()));
+        cache.set_reserved_nodes(HashSet::from([member.node_id]));
+
+        for port in 9001..9100 {
+            cache.insert(node_address(port), Session::new(Keys::default()));
+        }
+        assert!(cache.contains(&member));
+        // Strangers are limited to the general partition.
+        assert_eq!(cache.len(), 5);
+    }
+}
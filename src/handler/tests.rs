@@ -5,7 +5,7 @@ use crate::{
     packet::DefaultProtocolId,
     return_if_ipv6_is_not_supported,
     rpc::{Request, Response},
-    ConfigBuilder, IpMode,
+    ConfigBuilder, IpMode, ListenConfig,
 };
 use std::{
     collections::HashSet,
@@ -17,6 +17,7 @@ use std::{
 
 use crate::{handler::HandlerOut::RequestFailed, RequestError::SelfRequest};
 use active_requests::ActiveRequests;
+use enr::EnrKey;
 use std::time::Duration;
 use tokio::time::sleep;
 
@@ -32,13 +33,14 @@ async fn build_handler<P: ProtocolIdentity>(
     config: Config,
 ) -> (
     oneshot::Sender<()>,
-    mpsc::UnboundedSender<HandlerIn>,
+    mpsc::Sender<HandlerIn>,
     mpsc::Receiver<HandlerOut>,
     Handler,
 ) {
     let mut listen_sockets = SmallVec::default();
     listen_sockets.push((Ipv4Addr::LOCALHOST, 9000).into());
     let node_id = enr.node_id();
+    let local_node_ids = Arc::new(RwLock::new(socket::LocalNodeIds::new(node_id)));
     let filter_expected_responses = Arc::new(RwLock::new(HashMap::new()));
 
     let socket = {
@@ -48,39 +50,84 @@ async fn build_handler<P: ProtocolIdentity>(
                 rate_limiter: config.filter_rate_limiter.clone(),
                 max_nodes_per_ip: config.filter_max_nodes_per_ip,
                 max_bans_per_ip: config.filter_max_bans_per_ip,
+                clock: config.clock.clone(),
+                bootnode_permits: Default::default(),
             };
 
             socket::SocketConfig {
                 executor: config.executor.clone().expect("Executor must exist"),
                 filter_config,
                 listen_config: config.listen_config.clone(),
-                local_node_id: node_id,
+                local_node_ids: local_node_ids.clone(),
                 expected_responses: filter_expected_responses.clone(),
                 ban_duration: config.ban_duration,
+                transport_factory: None,
+                inbound_queue_capacity: config.inbound_queue_capacity,
+                ipv6_only: config.ipv6_only,
             }
         };
 
         Socket::new::<P>(socket_config).await.unwrap()
     };
-    let (handler_send, service_recv) = mpsc::unbounded_channel();
+    let (handler_send, service_recv) = mpsc::channel(1000);
     let (service_send, handler_recv) = mpsc::channel(50);
     let (exit_sender, exit) = oneshot::channel();
 
     let handler = Handler {
         request_retries: config.request_retries,
+        request_timeout: config.request_timeout,
         node_id,
+        local_node_ids,
+        retired_identity: None,
         enr: Arc::new(RwLock::new(enr)),
-        key: Arc::new(RwLock::new(key)),
         active_requests: ActiveRequests::new(config.request_timeout),
         pending_requests: HashMap::new(),
+        held_responses: HashMap::new(),
         filter_expected_responses,
-        sessions: LruTimeCache::new(config.session_timeout, Some(config.session_cache_capacity)),
-        active_challenges: HashMapDelay::new(config.request_timeout),
+        sessions: SessionCache::new(
+            config.session_cache_capacity,
+            config.idle_session_cache_capacity,
+            config.session_reserved_fraction,
+            config.session_idle_timeout,
+            config.session_timeout,
+            config.clock.clone(),
+        ),
+        resumption_tickets: config
+            .session_resumption
+            .map(|ttl| LruTimeCache::new(ttl, Some(config.session_cache_capacity))),
+        resuming_sessions: HashMap::new(),
+        active_challenges: HashMapDelay::new(
+            config.challenge_ttl.unwrap_or(config.request_timeout),
+        ),
+        max_active_challenges: config.challenge_cache_capacity,
         service_recv,
         service_send,
         listen_sockets,
         socket,
         exit,
+        allowed_cidr: config.allowed_cidr,
+        response_limiter: config.outbound_response_limit.map(|(max_bytes, interval)| {
+            Limiter::from_quota(Quota::n_every(max_bytes, interval))
+                .expect("The outbound response limit is validated by the config")
+        }),
+        challenge_limiter: None,
+        init_time: Instant::now(),
+        enable_relay: config.enable_relay,
+        relays: LruTimeCache::new(config.session_timeout, Some(config.session_cache_capacity)),
+        relay_limiter: Limiter::from_quota(Quota::n_every(RELAY_RATE_LIMIT.0, RELAY_RATE_LIMIT.1))
+            .unwrap(),
+        enr_allowlist: None,
+        crypto_pool: CryptoPool::new(
+            config.handshake_workers,
+            Arc::new(RwLock::new(key)),
+            node_id,
+        ),
+        handshakes_in_progress: HashMap::new(),
+        max_session_establishments: config.max_session_establishments,
+        establishment_queue: EstablishmentQueue::new(ESTABLISHMENT_QUEUE_CAPACITY),
+        coalesced_requests: HashMap::new(),
+        clock: config.clock.clone(),
+        maintenance_interval: config.maintenance_interval,
     };
     (exit_sender, handler_send, handler_recv, handler)
 }
@@ -124,6 +171,7 @@ async fn simple_session_message() {
     let (_exit_send, sender_send, _sender_recv) = Handler::spawn::<DefaultProtocolId>(
         arc_rw!(sender_enr.clone()),
         arc_rw!(key1),
+        Default::default(),
         sender_config,
     )
     .await
@@ -139,6 +187,7 @@ async fn simple_session_message() {
     let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn::<DefaultProtocolId>(
         arc_rw!(receiver_enr.clone()),
         arc_rw!(key2),
+        Default::default(),
         receiver_config,
     )
     .await
@@ -149,9 +198,11 @@ async fn simple_session_message() {
         body: RequestBody::Ping { enr_seq: 1 },
     });
 
-    let _ = sender_send.send(HandlerIn::Request(
+    let _ = sender_send.try_send(HandlerIn::Request(
         receiver_enr.into(),
         send_message.clone(),
+        RequestPriority::Query,
+        None,
     ));
 
     let receiver = async move {
@@ -159,8 +210,8 @@ async fn simple_session_message() {
             if let Some(message) = receiver_recv.recv().await {
                 match message {
                     HandlerOut::WhoAreYou(wru_ref) => {
-                        let _ =
-                            recv_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                        let _ = recv_send
+                            .try_send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
                     }
                     HandlerOut::Request(_, request) => {
                         assert_eq!(request, send_message);
@@ -246,9 +297,11 @@ async fn multiple_messages() {
     });
 
     // sender to send the first message then await for the session to be established
-    let _ = sender_send.send(HandlerIn::Request(
+    let _ = sender_send.try_send(HandlerIn::Request(
         receiver_enr.clone().into(),
         send_message.clone(),
+        RequestPriority::Query,
+        None,
     ));
 
     let pong_response = Response {
@@ -272,13 +325,15 @@ async fn multiple_messages() {
                 Some(HandlerOut::Established(_, _, _)) => {
                     // now the session is established, send the rest of the messages
                     for _ in 0..messages_to_send - 1 {
-                        let _ = sender_send.send(HandlerIn::Request(
+                        let _ = sender_send.try_send(HandlerIn::Request(
                             receiver_enr.clone().into(),
                             send_message.clone(),
+                            RequestPriority::Query,
+                            None,
                         ));
                     }
                 }
-                Some(HandlerOut::Response(_, _)) => {
+                Some(HandlerOut::Response(_, _, _)) => {
                     response_count += 1;
                     if response_count == messages_to_send {
                         // Notify the handlers that the message exchange has been completed.
@@ -296,15 +351,15 @@ async fn multiple_messages() {
         loop {
             match receiver_recv.recv().await {
                 Some(HandlerOut::WhoAreYou(wru_ref)) => {
-                    let _ =
-                        receiver_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                    let _ = receiver_send
+                        .try_send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
                 }
                 Some(HandlerOut::Request(addr, request)) => {
                     assert_eq!(request, recv_send_message);
                     message_count += 1;
                     // required to send a pong response to establish the session
                     let _ = receiver_send
-                        .send(HandlerIn::Response(addr, Box::new(pong_response.clone())));
+                        .try_send(HandlerIn::Response(addr, Box::new(pong_response.clone())));
                     if message_count == messages_to_send {
                         return;
                     }
@@ -513,18 +568,24 @@ async fn test_self_request_ipv4() {
         .enable_packet_filter()
         .build();
 
-    let (_exit_send, send, mut recv) =
-        Handler::spawn::<DefaultProtocolId>(arc_rw!(enr.clone()), arc_rw!(key), config)
-            .await
-            .unwrap();
+    let (_exit_send, send, mut recv) = Handler::spawn::<DefaultProtocolId>(
+        arc_rw!(enr.clone()),
+        arc_rw!(key),
+        Default::default(),
+        config,
+    )
+    .await
+    .unwrap();
 
     // self request (IPv4)
-    let _ = send.send(HandlerIn::Request(
+    let _ = send.try_send(HandlerIn::Request(
         NodeContact::try_from_enr(enr.clone(), IpMode::Ip4).unwrap(),
         Box::new(Request {
             id: RequestId(vec![1]),
             body: RequestBody::Ping { enr_seq: 1 },
         }),
+        RequestPriority::Query,
+        None,
     ));
     let handler_out = recv.recv().await;
     assert_eq!(
@@ -553,18 +614,24 @@ async fn test_self_request_ipv6() {
         .enable_packet_filter()
         .build();
 
-    let (_exit_send, send, mut recv) =
-        Handler::spawn::<DefaultProtocolId>(arc_rw!(enr.clone()), arc_rw!(key), config)
-            .await
-            .unwrap();
+    let (_exit_send, send, mut recv) = Handler::spawn::<DefaultProtocolId>(
+        arc_rw!(enr.clone()),
+        arc_rw!(key),
+        Default::default(),
+        config,
+    )
+    .await
+    .unwrap();
 
     // self request (IPv6)
-    let _ = send.send(HandlerIn::Request(
+    let _ = send.try_send(HandlerIn::Request(
         NodeContact::try_from_enr(enr, IpMode::Ip6).unwrap(),
         Box::new(Request {
             id: RequestId(vec![2]),
             body: RequestBody::Ping { enr_seq: 1 },
         }),
+        RequestPriority::Query,
+        None,
     ));
     let handler_out = recv.recv().await;
     assert_eq!(
@@ -700,12 +767,14 @@ async fn test_replay_active_requests() {
         expected_request_ids.insert(RequestId(vec![1]));
 
         // sender to send the first message then await for the session to be established
-        let _ = sender_send.send(HandlerIn::Request(
+        let _ = sender_send.try_send(HandlerIn::Request(
             receiver_enr.clone().into(),
             Box::new(Request {
                 id: RequestId(vec![1]),
                 body: RequestBody::Ping { enr_seq: 1 },
             }),
+            RequestPriority::Query,
+            None,
         ));
 
         match sender_recv.recv().await {
@@ -716,12 +785,14 @@ async fn test_replay_active_requests() {
                 for req_id in 2..=messages_to_send {
                     let request_id = RequestId(vec![req_id as u8]);
                     expected_request_ids.insert(request_id.clone());
-                    let _ = sender_send.send(HandlerIn::Request(
+                    let _ = sender_send.try_send(HandlerIn::Request(
                         receiver_enr.clone().into(),
                         Box::new(Request {
                             id: request_id,
                             body: RequestBody::Ping { enr_seq: 1 },
                         }),
+                        RequestPriority::Query,
+                        None,
                     ));
                 }
             }
@@ -730,7 +801,7 @@ async fn test_replay_active_requests() {
 
         loop {
             match sender_recv.recv().await {
-                Some(HandlerOut::Response(_, response)) => {
+                Some(HandlerOut::Response(_, response, _)) => {
                     assert!(expected_request_ids.remove(&response.id));
                     response_count += 1;
                     if response_count == messages_to_send {
@@ -752,7 +823,7 @@ async fn test_replay_active_requests() {
             match receiver_recv.recv().await {
                 Some(HandlerOut::WhoAreYou(wru_ref)) => {
                     receiver_send
-                        .send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())))
+                        .try_send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())))
                         .unwrap();
                 }
                 Some(HandlerOut::Request(addr, request)) => {
@@ -766,7 +837,7 @@ async fn test_replay_active_requests() {
                         },
                     };
                     receiver_send
-                        .send(HandlerIn::Response(addr, Box::new(pong_response)))
+                        .try_send(HandlerIn::Response(addr, Box::new(pong_response)))
                         .unwrap();
                     message_count += 1;
                     if message_count == messages_to_send {
@@ -913,18 +984,20 @@ async fn test_send_pending_request() {
         for req_id in 1..=messages_to_send {
             let request_id = RequestId(vec![req_id as u8]);
             expected_request_ids.insert(request_id.clone());
-            let _ = sender_send.send(HandlerIn::Request(
+            let _ = sender_send.try_send(HandlerIn::Request(
                 receiver_enr.clone().into(),
                 Box::new(Request {
                     id: request_id,
                     body: RequestBody::Ping { enr_seq: 1 },
                 }),
+                RequestPriority::Query,
+                None,
             ));
         }
 
         loop {
             match sender_recv.recv().await {
-                Some(HandlerOut::Response(_, response)) => {
+                Some(HandlerOut::Response(_, response, _)) => {
                     assert!(expected_request_ids.remove(&response.id));
                     response_count += 1;
                     if response_count == messages_to_send {
@@ -946,7 +1019,7 @@ async fn test_send_pending_request() {
             match receiver_recv.recv().await {
                 Some(HandlerOut::WhoAreYou(wru_ref)) => {
                     receiver_send
-                        .send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())))
+                        .try_send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())))
                         .unwrap();
                 }
                 Some(HandlerOut::Request(addr, request)) => {
@@ -960,7 +1033,7 @@ async fn test_send_pending_request() {
                         },
                     };
                     receiver_send
-                        .send(HandlerIn::Response(addr, Box::new(pong_response)))
+                        .try_send(HandlerIn::Response(addr, Box::new(pong_response)))
                         .unwrap();
                     message_count += 1;
                     if message_count == messages_to_send {
@@ -986,3 +1059,824 @@ async fn test_send_pending_request() {
         }
     }
 }
+
+#[tokio::test]
+async fn crypto_pool_returns_outcomes() {
+    init();
+
+    let key = arc_rw!(CombinedKey::generate_secp256k1());
+    let node_address = NodeAddress {
+        socket_addr: (Ipv4Addr::LOCALHOST, 9000).into(),
+        node_id: NodeId::random(),
+    };
+    let job = || HandshakeJob::Verify {
+        handshake: Box::new(InboundHandshake {
+            node_address: node_address.clone(),
+            message_nonce: [0; 12],
+            id_nonce_sig: vec![0; 64],
+            ephem_pubkey: vec![0; 33],
+            enr_record: None,
+            message: Vec::new(),
+            authenticated_data: Vec::new(),
+        }),
+        challenge: Challenge {
+            data: [0; 63][..].try_into().unwrap(),
+            remote_enr: None,
+        },
+    };
+    let is_unestablished = |outcome: HandshakeOutcome| match outcome {
+        HandshakeOutcome::Verified { handshake, result } => {
+            handshake.node_address == node_address
+                && matches!(result, Err(Error::SessionNotEstablished))
+        }
+        HandshakeOutcome::Answered { .. } => false,
+    };
+
+    // Without workers the handshake is processed by the caller.
+    let inline = CryptoPool::new(0, key.clone(), NodeId::random());
+    assert!(is_unestablished(
+        inline.process::<DefaultProtocolId>(job()).unwrap()
+    ));
+
+    let mut pool = CryptoPool::new(2, key, NodeId::random());
+    assert!(pool.process::<DefaultProtocolId>(job()).is_none());
+    assert!(is_unestablished(pool.outcomes.recv().await.unwrap()));
+}
+
+#[tokio::test]
+async fn challenge_rate_limit_suppresses_whoareyou() {
+    init();
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(5010)
+        .build(&key)
+        .unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::LOCALHOST,
+        port: 5010,
+    })
+    .build();
+    let (_exit, _send, mut recv, mut handler) =
+        build_handler::<DefaultProtocolId>(enr, key, config).await;
+    handler.challenge_limiter =
+        Some(Limiter::from_quota(Quota::n_every(1, Duration::from_secs(60))).unwrap());
+
+    // Messages without a session from two nodes behind the same IP call for two challenges.
+    for _ in 0..2 {
+        let node_address = NodeAddress {
+            socket_addr: (Ipv4Addr::new(192, 0, 2, 1), 9000).into(),
+            node_id: NodeId::random(),
+        };
+        handler
+            .handle_message::<DefaultProtocolId>(node_address, [0; 12], &[], &[], Instant::now())
+            .await;
+    }
+
+    assert!(matches!(recv.try_recv(), Ok(HandlerOut::WhoAreYou(_))));
+    assert!(recv.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn challenge_cache_capacity_suppresses_whoareyou() {
+    init();
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(5012)
+        .build(&key)
+        .unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::LOCALHOST,
+        port: 5012,
+    })
+    .challenge_cache_capacity(1)
+    .build();
+    let (_exit, _send, mut recv, mut handler) =
+        build_handler::<DefaultProtocolId>(enr, key, config).await;
+
+    // Messages without a session from two different IPs call for two challenges.
+    for ip in [1, 2] {
+        let node_address = NodeAddress {
+            socket_addr: (Ipv4Addr::new(192, 0, 2, ip), 9000).into(),
+            node_id: NodeId::random(),
+        };
+        handler
+            .handle_message::<DefaultProtocolId>(node_address, [0; 12], &[], &[], Instant::now())
+            .await;
+        if let Ok(HandlerOut::WhoAreYou(wru_ref)) = recv.try_recv() {
+            handler
+                .send_challenge::<DefaultProtocolId>(wru_ref, None)
+                .await;
+        }
+    }
+
+    // Only one challenge fits in the cache.
+    assert_eq!(handler.active_challenges.len(), 1);
+}
+
+#[tokio::test]
+async fn duplicate_findnode_requests_share_a_response() {
+    init();
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(5011)
+        .build(&key)
+        .unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::LOCALHOST,
+        port: 5011,
+    })
+    .build();
+    let (_exit, _send, mut recv, mut handler) =
+        build_handler::<DefaultProtocolId>(enr, key, config).await;
+
+    let remote = create_node();
+    let contact = NodeContact::from(remote);
+    let findnode = |id: u8, distances: Vec<u64>| Request {
+        id: RequestId(vec![id]),
+        body: RequestBody::FindNode { distances },
+    };
+    handler
+        .send_request::<DefaultProtocolId>(
+            contact.clone(),
+            HandlerReqId::External(RequestId(vec![1])),
+            findnode(1, vec![256]).body,
+            None,
+        )
+        .await
+        .unwrap();
+
+    // Only an identical request shares the one in flight.
+    assert_eq!(
+        handler.coalescable_request(&contact, &findnode(2, vec![256])),
+        Some(RequestId(vec![1]))
+    );
+    assert_eq!(
+        handler.coalescable_request(&contact, &findnode(3, vec![255])),
+        None
+    );
+    handler
+        .coalesced_requests
+        .entry(RequestId(vec![1]))
+        .or_default()
+        .push(RequestId(vec![2]));
+
+    let response = Response {
+        id: RequestId(vec![1]),
+        body: ResponseBody::Nodes {
+            total: 1,
+            nodes: vec![],
+        },
+    };
+    handler
+        .handle_response(contact.node_address(), response, Instant::now())
+        .await;
+
+    for id in [1, 2] {
+        match recv.try_recv() {
+            Ok(HandlerOut::Response(_, response, _)) => {
+                assert_eq!(response.id, RequestId(vec![id]))
+            }
+            _ => panic!("Expected a response to request {}", id),
+        }
+    }
+    assert!(handler.coalesced_requests.is_empty());
+}
+
+#[tokio::test]
+async fn response_without_session_is_held_for_a_handshake() {
+    init();
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(5013)
+        .build(&key)
+        .unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::LOCALHOST,
+        port: 5013,
+    })
+    .build();
+    let (_exit, _send, _recv, mut handler) =
+        build_handler::<DefaultProtocolId>(enr, key, config).await;
+
+    let contact = NodeContact::from(create_node());
+    let node_address = contact.node_address();
+    let talk = |id: u8| Response {
+        id: RequestId(vec![id]),
+        body: ResponseBody::Talk { response: vec![id] },
+    };
+    for id in [1, 2] {
+        handler
+            .send_response_establishing_session::<DefaultProtocolId>(contact.clone(), talk(id))
+            .await;
+    }
+
+    // Both responses wait for the one handshake started by the first.
+    assert_eq!(handler.held_responses[&node_address].len(), 2);
+    let requests = handler.active_requests.get(&node_address).unwrap();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].initiating_session());
+    assert!(matches!(requests[0].id(), HandlerReqId::Internal(_)));
+
+    handler
+        .fail_session(&node_address, RequestError::Timeout, true)
+        .await;
+    assert!(handler.held_responses.is_empty());
+}
+
+/// Spawns a handler advertising session resumption in its ENR, keeping tickets for `ttl` if set.
+async fn spawn_resumption_handler(
+    port: u16,
+    ttl: Option<Duration>,
+) -> (
+    oneshot::Sender<()>,
+    mpsc::Sender<HandlerIn>,
+    mpsc::Receiver<HandlerOut>,
+    Enr,
+) {
+    let key = CombinedKey::generate_secp256k1();
+    let mut builder = Enr::builder();
+    builder.ip4(Ipv4Addr::LOCALHOST).udp4(port);
+    builder.add_value(RESUMPTION_ENR_KEY, &1u8);
+    let enr = builder.build(&key).unwrap();
+    let mut config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::LOCALHOST,
+        port,
+    });
+    if let Some(ttl) = ttl {
+        config.session_resumption(ttl);
+    }
+    let (exit, send, recv) = Handler::spawn::<DefaultProtocolId>(
+        arc_rw!(enr.clone()),
+        arc_rw!(key),
+        Default::default(),
+        config.build(),
+    )
+    .await
+    .unwrap();
+    (exit, send, recv, enr)
+}
+
+/// A PING request to the node, with the request id `id`.
+fn resumption_ping(enr: &Enr, id: u8) -> HandlerIn {
+    HandlerIn::Request(
+        enr.clone().into(),
+        Box::new(Request {
+            id: RequestId(vec![id]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        }),
+        RequestPriority::Query,
+        None,
+    )
+}
+
+/// Counts the challenges the handler sends until it receives a request, which is returned.
+async fn receive_request(
+    recv: &mut mpsc::Receiver<HandlerOut>,
+    send: &mpsc::Sender<HandlerIn>,
+    remote_enr: &Enr,
+) -> (usize, NodeAddress, Request) {
+    let mut challenges = 0;
+    loop {
+        match recv.recv().await {
+            Some(HandlerOut::WhoAreYou(wru_ref)) => {
+                challenges += 1;
+                let _ = send.try_send(HandlerIn::WhoAreYou(wru_ref, Some(remote_enr.clone())));
+            }
+            Some(HandlerOut::Request(node_address, request)) => {
+                return (challenges, node_address, *request)
+            }
+            Some(_) => {}
+            None => panic!("Handler stopped"),
+        }
+    }
+}
+
+/// The directions of the sessions the handler reported established, until it reports the
+/// response to the request `id`.
+async fn established_until_response(
+    recv: &mut mpsc::Receiver<HandlerOut>,
+    id: u8,
+) -> Vec<ConnectionDirection> {
+    let mut established = Vec::new();
+    loop {
+        match recv.recv().await {
+            Some(HandlerOut::Established(_, _, direction)) => established.push(direction),
+            Some(HandlerOut::Response(_, response, _)) if response.id == RequestId(vec![id]) => {
+                return established
+            }
+            Some(_) => {}
+            None => panic!("Handler stopped"),
+        }
+    }
+}
+
+/// Answers a PING request with a PONG.
+fn pong(node_address: NodeAddress, request: Request) -> HandlerIn {
+    HandlerIn::Response(
+        node_address,
+        Box::new(Response {
+            id: request.id,
+            body: ResponseBody::Pong {
+                enr_seq: 1,
+                ip: Ipv4Addr::LOCALHOST.into(),
+                port: NonZeroU16::new(5000).unwrap(),
+            },
+        }),
+    )
+}
+
+/// Sends a PING from the sender to the receiver and answers it. Returns the number of
+/// challenges the receiver sent and the sessions the sender reported established.
+async fn resumption_exchange(
+    sender: (
+        &mpsc::Sender<HandlerIn>,
+        &mut mpsc::Receiver<HandlerOut>,
+        &Enr,
+    ),
+    receiver: (
+        &mpsc::Sender<HandlerIn>,
+        &mut mpsc::Receiver<HandlerOut>,
+        &Enr,
+    ),
+    id: u8,
+) -> (usize, Vec<ConnectionDirection>) {
+    let (sender_send, sender_recv, sender_enr) = sender;
+    let (receiver_send, receiver_recv, receiver_enr) = receiver;
+    sender_send
+        .try_send(resumption_ping(receiver_enr, id))
+        .unwrap();
+    let (challenges, node_address, request) = tokio::time::timeout(
+        Duration::from_secs(1),
+        receive_request(receiver_recv, receiver_send, sender_enr),
+    )
+    .await
+    .expect("Request received");
+    // Without a handshake, the sender can't consider the session established before the
+    // receiver answered.
+    sleep(Duration::from_millis(50)).await;
+    let mut established = Vec::new();
+    while let Ok(event) = sender_recv.try_recv() {
+        if let HandlerOut::Established(_, _, direction) = event {
+            assert!(
+                challenges > 0,
+                "Session reported established before the peer answered"
+            );
+            established.push(direction);
+        }
+    }
+    receiver_send.try_send(pong(node_address, request)).unwrap();
+    established.extend(
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            established_until_response(sender_recv, id),
+        )
+        .await
+        .expect("Response received"),
+    );
+    (challenges, established)
+}
+
+/// Drops the sessions the handlers hold with each other.
+async fn lose_sessions(a: (&mpsc::Sender<HandlerIn>, &Enr), b: (&mpsc::Sender<HandlerIn>, &Enr)) {
+    a.0.try_send(HandlerIn::RemoveSessions(b.1.node_id()))
+        .unwrap();
+    b.0.try_send(HandlerIn::RemoveSessions(a.1.node_id()))
+        .unwrap();
+    sleep(Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+// Tests that peers supporting resumption re-establish a lost session without a handshake, once
+// the peer answered under the resumed keys
+async fn lost_session_is_resumed_without_handshake() {
+    init();
+
+    let ttl = Some(Duration::from_secs(60));
+    let (_sender_exit, sender_send, mut sender_recv, sender_enr) =
+        spawn_resumption_handler(5014, ttl).await;
+    let (_receiver_exit, receiver_send, mut receiver_recv, receiver_enr) =
+        spawn_resumption_handler(5015, ttl).await;
+    let (challenges, _) = resumption_exchange(
+        (&sender_send, &mut sender_recv, &sender_enr),
+        (&receiver_send, &mut receiver_recv, &receiver_enr),
+        1,
+    )
+    .await;
+    assert_eq!(challenges, 1);
+
+    lose_sessions((&sender_send, &sender_enr), (&receiver_send, &receiver_enr)).await;
+
+    let (challenges, established) = resumption_exchange(
+        (&sender_send, &mut sender_recv, &sender_enr),
+        (&receiver_send, &mut receiver_recv, &receiver_enr),
+        2,
+    )
+    .await;
+    assert_eq!(challenges, 0);
+    assert_eq!(established, vec![ConnectionDirection::Outgoing]);
+}
+
+#[tokio::test]
+// Tests that a session is re-established by a handshake if the peer rejects the ticket
+async fn rejected_resumption_falls_back_to_handshake() {
+    init();
+
+    let (_sender_exit, sender_send, mut sender_recv, sender_enr) =
+        spawn_resumption_handler(5021, Some(Duration::from_secs(60))).await;
+    // The receiver advertises resumption but keeps no tickets.
+    let (_receiver_exit, receiver_send, mut receiver_recv, receiver_enr) =
+        spawn_resumption_handler(5022, None).await;
+
+    let (challenges, _) = resumption_exchange(
+        (&sender_send, &mut sender_recv, &sender_enr),
+        (&receiver_send, &mut receiver_recv, &receiver_enr),
+        1,
+    )
+    .await;
+    assert_eq!(challenges, 1);
+
+    lose_sessions((&sender_send, &sender_enr), (&receiver_send, &receiver_enr)).await;
+
+    let (challenges, established) = resumption_exchange(
+        (&sender_send, &mut sender_recv, &sender_enr),
+        (&receiver_send, &mut receiver_recv, &receiver_enr),
+        2,
+    )
+    .await;
+    assert_eq!(challenges, 1);
+    // Only the handshake established the session.
+    assert_eq!(established, vec![ConnectionDirection::Outgoing]);
+}
+
+#[tokio::test]
+// Tests that a session is re-established by a handshake once the ticket expired
+async fn expired_resumption_ticket_falls_back_to_handshake() {
+    init();
+
+    let ttl = Some(Duration::from_millis(100));
+    let (_sender_exit, sender_send, mut sender_recv, sender_enr) =
+        spawn_resumption_handler(5023, ttl).await;
+    let (_receiver_exit, receiver_send, mut receiver_recv, receiver_enr) =
+        spawn_resumption_handler(5024, ttl).await;
+
+    let (challenges, _) = resumption_exchange(
+        (&sender_send, &mut sender_recv, &sender_enr),
+        (&receiver_send, &mut receiver_recv, &receiver_enr),
+        1,
+    )
+    .await;
+    assert_eq!(challenges, 1);
+
+    lose_sessions((&sender_send, &sender_enr), (&receiver_send, &receiver_enr)).await;
+    sleep(Duration::from_millis(150)).await;
+
+    let (challenges, established) = resumption_exchange(
+        (&sender_send, &mut sender_recv, &sender_enr),
+        (&receiver_send, &mut receiver_recv, &receiver_enr),
+        2,
+    )
+    .await;
+    assert_eq!(challenges, 1);
+    assert_eq!(established, vec![ConnectionDirection::Outgoing]);
+}
+
+#[tokio::test]
+async fn outbound_response_limit_truncates_nodes_responses() {
+    init();
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(5016)
+        .build(&key)
+        .unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::LOCALHOST,
+        port: 5016,
+    })
+    .outbound_response_limit(Some((500, Duration::from_secs(60))))
+    .build();
+    let (_exit, _send, _recv, mut handler) =
+        build_handler::<DefaultProtocolId>(enr, key, config).await;
+
+    let node_id = NodeId::random();
+    let nodes_body = |count: u16| ResponseBody::Nodes {
+        total: 1,
+        nodes: (0..count).map(|_| Arc::new(create_node())).collect(),
+    };
+    let response = |body| Response {
+        id: RequestId::random(),
+        body,
+    };
+
+    // A response larger than the whole budget is cut down to the nodes that fit.
+    let message = handler
+        .limit_response(&node_id, response(nodes_body(16)))
+        .unwrap();
+    assert!(message.len() <= 500);
+    let Message::Response(Response {
+        body: ResponseBody::Nodes { nodes, .. },
+        ..
+    }) = Message::decode(&message).unwrap()
+    else {
+        panic!("Expected a NODES response");
+    };
+    assert!(!nodes.is_empty() && nodes.len() < 16);
+
+    // The budget left is too small for a single node, or a TALK response.
+    assert!(handler
+        .limit_response(&node_id, response(nodes_body(1)))
+        .is_none());
+    let talk = ResponseBody::Talk {
+        response: vec![0; 400],
+    };
+    assert!(handler.limit_response(&node_id, response(talk)).is_none());
+    // Other responses are not limited.
+    let pong = ResponseBody::Pong {
+        enr_seq: 1,
+        ip: Ipv4Addr::LOCALHOST.into(),
+        port: NonZeroU16::new(5016).unwrap(),
+    };
+    assert!(handler.limit_response(&node_id, response(pong)).is_some());
+    // Another peer has a budget of its own.
+    assert!(handler
+        .limit_response(&NodeId::random(), response(nodes_body(1)))
+        .is_some());
+}
+
+#[tokio::test]
+// Tests that a session with a node dialed without its ENR is dropped if the ENR it sends is not
+// allowed.
+async fn disallowed_enr_fails_session_awaiting_enr() {
+    init();
+    let ip = "127.0.0.1".parse().unwrap();
+    let sender_key = CombinedKey::generate_secp256k1();
+    let receiver_key = CombinedKey::generate_secp256k1();
+    let (sender_public_key, receiver_public_key) = (sender_key.public(), receiver_key.public());
+    let sender_enr = Enr::builder()
+        .ip4(ip)
+        .udp4(5017)
+        .build(&sender_key)
+        .unwrap();
+    let receiver_enr = Enr::builder()
+        .ip4(ip)
+        .udp4(5018)
+        .build(&receiver_key)
+        .unwrap();
+
+    // The sender only allows itself.
+    let sender_config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port: 5017 })
+        .enr_allowlist(EnrAllowlist::from_public_keys([sender_public_key]))
+        .build();
+    let (_exit_send, sender_send, mut sender_recv) = Handler::spawn::<DefaultProtocolId>(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(sender_key),
+        Default::default(),
+        sender_config,
+    )
+    .await
+    .unwrap();
+    let receiver_config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port: 5018 }).build();
+    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn::<DefaultProtocolId>(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        Default::default(),
+        receiver_config,
+    )
+    .await
+    .unwrap();
+
+    // The receiver is dialed by its address alone, so its ENR is requested once the session is
+    // established.
+    let node_id = receiver_enr.node_id();
+    let contact = NodeContact::new(
+        receiver_public_key,
+        receiver_enr.udp4_socket().unwrap().into(),
+        None,
+    );
+    let ping = Request {
+        id: RequestId(vec![1]),
+        body: RequestBody::Ping { enr_seq: 1 },
+    };
+    sender_send
+        .try_send(HandlerIn::Request(
+            contact,
+            Box::new(ping.clone()),
+            RequestPriority::Query,
+            None,
+        ))
+        .unwrap();
+
+    let receiver = async move {
+        while let Some(message) = receiver_recv.recv().await {
+            match message {
+                HandlerOut::WhoAreYou(wru_ref) => {
+                    let _ = recv_send.try_send(HandlerIn::WhoAreYou(wru_ref, None));
+                }
+                // The PING is left unanswered.
+                HandlerOut::Request(node_address, request) => {
+                    if let RequestBody::FindNode { .. } = request.body {
+                        let response = Response {
+                            id: request.id,
+                            body: ResponseBody::Nodes {
+                                total: 1,
+                                nodes: vec![Arc::new(receiver_enr.clone())],
+                            },
+                        };
+                        let _ = recv_send
+                            .try_send(HandlerIn::Response(node_address, Box::new(response)));
+                    }
+                }
+                _ => {}
+            }
+        }
+    };
+    let sender = async move {
+        loop {
+            match sender_recv.recv().await {
+                Some(HandlerOut::RequestFailed(id, error)) => return (id, error),
+                Some(HandlerOut::Established(..)) => {
+                    panic!("Session established with disallowed ENR")
+                }
+                Some(_) => {}
+                None => panic!("Handler stopped"),
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = receiver => panic!("Receiver stopped"),
+        failed = sender => {
+            assert_eq!(failed, (ping.id, RequestError::InvalidRemoteEnr(node_id)));
+        }
+        _ = sleep(Duration::from_millis(500)) => panic!("Test timed out"),
+    }
+}
+
+#[tokio::test]
+async fn relay_msg_punches_towards_observed_socket() {
+    init();
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(5019)
+        .build(&key)
+        .unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::LOCALHOST,
+        port: 5019,
+    })
+    .build();
+    let (_exit, _send, _recv, mut handler) =
+        build_handler::<DefaultProtocolId>(enr, key, config).await;
+
+    let relay_msg = |port: u16| {
+        let initiator_enr = Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(9100)
+            .build(&CombinedKey::generate_secp256k1())
+            .unwrap();
+        let initiator_socket: SocketAddr = (Ipv4Addr::LOCALHOST, port).into();
+        let initiator_address = NodeAddress {
+            socket_addr: initiator_socket,
+            node_id: initiator_enr.node_id(),
+        };
+        let notification = Notification::RelayMsg {
+            initiator_enr,
+            initiator_socket,
+            nonce: rand::random(),
+        };
+        (initiator_address, notification)
+    };
+    let relay = NodeAddress {
+        socket_addr: (Ipv4Addr::LOCALHOST, 9200).into(),
+        node_id: NodeId::random(),
+    };
+
+    // The challenge goes to the socket the relay observed, not the one in the initiator's ENR.
+    let (initiator_address, notification) = relay_msg(40000);
+    handler
+        .handle_notification::<DefaultProtocolId>(relay.clone(), notification)
+        .await;
+    assert!(handler.active_challenges.get(&initiator_address).is_some());
+    assert_eq!(handler.active_challenges.len(), 1);
+
+    // Each relay can only make us send a limited number of challenges.
+    for port in 40001..40000 + RELAY_RATE_LIMIT.0 as u16 {
+        let (_, notification) = relay_msg(port);
+        handler
+            .handle_notification::<DefaultProtocolId>(relay.clone(), notification)
+            .await;
+    }
+    let (initiator_address, notification) = relay_msg(41000);
+    handler
+        .handle_notification::<DefaultProtocolId>(relay, notification)
+        .await;
+    assert!(handler.active_challenges.get(&initiator_address).is_none());
+    assert_eq!(handler.active_challenges.len(), RELAY_RATE_LIMIT.0 as usize);
+
+    // Other relays are limited independently.
+    let other_relay = NodeAddress {
+        socket_addr: (Ipv4Addr::LOCALHOST, 9201).into(),
+        node_id: NodeId::random(),
+    };
+    let (initiator_address, notification) = relay_msg(41001);
+    handler
+        .handle_notification::<DefaultProtocolId>(other_relay, notification)
+        .await;
+    assert!(handler.active_challenges.get(&initiator_address).is_some());
+}
+
+#[tokio::test]
+async fn max_session_establishments_queues_and_expires_requests() {
+    init();
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(5020)
+        .build(&key)
+        .unwrap();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::LOCALHOST,
+        port: 5020,
+    })
+    .request_timeout(Duration::from_millis(300))
+    .request_retries(0)
+    .max_session_establishments(Some(1))
+    .build();
+    let (_exit, send, mut recv) =
+        Handler::spawn::<DefaultProtocolId>(arc_rw!(enr), arc_rw!(key), Default::default(), config)
+            .await
+            .unwrap();
+
+    let ping = |id: u8| {
+        Box::new(Request {
+            id: RequestId(vec![id]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        })
+    };
+    let requests = [
+        (1, RequestPriority::Maintenance, None),
+        (
+            2,
+            RequestPriority::Maintenance,
+            Some(Duration::from_millis(50)),
+        ),
+        (3, RequestPriority::Query, Some(Duration::from_secs(5))),
+    ];
+    for (id, priority, timeout) in requests {
+        let contact = NodeContact::from(create_node());
+        send.send(HandlerIn::Request(contact, ping(id), priority, timeout))
+            .await
+            .unwrap();
+    }
+    let pending_requests = || async {
+        let (callback, pending) = oneshot::channel();
+        send.send(HandlerIn::PendingRequests(callback))
+            .await
+            .unwrap();
+        let mut states: Vec<_> = pending
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|request| request.state)
+            .collect();
+        states.sort_by_key(|state| *state as u8);
+        states
+    };
+
+    // Only one session is established at once.
+    assert_eq!(
+        pending_requests().await,
+        vec![
+            OutgoingRequestState::Sent,
+            OutgoingRequestState::Queued,
+            OutgoingRequestState::Queued
+        ]
+    );
+
+    // The queued request with a short timeout fails before the establishment completes.
+    let failed = |out| match out {
+        Some(HandlerOut::RequestFailed(id, RequestError::Timeout)) => id,
+        _ => panic!("Expected a timed out request"),
+    };
+    assert_eq!(failed(recv.recv().await), RequestId(vec![2]));
+    assert_eq!(
+        pending_requests().await,
+        vec![OutgoingRequestState::Sent, OutgoingRequestState::Queued]
+    );
+
+    // Once the first establishment fails, the next queued request is sent.
+    assert_eq!(failed(recv.recv().await), RequestId(vec![1]));
+    assert_eq!(pending_requests().await, vec![OutgoingRequestState::Sent]);
+}
@@ -4,7 +4,7 @@ use crate::{
     Enr,
     IpMode::{DualStack, Ip4, Ip6},
 };
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 /// Sets the socket type to be established and also determines the type of ENRs that we will store
 /// in our routing table.
@@ -25,10 +25,10 @@ pub enum IpMode {
 
 impl IpMode {
     pub(crate) fn new_from_listen_config(listen_config: &ListenConfig) -> Self {
-        match listen_config {
-            ListenConfig::Ipv4 { .. } => Ip4,
-            ListenConfig::Ipv6 { .. } => Ip6,
-            ListenConfig::DualStack { .. } => DualStack,
+        match listen_config.socket_addrs() {
+            (Some(_), Some(_)) => DualStack,
+            (None, Some(_)) => Ip6,
+            _ => Ip4,
         }
     }
 
@@ -40,33 +40,116 @@ impl IpMode {
     /// dual stack, an Enr that advertises both an Ipv4 and a canonical Ipv6 address will be
     /// contacted using their Ipv6 address.
     pub fn get_contactable_addr(&self, enr: &Enr) -> Option<SocketAddr> {
-        // A function to get a canonical ipv6 address from an Enr
-
-        /// NOTE: There is nothing in the spec preventing compat/mapped addresses from being
-        /// transmitted in the ENR. Here we choose to enforce canonical addresses since
-        /// it simplifies the logic of matching socket_addr verification. For this we prevent
-        /// communications with Ipv4 addresses advertised in the Ipv6 field.
-        fn canonical_ipv6_enr_addr(enr: &Enr) -> Option<std::net::SocketAddrV6> {
-            enr.udp6_socket().and_then(|socket_addr| {
-                if to_ipv4_mapped(socket_addr.ip()).is_some() {
-                    None
-                } else {
-                    Some(socket_addr)
-                }
-            })
-        }
+        // NOTE: general consensus is that ipv6 addresses should be preferred.
+        self.get_contactable_addr_preferring(enr, true)
+    }
 
+    /// Get the contactable Socket address of an Enr under current configuration. When running in
+    /// dual stack, `prefer_ipv6` decides which address is used for an Enr that advertises both.
+    pub(crate) fn get_contactable_addr_preferring(
+        &self,
+        enr: &Enr,
+        prefer_ipv6: bool,
+    ) -> Option<SocketAddr> {
         match self {
             Ip4 => enr.udp4_socket().map(SocketAddr::V4),
             Ip6 => canonical_ipv6_enr_addr(enr).map(SocketAddr::V6),
             DualStack => {
-                canonical_ipv6_enr_addr(enr)
-                    .map(SocketAddr::V6)
-                    // NOTE: general consensus is that ipv6 addresses should be preferred.
-                    .or_else(|| enr.udp4_socket().map(SocketAddr::V4))
+                let ipv6 = canonical_ipv6_enr_addr(enr).map(SocketAddr::V6);
+                let ipv4 = enr.udp4_socket().map(SocketAddr::V4);
+                if prefer_ipv6 {
+                    ipv6.or(ipv4)
+                } else {
+                    ipv4.or(ipv6)
+                }
             }
         }
     }
+
+    /// Whether a node can be contacted over the IP versions in use.
+    pub fn reachability(&self, enr: &Enr) -> EnrReachability {
+        if self.get_contactable_addr(enr).is_some() {
+            EnrReachability::Reachable
+        } else if enr.udp4_socket().is_some() || canonical_ipv6_enr_addr(enr).is_some() {
+            EnrReachability::OtherIpVersion
+        } else {
+            EnrReachability::NoSocket
+        }
+    }
+
+    /// Get the contactable address of an Enr of the other IP version than `socket_addr`. This is
+    /// only ever `Some` when running in dual stack.
+    pub(crate) fn get_alternate_addr(
+        &self,
+        enr: &Enr,
+        socket_addr: &SocketAddr,
+    ) -> Option<SocketAddr> {
+        match (self, socket_addr) {
+            (DualStack, SocketAddr::V6(_)) => enr.udp4_socket().map(SocketAddr::V4),
+            (DualStack, SocketAddr::V4(_)) => canonical_ipv6_enr_addr(enr).map(SocketAddr::V6),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a node can be contacted according to its ENR, given the IP versions in use. Unlike
+/// [`crate::crawler::Reachability`], this doesn't tell whether the node answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum EnrReachability {
+    /// The ENR advertises a UDP socket of an IP version in use.
+    Reachable,
+    /// The ENR only advertises a UDP socket of the IP version not in use.
+    OtherIpVersion,
+    /// The ENR advertises no UDP socket, or only an IPv4 address mapped into its IPv6 field.
+    NoSocket,
+}
+
+/// Decides which address of a node that advertises both an Ipv4 and an Ipv6 address is contacted
+/// when running in dual stack. Once a session has been established with such a node, the address
+/// family it was established over is preferred for that node regardless of the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DialPolicy {
+    /// Contact the Ipv6 address.
+    #[default]
+    PreferIpv6,
+    /// Contact the Ipv4 address.
+    PreferIpv4,
+    /// Contact the Ipv6 address and, if no session has been established with the node after
+    /// `stagger`, also ping its Ipv4 address. Whichever address establishes a session first is
+    /// used for the node from then on.
+    HappyEyeballs { stagger: Duration },
+}
+
+impl DialPolicy {
+    /// Whether an Ipv6 address is contacted first under this policy.
+    pub(crate) fn prefers_ipv6(&self) -> bool {
+        !matches!(self, DialPolicy::PreferIpv4)
+    }
+}
+
+/// Get a canonical ipv6 address from an Enr.
+///
+/// NOTE: There is nothing in the spec preventing compat/mapped addresses from being
+/// transmitted in the ENR. Here we choose to enforce canonical addresses since
+/// it simplifies the logic of matching socket_addr verification. For this we prevent
+/// communications with Ipv4 addresses advertised in the Ipv6 field.
+fn canonical_ipv6_enr_addr(enr: &Enr) -> Option<std::net::SocketAddrV6> {
+    enr.udp6_socket().and_then(|socket_addr| {
+        if to_ipv4_mapped(socket_addr.ip()).is_some() {
+            None
+        } else {
+            Some(socket_addr)
+        }
+    })
+}
+
+/// Whether the address is an IPv4 address, or an IPv4 address mapped into IPv6.
+pub(crate) fn is_ipv4_or_mapped(socket_addr: &SocketAddr) -> bool {
+    match socket_addr {
+        SocketAddr::V4(_) => true,
+        SocketAddr::V6(socket_addr) => to_ipv4_mapped(socket_addr.ip()).is_some(),
+    }
 }
 
 /// Copied from the standard library. See <https://github.com/rust-lang/rust/issues/27709>
@@ -240,4 +323,56 @@ mod tests {
             .expect_ip6(Ipv6Addr::LOCALHOST)
             .test();
     }
+
+    #[test]
+    fn dual_stack_enr_dial_preference() {
+        let enr = enr::Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(IP4_TEST_PORT)
+            .ip6(Ipv6Addr::LOCALHOST)
+            .udp6(IP6_TEST_PORT)
+            .build(&enr::CombinedKey::generate_secp256k1())
+            .unwrap();
+        let ipv4 = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, IP4_TEST_PORT));
+        let ipv6 = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, IP6_TEST_PORT, 0, 0));
+
+        assert_eq!(
+            DualStack.get_contactable_addr_preferring(&enr, false),
+            Some(ipv4)
+        );
+        assert_eq!(
+            DualStack.get_contactable_addr_preferring(&enr, true),
+            Some(ipv6)
+        );
+        assert_eq!(DualStack.get_alternate_addr(&enr, &ipv6), Some(ipv4));
+        assert_eq!(DualStack.get_alternate_addr(&enr, &ipv4), Some(ipv6));
+        assert_eq!(Ip4.get_alternate_addr(&enr, &ipv4), None);
+        assert!(!DialPolicy::PreferIpv4.prefers_ipv6());
+    }
+
+    #[test]
+    fn enr_reachability() {
+        let key = enr::CombinedKey::generate_secp256k1();
+        let ipv6_only = enr::Enr::builder()
+            .ip6(Ipv6Addr::LOCALHOST)
+            .udp6(IP6_TEST_PORT)
+            .build(&key)
+            .unwrap();
+        let mapped_only = enr::Enr::builder()
+            .ip6(Ipv4Addr::LOCALHOST.to_ipv6_mapped())
+            .udp6(IP6_TEST_PORT)
+            .build(&key)
+            .unwrap();
+
+        assert_eq!(Ip6.reachability(&ipv6_only), EnrReachability::Reachable);
+        assert_eq!(
+            Ip4.reachability(&ipv6_only),
+            EnrReachability::OtherIpVersion
+        );
+        assert_eq!(Ip4.reachability(&mapped_only), EnrReachability::NoSocket);
+        assert_eq!(
+            DualStack.reachability(&mapped_only),
+            EnrReachability::NoSocket
+        );
+    }
 }
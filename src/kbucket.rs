@@ -62,7 +62,9 @@
 // the nodes in the buckets are not reordered as a result of RPC activity, but only as a
 // result of nodes being marked as connected or disconnected. In particular,
 // if a bucket is full and contains only entries for peers that are considered
-// connected, no pending entry is accepted. See the `bucket` submodule for
+// connected, no pending entry is accepted. Under `EvictionPolicy::PreferLongLived`
+// such entries are instead kept in a per-bucket replacement cache, from which
+// free or disconnected slots are refilled. See the `bucket` submodule for
 // further details.
 //
 // [0]: https://pdos.csail.mit.edu/~petar/papers/maymounkov-kademlia-lncs.pdf
@@ -75,17 +77,16 @@ mod key;
 pub use entry::*;
 
 pub use crate::handler::ConnectionDirection;
+use crate::time::{self, Instant};
 use arrayvec::{self, ArrayVec};
 use bucket::KBucket;
 pub use bucket::{
-    ConnectionState, FailureReason, InsertResult as BucketInsertResult, UpdateResult,
-    MAX_NODES_PER_BUCKET,
+    BucketChurn, ConnectionState, EvictionPolicy, FailureReason,
+    InsertResult as BucketInsertResult, UpdateResult, MAX_NODES_PER_BUCKET,
 };
 pub use filter::{Filter, IpBucketFilter, IpTableFilter};
-use std::{
-    collections::VecDeque,
-    time::{Duration, Instant},
-};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::{collections::VecDeque, time::Duration};
 
 /// Maximum number of k-buckets.
 const NUM_BUCKETS: usize = 256;
@@ -144,17 +145,23 @@ impl<TNodeId, TVal> PredicateValue<TNodeId, TVal> {
 }
 
 /// A `KBucketsTable` represents a Kademlia routing table.
-#[derive(Clone)]
+///
+/// Each bucket is behind a lock of its own, so that the table can be shared without an outer
+/// lock. Operations on a key only lock the bucket of the key, and reads of the whole table lock
+/// one bucket at a time, so that large reads don't hold up updates of other buckets.
 pub struct KBucketsTable<TNodeId, TVal: Eq> {
     /// The key identifying the local peer that owns the routing table.
-    local_key: Key<TNodeId>,
+    local_key: RwLock<Key<TNodeId>>,
     /// The buckets comprising the routing table.
-    buckets: Vec<KBucket<TNodeId, TVal>>,
+    buckets: Vec<RwLock<KBucket<TNodeId, TVal>>>,
     /// The list of evicted entries that have been replaced with pending
     /// entries since the last call to [`KBucketsTable::take_applied_pending`].
-    applied_pending: VecDeque<AppliedPending<TNodeId, TVal>>,
+    applied_pending: Mutex<VecDeque<AppliedPending<TNodeId, TVal>>>,
     /// Filter to be applied at the table level when adding/updating a node.
     table_filter: Option<Box<dyn Filter<TVal>>>,
+    /// Serializes the updates checked against the table filter, so that two updates can't both
+    /// pass a limit only one of them fits in.
+    update_lock: Mutex<()>,
 }
 
 #[must_use]
@@ -219,7 +226,7 @@ impl BucketIndex {
 impl<TNodeId, TVal> KBucketsTable<TNodeId, TVal>
 where
     TNodeId: Clone,
-    TVal: Eq,
+    TVal: Eq + Clone,
 {
     /// Creates a new, empty Kademlia routing table with entries partitioned
     /// into buckets as per the Kademlia protocol.
@@ -238,39 +245,99 @@ where
         bucket_filter: Option<Box<dyn Filter<TVal>>>,
     ) -> Self {
         KBucketsTable {
-            local_key,
+            local_key: RwLock::new(local_key),
             buckets: (0..NUM_BUCKETS)
                 .map(|_| {
-                    KBucket::new(
+                    RwLock::new(KBucket::new(
                         pending_timeout,
                         max_incoming_per_bucket,
                         bucket_filter.clone(),
-                    )
+                    ))
                 })
                 .collect(),
-            applied_pending: VecDeque::new(),
+            applied_pending: Mutex::new(VecDeque::new()),
             table_filter,
+            update_lock: Mutex::new(()),
+        }
+    }
+
+    /// Sets how full buckets treat new nodes.
+    pub fn set_eviction_policy(&self, policy: EvictionPolicy) {
+        for bucket in self.buckets.iter() {
+            bucket.write().set_eviction_policy(policy);
+        }
+    }
+
+    /// Returns the key of the local node.
+    pub fn local_key(&self) -> Key<TNodeId> {
+        self.local_key.read().clone()
+    }
+
+    /// Locks the bucket of the key for writing, after applying its pending entry. Returns `None`
+    /// for the local key.
+    fn bucket_mut(
+        &self,
+        key: &Key<TNodeId>,
+    ) -> Option<RwLockWriteGuard<'_, KBucket<TNodeId, TVal>>> {
+        loop {
+            let index = self.get_index(key)?;
+            let mut bucket = self.buckets[index].write();
+            // The local key only changes while all buckets are locked, see `set_local_key`.
+            if self.get_index(key) == Some(index) {
+                self.apply_pending(&mut bucket);
+                return Some(bucket);
+            }
+        }
+    }
+
+    /// Locks the bucket at the index for reading. The bucket is locked for writing first if its
+    /// pending entry is due to be applied.
+    fn read_bucket(&self, index: usize) -> RwLockReadGuard<'_, KBucket<TNodeId, TVal>> {
+        let bucket = self.buckets[index].read();
+        if !bucket.pending().is_some_and(|pending| pending.is_ready()) {
+            return bucket;
         }
+        drop(bucket);
+        let mut bucket = self.buckets[index].write();
+        self.apply_pending(&mut bucket);
+        RwLockWriteGuard::downgrade(bucket)
+    }
+
+    /// Inserts the pending entry of the bucket if it is due, recording it for
+    /// [`KBucketsTable::take_applied_pending`].
+    fn apply_pending(&self, bucket: &mut KBucket<TNodeId, TVal>) {
+        if let Some(applied) = bucket.apply_pending() {
+            self.applied_pending.lock().push_back(applied)
+        }
+    }
+
+    /// Whether the value passes the table filter. Values already stored for the key are not
+    /// checked again.
+    fn passes_table_filter(&self, key: &Key<TNodeId>, value: &TVal) -> bool {
+        let Some(table_filter) = self.table_filter.as_ref() else {
+            return true;
+        };
+        // Check if the value is a duplicate before applying the table filter (optimisation).
+        let duplicate = self.get_index(key).is_some_and(|index| {
+            self.buckets[index]
+                .read()
+                .get(key)
+                .is_some_and(|node| node.value == *value)
+        });
+        duplicate || table_filter.filter(value, &mut self.table_values().iter())
     }
 
     // Updates a node's status if it exists in the table.
     // This checks all table and bucket filters before performing the update.
     pub fn update_node_status(
-        &mut self,
+        &self,
         key: &Key<TNodeId>,
         state: ConnectionState,
         direction: Option<ConnectionDirection>,
     ) -> UpdateResult {
-        let index = BucketIndex::new(&self.local_key.distance(key));
-        if let Some(i) = index {
-            let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
-            }
-
-            bucket.update_status(key, state, direction)
-        } else {
-            UpdateResult::NotModified // The key refers to our current node.
+        match self.bucket_mut(key) {
+            Some(mut bucket) => bucket.update_status(key, state, direction),
+            None => UpdateResult::NotModified, // The key refers to our current node.
         }
     }
 
@@ -278,272 +345,202 @@ where
     ///
     /// Optionally the connection state can be modified.
     pub fn update_node(
-        &mut self,
+        &self,
         key: &Key<TNodeId>,
         value: TVal,
         state: Option<ConnectionState>,
     ) -> UpdateResult {
-        // Apply the table filter
-        let mut passed_table_filter = true;
-        if let Some(table_filter) = self.table_filter.as_ref() {
-            // Check if the value is a duplicate before applying the table filter (optimisation).
-            let duplicate = {
-                let index = BucketIndex::new(&self.local_key.distance(key));
-                if let Some(i) = index {
-                    let bucket = &mut self.buckets[i.get()];
-                    if let Some(node) = bucket.get(key) {
-                        node.value == value
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            };
-
-            // If the Value is new, check the table filter
-            if !duplicate && !table_filter.filter(&value, &mut self.table_iter()) {
-                passed_table_filter = false;
-            }
-        }
+        // The table filter and the update are applied together.
+        let _update = self.update_lock.lock();
+        let passed_table_filter = self.passes_table_filter(key, &value);
 
-        let index = BucketIndex::new(&self.local_key.distance(key));
-        if let Some(i) = index {
-            let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
-            }
-
-            if !passed_table_filter {
-                bucket.remove(key);
-                return UpdateResult::Failed(FailureReason::TableFilter);
-            }
+        let Some(mut bucket) = self.bucket_mut(key) else {
+            return UpdateResult::NotModified; // The key refers to our current node.
+        };
 
-            let update_result = bucket.update_value(key, value);
+        if !passed_table_filter {
+            bucket.remove(key);
+            return UpdateResult::Failed(FailureReason::TableFilter);
+        }
 
-            if let UpdateResult::Failed(_) = &update_result {
-                return update_result;
-            }
+        let update_result = bucket.update_value(key, value);
 
-            // If we need to update the connection state, update it here.
-            let status_result = if let Some(state) = state {
-                bucket.update_status(key, state, None)
-            } else {
-                UpdateResult::NotModified
-            };
+        if let UpdateResult::Failed(_) = &update_result {
+            return update_result;
+        }
 
-            // Return an appropriate value
-            match (&update_result, &status_result) {
-                (_, UpdateResult::Failed(_)) => status_result,
-                (UpdateResult::Failed(_), _) => update_result,
-                (_, UpdateResult::UpdatedAndPromoted) => UpdateResult::UpdatedAndPromoted,
-                (UpdateResult::UpdatedPending, _) => UpdateResult::UpdatedPending,
-                (_, UpdateResult::UpdatedPending) => UpdateResult::UpdatedPending,
-                (UpdateResult::NotModified, UpdateResult::NotModified) => UpdateResult::NotModified,
-                (_, _) => UpdateResult::Updated,
-            }
+        // If we need to update the connection state, update it here.
+        let status_result = if let Some(state) = state {
+            bucket.update_status(key, state, None)
         } else {
-            UpdateResult::NotModified // The key refers to our current node.
+            UpdateResult::NotModified
+        };
+
+        // Return an appropriate value
+        match (&update_result, &status_result) {
+            (_, UpdateResult::Failed(_)) => status_result,
+            (UpdateResult::Failed(_), _) => update_result,
+            (_, UpdateResult::UpdatedAndPromoted) => UpdateResult::UpdatedAndPromoted,
+            (UpdateResult::UpdatedPending, _) => UpdateResult::UpdatedPending,
+            (_, UpdateResult::UpdatedPending) => UpdateResult::UpdatedPending,
+            (UpdateResult::NotModified, UpdateResult::NotModified) => UpdateResult::NotModified,
+            (_, _) => UpdateResult::Updated,
         }
     }
 
     // Attempts to insert or update
     pub fn insert_or_update(
-        &mut self,
+        &self,
         key: &Key<TNodeId>,
         value: TVal,
         status: NodeStatus,
     ) -> InsertResult<TNodeId> {
-        // Check the table filter
-        let mut passed_table_filter = true;
-        if let Some(table_filter) = self.table_filter.as_ref() {
-            // Check if the value is a duplicate before applying the table filter (optimisation).
-            let duplicate = {
-                let index = BucketIndex::new(&self.local_key.distance(key));
-                if let Some(i) = index {
-                    let bucket = &mut self.buckets[i.get()];
-                    if let Some(node) = bucket.get(key) {
-                        node.value == value
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            };
+        // The table filter and the insertion are applied together.
+        let _update = self.update_lock.lock();
+        let passed_table_filter = self.passes_table_filter(key, &value);
 
-            if !duplicate && !table_filter.filter(&value, &mut self.table_iter()) {
-                passed_table_filter = false;
-            }
+        let Some(mut bucket) = self.bucket_mut(key) else {
+            // Cannot insert our local entry.
+            return InsertResult::Failed(FailureReason::InvalidSelfUpdate);
+        };
+
+        if !passed_table_filter {
+            bucket.remove(key);
+            return InsertResult::Failed(FailureReason::TableFilter);
         }
 
-        let index = BucketIndex::new(&self.local_key.distance(key));
-        if let Some(i) = index {
-            let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
+        // If the node doesn't exist, insert it
+        if bucket.position(key).is_none() {
+            let node = Node {
+                key: key.clone(),
+                value,
+                status,
+            };
+            match bucket.insert(node) {
+                bucket::InsertResult::NodeExists => unreachable!("Node must exist"),
+                bucket::InsertResult::Full => InsertResult::Failed(FailureReason::BucketFull),
+                bucket::InsertResult::TooManyIncoming => {
+                    InsertResult::Failed(FailureReason::TooManyIncoming)
+                }
+                bucket::InsertResult::FailedFilter => {
+                    InsertResult::Failed(FailureReason::BucketFilter)
+                }
+                bucket::InsertResult::Pending { disconnected } => {
+                    InsertResult::Pending { disconnected }
+                }
+                bucket::InsertResult::Inserted => InsertResult::Inserted,
             }
+        } else {
+            // The node exists in the bucket
+            // Attempt to update the status
+            let update_status = bucket.update_status(key, status.state, Some(status.direction));
 
-            if !passed_table_filter {
-                bucket.remove(key);
-                return InsertResult::Failed(FailureReason::TableFilter);
+            if update_status.failed() {
+                // The node was removed from the table
+                return InsertResult::Failed(FailureReason::TooManyIncoming);
             }
+            // Attempt to update the value
+            let update_value = bucket.update_value(key, value);
 
-            // If the node doesn't exist, insert it
-            if bucket.position(key).is_none() {
-                let node = Node {
-                    key: key.clone(),
-                    value,
-                    status,
-                };
-                match bucket.insert(node) {
-                    bucket::InsertResult::NodeExists => unreachable!("Node must exist"),
-                    bucket::InsertResult::Full => InsertResult::Failed(FailureReason::BucketFull),
-                    bucket::InsertResult::TooManyIncoming => {
-                        InsertResult::Failed(FailureReason::TooManyIncoming)
-                    }
-                    bucket::InsertResult::FailedFilter => {
-                        InsertResult::Failed(FailureReason::BucketFilter)
-                    }
-                    bucket::InsertResult::Pending { disconnected } => {
-                        InsertResult::Pending { disconnected }
+            match (update_value, update_status) {
+                (UpdateResult::Updated { .. }, UpdateResult::Updated) => InsertResult::Updated {
+                    promoted_to_connected: false,
+                },
+                (UpdateResult::Updated { .. }, UpdateResult::UpdatedAndPromoted) => {
+                    InsertResult::Updated {
+                        promoted_to_connected: true,
                     }
-                    bucket::InsertResult::Inserted => InsertResult::Inserted,
                 }
-            } else {
-                // The node exists in the bucket
-                // Attempt to update the status
-                let update_status = bucket.update_status(key, status.state, Some(status.direction));
-
-                if update_status.failed() {
-                    // The node was removed from the table
-                    return InsertResult::Failed(FailureReason::TooManyIncoming);
+                (UpdateResult::Updated { .. }, UpdateResult::NotModified)
+                | (UpdateResult::Updated { .. }, UpdateResult::UpdatedPending) => {
+                    InsertResult::ValueUpdated
                 }
-                // Attempt to update the value
-                let update_value = bucket.update_value(key, value);
-
-                match (update_value, update_status) {
-                    (UpdateResult::Updated { .. }, UpdateResult::Updated) => {
-                        InsertResult::Updated {
-                            promoted_to_connected: false,
-                        }
-                    }
-                    (UpdateResult::Updated { .. }, UpdateResult::UpdatedAndPromoted) => {
-                        InsertResult::Updated {
-                            promoted_to_connected: true,
-                        }
-                    }
-                    (UpdateResult::Updated { .. }, UpdateResult::NotModified)
-                    | (UpdateResult::Updated { .. }, UpdateResult::UpdatedPending) => {
-                        InsertResult::ValueUpdated
-                    }
-                    (UpdateResult::NotModified, UpdateResult::Updated) => {
-                        InsertResult::StatusUpdated {
-                            promoted_to_connected: false,
-                        }
-                    }
-                    (UpdateResult::NotModified, UpdateResult::UpdatedAndPromoted) => {
-                        InsertResult::StatusUpdated {
-                            promoted_to_connected: true,
-                        }
-                    }
-                    (UpdateResult::NotModified, UpdateResult::NotModified) => {
-                        InsertResult::Updated {
-                            promoted_to_connected: false,
-                        }
-                    }
-                    (UpdateResult::UpdatedPending, _) | (_, UpdateResult::UpdatedPending) => {
-                        InsertResult::UpdatedPending
-                    }
-                    (UpdateResult::Failed(reason), _) => InsertResult::Failed(reason),
-                    (_, UpdateResult::Failed(_)) => unreachable!("Status failure handled earlier."),
-                    (UpdateResult::UpdatedAndPromoted, _) => {
-                        unreachable!("Value update cannot promote a connection.")
+                (UpdateResult::NotModified, UpdateResult::Updated) => InsertResult::StatusUpdated {
+                    promoted_to_connected: false,
+                },
+                (UpdateResult::NotModified, UpdateResult::UpdatedAndPromoted) => {
+                    InsertResult::StatusUpdated {
+                        promoted_to_connected: true,
                     }
                 }
+                (UpdateResult::NotModified, UpdateResult::NotModified) => InsertResult::Updated {
+                    promoted_to_connected: false,
+                },
+                (UpdateResult::UpdatedPending, _) | (_, UpdateResult::UpdatedPending) => {
+                    InsertResult::UpdatedPending
+                }
+                (UpdateResult::Failed(reason), _) => InsertResult::Failed(reason),
+                (_, UpdateResult::Failed(_)) => unreachable!("Status failure handled earlier."),
+                (UpdateResult::UpdatedAndPromoted, _) => {
+                    unreachable!("Value update cannot promote a connection.")
+                }
             }
-        } else {
-            // Cannot insert our local entry.
-            InsertResult::Failed(FailureReason::InvalidSelfUpdate)
         }
     }
 
     /// Removes a node from the routing table. Returns `true` of the node existed.
-    pub fn remove(&mut self, key: &Key<TNodeId>) -> bool {
-        let index = BucketIndex::new(&self.local_key.distance(key));
-        if let Some(i) = index {
-            let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
-            }
-            bucket.remove(key)
-        } else {
-            false
-        }
+    pub fn remove(&self, key: &Key<TNodeId>) -> bool {
+        self.bucket_mut(key)
+            .is_some_and(|mut bucket| bucket.remove(key))
     }
 
     /// Returns an `Entry` for the given key, representing the state of the entry
-    /// in the routing table.
+    /// in the routing table. The bucket of the key stays locked until the entry is dropped.
     /// NOTE: This must be used with caution. Modifying values manually can bypass the internal
     /// table filters and ingoing/outgoing limits.
-    pub fn entry<'a>(&'a mut self, key: &'a Key<TNodeId>) -> Entry<'a, TNodeId, TVal> {
-        let index = BucketIndex::new(&self.local_key.distance(key));
-        if let Some(i) = index {
-            let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
-            }
-            Entry::new(bucket, key)
-        } else {
-            Entry::SelfEntry
+    pub fn entry<'a>(&'a self, key: &'a Key<TNodeId>) -> Entry<'a, TNodeId, TVal> {
+        match self.bucket_mut(key) {
+            Some(bucket) => Entry::new(bucket, key),
+            None => Entry::SelfEntry,
         }
     }
 
-    /// Returns an iterator over all the entries in the routing table.
-    pub fn iter(&mut self) -> impl Iterator<Item = EntryRefView<'_, TNodeId, TVal>> {
-        let applied_pending = &mut self.applied_pending;
-        self.buckets.iter_mut().flat_map(move |table| {
-            if let Some(applied) = table.apply_pending() {
-                applied_pending.push_back(applied)
-            }
-            table.iter().map(move |n| EntryRefView {
-                node: NodeRefView {
-                    key: &n.key,
-                    value: &n.value,
-                },
-                status: n.status,
-            })
-        })
+    /// Returns an iterator over all the entries in the routing table. The buckets are locked one
+    /// at a time, so that the iterator doesn't hold up updates of the other buckets.
+    pub fn iter(&self) -> impl Iterator<Item = EntryView<TNodeId, TVal>> + '_ {
+        (0..NUM_BUCKETS).flat_map(move |index| entry_views(&self.read_bucket(index)))
     }
 
-    /// Returns an iterator over all the buckets in the routing table
-    pub fn buckets_iter(&self) -> impl Iterator<Item = &KBucket<TNodeId, TVal>> {
-        self.buckets.iter()
+    /// The churn of each bucket that has held a node, by the log2 distance of the bucket.
+    pub fn churn(&self) -> Vec<(u64, BucketChurn)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(index, bucket)| (index as u64 + 1, bucket.read().churn()))
+            .filter(|(_, churn)| churn.insertions > 0)
+            .collect()
     }
 
-    /// Returns an iterator over all the entries in the routing table to give to a table filter.
+    /// Returns an iterator over all the buckets in the routing table, locking each bucket for
+    /// reading in turn.
+    pub fn buckets_iter(
+        &self,
+    ) -> impl Iterator<Item = RwLockReadGuard<'_, KBucket<TNodeId, TVal>>> {
+        self.buckets.iter().map(|bucket| bucket.read())
+    }
+
+    /// Returns the values of all the entries in the routing table to give to a table filter.
     ///
-    /// This differs from the regular iterator as it doesn't take ownership of self and doesn't try
-    /// to apply any pending nodes.
-    fn table_iter(&self) -> impl Iterator<Item = &TVal> {
+    /// This differs from the regular iterator as it doesn't try to apply any pending nodes.
+    fn table_values(&self) -> Vec<TVal> {
         self.buckets
             .iter()
-            .flat_map(move |table| table.iter().map(|n| &n.value))
+            .flat_map(|bucket| {
+                bucket
+                    .read()
+                    .iter()
+                    .map(|n| n.value.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
     }
 
     /// Returns an iterator over all the entries in the routing table.
-    /// Does not add pending node to kbucket to get an iterator which
-    /// takes a mutable reference instead of a reference.
-    pub fn iter_ref(&self) -> impl Iterator<Item = EntryRefView<'_, TNodeId, TVal>> {
-        self.buckets.iter().flat_map(move |table| {
-            table.iter().map(move |n| EntryRefView {
-                node: NodeRefView {
-                    key: &n.key,
-                    value: &n.value,
-                },
-                status: n.status,
-            })
-        })
+    /// Does not add pending nodes to the buckets, so that the buckets are only locked for reading.
+    pub fn iter_ref(&self) -> impl Iterator<Item = EntryView<TNodeId, TVal>> + '_ {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| entry_views(&bucket.read()))
     }
 
     /// Consumes the next applied pending entry, if any.
@@ -558,62 +555,27 @@ where
     /// buckets are updated accordingly. The fact that a pending entry was applied is
     /// recorded in the `KBucketsTable` in the form of `AppliedPending` results, which must be
     /// consumed by calling this function.
-    pub fn take_applied_pending(&mut self) -> Option<AppliedPending<TNodeId, TVal>> {
-        self.applied_pending.pop_front()
+    pub fn take_applied_pending(&self) -> Option<AppliedPending<TNodeId, TVal>> {
+        self.applied_pending.lock().pop_front()
     }
 
-    /// Returns an iterator over the keys that are contained in a kbucket, specified by a log2 distance.
+    /// Returns the entries that are contained in the kbuckets at the given log2 distances.
     pub fn nodes_by_distances(
-        &mut self,
+        &self,
         log2_distances: &[u64],
         max_nodes: usize,
-    ) -> Vec<EntryRefView<'_, TNodeId, TVal>> {
-        // Filter log2 distances to only include those in the closed interval [1, 256]
-        let distances = log2_distances
-            .iter()
-            .filter_map(|&d| {
-                if d > 0 && d <= (NUM_BUCKETS as u64) {
-                    Some(d)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
-
-        // Apply pending nodes
-        let mut node_count = 0;
-        for distance in &distances {
-            // The log2 distance ranges from 1-256 and is always 1 more than the bucket index. For this
-            // reason we subtract 1 from log2 distance to get the correct bucket index.
-            let bucket = &mut self.buckets[(distance - 1) as usize];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied);
-                // Break if we've reached the maximum number of nodes we will provide in the
-                // response. There's no need to apply pending buckets past this point, the nodes
-                // in those buckets won't be part of the response.
-                node_count += bucket.num_entries();
-                if node_count >= max_nodes {
-                    break;
-                }
-            }
-        }
-
-        // Find the matching nodes
+    ) -> Vec<EntryView<TNodeId, TVal>> {
         let mut matching_nodes = Vec::new();
 
         // Note we search via distance in order
-        for distance in distances {
-            let bucket = &self.buckets[(distance - 1) as usize];
-            for node in bucket.iter().map(|n| {
-                let node = NodeRefView {
-                    key: &n.key,
-                    value: &n.value,
-                };
-                EntryRefView {
-                    node,
-                    status: n.status,
-                }
-            }) {
+        // Filter log2 distances to only include those in the closed interval [1, 256]
+        for &distance in log2_distances {
+            if distance == 0 || distance > NUM_BUCKETS as u64 {
+                continue;
+            }
+            // The log2 distance ranges from 1-256 and is always 1 more than the bucket index. For
+            // this reason we subtract 1 from log2 distance to get the correct bucket index.
+            for node in entry_views(&self.read_bucket((distance - 1) as usize)) {
                 matching_nodes.push(node);
                 // Exit early if we have found enough nodes
                 if matching_nodes.len() >= max_nodes {
@@ -627,13 +589,13 @@ where
     /// Returns an iterator over the keys closest to `target`, ordered by
     /// increasing distance.
     pub fn closest_keys<'a, T>(
-        &'a mut self,
+        &'a self,
         target: &'a Key<T>,
     ) -> impl Iterator<Item = Key<TNodeId>> + 'a
     where
         T: Clone,
     {
-        let distance = self.local_key.distance(target);
+        let distance = self.local_key.read().distance(target);
         ClosestIter {
             target,
             iter: None,
@@ -648,14 +610,13 @@ where
     /// Returns an iterator over the keys closest to `target`, ordered by
     /// increasing distance.
     pub fn closest_values<'a, T>(
-        &'a mut self,
+        &'a self,
         target: &'a Key<T>,
     ) -> impl Iterator<Item = ClosestValue<TNodeId, TVal>> + 'a
     where
         T: Clone,
-        TVal: Clone,
     {
-        let distance = self.local_key.distance(target);
+        let distance = self.local_key.read().distance(target);
         ClosestIter {
             target,
             iter: None,
@@ -675,16 +636,15 @@ where
     /// Returns an iterator over the keys closest to `target`, ordered by
     /// increasing distance specifying which keys agree with a value predicate.
     pub fn closest_values_predicate<'a, T, F>(
-        &'a mut self,
+        &'a self,
         target: &'a Key<T>,
         predicate: F,
     ) -> impl Iterator<Item = PredicateValue<TNodeId, TVal>> + 'a
     where
         T: Clone,
         F: Fn(&TVal) -> bool + 'a,
-        TVal: Clone,
     {
-        let distance = self.local_key.distance(target);
+        let distance = self.local_key.read().distance(target);
         ClosestIter {
             target,
             iter: None,
@@ -702,24 +662,102 @@ where
         }
     }
 
-    /// Returns a reference to a bucket given the key. Returns None if bucket does not exist.
-    pub fn get_bucket(&self, key: &Key<TNodeId>) -> Option<&KBucket<TNodeId, TVal>> {
-        let index = BucketIndex::new(&self.local_key.distance(key));
-        if let Some(i) = index {
-            let bucket = &self.buckets[i.get()];
-            Some(bucket)
-        } else {
-            None
+    /// Marks a node disconnected, as if it had failed to respond, and makes the pending entry of
+    /// its bucket, if any, eligible to replace it immediately.
+    #[cfg(feature = "test_utils")]
+    pub(crate) fn expire_node(&self, key: &Key<TNodeId>) -> UpdateResult {
+        let Some(mut bucket) = self.bucket_mut(key) else {
+            return UpdateResult::NotModified;
+        };
+        let result = bucket.update_status(key, ConnectionState::Disconnected, None);
+        if let Some(pending) = bucket.pending_mut() {
+            pending.set_ready_at(time::now());
+        }
+        self.apply_pending(&mut bucket);
+        result
+    }
+
+    /// Changes the key of the local node. The entries are moved to the buckets of their distance
+    /// to the new key, in which they keep their order. The keys of the entries that no longer fit
+    /// are returned, while the previous pending entries and replacements are dropped.
+    pub fn set_local_key(&self, local_key: Key<TNodeId>) -> Vec<Key<TNodeId>> {
+        // All buckets are locked before the key, which other operations only read while holding
+        // no bucket lock.
+        let mut buckets: Vec<_> = self.buckets.iter().map(|bucket| bucket.write()).collect();
+        let mut current = self.local_key.write();
+        *current = local_key;
+        self.applied_pending.lock().clear();
+        let nodes: Vec<(Node<TNodeId, TVal>, Instant)> = buckets
+            .iter_mut()
+            .flat_map(|bucket| bucket.take_nodes())
+            .collect();
+        let mut dropped = Vec::new();
+        for (node, inserted_at) in nodes {
+            let key = node.key.clone();
+            let inserted = BucketIndex::new(&current.distance(&key)).is_some_and(|index| {
+                matches!(
+                    buckets[index.get()].restore(node, inserted_at),
+                    BucketInsertResult::Inserted | BucketInsertResult::Pending { .. }
+                )
+            });
+            if !inserted {
+                dropped.push(key);
+            }
         }
+        dropped
+    }
+
+    /// Returns the bucket of the given key, locked for reading. Returns None if bucket does not
+    /// exist.
+    pub fn get_bucket(
+        &self,
+        key: &Key<TNodeId>,
+    ) -> Option<RwLockReadGuard<'_, KBucket<TNodeId, TVal>>> {
+        self.get_index(key).map(|index| self.buckets[index].read())
     }
 
     /// Returns a bucket index given the key. Returns None if bucket index does not exist.
     pub fn get_index(&self, key: &Key<TNodeId>) -> Option<usize> {
-        let index = BucketIndex::new(&self.local_key.distance(key));
+        let index = BucketIndex::new(&self.local_key.read().distance(key));
         index.map(|i| i.get())
     }
 }
 
+impl<TNodeId, TVal> Clone for KBucketsTable<TNodeId, TVal>
+where
+    TNodeId: Clone,
+    TVal: Eq + Clone,
+{
+    fn clone(&self) -> Self {
+        KBucketsTable {
+            local_key: RwLock::new(self.local_key()),
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| RwLock::new(bucket.read().clone()))
+                .collect(),
+            applied_pending: Mutex::new(self.applied_pending.lock().clone()),
+            table_filter: self.table_filter.clone(),
+            update_lock: Mutex::new(()),
+        }
+    }
+}
+
+/// Copies the entries of a bucket, so that they outlive the lock on the bucket.
+fn entry_views<TNodeId, TVal>(bucket: &KBucket<TNodeId, TVal>) -> Vec<EntryView<TNodeId, TVal>>
+where
+    TNodeId: Clone,
+    TVal: Eq + Clone,
+{
+    bucket
+        .iter()
+        .map(|node| EntryView {
+            node: node.clone(),
+            status: node.status,
+        })
+        .collect()
+}
+
 /// An iterator over (some projection of) the closest entries in a
 /// `KBucketsTable` w.r.t. some target `Key`.
 struct ClosestIter<'a, TTarget, TNodeId, TVal: Eq, TMap, TOut> {
@@ -729,7 +767,7 @@ struct ClosestIter<'a, TTarget, TNodeId, TVal: Eq, TMap, TOut> {
     /// sorted according to the distance to the target.
     target: &'a Key<TTarget>,
     /// A reference to all buckets of the `KBucketsTable`.
-    table: &'a mut KBucketsTable<TNodeId, TVal>,
+    table: &'a KBucketsTable<TNodeId, TVal>,
     /// The iterator over the bucket indices in the order determined by the
     /// distance of the local key to the target.
     buckets_iter: ClosestBucketsIter,
@@ -838,7 +876,7 @@ impl<TTarget, TNodeId, TVal, TMap, TOut> Iterator
     for ClosestIter<'_, TTarget, TNodeId, TVal, TMap, TOut>
 where
     TNodeId: Clone,
-    TVal: Eq,
+    TVal: Eq + Clone,
     TMap: Fn(&KBucket<TNodeId, TVal>) -> ArrayVec<TOut, MAX_NODES_PER_BUCKET>,
     TOut: AsRef<Key<TNodeId>>,
 {
@@ -853,11 +891,7 @@ where
                 },
                 None => {
                     if let Some(i) = self.buckets_iter.next() {
-                        let bucket = &mut self.table.buckets[i.get()];
-                        if let Some(applied) = bucket.apply_pending() {
-                            self.table.applied_pending.push_back(applied)
-                        }
-                        let mut v = (self.fmap)(bucket);
+                        let mut v = (self.fmap)(&self.table.read_bucket(i.get()));
                         v.sort_by(|a, b| {
                             self.target
                                 .distance(a.as_ref())
@@ -897,7 +931,7 @@ mod tests {
         let local_key = Key::from(NodeId::random());
         let other_id = Key::from(NodeId::random());
 
-        let mut table = KBucketsTable::<_, ()>::new(
+        let table = KBucketsTable::<_, ()>::new(
             local_key,
             Duration::from_secs(5),
             MAX_NODES_PER_BUCKET,
@@ -921,7 +955,7 @@ mod tests {
     #[test]
     fn update_local_id_fails() {
         let local_key = Key::from(NodeId::random());
-        let mut table = KBucketsTable::<_, ()>::new(
+        let table = KBucketsTable::<_, ()>::new(
             local_key.clone(),
             Duration::from_secs(5),
             MAX_NODES_PER_BUCKET,
@@ -931,13 +965,13 @@ mod tests {
         match table.entry(&local_key) {
             Entry::SelfEntry => (),
             _ => panic!(),
-        }
+        };
     }
 
     #[test]
     fn closest() {
         let local_key = Key::from(NodeId::random());
-        let mut table = KBucketsTable::<_, ()>::new(
+        let table = KBucketsTable::<_, ()>::new(
             local_key,
             Duration::from_secs(5),
             MAX_NODES_PER_BUCKET,
@@ -957,13 +991,13 @@ mod tests {
                 }
             } else {
                 panic!("entry exists")
-            }
+            };
         }
 
         let mut expected_keys: Vec<_> = table
             .buckets
             .iter()
-            .flat_map(|t| t.iter().map(|n| n.key.clone()))
+            .flat_map(|t| t.read().iter().map(|n| n.key.clone()).collect::<Vec<_>>())
             .collect();
 
         for _ in 0..10 {
@@ -978,7 +1012,7 @@ mod tests {
     #[test]
     fn closest_local() {
         let local_key = Key::from(NodeId::random());
-        let mut table = KBucketsTable::<_, ()>::new(
+        let table = KBucketsTable::<_, ()>::new(
             local_key,
             Duration::from_secs(5),
             MAX_NODES_PER_BUCKET,
@@ -998,16 +1032,16 @@ mod tests {
                 }
             } else {
                 panic!("entry exists")
-            }
+            };
         }
-        let local_key = table.local_key.clone();
+        let local_key = table.local_key();
         assert_eq!(table.closest_keys(&local_key).count(), count);
     }
 
     #[test]
     fn applied_pending() {
         let local_key = Key::from(NodeId::random());
-        let mut table = KBucketsTable::<_, ()>::new(
+        let table = KBucketsTable::<_, ()>::new(
             local_key.clone(),
             Duration::from_millis(1),
             MAX_NODES_PER_BUCKET,
@@ -1031,6 +1065,7 @@ mod tests {
                                             value: (),
                                             status: disconnected_state(),
                                         }),
+                                        direction: ConnectionDirection::Outgoing,
                                     };
                                     full_bucket_index = BucketIndex::new(&key.distance(&local_key));
                                     break;
@@ -1045,13 +1080,14 @@ mod tests {
                 }
             } else {
                 panic!("entry exists")
-            }
+            };
         }
 
         // Expire the timeout for the pending entry on the full bucket.`
-        let full_bucket = &mut table.buckets[full_bucket_index.unwrap().get()];
+        let mut full_bucket = table.buckets[full_bucket_index.unwrap().get()].write();
         let elapsed = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
         full_bucket.pending_mut().unwrap().set_ready_at(elapsed);
+        drop(full_bucket);
 
         match table.entry(&expected_applied.inserted) {
             Entry::Present(
@@ -1072,4 +1108,92 @@ mod tests {
         assert_eq!(Some(expected_applied), table.take_applied_pending());
         assert_eq!(None, table.take_applied_pending());
     }
+
+    #[test]
+    fn readers_are_not_blocked_by_a_writer_of_another_bucket() {
+        let table = KBucketsTable::<_, ()>::new(
+            Key::from(NodeId::random()),
+            Duration::from_secs(5),
+            MAX_NODES_PER_BUCKET,
+            None,
+            None,
+        );
+        let (held, other) = loop {
+            let held = Key::from(NodeId::random());
+            let other = Key::from(NodeId::random());
+            if table.get_index(&held) != table.get_index(&other) {
+                break (held, other);
+            }
+        };
+        assert!(matches!(
+            table.insert_or_update(&other, (), connected_state()),
+            InsertResult::Inserted
+        ));
+        let other_distance = table.get_index(&other).unwrap() as u64 + 1;
+
+        let (locked_send, locked_recv) = std::sync::mpsc::channel();
+        let (release_send, release_recv) = std::sync::mpsc::channel::<()>();
+        let (read_send, read_recv) = std::sync::mpsc::channel();
+        let (table, held) = (&table, &held);
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                // The entry keeps the bucket of `held` locked for writing.
+                let _entry = table.entry(held);
+                locked_send.send(()).unwrap();
+                let _ = release_recv.recv_timeout(Duration::from_secs(5));
+            });
+            locked_recv.recv().unwrap();
+            scope.spawn(|| {
+                let nodes = table.nodes_by_distances(&[other_distance], MAX_NODES_PER_BUCKET);
+                let present = table
+                    .get_bucket(&other)
+                    .is_some_and(|bucket| bucket.get(&other).is_some());
+                read_send.send((nodes.len(), present)).unwrap();
+            });
+            let read = read_recv.recv_timeout(Duration::from_secs(1));
+            release_send.send(()).unwrap();
+            assert_eq!(read, Ok((1, true)));
+        });
+    }
+
+    #[test]
+    fn set_local_key_moves_entries() {
+        let local_key = Key::from(NodeId::random());
+        let table = KBucketsTable::<_, ()>::new(
+            local_key,
+            Duration::from_secs(5),
+            MAX_NODES_PER_BUCKET,
+            None,
+            None,
+        );
+        let keys: Vec<Key<NodeId>> = (0..20).map(|_| Key::from(NodeId::random())).collect();
+        for key in &keys {
+            if let Entry::Absent(entry) = table.entry(key) {
+                let _ = entry.insert((), connected_state());
+            }
+        }
+        let before: Vec<Key<NodeId>> = table.iter().map(|entry| entry.node.key.clone()).collect();
+        let insertions = |table: &KBucketsTable<NodeId, ()>| {
+            table
+                .churn()
+                .iter()
+                .map(|(_, churn)| churn.insertions)
+                .sum::<u64>()
+        };
+        assert_eq!(insertions(&table), 20);
+
+        // The new local key is one of the entries, which can't be its own neighbour.
+        let new_local_key = before[0].clone();
+        let dropped = table.set_local_key(new_local_key.clone());
+        assert_eq!(dropped, vec![new_local_key.clone()]);
+        let after: Vec<Key<NodeId>> = table.iter().map(|entry| entry.node.key.clone()).collect();
+        assert_eq!(after.len(), before.len() - 1);
+        // Moving the entries is not churn.
+        assert_eq!(insertions(&table), 20);
+        for key in after {
+            assert!(table
+                .get_bucket(&key)
+                .is_some_and(|bucket| bucket.get(&key).is_some()));
+        }
+    }
 }
@@ -31,6 +31,8 @@
 #![allow(dead_code)]
 
 use super::*;
+use crate::metrics::METRICS;
+use std::sync::atomic::Ordering;
 use tracing::{debug, error};
 
 /// Maximum number of nodes in a bucket, i.e. the (fixed) `k` parameter.
@@ -52,6 +54,7 @@ pub struct PendingNode<TNodeId, TVal: Eq> {
 /// last status change determines the position of the node in a
 /// bucket.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeStatus {
     /// The direction (incoming or outgoing) for the node. If in the disconnected state, this
     /// represents the last connection status.
@@ -62,6 +65,7 @@ pub struct NodeStatus {
 
 /// The connection state of a node.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConnectionState {
     /// The node is connected.
     Connected,
@@ -97,12 +101,24 @@ impl<TNodeId, TVal: Eq> PendingNode<TNodeId, TVal> {
     pub fn set_ready_at(&mut self, t: Instant) {
         self.replace = t;
     }
+
+    /// Whether the node is due to be applied to its bucket.
+    pub fn is_ready(&self) -> bool {
+        self.replace <= time::now()
+    }
 }
 
 /// A `Node` in a bucket, representing a peer participating
 /// in the Kademlia DHT together with an associated value (e.g. contact
 /// information).
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        deserialize = "Key<TNodeId>: serde::Deserialize<'de>, TVal: serde::Deserialize<'de>"
+    ))
+)]
 pub struct Node<TNodeId, TVal: Eq> {
     /// The key of the node, identifying the peer.
     pub key: Key<TNodeId>,
@@ -121,8 +137,9 @@ pub struct Position(usize);
 /// ordered from least-recently connected to most-recently connected.
 #[derive(Clone)]
 pub struct KBucket<TNodeId, TVal: Eq> {
-    /// The nodes contained in the bucket.
-    nodes: ArrayVec<Node<TNodeId, TVal>, MAX_NODES_PER_BUCKET>,
+    /// The nodes contained in the bucket. The storage is only allocated once a node is inserted,
+    /// as most of the buckets of a routing table remain empty.
+    nodes: Vec<Node<TNodeId, TVal>>,
 
     /// The position (index) in `nodes` that marks the first connected node.
     ///
@@ -155,6 +172,66 @@ pub struct KBucket<TNodeId, TVal: Eq> {
     /// The maximum number of incoming connections allowed per bucket. Setting this to
     /// MAX_NODES_PER_BUCKET means there is no restriction on incoming nodes.
     max_incoming: usize,
+
+    /// The churn of the nodes of the bucket.
+    churn: BucketChurn,
+
+    /// The time each node of the bucket was inserted.
+    inserted_at: Vec<(Key<TNodeId>, Instant)>,
+
+    /// How the bucket treats new nodes while it is full.
+    eviction_policy: EvictionPolicy,
+
+    /// Nodes that found the bucket full under [`EvictionPolicy::PreferLongLived`], from the
+    /// least to the most recently seen.
+    replacements: Vec<Node<TNodeId, TVal>>,
+}
+
+/// How a full bucket treats new nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// A new node becomes pending if the least-recently connected node is disconnected, replacing
+    /// it unless it reconnects in time. Nodes arriving while the bucket is full of connected nodes
+    /// or already has a pending node are dropped.
+    #[default]
+    LeastRecentlyConnected,
+    /// Nodes that have proven to stay online are kept. New nodes that find the bucket full go to
+    /// a replacement cache of up to [`MAX_NODES_PER_BUCKET`] nodes instead of being dropped, and
+    /// only replace a member once it is disconnected or removed. A pending node is returned to
+    /// the cache if the member it would replace has reconnected.
+    PreferLongLived,
+}
+
+/// The nodes that have entered and left a bucket since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BucketChurn {
+    /// The number of nodes inserted.
+    pub insertions: u64,
+    /// The number of nodes evicted to make room for a pending node.
+    pub evictions: u64,
+    /// The number of nodes removed otherwise, as when their updated ENR fails a filter or the
+    /// application removes them.
+    pub removals: u64,
+    /// The summed time the evicted and removed nodes spent in the bucket.
+    pub total_lifetime: Duration,
+}
+
+impl BucketChurn {
+    /// The average time the evicted and removed nodes spent in the bucket, if any have left.
+    pub fn average_lifetime(&self) -> Option<Duration> {
+        let departures = self.evictions + self.removals;
+        (departures > 0)
+            .then(|| Duration::from_secs_f64(self.total_lifetime.as_secs_f64() / departures as f64))
+    }
+}
+
+impl std::ops::AddAssign for BucketChurn {
+    fn add_assign(&mut self, other: Self) {
+        self.insertions += other.insertions;
+        self.evictions += other.evictions;
+        self.removals += other.removals;
+        self.total_lifetime += other.total_lifetime;
+    }
 }
 
 /// The result of inserting an entry into a bucket.
@@ -233,6 +310,8 @@ pub struct AppliedPending<TNodeId, TVal: Eq> {
     /// The node that has been evicted from the bucket to make room for the
     /// pending node, if any.
     pub evicted: Option<Node<TNodeId, TVal>>,
+    /// Whether the inserted node connected to us or we to it.
+    pub direction: ConnectionDirection,
 }
 
 impl<TNodeId, TVal> KBucket<TNodeId, TVal>
@@ -247,13 +326,108 @@ where
         filter: Option<Box<dyn Filter<TVal>>>,
     ) -> Self {
         KBucket {
-            nodes: ArrayVec::new(),
+            nodes: Vec::new(),
             first_connected_pos: None,
             pending: None,
             pending_timeout,
             filter,
             max_incoming,
+            churn: BucketChurn::default(),
+            inserted_at: Vec::new(),
+            eviction_policy: EvictionPolicy::default(),
+            replacements: Vec::new(),
+        }
+    }
+
+    /// Sets how the bucket treats new nodes while it is full.
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.eviction_policy = policy;
+        if policy == EvictionPolicy::LeastRecentlyConnected {
+            self.replacements.clear();
+        }
+    }
+
+    /// The nodes waiting in the replacement cache, from the least to the most recently seen.
+    pub fn replacements(&self) -> &[Node<TNodeId, TVal>] {
+        &self.replacements
+    }
+
+    /// Adds a node that found the bucket full to the replacement cache, if the eviction policy
+    /// keeps one. The least recently seen node is dropped from a full cache.
+    fn add_replacement(&mut self, node: Node<TNodeId, TVal>) {
+        if self.eviction_policy != EvictionPolicy::PreferLongLived
+            || self.position(&node.key).is_some()
+        {
+            return;
+        }
+        self.replacements
+            .retain(|replacement| replacement.key != node.key);
+        if self.replacements.len() >= MAX_NODES_PER_BUCKET {
+            self.replacements.remove(0);
         }
+        self.replacements.push(node);
+    }
+
+    /// Makes the most recently seen replacement the pending node, if there is none. It is
+    /// inserted right away if the bucket has room, and otherwise replaces the least-recently
+    /// connected node if that is disconnected and stays so for the pending timeout.
+    fn stage_replacement(&mut self) {
+        if self.pending.is_some() {
+            return;
+        }
+        let replace = if !self.is_full() {
+            time::now()
+        } else if self.first_connected_pos != Some(0) {
+            time::now() + self.pending_timeout
+        } else {
+            return;
+        };
+        if let Some(node) = self.replacements.pop() {
+            self.pending = Some(PendingNode { node, replace });
+        }
+    }
+
+    /// The nodes that have entered and left the bucket since it was created.
+    pub fn churn(&self) -> BucketChurn {
+        self.churn
+    }
+
+    /// Records that a node entered the bucket. Nodes re-inserted by a status update are not
+    /// counted again.
+    fn record_insertion(&mut self, key: &Key<TNodeId>) {
+        if self.inserted_at.iter().any(|(inserted, _)| inserted == key) {
+            return;
+        }
+        self.inserted_at.push((key.clone(), time::now()));
+        self.churn.insertions += 1;
+        METRICS.table_insertions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a node left the bucket, evicted for a pending node or removed otherwise.
+    fn record_departure(&mut self, key: &Key<TNodeId>, evicted: bool) {
+        let Some(pos) = self
+            .inserted_at
+            .iter()
+            .position(|(inserted, _)| inserted == key)
+        else {
+            return;
+        };
+        let (_, inserted_at) = self.inserted_at.swap_remove(pos);
+        self.churn.total_lifetime += time::now().saturating_duration_since(inserted_at);
+        if evicted {
+            self.churn.evictions += 1;
+            METRICS.table_evictions.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.churn.removals += 1;
+            METRICS.table_removals.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Evicts the least-recently connected node to make room for the pending node.
+    fn evict_first(&mut self) -> Node<TNodeId, TVal> {
+        let evicted = self.nodes.remove(0);
+        self.record_departure(&evicted.key, true);
+        evicted
     }
 
     /// Returns a reference to the pending node of the bucket, if there is any.
@@ -285,13 +459,15 @@ where
     /// bucket remained unchanged.
     pub fn apply_pending(&mut self) -> Option<AppliedPending<TNodeId, TVal>> {
         if let Some(pending) = self.pending.take() {
-            if pending.replace <= Instant::now() {
+            if pending.is_ready() {
                 // Check if the bucket is full
-                if self.nodes.is_full() {
+                if self.is_full() {
                     // Apply bucket filters
 
                     if self.nodes[0].status.is_connected() {
-                        // The bucket is full with connected nodes. Drop the pending node.
+                        // The bucket is full with connected nodes. Drop the pending node, or keep
+                        // it as a replacement.
+                        self.add_replacement(pending.node);
                         return None;
                     }
                     // Check the custom filter
@@ -317,39 +493,57 @@ where
 
                     // The pending node will be inserted.
                     let inserted = pending.node.key.clone();
+                    let direction = pending.status().direction;
                     // A connected pending node goes at the end of the list for
                     // the connected peers, removing the least-recently connected.
                     if pending.status().is_connected() {
-                        let evicted = Some(self.nodes.remove(0));
+                        let evicted = Some(self.evict_first());
                         self.first_connected_pos = self
                             .first_connected_pos
                             .map_or_else(|| Some(self.nodes.len()), |p| p.checked_sub(1));
+                        self.record_insertion(&inserted);
                         self.nodes.push(pending.node);
-                        return Some(AppliedPending { inserted, evicted });
+                        return Some(AppliedPending {
+                            inserted,
+                            evicted,
+                            direction,
+                        });
                     }
                     // A disconnected pending node goes at the end of the list
                     // for the disconnected peers.
                     else if let Some(p) = self.first_connected_pos {
                         if let Some(insert_pos) = p.checked_sub(1) {
-                            let evicted = Some(self.nodes.remove(0));
+                            let evicted = Some(self.evict_first());
+                            self.record_insertion(&inserted);
                             self.nodes.insert(insert_pos, pending.node);
-                            return Some(AppliedPending { inserted, evicted });
+                            return Some(AppliedPending {
+                                inserted,
+                                evicted,
+                                direction,
+                            });
                         }
                     } else {
                         // All nodes are disconnected. Insert the new node as the most
                         // recently disconnected, removing the least-recently disconnected.
-                        let evicted = Some(self.nodes.remove(0));
+                        let evicted = Some(self.evict_first());
+                        self.record_insertion(&inserted);
                         self.nodes.push(pending.node);
-                        return Some(AppliedPending { inserted, evicted });
+                        return Some(AppliedPending {
+                            inserted,
+                            evicted,
+                            direction,
+                        });
                     }
                 } else {
                     // There is room in the bucket, so just insert the pending node.
                     let inserted = pending.node.key.clone();
+                    let direction = pending.status().direction;
                     match self.insert(pending.node) {
                         InsertResult::Inserted => {
                             return Some(AppliedPending {
                                 inserted,
                                 evicted: None,
+                                direction,
                             })
                         }
                         InsertResult::Full => unreachable!("Bucket cannot be full"),
@@ -367,6 +561,7 @@ where
             }
         }
 
+        self.stage_replacement();
         None
     }
 
@@ -439,12 +634,14 @@ where
                     }
                 }
                 InsertResult::TooManyIncoming => {
+                    self.record_departure(key, false);
                     UpdateResult::Failed(FailureReason::TooManyIncoming)
                 }
                 // Node could not be inserted. None of these should be possible.
                 InsertResult::FailedFilter => {
                     // If the filter is non-deterministic, potentially a re-insertion of the same
                     // node can fail the filter.
+                    self.record_departure(key, false);
                     UpdateResult::Failed(FailureReason::BucketFilter)
                 }
                 InsertResult::NodeExists => {
@@ -491,6 +688,7 @@ where
                     if !filter.filter(&value, &mut self.iter().map(|node| &node.value)) {
                         // Node is removed, update the `first_connected_pos` accordingly.
                         self.update_first_connected_pos_for_removal(pos);
+                        self.record_departure(key, false);
 
                         return UpdateResult::Failed(FailureReason::BucketFilter);
                     }
@@ -550,6 +748,7 @@ where
             .as_ref()
             .map(|pending| pending.node.key == node.key)
             .unwrap_or_default();
+        let key = node.key.clone();
 
         let insert_result = match node.status.state {
             ConnectionState::Connected => {
@@ -559,13 +758,14 @@ where
                         return InsertResult::TooManyIncoming;
                     }
                 }
-                if self.nodes.is_full() {
+                if self.is_full() {
                     if self.first_connected_pos == Some(0) || self.pending.is_some() {
+                        self.add_replacement(node);
                         return InsertResult::Full;
                     } else {
                         self.pending = Some(PendingNode {
                             node,
-                            replace: Instant::now() + self.pending_timeout,
+                            replace: time::now() + self.pending_timeout,
                         });
                         return InsertResult::Pending {
                             disconnected: self.nodes[0].key.clone(),
@@ -579,7 +779,8 @@ where
                 InsertResult::Inserted
             }
             ConnectionState::Disconnected => {
-                if self.nodes.is_full() {
+                if self.is_full() {
+                    self.add_replacement(node);
                     return InsertResult::Full;
                 }
 
@@ -596,17 +797,61 @@ where
         // If we inserted the node, make sure there is no pending node of the same key. This can
         // happen when a pending node is inserted, a node gets removed from the bucket, freeing up
         // space and then re-inserted here.
-        if matches!(insert_result, InsertResult::Inserted) && inserting_pending {
-            self.pending = None
+        if matches!(insert_result, InsertResult::Inserted) {
+            self.record_insertion(&key);
+            self.replacements
+                .retain(|replacement| replacement.key != key);
+            if inserting_pending {
+                self.pending = None
+            }
         }
         insert_result
     }
 
+    /// Takes all nodes out of the bucket with the time they were inserted, in order from the least
+    /// to the most recently connected. The pending node and the replacements are dropped.
+    pub fn take_nodes(&mut self) -> Vec<(Node<TNodeId, TVal>, Instant)> {
+        self.first_connected_pos = None;
+        self.pending = None;
+        self.replacements.clear();
+        let inserted_at = std::mem::take(&mut self.inserted_at);
+        std::mem::take(&mut self.nodes)
+            .into_iter()
+            .map(|node| {
+                let inserted = inserted_at
+                    .iter()
+                    .find(|(key, _)| key == &node.key)
+                    .map_or_else(time::now, |(_, inserted)| *inserted);
+                (node, inserted)
+            })
+            .collect()
+    }
+
+    /// Inserts a node taken out of another bucket by [`Self::take_nodes`], keeping the time it was
+    /// inserted so that the move is not counted as churn. A node that doesn't make it into the
+    /// bucket is counted as removed.
+    pub fn restore(
+        &mut self,
+        node: Node<TNodeId, TVal>,
+        inserted_at: Instant,
+    ) -> InsertResult<TNodeId> {
+        let key = node.key.clone();
+        self.inserted_at.push((key.clone(), inserted_at));
+        let result = self.insert(node);
+        if !matches!(result, InsertResult::Inserted) {
+            self.record_departure(&key, false);
+        }
+        result
+    }
+
     /// Removes a node from the bucket.
     pub fn remove(&mut self, key: &Key<TNodeId>) -> bool {
+        self.replacements
+            .retain(|replacement| &replacement.key != key);
         if let Some(Position(position)) = self.position(key) {
             self.nodes.remove(position);
             self.update_first_connected_pos_for_removal(position);
+            self.record_departure(key, false);
             self.apply_pending();
             true
         } else {
@@ -619,6 +864,11 @@ where
         self.nodes.len()
     }
 
+    /// Whether the bucket holds `MAX_NODES_PER_BUCKET` entries.
+    fn is_full(&self) -> bool {
+        self.nodes.len() >= MAX_NODES_PER_BUCKET
+    }
+
     /// Gets the number of entries in the bucket that are considered connected.
     pub fn num_connected(&self) -> usize {
         self.first_connected_pos.map_or(0, |i| self.nodes.len() - i)
@@ -1027,6 +1277,29 @@ pub mod tests {
         quickcheck(prop as fn(_) -> _);
     }
 
+    #[test]
+    fn applied_pending_reports_direction() {
+        let mut bucket =
+            KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+        fill_bucket(&mut bucket, disconnected_state());
+
+        let key = Key::from(NodeId::random());
+        let node = Node {
+            key: key.clone(),
+            value: (),
+            status: NodeStatus {
+                state: ConnectionState::Connected,
+                direction: ConnectionDirection::Incoming,
+            },
+        };
+        assert!(matches!(bucket.insert(node), InsertResult::Pending { .. }));
+        let pending = bucket.pending_mut().expect("No pending node.");
+        pending.set_ready_at(Instant::now().checked_sub(Duration::from_secs(1)).unwrap());
+        let applied = bucket.apply_pending().expect("The pending node is applied");
+        assert_eq!(applied.inserted, key);
+        assert_eq!(applied.direction, ConnectionDirection::Incoming);
+    }
+
     #[test]
     fn full_bucket() {
         let mut bucket =
@@ -1088,7 +1361,8 @@ pub mod tests {
                 result,
                 Some(AppliedPending {
                     inserted: key.clone(),
-                    evicted: Some(first_disconnected)
+                    evicted: Some(first_disconnected),
+                    direction: ConnectionDirection::Outgoing,
                 })
             );
             assert_eq!(
@@ -1160,6 +1434,60 @@ pub mod tests {
         assert_eq!(MAX_NODES_PER_BUCKET - 1, bucket.num_disconnected());
     }
 
+    #[test]
+    fn bucket_churn() {
+        let mut bucket =
+            KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+        let keys: Vec<_> = (0..2).map(|_| Key::from(NodeId::random())).collect();
+        for key in &keys {
+            let node = Node {
+                key: key.clone(),
+                value: (),
+                status: disconnected_state(),
+            };
+            assert_eq!(InsertResult::Inserted, bucket.insert(node));
+        }
+
+        // Moving a node within the bucket is not churn.
+        let _ = bucket.update_status(&keys[0], ConnectionState::Connected, None);
+        assert!(bucket.remove(&keys[1]));
+
+        let churn = bucket.churn();
+        assert_eq!(churn.insertions, 2);
+        assert_eq!(churn.evictions, 0);
+        assert_eq!(churn.removals, 1);
+        assert!(churn.average_lifetime().is_some());
+    }
+
+    #[test]
+    fn full_bucket_keeps_replacements() {
+        let mut bucket =
+            KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+        bucket.set_eviction_policy(EvictionPolicy::PreferLongLived);
+        fill_bucket(&mut bucket, connected_state());
+        let first = bucket.iter().next().unwrap().key.clone();
+
+        // The members are connected, so the new node waits as a replacement.
+        let key = Key::from(NodeId::random());
+        let node = Node {
+            key: key.clone(),
+            value: (),
+            status: connected_state(),
+        };
+        assert_eq!(InsertResult::Full, bucket.insert(node));
+        assert_eq!(bucket.replacements().len(), 1);
+        assert_eq!(bucket.apply_pending(), None);
+        assert!(bucket.pending().is_none());
+
+        // Once a member leaves, the replacement takes its place.
+        assert!(bucket.remove(&first));
+        let applied = bucket.apply_pending().expect("The replacement is inserted");
+        assert_eq!(applied.inserted, key);
+        assert_eq!(applied.evicted, None);
+        assert!(bucket.replacements().is_empty());
+        assert!(bucket.position(&key).is_some());
+    }
+
     /// No duplicate nodes can be inserted via the apply_pending function.
     #[test]
     fn full_bucket_applied_no_duplicates() {
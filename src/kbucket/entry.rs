@@ -31,24 +31,18 @@ pub use super::{
 };
 
 use super::*;
-
-/// An immutable by-reference view of a bucket entry.
-pub struct EntryRefView<'a, TPeerId, TVal: Eq> {
-    /// The node represented by the entry.
-    pub node: NodeRefView<'a, TPeerId, TVal>,
-    /// The status of the node identified by the key.
-    pub status: NodeStatus,
-}
-
-/// An immutable by-reference view of a `Node`.
-pub struct NodeRefView<'a, TPeerId, TVal: Eq> {
-    pub key: &'a Key<TPeerId>,
-    pub value: &'a TVal,
-}
+use parking_lot::RwLockWriteGuard;
 
 /// A cloned, immutable view of an entry that is either present in a bucket
 /// or pending insertion.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        deserialize = "Key<TPeerId>: serde::Deserialize<'de>, TVal: serde::Deserialize<'de>"
+    ))
+)]
 pub struct EntryView<TPeerId, TVal: Eq> {
     /// The node represented by the entry.
     pub node: Node<TPeerId, TVal>,
@@ -77,10 +71,10 @@ pub enum Entry<'a, TPeerId, TVal: Eq> {
 }
 
 /// The internal representation of the different states of an `Entry`,
-/// referencing the associated key and bucket.
+/// referencing the associated key and holding the lock on its bucket.
 #[derive(Debug)]
 struct EntryRef<'a, TPeerId, TVal: Eq> {
-    bucket: &'a mut KBucket<TPeerId, TVal>,
+    bucket: RwLockWriteGuard<'a, KBucket<TPeerId, TVal>>,
     key: &'a Key<TPeerId>,
 }
 
@@ -90,7 +84,10 @@ where
     TVal: Eq,
 {
     /// Creates a new `Entry` for a `Key`, encapsulating access to a bucket.
-    pub(super) fn new(bucket: &'a mut KBucket<TPeerId, TVal>, key: &'a Key<TPeerId>) -> Self {
+    pub(super) fn new(
+        bucket: RwLockWriteGuard<'a, KBucket<TPeerId, TVal>>,
+        key: &'a Key<TPeerId>,
+    ) -> Self {
         if let Some(pos) = bucket.position(key) {
             let status = bucket.status(pos);
             Entry::Present(PresentEntry::new(bucket, key), status)
@@ -112,7 +109,7 @@ where
     TPeerId: Clone,
     TVal: Eq,
 {
-    fn new(bucket: &'a mut KBucket<TPeerId, TVal>, key: &'a Key<TPeerId>) -> Self {
+    fn new(bucket: RwLockWriteGuard<'a, KBucket<TPeerId, TVal>>, key: &'a Key<TPeerId>) -> Self {
         PresentEntry(EntryRef { bucket, key })
     }
 
@@ -139,7 +136,7 @@ where
     /// Sets the status of the entry.
     /// This can fail if the new state violates buckets or table conditions.
     pub fn update(
-        self,
+        mut self,
         state: ConnectionState,
         direction: Option<ConnectionDirection>,
     ) -> Result<Self, FailureReason> {
@@ -156,7 +153,7 @@ where
     }
 
     /// Removes the entry from the table.
-    pub fn remove(self) {
+    pub fn remove(mut self) {
         self.0.bucket.remove(self.0.key);
     }
 }
@@ -170,7 +167,7 @@ where
     TPeerId: Clone,
     TVal: Eq,
 {
-    fn new(bucket: &'a mut KBucket<TPeerId, TVal>, key: &'a Key<TPeerId>) -> Self {
+    fn new(bucket: RwLockWriteGuard<'a, KBucket<TPeerId, TVal>>, key: &'a Key<TPeerId>) -> Self {
         PendingEntry(EntryRef { bucket, key })
     }
 
@@ -184,13 +181,13 @@ where
     }
 
     /// Updates the status of the pending entry.
-    pub fn update(self, status: NodeStatus) -> PendingEntry<'a, TPeerId, TVal> {
+    pub fn update(mut self, status: NodeStatus) -> PendingEntry<'a, TPeerId, TVal> {
         self.0.bucket.update_pending(status);
         PendingEntry::new(self.0.bucket, self.0.key)
     }
 
     /// Removes the entry from the table.
-    pub fn remove(self) {
+    pub fn remove(mut self) {
         self.0.bucket.remove(self.0.key);
     }
 }
@@ -204,12 +201,12 @@ where
     TPeerId: Clone,
     TVal: Eq,
 {
-    fn new(bucket: &'a mut KBucket<TPeerId, TVal>, key: &'a Key<TPeerId>) -> Self {
+    fn new(bucket: RwLockWriteGuard<'a, KBucket<TPeerId, TVal>>, key: &'a Key<TPeerId>) -> Self {
         AbsentEntry(EntryRef { bucket, key })
     }
 
     /// Attempts to insert the entry into a bucket.
-    pub fn insert(self, value: TVal, status: NodeStatus) -> InsertResult<TPeerId> {
+    pub fn insert(mut self, value: TVal, status: NodeStatus) -> InsertResult<TPeerId> {
         self.0.bucket.insert(Node {
             key: self.0.key.clone(),
             value,
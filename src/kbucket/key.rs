@@ -109,6 +109,25 @@ impl From<NodeId> for Key<NodeId> {
     }
 }
 
+/// Keys are serialized as their preimage, from which the hash is derived again.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for Key<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.preimage.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T> serde::Deserialize<'de> for Key<T>
+where
+    T: serde::Deserialize<'de>,
+    Key<T>: From<T>,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Key::from)
+    }
+}
+
 /// A distance between two `Key`s.
 #[derive(Copy, Clone, PartialEq, Eq, Default, PartialOrd, Ord, Debug)]
 pub struct Distance(pub(super) U256);
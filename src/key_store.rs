@@ -0,0 +1,250 @@
+//! Passphrase-encrypted storage of the node key.
+//!
+//! The node key is the identity of the node, and whoever reads it can impersonate the node.
+//! Rather than keeping it as a plain hex file, [`EncryptedKeyFile`] stores the key encrypted with
+//! AES-256-GCM under a key derived from a passphrase with PBKDF2-HMAC-SHA256. The file header,
+//! including the number of PBKDF2 iterations, is authenticated, so a tampered file fails to load
+//! rather than weakening the derivation. The iterations are capped at [`MAX_ITERATIONS`], so a
+//! crafted file cannot make loading it take arbitrarily long either.
+//!
+//! Both secp256k1 and ed25519 keys can be stored. [`encrypt_key`] and [`decrypt_key`] expose the
+//! format directly for applications keeping the key somewhere other than a file, and
+//! [`crate::Discv5::from_key_file`] creates a server with a stored key.
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use enr::{k256::sha2::Sha256, CombinedKey};
+use std::{
+    convert::TryInto,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroize;
+
+/// The number of PBKDF2 iterations of newly stored keys.
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+
+/// The maximum number of PBKDF2 iterations. Keys stored with more iterations are rejected.
+pub const MAX_ITERATIONS: u32 = 10 * DEFAULT_ITERATIONS;
+
+/// Identifies an encrypted key, followed by the version of the format.
+const MAGIC: &[u8; 4] = b"DV5K";
+const VERSION: u8 = 1;
+
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+/// The magic, version, key type, iterations, salt and nonce, which are authenticated but not
+/// encrypted.
+const HEADER_LENGTH: usize = MAGIC.len() + 1 + 1 + 4 + SALT_LENGTH + NONCE_LENGTH;
+
+const KEY_TYPE_SECP256K1: u8 = 0;
+const KEY_TYPE_ED25519: u8 = 1;
+
+/// Encrypts the key with the passphrase, deriving the encryption key with `iterations` rounds of
+/// PBKDF2, clamped to between 1 and [`MAX_ITERATIONS`].
+pub fn encrypt_key(key: &CombinedKey, passphrase: &[u8], iterations: u32) -> Vec<u8> {
+    let iterations = iterations.clamp(1, MAX_ITERATIONS);
+    let key_type = match key {
+        CombinedKey::Secp256k1(_) => KEY_TYPE_SECP256K1,
+        CombinedKey::Ed25519(_) => KEY_TYPE_ED25519,
+    };
+    let salt: [u8; SALT_LENGTH] = rand::random();
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+
+    let mut encrypted = Vec::with_capacity(HEADER_LENGTH + 64);
+    encrypted.extend_from_slice(MAGIC);
+    encrypted.push(VERSION);
+    encrypted.push(key_type);
+    encrypted.extend_from_slice(&iterations.to_be_bytes());
+    encrypted.extend_from_slice(&salt);
+    encrypted.extend_from_slice(&nonce);
+
+    let mut derived_key = derive_key(passphrase, &salt, iterations);
+    let mut secret = key.encode();
+    let ciphertext = Aes256Gcm::new(GenericArray::from_slice(&derived_key))
+        .encrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: &secret,
+                aad: &encrypted,
+            },
+        )
+        .expect("The plaintext is within the size limit of AES-GCM");
+    derived_key.zeroize();
+    secret.zeroize();
+
+    encrypted.extend_from_slice(&ciphertext);
+    encrypted
+}
+
+/// Decrypts a key encrypted by [`encrypt_key`]. Fails with [`io::ErrorKind::InvalidData`] if the
+/// passphrase is wrong or the data is corrupted.
+pub fn decrypt_key(encrypted: &[u8], passphrase: &[u8]) -> io::Result<CombinedKey> {
+    if encrypted.len() < HEADER_LENGTH || &encrypted[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data("Not an encrypted node key"));
+    }
+    let (header, ciphertext) = encrypted.split_at(HEADER_LENGTH);
+    let version = header[4];
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "Unsupported encrypted key version {version}"
+        )));
+    }
+    let key_type = header[5];
+    let iterations = u32::from_be_bytes(header[6..10].try_into().expect("Four bytes"));
+    if iterations == 0 || iterations > MAX_ITERATIONS {
+        return Err(invalid_data(format!(
+            "Unsupported number of key derivation iterations {iterations}"
+        )));
+    }
+    let salt = &header[10..10 + SALT_LENGTH];
+    let nonce = &header[10 + SALT_LENGTH..];
+
+    let mut derived_key = derive_key(passphrase, salt, iterations);
+    let decrypted = Aes256Gcm::new(GenericArray::from_slice(&derived_key)).decrypt(
+        GenericArray::from_slice(nonce),
+        Payload {
+            msg: ciphertext,
+            aad: header,
+        },
+    );
+    derived_key.zeroize();
+    let mut secret =
+        decrypted.map_err(|_| invalid_data("Wrong passphrase or corrupted node key"))?;
+
+    // The constructors zeroize the secret on success.
+    let key = match key_type {
+        KEY_TYPE_SECP256K1 => CombinedKey::secp256k1_from_bytes(&mut secret),
+        KEY_TYPE_ED25519 => CombinedKey::ed25519_from_bytes(&mut secret),
+        _ => {
+            secret.zeroize();
+            return Err(invalid_data(format!("Unknown key type {key_type}")));
+        }
+    };
+    secret.zeroize();
+    key.map_err(invalid_data)
+}
+
+/// Whether the data looks like a key encrypted by [`encrypt_key`], as opposed to a plain key.
+pub fn is_encrypted_key(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// A node key stored encrypted in a file.
+///
+/// The file is replaced atomically on each store and, on unix, is only readable by its owner.
+#[derive(Debug, Clone)]
+pub struct EncryptedKeyFile {
+    path: PathBuf,
+    iterations: u32,
+}
+
+impl EncryptedKeyFile {
+    /// A key file at the given path, storing keys with [`DEFAULT_ITERATIONS`].
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        EncryptedKeyFile {
+            path: path.into(),
+            iterations: DEFAULT_ITERATIONS,
+        }
+    }
+
+    /// Sets the number of PBKDF2 iterations of stored keys, at most [`MAX_ITERATIONS`]. Loading
+    /// reads the number of iterations from the file.
+    pub fn iterations(mut self, iterations: u32) -> Self {
+        self.iterations = iterations.clamp(1, MAX_ITERATIONS);
+        self
+    }
+
+    /// The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads and decrypts the key.
+    pub fn load(&self, passphrase: &[u8]) -> io::Result<CombinedKey> {
+        decrypt_key(&fs::read(&self.path)?, passphrase)
+    }
+
+    /// Encrypts and stores the key, replacing any previously stored key.
+    pub fn store(&self, key: &CombinedKey, passphrase: &[u8]) -> io::Result<()> {
+        let encrypted = encrypt_key(key, passphrase, self.iterations);
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temp_path)?;
+        file.write_all(&encrypted)?;
+        file.sync_all()?;
+        fs::rename(temp_path, &self.path)
+    }
+
+    /// Loads the key, or generates and stores a new secp256k1 key if the file does not exist.
+    pub fn load_or_generate(&self, passphrase: &[u8]) -> io::Result<CombinedKey> {
+        match self.load(passphrase) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = CombinedKey::generate_secp256k1();
+                self.store(&key, passphrase)?;
+                Ok(key)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Derives a 256 bit key with PBKDF2-HMAC-SHA256.
+fn derive_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut derived = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, iterations, &mut derived);
+    derived
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypted_key_file_round_trip() {
+        // RFC 7914, section 11.
+        assert_eq!(
+            hex::encode(derive_key(b"passwd", b"salt", 1)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+
+        let path = std::env::temp_dir().join(format!("discv5-key-{}", rand::random::<u64>()));
+        let file = EncryptedKeyFile::new(&path).iterations(10);
+        let key = file.load_or_generate(b"passphrase").unwrap();
+        assert!(is_encrypted_key(&fs::read(&path).unwrap()));
+        assert_eq!(file.load(b"passphrase").unwrap().encode(), key.encode());
+        assert!(matches!(file.load(b"wrong"), Err(e) if e.kind() == io::ErrorKind::InvalidData));
+
+        let ed25519 = CombinedKey::generate_ed25519();
+        file.store(&ed25519, b"other").unwrap();
+        assert_eq!(
+            file.load_or_generate(b"other").unwrap().encode(),
+            ed25519.encode()
+        );
+
+        // Lowering the iterations of a stored key is detected.
+        let mut tampered = fs::read(&path).unwrap();
+        tampered[9] = 1;
+        assert!(decrypt_key(&tampered, b"other").is_err());
+
+        // Files demanding more than the maximum iterations are rejected before deriving a key.
+        tampered[6..10].copy_from_slice(&(MAX_ITERATIONS + 1).to_be_bytes());
+        assert!(
+            matches!(decrypt_key(&tampered, b"other"), Err(e) if e.to_string().contains("iterations"))
+        );
+        let capped = EncryptedKeyFile::new(&path).iterations(u32::MAX);
+        assert_eq!(capped.iterations, MAX_ITERATIONS);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -94,38 +94,88 @@
 //!    });
 //! ```
 
+#[cfg(feature = "admin_rpc")]
+pub mod admin_rpc;
+pub mod audit;
+mod bootnodes;
 mod config;
+pub mod crawler;
 mod discv5;
+#[cfg(feature = "dns")]
+pub mod dns;
+mod enr_allowlist;
+pub mod enr_ext;
+mod enr_store;
+mod enr_watch;
 mod error;
 mod executor;
+mod external_address;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod handler;
 mod ipmode;
 pub mod kbucket;
+pub mod key_store;
+#[cfg(feature = "libp2p")]
+pub mod libp2p;
 mod lru_time_cache;
 pub mod metrics;
+mod network_size;
 mod node_info;
 pub mod packet;
+pub mod peer_store;
 pub mod permit_ban;
 mod query_pool;
+pub mod rendezvous;
 pub mod rpc;
 pub mod service;
+#[cfg(feature = "simulation")]
+pub mod simulation;
 pub mod socket;
+mod talk;
+#[cfg(feature = "test_utils")]
+pub mod test_utils;
+#[cfg(feature = "test_vectors")]
+pub mod test_vectors;
+pub mod time;
 
 #[macro_use]
 extern crate lazy_static;
 
 pub type Enr = enr::Enr<enr::CombinedKey>;
 
-pub use crate::discv5::{Discv5, Event};
+pub use crate::discv5::{Discv5, Event, PeerRef};
+pub use bootnodes::BootnodeHealth;
 pub use config::{Config, ConfigBuilder};
-pub use error::{Error, QueryError, RequestError, ResponseError};
+pub use crawler::{CrawlConfig, CrawlSnapshot};
+pub use enr_allowlist::EnrAllowlist;
+pub use enr_ext::EnrExt;
+pub use enr_watch::EnrKeyChange;
+#[cfg(feature = "dns")]
+pub use error::DnsError;
+#[cfg(feature = "test_vectors")]
+pub use error::VectorError;
+pub use error::{Error, ErrorCategory, QueryError, RequestError, ResponseError, TalkError};
 pub use executor::{Executor, TokioExecutor};
-pub use ipmode::IpMode;
-pub use kbucket::{ConnectionDirection, ConnectionState, Key};
+pub use external_address::ExternalAddressPolicy;
+pub use handler::{OutgoingRequest, OutgoingRequestState};
+pub use ipmode::{DialPolicy, EnrReachability, IpMode};
+pub use kbucket::{ConnectionDirection, ConnectionState, EvictionPolicy, Key};
+pub use network_size::NetworkSizeEstimate;
 pub use packet::{DefaultProtocolId, ProtocolIdentity};
+pub use peer_store::{FilePeerStore, PeerStore, PeerStoreSnapshot, StateSnapshot};
 pub use permit_ban::PermitBanList;
-pub use service::TalkRequest;
-pub use socket::{ListenConfig, RateLimiter, RateLimiterBuilder};
+pub use rpc::TopicHash;
+pub use service::{
+    BanIntelConfig, DialBackConfig, EclipseDetectionConfig, EclipseWarning, InjectionOutcome,
+    NatStatus, NatType, NodesSelection, PeerSource, QuerySeeding, QueryTrace, TalkRequest,
+    TopicConfig, TracedContact, TracedOutcome,
+};
+pub use socket::{
+    FilterStats, LimitBudget, ListenConfig, RateLimiter, RateLimiterBuilder, RateLimiterStats,
+    Talker, Transport, TransportFactory,
+};
+pub use talk::Talk;
 // Re-export the ENR crate
 pub use enr;
 
@@ -0,0 +1,350 @@
+//! An adapter surfacing the peers found by discv5 to a libp2p swarm.
+//!
+//! [`Discv5Discovery`] runs the discv5 service, periodically looks up random targets and yields
+//! the discovered peers together with the libp2p addresses found in their ENRs. A peer found again
+//! is only surfaced once more if its ENR has been updated, or once it has not been surfaced for
+//! [`SEEN_PEERS_TTL`]. A swarm typically
+//! registers these with `Swarm::add_peer_address` and dials as many as it needs:
+//!
+//! ```ignore
+//! let mut discovery = Discv5Discovery::new(discv5, Duration::from_secs(30)).await?;
+//! while let Some(event) = discovery.next().await {
+//!     match event {
+//!         DiscoveryEvent::Discovered { peer_id, addresses, .. } => {
+//!             for address in addresses {
+//!                 swarm.add_peer_address(peer_id, address);
+//!             }
+//!         }
+//!         DiscoveryEvent::ExternalAddresses(addresses) => {
+//!             for address in addresses {
+//!                 swarm.add_external_address(address);
+//!             }
+//!         }
+//!         DiscoveryEvent::Discv5(_) => {}
+//!     }
+//! }
+//! ```
+use crate::{
+    enr_ext::EnrExt, lru_time_cache::LruTimeCache, packet::ProtocolIdentity, DefaultProtocolId,
+    Discv5, Enr, Error, Event, QueryError,
+};
+use enr::NodeId;
+use futures::{future::BoxFuture, FutureExt, Stream};
+use libp2p_identity::PeerId;
+use multiaddr::{Multiaddr, Protocol};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc,
+    time::{Interval, MissedTickBehavior},
+};
+use tracing::debug;
+
+/// The time after which a peer that has been surfaced is surfaced again when found.
+pub const SEEN_PEERS_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// The maximum number of surfaced peers remembered to avoid surfacing them again.
+const SEEN_PEERS_CAPACITY: usize = 4096;
+
+/// An event of a [`Discv5Discovery`].
+#[derive(Debug)]
+pub enum DiscoveryEvent {
+    /// A peer advertising libp2p addresses has been discovered.
+    Discovered {
+        peer_id: PeerId,
+        /// The TCP and QUIC addresses of the peer, without their `/p2p` component.
+        addresses: Vec<Multiaddr>,
+        enr: Arc<Enr>,
+    },
+    /// Our ENR has been updated with a new socket. These are the libp2p addresses it now
+    /// advertises.
+    ExternalAddresses(Vec<Multiaddr>),
+    /// Any other event of the discv5 service, such as a TALK request.
+    Discv5(Event),
+}
+
+/// Decides whether a discovered peer is surfaced.
+type PeerPredicate = Box<dyn Fn(&Enr) -> bool + Send + Sync>;
+
+/// Runs a discv5 service and streams the libp2p peers it discovers.
+pub struct Discv5Discovery<P: ProtocolIdentity = DefaultProtocolId> {
+    discv5: Discv5<P>,
+    /// The events of the discv5 service.
+    events: mpsc::Receiver<Event>,
+    /// The timer starting random lookups.
+    lookup_interval: Interval,
+    /// The lookup in progress, if any.
+    lookup: Option<BoxFuture<'static, Result<Vec<Enr>, QueryError>>>,
+    /// Filters the discovered peers, for instance by the network they are on.
+    predicate: Option<PeerPredicate>,
+    /// The sequence numbers of the ENRs of the peers recently surfaced.
+    seen_peers: LruTimeCache<NodeId, u64>,
+    /// Events ready to be yielded.
+    pending_events: VecDeque<DiscoveryEvent>,
+}
+
+impl<P: ProtocolIdentity> Discv5Discovery<P> {
+    /// Starts the discv5 service and returns the adapter, which looks up a random target every
+    /// `lookup_interval`, the first immediately.
+    pub async fn new(mut discv5: Discv5<P>, lookup_interval: Duration) -> Result<Self, Error> {
+        discv5.start().await?;
+        let events = discv5.event_stream().await?;
+        let mut lookup_interval = tokio::time::interval(lookup_interval);
+        lookup_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Discv5Discovery {
+            discv5,
+            events,
+            lookup_interval,
+            lookup: None,
+            predicate: None,
+            seen_peers: LruTimeCache::new(SEEN_PEERS_TTL, Some(SEEN_PEERS_CAPACITY)),
+            pending_events: VecDeque::new(),
+        })
+    }
+
+    /// Only surfaces the peers whose ENR satisfies `predicate`.
+    pub fn with_predicate(
+        mut self,
+        predicate: impl Fn(&Enr) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.predicate = Some(Box::new(predicate));
+        self
+    }
+
+    /// The underlying discv5 service.
+    pub fn discv5(&self) -> &Discv5<P> {
+        &self.discv5
+    }
+
+    /// Queues a `Discovered` event for a peer, if it advertises libp2p addresses, passes the
+    /// predicate and hasn't been surfaced recently with the same ENR.
+    fn discovered(&mut self, enr: Arc<Enr>) {
+        if self
+            .predicate
+            .as_ref()
+            .is_some_and(|predicate| !predicate(&enr))
+        {
+            return;
+        }
+        if self
+            .seen_peers
+            .peek(&enr.node_id())
+            .is_some_and(|seq| *seq >= enr.seq())
+        {
+            return;
+        }
+        let addresses: Vec<Multiaddr> = enr
+            .multiaddr_libp2p()
+            .into_iter()
+            .map(without_peer_id)
+            .collect();
+        if addresses.is_empty() {
+            return;
+        }
+        self.seen_peers.insert(enr.node_id(), enr.seq());
+        self.pending_events.push_back(DiscoveryEvent::Discovered {
+            peer_id: enr.peer_id(),
+            addresses,
+            enr,
+        });
+    }
+
+    /// Polls for the next event. Returns `None` once the discv5 service has shut down.
+    pub fn poll_next_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<DiscoveryEvent>> {
+        loop {
+            if let Some(event) = self.pending_events.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+
+            match self.events.poll_recv(cx) {
+                Poll::Ready(Some(Event::Discovered(enr))) => {
+                    self.discovered(enr);
+                    continue;
+                }
+                Poll::Ready(Some(Event::SocketUpdated(_))) => {
+                    let addresses = self
+                        .discv5
+                        .local_enr()
+                        .multiaddr_libp2p()
+                        .into_iter()
+                        .map(without_peer_id)
+                        .collect();
+                    self.pending_events
+                        .push_back(DiscoveryEvent::ExternalAddresses(addresses));
+                    continue;
+                }
+                Poll::Ready(Some(event)) => {
+                    return Poll::Ready(Some(DiscoveryEvent::Discv5(event)))
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => {}
+            }
+
+            if let Some(lookup) = self.lookup.as_mut() {
+                if let Poll::Ready(result) = lookup.poll_unpin(cx) {
+                    self.lookup = None;
+                    match result {
+                        Ok(enrs) => enrs
+                            .into_iter()
+                            .for_each(|enr| self.discovered(Arc::new(enr))),
+                        Err(e) => debug!(error = ?e, "Discovery lookup failed"),
+                    }
+                    continue;
+                }
+            }
+
+            if self.lookup_interval.poll_tick(cx).is_ready() {
+                if self.lookup.is_none() {
+                    self.lookup = Some(self.discv5.find_node(NodeId::random()).boxed());
+                }
+                continue;
+            }
+
+            return Poll::Pending;
+        }
+    }
+}
+
+impl<P: ProtocolIdentity + Unpin> Stream for Discv5Discovery<P> {
+    type Item = DiscoveryEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_next_event(cx)
+    }
+}
+
+/// Strips the trailing `/p2p` component, which swarms expect to be given separately.
+fn without_peer_id(mut multiaddr: Multiaddr) -> Multiaddr {
+    if let Some(Protocol::P2p(_)) = multiaddr.iter().last() {
+        multiaddr.pop();
+    }
+    multiaddr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{socket::ListenConfig, ConfigBuilder};
+    use enr::CombinedKey;
+    use futures::StreamExt;
+    use std::net::Ipv4Addr;
+
+    async fn build_discovery(port: u16) -> Discv5Discovery {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(port)
+            .build(&key)
+            .unwrap();
+        let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+            ip: Ipv4Addr::LOCALHOST,
+            port,
+        })
+        .build();
+        let discv5 = Discv5::new(enr, key, config).unwrap();
+        Discv5Discovery::new(discv5, Duration::from_secs(60))
+            .await
+            .unwrap()
+    }
+
+    fn peer_enr(key: &CombinedKey, tcp_port: u16) -> Enr {
+        Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .tcp4(tcp_port)
+            .build(key)
+            .unwrap()
+    }
+
+    #[test]
+    fn peer_id_is_stripped() {
+        let peer_id = peer_enr(&CombinedKey::generate_secp256k1(), 9000).peer_id();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/9000".parse().unwrap();
+        assert_eq!(
+            without_peer_id(address.clone().with(Protocol::P2p(peer_id))),
+            address
+        );
+        assert_eq!(without_peer_id(address.clone()), address);
+    }
+
+    #[tokio::test]
+    async fn peers_are_surfaced_once_per_enr() {
+        let mut discovery = build_discovery(10192).await;
+        let key = CombinedKey::generate_secp256k1();
+        let mut enr = peer_enr(&key, 9000);
+
+        discovery.discovered(Arc::new(enr.clone()));
+        discovery.discovered(Arc::new(enr.clone()));
+        assert_eq!(discovery.pending_events.len(), 1);
+        match discovery.pending_events.pop_front() {
+            Some(DiscoveryEvent::Discovered {
+                peer_id, addresses, ..
+            }) => {
+                assert_eq!(peer_id, enr.peer_id());
+                assert_eq!(
+                    addresses,
+                    vec!["/ip4/127.0.0.1/tcp/9000".parse::<Multiaddr>().unwrap()]
+                );
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+
+        // An updated ENR is surfaced again.
+        enr.set_tcp4(9001, &key).unwrap();
+        discovery.discovered(Arc::new(enr));
+        assert_eq!(discovery.pending_events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn peers_are_filtered() {
+        let mut discovery = build_discovery(10193)
+            .await
+            .with_predicate(|enr| enr.tcp4() == Some(9000));
+
+        // Peers without libp2p addresses are not surfaced.
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder().ip4(Ipv4Addr::LOCALHOST).build(&key).unwrap();
+        discovery.discovered(Arc::new(enr));
+        discovery.discovered(Arc::new(peer_enr(&CombinedKey::generate_secp256k1(), 9001)));
+        assert!(discovery.pending_events.is_empty());
+
+        discovery.discovered(Arc::new(peer_enr(&CombinedKey::generate_secp256k1(), 9000)));
+        assert_eq!(discovery.pending_events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn lookups_surface_peers() {
+        let key = CombinedKey::generate_secp256k1();
+        let port = 10195;
+        let enr = Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(port)
+            .tcp4(9000)
+            .build(&key)
+            .unwrap();
+        let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+            ip: Ipv4Addr::LOCALHOST,
+            port,
+        })
+        .build();
+        let mut peer: Discv5 = Discv5::new(enr.clone(), key, config).unwrap();
+        peer.start().await.unwrap();
+
+        let mut discovery = build_discovery(10194).await;
+        discovery.discv5().add_enr(enr.clone()).unwrap();
+        let peer_id = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(DiscoveryEvent::Discovered { peer_id, .. }) = discovery.next().await {
+                    return peer_id;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(peer_id, enr.peer_id());
+    }
+}
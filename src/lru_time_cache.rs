@@ -1,8 +1,6 @@
+use crate::time::{Clock, Instant, SystemClock};
 use hashlink::LinkedHashMap;
-use std::{
-    hash::Hash,
-    time::{Duration, Instant},
-};
+use std::{hash::Hash, sync::Arc, time::Duration};
 
 pub struct LruTimeCache<K, V> {
     map: LinkedHashMap<K, (V, Instant)>,
@@ -10,10 +8,20 @@ pub struct LruTimeCache<K, V> {
     ttl: Duration,
     /// The max size of the cache.
     capacity: usize,
+    clock: Arc<dyn Clock>,
 }
 
 impl<K: Clone + Eq + Hash, V> LruTimeCache<K, V> {
     pub fn new(ttl: Duration, capacity: Option<usize>) -> LruTimeCache<K, V> {
+        Self::with_clock(ttl, capacity, Arc::new(SystemClock))
+    }
+
+    /// Creates a cache whose elements expire according to the given clock.
+    pub fn with_clock(
+        ttl: Duration,
+        capacity: Option<usize>,
+        clock: Arc<dyn Clock>,
+    ) -> LruTimeCache<K, V> {
         let capacity = if let Some(cap) = capacity {
             cap
         } else {
@@ -23,17 +31,28 @@ impl<K: Clone + Eq + Hash, V> LruTimeCache<K, V> {
             map: LinkedHashMap::new(),
             ttl,
             capacity,
+            clock,
         }
     }
 
     /// Inserts a key-value pair into the cache.
     pub fn insert(&mut self, key: K, value: V) {
-        let now = Instant::now();
+        self.insert_evicting(key, value);
+    }
+
+    /// Inserts a key-value pair into the cache, returning the least recently used pair if it was
+    /// evicted to stay within capacity.
+    pub fn insert_evicting(&mut self, key: K, value: V) -> Option<(K, V)> {
+        let now = self.clock.now();
         self.map.insert(key, (value, now));
 
         if self.map.len() > self.capacity {
-            self.map.pop_front();
+            return self
+                .map
+                .pop_front()
+                .map(|(key, (value, _time))| (key, value));
         }
+        None
     }
 
     /// Retrieves a reference to the value stored under `key`, or `None` if the key doesn't exist.
@@ -46,7 +65,7 @@ impl<K: Clone + Eq + Hash, V> LruTimeCache<K, V> {
     /// Retrieves a mutable reference to the value stored under `key`, or `None` if the key doesn't exist.
     /// Also removes expired elements and updates the time.
     pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        let now = Instant::now();
+        let now = self.clock.now();
         self.remove_expired_values(now);
 
         match self.map.raw_entry_mut().from_key(key) {
@@ -64,7 +83,7 @@ impl<K: Clone + Eq + Hash, V> LruTimeCache<K, V> {
     #[allow(dead_code)]
     pub fn peek(&self, key: &K) -> Option<&V> {
         if let Some((value, time)) = self.map.get(key) {
-            return if *time + self.ttl >= Instant::now() {
+            return if *time + self.ttl >= self.clock.now() {
                 Some(value)
             } else {
                 None
@@ -74,9 +93,25 @@ impl<K: Clone + Eq + Hash, V> LruTimeCache<K, V> {
         None
     }
 
+    /// Returns an iterator over the key-value pairs that have not expired, without updating their
+    /// timestamps.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        let now = self.clock.now();
+        self.map
+            .iter()
+            .filter(move |(_key, (_value, time))| *time + self.ttl >= now)
+            .map(|(key, (value, _time))| (key, value))
+    }
+
+    /// Returns an iterator over the keys that have not expired, without updating their
+    /// timestamps.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _value)| key)
+    }
+
     /// Returns the size of the cache, i.e. the number of cached non-expired key-value pairs.
     pub fn len(&mut self) -> usize {
-        self.remove_expired_values(Instant::now());
+        self.remove_expired_values(self.clock.now());
         self.map.len()
     }
 
@@ -86,6 +121,17 @@ impl<K: Clone + Eq + Hash, V> LruTimeCache<K, V> {
         self.map.remove(key).map(|v| v.0)
     }
 
+    /// Removes and returns the least recently used pair if it has expired.
+    pub fn pop_expired(&mut self) -> Option<(K, V)> {
+        let (_key, (_value, time)) = self.map.front()?;
+        if *time + self.ttl >= self.clock.now() {
+            return None;
+        }
+        self.map
+            .pop_front()
+            .map(|(key, (value, _time))| (key, value))
+    }
+
     /// Removes expired items from the cache.
     fn remove_expired_values(&mut self, now: Instant) {
         while let Some((_front, (_value, time))) = self.map.front() {
@@ -181,6 +227,15 @@ mod tests {
         assert_eq!(3, cache.len());
     }
 
+    #[test]
+    fn insert_evicting() {
+        let mut cache = LruTimeCache::new(Duration::from_secs(10), Some(2));
+
+        assert_eq!(None, cache.insert_evicting(1, 10));
+        assert_eq!(None, cache.insert_evicting(2, 20));
+        assert_eq!(Some((1, 10)), cache.insert_evicting(3, 30));
+    }
+
     #[test]
     fn remove() {
         let mut cache = LruTimeCache::new(Duration::from_secs(10), None);
@@ -192,8 +247,8 @@ mod tests {
     }
 
     mod ttl {
-        use crate::lru_time_cache::LruTimeCache;
-        use std::{thread::sleep, time::Duration};
+        use crate::{lru_time_cache::LruTimeCache, time::ManualClock};
+        use std::{sync::Arc, thread::sleep, time::Duration};
 
         const TTL: Duration = Duration::from_millis(100);
 
@@ -217,6 +272,18 @@ mod tests {
             assert_eq!(None, cache.peek(&1));
         }
 
+        #[test]
+        fn pop_expired() {
+            let mut cache = LruTimeCache::new(TTL, None);
+            cache.insert(1, 10);
+            assert_eq!(None, cache.pop_expired());
+
+            sleep(TTL);
+            cache.insert(2, 20);
+            assert_eq!(Some((1, 10)), cache.pop_expired());
+            assert_eq!(None, cache.pop_expired());
+        }
+
         #[test]
         fn len() {
             let mut cache = LruTimeCache::new(TTL, None);
@@ -245,5 +312,17 @@ mod tests {
             assert_eq!(Some(&30), cache.get(&3));
             assert_eq!(Some(&40), cache.get(&4));
         }
+
+        #[test]
+        fn manual_clock() {
+            let clock = ManualClock::default();
+            let mut cache = LruTimeCache::with_clock(TTL, None, Arc::new(clock.clone()));
+            cache.insert(1, 10);
+
+            clock.advance(TTL);
+            assert_eq!(Some(&10), cache.peek(&1));
+            clock.advance(Duration::from_millis(1));
+            assert_eq!(None, cache.peek(&1));
+        }
     }
 }
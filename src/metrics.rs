@@ -1,4 +1,10 @@
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::kbucket::BucketChurn;
+use std::{
+    convert::TryFrom,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::sync::mpsc;
 
 lazy_static! {
     pub static ref METRICS: InternalMetrics = InternalMetrics::default();
@@ -8,6 +14,8 @@ lazy_static! {
 pub struct InternalMetrics {
     /// The number of active UDP sessions that are currently established.
     pub active_sessions: AtomicUsize,
+    /// The number of established sessions that are idle and kept as keys only.
+    pub idle_sessions: AtomicUsize,
     /// The number of seconds to store received packets to taking a moving average over.
     pub moving_window: u64,
     /// The number of unsolicited requests received per moving window.
@@ -20,6 +28,64 @@ pub struct InternalMetrics {
     pub ipv4_contactable: AtomicBool,
     /// Whether we consider ourselves contactable or not on ipv6.
     pub ipv6_contactable: AtomicBool,
+    /// The number of decoded packets waiting for the handler.
+    pub inbound_queue_depth: AtomicUsize,
+    /// The number of messages from the service waiting for the handler.
+    pub handler_queue_depth: AtomicUsize,
+    /// The number of messages from the handler waiting for the service.
+    pub service_queue_depth: AtomicUsize,
+    /// The number of requests from the application waiting for the service.
+    pub request_queue_depth: AtomicUsize,
+    /// The number of events waiting for the application.
+    pub event_queue_depth: AtomicUsize,
+    /// The smoothed time of an iteration of the service loop in microseconds, zero until measured.
+    pub service_loop_micros: AtomicUsize,
+    /// The longest iteration of the service loop in microseconds.
+    pub service_loop_max_micros: AtomicUsize,
+    /// The number of iterations of the service loop per task, indexed as [`ServiceTask::ALL`].
+    pub service_task_iterations: [AtomicUsize; ServiceTask::ALL.len()],
+    /// The total time of the iterations of the service loop in microseconds per task, indexed as
+    /// [`ServiceTask::ALL`].
+    pub service_task_micros: [AtomicUsize; ServiceTask::ALL.len()],
+    /// The number of inbound packets dropped because the handler's queue was full.
+    pub inbound_packets_dropped: AtomicUsize,
+    /// The number of messages from the service dropped because the handler's queue was full.
+    pub handler_messages_dropped: AtomicUsize,
+    /// The number of events dropped because the event stream was full.
+    pub events_dropped: AtomicUsize,
+    /// The number of WHOAREYOU challenges awaiting a handshake.
+    pub active_challenges: AtomicUsize,
+    /// The number of requests waiting for a session establishment.
+    pub queued_requests: AtomicUsize,
+    /// The number of requests failed while waiting for a session establishment, because they
+    /// timed out or the queue was full.
+    pub queued_requests_dropped: AtomicUsize,
+    /// The number of WHOAREYOU challenges not sent due to the challenge limits.
+    pub challenges_suppressed: AtomicUsize,
+    /// The number of sessions re-keyed as their message counter neared exhaustion.
+    pub rekeys: AtomicUsize,
+    /// The number of requests answered by an identical request already in flight.
+    pub coalesced_requests: AtomicUsize,
+    /// The number of messages dropped as replays of a message already received in the session.
+    pub replayed_messages: AtomicUsize,
+    /// The number of sessions resumed from a ticket rather than a handshake.
+    pub resumed_sessions: AtomicUsize,
+    /// The smoothed round trip time of requests in microseconds, zero until measured.
+    pub smoothed_rtt_micros: AtomicUsize,
+    /// The number of sessions with table members and permitted peers evicted from the cache.
+    pub reserved_session_evictions: AtomicUsize,
+    /// The number of other sessions evicted from the cache.
+    pub general_session_evictions: AtomicUsize,
+    /// The number of nodes inserted into the routing table.
+    pub table_insertions: AtomicUsize,
+    /// The number of nodes evicted from the routing table for a pending node.
+    pub table_evictions: AtomicUsize,
+    /// The number of nodes removed from the routing table otherwise.
+    pub table_removals: AtomicUsize,
+    /// The number of finished lookups.
+    pub lookups: AtomicUsize,
+    /// The number of finished lookups with peers that failed to respond among their results.
+    pub lookups_with_dead_peers: AtomicUsize,
 }
 
 impl Default for InternalMetrics {
@@ -27,11 +93,40 @@ impl Default for InternalMetrics {
         InternalMetrics {
             moving_window: 5,
             active_sessions: AtomicUsize::new(0),
+            idle_sessions: AtomicUsize::new(0),
             unsolicited_requests_per_window: AtomicUsize::new(0),
             bytes_sent: AtomicUsize::new(0),
             bytes_recv: AtomicUsize::new(0),
             ipv4_contactable: AtomicBool::new(false),
             ipv6_contactable: AtomicBool::new(false),
+            inbound_queue_depth: AtomicUsize::new(0),
+            handler_queue_depth: AtomicUsize::new(0),
+            service_queue_depth: AtomicUsize::new(0),
+            request_queue_depth: AtomicUsize::new(0),
+            event_queue_depth: AtomicUsize::new(0),
+            service_loop_micros: AtomicUsize::new(0),
+            service_loop_max_micros: AtomicUsize::new(0),
+            service_task_iterations: Default::default(),
+            service_task_micros: Default::default(),
+            inbound_packets_dropped: AtomicUsize::new(0),
+            handler_messages_dropped: AtomicUsize::new(0),
+            events_dropped: AtomicUsize::new(0),
+            active_challenges: AtomicUsize::new(0),
+            queued_requests: AtomicUsize::new(0),
+            queued_requests_dropped: AtomicUsize::new(0),
+            challenges_suppressed: AtomicUsize::new(0),
+            rekeys: AtomicUsize::new(0),
+            coalesced_requests: AtomicUsize::new(0),
+            replayed_messages: AtomicUsize::new(0),
+            resumed_sessions: AtomicUsize::new(0),
+            smoothed_rtt_micros: AtomicUsize::new(0),
+            reserved_session_evictions: AtomicUsize::new(0),
+            general_session_evictions: AtomicUsize::new(0),
+            table_insertions: AtomicUsize::new(0),
+            table_evictions: AtomicUsize::new(0),
+            table_removals: AtomicUsize::new(0),
+            lookups: AtomicUsize::new(0),
+            lookups_with_dead_peers: AtomicUsize::new(0),
         }
     }
 }
@@ -48,13 +143,146 @@ impl InternalMetrics {
         self.bytes_sent
             .store(current_bytes_sent.saturating_add(bytes), Ordering::Relaxed);
     }
+
+    /// Folds a round trip time into the smoothed round trip time, weighting it by 1/8 as TCP does.
+    pub fn add_rtt_sample(&self, rtt: Duration) {
+        let sample = usize::try_from(rtt.as_micros())
+            .unwrap_or(usize::MAX)
+            .max(1);
+        let _ =
+            self.smoothed_rtt_micros
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |srtt| {
+                    Some(match srtt {
+                        0 => sample,
+                        srtt => srtt - srtt / 8 + sample / 8,
+                    })
+                });
+    }
+
+    /// Records the number of messages queued on a channel.
+    pub fn set_queue_depth<T>(&self, depth: &AtomicUsize, sender: &mpsc::Sender<T>) {
+        depth.store(sender.max_capacity() - sender.capacity(), Ordering::Relaxed);
+    }
+
+    /// Records an iteration of the service loop, folding its time into the smoothed iteration
+    /// time with a weight of 1/8.
+    pub fn add_service_task(&self, task: ServiceTask, elapsed: Duration) {
+        let micros = usize::try_from(elapsed.as_micros())
+            .unwrap_or(usize::MAX)
+            .max(1);
+        self.service_task_iterations[task as usize].fetch_add(1, Ordering::Relaxed);
+        self.service_task_micros[task as usize].fetch_add(micros, Ordering::Relaxed);
+        self.service_loop_max_micros
+            .fetch_max(micros, Ordering::Relaxed);
+        let _ = self.service_loop_micros.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |smoothed| {
+                Some(match smoothed {
+                    0 => micros,
+                    smoothed => smoothed - smoothed / 8 + micros / 8,
+                })
+            },
+        );
+    }
+}
+
+/// The kinds of work the time of the service loop is broken down by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ServiceTask {
+    /// Requests of the application.
+    ApplicationRequest,
+    /// Requests, responses and session events from the handler.
+    HandlerMessage,
+    /// Insertions of pending nodes into the routing table.
+    TableMaintenance,
+    /// Progress and completion of queries.
+    Query,
+    /// Pings of the peers of the routing table.
+    Ping,
+    /// Topic registrations and advertisements.
+    Topics,
+    /// Periodic work, such as checkpoints, keep-alives and connectivity tests.
+    Timer,
+}
+
+impl ServiceTask {
+    /// All tasks, in the order of their discriminants.
+    pub const ALL: [ServiceTask; 7] = [
+        ServiceTask::ApplicationRequest,
+        ServiceTask::HandlerMessage,
+        ServiceTask::TableMaintenance,
+        ServiceTask::Query,
+        ServiceTask::Ping,
+        ServiceTask::Topics,
+        ServiceTask::Timer,
+    ];
+}
+
+/// Records the time of an iteration of the service loop when dropped, along with the depths of
+/// the queues of application requests and of events sampled as the iteration started. Sampling
+/// on every iteration keeps the depths current as the queues drain, rather than only as they are
+/// filled.
+pub(crate) struct ServiceTaskTimer {
+    metrics: &'static InternalMetrics,
+    task: ServiceTask,
+    started: std::time::Instant,
+}
+
+impl ServiceTaskTimer {
+    pub fn start(task: ServiceTask, request_queue_depth: usize, event_queue_depth: usize) -> Self {
+        Self::start_with(&METRICS, task, request_queue_depth, event_queue_depth)
+    }
+
+    /// Starts the timer, recording into the given metrics.
+    fn start_with(
+        metrics: &'static InternalMetrics,
+        task: ServiceTask,
+        request_queue_depth: usize,
+        event_queue_depth: usize,
+    ) -> Self {
+        metrics
+            .request_queue_depth
+            .store(request_queue_depth, Ordering::Relaxed);
+        metrics
+            .event_queue_depth
+            .store(event_queue_depth, Ordering::Relaxed);
+        ServiceTaskTimer {
+            metrics,
+            task,
+            started: std::time::Instant::now(),
+        }
+    }
+}
+
+impl Drop for ServiceTaskTimer {
+    fn drop(&mut self) {
+        self.metrics
+            .add_service_task(self.task, self.started.elapsed());
+    }
+}
+
+/// The time the service loop spent on a kind of work.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServiceTaskTime {
+    /// The kind of work.
+    pub task: ServiceTask,
+    /// The number of iterations of the loop spent on the task.
+    pub iterations: usize,
+    /// The total time of these iterations.
+    pub total: Duration,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// The publicly accessible metrics that can be obtained from the Discv5 server.
 pub struct Metrics {
     /// The number of active UDP sessions that are currently established.
     pub active_sessions: usize,
+    /// The number of established sessions that are idle and kept as keys only.
+    pub idle_sessions: usize,
     /// The number of unsolicited requests received per second (averaged over a moving window).
     pub unsolicited_requests_per_second: f64,
     /// The number of bytes sent.
@@ -65,12 +293,73 @@ pub struct Metrics {
     pub ipv4_contactable: bool,
     /// Whether we consider ourselves contactable or not.
     pub ipv6_contactable: bool,
+    /// The number of decoded packets waiting for the handler.
+    pub inbound_queue_depth: usize,
+    /// The number of messages from the service waiting for the handler.
+    pub handler_queue_depth: usize,
+    /// The number of messages from the handler waiting for the service.
+    pub service_queue_depth: usize,
+    /// The number of requests from the application waiting for the service.
+    pub request_queue_depth: usize,
+    /// The number of events waiting for the application.
+    pub event_queue_depth: usize,
+    /// The smoothed time of an iteration of the service loop. `None` until an iteration has
+    /// completed.
+    pub service_loop_latency: Option<Duration>,
+    /// The longest iteration of the service loop. A long iteration delays every packet and
+    /// request behind it.
+    pub service_loop_max_latency: Duration,
+    /// The time the service loop spent per kind of work.
+    pub service_task_times: Vec<ServiceTaskTime>,
+    /// The number of inbound packets dropped because the handler's queue was full.
+    pub inbound_packets_dropped: usize,
+    /// The number of messages from the service dropped because the handler's queue was full.
+    pub handler_messages_dropped: usize,
+    /// The number of events dropped because the event stream was full.
+    pub events_dropped: usize,
+    /// The number of WHOAREYOU challenges awaiting a handshake. Once
+    /// [`crate::Config::challenge_cache_capacity`] are, further challenges are suppressed.
+    pub active_challenges: usize,
+    /// The number of requests waiting for a session establishment, see
+    /// [`crate::Config::max_session_establishments`].
+    pub queued_requests: usize,
+    /// The number of requests failed while waiting for a session establishment, because they
+    /// timed out or the queue was full.
+    pub queued_requests_dropped: usize,
+    /// The number of WHOAREYOU challenges not sent due to the challenge limits.
+    pub challenges_suppressed: usize,
+    /// The number of sessions re-keyed as their message counter neared exhaustion.
+    pub rekeys: usize,
+    /// The number of requests answered by an identical request already in flight.
+    pub coalesced_requests: usize,
+    /// The number of messages dropped as replays of a message already received in the session.
+    pub replayed_messages: usize,
+    /// The number of sessions resumed from a ticket rather than a handshake.
+    pub resumed_sessions: usize,
+    /// The smoothed round trip time of requests, measured against kernel receive timestamps
+    /// where the platform supports them. `None` until a request has been answered.
+    pub smoothed_rtt: Option<Duration>,
+    /// The number of sessions with table members and permitted peers evicted from the cache.
+    pub reserved_session_evictions: usize,
+    /// The number of other sessions evicted from the cache.
+    pub general_session_evictions: usize,
+    /// The number of nodes inserted into the routing table.
+    pub table_insertions: usize,
+    /// The number of nodes evicted from the routing table for a pending node.
+    pub table_evictions: usize,
+    /// The number of nodes removed from the routing table otherwise.
+    pub table_removals: usize,
+    /// The number of finished lookups.
+    pub lookups: usize,
+    /// The number of finished lookups with peers that failed to respond among their results.
+    pub lookups_with_dead_peers: usize,
 }
 
 impl From<&METRICS> for Metrics {
     fn from(internal_metrics: &METRICS) -> Self {
         Metrics {
             active_sessions: internal_metrics.active_sessions.load(Ordering::Relaxed),
+            idle_sessions: internal_metrics.idle_sessions.load(Ordering::Relaxed),
             unsolicited_requests_per_second: internal_metrics
                 .unsolicited_requests_per_window
                 .load(Ordering::Relaxed) as f64
@@ -79,6 +368,124 @@ impl From<&METRICS> for Metrics {
             bytes_recv: internal_metrics.bytes_recv.load(Ordering::Relaxed),
             ipv4_contactable: internal_metrics.ipv4_contactable.load(Ordering::Relaxed),
             ipv6_contactable: internal_metrics.ipv6_contactable.load(Ordering::Relaxed),
+            inbound_queue_depth: internal_metrics.inbound_queue_depth.load(Ordering::Relaxed),
+            handler_queue_depth: internal_metrics.handler_queue_depth.load(Ordering::Relaxed),
+            service_queue_depth: internal_metrics.service_queue_depth.load(Ordering::Relaxed),
+            request_queue_depth: internal_metrics.request_queue_depth.load(Ordering::Relaxed),
+            event_queue_depth: internal_metrics.event_queue_depth.load(Ordering::Relaxed),
+            service_loop_latency: match internal_metrics.service_loop_micros.load(Ordering::Relaxed)
+            {
+                0 => None,
+                micros => Some(Duration::from_micros(micros as u64)),
+            },
+            service_loop_max_latency: Duration::from_micros(
+                internal_metrics
+                    .service_loop_max_micros
+                    .load(Ordering::Relaxed) as u64,
+            ),
+            service_task_times: ServiceTask::ALL
+                .iter()
+                .map(|task| ServiceTaskTime {
+                    task: *task,
+                    iterations: internal_metrics.service_task_iterations[*task as usize]
+                        .load(Ordering::Relaxed),
+                    total: Duration::from_micros(
+                        internal_metrics.service_task_micros[*task as usize].load(Ordering::Relaxed)
+                            as u64,
+                    ),
+                })
+                .collect(),
+            inbound_packets_dropped: internal_metrics
+                .inbound_packets_dropped
+                .load(Ordering::Relaxed),
+            handler_messages_dropped: internal_metrics
+                .handler_messages_dropped
+                .load(Ordering::Relaxed),
+            events_dropped: internal_metrics.events_dropped.load(Ordering::Relaxed),
+            active_challenges: internal_metrics.active_challenges.load(Ordering::Relaxed),
+            queued_requests: internal_metrics.queued_requests.load(Ordering::Relaxed),
+            queued_requests_dropped: internal_metrics
+                .queued_requests_dropped
+                .load(Ordering::Relaxed),
+            challenges_suppressed: internal_metrics
+                .challenges_suppressed
+                .load(Ordering::Relaxed),
+            rekeys: internal_metrics.rekeys.load(Ordering::Relaxed),
+            coalesced_requests: internal_metrics.coalesced_requests.load(Ordering::Relaxed),
+            replayed_messages: internal_metrics.replayed_messages.load(Ordering::Relaxed),
+            resumed_sessions: internal_metrics.resumed_sessions.load(Ordering::Relaxed),
+            smoothed_rtt: match internal_metrics.smoothed_rtt_micros.load(Ordering::Relaxed) {
+                0 => None,
+                micros => Some(Duration::from_micros(micros as u64)),
+            },
+            reserved_session_evictions: internal_metrics
+                .reserved_session_evictions
+                .load(Ordering::Relaxed),
+            general_session_evictions: internal_metrics
+                .general_session_evictions
+                .load(Ordering::Relaxed),
+            table_insertions: internal_metrics.table_insertions.load(Ordering::Relaxed),
+            table_evictions: internal_metrics.table_evictions.load(Ordering::Relaxed),
+            table_removals: internal_metrics.table_removals.load(Ordering::Relaxed),
+            lookups: internal_metrics.lookups.load(Ordering::Relaxed),
+            lookups_with_dead_peers: internal_metrics
+                .lookups_with_dead_peers
+                .load(Ordering::Relaxed),
         }
     }
 }
+
+/// The churn of the routing table and the share of lookups running into unresponsive peers,
+/// returned by [`crate::Discv5::churn_stats`]. The lookup counts are those of the process.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChurnStats {
+    /// The churn of each bucket that has held a node, by the log2 distance of the bucket.
+    pub buckets: Vec<(u64, BucketChurn)>,
+    /// The churn of the whole routing table.
+    pub total: BucketChurn,
+    /// The number of finished lookups.
+    pub lookups: usize,
+    /// The number of finished lookups with peers that failed to respond among their results.
+    pub lookups_with_dead_peers: usize,
+}
+
+impl ChurnStats {
+    /// The percentage of lookups with peers that failed to respond among their results, if any
+    /// lookup has finished.
+    pub fn dead_peer_lookup_percentage(&self) -> Option<f64> {
+        (self.lookups > 0)
+            .then(|| 100.0 * self.lookups_with_dead_peers as f64 / self.lookups as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_task_timer_records_time_and_queue_depths() {
+        let metrics: &'static InternalMetrics = Box::leak(Box::default());
+        let timer = ServiceTaskTimer::start_with(metrics, ServiceTask::Query, 3, 5);
+        assert_eq!(metrics.request_queue_depth.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.event_queue_depth.load(Ordering::Relaxed), 5);
+        drop(timer);
+
+        let task = ServiceTask::Query as usize;
+        assert_eq!(
+            metrics.service_task_iterations[task].load(Ordering::Relaxed),
+            1
+        );
+        assert!(metrics.service_task_micros[task].load(Ordering::Relaxed) > 0);
+        assert!(metrics.service_loop_micros.load(Ordering::Relaxed) > 0);
+
+        // A drained queue is recorded by the next iteration.
+        drop(ServiceTaskTimer::start_with(
+            metrics,
+            ServiceTask::Timer,
+            0,
+            0,
+        ));
+        assert_eq!(metrics.request_queue_depth.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.event_queue_depth.load(Ordering::Relaxed), 0);
+    }
+}
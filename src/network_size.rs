@@ -0,0 +1,154 @@
+//! Estimation of the number of nodes of the network.
+//!
+//! Node ids are uniformly distributed, so the distances from any target to its closest nodes
+//! reveal the density of the network: among `N` nodes, the `i`-th closest to a target is expected
+//! at a fraction `i / (N + 1)` of the id space. [`crate::Discv5::estimate_network_size`] runs a
+//! few lookups for targets spread evenly across the id space, fits the density to the closest
+//! nodes found by each, and combines the estimates of the lookups into an estimate with confidence
+//! bounds.
+use crate::kbucket::MAX_NODES_PER_BUCKET;
+use enr::NodeId;
+use std::collections::HashSet;
+
+/// The number of lookups an estimate is based on.
+pub(crate) const NETWORK_SIZE_LOOKUPS: usize = 8;
+
+/// The z-score of the two-sided 95% confidence interval.
+const Z_95: f64 = 1.96;
+
+/// An estimate of the number of nodes of the network, including the local node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkSizeEstimate {
+    /// The estimated number of nodes.
+    pub estimate: f64,
+    /// The lower bound of the 95% confidence interval. Never less than the number of nodes found.
+    pub lower: f64,
+    /// The upper bound of the 95% confidence interval.
+    pub upper: f64,
+    /// The number of lookups the estimate is based on.
+    pub lookups: usize,
+    /// The number of distinct nodes found by the lookups, including the local node.
+    pub nodes_found: usize,
+}
+
+/// Random lookup targets, one in each of `lookups` equal slices of the id space, so that the
+/// lookups sample the whole network rather than possibly the same region.
+pub(crate) fn lookup_targets(lookups: usize) -> Vec<NodeId> {
+    let stride = u64::MAX / lookups.max(1) as u64;
+    (0..lookups as u64)
+        .map(|slice| {
+            let mut raw: [u8; 32] = rand::random();
+            let prefix = slice * stride + rand::random::<u64>() % stride.max(1);
+            raw[..8].copy_from_slice(&prefix.to_be_bytes());
+            NodeId::new(&raw)
+        })
+        .collect()
+}
+
+/// Estimates the number of nodes from the nodes found by a lookup for `target`, which must include
+/// the local node as the lookup never returns it.
+///
+/// A lookup returns at most [`MAX_NODES_PER_BUCKET`] nodes, and one returning fewer has found
+/// every node it could reach, so that their number is the estimate. Otherwise the density is
+/// fitted by least squares to the distances of the closest nodes.
+pub(crate) fn lookup_estimate(target: &NodeId, found: &[NodeId]) -> f64 {
+    // The local node is never returned, so a lookup that found every reachable node returns one
+    // node fewer than the lookup limit.
+    if found.len() < MAX_NODES_PER_BUCKET + 1 {
+        return found.len() as f64;
+    }
+    let mut distances: Vec<f64> = found
+        .iter()
+        .map(|node_id| distance_fraction(target, node_id))
+        .collect();
+    distances.sort_by(f64::total_cmp);
+    let (squares, weighted) = distances
+        .iter()
+        .take(MAX_NODES_PER_BUCKET)
+        .enumerate()
+        .fold((0.0, 0.0), |(squares, weighted), (index, distance)| {
+            let rank = (index + 1) as f64;
+            (squares + rank * rank, weighted + rank * distance)
+        });
+    if weighted <= 0.0 {
+        return found.len() as f64;
+    }
+    // The expected distance of the i-th closest node is i / (N + 1).
+    (squares / weighted - 1.0).max(found.len() as f64)
+}
+
+/// Combines the estimates of the lookups, given the distinct nodes they found.
+pub(crate) fn combine_estimates(estimates: &[f64], found: &HashSet<NodeId>) -> NetworkSizeEstimate {
+    let nodes_found = found.len();
+    let lookups = estimates.len();
+    if lookups == 0 {
+        return NetworkSizeEstimate {
+            estimate: nodes_found as f64,
+            lower: nodes_found as f64,
+            upper: nodes_found as f64,
+            lookups,
+            nodes_found,
+        };
+    }
+    let mean = estimates.iter().sum::<f64>() / lookups as f64;
+    let standard_error = if lookups > 1 {
+        let variance = estimates
+            .iter()
+            .map(|estimate| (estimate - mean).powi(2))
+            .sum::<f64>()
+            / (lookups - 1) as f64;
+        (variance / lookups as f64).sqrt()
+    } else {
+        // The spread of a single fit over the closest nodes of a lookup.
+        mean / (MAX_NODES_PER_BUCKET as f64).sqrt()
+    };
+    let estimate = mean.max(nodes_found as f64);
+    NetworkSizeEstimate {
+        estimate,
+        lower: (mean - Z_95 * standard_error).max(nodes_found as f64),
+        upper: (mean + Z_95 * standard_error).max(estimate),
+        lookups,
+        nodes_found,
+    }
+}
+
+/// The XOR distance between the ids as a fraction of the id space.
+fn distance_fraction(a: &NodeId, b: &NodeId) -> f64 {
+    let mut high = [0u8; 16];
+    for (byte, (a, b)) in high.iter_mut().zip(a.raw().iter().zip(b.raw().iter())) {
+        *byte = a ^ b;
+    }
+    u128::from_be_bytes(high) as f64 / 2f64.powi(128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_the_size_of_a_uniform_network() {
+        let network: Vec<NodeId> = (0..5000).map(|_| NodeId::random()).collect();
+        let mut found = HashSet::new();
+        let estimates: Vec<f64> = lookup_targets(NETWORK_SIZE_LOOKUPS)
+            .iter()
+            .map(|target| {
+                let mut closest = network.clone();
+                closest.sort_by_key(|node_id| distance_fraction(target, node_id).to_bits());
+                closest.truncate(MAX_NODES_PER_BUCKET + 1);
+                found.extend(closest.iter().copied());
+                lookup_estimate(target, &closest)
+            })
+            .collect();
+        let estimate = combine_estimates(&estimates, &found);
+        assert!(
+            (3000.0..8000.0).contains(&estimate.estimate),
+            "{:?}",
+            estimate
+        );
+        assert!(estimate.lower <= estimate.estimate && estimate.estimate <= estimate.upper);
+
+        // A lookup that couldn't fill its results has found the whole network.
+        let small: Vec<NodeId> = network.iter().take(5).copied().collect();
+        assert_eq!(lookup_estimate(&NodeId::random(), &small), 5.0);
+    }
+}
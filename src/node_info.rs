@@ -1,10 +1,8 @@
 use super::*;
 use crate::Enr;
 use enr::{CombinedPublicKey, NodeId};
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
-#[cfg(feature = "libp2p")]
-use libp2p_identity::{KeyType, PublicKey};
 #[cfg(feature = "libp2p")]
 use multiaddr::{Multiaddr, Protocol};
 
@@ -17,16 +15,20 @@ pub struct NodeContact {
     /// Address to use to contact the node.
     socket_addr: SocketAddr,
     /// The ENR of the node if known.
-    enr: Option<Enr>,
+    enr: Option<Arc<Enr>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct NonContactable {
-    pub enr: Enr,
+    pub enr: Arc<Enr>,
 }
 
 impl NodeContact {
-    pub fn new(public_key: CombinedPublicKey, socket_addr: SocketAddr, enr: Option<Enr>) -> Self {
+    pub fn new(
+        public_key: CombinedPublicKey,
+        socket_addr: SocketAddr,
+        enr: Option<Arc<Enr>>,
+    ) -> Self {
         NodeContact {
             public_key,
             socket_addr,
@@ -49,7 +51,7 @@ impl NodeContact {
         self.public_key.clone()
     }
 
-    pub fn enr(&self) -> Option<Enr> {
+    pub fn enr(&self) -> Option<Arc<Enr>> {
         self.enr.clone()
     }
 
@@ -64,7 +66,7 @@ impl NodeContact {
         }
     }
 
-    pub fn to_address_and_enr(self) -> (NodeAddress, Option<Enr>) {
+    pub fn to_address_and_enr(self) -> (NodeAddress, Option<Arc<Enr>>) {
         let NodeContact {
             public_key,
             socket_addr,
@@ -79,8 +81,19 @@ impl NodeContact {
         )
     }
 
-    pub fn try_from_enr(enr: Enr, ip_mode: IpMode) -> Result<Self, NonContactable> {
-        let socket_addr = match ip_mode.get_contactable_addr(&enr) {
+    pub fn try_from_enr(enr: impl Into<Arc<Enr>>, ip_mode: IpMode) -> Result<Self, NonContactable> {
+        Self::try_from_enr_preferring(enr, ip_mode, true)
+    }
+
+    /// Like [`NodeContact::try_from_enr`], with `prefer_ipv6` choosing the address of a dual
+    /// stack ENR when running in dual stack.
+    pub(crate) fn try_from_enr_preferring(
+        enr: impl Into<Arc<Enr>>,
+        ip_mode: IpMode,
+        prefer_ipv6: bool,
+    ) -> Result<Self, NonContactable> {
+        let enr = enr.into();
+        let socket_addr = match ip_mode.get_contactable_addr_preferring(&enr, prefer_ipv6) {
             Some(socket_addr) => socket_addr,
             None => return Err(NonContactable { enr }),
         };
@@ -116,25 +129,7 @@ impl NodeContact {
         let ip_addr = ip_addr.ok_or("An IP address must be specified in the multiaddr")?;
         let peer_id = p2p.ok_or("The p2p protocol must be specified in the multiaddr")?;
 
-        let public_key: CombinedPublicKey = {
-            let pk = PublicKey::try_decode_protobuf(&peer_id.to_bytes()[2..])
-                .map_err(|_| "Invalid public key")?;
-            match pk.key_type() {
-                KeyType::Secp256k1 => enr::k256::ecdsa::VerifyingKey::from_sec1_bytes(
-                    &pk.try_into_secp256k1()
-                        .expect("Must be secp256k1")
-                        .to_bytes_uncompressed(),
-                )
-                .expect("Libp2p key conversion, always valid")
-                .into(),
-                KeyType::Ed25519 => enr::ed25519_dalek::VerifyingKey::from_bytes(
-                    &pk.try_into_ed25519().expect("Must be ed25519").to_bytes(),
-                )
-                .expect("Libp2p key conversion, always valid")
-                .into(),
-                _ => return Err("The key type is not supported"),
-            }
-        };
+        let public_key = crate::enr_ext::public_key_from_peer_id(&peer_id)?;
 
         Ok(NodeContact {
             public_key,
@@ -160,6 +155,7 @@ impl std::fmt::Display for NodeContact {
 
 /// A representation of an unsigned contactable node.
 #[derive(PartialEq, Hash, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeAddress {
     /// The destination socket address.
     pub socket_addr: SocketAddr,
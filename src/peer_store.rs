@@ -0,0 +1,391 @@
+//! Persistence of the routing table and the ban list across restarts.
+//!
+//! The service periodically checkpoints its known ENRs and active bans, together with metadata
+//! supplied by the application, to a [`PeerStore`] and repopulates the routing table and ban list
+//! from it when started. Bans are checkpointed shortly after they change, so that restarting the
+//! node does not lift the bans on peers that are attacking it.
+//! Applications can back the store with their own database by implementing the trait. A simple
+//! file-based implementation is provided by [`FilePeerStore`].
+//!
+//! To move a node between hosts, [`crate::Discv5::export_state`] captures a [`StateSnapshot`],
+//! which adds the ENR store and the metrics to what a peer store holds, and
+//! [`crate::Discv5::import_state`] restores it on the new host.
+use crate::time::{Clock, Instant};
+use crate::{metrics::Metrics, Enr, PermitBanList};
+use enr::NodeId;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+use tracing::{debug, error, warn};
+
+/// The state saved to and loaded from a [`PeerStore`].
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerStoreSnapshot {
+    /// The ENRs of the routing table.
+    pub enrs: Vec<Enr>,
+    /// Arbitrary application data, set via `Discv5::set_peer_store_metadata`.
+    pub metadata: BTreeMap<String, Vec<u8>>,
+    /// Banned node ids, with the time their ban expires. `None` is a permanent ban.
+    pub banned_nodes: Vec<(NodeId, Option<SystemTime>)>,
+    /// Banned IPs, with the time their ban expires. `None` is a permanent ban.
+    pub banned_ips: Vec<(IpAddr, Option<SystemTime>)>,
+}
+
+impl PeerStoreSnapshot {
+    /// Records the active bans of the ban list, with their expiries converted to wall clock time.
+    pub(crate) fn set_bans(&mut self, list: &PermitBanList, clock: &dyn Clock) {
+        let now = (clock.now(), clock.system_time());
+        self.banned_nodes = list
+            .ban_nodes()
+            .iter()
+            .filter_map(|(node_id, expiry)| Some((*node_id, to_system_time(*expiry, now)?)))
+            .collect();
+        self.banned_ips = list
+            .ban_ips()
+            .iter()
+            .filter_map(|(ip, expiry)| Some((*ip, to_system_time(*expiry, now)?)))
+            .collect();
+    }
+
+    /// Adds the bans of the snapshot that have not expired to the ban list. Permitted nodes and
+    /// IPs are not banned.
+    pub(crate) fn restore_bans(&self, list: &mut PermitBanList, clock: &dyn Clock) {
+        let now = (clock.now(), clock.system_time());
+        for (node_id, expiry) in &self.banned_nodes {
+            if let Some(expiry) = to_instant(*expiry, now) {
+                if !list.permit_nodes.contains(node_id) {
+                    list.ban_node(*node_id, expiry);
+                }
+            }
+        }
+        for (ip, expiry) in &self.banned_ips {
+            if let Some(expiry) = to_instant(*expiry, now) {
+                if !list.permit_ips.contains(ip) {
+                    list.ban_ip(*ip, expiry);
+                }
+            }
+        }
+    }
+}
+
+/// A snapshot of the discovery state, taken by [`crate::Discv5::export_state`].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateSnapshot {
+    /// The routing table, the active bans and the application metadata, as saved to a
+    /// [`PeerStore`].
+    pub peers: PeerStoreSnapshot,
+    /// The ENRs of the ENR store. Empty if the ENR store is disabled.
+    pub stored_enrs: Vec<Enr>,
+    /// The metrics at the time of the export. These are informational and not restored on
+    /// import.
+    pub metrics: Metrics,
+}
+
+/// Converts the expiry of an active ban to wall clock time. Returns `None` if the ban has expired.
+fn to_system_time(
+    expiry: Option<Instant>,
+    (now, system_now): (Instant, SystemTime),
+) -> Option<Option<SystemTime>> {
+    match expiry {
+        None => Some(None),
+        Some(expiry) if expiry > now => Some(Some(system_now + (expiry - now))),
+        Some(_) => None,
+    }
+}
+
+/// Converts the wall clock expiry of a persisted ban back to an `Instant`. Returns `None` if the
+/// ban has expired.
+fn to_instant(
+    expiry: Option<SystemTime>,
+    (now, system_now): (Instant, SystemTime),
+) -> Option<Option<Instant>> {
+    match expiry {
+        None => Some(None),
+        Some(expiry) => match expiry.duration_since(system_now) {
+            Ok(remaining) if !remaining.is_zero() => Some(Some(now + remaining)),
+            _ => None,
+        },
+    }
+}
+
+/// A backend persisting the discovery state across restarts.
+pub trait PeerStore: Send + Sync {
+    /// Loads the most recently saved snapshot. A store that has never been saved to returns an
+    /// empty snapshot.
+    fn load(&self) -> io::Result<PeerStoreSnapshot>;
+
+    /// Saves a snapshot, replacing the previous one.
+    fn save(&self, snapshot: &PeerStoreSnapshot) -> io::Result<()>;
+}
+
+/// Saves snapshots to a [`PeerStore`] on a blocking thread, so that slow storage doesn't hold up
+/// the service. Snapshots taken while another is being saved replace each other, only the latest
+/// is saved next. Snapshots still pending when this is dropped are saved before the task exits.
+pub(crate) struct PeerStoreCheckpointer {
+    latest: watch::Sender<Option<PeerStoreSnapshot>>,
+}
+
+impl PeerStoreCheckpointer {
+    /// Spawns the task saving the snapshots to `store`.
+    pub fn spawn(store: Arc<dyn PeerStore>) -> Self {
+        let (latest, mut latest_recv) = watch::channel::<Option<PeerStoreSnapshot>>(None);
+        tokio::spawn(async move {
+            while latest_recv.changed().await.is_ok() {
+                let Some(snapshot) = latest_recv.borrow_and_update().clone() else {
+                    continue;
+                };
+                let store = store.clone();
+                let enrs = snapshot.enrs.len();
+                match tokio::task::spawn_blocking(move || store.save(&snapshot)).await {
+                    Ok(Ok(())) => debug!(enrs, "Checkpointed the peer store"),
+                    Ok(Err(error)) => warn!(%error, "Failed to checkpoint the peer store"),
+                    Err(e) => error!(error = %e, "Peer store checkpoint failed"),
+                }
+            }
+        });
+        PeerStoreCheckpointer { latest }
+    }
+
+    /// Queues the snapshot to be saved.
+    pub fn checkpoint(&self, snapshot: PeerStoreSnapshot) {
+        self.latest.send_replace(Some(snapshot));
+    }
+}
+
+/// A [`PeerStore`] keeping the snapshot in a single text file, one entry per line.
+///
+/// The file is replaced atomically on each save, so a crash mid-save leaves the previous snapshot
+/// intact.
+#[derive(Debug, Clone)]
+pub struct FilePeerStore {
+    path: PathBuf,
+}
+
+impl FilePeerStore {
+    /// Creates a store persisting to the file at `path`. The file is created on the first save.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FilePeerStore { path: path.into() }
+    }
+
+    /// The temporary file a snapshot is written to before replacing the store.
+    fn temp_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".tmp");
+        path.into()
+    }
+}
+
+impl PeerStore for FilePeerStore {
+    fn load(&self) -> io::Result<PeerStoreSnapshot> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(PeerStoreSnapshot::default())
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut snapshot = PeerStoreSnapshot::default();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("enr"), Some(enr), None) => {
+                    snapshot.enrs.push(enr.parse().map_err(invalid_data)?);
+                }
+                (Some("meta"), Some(key), Some(value)) => {
+                    let key = String::from_utf8(hex::decode(key).map_err(invalid_data)?)
+                        .map_err(invalid_data)?;
+                    snapshot
+                        .metadata
+                        .insert(key, hex::decode(value).map_err(invalid_data)?);
+                }
+                (Some("ban-node"), Some(node_id), Some(expiry)) => {
+                    let node_id = NodeId::parse(&hex::decode(node_id).map_err(invalid_data)?)
+                        .map_err(invalid_data)?;
+                    snapshot.banned_nodes.push((node_id, parse_expiry(expiry)?));
+                }
+                (Some("ban-ip"), Some(ip), Some(expiry)) => {
+                    let ip = ip.parse().map_err(invalid_data)?;
+                    snapshot.banned_ips.push((ip, parse_expiry(expiry)?));
+                }
+                (None, ..) => {}
+                _ => return Err(invalid_data(format!("Malformed peer store line: {line}"))),
+            }
+        }
+        Ok(snapshot)
+    }
+
+    fn save(&self, snapshot: &PeerStoreSnapshot) -> io::Result<()> {
+        let temp_path = self.temp_path();
+        let mut file = io::BufWriter::new(File::create(&temp_path)?);
+        for enr in &snapshot.enrs {
+            writeln!(file, "enr {}", enr.to_base64())?;
+        }
+        for (key, value) in &snapshot.metadata {
+            writeln!(file, "meta {} {}", hex::encode(key), hex::encode(value))?;
+        }
+        for (node_id, expiry) in &snapshot.banned_nodes {
+            writeln!(
+                file,
+                "ban-node {} {}",
+                hex::encode(node_id.raw()),
+                format_expiry(*expiry)
+            )?;
+        }
+        for (ip, expiry) in &snapshot.banned_ips {
+            writeln!(file, "ban-ip {} {}", ip, format_expiry(*expiry))?;
+        }
+        file.into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+        fs::rename(temp_path, &self.path)
+    }
+}
+
+/// Ban expiries are stored as milliseconds since the unix epoch, `-` being a permanent ban.
+fn format_expiry(expiry: Option<SystemTime>) -> String {
+    match expiry {
+        Some(expiry) => expiry
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+            .to_string(),
+        None => "-".to_string(),
+    }
+}
+
+fn parse_expiry(expiry: &str) -> io::Result<Option<SystemTime>> {
+    if expiry == "-" {
+        return Ok(None);
+    }
+    let millis: u64 = expiry.parse().map_err(invalid_data)?;
+    Ok(Some(UNIX_EPOCH + Duration::from_millis(millis)))
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::ManualClock;
+    use enr::CombinedKey;
+
+    #[test]
+    fn test_file_peer_store_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("discv5-peer-store-{}", rand::random::<u64>()));
+        let store = FilePeerStore::new(&path);
+        assert_eq!(store.load().unwrap(), PeerStoreSnapshot::default());
+
+        let key = CombinedKey::generate_secp256k1();
+        let snapshot = PeerStoreSnapshot {
+            enrs: vec![Enr::builder()
+                .ip4("10.0.0.1".parse().unwrap())
+                .udp4(9000)
+                .build(&key)
+                .unwrap()],
+            metadata: BTreeMap::from([("fork digest".to_string(), vec![1, 2, 3])]),
+            banned_nodes: vec![(NodeId::random(), None)],
+            banned_ips: vec![(
+                "10.0.0.2".parse().unwrap(),
+                Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_000)),
+            )],
+        };
+        store.save(&snapshot).unwrap();
+        assert_eq!(store.load().unwrap(), snapshot);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_ban_restore_prunes_expired() {
+        let clock = ManualClock::default();
+        let mut list = PermitBanList::default();
+        let permanent = NodeId::random();
+        let permitted = NodeId::random();
+        let expired: IpAddr = "10.0.0.1".parse().unwrap();
+        let expiring: IpAddr = "10.0.0.2".parse().unwrap();
+        let active: IpAddr = "10.0.0.3".parse().unwrap();
+        list.ban_node(permanent, None);
+        list.ban_node(permitted, Some(clock.now() + Duration::from_secs(3600)));
+        list.ban_ip(expired, Some(clock.now()));
+        list.ban_ip(expiring, Some(clock.now() + Duration::from_secs(60)));
+        list.ban_ip(active, Some(clock.now() + Duration::from_secs(3600)));
+
+        let mut snapshot = PeerStoreSnapshot::default();
+        snapshot.set_bans(&list, &clock);
+        assert_eq!(snapshot.banned_nodes.len(), 2);
+        assert_eq!(snapshot.banned_ips.len(), 2);
+        assert!(snapshot.banned_ips.contains(&(
+            expiring,
+            Some(clock.system_time() + Duration::from_secs(60))
+        )));
+
+        // Time passes until the snapshot is restored.
+        clock.advance(Duration::from_secs(60));
+        let mut restored = PermitBanList::default();
+        restored.permit_nodes.insert(permitted);
+        snapshot.restore_bans(&mut restored, &clock);
+        assert_eq!(restored.ban_nodes().get(&permanent), Some(&None));
+        assert!(!restored.ban_nodes().contains_key(&permitted));
+        assert_eq!(
+            restored.ban_ips().get(&active),
+            Some(&Some(clock.now() + Duration::from_secs(3540)))
+        );
+        assert!(!restored.ban_ips().contains_key(&expiring));
+        assert!(!restored.ban_ips().contains_key(&expired));
+    }
+
+    /// A store recording the metadata of the snapshots saved.
+    #[derive(Default)]
+    struct RecordingStore {
+        saved: parking_lot::Mutex<Vec<BTreeMap<String, Vec<u8>>>>,
+    }
+
+    impl PeerStore for RecordingStore {
+        fn load(&self) -> io::Result<PeerStoreSnapshot> {
+            Ok(PeerStoreSnapshot::default())
+        }
+
+        fn save(&self, snapshot: &PeerStoreSnapshot) -> io::Result<()> {
+            self.saved.lock().push(snapshot.metadata.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_checkpointer_saves_latest_snapshot() {
+        let store = Arc::new(RecordingStore::default());
+        let checkpointer = PeerStoreCheckpointer::spawn(store.clone());
+        let snapshot = |n: u8| PeerStoreSnapshot {
+            metadata: BTreeMap::from([("n".to_owned(), vec![n])]),
+            ..Default::default()
+        };
+
+        // Snapshots taken before the previous one is saved replace it.
+        for n in 0..3 {
+            checkpointer.checkpoint(snapshot(n));
+        }
+        // The pending snapshot is saved after the checkpointer is dropped.
+        drop(checkpointer);
+        for _ in 0..100 {
+            if !store.saved.lock().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*store.saved.lock(), vec![snapshot(2).metadata]);
+    }
+}
@@ -1,27 +1,192 @@
 use crate::node_info::NodeAddress;
+use crate::time::Instant;
 use enr::NodeId;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     net::IpAddr,
-    time::Instant,
 };
+use tokio::sync::watch;
+
+lazy_static! {
+    /// Signals that a ban with an expiry was added, which may expire before the bans the
+    /// handlers are waiting on.
+    static ref BAN_EXPIRY_ADDED: watch::Sender<()> = watch::channel(()).0;
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct PermitBanList {
     /// A set of IPs which pass all filters.
     pub permit_ips: HashSet<IpAddr>,
-    /// A set of IPs whose packets get dropped instantly.
-    pub ban_ips: HashMap<IpAddr, Option<Instant>>,
+    /// A set of IPs whose packets get dropped instantly, with the instant their ban expires.
+    ban_ips: HashMap<IpAddr, Option<Instant>>,
     /// A set of NodeIds which pass all filters.
     pub permit_nodes: HashSet<NodeId>,
-    /// A set of NodeIds whose packets get dropped instantly.
-    pub ban_nodes: HashMap<NodeId, Option<Instant>>,
+    /// A set of NodeIds whose packets get dropped instantly, with the instant their ban expires.
+    ban_nodes: HashMap<NodeId, Option<Instant>>,
+    /// The bans with an expiry, by the instant they expire. Entries of bans that have been
+    /// lifted or renewed since are skipped when they come due.
+    expiries: BTreeMap<Instant, Vec<Banned>>,
+    /// Counts the changes to the bans made through the methods of the list.
+    ban_changes: u64,
+}
+
+/// An IP or node with a ban that expires.
+#[derive(Debug, Clone, Copy)]
+enum Banned {
+    Ip(IpAddr),
+    Node(NodeId),
 }
 
 impl PermitBanList {
     pub fn ban(&mut self, node_address: NodeAddress, time_to_unban: Option<Instant>) {
-        self.ban_ips
-            .insert(node_address.socket_addr.ip(), time_to_unban);
-        self.ban_nodes.insert(node_address.node_id, time_to_unban);
+        self.ban_ip(node_address.socket_addr.ip(), time_to_unban);
+        self.ban_node(node_address.node_id, time_to_unban);
+    }
+
+    /// Bans the IP until `time_to_unban`, or permanently.
+    pub fn ban_ip(&mut self, ip: IpAddr, time_to_unban: Option<Instant>) {
+        self.ban_ips.insert(ip, time_to_unban);
+        self.ban_changes += 1;
+        self.schedule_expiry(Banned::Ip(ip), time_to_unban);
+    }
+
+    /// Bans the node until `time_to_unban`, or permanently.
+    pub fn ban_node(&mut self, node_id: NodeId, time_to_unban: Option<Instant>) {
+        self.ban_nodes.insert(node_id, time_to_unban);
+        self.ban_changes += 1;
+        self.schedule_expiry(Banned::Node(node_id), time_to_unban);
+    }
+
+    /// The banned IPs, with the instant their ban expires. `None` is a permanent ban.
+    pub fn ban_ips(&self) -> &HashMap<IpAddr, Option<Instant>> {
+        &self.ban_ips
+    }
+
+    /// The banned nodes, with the instant their ban expires. `None` is a permanent ban.
+    pub fn ban_nodes(&self) -> &HashMap<NodeId, Option<Instant>> {
+        &self.ban_nodes
+    }
+
+    /// Lifts the ban of the IP. Returns whether it was banned.
+    pub fn unban_ip(&mut self, ip: &IpAddr) -> bool {
+        let banned = self.ban_ips.remove(ip).is_some();
+        self.ban_changes += u64::from(banned);
+        banned
+    }
+
+    /// Lifts the ban of the node. Returns whether it was banned.
+    pub fn unban_node(&mut self, node_id: &NodeId) -> bool {
+        let banned = self.ban_nodes.remove(node_id).is_some();
+        self.ban_changes += u64::from(banned);
+        banned
+    }
+
+    /// The number of changes to the bans, which changes whenever the bans do.
+    pub(crate) fn ban_changes(&self) -> u64 {
+        self.ban_changes
+    }
+
+    fn schedule_expiry(&mut self, banned: Banned, time_to_unban: Option<Instant>) {
+        if let Some(expiry) = time_to_unban {
+            self.expiries.entry(expiry).or_default().push(banned);
+            BAN_EXPIRY_ADDED.send_replace(());
+        }
+    }
+
+    /// The instant the next ban expires, if any ban has an expiry.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.expiries.keys().next().copied()
+    }
+
+    /// Lifts the bans that have expired by `now`.
+    pub fn remove_expired_bans(&mut self, now: Instant) {
+        while let Some(entry) = self.expiries.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+            let (expiry, due) = entry.remove_entry();
+            for banned in due {
+                // The ban may have been lifted or renewed since it was scheduled.
+                match banned {
+                    Banned::Ip(ip) => {
+                        if self.ban_ips.get(&ip) == Some(&Some(expiry)) {
+                            self.unban_ip(&ip);
+                        }
+                    }
+                    Banned::Node(node_id) => {
+                        if self.ban_nodes.get(&node_id) == Some(&Some(expiry)) {
+                            self.unban_node(&node_id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Notifies the subscribers of [`Self::subscribe_expiries`] that the global list was
+    /// replaced.
+    pub(crate) fn notify_expiries() {
+        BAN_EXPIRY_ADDED.send_replace(());
+    }
+
+    /// Notifies of bans with an expiry added to any list, which may change the
+    /// [`Self::next_expiry`] of the global list.
+    pub(crate) fn subscribe_expiries() -> watch::Receiver<()> {
+        BAN_EXPIRY_ADDED.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::{Clock, ManualClock};
+    use std::time::Duration;
+
+    #[test]
+    fn expired_bans_are_lifted() {
+        let clock = ManualClock::default();
+        let mut list = PermitBanList::default();
+        let expiring = NodeId::random();
+        let permanent = NodeId::random();
+        list.ban_node(expiring, Some(clock.now() + Duration::from_secs(60)));
+        list.ban_node(permanent, None);
+        assert_eq!(
+            list.next_expiry(),
+            Some(clock.now() + Duration::from_secs(60))
+        );
+
+        list.remove_expired_bans(clock.now());
+        assert_eq!(list.ban_nodes.len(), 2);
+
+        clock.advance(Duration::from_secs(60));
+        list.remove_expired_bans(clock.now());
+        assert!(!list.ban_nodes.contains_key(&expiring));
+        assert!(list.ban_nodes.contains_key(&permanent));
+        assert_eq!(list.next_expiry(), None);
+    }
+
+    #[test]
+    fn renewed_bans_outlast_their_first_expiry() {
+        let clock = ManualClock::default();
+        let mut list = PermitBanList::default();
+        let ip = "192.0.2.1".parse().unwrap();
+        list.ban_ip(ip, Some(clock.now() + Duration::from_millis(200)));
+        list.ban_ip(ip, Some(clock.now() + Duration::from_millis(500)));
+
+        clock.advance(Duration::from_millis(200));
+        list.remove_expired_bans(clock.now());
+        assert!(list.ban_ips.contains_key(&ip));
+
+        clock.advance(Duration::from_millis(300));
+        list.remove_expired_bans(clock.now());
+        assert!(!list.ban_ips.contains_key(&ip));
+
+        // Bans lifted early are not lifted again when their expiry comes due.
+        list.ban_ip(ip, Some(clock.now() + Duration::from_millis(100)));
+        assert!(list.unban_ip(&ip));
+        list.ban_ip(ip, None);
+        clock.advance(Duration::from_millis(100));
+        list.remove_expired_bans(clock.now());
+        assert_eq!(list.ban_ips().get(&ip), Some(&None));
     }
 }
@@ -30,8 +30,9 @@ pub(crate) use peers::{
 };
 
 use crate::kbucket::{Key, PredicateKey};
+use crate::time::{Clock, Instant};
 use fnv::FnvHashMap;
-use std::time::{Duration, Instant};
+use std::{sync::Arc, time::Duration};
 
 pub trait TargetKey<TNodeId> {
     fn key(&self) -> Key<TNodeId>;
@@ -46,6 +47,8 @@ pub struct QueryPool<TTarget, TNodeId, TResult> {
     next_id: usize,
     query_timeout: Duration,
     queries: FnvHashMap<QueryId, Query<TTarget, TNodeId, TResult>>,
+    /// The clock query and peer timeouts are measured against.
+    clock: Arc<dyn Clock>,
 }
 
 /// The observable states emitted by [`QueryPool::poll`].
@@ -69,11 +72,12 @@ where
     TResult: Into<TNodeId> + Clone,
 {
     /// Creates a new `QueryPool` with the given configuration.
-    pub fn new(query_timeout: Duration) -> Self {
+    pub fn new(query_timeout: Duration, clock: Arc<dyn Clock>) -> Self {
         QueryPool {
             next_id: 0,
             query_timeout,
             queries: Default::default(),
+            clock,
         }
     }
 
@@ -130,7 +134,7 @@ where
 
     /// Polls the pool to advance the queries.
     pub fn poll(&mut self) -> QueryPoolState<'_, TTarget, TNodeId, TResult> {
-        let now = Instant::now();
+        let now = self.clock.now();
         let mut finished = None;
         let mut waiting = None;
         let mut timeout = None;
@@ -241,15 +245,29 @@ where
     /// Informs the query that the attempt to contact `peer` succeeded,
     /// possibly resulting in new peers that should be incorporated into
     /// the query, if applicable.
-    pub fn on_success<'a>(&mut self, peer: &TNodeId, new_peers: &'a [TResult])
-    where
+    pub fn on_success<'a>(
+        &mut self,
+        peer: &TNodeId,
+        new_peers: impl IntoIterator<Item = &'a TResult>,
+    ) where
+        TResult: 'a,
         &'a TResult: Into<TNodeId>,
     {
         match &mut self.peer_iter {
             QueryPeerIter::FindNode(iter) => {
-                iter.on_success(peer, new_peers.iter().map(|result| result.into()).collect())
+                iter.on_success(peer, new_peers.into_iter().map(Into::into).collect())
+            }
+            QueryPeerIter::Predicate(iter) => {
+                iter.on_success(peer, &new_peers.into_iter().collect::<Vec<_>>())
             }
-            QueryPeerIter::Predicate(iter) => iter.on_success(peer, new_peers),
+        }
+    }
+
+    /// Whether `peer` is among the peers the query tracks.
+    pub fn contains(&self, peer: &TNodeId) -> bool {
+        match &self.peer_iter {
+            QueryPeerIter::FindNode(iter) => iter.contains(peer),
+            QueryPeerIter::Predicate(iter) => iter.contains(peer),
         }
     }
 
@@ -261,6 +279,14 @@ where
         }
     }
 
+    /// The number of peers that failed to respond among the closest peers returned as results.
+    pub fn num_dead_in_results(&self) -> usize {
+        match &self.peer_iter {
+            QueryPeerIter::FindNode(iter) => iter.num_dead_in_results(),
+            QueryPeerIter::Predicate(iter) => iter.num_dead_in_results(),
+        }
+    }
+
     /// Consumes the query, producing the final `QueryResult`.
     pub fn into_result(self) -> QueryResult<TTarget, impl Iterator<Item = TNodeId>> {
         let peers = match self.peer_iter {
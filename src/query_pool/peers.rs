@@ -44,6 +44,36 @@
 pub mod closest;
 pub mod predicate;
 
+/// The outcome of a peer tracked by a query, for counting the dead entries among its results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerOutcome {
+    /// The peer is one of the results of the query.
+    Result,
+    /// The peer failed to respond.
+    Dead,
+    /// The peer has not been contacted, is still awaited or responded without being a result.
+    Other,
+}
+
+/// Counts the dead peers among the results of a query: the peers that failed to respond and are
+/// closer to the target than the last of the `num_results` results. These are the stale entries
+/// that would otherwise have been returned. `outcomes` is ordered by distance to the target.
+fn dead_in_results(outcomes: impl Iterator<Item = PeerOutcome>, num_results: usize) -> usize {
+    let mut results = 0;
+    let mut dead = 0;
+    for outcome in outcomes {
+        if results >= num_results {
+            break;
+        }
+        match outcome {
+            PeerOutcome::Result => results += 1,
+            PeerOutcome::Dead => dead += 1,
+            PeerOutcome::Other => {}
+        }
+    }
+    dead
+}
+
 /// The state of the query reported by [`closest::FindNodeQuery::next`] or
 /// [`predicate::PredicateQuery::next`].
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -22,13 +22,14 @@
 // https://github.com/libp2p/rust-libp2p
 //
 use super::*;
+use crate::time::Instant;
 use crate::{
     config::Config,
     kbucket::{Distance, Key, MAX_NODES_PER_BUCKET},
 };
 use std::{
     collections::btree_map::{BTreeMap, Entry},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 #[derive(Debug, Clone)]
@@ -73,6 +74,12 @@ pub struct FindNodeQueryConfig {
     /// the peer when evaluating the termination conditions, until and unless a
     /// result is delivered. Defaults to `10` seconds.
     pub peer_timeout: Duration,
+
+    /// The maximum number of peers tracked by the query.
+    ///
+    /// When more peers are discovered, the furthest ones that have not been
+    /// contacted yet are dropped. Defaults to no limit.
+    pub max_peers: Option<usize>,
 }
 
 impl FindNodeQueryConfig {
@@ -81,6 +88,7 @@ impl FindNodeQueryConfig {
             parallelism: config.query_parallelism,
             num_results: MAX_NODES_PER_BUCKET,
             peer_timeout: config.query_peer_timeout,
+            max_peers: config.query_peer_limit,
         }
     }
 }
@@ -185,6 +193,7 @@ where
             progress = self.closest_peers.keys().next() == Some(&distance)
                 || num_closest < self.config.num_results;
         }
+        self.prune();
 
         // Update the query progress.
         self.progress = match self.progress {
@@ -336,6 +345,43 @@ where
             .collect()
     }
 
+    /// The number of peers that failed to respond among the results, see [`dead_in_results`].
+    pub fn num_dead_in_results(&self) -> usize {
+        let outcomes = self.closest_peers.values().map(|peer| match peer.state {
+            QueryPeerState::Succeeded => PeerOutcome::Result,
+            QueryPeerState::Failed | QueryPeerState::Unresponsive => PeerOutcome::Dead,
+            QueryPeerState::NotContacted | QueryPeerState::Waiting(_) => PeerOutcome::Other,
+        });
+        dead_in_results(outcomes, self.config.num_results)
+    }
+
+    /// Whether the peer is tracked by the query.
+    pub fn contains(&self, peer: &TNodeId) -> bool {
+        let key: Key<TNodeId> = peer.clone().into();
+        self.closest_peers
+            .contains_key(&key.distance(&self.target_key))
+    }
+
+    /// Drops the furthest peers that have not been contacted while the query
+    /// tracks more than `max_peers`.
+    fn prune(&mut self) {
+        let Some(max_peers) = self.config.max_peers else {
+            return;
+        };
+        let excess = self.closest_peers.len().saturating_sub(max_peers);
+        let pruned: Vec<Distance> = self
+            .closest_peers
+            .iter()
+            .rev()
+            .filter(|(_, peer)| matches!(peer.state, QueryPeerState::NotContacted))
+            .map(|(distance, _)| *distance)
+            .take(excess)
+            .collect();
+        for distance in pruned {
+            self.closest_peers.remove(&distance);
+        }
+    }
+
     /// Checks if the query is at capacity w.r.t. the permitted parallelism.
     ///
     /// While the query is stalled, up to `num_results` parallel requests
@@ -460,6 +506,7 @@ mod tests {
             parallelism: g.gen_range(1, 10),
             num_results: g.gen_range(1, 25),
             peer_timeout: Duration::from_secs(g.gen_range(10, 30)),
+            max_peers: None,
         };
         FindNodeQuery::with_config(config, target.into(), known_closest_peers)
     }
@@ -704,4 +751,81 @@ mod tests {
 
         QuickCheck::new().tests(10).quickcheck(prop as fn(_) -> _)
     }
+
+    #[test]
+    fn max_peers() {
+        let target: Key<NodeId> = NodeId::random().into();
+        let config = FindNodeQueryConfig {
+            parallelism: 3,
+            num_results: 4,
+            peer_timeout: Duration::from_secs(10),
+            max_peers: Some(8),
+        };
+        let known: Vec<NodeId> = random_nodes(4).collect();
+        let mut query =
+            FindNodeQuery::with_config(config, target.clone(), known.iter().map(|id| (*id).into()));
+
+        let peer = match query.next(Instant::now()) {
+            QueryState::Waiting(Some(peer)) => peer,
+            state => panic!("Unexpected query state: {:?}", state),
+        };
+        let discovered: Vec<NodeId> = random_nodes(20).collect();
+        query.on_success(&peer, discovered.clone());
+
+        // The contacted peer is kept, along with the closest of the others.
+        assert_eq!(query.closest_peers.len(), 8);
+        assert!(query.contains(&peer));
+        let mut uncontacted: Vec<Key<NodeId>> = known
+            .iter()
+            .chain(&discovered)
+            .filter(|id| **id != peer)
+            .map(|id| (*id).into())
+            .collect();
+        uncontacted.sort_by_key(|key| key.distance(&target));
+        for key in &uncontacted[..7] {
+            assert!(query.contains(key.preimage()));
+        }
+    }
+
+    #[test]
+    fn dead_peers_counted_among_results() {
+        let target: Key<NodeId> = NodeId::random().into();
+        let config = FindNodeQueryConfig {
+            parallelism: 5,
+            num_results: 2,
+            peer_timeout: Duration::from_secs(10),
+            max_peers: None,
+        };
+        let mut peers: Vec<Key<NodeId>> = random_nodes(6).map(Key::from).collect();
+        peers.sort_by_key(|key| key.distance(&target));
+        let (farthest, others) = peers.split_last().unwrap();
+
+        // The farthest peer responds with the others, which are all contacted.
+        let mut query = FindNodeQuery::with_config(config, target, vec![farthest.clone()]);
+        assert!(matches!(
+            query.next(Instant::now()),
+            QueryState::Waiting(Some(_))
+        ));
+        query.on_success(
+            farthest.preimage(),
+            others.iter().map(|key| *key.preimage()).collect(),
+        );
+        for _ in others {
+            assert!(matches!(
+                query.next(Instant::now()),
+                QueryState::Waiting(Some(_))
+            ));
+        }
+
+        // The first and third closest peers are dead, and so is the fifth, which is beyond the
+        // two results.
+        for (i, key) in others.iter().enumerate() {
+            if i % 2 == 0 {
+                query.on_failure(key.preimage());
+            } else {
+                query.on_success(key.preimage(), Vec::new());
+            }
+        }
+        assert_eq!(query.num_dead_in_results(), 2);
+    }
 }
@@ -1,11 +1,12 @@
 use super::*;
+use crate::time::Instant;
 use crate::{
     config::Config,
     kbucket::{Distance, Key, PredicateKey, MAX_NODES_PER_BUCKET},
 };
 use std::{
     collections::btree_map::{BTreeMap, Entry},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 pub(crate) struct PredicateQuery<TNodeId, TResult> {
@@ -52,6 +53,12 @@ pub(crate) struct PredicateQueryConfig {
     /// the peer when evaluating the termination conditions, until and unless a
     /// result is delivered. Defaults to `10` seconds.
     pub(crate) peer_timeout: Duration,
+
+    /// The maximum number of peers tracked by the query.
+    ///
+    /// When more peers are discovered, the furthest ones that have not been
+    /// contacted yet are dropped. Defaults to no limit.
+    pub(crate) max_peers: Option<usize>,
 }
 
 impl PredicateQueryConfig {
@@ -60,6 +67,7 @@ impl PredicateQueryConfig {
             parallelism: config.query_parallelism,
             num_results: MAX_NODES_PER_BUCKET,
             peer_timeout: config.query_peer_timeout,
+            max_peers: config.query_peer_limit,
         }
     }
 }
@@ -121,7 +129,7 @@ where
     /// If the query is finished, the query is not currently waiting for a
     /// result from `peer`, or a result for `peer` has already been reported,
     /// calling this function has no effect.
-    pub fn on_success<'a>(&mut self, node_id: &TNodeId, closer_peers: &'a [TResult])
+    pub fn on_success<'a>(&mut self, node_id: &TNodeId, closer_peers: &[&'a TResult])
     where
         &'a TResult: Into<TNodeId>,
     {
@@ -161,7 +169,7 @@ where
         let num_closest = self.closest_peers.len();
 
         // Incorporate the reported closer peers into the query.
-        for result in closer_peers {
+        for result in closer_peers.iter().copied() {
             // If ENR satisfies the predicate, add to list of peers that satisfies predicate
             let predicate_match = (self.predicate)(result);
             let key: TNodeId = result.into();
@@ -175,6 +183,7 @@ where
             progress = self.closest_peers.keys().next() == Some(&distance)
                 || num_closest < self.config.num_results;
         }
+        self.prune();
 
         // Update the query progress.
         self.progress = match self.progress {
@@ -223,6 +232,43 @@ where
         }
     }
 
+    /// The number of peers that failed to respond among the results, see [`dead_in_results`].
+    pub fn num_dead_in_results(&self) -> usize {
+        let outcomes = self.closest_peers.values().map(|peer| match peer.state {
+            QueryPeerState::Succeeded if peer.predicate_match => PeerOutcome::Result,
+            QueryPeerState::Failed | QueryPeerState::Unresponsive => PeerOutcome::Dead,
+            _ => PeerOutcome::Other,
+        });
+        dead_in_results(outcomes, self.config.num_results)
+    }
+
+    /// Whether the peer is tracked by the query.
+    pub fn contains(&self, peer: &TNodeId) -> bool {
+        let key: Key<TNodeId> = peer.clone().into();
+        self.closest_peers
+            .contains_key(&key.distance(&self.target_key))
+    }
+
+    /// Drops the furthest peers that have not been contacted while the query
+    /// tracks more than `max_peers`.
+    fn prune(&mut self) {
+        let Some(max_peers) = self.config.max_peers else {
+            return;
+        };
+        let excess = self.closest_peers.len().saturating_sub(max_peers);
+        let pruned: Vec<Distance> = self
+            .closest_peers
+            .iter()
+            .rev()
+            .filter(|(_, peer)| matches!(peer.state, QueryPeerState::NotContacted))
+            .map(|(distance, _)| *distance)
+            .take(excess)
+            .collect();
+        for distance in pruned {
+            self.closest_peers.remove(&distance);
+        }
+    }
+
     /// Advances the state of the query, potentially getting a new peer to contact.
     ///
     /// See [`QueryState`].
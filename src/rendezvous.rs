@@ -0,0 +1,85 @@
+//! Service discovery over topic advertisement.
+//!
+//! A service is advertised under the topic derived from its name, and its metadata is published
+//! in the ENR of the provider under a key derived from the same name. A node found for the topic
+//! is only reported as a provider if its ENR carries the key, so that registrars cannot pass off
+//! nodes that do not offer the service, and the metadata comes signed by the provider itself.
+//! See [`crate::Discv5::advertise_service`] and [`crate::Discv5::find_service`].
+use crate::{enr_ext::EnrExt, rpc::TopicHash, Enr};
+use enr::NodeId;
+use std::{collections::HashMap, time::Duration};
+
+/// The time between the topic queries of [`crate::Discv5::find_service`].
+pub(crate) const SERVICE_QUERY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The topic a service is advertised under.
+pub fn service_topic(name: &str) -> TopicHash {
+    TopicHash::new(&format!("service/{}", name))
+}
+
+/// The ENR key the metadata of a service is published under.
+pub fn service_enr_key(name: &str) -> String {
+    format!("svc/{}", name)
+}
+
+/// The metadata a provider publishes for a service, or `None` if it does not provide it.
+pub fn service_metadata(enr: &Enr, name: &str) -> Option<Vec<u8>> {
+    enr.get_bytes(&service_enr_key(name))
+}
+
+/// Filters the nodes found for a service down to verified providers not reported before.
+pub(crate) struct Providers {
+    name: String,
+    /// The sequence number of the last ENR reported for each provider.
+    seen: HashMap<NodeId, u64>,
+}
+
+impl Providers {
+    pub fn new(name: String) -> Self {
+        Providers {
+            name,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Whether to report the node. A provider is reported again when its ENR is updated.
+    pub fn accept(&mut self, enr: &Enr) -> bool {
+        if service_metadata(enr, &self.name).is_none() {
+            return false;
+        }
+        let seq = enr.seq();
+        if self
+            .seen
+            .get(&enr.node_id())
+            .is_some_and(|seen| *seen >= seq)
+        {
+            return false;
+        }
+        self.seen.insert(enr.node_id(), seq);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::CombinedKey;
+
+    #[test]
+    fn accepts_verified_providers_once_per_record() {
+        let key = CombinedKey::generate_secp256k1();
+        let mut enr = Enr::builder().build(&key).unwrap();
+        let mut providers = Providers::new("relay".into());
+        assert!(!providers.accept(&enr));
+
+        enr.insert(service_enr_key("relay"), &b"v1".as_slice(), &key)
+            .unwrap();
+        assert!(providers.accept(&enr));
+        assert!(!providers.accept(&enr));
+
+        enr.insert(service_enr_key("relay"), &b"v2".as_slice(), &key)
+            .unwrap();
+        assert!(providers.accept(&enr));
+        assert_eq!(service_metadata(&enr, "relay"), Some(b"v2".to_vec()));
+    }
+}
@@ -1,12 +1,17 @@
+use crate::packet::MessageNonce;
 use alloy_rlp::{
     bytes::{Buf, Bytes, BytesMut},
     Decodable, Encodable, Error as DecoderError, Header,
 };
-use enr::{CombinedKey, Enr};
+use enr::{
+    k256::sha2::{Digest, Sha256},
+    CombinedKey, Enr, NodeId,
+};
 use std::{
-    convert::TryInto,
-    net::{IpAddr, Ipv6Addr},
+    convert::{TryFrom, TryInto},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     num::NonZeroU16,
+    sync::Arc,
 };
 use tracing::{debug, warn};
 
@@ -39,6 +44,45 @@ impl RequestId {
     }
 }
 
+/// The hash of a topic name. Topics are advertised at the nodes closest to their hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TopicHash([u8; 32]);
+
+impl TopicHash {
+    /// Hashes a topic name with SHA256.
+    pub fn new(topic: &str) -> Self {
+        TopicHash(Sha256::digest(topic.as_bytes()).into())
+    }
+
+    pub fn from_raw(raw: [u8; 32]) -> Self {
+        TopicHash(raw)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The position of the topic in the keyspace.
+    pub fn as_node_id(&self) -> NodeId {
+        NodeId::new(&self.0)
+    }
+
+    fn decode(payload: &mut &[u8]) -> Result<Self, DecoderError> {
+        let bytes = Bytes::decode(payload)?;
+        bytes[..]
+            .try_into()
+            .map(TopicHash)
+            .map_err(|_| DecoderError::Custom("Invalid topic length"))
+    }
+}
+
+impl std::fmt::Display for TopicHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 /// A combined type representing requests and responses.
 pub enum Message {
@@ -46,6 +90,8 @@ pub enum Message {
     Request(Request),
     /// A Response, which contains the [`RequestId`] of its associated request.
     Response(Response),
+    /// A notification, which does not expect a response.
+    Notification(Notification),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -66,6 +112,31 @@ pub struct Response {
     pub body: ResponseBody,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A message that does not expect a response. These are used by the NAT hole punching
+/// extension.
+pub enum Notification {
+    /// A RELAYINIT notification. Asks the receiver to relay a handshake initiation to the target.
+    RelayInit {
+        /// The ENR of the node initiating the handshake.
+        initiator_enr: Enr<CombinedKey>,
+        /// The node the initiator is attempting to reach.
+        target: NodeId,
+        /// The nonce of the message that timed out when sent to the target.
+        nonce: MessageNonce,
+    },
+    /// A RELAYMSG notification. Forwarded by a relay to the target of a RELAYINIT.
+    RelayMsg {
+        /// The ENR of the node initiating the handshake.
+        initiator_enr: Enr<CombinedKey>,
+        /// The socket the relay observes the initiator at. The target punches towards this socket
+        /// rather than the one advertised in the ENR.
+        initiator_socket: SocketAddr,
+        /// The nonce of the message that timed out when sent to the target.
+        nonce: MessageNonce,
+    },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RequestBody {
     /// A PING request.
@@ -85,6 +156,20 @@ pub enum RequestBody {
         /// The request.
         request: Vec<u8>,
     },
+    /// A REGTOPIC request, asking the receiver to advertise us for a topic.
+    RegisterTopic {
+        /// The topic to advertise.
+        topic: TopicHash,
+        /// Our ENR, which is handed out to nodes searching for the topic.
+        enr: Enr<CombinedKey>,
+        /// The ticket of an earlier attempt, or empty on the first attempt.
+        ticket: Vec<u8>,
+    },
+    /// A TOPICQUERY request, asking for the nodes advertised for a topic.
+    TopicQuery {
+        /// The topic searched for.
+        topic: TopicHash,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -102,14 +187,28 @@ pub enum ResponseBody {
     Nodes {
         /// The total number of responses that make up this response.
         total: u64,
-        /// A list of ENR's returned by the responder.
-        nodes: Vec<Enr<CombinedKey>>,
+        /// A list of ENR's returned by the responder. The records are shared with the routing
+        /// table they are served from.
+        nodes: Vec<Arc<Enr<CombinedKey>>>,
     },
     /// The TALK response.
     Talk {
         /// The response for the talk.
         response: Vec<u8>,
     },
+    /// A TICKET response. The registration may be attempted again with the ticket once the wait
+    /// time has passed.
+    Ticket {
+        /// The ticket, which is opaque to the registrant.
+        ticket: Vec<u8>,
+        /// The time to wait in seconds.
+        wait_time: u64,
+    },
+    /// A REGCONFIRMATION response. The registrant is now advertised for the topic.
+    RegisterConfirmation {
+        /// The topic advertised.
+        topic: TopicHash,
+    },
 }
 
 impl Request {
@@ -118,6 +217,8 @@ impl Request {
             RequestBody::Ping { .. } => 1,
             RequestBody::FindNode { .. } => 3,
             RequestBody::Talk { .. } => 5,
+            RequestBody::RegisterTopic { .. } => 9,
+            RequestBody::TopicQuery { .. } => 12,
         }
     }
 
@@ -165,6 +266,32 @@ impl Request {
                 buf.extend_from_slice(&list);
                 buf
             }
+            RequestBody::RegisterTopic { topic, enr, ticket } => {
+                let mut list = Vec::<u8>::new();
+                id.as_bytes().encode(&mut list);
+                topic.as_bytes().as_slice().encode(&mut list);
+                enr.encode(&mut list);
+                ticket.as_slice().encode(&mut list);
+                let header = Header {
+                    list: true,
+                    payload_length: list.len(),
+                };
+                header.encode(&mut buf);
+                buf.extend_from_slice(&list);
+                buf
+            }
+            RequestBody::TopicQuery { topic } => {
+                let mut list = Vec::<u8>::new();
+                id.as_bytes().encode(&mut list);
+                topic.as_bytes().as_slice().encode(&mut list);
+                let header = Header {
+                    list: true,
+                    payload_length: list.len(),
+                };
+                header.encode(&mut buf);
+                buf.extend_from_slice(&list);
+                buf
+            }
         }
     }
 }
@@ -175,6 +302,8 @@ impl Response {
             ResponseBody::Pong { .. } => 2,
             ResponseBody::Nodes { .. } => 4,
             ResponseBody::Talk { .. } => 6,
+            ResponseBody::Ticket { .. } => 10,
+            ResponseBody::RegisterConfirmation { .. } => 11,
         }
     }
 
@@ -183,9 +312,15 @@ impl Response {
         match self.body {
             ResponseBody::Pong { .. } => matches!(req, RequestBody::Ping { .. }),
             ResponseBody::Nodes { .. } => {
-                matches!(req, RequestBody::FindNode { .. })
+                matches!(
+                    req,
+                    RequestBody::FindNode { .. } | RequestBody::TopicQuery { .. }
+                )
             }
             ResponseBody::Talk { .. } => matches!(req, RequestBody::Talk { .. }),
+            ResponseBody::Ticket { .. } | ResponseBody::RegisterConfirmation { .. } => {
+                matches!(req, RequestBody::RegisterTopic { .. })
+            }
         }
     }
 
@@ -219,7 +354,7 @@ impl Response {
                 total.encode(&mut list);
                 if !nodes.is_empty() {
                     let mut out = BytesMut::new();
-                    for node in nodes.clone() {
+                    for node in nodes {
                         node.encode(&mut out);
                     }
                     let tmp_header = Header {
@@ -255,6 +390,133 @@ impl Response {
                 buf.extend_from_slice(&list);
                 buf
             }
+            ResponseBody::Ticket { ticket, wait_time } => {
+                let mut list = Vec::<u8>::new();
+                id.as_bytes().encode(&mut list);
+                ticket.as_slice().encode(&mut list);
+                wait_time.encode(&mut list);
+                let header = Header {
+                    list: true,
+                    payload_length: list.len(),
+                };
+                header.encode(&mut buf);
+                buf.extend_from_slice(&list);
+                buf
+            }
+            ResponseBody::RegisterConfirmation { topic } => {
+                let mut list = Vec::<u8>::new();
+                id.as_bytes().encode(&mut list);
+                topic.as_bytes().as_slice().encode(&mut list);
+                let header = Header {
+                    list: true,
+                    payload_length: list.len(),
+                };
+                header.encode(&mut buf);
+                buf.extend_from_slice(&list);
+                buf
+            }
+        }
+    }
+}
+
+impl Notification {
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            Notification::RelayInit { .. } => 7,
+            Notification::RelayMsg { .. } => 8,
+        }
+    }
+
+    /// Encodes a Message to RLP-encoded bytes.
+    pub fn encode(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(10);
+        let msg_type = self.msg_type();
+        buf.push(msg_type);
+        let mut list = Vec::<u8>::new();
+        match self {
+            Notification::RelayInit {
+                initiator_enr,
+                target,
+                nonce,
+            } => {
+                initiator_enr.encode(&mut list);
+                target.raw().as_slice().encode(&mut list);
+                nonce.as_slice().encode(&mut list);
+            }
+            Notification::RelayMsg {
+                initiator_enr,
+                initiator_socket,
+                nonce,
+            } => {
+                initiator_enr.encode(&mut list);
+                match initiator_socket.ip() {
+                    IpAddr::V4(addr) => addr.encode(&mut list),
+                    IpAddr::V6(addr) => addr.encode(&mut list),
+                };
+                initiator_socket.port().encode(&mut list);
+                nonce.as_slice().encode(&mut list);
+            }
+        }
+        let header = Header {
+            list: true,
+            payload_length: list.len(),
+        };
+        header.encode(&mut buf);
+        buf.extend_from_slice(&list);
+        buf
+    }
+
+    /// Decodes the body of a notification, excluding the message type and list header.
+    fn decode(msg_type: u8, payload: &mut &[u8]) -> Result<Self, DecoderError> {
+        let initiator_enr = Enr::<CombinedKey>::decode(payload)?;
+        let notification = match msg_type {
+            7 => {
+                let target_bytes = Bytes::decode(payload)?;
+                let target: [u8; 32] = target_bytes[..]
+                    .try_into()
+                    .map_err(|_| DecoderError::Custom("Invalid node id length"))?;
+                let nonce = Self::decode_nonce(payload)?;
+                Notification::RelayInit {
+                    initiator_enr,
+                    target: NodeId::new(&target),
+                    nonce,
+                }
+            }
+            8 => {
+                let initiator_socket = Self::decode_socket(payload)?;
+                let nonce = Self::decode_nonce(payload)?;
+                Notification::RelayMsg {
+                    initiator_enr,
+                    initiator_socket,
+                    nonce,
+                }
+            }
+            _ => return Err(DecoderError::Custom("Unknown notification type")),
+        };
+        if !payload.is_empty() {
+            return Err(DecoderError::Custom("Payload should be empty"));
+        }
+        Ok(notification)
+    }
+
+    fn decode_nonce(payload: &mut &[u8]) -> Result<MessageNonce, DecoderError> {
+        let nonce_bytes = Bytes::decode(payload)?;
+        nonce_bytes[..]
+            .try_into()
+            .map_err(|_| DecoderError::Custom("Invalid nonce length"))
+    }
+
+    fn decode_socket(payload: &mut &[u8]) -> Result<SocketAddr, DecoderError> {
+        let ip_bytes = Bytes::decode(payload)?;
+        let ip = match ip_bytes.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(&ip_bytes[..]).expect("Length checked")),
+            16 => IpAddr::from(<[u8; 16]>::try_from(&ip_bytes[..]).expect("Length checked"))
+                .to_canonical(),
+            _ => return Err(DecoderError::Custom("Invalid IP length")),
+        };
+        match u16::decode(payload)? {
+            0 => Err(DecoderError::Custom("Invalid port")),
+            port => Ok(SocketAddr::new(ip, port)),
         }
     }
 }
@@ -270,6 +532,36 @@ impl std::fmt::Display for Message {
         match self {
             Message::Request(request) => write!(f, "{request}"),
             Message::Response(response) => write!(f, "{response}"),
+            Message::Notification(notification) => write!(f, "{notification}"),
+        }
+    }
+}
+
+impl std::fmt::Display for Notification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Notification::RelayInit {
+                initiator_enr,
+                target,
+                nonce,
+            } => write!(
+                f,
+                "RELAYINIT: initiator: {}, target: {}, nonce: {}",
+                initiator_enr.node_id(),
+                target,
+                hex::encode(nonce)
+            ),
+            Notification::RelayMsg {
+                initiator_enr,
+                initiator_socket,
+                nonce,
+            } => write!(
+                f,
+                "RELAYMSG: initiator: {}, socket: {}, nonce: {}",
+                initiator_enr.node_id(),
+                initiator_socket,
+                hex::encode(nonce)
+            ),
         }
     }
 }
@@ -303,6 +595,16 @@ impl std::fmt::Display for ResponseBody {
             ResponseBody::Talk { response } => {
                 write!(f, "Response: Response {}", hex::encode(response))
             }
+            ResponseBody::Ticket { ticket, wait_time } => {
+                write!(
+                    f,
+                    "TICKET: ticket: {}, wait_time: {wait_time}",
+                    hex::encode(ticket)
+                )
+            }
+            ResponseBody::RegisterConfirmation { topic } => {
+                write!(f, "REGCONFIRMATION: topic: {topic}")
+            }
         }
     }
 }
@@ -326,6 +628,14 @@ impl std::fmt::Display for RequestBody {
                 hex::encode(protocol),
                 hex::encode(request)
             ),
+            RequestBody::RegisterTopic { topic, enr, ticket } => write!(
+                f,
+                "REGTOPIC: topic: {}, enr: {}, ticket: {}",
+                topic,
+                enr.node_id(),
+                hex::encode(ticket)
+            ),
+            RequestBody::TopicQuery { topic } => write!(f, "TOPICQUERY: topic: {topic}"),
         }
     }
 }
@@ -335,6 +645,7 @@ impl Message {
         match self {
             Self::Request(request) => request.encode(),
             Self::Response(response) => response.encode(),
+            Self::Notification(notification) => notification.encode(),
         }
     }
 
@@ -356,6 +667,11 @@ impl Message {
             return Err(DecoderError::Custom("Reject the extra data"));
         }
 
+        // Notifications do not carry a request id
+        if let 7 | 8 = msg_type {
+            return Notification::decode(msg_type, payload).map(Message::Notification);
+        }
+
         let id_bytes = Bytes::decode(payload)?;
         let id = RequestId(id_bytes.to_vec());
 
@@ -445,7 +761,7 @@ impl Message {
                     if !header.list {
                         return Err(DecoderError::Custom("Invalid format of header"));
                     }
-                    let mut enr_list_rlp = Vec::<Enr<CombinedKey>>::new();
+                    let mut enr_list_rlp = Vec::<Arc<Enr<CombinedKey>>>::new();
                     while !payload.is_empty() {
                         let node_header = Header::decode(&mut &payload[..])?;
                         if !node_header.list {
@@ -460,7 +776,7 @@ impl Message {
                             &mut &payload[..node_header.payload_length + 2],
                         )?;
                         payload.advance(enr_rlp.size());
-                        enr_list_rlp.append(&mut vec![enr_rlp]);
+                        enr_list_rlp.push(Arc::new(enr_rlp));
                     }
                     if enr_list_rlp.is_empty() {
                         // no records
@@ -502,6 +818,54 @@ impl Message {
                     },
                 })
             }
+            // 7 and 8 are the NAT hole punching notifications.
+            9 => {
+                // RegisterTopic Request
+                let topic = TopicHash::decode(payload)?;
+                let enr = Enr::<CombinedKey>::decode(payload)?;
+                let ticket = Bytes::decode(payload)?.to_vec();
+                if !payload.is_empty() {
+                    return Err(DecoderError::Custom("Payload should be empty"));
+                }
+                Message::Request(Request {
+                    id,
+                    body: RequestBody::RegisterTopic { topic, enr, ticket },
+                })
+            }
+            10 => {
+                // Ticket Response
+                let ticket = Bytes::decode(payload)?.to_vec();
+                let wait_time = u64::decode(payload)?;
+                if !payload.is_empty() {
+                    return Err(DecoderError::Custom("Payload should be empty"));
+                }
+                Message::Response(Response {
+                    id,
+                    body: ResponseBody::Ticket { ticket, wait_time },
+                })
+            }
+            11 => {
+                // RegisterConfirmation Response
+                let topic = TopicHash::decode(payload)?;
+                if !payload.is_empty() {
+                    return Err(DecoderError::Custom("Payload should be empty"));
+                }
+                Message::Response(Response {
+                    id,
+                    body: ResponseBody::RegisterConfirmation { topic },
+                })
+            }
+            12 => {
+                // TopicQuery Request
+                let topic = TopicHash::decode(payload)?;
+                if !payload.is_empty() {
+                    return Err(DecoderError::Custom("Payload should be empty"));
+                }
+                Message::Request(Request {
+                    id,
+                    body: RequestBody::TopicQuery { topic },
+                })
+            }
             _ => {
                 return Err(DecoderError::Custom("Unknown RPC message type"));
             }
@@ -606,7 +970,7 @@ mod tests {
             id,
             body: ResponseBody::Nodes {
                 total,
-                nodes: vec![enr],
+                nodes: vec![Arc::new(enr)],
             },
         });
         dbg!(hex::encode(message.clone().encode()));
@@ -629,7 +993,7 @@ mod tests {
             id,
             body: ResponseBody::Nodes {
                 total,
-                nodes: vec![enr, enr2],
+                nodes: vec![Arc::new(enr), Arc::new(enr2)],
             },
         });
         dbg!(hex::encode(message.clone().encode()));
@@ -649,8 +1013,8 @@ mod tests {
             Message::Response(response) => match response.body {
                 ResponseBody::Nodes { total, nodes } => {
                     assert_eq!(total, 1);
-                    assert_eq!(nodes[0], expected_enr1);
-                    assert_eq!(nodes[1], expected_enr2);
+                    assert_eq!(*nodes[0], expected_enr1);
+                    assert_eq!(*nodes[1], expected_enr2);
                 }
                 _ => panic!("Invalid decoding"),
             },
@@ -768,7 +1132,7 @@ mod tests {
             .build(&key)
             .unwrap();
 
-        let enr_list = vec![enr1, enr2, enr3];
+        let enr_list = vec![Arc::new(enr1), Arc::new(enr2), Arc::new(enr3)];
         let id = RequestId(vec![1]);
         let request = Message::Response(Response {
             id,
@@ -858,4 +1222,86 @@ mod tests {
         assert_eq!(encoded_message.clone(), expected_output);
         assert_eq!(Message::decode(&encoded_message).unwrap(), message);
     }
+
+    #[test]
+    fn encode_decode_topic_messages() {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(500)
+            .build(&key)
+            .unwrap();
+        let topic = TopicHash::new("topic");
+        let id = RequestId(vec![1]);
+        let messages = [
+            Message::Request(Request {
+                id: id.clone(),
+                body: RequestBody::RegisterTopic {
+                    topic,
+                    enr,
+                    ticket: vec![1, 2, 3],
+                },
+            }),
+            Message::Request(Request {
+                id: id.clone(),
+                body: RequestBody::TopicQuery { topic },
+            }),
+            Message::Response(Response {
+                id: id.clone(),
+                body: ResponseBody::Ticket {
+                    ticket: vec![1, 2, 3],
+                    wait_time: 10,
+                },
+            }),
+            Message::Response(Response {
+                id,
+                body: ResponseBody::RegisterConfirmation { topic },
+            }),
+        ];
+
+        for message in messages {
+            let encoded = message.clone().encode();
+            assert_eq!(Message::decode(&encoded).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn encode_decode_relay_init_notification() {
+        let key = CombinedKey::generate_secp256k1();
+        let initiator_enr = Enr::builder()
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(500)
+            .build(&key)
+            .unwrap();
+        let message = Message::Notification(Notification::RelayInit {
+            initiator_enr,
+            target: NodeId::random(),
+            nonce: rand::random(),
+        });
+
+        let encoded = message.clone().encode();
+        let decoded = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+    }
+
+    #[test]
+    fn encode_decode_relay_msg_notification() {
+        let key = CombinedKey::generate_secp256k1();
+        let initiator_enr = Enr::builder()
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(500)
+            .build(&key)
+            .unwrap();
+        let message = Message::Notification(Notification::RelayMsg {
+            initiator_enr,
+            initiator_socket: "192.0.2.1:30303".parse().unwrap(),
+            nonce: rand::random(),
+        });
+
+        let encoded = message.clone().encode();
+        let decoded = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+    }
 }
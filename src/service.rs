@@ -14,53 +14,98 @@
 //! secp256k1 keys are supported currently.
 
 use self::{
+    ban_intel::{BanIntel, BanTarget, BAN_INTEL_PROTOCOL},
+    dial_back::{DialBack, Verified, DIAL_BACK_PROTOCOL},
+    eclipse_monitor::EclipseMonitor,
     ip_vote::IpVote,
     query_info::{QueryInfo, QueryType},
+    query_trace::QueryTracer,
+    seeding::PeerQuality,
+    topics::{
+        estimate_radius, log2_distance, select_registrars, Registration, TopicRegistrations,
+        TopicTable,
+    },
 };
+use crate::time::Instant;
 use crate::{
+    audit::{BanReason, PacketType, ResponseViolation, SecurityEventKind, AUDIT_LOG},
+    bootnodes::Bootnodes,
+    enr_store::EnrStore,
+    enr_watch::EnrWatch,
     error::{RequestError, ResponseError},
-    handler::{Handler, HandlerIn, HandlerOut},
+    handler::{Handler, HandlerIn, HandlerOut, OutgoingRequest, RequestPriority},
+    ipmode::is_ipv4_or_mapped,
     kbucket::{
         self, ConnectionDirection, ConnectionState, FailureReason, InsertResult, KBucketsTable,
         NodeStatus, UpdateResult, MAX_NODES_PER_BUCKET,
     },
+    lru_time_cache::LruTimeCache,
+    metrics::{ServiceTask, ServiceTaskTimer, METRICS},
     node_info::{NodeAddress, NodeContact, NonContactable},
-    packet::{ProtocolIdentity, MAX_PACKET_SIZE},
+    packet::ProtocolIdentity,
+    peer_store::{PeerStoreCheckpointer, PeerStoreSnapshot},
     query_pool::{
         FindNodeQueryConfig, PredicateQueryConfig, QueryId, QueryPool, QueryPoolState, TargetKey,
     },
-    rpc, Config, Enr, Event, IpMode,
+    rpc, Config, DialPolicy, Enr, EnrReachability, Event, ExternalAddressPolicy, FilterStats,
+    IpMode,
 };
 use connectivity_state::{
     ConnectivityState, TimerFailure, DURATION_UNTIL_NEXT_CONNECTIVITY_ATTEMPT,
 };
-use delay_map::HashSetDelay;
+use delay_map::{HashMapDelay, HashSetDelay};
 use enr::{CombinedKey, NodeId};
 use fnv::FnvHashMap;
-use futures::prelude::*;
+use futures::{future::BoxFuture, prelude::*, stream::FuturesUnordered};
 use more_asserts::debug_unreachable;
 use parking_lot::RwLock;
+use rand::seq::SliceRandom;
 use rpc::*;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     convert::TryInto,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     task::Poll,
-    time::Instant,
+    time::Duration,
+};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
 };
-use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, trace, warn};
 
+mod ban_intel;
 mod connectivity_state;
+mod dial_back;
+mod eclipse_monitor;
 mod ip_vote;
+mod nodes_response;
 mod query_info;
+mod query_trace;
+mod seeding;
 mod test;
+mod topics;
+
+pub use ban_intel::BanIntelConfig;
+pub use dial_back::DialBackConfig;
+pub use eclipse_monitor::{EclipseDetectionConfig, EclipseWarning};
+pub use ip_vote::{NatStatus, NatType};
+pub use nodes_response::NodesSelection;
+pub use query_trace::{QueryTrace, TracedContact, TracedOutcome};
+pub use seeding::QuerySeeding;
+pub use topics::TopicConfig;
 
 /// The number of distances (buckets) we simultaneously request from each peer.
 /// NOTE: This must not be larger than 127.
 pub(crate) const DISTANCES_TO_REQUEST_PER_PEER: usize = 3;
 
+/// The interval at which the topics we advertise are registered with further registrars.
+const TOPIC_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The number of ban reports sent per TALK request, which keeps a request within a packet.
+const MAX_BAN_REPORTS_PER_REQUEST: usize = 16;
+
 /// Currently, a maximum of `DISTANCES_TO_REQUEST_PER_PEER * BUCKET_SIZE` peers
 /// can be returned. Datagrams have a max size of 1280 and ENR's have a max size
 /// of 300 bytes. Bucket sizes should be 16. Therefore, to return all required peers
@@ -68,17 +113,46 @@ pub(crate) const DISTANCES_TO_REQUEST_PER_PEER: usize = 3;
 pub(crate) const MAX_NODES_RESPONSES: usize =
     (MAX_NODES_PER_BUCKET / 4 + 1) * DISTANCES_TO_REQUEST_PER_PEER;
 
+/// How often we check whether the bootnodes need to be retried. The retries themselves are paced
+/// by each bootnode's backoff.
+const BOOTNODE_RETRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often we check whether the ban list has changed since the last peer store checkpoint. New
+/// bans are persisted promptly, rather than at the next regular checkpoint, and the changes within
+/// an interval are persisted together.
+const BAN_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the nodes with reserved sessions are sent to the handler. The handler decides on
+/// this snapshot, rather than taking the routing table and permit list locks for each session.
+const RESERVED_SESSIONS_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The number of peers whose round trip times are kept for seeding queries.
+const PEER_RTT_CAPACITY: usize = 4096;
+
+/// The time the round trip time of a peer is kept after it was last measured.
+const PEER_RTT_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// The age a session must have reached for the peer's votes on our external address to weigh more,
+/// if votes are weighted.
+const IP_VOTE_SESSION_AGE: Duration = Duration::from_secs(10 * 60);
+
 /// Request type for Protocols using `TalkReq` message.
 ///
 /// Automatically responds with an empty body on drop if
 /// [`TalkRequest::respond`] is not called.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct TalkRequest {
+    #[cfg_attr(feature = "serde", serde(skip))]
     id: RequestId,
     node_address: NodeAddress,
     protocol: Vec<u8>,
     body: Vec<u8>,
-    sender: Option<mpsc::UnboundedSender<HandlerIn>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    sender: Option<mpsc::Sender<HandlerIn>>,
+    /// The ENR of the requester, if the response re-establishes an expired session with it.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    enr: Option<Arc<Enr>>,
 }
 
 impl Drop for TalkRequest {
@@ -94,10 +168,7 @@ impl Drop for TalkRequest {
         };
 
         debug!(node_address = %self.node_address, "Sending empty TALK response");
-        if let Err(e) = sender.send(HandlerIn::Response(
-            self.node_address.clone(),
-            Box::new(response),
-        )) {
+        if let Err(e) = send_to_handler(&sender, self.handler_message(response)) {
             warn!(error = %e,"Failed to send empty talk response")
         }
     }
@@ -128,45 +199,94 @@ impl TalkRequest {
             body: ResponseBody::Talk { response },
         };
 
-        self.sender
-            .take()
-            .unwrap()
-            .send(HandlerIn::Response(
-                self.node_address.clone(),
-                Box::new(response),
-            ))
-            .map_err(|_| ResponseError::ChannelClosed)?;
+        let message = self.handler_message(response);
+        send_to_handler(&self.sender.take().unwrap(), message).map_err(|e| match e {
+            TrySendError::Full(_) => ResponseError::QueueFull,
+            TrySendError::Closed(_) => ResponseError::ChannelClosed,
+        })
+    }
 
-        Ok(())
+    fn handler_message(&mut self, response: Response) -> HandlerIn {
+        match self.enr.take() {
+            // The response is sent to the address the request came from.
+            Some(enr) => HandlerIn::ResponseEstablishingSession(
+                NodeContact::new(enr.public_key(), self.node_address.socket_addr, Some(enr)),
+                Box::new(response),
+            ),
+            None => HandlerIn::Response(self.node_address.clone(), Box::new(response)),
+        }
     }
 }
 
+/// Queues a message for the handler. The service never waits for the handler, so the message is
+/// dropped if the handler's queue is full.
+fn send_to_handler(
+    sender: &mpsc::Sender<HandlerIn>,
+    message: HandlerIn,
+) -> Result<(), TrySendError<()>> {
+    let result = sender.try_send(message).map_err(|e| match e {
+        TrySendError::Full(_) => {
+            METRICS
+                .handler_messages_dropped
+                .fetch_add(1, Ordering::Relaxed);
+            TrySendError::Full(())
+        }
+        TrySendError::Closed(_) => TrySendError::Closed(()),
+    });
+    METRICS.set_queue_depth(&METRICS.handler_queue_depth, sender);
+    result
+}
+
 /// The types of requests to send to the Discv5 service.
 pub enum ServiceRequest {
     /// A request to start a query. There are two types of queries:
     /// - A FindNode Query - Searches for peers using a random target.
     /// - A Predicate Query - Searches for peers closest to a random target that match a specified
     ///   predicate.
-    StartQuery(QueryKind, oneshot::Sender<Vec<Enr>>),
+    StartQuery(QueryKind, oneshot::Sender<Vec<Arc<Enr>>>),
     /// Send a FINDNODE request for nodes that fall within the given set of distances,
     /// to the designated peer and wait for a response.
     FindNodeDesignated(
         NodeContact,
         Vec<u64>,
-        oneshot::Sender<Result<Vec<Enr>, RequestError>>,
+        oneshot::Sender<Result<Vec<Arc<Enr>>, RequestError>>,
     ),
-    /// The TALK discv5 RPC function.
+    /// The TALK discv5 RPC function. The timeout, if any, replaces the request timeout and
+    /// retries of the configuration.
     Talk(
         NodeContact,
         Vec<u8>,
         Vec<u8>,
+        Option<Duration>,
         oneshot::Sender<Result<Vec<u8>, RequestError>>,
     ),
     /// The PING discv5 RPC function.
-    Ping(Enr, Option<oneshot::Sender<Result<Pong, RequestError>>>),
+    Ping(
+        Arc<Enr>,
+        Option<oneshot::Sender<Result<Pong, RequestError>>>,
+    ),
     /// Sets up an event stream where the discv5 server will return various events such as
     /// discovered nodes as it traverses the DHT.
     RequestEventStream(oneshot::Sender<mpsc::Receiver<Event>>),
+    /// Starts advertising the local node for a topic, or renews the advertisements if it is
+    /// already advertised.
+    RegisterTopic(TopicHash),
+    /// Stops advertising the local node for a topic.
+    UnregisterTopic(TopicHash),
+    /// Asks the registrars of a topic for the nodes advertised for it.
+    TopicQuery(TopicHash, oneshot::Sender<Vec<Arc<Enr>>>),
+    /// Verifies a peer learned out of band, inserting it into the routing table if it answers.
+    InjectPeer(Enr, PeerSource),
+    /// Reports the requests of the handler that have not been answered yet.
+    PendingRequests(oneshot::Sender<Vec<OutgoingRequest>>),
+    /// Returns the state of the filter of unsolicited packets.
+    FilterStats(oneshot::Sender<FilterStats>),
+    /// The local ENR has been re-signed with a new key. The sessions established under the
+    /// previous identity are kept for the grace period, if any.
+    RotateIdentity(Option<Duration>),
+    /// Perturbs the sessions of the handler, for tests.
+    #[cfg(feature = "test_utils")]
+    Chaos(crate::handler::ChaosHook),
 }
 
 use crate::discv5::PERMIT_BAN_LIST;
@@ -178,8 +298,10 @@ pub struct Service {
     local_enr: Arc<RwLock<Enr>>,
     /// The key associated with the local ENR.
     enr_key: Arc<RwLock<CombinedKey>>,
+    /// The subscriptions to fields of the local ENR and of the ENRs of the routing table.
+    enr_watch: Arc<EnrWatch>,
     /// Storage of the ENR record for each node.
-    kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+    kbuckets: Arc<KBucketsTable<NodeId, Arc<Enr>>>,
     /// All the iterative queries we are currently performing.
     queries: QueryPool<QueryInfo, NodeId, Enr>,
     /// RPC requests that have been sent and are awaiting a response. Some requests are linked to a
@@ -190,7 +312,7 @@ pub struct Service {
     /// A map of votes nodes have made about our external IP address. We accept the majority.
     ip_votes: Option<IpVote>,
     /// The channel to send messages to the handler.
-    handler_send: mpsc::UnboundedSender<HandlerIn>,
+    handler_send: mpsc::Sender<HandlerIn>,
     /// The channel to receive messages from the handler.
     handler_recv: mpsc::Receiver<HandlerOut>,
     /// The exit channel to shutdown the handler.
@@ -209,6 +331,80 @@ pub struct Service {
     /// contactable or not. This decides if we should update our ENR or set it to None, if we are
     /// not contactable.
     connectivity_state: ConnectivityState,
+    /// The NAT classification derived from our IP votes, shared with the `Discv5` struct.
+    nat_status: Arc<RwLock<NatStatus>>,
+    /// Peers that are pinged every `nat_keepalive_interval` to keep our NAT mappings open.
+    nat_keepalive_peers: Arc<RwLock<HashSet<NodeId>>>,
+    /// The timer driving the NAT keepalive pings, if enabled.
+    nat_keepalive: Option<tokio::time::Interval>,
+    /// The timer driving the lookups of our own node id, if enabled.
+    self_lookup: Option<tokio::time::Interval>,
+    /// The result of the running self-lookup, if any.
+    self_lookup_result: Option<oneshot::Receiver<Vec<Arc<Enr>>>>,
+    /// Recently seen ENRs, retained beyond the routing table if enabled.
+    enr_store: Option<Arc<RwLock<EnrStore>>>,
+    /// The timer pacing the re-advertisement of our ENR, if enabled.
+    readvertise: Option<tokio::time::Interval>,
+    /// Connected peers yet to be informed of our current ENR sequence number.
+    readvertise_queue: VecDeque<NodeId>,
+    /// The local ENR sequence number last queued for re-advertisement.
+    advertised_enr_seq: u64,
+    /// The bootnodes, retried with backoff when we have no connected peers.
+    bootnodes: Arc<RwLock<Bootnodes>>,
+    /// The timer on which the bootnodes are checked for retries.
+    bootnode_retry: tokio::time::Interval,
+    /// The timer on which the nodes with reserved sessions are sent to the handler, if any session
+    /// capacity is reserved.
+    reserved_sessions_refresh: Option<tokio::time::Interval>,
+    /// The nodes with reserved sessions last sent to the handler.
+    reserved_nodes: HashSet<NodeId>,
+    /// The IP version a session was last established or a response last received over, per dual
+    /// stack peer. Whether it was over IPv6 is stored.
+    dial_preferences: LruTimeCache<NodeId, bool>,
+    /// Dual stack peers contacted over IPv6 under the happy eyeballs dial policy, which are also
+    /// pinged over IPv4 if no session has been established when their stagger expires.
+    happy_eyeballs: HashMapDelay<NodeId, Arc<Enr>>,
+    /// Application data saved alongside the routing table in the peer store.
+    peer_store_metadata: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
+    /// The timer on which the routing table is checkpointed to the peer store, if configured.
+    peer_store_checkpoint: Option<tokio::time::Interval>,
+    /// Saves the checkpoints to the peer store off the service task, if configured.
+    peer_store_checkpointer: Option<PeerStoreCheckpointer>,
+    /// The timer on which the ban list is checked for changes to persist, if a peer store is
+    /// configured.
+    ban_checkpoint: Option<tokio::time::Interval>,
+    /// The count of changes to the ban list as of the last peer store checkpoint.
+    checkpointed_ban_changes: u64,
+    /// The nodes recently returned for each set of requested distances, if enabled.
+    nodes_response_cache: Option<LruTimeCache<Vec<u64>, Vec<Arc<Enr>>>>,
+    /// The sequence numbers of the recently reported discovered ENRs, if deduplicated.
+    reported_discovered: Option<LruTimeCache<NodeId, u64>>,
+    /// Watches the routing table and lookups for signs of an eclipse attack, if enabled.
+    eclipse_monitor: Option<EclipseMonitor>,
+    /// Exchanges ban reports with the trusted peers, if enabled.
+    ban_intel: Option<BanIntel>,
+    /// The timer on which new bans are reported to the trusted peers, if enabled.
+    ban_intel_share: Option<tokio::time::Interval>,
+    /// Verifies new external addresses and answers the dial-back requests of peers, if enabled.
+    dial_back: Option<DialBack>,
+    /// The advertisements we hold for other nodes, if topic advertisement is enabled.
+    topic_table: Option<TopicTable>,
+    /// The topics we advertise and our registrations with their registrars.
+    topic_registrations: TopicRegistrations,
+    /// The timer on which the topics we advertise are registered with further registrars.
+    topic_refresh: tokio::time::Interval,
+    /// The topic queries awaiting responses from registrars.
+    active_topic_queries: HashMap<TopicHash, ActiveTopicQuery>,
+    /// The lookups of topic hashes finding the registrars of the topics.
+    topic_lookups: FuturesUnordered<TopicLookupResult>,
+    /// The injected peers awaiting the answer to their verification PING.
+    injected_peers: HashMap<NodeId, PeerSource>,
+    /// The smoothed round trip times of the requests to peers, used to seed queries.
+    peer_rtts: LruTimeCache<NodeId, Duration>,
+    /// The number of requests in a row that failed, per peer of the routing table, if tracked.
+    successive_failures: HashMap<NodeId, usize>,
+    /// When the current session with each peer was established, to weigh its IP votes.
+    session_starts: LruTimeCache<NodeId, Instant>,
 }
 
 /// Active RPC request awaiting a response from the handler.
@@ -224,6 +420,7 @@ struct ActiveRequest {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Pong {
     /// The current ENR sequence number of the responder.
     pub enr_seq: u64,
@@ -233,23 +430,73 @@ pub struct Pong {
     pub port: u16,
 }
 
+/// Where a peer passed to [`crate::Discv5::inject_peer`] was learned from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum PeerSource {
+    /// Gossiped by another protocol.
+    Gossip,
+    /// Configured statically by the operator.
+    Static,
+    /// Any other source, as described by the application.
+    Other(String),
+}
+
+/// The outcome of the verification of an injected peer, reported by
+/// [`crate::Event::PeerInjected`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum InjectionOutcome {
+    /// The peer answered from the address in its ENR and was handed to the routing table.
+    Verified,
+    /// The peer did not answer.
+    Unreachable,
+    /// The ENR has no address we can contact.
+    NotContactable,
+    /// The node or the IP of its ENR is banned.
+    Banned,
+}
+
 /// The kinds of responses we can send back to the discv5 layer.
 pub enum CallbackResponse {
     /// A response to a requested Nodes.
-    Nodes(oneshot::Sender<Result<Vec<Enr>, RequestError>>),
+    Nodes(oneshot::Sender<Result<Vec<Arc<Enr>>, RequestError>>),
     /// A response from a TALK request
     Talk(oneshot::Sender<Result<Vec<u8>, RequestError>>),
     /// A response from a Pong request
     Pong(oneshot::Sender<Result<Pong, RequestError>>),
 }
 
+/// What the registrars of a topic are looked up for.
+#[derive(Debug, Clone, Copy)]
+enum TopicLookup {
+    /// Registering the topics we advertise with further registrars.
+    Register,
+    /// Asking the registrars for the nodes advertised for the topic.
+    Query,
+}
+
+/// A lookup of the registrars of a topic, resolving to the nodes found.
+type TopicLookupResult = BoxFuture<'static, (TopicHash, TopicLookup, Vec<Arc<Enr>>)>;
+
+/// A topic query awaiting the responses of the registrars. Concurrent queries for a topic are
+/// merged.
+struct ActiveTopicQuery {
+    /// The callbacks of the queries.
+    callbacks: Vec<oneshot::Sender<Vec<Arc<Enr>>>>,
+    /// The number of registrars yet to respond. None while the registrars are looked up.
+    pending: Option<usize>,
+    /// The nodes advertised for the topic.
+    found: HashMap<NodeId, Arc<Enr>>,
+}
+
 /// For multiple responses to a FindNodes request, this keeps track of the request count
 /// and the nodes that have been received.
 struct NodesResponse {
     /// The response count.
     count: usize,
     /// The filtered nodes that have been received.
-    received_nodes: Vec<Enr>,
+    received_nodes: Vec<Arc<Enr>>,
 }
 
 impl Default for NodesResponse {
@@ -267,33 +514,75 @@ impl Service {
     /// `local_enr` is the `ENR` representing the local node. This contains node identifying information, such
     /// as IP addresses and ports which we wish to broadcast to other nodes via this discovery
     /// mechanism.
+    #[allow(clippy::too_many_arguments)]
     pub async fn spawn<P: ProtocolIdentity>(
         local_enr: Arc<RwLock<Enr>>,
         enr_key: Arc<RwLock<CombinedKey>>,
-        kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+        enr_watch: Arc<EnrWatch>,
+        kbuckets: Arc<KBucketsTable<NodeId, Arc<Enr>>>,
+        nat_status: Arc<RwLock<NatStatus>>,
+        nat_keepalive_peers: Arc<RwLock<HashSet<NodeId>>>,
+        enr_store: Option<Arc<RwLock<EnrStore>>>,
+        bootnodes: Arc<RwLock<Bootnodes>>,
+        peer_store_metadata: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
         config: Config,
     ) -> Result<(oneshot::Sender<()>, mpsc::Sender<ServiceRequest>), std::io::Error> {
         // process behaviour-level configuration parameters
-        let ip_votes = if config.enr_update {
-            Some(IpVote::new(
-                config.enr_peer_update_min,
-                config.vote_duration,
-            ))
+        let ip_votes = if matches!(config.external_address_policy, ExternalAddressPolicy::Votes) {
+            Some(
+                IpVote::with_clock(
+                    config.enr_peer_update_min,
+                    config.vote_duration,
+                    config.clock.clone(),
+                )
+                .min_subnets(config.ip_vote_min_subnets),
+            )
         } else {
             None
         };
 
         let ip_mode = IpMode::new_from_listen_config(&config.listen_config);
 
+        if let ExternalAddressPolicy::Fixed(socket) = config.external_address_policy {
+            let advertised = if socket.is_ipv6() {
+                local_enr.read().udp6_socket().map(SocketAddr::V6)
+            } else {
+                local_enr.read().udp4_socket().map(SocketAddr::V4)
+            };
+            if advertised != Some(socket) {
+                if let Err(error) = enr_watch.update(&local_enr, |enr| {
+                    enr.set_udp_socket(socket, &enr_key.read())
+                }) {
+                    warn!(%socket, ?error, "Failed to advertise the fixed external address");
+                }
+            }
+        }
+
         // build the session service
-        let (handler_exit, handler_send, handler_recv) =
-            Handler::spawn::<P>(local_enr.clone(), enr_key.clone(), config.clone()).await?;
+        let bootnode_permits = bootnodes.read().permits();
+        let (handler_exit, handler_send, handler_recv) = Handler::spawn::<P>(
+            local_enr.clone(),
+            enr_key.clone(),
+            bootnode_permits,
+            config.clone(),
+        )
+        .await?;
 
         // create the required channels
         let (discv5_send, discv5_recv) = mpsc::channel(30);
         let (exit_send, exit) = oneshot::channel();
 
-        let connectivity_state = ConnectivityState::new(config.auto_nat_listen_duration);
+        let mut connectivity_state = ConnectivityState::new(config.auto_nat_listen_duration);
+        if let ExternalAddressPolicy::Fixed(socket) = config.external_address_policy {
+            // The fixed address is checked for incoming connections like a voted one.
+            connectivity_state.enr_socket_update(&socket);
+        }
+        let advertised_enr_seq = local_enr.read().seq();
+        let happy_eyeballs_stagger = match config.dial_policy {
+            DialPolicy::HappyEyeballs { stagger } => stagger,
+            // Nothing is ever queued under the other policies.
+            _ => Duration::from_millis(250),
+        };
 
         config
             .executor
@@ -303,8 +592,9 @@ impl Service {
                 let mut service = Service {
                     local_enr,
                     enr_key,
+                    enr_watch,
                     kbuckets,
-                    queries: QueryPool::new(config.query_timeout),
+                    queries: QueryPool::new(config.query_timeout, config.clock.clone()),
                     active_requests: Default::default(),
                     active_nodes_responses: HashMap::new(),
                     ip_votes,
@@ -318,6 +608,88 @@ impl Service {
                     config: config.clone(),
                     ip_mode,
                     connectivity_state,
+                    nat_status,
+                    nat_keepalive_peers,
+                    nat_keepalive: config.nat_keepalive_interval.map(tokio::time::interval),
+                    self_lookup: config.self_lookup_interval.map(|interval| {
+                        tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+                    }),
+                    self_lookup_result: None,
+                    enr_store,
+                    readvertise: config
+                        .enr_readvertise
+                        .map(|(_, interval)| tokio::time::interval(interval)),
+                    readvertise_queue: VecDeque::new(),
+                    advertised_enr_seq,
+                    bootnodes,
+                    bootnode_retry: tokio::time::interval(BOOTNODE_RETRY_CHECK_INTERVAL),
+                    reserved_sessions_refresh: (config.session_reserved_fraction > 0.0)
+                        .then(|| tokio::time::interval(RESERVED_SESSIONS_REFRESH_INTERVAL)),
+                    reserved_nodes: HashSet::new(),
+                    dial_preferences: LruTimeCache::with_clock(
+                        config.session_timeout,
+                        Some(config.session_cache_capacity),
+                        config.clock.clone(),
+                    ),
+                    happy_eyeballs: HashMapDelay::new(happy_eyeballs_stagger),
+                    peer_store_metadata,
+                    peer_store_checkpoint: config.peer_store.as_ref().map(|_| {
+                        let interval = config.peer_store_checkpoint_interval;
+                        tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+                    }),
+                    peer_store_checkpointer: config
+                        .peer_store
+                        .clone()
+                        .map(PeerStoreCheckpointer::spawn),
+                    ban_checkpoint: config
+                        .peer_store
+                        .as_ref()
+                        .map(|_| tokio::time::interval(BAN_CHECKPOINT_INTERVAL)),
+                    checkpointed_ban_changes: 0,
+                    nodes_response_cache: config
+                        .nodes_response_cache
+                        .map(|(capacity, ttl)| LruTimeCache::new(ttl, Some(capacity))),
+                    reported_discovered: config
+                        .discovered_peers_dedup
+                        .map(|(capacity, ttl)| LruTimeCache::new(ttl, Some(capacity))),
+                    eclipse_monitor: config
+                        .eclipse_detection
+                        .clone()
+                        .map(|detection| EclipseMonitor::new(detection, config.clock.clone())),
+                    ban_intel_share: config.ban_intel.as_ref().map(|ban_intel| {
+                        let interval = ban_intel.share_interval;
+                        tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+                    }),
+                    ban_intel: config
+                        .ban_intel
+                        .clone()
+                        .map(|ban_intel| BanIntel::new(ban_intel, config.clock.clone())),
+                    dial_back: config
+                        .dial_back
+                        .clone()
+                        .map(|dial_back| DialBack::new(dial_back, config.clock.clone())),
+                    topic_table: config
+                        .topics
+                        .clone()
+                        .map(|topics| TopicTable::new(topics, config.clock.clone())),
+                    topic_registrations: TopicRegistrations::new(
+                        config.topics.clone().unwrap_or_default(),
+                    ),
+                    topic_refresh: tokio::time::interval(TOPIC_REFRESH_INTERVAL),
+                    active_topic_queries: HashMap::new(),
+                    topic_lookups: FuturesUnordered::new(),
+                    injected_peers: HashMap::new(),
+                    peer_rtts: LruTimeCache::with_clock(
+                        PEER_RTT_TTL,
+                        Some(PEER_RTT_CAPACITY),
+                        config.clock.clone(),
+                    ),
+                    successive_failures: HashMap::new(),
+                    session_starts: LruTimeCache::with_clock(
+                        config.session_timeout,
+                        Some(config.session_cache_capacity),
+                        config.clock.clone(),
+                    ),
                 };
 
                 info!(mode = ?service.ip_mode, "Discv5 Service started");
@@ -332,6 +704,7 @@ impl Service {
         loop {
             tokio::select! {
                 _ = &mut self.exit => {
+                    self.checkpoint_peer_store();
                     if let Some(exit) = self.handler_exit.take() {
                         let _ = exit.send(());
                         info!("Discv5 Service shutdown");
@@ -339,11 +712,15 @@ impl Service {
                     return;
                 }
                 Some(service_request) = self.discv5_recv.recv() => {
+                    let _timer = self.task_timer(ServiceTask::ApplicationRequest);
                     match service_request {
                         ServiceRequest::StartQuery(query, callback) => {
                             match query {
                                 QueryKind::FindNode { target_node } => {
-                                    self.start_findnode_query(target_node, callback);
+                                    self.start_findnode_query(target_node, callback, None);
+                                }
+                                QueryKind::TracedFindNode { target_node, trace } => {
+                                    self.start_findnode_query(target_node, callback, Some(trace));
                                 }
                                 QueryKind::Predicate { target_node, target_peer_no, predicate } => {
                                     self.start_predicate_query(target_node, target_peer_no, predicate, callback);
@@ -353,8 +730,8 @@ impl Service {
                         ServiceRequest::FindNodeDesignated(node_contact, distance, callback) => {
                             self.request_find_node_designated_peer(node_contact, distance, Some(callback));
                         }
-                        ServiceRequest::Talk(node_contact, protocol, request, callback) => {
-                            self.talk_request(node_contact, protocol, request, callback);
+                        ServiceRequest::Talk(node_contact, protocol, request, timeout, callback) => {
+                            self.talk_request(node_contact, protocol, request, timeout, callback);
                         }
                         ServiceRequest::Ping(enr, callback) => {
                             self.send_ping(enr, callback);
@@ -362,37 +739,90 @@ impl Service {
                         ServiceRequest::RequestEventStream(callback) => {
                             // the channel size needs to be large to handle many discovered peers
                             // if we are reporting them on the event stream.
-                            let channel_size = if self.config.report_discovered_peers { 100 } else { 30 };
+                            let channel_size = self.config.event_stream_capacity.unwrap_or(if self.config.report_discovered_peers { 100 } else { 30 });
                             let (event_stream, event_stream_recv) = mpsc::channel(channel_size);
                             self.event_stream = Some(event_stream);
                             if callback.send(event_stream_recv).is_err() {
                                 error!("Failed to return the event stream channel");
                             }
                         }
+                        ServiceRequest::RegisterTopic(topic) => {
+                            if self.topic_registrations.add(topic) {
+                                self.register_topics();
+                            } else {
+                                self.topic_registrations.renew(&topic);
+                            }
+                        }
+                        ServiceRequest::UnregisterTopic(topic) => {
+                            self.topic_registrations.remove(&topic);
+                        }
+                        ServiceRequest::TopicQuery(topic, callback) => {
+                            self.start_topic_query(topic, callback);
+                        }
+                        ServiceRequest::InjectPeer(enr, source) => {
+                            self.inject_peer(enr, source);
+                        }
+                        ServiceRequest::PendingRequests(callback) => {
+                            if send_to_handler(&self.handler_send, HandlerIn::PendingRequests(callback)).is_err() {
+                                error!("Failed to request the pending requests of the handler");
+                            }
+                        }
+                        ServiceRequest::FilterStats(callback) => {
+                            if send_to_handler(&self.handler_send, HandlerIn::FilterStats(callback)).is_err() {
+                                error!("Failed to request the state of the filter");
+                            }
+                        }
+                        ServiceRequest::RotateIdentity(grace_period) => {
+                            if send_to_handler(&self.handler_send, HandlerIn::RotateIdentity(grace_period)).is_err() {
+                                error!("Failed to rotate the identity of the handler");
+                            }
+                        }
+                        #[cfg(feature = "test_utils")]
+                        ServiceRequest::Chaos(hook) => {
+                            if send_to_handler(&self.handler_send, HandlerIn::Chaos(hook)).is_err() {
+                                error!("Failed to send chaos hook to the handler");
+                            }
+                        }
                     }
                 }
                 Some(event) = self.handler_recv.recv() => {
+                    let _timer = self.task_timer(ServiceTask::HandlerMessage);
                     match event {
                         HandlerOut::Established(enr, socket_addr, direction) => {
+                            let key = kbucket::Key::from(enr.node_id());
+                            let new_to_table = !matches!(
+                                self.kbuckets.entry(&key),
+                                kbucket::Entry::Present(..)
+                            );
+                            self.session_starts.insert(enr.node_id(), self.config.clock.now());
                             self.inject_session_established(enr.clone(), &socket_addr, direction);
-                            self.send_event(Event::SessionEstablished(enr, socket_addr));
+                            self.send_event(Event::SessionEstablished(enr.clone(), socket_addr));
+                            self.send_event(Event::PeerConnected {
+                                enr,
+                                socket: socket_addr,
+                                direction,
+                                new_to_table,
+                            });
                         }
                         HandlerOut::Request(node_address, request) => {
                                 self.handle_rpc_request(node_address, *request);
                             }
-                        HandlerOut::Response(node_address, response) => {
+                        HandlerOut::Response(node_address, response, rtt) => {
+                                if let Some(rtt) = rtt {
+                                    self.record_rtt(node_address.node_id, rtt);
+                                }
                                 self.handle_rpc_response(node_address, *response);
                             }
                         HandlerOut::WhoAreYou(whoareyou_ref) => {
                             // check what our latest known ENR is for this node.
                             if let Some(known_enr) = self.find_enr(&whoareyou_ref.0.node_id) {
-                                if let Err(e) = self.handler_send.send(HandlerIn::WhoAreYou(whoareyou_ref, Some(known_enr))) {
+                                if let Err(e) = send_to_handler(&self.handler_send, HandlerIn::WhoAreYou(whoareyou_ref, Some((*known_enr).clone()))) {
                                     warn!(error = %e, "Failed to send whoareyou");
                                 };
                             } else {
                                 // do not know of this peer
                                 debug!(node_address = %whoareyou_ref.0, "NodeId unknown, requesting ENR.");
-                                if let Err(e) = self.handler_send.send(HandlerIn::WhoAreYou(whoareyou_ref, None)) {
+                                if let Err(e) = send_to_handler(&self.handler_send, HandlerIn::WhoAreYou(whoareyou_ref, None)) {
                                     warn!(error = %e, "Failed to send who are you to unknown enr peer");
                                 }
                             }
@@ -411,17 +841,27 @@ impl Service {
                             // If this node exists in our routing table, remove it, as it may have shifted
                             // ip addresses and is now no longer contactable.
                             let key = kbucket::Key::from(node_id);
-                            if self.kbuckets.write().remove(&key) {
+                            if self.kbuckets.remove(&key) {
                                 debug!(?node_id, "Uncontactable node removed from routing table");
                             }
                             self.send_event(Event::UnverifiableEnr{enr, socket, node_id});
                         }
+                        HandlerOut::InvalidResponse(node_address, violation) => {
+                            self.penalize_response(&node_address, violation);
+                        }
                     }
                 }
                 event = Service::bucket_maintenance_poll(&self.kbuckets) => {
+                    let _timer = self.task_timer(ServiceTask::TableMaintenance);
+                    if let Event::NodeInserted { node_id, .. } = event {
+                        if let Some(enr) = self.find_enr(&node_id) {
+                            self.check_eclipse_insertion(&enr);
+                        }
+                    }
                     self.send_event(event);
                 }
                 query_event = Service::query_event_poll(&mut self.queries) => {
+                    let _timer = self.task_timer(ServiceTask::Query);
                     match query_event {
                         QueryEvent::Waiting(query_id, node_id, request_body) => {
                             self.send_rpc_query(query_id, node_id, request_body);
@@ -430,6 +870,10 @@ impl Service {
                         // query is superfluous, however it may be useful in future versions.
                         QueryEvent::Finished(query) | QueryEvent::TimedOut(query) => {
                             let id = query.id();
+                            METRICS.lookups.fetch_add(1, Ordering::Relaxed);
+                            if query.num_dead_in_results() > 0 {
+                                METRICS.lookups_with_dead_peers.fetch_add(1, Ordering::Relaxed);
+                            }
                             let mut result = query.into_result();
                             // obtain the ENR's for the resulting nodes
                             let mut found_enrs = Vec::new();
@@ -445,6 +889,19 @@ impl Service {
                                     warn!("ENR not present in queries results");
                                 }
                             }
+                            if let Some(warning) = self.eclipse_monitor.as_mut().and_then(|monitor| monitor.lookup_finished(&found_enrs)) {
+                                self.report_eclipse_warning(warning);
+                            }
+                            if let Some(mut unreachable) = result.target.unreachable_enrs.take() {
+                                // The closest of the nodes that can't be contacted follow the results.
+                                let target = result.target.key();
+                                unreachable.sort_by_key(|enr| target.distance(&kbucket::Key::from(enr.node_id())));
+                                unreachable.truncate(MAX_NODES_PER_BUCKET);
+                                found_enrs.extend(unreachable);
+                            }
+                            if let Some(tracer) = result.target.trace.take() {
+                                tracer.finish(self.config.clock.now());
+                            }
                             if result.target.callback.send(found_enrs).is_err() {
                                 warn!(query_id = *id, "Callback dropped for query. Results dropped");
                             }
@@ -452,10 +909,11 @@ impl Service {
                     }
                 }
                 Some(Ok(node_id)) = self.peers_to_ping.next() => {
+                    let _timer = self.task_timer(ServiceTask::Ping);
                     // If the node is in the routing table, Ping it and re-queue the node.
                     let key = kbucket::Key::from(node_id);
                     let enr =  {
-                        if let kbucket::Entry::Present(entry, _) = self.kbuckets.write().entry(&key) {
+                        if let kbucket::Entry::Present(entry, _) = self.kbuckets.entry(&key) {
                         // The peer is in the routing table, ping it and re-queue the ping
                         self.peers_to_ping.insert(node_id);
                         Some(entry.value().clone())
@@ -466,13 +924,68 @@ impl Service {
                         self.send_ping(enr, None);
                     }
                 }
+                Some(Ok((_, enr))) = self.happy_eyeballs.next() => {
+                    let _timer = self.task_timer(ServiceTask::Ping);
+                    self.send_happy_eyeballs_ping(enr);
+                }
+                Some((topic, registrar, ticket)) = self.topic_registrations.next() => {
+                    let _timer = self.task_timer(ServiceTask::Topics);
+                    // Registrars in the routing table are sent our registration at their latest
+                    // ENR.
+                    let registrar = self.find_enr(&registrar.node_id()).unwrap_or(registrar);
+                    self.send_register_topic(topic, registrar, ticket);
+                }
+                Some((topic, lookup, found)) = self.topic_lookups.next() => {
+                    let _timer = self.task_timer(ServiceTask::Topics);
+                    match lookup {
+                        TopicLookup::Register => self.register_topic_at(topic, found),
+                        TopicLookup::Query => self.query_topic_at(topic, found),
+                    }
+                }
+                _ = self.topic_refresh.tick() => {
+                    let _timer = self.task_timer(ServiceTask::Topics);
+                    self.register_topics();
+                }
+                _ = Service::interval_poll(&mut self.nat_keepalive) => {
+                    let _timer = self.task_timer(ServiceTask::Timer);
+                    self.send_nat_keepalives();
+                }
+                _ = Service::interval_poll(&mut self.self_lookup) => {
+                    let _timer = self.task_timer(ServiceTask::Timer);
+                    self.start_self_lookup();
+                }
+                _ = Service::interval_poll(&mut self.readvertise) => {
+                    let _timer = self.task_timer(ServiceTask::Timer);
+                    self.send_readvertise_pings();
+                }
+                _ = Service::interval_poll(&mut self.peer_store_checkpoint) => {
+                    let _timer = self.task_timer(ServiceTask::Timer);
+                    self.checkpoint_peer_store();
+                }
+                _ = Service::interval_poll(&mut self.ban_checkpoint) => {
+                    let _timer = self.task_timer(ServiceTask::Timer);
+                    self.checkpoint_changed_bans();
+                }
+                _ = Service::interval_poll(&mut self.ban_intel_share) => {
+                    let _timer = self.task_timer(ServiceTask::Timer);
+                    self.share_ban_intel();
+                }
+                _ = self.bootnode_retry.tick() => {
+                    let _timer = self.task_timer(ServiceTask::Timer);
+                    self.retry_bootnodes();
+                }
+                _ = Service::interval_poll(&mut self.reserved_sessions_refresh) => {
+                    let _timer = self.task_timer(ServiceTask::Timer);
+                    self.refresh_reserved_sessions();
+                }
                 connectivity_timeout = self.connectivity_state.poll() => {
+                    let _timer = self.task_timer(ServiceTask::Timer);
                     let updated_enr = match connectivity_timeout {
                         TimerFailure::V4 => {
                             // We have not received enough incoming connections in the required
                             // time. Remove our ENR advertisement.
                             info!(ip_version="v4", next_attempt_in=%DURATION_UNTIL_NEXT_CONNECTIVITY_ATTEMPT.as_secs(), "UDP Socket removed from ENR");
-                            if let Err(error) = self.local_enr.write().remove_udp_socket(&self.enr_key.read()) {
+                            if let Err(error) = self.enr_watch.update(&self.local_enr, |enr| enr.remove_udp_socket(&self.enr_key.read())) {
                                 error!(?error, "Failed to update the ENR");
                                 false
                             } else {
@@ -484,7 +997,7 @@ impl Service {
                             // We have not received enough incoming connections in the required
                             // time. Remove our ENR advertisement.
                             info!(ip_version="v6", next_attempt_in=%DURATION_UNTIL_NEXT_CONNECTIVITY_ATTEMPT.as_secs(), "UDP Socket removed from ENR");
-                            if let Err(error) = self.local_enr.write().remove_udp6_socket(&self.enr_key.read()) {
+                            if let Err(error) = self.enr_watch.update(&self.local_enr, |enr| enr.remove_udp6_socket(&self.enr_key.read())) {
                                 error!(?error, "Failed to update the ENR");
                                 false
                             } else {
@@ -493,9 +1006,16 @@ impl Service {
                             }
                         }
                     };
+                    // Votes gathered for the revoked address are stale. A fresh majority is
+                    // required once the next connectivity attempt begins. The votes for the other
+                    // IP version are unaffected.
+                    if let Some(ip_votes) = self.ip_votes.as_mut() {
+                        ip_votes.clear(matches!(connectivity_timeout, TimerFailure::V6));
+                    }
+                    self.update_nat_status();
                     if updated_enr {
                         // Inform our known peers of our updated ENR
-                        self.ping_connected_peers();
+                        self.advertise_local_enr();
                     }
                 }
             }
@@ -503,19 +1023,35 @@ impl Service {
     }
 
     /// Internal function that starts a query.
-    fn start_findnode_query(&mut self, target_node: NodeId, callback: oneshot::Sender<Vec<Enr>>) {
+    fn start_findnode_query(
+        &mut self,
+        target_node: NodeId,
+        callback: oneshot::Sender<Vec<Arc<Enr>>>,
+        trace: Option<oneshot::Sender<QueryTrace>>,
+    ) {
         let mut target = QueryInfo {
             query_type: QueryType::FindNode(target_node),
             untrusted_enrs: Default::default(),
+            unreachable_enrs: self.config.report_unreachable_enrs.then(Vec::new),
             distances_to_request: DISTANCES_TO_REQUEST_PER_PEER,
             callback,
+            trace: trace.map(|trace| QueryTracer::new(target_node, self.config.clock.now(), trace)),
         };
 
         let target_key: kbucket::Key<NodeId> = target.key();
+        let query_config = FindNodeQueryConfig::new_from_config(&self.config);
+        let seeding = self.config.query_seeding;
         let mut known_closest_peers = Vec::new();
         {
-            let mut kbuckets = self.kbuckets.write();
-            for closest in kbuckets.closest_values(&target_key) {
+            let kbuckets = &self.kbuckets;
+            let closest = kbuckets
+                .closest_values(&target_key)
+                .take(seeding.pool_size(query_config.num_results))
+                .collect();
+            let seeds = seeding.seed(closest, query_config.num_results, |closest| {
+                self.peer_quality(kbuckets, &closest.key)
+            });
+            for closest in seeds {
                 // Add the known ENR's to the untrusted list
                 target.untrusted_enrs.push(closest.value);
                 // Add the key to the list for the query
@@ -529,7 +1065,6 @@ impl Service {
                 warn!("Failed to callback");
             }
         } else {
-            let query_config = FindNodeQueryConfig::new_from_config(&self.config);
             self.queries
                 .add_findnode_query(query_config, target, known_closest_peers);
         }
@@ -541,25 +1076,38 @@ impl Service {
         target_node: NodeId,
         num_nodes: usize,
         predicate: Box<dyn Fn(&Enr) -> bool + Send>,
-        callback: oneshot::Sender<Vec<Enr>>,
+        callback: oneshot::Sender<Vec<Arc<Enr>>>,
     ) {
         let mut target = QueryInfo {
             query_type: QueryType::FindNode(target_node),
             untrusted_enrs: Default::default(),
+            // The unreachable ENRs are not matched against the predicate.
+            unreachable_enrs: None,
             distances_to_request: DISTANCES_TO_REQUEST_PER_PEER,
             callback,
+            trace: None,
         };
 
         let target_key: kbucket::Key<NodeId> = target.key();
 
         // Map the TableEntry to an ENR.
-        let kbucket_predicate = |e: &Enr| predicate(e);
+        let kbucket_predicate = |e: &Arc<Enr>| predicate(e);
 
+        let mut query_config = PredicateQueryConfig::new_from_config(&self.config);
+        query_config.num_results = num_nodes;
+        let seeding = self.config.query_seeding;
         let mut known_closest_peers = Vec::<kbucket::PredicateKey<_>>::new();
         {
-            let mut kbuckets = self.kbuckets.write();
-            for closest in kbuckets.closest_values_predicate(&target_key, &kbucket_predicate) {
-                let (node_id_predicate, enr) = closest.to_key_value();
+            let kbuckets = &self.kbuckets;
+            let closest = kbuckets
+                .closest_values_predicate(&target_key, &kbucket_predicate)
+                .take(seeding.pool_size(query_config.num_results))
+                .map(|closest| closest.to_key_value())
+                .collect();
+            let seeds = seeding.seed(closest, query_config.num_results, |(key, _)| {
+                self.peer_quality(kbuckets, &key.key)
+            });
+            for (node_id_predicate, enr) in seeds {
                 // Add the known ENR's to the untrusted list
                 target.untrusted_enrs.push(enr);
                 // Add the key to the list for the query
@@ -573,18 +1121,42 @@ impl Service {
                 warn!("Failed to callback");
             }
         } else {
-            let mut query_config = PredicateQueryConfig::new_from_config(&self.config);
-            query_config.num_results = num_nodes;
             self.queries
                 .add_predicate_query(query_config, target, known_closest_peers, predicate);
         }
     }
 
+    /// Folds a round trip time measured for a request to the peer into its smoothed round trip
+    /// time.
+    fn record_rtt(&mut self, node_id: NodeId, rtt: Duration) {
+        let smoothed = match self.peer_rtts.peek(&node_id) {
+            Some(smoothed) => (*smoothed * 7 + rtt) / 8,
+            None => rtt,
+        };
+        self.peer_rtts.insert(node_id, smoothed);
+    }
+
+    /// What is known of the liveness and latency of a peer of the routing table.
+    fn peer_quality(
+        &self,
+        kbuckets: &KBucketsTable<NodeId, Arc<Enr>>,
+        key: &kbucket::Key<NodeId>,
+    ) -> PeerQuality {
+        let connected = kbuckets
+            .get_bucket(key)
+            .and_then(|bucket| bucket.get(key).map(|node| node.status.is_connected()))
+            .unwrap_or(false);
+        PeerQuality {
+            connected,
+            rtt: self.peer_rtts.peek(key.preimage()).copied(),
+        }
+    }
+
     /// Returns an ENR if one is known for the given NodeId.
-    pub fn find_enr(&self, node_id: &NodeId) -> Option<Enr> {
+    pub fn find_enr(&self, node_id: &NodeId) -> Option<Arc<Enr>> {
         // check if we know this node id in our routing table
         let key = kbucket::Key::from(*node_id);
-        if let kbucket::Entry::Present(entry, _) = self.kbuckets.write().entry(&key) {
+        if let kbucket::Entry::Present(entry, _) = self.kbuckets.entry(&key) {
             return Some(entry.value().clone());
         }
         // check the untrusted addresses for ongoing queries
@@ -612,7 +1184,7 @@ impl Service {
             RequestBody::Ping { enr_seq } => {
                 // check if we need to update the known ENR
                 let mut to_request_enr = None;
-                match self.kbuckets.write().entry(&node_address.node_id.into()) {
+                match self.kbuckets.entry(&node_address.node_id.into()) {
                     kbucket::Entry::Present(ref mut entry, _) => {
                         if entry.value().seq() < enr_seq {
                             let enr = entry.value().clone();
@@ -629,7 +1201,7 @@ impl Service {
                     _ => {}
                 }
                 if let Some(enr) = to_request_enr {
-                    match NodeContact::try_from_enr(enr, self.ip_mode) {
+                    match self.contact_from_enr(enr) {
                         Ok(contact) => {
                             self.request_find_node_designated_peer(contact, vec![0], None);
                         }
@@ -655,10 +1227,10 @@ impl Service {
                         },
                     };
                     debug!(%node_address, "Sending PONG response");
-                    if let Err(e) = self
-                        .handler_send
-                        .send(HandlerIn::Response(node_address, Box::new(response)))
-                    {
+                    if let Err(e) = send_to_handler(
+                        &self.handler_send,
+                        HandlerIn::Response(node_address, Box::new(response)),
+                    ) {
                         warn!(error = %e, "Failed to send response");
                     }
                 } else {
@@ -666,16 +1238,85 @@ impl Service {
                 }
             }
             RequestBody::Talk { protocol, request } => {
+                let enr = if self
+                    .config
+                    .reestablish_session_for_talk_responses
+                    .contains(&protocol)
+                {
+                    let enr = self.find_enr(&node_address.node_id);
+                    if enr.is_none() {
+                        debug!(
+                            %node_address,
+                            "Unknown ENR, the TALK response will not re-establish an expired session"
+                        );
+                    }
+                    enr
+                } else {
+                    None
+                };
                 let req = TalkRequest {
                     id,
                     node_address,
                     protocol,
                     body: request,
                     sender: Some(self.handler_send.clone()),
+                    enr,
                 };
 
+                if self.ban_intel.is_some() && req.protocol == BAN_INTEL_PROTOCOL {
+                    // Dropping the request acknowledges it with an empty response.
+                    self.receive_ban_intel(&req.node_address, &req.body);
+                    return;
+                }
+                if self.dial_back.is_some() && req.protocol == DIAL_BACK_PROTOCOL {
+                    self.answer_dial_back(req);
+                    return;
+                }
                 self.send_event(Event::TalkRequest(req));
             }
+            RequestBody::RegisterTopic { topic, enr, ticket } => {
+                let Some(topic_table) = self.topic_table.as_mut() else {
+                    debug!(%node_address, %topic, "Ignoring REGTOPIC, topics are not enabled");
+                    return;
+                };
+                // Nodes may only advertise themselves, at an address they can be reached on.
+                if enr.node_id() != node_address.node_id
+                    || self.ip_mode.get_contactable_addr(&enr).is_none()
+                {
+                    debug!(%node_address, %topic, "Ignoring REGTOPIC with an invalid ENR");
+                    return;
+                }
+                let body = match topic_table.register(
+                    topic,
+                    Arc::new(enr),
+                    node_address.socket_addr.ip(),
+                    &ticket,
+                ) {
+                    Registration::Confirmed => ResponseBody::RegisterConfirmation { topic },
+                    Registration::Ticket { ticket, wait_time } => ResponseBody::Ticket {
+                        ticket,
+                        // Rounded up, so that the ticket is not presented early.
+                        wait_time: wait_time.as_secs() + u64::from(wait_time.subsec_nanos() > 0),
+                    },
+                };
+                let response = Response { id, body };
+                debug!(%node_address, %response, "Answering REGTOPIC");
+                if let Err(e) = send_to_handler(
+                    &self.handler_send,
+                    HandlerIn::Response(node_address, Box::new(response)),
+                ) {
+                    warn!(error = %e, "Failed to send response");
+                }
+            }
+            RequestBody::TopicQuery { topic } => {
+                // Without topics enabled, we hold no advertisements.
+                let ads = self
+                    .topic_table
+                    .as_mut()
+                    .map(|topic_table| topic_table.ads(&topic))
+                    .unwrap_or_default();
+                self.send_nodes(node_address, id, ads);
+            }
         }
     }
 
@@ -685,11 +1326,13 @@ impl Service {
         let id = response.id.clone();
 
         let Some(mut active_request) = self.active_requests.remove(&id) else {
-            warn!(%id, "Received an RPC response which doesn't match a request");
+            warn!(request_id = %id, "Received an RPC response which doesn't match a request");
             return;
         };
+        self.successive_failures.remove(&node_address.node_id);
 
         debug!(
+            request_id = %id,
             response = %response.body,
             request = %active_request.request_body,
             from = %active_request.contact,
@@ -711,21 +1354,46 @@ impl Service {
         if !response.match_request(&active_request.request_body) {
             warn!(
                 %node_address,
+                request_id = %id,
                 "Node gave an incorrect response type. Ignoring response"
             );
             return;
         }
 
+        self.record_dial_preference(node_address.node_id, &node_address.socket_addr);
         let node_id = node_address.node_id;
 
         match response.body {
             ResponseBody::Nodes { total, mut nodes } => {
-                if total > MAX_NODES_RESPONSES as u64 {
+                if total > MAX_NODES_RESPONSES as u64
+                    && !self.active_nodes_responses.contains_key(&id)
+                {
                     warn!(
                         total,
                         "NodesResponse has a total larger than {}, nodes will be truncated",
                         MAX_NODES_RESPONSES
                     );
+                    self.penalize_response(&node_address, ResponseViolation::ExcessiveTotal);
+                }
+
+                if let RequestBody::TopicQuery { topic } = active_request.request_body {
+                    if total > 1 {
+                        let mut current_response =
+                            self.active_nodes_responses.remove(&id).unwrap_or_default();
+                        current_response.received_nodes.append(&mut nodes);
+                        if (current_response.count as u64) < total
+                            && current_response.count < MAX_NODES_RESPONSES
+                        {
+                            current_response.count += 1;
+                            self.active_nodes_responses
+                                .insert(id.clone(), current_response);
+                            self.active_requests.insert(id, active_request);
+                            return;
+                        }
+                        nodes = current_response.received_nodes;
+                    }
+                    self.topic_query_answered(&topic, nodes);
+                    return;
                 }
 
                 // These are sanitized and ordered
@@ -748,14 +1416,14 @@ impl Service {
                 // We never send an ENR request in combination of other requests.
                 if distances_requested.len() == 1 && distances_requested[0] == 0 {
                     // we requested an ENR update
-                    if nodes.len() > 1 {
+                    let before_len = nodes.len();
+                    nodes.retain(|enr| peer_key.log2_distance(&enr.node_id().into()).is_none());
+                    if nodes.len() < before_len {
                         warn!(
                             %node_address,
-                            "Peer returned more than one ENR for itself. Blacklisting",
+                            "Peer returned ENRs other than its own. Blacklisting",
                         );
-                        let ban_timeout = self.config.ban_duration.map(|v| Instant::now() + v);
-                        PERMIT_BAN_LIST.write().ban(node_address, ban_timeout);
-                        nodes.retain(|enr| peer_key.log2_distance(&enr.node_id().into()).is_none());
+                        self.penalize_response(&node_address, ResponseViolation::ForeignEnr);
                     }
                 } else {
                     let before_len = nodes.len();
@@ -771,8 +1439,10 @@ impl Service {
                         let node_id = active_request.contact.node_id();
                         let addr = active_request.contact.socket_addr();
                         warn!(%node_id, %addr, "ENRs received of unsolicited distances. Blacklisting");
-                        let ban_timeout = self.config.ban_duration.map(|v| Instant::now() + v);
-                        PERMIT_BAN_LIST.write().ban(node_address, ban_timeout);
+                        self.penalize_response(
+                            &node_address,
+                            ResponseViolation::UnrequestedDistance,
+                        );
                     }
                 }
 
@@ -809,6 +1479,11 @@ impl Service {
                     nodes = current_response.received_nodes;
                 }
 
+                if nodes.len() > self.config.max_nodes_response {
+                    nodes.truncate(self.config.max_nodes_response);
+                    self.penalize_response(&node_address, ResponseViolation::ExcessNodes);
+                }
+
                 debug!(
                     len = nodes.len(),
                     total,
@@ -836,11 +1511,20 @@ impl Service {
                     };
                     return;
                 }
+                if let Some(dial_back) = self.dial_back.as_mut() {
+                    if dial_back.pinged(&active_request.contact.node_address(), true) {
+                        return;
+                    }
+                }
 
                 let socket = SocketAddr::new(ip, port.get());
                 // Register the vote, this counts towards potentially updating the ENR for external
                 // advertisement
-                self.handle_ip_vote_from_pong(node_id, socket);
+                self.handle_ip_vote_from_pong(
+                    node_id,
+                    active_request.contact.socket_addr().ip(),
+                    socket,
+                );
 
                 // check if we need to request a new ENR
                 if let Some(enr) = self.find_enr(&node_id) {
@@ -861,7 +1545,30 @@ impl Service {
                         self.connection_updated(node_id, ConnectionStatus::PongReceived(enr));
                     }
                 }
+                self.peer_injected(node_id, InjectionOutcome::Verified);
             }
+            ResponseBody::Ticket { ticket, wait_time } => {
+                if let RequestBody::RegisterTopic { topic, .. } = active_request.request_body {
+                    let wait_time = Duration::from_secs(wait_time);
+                    if !self.topic_registrations.answered(
+                        topic,
+                        node_id,
+                        Registration::Ticket { ticket, wait_time },
+                    ) {
+                        debug!(%topic, %node_id, ?wait_time, "Dropping topic registrar");
+                    }
+                }
+            }
+            ResponseBody::RegisterConfirmation { topic } => match active_request.request_body {
+                RequestBody::RegisterTopic {
+                    topic: requested, ..
+                } if requested == topic => {
+                    debug!(%topic, %node_id, "Advertised for topic");
+                    self.topic_registrations
+                        .answered(topic, node_id, Registration::Confirmed);
+                }
+                _ => warn!(%topic, %node_id, "Confirmation for a topic that was not registered"),
+            },
             ResponseBody::Talk { response } => {
                 // Send the response to the user
                 match active_request.callback {
@@ -870,6 +1577,15 @@ impl Service {
                             warn!(error = ?e, "Failed to send callback response")
                         };
                     }
+                    None => {
+                        // Requests of our own, such as ban reports, expect no response, except
+                        // those for dial-backs.
+                        if let Some(candidate) =
+                            dial_back::requested_candidate(&active_request.request_body)
+                        {
+                            self.dial_back_answered(candidate, response == [1]);
+                        }
+                    }
                     _ => error!("Invalid callback for response"),
                 }
             }
@@ -877,9 +1593,19 @@ impl Service {
     }
 
     // We have received a PONG which informs us for our external socket. This function decides
-    // how we should handle this vote and whether or not to update our ENR. This is done on a
-    // majority-based voting system, see `IpVote` for more details.
-    fn handle_ip_vote_from_pong(&mut self, node_id: NodeId, socket: SocketAddr) {
+    // how we should handle this vote and whether or not to update our ENR. By default this is
+    // done on a majority-based voting system, see `IpVote` for more details, unless the
+    // `ExternalAddressPolicy` decides otherwise.
+    fn handle_ip_vote_from_pong(&mut self, node_id: NodeId, voter_ip: IpAddr, socket: SocketAddr) {
+        if !self.config.external_address_policy.updates_from_peers() {
+            return;
+        }
+
+        // An IPv6-only node never advertises an IPv4 address.
+        if self.config.ipv6_only && is_ipv4_or_mapped(&socket) {
+            return;
+        }
+
         // Check that we are in a state to handle any IP votes
         if !self.connectivity_state.should_count_ip_vote(&socket) {
             return;
@@ -888,7 +1614,7 @@ impl Service {
         // Only count votes that are from peers we have contacted.
         let key: kbucket::Key<NodeId> = node_id.into();
         let is_connected_and_outgoing = matches!(
-                        self.kbuckets.write().entry(&key),
+                        self.kbuckets.entry(&key),
                         kbucket::Entry::Present(_, status)
                             if status.is_connected() && !status.is_incoming());
 
@@ -898,90 +1624,285 @@ impl Service {
             return;
         }
 
-        match socket {
-            SocketAddr::V4(_) => {
-                let local_ip4_socket = self.local_enr.read().udp4_socket();
-                if let Some(ip_votes) = self.ip_votes.as_mut() {
-                    ip_votes.insert(node_id, socket);
-                    let maybe_ip4_majority = ip_votes.majority().0;
-
-                    let new_ip4 = maybe_ip4_majority.and_then(|majority| {
-                        if Some(majority) != local_ip4_socket {
-                            Some(majority)
-                        } else {
-                            None
-                        }
-                    });
+        let local_socket = if socket.is_ipv6() {
+            self.local_enr.read().udp6_socket().map(SocketAddr::V6)
+        } else {
+            self.local_enr.read().udp4_socket().map(SocketAddr::V4)
+        };
 
-                    // If we have a new ipv4 majority
-                    if let Some(new_ip4) = new_ip4 {
-                        let new_ip4: SocketAddr = new_ip4.into();
-                        let result = self
-                            .local_enr
-                            .write()
-                            .set_udp_socket(new_ip4, &self.enr_key.read());
-                        match result {
-                            Ok(_) => {
-                                // Inform the connectivity state that we have updated our IP advertisement
-                                self.connectivity_state.enr_socket_update(&new_ip4);
-                                info!(ip_version="v4", %new_ip4, "Local UDP socket updated");
-                                self.send_event(Event::SocketUpdated(new_ip4));
-                                self.ping_connected_peers();
-                            }
-                            Err(e) => {
-                                warn!(ip = %new_ip4, error = ?e, "Failed to update local UDP socket.");
-                            }
-                        }
+        let advertised_port = self.advertised_port(socket.is_ipv6());
+        let new_socket = match &self.config.external_address_policy {
+            ExternalAddressPolicy::Votes => {
+                let weight = self.ip_vote_weight(&node_id, is_connected_and_outgoing);
+                let Some(ip_votes) = self.ip_votes.as_mut() else {
+                    return;
+                };
+                // The observed socket is recorded as is, so the NAT can be classified from the
+                // votes. With a known external port, only the reported IP is voted on.
+                ip_votes.insert_weighted(node_id, socket, voter_ip, weight);
+                if let Some(port) = advertised_port {
+                    let (ip4_majority, ip6_majority) = ip_votes.majority_ip();
+                    match socket {
+                        SocketAddr::V4(_) => ip4_majority.map(IpAddr::V4),
+                        SocketAddr::V6(_) => ip6_majority.map(IpAddr::V6),
+                    }
+                    .map(|ip| SocketAddr::new(ip, port))
+                } else {
+                    let (ip4_majority, ip6_majority) = ip_votes.majority();
+                    match socket {
+                        SocketAddr::V4(_) => ip4_majority.map(SocketAddr::V4),
+                        SocketAddr::V6(_) => ip6_majority.map(SocketAddr::V6),
                     }
                 }
             }
-            SocketAddr::V6(_) => {
-                let local_ip6_socket = self.local_enr.read().udp6_socket();
-                if let Some(ip_votes) = self.ip_votes.as_mut() {
-                    ip_votes.insert(node_id, socket);
-                    let maybe_ip6_majority = ip_votes.majority().1;
+            ExternalAddressPolicy::Custom(accept) => {
+                let socket = SocketAddr::new(socket.ip(), advertised_port.unwrap_or(socket.port()));
+                (Some(socket) != local_socket && accept(socket)).then_some(socket)
+            }
+            ExternalAddressPolicy::Fixed(_) | ExternalAddressPolicy::Disabled => None,
+        };
 
-                    let new_ip6 = maybe_ip6_majority.and_then(|majority| {
-                        if Some(majority) != local_ip6_socket {
-                            Some(majority)
-                        } else {
-                            None
-                        }
-                    });
-                    // Check if our advertised IPV6 address needs to be updated.
-                    if let Some(new_ip6) = new_ip6 {
-                        let new_ip6: SocketAddr = new_ip6.into();
-                        let result = self
-                            .local_enr
-                            .write()
-                            .set_udp_socket(new_ip6, &self.enr_key.read());
-                        match result {
-                            Ok(_) => {
-                                // Inform the connectivity state that we have updated our IP advertisement
-                                self.connectivity_state.enr_socket_update(&new_ip6);
-                                info!(ip_version="v6", %new_ip6, "Local UDP socket updated");
-                                self.send_event(Event::SocketUpdated(new_ip6));
-                                self.ping_connected_peers();
-                            }
-                            Err(e) => {
-                                warn!(ip6 = %new_ip6, error = ?e, "Failed to update local UDP ip6 socket.");
-                            }
-                        }
-                    }
+        // If the advertised address needs to be updated.
+        if let Some(new_socket) = new_socket.filter(|new_socket| Some(*new_socket) != local_socket)
+        {
+            let is_voted = matches!(
+                self.config.external_address_policy,
+                ExternalAddressPolicy::Votes
+            );
+            if is_voted && self.dial_back.is_some() {
+                self.request_dial_backs(new_socket);
+            } else {
+                self.update_local_socket(new_socket);
+            }
+        }
+
+        self.update_nat_status();
+    }
+
+    /// Advertises the socket as our external UDP socket.
+    fn update_local_socket(&mut self, new_socket: SocketAddr) {
+        let ip_version = if new_socket.is_ipv6() { "v6" } else { "v4" };
+        let result = self.enr_watch.update(&self.local_enr, |enr| {
+            enr.set_udp_socket(new_socket, &self.enr_key.read())
+        });
+        match result {
+            Ok(_) => {
+                // Inform the connectivity state that we have updated our IP advertisement
+                self.connectivity_state.enr_socket_update(&new_socket);
+                info!(ip_version, %new_socket, "Local UDP socket updated");
+                self.send_event(Event::SocketUpdated(new_socket));
+                self.advertise_local_enr();
+            }
+            Err(e) => {
+                warn!(ip_version, ip = %new_socket, error = ?e, "Failed to update local UDP socket.");
+            }
+        }
+    }
+
+    /// Asks connected peers to ping us at the candidate external socket before it is advertised,
+    /// see [`Config::dial_back`]. Peers that voted for the candidate are asked last, as their
+    /// sessions with us are likely at the candidate already.
+    fn request_dial_backs(&mut self, candidate: SocketAddr) {
+        let Some(dial_back) = self.dial_back.as_mut() else {
+            return;
+        };
+        if !dial_back.should_verify(&candidate) {
+            return;
+        }
+        let mut helpers: Vec<Arc<Enr>> = self
+            .kbuckets
+            .iter()
+            .filter(|entry| entry.status.is_connected())
+            .map(|entry| entry.node.value.clone())
+            .collect();
+        helpers.shuffle(&mut rand::thread_rng());
+        if let Some(ip_votes) = self.ip_votes.as_ref() {
+            helpers.sort_by_key(|enr| ip_votes.voted_for(&enr.node_id(), &candidate));
+        }
+        helpers.truncate(dial_back.config().helpers);
+        if dial_back.start(candidate, helpers.len()) == Verified::Rejected {
+            info!(%candidate, helpers = helpers.len(), "Too few peers to verify the external socket");
+            return;
+        }
+
+        debug!(%candidate, helpers = helpers.len(), "Verifying the external socket");
+        let request = dial_back::encode_candidate(&candidate);
+        for enr in helpers {
+            match self.contact_from_enr(enr) {
+                Ok(contact) => {
+                    let active_request = ActiveRequest {
+                        contact,
+                        request_body: RequestBody::Talk {
+                            protocol: DIAL_BACK_PROTOCOL.to_vec(),
+                            request: request.clone(),
+                        },
+                        query_id: None,
+                        callback: None,
+                    };
+                    self.send_rpc_request(active_request);
+                }
+                Err(NonContactable { enr }) => {
+                    debug_unreachable!("Stored ENR is not contactable. {}", enr);
+                    error!(%enr, "Stored ENR is not contactable! This should never happen");
+                    self.dial_back_answered(candidate, false);
                 }
             }
         }
     }
 
+    /// Records whether a peer reached us at the candidate external socket, advertising it once
+    /// enough peers have.
+    fn dial_back_answered(&mut self, candidate: SocketAddr, reached: bool) {
+        let Some(dial_back) = self.dial_back.as_mut() else {
+            return;
+        };
+        match dial_back.answered(candidate, reached) {
+            Verified::Confirmed => {
+                self.update_local_socket(candidate);
+                self.update_nat_status();
+            }
+            Verified::Rejected => {
+                warn!(%candidate, "Peers could not reach us at the external socket, not advertising it");
+            }
+            Verified::Pending => {}
+        }
+    }
+
+    /// Pings the requester at the socket it asks to be verified, answering whether it responded.
+    fn answer_dial_back(&mut self, request: TalkRequest) {
+        let Some(candidate) = dial_back::decode_candidate(&request.body) else {
+            // Dropping the request answers it with an empty response.
+            debug!(node_address = %request.node_address, "Invalid dial-back request");
+            return;
+        };
+        let Some(dial_back) = self.dial_back.as_mut() else {
+            return;
+        };
+        if !dial_back.may_dial_back(&request.node_address, &candidate) {
+            debug!(node_address = %request.node_address, %candidate, "Refused dial-back request");
+            return;
+        }
+        let node_id = request.node_address.node_id;
+        let Some(enr) = self.find_enr(&node_id) else {
+            if let Err(error) = request.respond(vec![0]) {
+                debug!(%error, %node_id, "Failed to answer a dial-back request");
+            }
+            return;
+        };
+        let contact = NodeContact::new(enr.public_key(), candidate, Some(enr));
+        let Some(dial_back) = self.dial_back.as_mut() else {
+            return;
+        };
+        if dial_back.answer(contact.node_address(), request) {
+            debug!(%node_id, %candidate, "Dialing back peer");
+            let request_body = RequestBody::Ping {
+                enr_seq: self.local_enr.read().seq(),
+            };
+            self.send_rpc_request(ActiveRequest {
+                contact,
+                request_body,
+                query_id: None,
+                callback: None,
+            });
+        }
+    }
+
+    /// The weight of the vote of a peer on our external address, see
+    /// [`Config::weighted_ip_votes`].
+    fn ip_vote_weight(&self, node_id: &NodeId, is_connected: bool) -> u32 {
+        if !self.config.weighted_ip_votes {
+            return 1;
+        }
+        let now = self.config.clock.now();
+        let established_session = self
+            .session_starts
+            .peek(node_id)
+            .is_some_and(|start| now.saturating_duration_since(*start) >= IP_VOTE_SESSION_AGE);
+        let responsive = self.peer_rtts.peek(node_id).is_some()
+            && !self.successive_failures.contains_key(node_id);
+        1 + u32::from(is_connected) + u32::from(established_session) + u32::from(responsive)
+    }
+
+    /// The configured port to advertise in place of the observed port of the IP version, if any.
+    /// Votes record the observed socket, the advertised port only applies to the ENR.
+    fn advertised_port(&self, is_ipv6: bool) -> Option<u16> {
+        if is_ipv6 {
+            self.config.advertised_udp6_port
+        } else {
+            self.config.advertised_udp4_port
+        }
+    }
+
+    /// Re-classifies our NAT from the current IP votes and informs the application if the
+    /// classification has changed.
+    fn update_nat_status(&mut self) {
+        let Some(ip_votes) = self.ip_votes.as_mut() else {
+            return;
+        };
+        let (listen_ipv4, listen_ipv6) = self.config.listen_config.socket_addrs();
+        let nat_status = ip_votes.nat_status(listen_ipv4, listen_ipv6);
+        if *self.nat_status.read() != nat_status {
+            *self.nat_status.write() = nat_status;
+            info!(ipv4 = ?nat_status.ipv4, ipv6 = ?nat_status.ipv6, "NAT status updated");
+            self.send_event(Event::NatStatusChanged(nat_status));
+        }
+    }
+
     // Send RPC Requests //
 
+    /// Resolves the address to contact a node at. For dual stack peers, the IP version a session
+    /// was last established over takes precedence over the configured dial policy.
+    fn contact_from_enr(&self, enr: Arc<Enr>) -> Result<NodeContact, NonContactable> {
+        let prefer_ipv6 = self
+            .dial_preferences
+            .peek(&enr.node_id())
+            .copied()
+            .unwrap_or_else(|| self.config.dial_policy.prefers_ipv6());
+        NodeContact::try_from_enr_preferring(enr, self.ip_mode, prefer_ipv6)
+    }
+
+    /// Keeps dialing a dual stack peer over the IP version we just exchanged packets with it over,
+    /// and cancels its pending happy eyeballs fallback.
+    fn record_dial_preference(&mut self, node_id: NodeId, socket: &SocketAddr) {
+        if self.ip_mode == IpMode::DualStack {
+            self.dial_preferences.insert(node_id, socket.is_ipv6());
+            self.happy_eyeballs.remove(&node_id);
+        }
+    }
+
+    /// The happy eyeballs stagger of a dual stack peer expired. If we have neither established a
+    /// session with it nor received a response from it in the meantime, it is pinged on its IPv4
+    /// address.
+    fn send_happy_eyeballs_ping(&mut self, enr: Arc<Enr>) {
+        if self.dial_preferences.peek(&enr.node_id()).is_some() {
+            return;
+        }
+        let Some(socket_addr) = self
+            .ip_mode
+            .get_contactable_addr_preferring(&enr, false)
+            .filter(SocketAddr::is_ipv4)
+        else {
+            return;
+        };
+        trace!(node_id = %enr.node_id(), %socket_addr, "Happy eyeballs fallback to IPv4");
+        let active_request = ActiveRequest {
+            contact: NodeContact::new(enr.public_key(), socket_addr, Some(enr)),
+            request_body: RequestBody::Ping {
+                enr_seq: self.local_enr.read().seq(),
+            },
+            query_id: None,
+            callback: None,
+        };
+        self.send_rpc_request(active_request);
+    }
+
     /// Sends a PING request to a node.
     fn send_ping(
         &mut self,
-        enr: Enr,
+        enr: Arc<Enr>,
         callback: Option<oneshot::Sender<Result<Pong, RequestError>>>,
     ) {
-        match NodeContact::try_from_enr(enr, self.ip_mode) {
+        match self.contact_from_enr(enr) {
             Ok(contact) => {
                 let request_body = RequestBody::Ping {
                     enr_seq: self.local_enr.read().seq(),
@@ -1002,7 +1923,7 @@ impl Service {
     fn ping_connected_peers(&mut self) {
         // maintain the ping interval
         let connected_peers = {
-            let mut kbuckets = self.kbuckets.write();
+            let kbuckets = &self.kbuckets;
             kbuckets
                 .iter()
                 .filter_map(|entry| {
@@ -1020,18 +1941,114 @@ impl Service {
         }
     }
 
-    /// Request an external node's ENR.
-    fn request_find_node_designated_peer(
-        &mut self,
-        contact: NodeContact,
-        distances: Vec<u64>,
-        callback: Option<oneshot::Sender<Result<Vec<Enr>, RequestError>>>,
-    ) {
-        let request_body = RequestBody::FindNode { distances };
-        let active_request = ActiveRequest {
-            contact,
-            request_body,
-            query_id: None,
+    /// Informs connected peers of a change to our ENR. If re-advertisement is paced, the peers
+    /// are queued and pinged a few at a time, otherwise they are all pinged at once.
+    fn advertise_local_enr(&mut self) {
+        if self.readvertise.is_some() {
+            self.queue_readvertise();
+        } else {
+            self.ping_connected_peers();
+        }
+    }
+
+    /// Replaces the re-advertisement queue with the currently connected peers.
+    fn queue_readvertise(&mut self) {
+        self.advertised_enr_seq = self.local_enr.read().seq();
+        self.readvertise_queue = self
+            .kbuckets
+            .iter()
+            .filter(|entry| entry.status.is_connected())
+            .map(|entry| *entry.node.key.preimage())
+            .collect();
+        debug!(
+            enr_seq = self.advertised_enr_seq,
+            peers = self.readvertise_queue.len(),
+            "Re-advertising local ENR"
+        );
+    }
+
+    /// Pings the next batch of peers awaiting our current ENR sequence number. Changes made to the
+    /// local ENR outside of the service are picked up here.
+    fn send_readvertise_pings(&mut self) {
+        if self.local_enr.read().seq() != self.advertised_enr_seq {
+            self.queue_readvertise();
+        }
+        let fanout = self
+            .config
+            .enr_readvertise
+            .map(|(fanout, _)| fanout)
+            .unwrap_or_default();
+        for _ in 0..fanout {
+            let Some(node_id) = self.readvertise_queue.pop_front() else {
+                break;
+            };
+            if let Some(enr) = self.find_enr(&node_id) {
+                self.send_ping(enr, None);
+            }
+        }
+    }
+
+    /// Returns whether the ENR may be admitted to the routing table.
+    fn is_allowed(&self, enr: &Enr) -> bool {
+        match &self.config.enr_allowlist {
+            Some(allowlist) => allowlist.allows(enr),
+            None => true,
+        }
+    }
+
+    /// Sends the handler the nodes whose sessions are kept in the reserved part of its session
+    /// cache, the members of the routing table, the peers on the permit list and the bootnodes, if
+    /// they have changed.
+    fn refresh_reserved_sessions(&mut self) {
+        let mut nodes: HashSet<NodeId> = self
+            .kbuckets
+            .iter_ref()
+            .map(|entry| *entry.node.key.preimage())
+            .collect();
+        nodes.extend(PERMIT_BAN_LIST.read().permit_nodes.iter().copied());
+        nodes.extend(self.bootnodes.read().node_ids().copied());
+        if nodes == self.reserved_nodes {
+            return;
+        }
+        self.reserved_nodes = nodes.clone();
+        if let Err(e) = send_to_handler(&self.handler_send, HandlerIn::ReservedNodes(nodes)) {
+            warn!(error = %e, "Failed to send the reserved nodes to the handler");
+            // Retried on the next refresh.
+            self.reserved_nodes.clear();
+        }
+    }
+
+    /// Pings the bootnodes whose backoff has elapsed, if we have no connected peers.
+    fn retry_bootnodes(&mut self) {
+        if self.bootnodes.read().is_empty() {
+            return;
+        }
+        let has_connected_peers = self
+            .kbuckets
+            .iter_ref()
+            .any(|entry| entry.status.is_connected());
+        if has_connected_peers {
+            return;
+        }
+        let due = self.bootnodes.write().due(self.config.clock.now());
+        for enr in due {
+            debug!(node_id = %enr.node_id(), "No connected peers, retrying bootnode");
+            self.send_ping(Arc::new(enr), None);
+        }
+    }
+
+    /// Request an external node's ENR.
+    fn request_find_node_designated_peer(
+        &mut self,
+        contact: NodeContact,
+        distances: Vec<u64>,
+        callback: Option<oneshot::Sender<Result<Vec<Arc<Enr>>, RequestError>>>,
+    ) {
+        let request_body = RequestBody::FindNode { distances };
+        let active_request = ActiveRequest {
+            contact,
+            request_body,
+            query_id: None,
             callback: callback.map(CallbackResponse::Nodes),
         };
         self.send_rpc_request(active_request);
@@ -1043,6 +2060,7 @@ impl Service {
         contact: NodeContact,
         protocol: Vec<u8>,
         request: Vec<u8>,
+        timeout: Option<Duration>,
         callback: oneshot::Sender<Result<Vec<u8>, RequestError>>,
     ) {
         let request_body = RequestBody::Talk { protocol, request };
@@ -1053,7 +2071,7 @@ impl Service {
             query_id: None,
             callback: Some(CallbackResponse::Talk(callback)),
         };
-        self.send_rpc_request(active_request);
+        self.send_rpc_request_with_timeout(active_request, timeout);
     }
 
     /// Sends a NODES response, given a list of found ENR's. This function splits the nodes up
@@ -1070,28 +2088,48 @@ impl Service {
 
         if let Some(0) = distances.first() {
             // if the distance is 0 send our local ENR
-            nodes_to_send.push(self.local_enr.read().clone());
+            nodes_to_send.push(Arc::new(self.local_enr.read().clone()));
             debug!("Sending our ENR to node: {}", node_address);
             distances.remove(0);
         }
 
         if !distances.is_empty() {
-            let mut kbuckets = self.kbuckets.write();
-            for node in kbuckets
-                .nodes_by_distances(distances.as_slice(), self.config.max_nodes_response)
-                .into_iter()
-                .filter_map(|entry| {
-                    if entry.node.key.preimage() != &node_address.node_id {
-                        Some(entry.node.value.clone())
-                    } else {
-                        None
+            let cached = self
+                .nodes_response_cache
+                .as_mut()
+                .and_then(|cache| cache.get(&distances).cloned());
+            let nodes = match cached {
+                Some(nodes) => nodes,
+                None => {
+                    let nodes = nodes_response::select_nodes(
+                        &self.kbuckets,
+                        &distances,
+                        self.config.max_nodes_response,
+                        self.config.nodes_selection,
+                    );
+                    if let Some(cache) = self.nodes_response_cache.as_mut() {
+                        cache.insert(distances.clone(), nodes.clone());
                     }
-                })
-            {
-                nodes_to_send.push(node);
-            }
+                    nodes
+                }
+            };
+            nodes_to_send.extend(
+                nodes
+                    .into_iter()
+                    .filter(|enr| enr.node_id() != node_address.node_id),
+            );
         }
 
+        self.send_nodes(node_address, rpc_id, nodes_to_send);
+    }
+
+    /// Sends a NODES response, split into as many packets as it takes.
+    fn send_nodes(
+        &mut self,
+        node_address: NodeAddress,
+        rpc_id: RequestId,
+        nodes_to_send: Vec<Arc<Enr>>,
+    ) {
         // if there are no nodes, send an empty response
         if nodes_to_send.is_empty() {
             let response = Response {
@@ -1105,57 +2143,21 @@ impl Service {
                 to = %node_address.node_id,
                 "Sending empty FINDNODES response",
             );
-            if let Err(e) = self
-                .handler_send
-                .send(HandlerIn::Response(node_address, Box::new(response)))
-            {
+            if let Err(e) = send_to_handler(
+                &self.handler_send,
+                HandlerIn::Response(node_address, Box::new(response)),
+            ) {
                 warn!(error = %e, "Failed to send empty FINDNODES response")
             }
         } else {
             // build the NODES response
-            let mut to_send_nodes: Vec<Vec<Enr>> = Vec::new();
-            let mut total_size = 0;
-            let mut rpc_index = 0;
-            to_send_nodes.push(Vec::new());
-            for enr in nodes_to_send.into_iter() {
-                let entry_size = alloy_rlp::encode(&enr).len();
-                // Responses assume that a session is established. Thus, on top of the encoded
-                // ENR's the packet should be a regular message. A regular message has an IV (16
-                // bytes), and a header of 55 bytes. The find-nodes RPC requires 16 bytes for the ID and the
-                // `total` field. Also there is a 16 byte HMAC for encryption and an extra byte for
-                // RLP encoding.
-                //
-                // We could also be responding via an authheader which can take up to 282 bytes in its
-                // header.
-                // As most messages will be normal messages we will try and pack as many ENR's we
-                // can in and drop the response packet if a user requests an auth message of a very
-                // packed response.
-                //
-                // The estimated total overhead for a regular message is therefore 104 bytes.
-                if entry_size + total_size < MAX_PACKET_SIZE - 104 {
-                    total_size += entry_size;
-                    trace!(
-                        %enr,
-                        entry_size,
-                        total_size,
-                        "Adding ENR",
-                    );
-                    to_send_nodes[rpc_index].push(enr);
-                } else {
-                    total_size = entry_size;
-                    to_send_nodes.push(vec![enr]);
-                    rpc_index += 1;
-                }
-            }
-
+            let to_send_nodes = nodes_response::pack_nodes(nodes_to_send);
+            let total = to_send_nodes.len() as u64;
             let responses: Vec<Response> = to_send_nodes
                 .into_iter()
                 .map(|nodes| Response {
                     id: rpc_id.clone(),
-                    body: ResponseBody::Nodes {
-                        total: (rpc_index + 1) as u64,
-                        nodes,
-                    },
+                    body: ResponseBody::Nodes { total, nodes },
                 })
                 .collect();
 
@@ -1165,10 +2167,10 @@ impl Service {
                     %response,
                     "Sending FINDNODES response",
                 );
-                if let Err(e) = self.handler_send.send(HandlerIn::Response(
-                    node_address.clone(),
-                    Box::new(response),
-                )) {
+                if let Err(e) = send_to_handler(
+                    &self.handler_send,
+                    HandlerIn::Response(node_address.clone(), Box::new(response)),
+                ) {
                     warn!(error = %e, "Failed to send FINDNODES response")
                 }
             }
@@ -1182,9 +2184,14 @@ impl Service {
         return_peer: NodeId,
         request_body: RequestBody,
     ) {
+        if let RequestBody::FindNode { distances } = &request_body {
+            self.trace_query(query_id, |tracer, now| {
+                tracer.asked(return_peer, distances.clone(), now)
+            });
+        }
         // find the ENR associated with the query
         if let Some(enr) = self.find_enr(&return_peer) {
-            match NodeContact::try_from_enr(enr, self.ip_mode) {
+            match self.contact_from_enr(enr) {
                 Ok(contact) => {
                     let active_request = ActiveRequest {
                         contact,
@@ -1210,13 +2217,48 @@ impl Service {
         // query of the failed request.
         // TODO: Come up with a better design to ensure that all query RPC requests
         // are forced to be responded to.
+        self.trace_query(query_id, |tracer, now| {
+            tracer.failed(&return_peer, "No contactable ENR".into(), now)
+        });
         if let Some(query) = self.queries.get_mut(query_id) {
             query.on_failure(&return_peer);
         }
     }
 
+    /// Records an event of the query, if it is traced.
+    fn trace_query(&mut self, query_id: QueryId, record: impl FnOnce(&mut QueryTracer, Instant)) {
+        let now = self.config.clock.now();
+        if let Some(tracer) = self
+            .queries
+            .get_mut(query_id)
+            .and_then(|query| query.target_mut().trace.as_mut())
+        {
+            record(tracer, now);
+        }
+    }
+
+    /// Starts timing an iteration of the service loop spent on the task, sampling the depths of
+    /// the queues of application requests and of events.
+    fn task_timer(&self, task: ServiceTask) -> ServiceTaskTimer {
+        let event_queue_depth = match &self.event_stream {
+            Some(stream) => stream.max_capacity() - stream.capacity(),
+            None => 0,
+        };
+        ServiceTaskTimer::start(task, self.discv5_recv.len(), event_queue_depth)
+    }
+
     /// Sends generic RPC requests. Each request gets added to known outputs, awaiting a response.
     fn send_rpc_request(&mut self, active_request: ActiveRequest) {
+        self.send_rpc_request_with_timeout(active_request, None)
+    }
+
+    /// Sends a request that fails once `timeout` elapses, if given, rather than after the request
+    /// timeout and retries of the configuration.
+    fn send_rpc_request_with_timeout(
+        &mut self,
+        active_request: ActiveRequest,
+        timeout: Option<Duration>,
+    ) {
         // Generate a random rpc_id which is matched per node id
         let id = RequestId::random();
         let request: Request = Request {
@@ -1224,60 +2266,154 @@ impl Service {
             body: active_request.request_body.clone(),
         };
         let contact = active_request.contact.clone();
+        let priority = if PERMIT_BAN_LIST
+            .read()
+            .permit_nodes
+            .contains(&contact.node_id())
+            || self.bootnodes.read().contains(&contact.node_id())
+        {
+            RequestPriority::Pinned
+        } else if active_request.query_id.is_some() || active_request.callback.is_some() {
+            RequestPriority::Query
+        } else {
+            RequestPriority::Maintenance
+        };
+
+        debug!(request_id = %id, body = %request.body, node = %contact, "Sending RPC to node");
+        match send_to_handler(
+            &self.handler_send,
+            HandlerIn::Request(contact.clone(), Box::new(request), priority, timeout),
+        ) {
+            Ok(()) => {
+                self.active_requests.insert(id, active_request);
+                self.queue_happy_eyeballs(&contact);
+            }
+            Err(e) => {
+                // Fail the request right away, so that callers and queries don't wait on it.
+                warn!(error = %e, node = %contact, "Failed to send request to the handler");
+                self.active_requests.insert(id.clone(), active_request);
+                self.rpc_failure(id, RequestError::ChannelFailed(e.to_string()));
+            }
+        }
+    }
 
-        debug!(%request, node = %contact,"Sending RPC to node");
-        if self
-            .handler_send
-            .send(HandlerIn::Request(contact, Box::new(request)))
-            .is_ok()
+    /// Under the happy eyeballs dial policy, schedules the IPv4 fallback for a dual stack peer
+    /// we are contacting over IPv6 for the first time.
+    fn queue_happy_eyeballs(&mut self, contact: &NodeContact) {
+        if !matches!(self.config.dial_policy, DialPolicy::HappyEyeballs { .. })
+            || !contact.socket_addr().is_ipv6()
         {
-            self.active_requests.insert(id, active_request);
+            return;
+        }
+        let node_id = contact.node_id();
+        if self.dial_preferences.peek(&node_id).is_some()
+            || self.happy_eyeballs.contains_key(&node_id)
+        {
+            return;
+        }
+        if let Some(enr) = contact.enr() {
+            if self
+                .ip_mode
+                .get_alternate_addr(&enr, &contact.socket_addr())
+                .is_some()
+            {
+                self.happy_eyeballs.insert(node_id, enr);
+            }
         }
     }
 
     fn send_event(&mut self, event: Event) {
         if let Some(stream) = self.event_stream.as_mut() {
-            if let Err(mpsc::error::TrySendError::Closed(_)) = stream.try_send(event) {
-                // If the stream has been dropped prevent future attempts to send events
-                self.event_stream = None;
+            match stream.try_send(event) {
+                Ok(()) => METRICS.set_queue_depth(&METRICS.event_queue_depth, stream),
+                Err(TrySendError::Full(_)) => {
+                    METRICS.events_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    // If the stream has been dropped prevent future attempts to send events
+                    self.event_stream = None;
+                }
             }
         }
     }
 
+    /// Whether the ENR is new or updated since it was last reported as discovered. Always true if
+    /// discovered peers aren't deduplicated.
+    fn is_newly_discovered(&mut self, enr: &Enr) -> bool {
+        let Some(reported) = self.reported_discovered.as_mut() else {
+            return true;
+        };
+        if reported
+            .get(&enr.node_id())
+            .is_some_and(|seq| *seq >= enr.seq())
+        {
+            return false;
+        }
+        reported.insert(enr.node_id(), enr.seq());
+        true
+    }
+
     /// Processes discovered peers from a query.
-    fn discovered(&mut self, source: &NodeId, mut enrs: Vec<Enr>, query_id: Option<QueryId>) {
+    fn discovered(&mut self, source: &NodeId, mut enrs: Vec<Arc<Enr>>, query_id: Option<QueryId>) {
+        if let Some(query_id) = query_id {
+            let nodes = enrs.iter().map(|enr| enr.node_id()).collect();
+            self.trace_query(query_id, |tracer, now| tracer.responded(source, nodes, now));
+        }
         let local_id = self.local_enr.read().node_id();
+        let mut unreachable_enrs = Vec::new();
         enrs.retain(|enr| {
             if enr.node_id() == local_id {
                 return false;
             }
 
-            // If there is an event stream send the Discovered event
-            if self.config.report_discovered_peers {
-                self.send_event(Event::Discovered(enr.clone()));
+            if let Some(enr_store) = self.enr_store.as_ref() {
+                enr_store.write().insert(enr.clone());
+            }
+            if let Some(monitor) = self.eclipse_monitor.as_mut() {
+                monitor.seen(enr.node_id());
+            }
+
+            let reachability = self.ip_mode.reachability(enr);
+            let unreachable =
+                self.config.report_unreachable_enrs && reachability != EnrReachability::Reachable;
+
+            // If there is an event stream send the Discovered event, unless the ENR was reported
+            // recently
+            if self.config.report_discovered_peers && self.is_newly_discovered(enr) {
+                if unreachable {
+                    self.send_event(Event::DiscoveredUnreachable {
+                        enr: enr.clone(),
+                        reachability,
+                    });
+                } else {
+                    self.send_event(Event::Discovered(enr.clone()));
+                }
             }
 
             // Check that peers are compatible to be included into the routing table. They must:
             // - Pass the table filter
+            // - Be in the ENR allowlist, if configured
             // - Be contactable
             //
             // Failing this, they are not added, and if there is an older version of them in our
             // table, we remove them.
             let key = kbucket::Key::from(enr.node_id());
-            if (self.config.table_filter)(enr) && self.ip_mode.get_contactable_addr(enr).is_some() {
+            let compatible = (self.config.table_filter)(enr) && self.is_allowed(enr);
+            if compatible && reachability == EnrReachability::Reachable {
                 // If the ENR exists in the routing table and the discovered ENR has a greater
                 // sequence number, perform some filter checks before updating the enr.
 
-                let must_update_enr = match self.kbuckets.write().entry(&key) {
+                let must_update_enr = match self.kbuckets.entry(&key) {
                     kbucket::Entry::Present(entry, _) => entry.value().seq() < enr.seq(),
                     kbucket::Entry::Pending(mut entry, _) => entry.value().seq() < enr.seq(),
                     _ => false,
                 };
 
                 if must_update_enr {
-                    if let UpdateResult::Failed(reason) =
-                        self.kbuckets.write().update_node(&key, enr.clone(), None)
-                    {
+                    let previous_enr = self.watched_enr(&key);
+                    let update_result = self.kbuckets.update_node(&key, enr.clone(), None);
+                    self.report_enr_update(&key, previous_enr);
+                    if let UpdateResult::Failed(reason) = update_result {
                         self.peers_to_ping.remove(&enr.node_id());
                         debug!(node = %source, ?reason, "Failed to update discovered ENR.");
 
@@ -1287,7 +2423,7 @@ impl Service {
             } else {
                 // Is either non-contactable or didn't pass the table filter. If it exists in the
                 // routing table, remove it.
-                match self.kbuckets.write().entry(&key) {
+                match self.kbuckets.entry(&key) {
                     kbucket::Entry::Present(entry, _) if entry.value().seq() < enr.seq() => {
                         entry.remove()
                     }
@@ -1299,6 +2435,9 @@ impl Service {
                     _ => {}
                 }
 
+                if compatible && unreachable {
+                    unreachable_enrs.push(enr.clone());
+                }
                 // Didn't pass the requirements remove the ENR
                 return false;
             }
@@ -1325,8 +2464,29 @@ impl Service {
                     }
                     peer_count += 1;
                 }
+                if let Some(unreachable) = query.target_mut().unreachable_enrs.as_mut() {
+                    for enr in unreachable_enrs {
+                        if !unreachable.iter().any(|e| e.node_id() == enr.node_id()) {
+                            unreachable.push(enr);
+                        }
+                    }
+                }
                 debug!(peer_count, ?query_id, "peers found for query id");
-                query.on_success(source, &enrs)
+                query.on_success(source, enrs.iter().map(|enr| &**enr));
+                if self.config.query_peer_limit.is_some() {
+                    // Forget the ENRs of the peers the query has dropped.
+                    let tracked: Vec<bool> = query
+                        .target()
+                        .untrusted_enrs
+                        .iter()
+                        .map(|enr| query.contains(&enr.node_id()))
+                        .collect();
+                    let mut tracked = tracked.into_iter();
+                    query
+                        .target_mut()
+                        .untrusted_enrs
+                        .retain(|_| tracked.next().unwrap_or_default());
+                }
             } else {
                 debug!(?query_id, "Response returned for ended query")
             }
@@ -1342,6 +2502,7 @@ impl Service {
         let mut event_to_send = None;
 
         let key = kbucket::Key::from(node_id);
+        let previous_enr = self.watched_enr(&key);
         match new_status {
             ConnectionStatus::Connected(enr, direction) => {
                 // attempt to update or insert the new ENR.
@@ -1350,25 +2511,25 @@ impl Service {
                     direction,
                 };
 
-                let insert_result =
-                    self.kbuckets
-                        .write()
-                        .insert_or_update(&key, enr.clone(), status);
+                let insert_result = self.kbuckets.insert_or_update(&key, enr.clone(), status);
                 match insert_result {
                     InsertResult::Inserted => {
                         // We added this peer to the table
                         debug!(%node_id, "New connected node added to routing table");
                         self.peers_to_ping.insert(node_id);
+                        self.check_eclipse_insertion(&enr);
 
                         // PING immediately if the direction is outgoing. This allows us to receive
                         // a PONG without waiting for the ping_interval, making ENR updates faster.
-                        if direction == ConnectionDirection::Outgoing {
+                        if direction == ConnectionDirection::Outgoing && !self.config.bootnode_mode
+                        {
                             self.send_ping(enr, None);
                         }
 
                         let event = Event::NodeInserted {
                             node_id,
                             replaced: None,
+                            direction,
                         };
                         event_to_send = Some(event);
                     }
@@ -1397,6 +2558,7 @@ impl Service {
                         // forced on us) then lets get a PONG from this node.
 
                         if direction == ConnectionDirection::Outgoing
+                            && !self.config.bootnode_mode
                             && self.require_more_ip_votes(enr.udp6_socket().is_some())
                         {
                             self.send_ping(enr, None);
@@ -1410,7 +2572,6 @@ impl Service {
             ConnectionStatus::PongReceived(enr) => {
                 match self
                     .kbuckets
-                    .write()
                     .update_node(&key, enr, Some(ConnectionState::Connected))
                 {
                     UpdateResult::Failed(reason) => {
@@ -1424,11 +2585,10 @@ impl Service {
             }
             ConnectionStatus::Disconnected => {
                 // If the node has disconnected, remove any ping timer for the node.
-                match self.kbuckets.write().update_node_status(
-                    &key,
-                    ConnectionState::Disconnected,
-                    None,
-                ) {
+                match self
+                    .kbuckets
+                    .update_node_status(&key, ConnectionState::Disconnected, None)
+                {
                     UpdateResult::Failed(reason) => match reason {
                         FailureReason::KeyNonExistent => {}
                         others => {
@@ -1449,15 +2609,15 @@ impl Service {
 
         // Post processing
 
+        self.report_enr_update(&key, previous_enr);
+
         if let Some(event) = event_to_send {
             self.send_event(event);
         }
 
         if let Some(node_key) = ping_peer {
             let optional_enr = {
-                if let kbucket::Entry::Present(entry, _status) =
-                    self.kbuckets.write().entry(&node_key)
-                {
+                if let kbucket::Entry::Present(entry, _status) = self.kbuckets.entry(&node_key) {
                     // NOTE: We don't check the status of this peer. We try and ping outdated peers.
                     Some(entry.value().clone())
                 } else {
@@ -1470,11 +2630,31 @@ impl Service {
         }
     }
 
+    /// The ENR of a node of the routing table, if any ENR key is watched.
+    fn watched_enr(&self, key: &kbucket::Key<NodeId>) -> Option<Arc<Enr>> {
+        if !self.enr_watch.is_watching() {
+            return None;
+        }
+        self.kbuckets
+            .get_bucket(key)
+            .and_then(|bucket| bucket.get(key).map(|node| node.value.clone()))
+    }
+
+    /// Reports the changes of the watched ENR keys made by an update of the routing table, given
+    /// the ENR the node had before.
+    fn report_enr_update(&self, key: &kbucket::Key<NodeId>, previous_enr: Option<Arc<Enr>>) {
+        if let Some(previous_enr) = previous_enr {
+            if let Some(enr) = self.watched_enr(key) {
+                self.enr_watch.compare(&previous_enr, &enr);
+            }
+        }
+    }
+
     /// The equivalent of libp2p `inject_connected()` for a udp session. We have no stream, but a
     /// session key-pair has been negotiated.
     fn inject_session_established(
         &mut self,
-        enr: Enr,
+        enr: Arc<Enr>,
         socket: &SocketAddr,
         connection_direction: ConnectionDirection,
     ) {
@@ -1483,6 +2663,12 @@ impl Service {
         if matches!(connection_direction, ConnectionDirection::Incoming) {
             self.connectivity_state.received_incoming_connection(socket);
         }
+        self.record_dial_preference(enr.node_id(), socket);
+
+        if let Some(enr_store) = self.enr_store.as_ref() {
+            enr_store.write().insert(enr.clone());
+        }
+        self.bootnodes.write().success(&enr);
 
         // Ignore sessions with non-contactable ENRs
         if self.ip_mode.get_contactable_addr(&enr).is_none() {
@@ -1496,12 +2682,11 @@ impl Service {
         let key = kbucket::Key::from(node_id);
         let direction = match self
             .kbuckets
-            .read()
             .get_bucket(&key)
-            .map(|bucket| bucket.get(&key))
+            .and_then(|bucket| bucket.get(&key).map(|node| node.status.direction))
         {
-            Some(Some(node)) => node.status.direction,
-            _ => connection_direction,
+            Some(direction) => direction,
+            None => connection_direction,
         };
 
         debug!(node = %node_id, %direction, %socket, "Session established with Node");
@@ -1511,8 +2696,24 @@ impl Service {
     /// A session could not be established or an RPC request timed-out (after a few retries, if
     /// specified).
     fn rpc_failure(&mut self, id: RequestId, error: RequestError) {
-        trace!(reason = ?error, %id, "RPC Error removing request.");
+        trace!(reason = ?error, request_id = %id, "RPC Error removing request.");
         if let Some(active_request) = self.active_requests.remove(&id) {
+            // A peer we failed to dial back is not at fault.
+            if let (RequestBody::Ping { .. }, Some(dial_back)) =
+                (&active_request.request_body, self.dial_back.as_mut())
+            {
+                if dial_back.pinged(&active_request.contact.node_address(), false) {
+                    return;
+                }
+            }
+            // Local failures, such as a full handler queue, are not held against the peer.
+            if error.is_peer_failure() {
+                self.bootnodes
+                    .write()
+                    .failure(&active_request.contact.node_id());
+                self.count_failure(active_request.contact.node_id());
+            }
+
             // If this is initiated by the user, return an error on the callback. All callbacks
             // support a request error.
             match active_request.callback {
@@ -1543,6 +2744,22 @@ impl Service {
 
             let node_id = active_request.contact.node_id();
             match active_request.request_body {
+                RequestBody::Ping { .. } if self.injected_peers.contains_key(&node_id) => {
+                    debug!(%node_id, %error, "Injected peer failed verification");
+                    self.peer_injected(node_id, InjectionOutcome::Unreachable);
+                }
+                RequestBody::RegisterTopic { topic, .. } => {
+                    debug!(%topic, %node_id, %error, "Topic registration failed");
+                    self.topic_registrations.failed(&topic, &node_id);
+                }
+                RequestBody::TopicQuery { topic } => {
+                    let partial = self
+                        .active_nodes_responses
+                        .remove(&id)
+                        .map(|response| response.received_nodes)
+                        .unwrap_or_default();
+                    self.topic_query_answered(&topic, partial);
+                }
                 // if a failed FindNodes request, ensure we haven't partially received packets. If
                 // so, process the partially found nodes
                 RequestBody::FindNode { ref distances } => {
@@ -1564,6 +2781,9 @@ impl Service {
                         // there was no partially downloaded nodes inform the query of the failure
                         // if it's part of a query
                         if let Some(query_id) = active_request.query_id {
+                            self.trace_query(query_id, |tracer, now| {
+                                tracer.failed(&node_id, error.to_string(), now)
+                            });
                             if let Some(query) = self.queries.get_mut(query_id) {
                                 query.on_failure(&node_id);
                             }
@@ -1576,9 +2796,23 @@ impl Service {
                         }
                     }
                 }
+                RequestBody::Talk { .. } => {
+                    match dial_back::requested_candidate(&active_request.request_body) {
+                        Some(candidate) => self.dial_back_answered(candidate, false),
+                        None => debug!(
+                            request_body = %active_request.request_body,
+                            node = %active_request.contact,
+                            error = ?error,
+                            "Failed RPC request",
+                        ),
+                    }
+                }
                 // for all other requests, if any are queries, mark them as failures.
                 _ => {
                     if let Some(query_id) = active_request.query_id {
+                        self.trace_query(query_id, |tracer, now| {
+                            tracer.failed(&node_id, error.to_string(), now)
+                        });
                         if let Some(query) = self.queries.get_mut(query_id) {
                             debug!(
                                 request_body = %active_request.request_body,
@@ -1631,15 +2865,561 @@ impl Service {
             }
     }
 
+    /// A future that resolves on each tick of an optional timer. Never resolves if the timer is
+    /// disabled.
+    async fn interval_poll(interval: &mut Option<tokio::time::Interval>) {
+        match interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    /// Saves the routing table, the active bans and the application metadata to the peer store, if
+    /// configured. The snapshot is saved in the background.
+    fn checkpoint_peer_store(&mut self) {
+        let Some(checkpointer) = self.peer_store_checkpointer.as_ref() else {
+            return;
+        };
+        let mut snapshot = PeerStoreSnapshot {
+            enrs: self
+                .kbuckets
+                .iter()
+                .map(|entry| Enr::clone(&entry.node.value))
+                .collect(),
+            metadata: self.peer_store_metadata.read().clone(),
+            ..Default::default()
+        };
+        {
+            let permit_ban_list = PERMIT_BAN_LIST.read();
+            snapshot.set_bans(&permit_ban_list, &*self.config.clock);
+            self.checkpointed_ban_changes = permit_ban_list.ban_changes();
+        }
+        checkpointer.checkpoint(snapshot);
+    }
+
+    /// Checkpoints the peer store if the bans changed since the last checkpoint. Changes within a
+    /// [`BAN_CHECKPOINT_INTERVAL`], or while a checkpoint is being saved, are saved together.
+    fn checkpoint_changed_bans(&mut self) {
+        if PERMIT_BAN_LIST.read().ban_changes() != self.checkpointed_ban_changes {
+            self.checkpoint_peer_store();
+        }
+    }
+
+    /// Pings the NAT keepalive peers that are in the routing table.
+    fn send_nat_keepalives(&mut self) {
+        let enrs: Vec<Arc<Enr>> = self
+            .nat_keepalive_peers
+            .read()
+            .iter()
+            .filter_map(|node_id| self.find_enr(node_id))
+            .collect();
+        for enr in enrs {
+            trace!(node_id = %enr.node_id(), "Sending NAT keepalive");
+            self.send_ping(enr, None);
+        }
+    }
+
+    /// Starts a lookup of our own node id, unless the previous one is still running.
+    fn start_self_lookup(&mut self) {
+        if let Some(result) = self.self_lookup_result.as_mut() {
+            if let Err(oneshot::error::TryRecvError::Empty) = result.try_recv() {
+                trace!("Previous self-lookup still running");
+                return;
+            }
+        }
+        debug!("Starting self-lookup");
+        let (callback, result) = oneshot::channel();
+        self.self_lookup_result = Some(result);
+        let local_id = self.local_enr.read().node_id();
+        self.start_findnode_query(local_id, callback, None);
+    }
+
+    /// Passes a node newly inserted into the routing table to the eclipse monitor, if enabled.
+    fn check_eclipse_insertion(&mut self, enr: &Enr) {
+        let Some(monitor) = self.eclipse_monitor.as_mut() else {
+            return;
+        };
+        let close = {
+            let kbuckets = &self.kbuckets;
+            // The close buckets are the closest buckets holding nodes.
+            let furthest_close_bucket = kbuckets
+                .buckets_iter()
+                .enumerate()
+                .filter(|(_, bucket)| bucket.num_entries() > 0)
+                .map(|(index, _)| index)
+                .take(monitor.config().close_buckets)
+                .last();
+            kbuckets
+                .get_index(&kbucket::Key::from(enr.node_id()))
+                .zip(furthest_close_bucket)
+                .is_some_and(|(index, furthest)| index <= furthest)
+        };
+        if let Some(warning) = monitor.inserted(enr, close) {
+            self.report_eclipse_warning(warning);
+        }
+    }
+
+    /// Reports a warning of the eclipse monitor and, if configured, removes the nodes it implicates
+    /// from the routing table.
+    fn report_eclipse_warning(&mut self, warning: EclipseWarning) {
+        warn!(?warning, "Routing table shows signs of an eclipse attack");
+        let mut removed = Vec::new();
+        if let Some(monitor) = self.eclipse_monitor.as_mut() {
+            if monitor.config().reset_table {
+                for node_id in monitor.take_implicated(&warning) {
+                    if self.kbuckets.remove(&kbucket::Key::from(node_id)) {
+                        self.peers_to_ping.remove(&node_id);
+                        removed.push(node_id);
+                    }
+                }
+            }
+        }
+        if !removed.is_empty() {
+            info!(
+                removed = removed.len(),
+                "Removed suspicious nodes from the routing table"
+            );
+        }
+        self.send_event(Event::EclipseWarning { warning, removed });
+    }
+
+    /// Pings a peer learned out of band. The handshake verifies that the peer holds the key of
+    /// the ENR, and once it answers, the session inserts it into the routing table like any
+    /// other peer.
+    fn inject_peer(&mut self, enr: Enr, source: PeerSource) {
+        let node_id = enr.node_id();
+        let banned = {
+            let permit_ban_list = PERMIT_BAN_LIST.read();
+            permit_ban_list.ban_nodes().contains_key(&node_id)
+                || [enr.ip4().map(IpAddr::from), enr.ip6().map(IpAddr::from)]
+                    .iter()
+                    .flatten()
+                    .any(|ip| permit_ban_list.ban_ips().contains_key(ip))
+        };
+        let outcome = if banned {
+            InjectionOutcome::Banned
+        } else if self.ip_mode.get_contactable_addr(&enr).is_none() {
+            InjectionOutcome::NotContactable
+        } else {
+            debug!(%node_id, ?source, "Verifying injected peer");
+            self.injected_peers.insert(node_id, source);
+            self.send_ping(Arc::new(enr), None);
+            return;
+        };
+        self.send_event(Event::PeerInjected {
+            node_id,
+            source,
+            outcome,
+        });
+    }
+
+    /// Counts a failed request to a peer of the routing table. Once the peer has failed
+    /// `max_successive_failures` requests in a row, its sessions are dropped and it is marked
+    /// disconnected, so that queries stop being routed through it.
+    fn count_failure(&mut self, node_id: NodeId) {
+        let Some(max_failures) = self.config.max_successive_failures else {
+            return;
+        };
+        let key = kbucket::Key::from(node_id);
+        if !matches!(
+            self.kbuckets.entry(&key),
+            kbucket::Entry::Present(..) | kbucket::Entry::Pending(..)
+        ) {
+            self.successive_failures.remove(&node_id);
+            return;
+        }
+        let failures = self.successive_failures.entry(node_id).or_default();
+        *failures += 1;
+        if *failures < max_failures {
+            return;
+        }
+        let failures = *failures;
+        self.successive_failures.remove(&node_id);
+        debug!(%node_id, failures, "Peer is unresponsive, dropping its sessions");
+        if send_to_handler(&self.handler_send, HandlerIn::RemoveSessions(node_id)).is_err() {
+            warn!(%node_id, "Failed to drop the sessions of an unresponsive peer");
+        }
+        self.connection_updated(node_id, ConnectionStatus::Disconnected);
+        self.send_event(Event::PeerUnresponsive { node_id, failures });
+    }
+
+    /// Reports the outcome of the verification of an injected peer, if one is pending.
+    fn peer_injected(&mut self, node_id: NodeId, outcome: InjectionOutcome) {
+        if let Some(source) = self.injected_peers.remove(&node_id) {
+            self.send_event(Event::PeerInjected {
+                node_id,
+                source,
+                outcome,
+            });
+        }
+    }
+
+    /// Looks up further registrars for the topics we advertise, until each has as many as
+    /// configured.
+    fn register_topics(&mut self) {
+        let topics: Vec<TopicHash> = self.topic_registrations.topics().copied().collect();
+        for topic in topics {
+            if self.missing_registrars(&topic) > 0 && self.topic_registrations.start_lookup(topic) {
+                self.start_topic_lookup(topic, TopicLookup::Register);
+            }
+        }
+    }
+
+    /// The number of registrars a topic we advertise is newly registered with, a few at a time.
+    fn missing_registrars(&self, topic: &TopicHash) -> usize {
+        let config = self.topic_registrations.config();
+        config
+            .registrars
+            .saturating_sub(self.topic_registrations.registrar_count(topic))
+            .min(config.registrations_per_refresh)
+    }
+
+    /// Starts a lookup of the topic hash, finding the registrars of the topic.
+    fn start_topic_lookup(&mut self, topic: TopicHash, lookup: TopicLookup) {
+        trace!(%topic, ?lookup, "Looking up topic registrars");
+        let (callback, result) = oneshot::channel();
+        self.start_findnode_query(topic.as_node_id(), callback, None);
+        self.topic_lookups.push(
+            result
+                .map(move |found| (topic, lookup, found.unwrap_or_default()))
+                .boxed(),
+        );
+    }
+
+    /// Picks up to `count` registrars of the topic among the nodes found by a lookup of the topic
+    /// hash and the nodes in the routing table. The registrars are picked within the radius of
+    /// the topic, estimated from the same nodes. Our own registrars are left out if `new` is set.
+    fn topic_registrars(
+        &self,
+        topic: &TopicHash,
+        found: Vec<Arc<Enr>>,
+        count: usize,
+        new: bool,
+    ) -> Vec<Arc<Enr>> {
+        let key = kbucket::Key::from(topic.as_node_id());
+        let local_id = self.local_enr.read().node_id();
+        // The ENRs found by the lookup may be newer than those in the routing table.
+        let mut candidates: HashMap<NodeId, Arc<Enr>> = self
+            .kbuckets
+            .closest_values(&key)
+            .map(|entry| (*entry.key.preimage(), entry.value))
+            .collect();
+        candidates.extend(found.into_iter().map(|enr| (enr.node_id(), enr)));
+        let mut candidates: Vec<(u64, Arc<Enr>)> = candidates
+            .into_iter()
+            .filter(|(node_id, enr)| {
+                *node_id != local_id && self.ip_mode.get_contactable_addr(enr).is_some()
+            })
+            .map(|(node_id, enr)| (log2_distance(topic, &node_id), enr))
+            .collect();
+        candidates.sort_by_key(|(_, enr)| key.distance(&kbucket::Key::from(enr.node_id())));
+        let radius = estimate_radius(
+            candidates.iter().map(|(distance, _)| *distance),
+            self.topic_registrations.config().registrars,
+            self.topic_registrations.radius(topic),
+        );
+        let candidates = candidates.into_iter().filter(|(_, enr)| {
+            !(new && self.topic_registrations.is_registrar(topic, &enr.node_id()))
+        });
+        select_registrars(candidates, radius, count)
+    }
+
+    /// Registers a topic we advertise with further registrars, found by a lookup of the topic
+    /// hash.
+    fn register_topic_at(&mut self, topic: TopicHash, found: Vec<Arc<Enr>>) {
+        if !self.topic_registrations.lookup_finished(&topic) {
+            return;
+        }
+        let missing = self.missing_registrars(&topic);
+        let registrars = self.topic_registrars(&topic, found, missing, true);
+        debug!(%topic, registrars = registrars.len(), "Registering topic");
+        for enr in registrars {
+            self.send_register_topic(topic, enr, Vec::new());
+        }
+    }
+
+    /// Asks a registrar to advertise us for the topic.
+    fn send_register_topic(&mut self, topic: TopicHash, registrar: Arc<Enr>, ticket: Vec<u8>) {
+        let contact = match self.contact_from_enr(registrar.clone()) {
+            Ok(contact) => contact,
+            Err(NonContactable { enr }) => {
+                debug_unreachable!("Stored ENR is not contactable. {}", enr);
+                return error!(%enr, "Topic registrar is not contactable");
+            }
+        };
+        self.topic_registrations.sent(topic, registrar);
+        let active_request = ActiveRequest {
+            contact,
+            request_body: RequestBody::RegisterTopic {
+                topic,
+                enr: self.local_enr.read().clone(),
+                ticket,
+            },
+            query_id: None,
+            callback: None,
+        };
+        self.send_rpc_request(active_request);
+    }
+
+    /// Asks registrars of the topic for the nodes advertised for it, once a lookup of the topic
+    /// hash has found them.
+    fn start_topic_query(&mut self, topic: TopicHash, callback: oneshot::Sender<Vec<Arc<Enr>>>) {
+        if let Some(query) = self.active_topic_queries.get_mut(&topic) {
+            query.callbacks.push(callback);
+            return;
+        }
+        self.active_topic_queries.insert(
+            topic,
+            ActiveTopicQuery {
+                callbacks: vec![callback],
+                pending: None,
+                found: HashMap::new(),
+            },
+        );
+        self.start_topic_lookup(topic, TopicLookup::Query);
+    }
+
+    /// Sends a topic query to the registrars of the topic, picked among the nodes found by a
+    /// lookup of the topic hash like our own registrars.
+    fn query_topic_at(&mut self, topic: TopicHash, found: Vec<Arc<Enr>>) {
+        let count = self.topic_registrations.config().registrars;
+        let registrars = self.topic_registrars(&topic, found, count, false);
+        let Some(query) = self.active_topic_queries.get_mut(&topic) else {
+            return;
+        };
+        query.pending = Some(registrars.len());
+        if registrars.is_empty() {
+            return self.finish_topic_query(&topic);
+        }
+
+        for enr in registrars {
+            match self.contact_from_enr(enr) {
+                Ok(contact) => {
+                    let active_request = ActiveRequest {
+                        contact,
+                        request_body: RequestBody::TopicQuery { topic },
+                        query_id: None,
+                        callback: None,
+                    };
+                    self.send_rpc_request(active_request);
+                }
+                Err(NonContactable { enr }) => {
+                    debug_unreachable!("Stored ENR is not contactable. {}", enr);
+                    error!(%enr, "Topic registrar is not contactable");
+                    self.topic_query_answered(&topic, Vec::new());
+                }
+            }
+        }
+    }
+
+    /// Records the answer of a registrar to a topic query, completing the query once every
+    /// registrar has answered.
+    fn topic_query_answered(&mut self, topic: &TopicHash, nodes: Vec<Arc<Enr>>) {
+        let local_id = self.local_enr.read().node_id();
+        let Some(query) = self.active_topic_queries.get_mut(topic) else {
+            return;
+        };
+        let Some(pending) = query.pending.as_mut() else {
+            return;
+        };
+        for enr in nodes {
+            if enr.node_id() == local_id || self.ip_mode.get_contactable_addr(&enr).is_none() {
+                continue;
+            }
+            match query.found.get(&enr.node_id()) {
+                Some(found) if found.seq() >= enr.seq() => {}
+                _ => {
+                    query.found.insert(enr.node_id(), enr);
+                }
+            }
+        }
+        *pending -= 1;
+        if *pending == 0 {
+            self.finish_topic_query(topic);
+        }
+    }
+
+    /// Hands the nodes found by a topic query to its callbacks.
+    fn finish_topic_query(&mut self, topic: &TopicHash) {
+        if let Some(query) = self.active_topic_queries.remove(topic) {
+            let found: Vec<Arc<Enr>> = query.found.into_values().collect();
+            debug!(%topic, found = found.len(), "Topic query finished");
+            for callback in query.callbacks {
+                if callback.send(found.clone()).is_err() {
+                    warn!(%topic, "Callback dropped for topic query. Results dropped");
+                }
+            }
+        }
+    }
+
+    /// Reports the local bans made since the last report to the trusted peers.
+    fn share_ban_intel(&mut self) {
+        let Some(ban_intel) = self.ban_intel.as_mut() else {
+            return;
+        };
+        let reports = {
+            let permit_ban_list = PERMIT_BAN_LIST.read();
+            let now = self.config.clock.now();
+            let ips = permit_ban_list
+                .ban_ips()
+                .iter()
+                .map(|(ip, unban)| (BanTarget::Ip(*ip), *unban));
+            let nodes = permit_ban_list
+                .ban_nodes()
+                .iter()
+                .map(|(node_id, unban)| (BanTarget::Node(*node_id), *unban));
+            ban_intel.new_reports(
+                ips.chain(nodes)
+                    // Expired bans linger until the handler lifts them.
+                    .filter(|(_, unban)| match unban {
+                        Some(unban) => *unban > now,
+                        None => true,
+                    })
+                    .map(|(target, unban)| (target, unban.map(|unban| unban.duration_since(now)))),
+            )
+        };
+        if reports.is_empty() {
+            return;
+        }
+
+        let trusted_peers = ban_intel.config().trusted_peers.clone();
+        let issued_at = self.config.clock.system_time();
+        for chunk in reports.chunks(MAX_BAN_REPORTS_PER_REQUEST) {
+            let message = match ban_intel::encode_reports(&self.enr_key.read(), chunk, issued_at) {
+                Ok(message) => message,
+                Err(error) => {
+                    warn!(error, "Failed to encode ban reports");
+                    return;
+                }
+            };
+            for enr in trusted_peers.iter() {
+                let contact = match NodeContact::try_from_enr(enr.clone(), self.ip_mode) {
+                    Ok(contact) => contact,
+                    Err(NonContactable { enr }) => {
+                        debug_unreachable!("Stored ENR is not contactable. {}", enr);
+                        error!(%enr, "Trusted peer is not contactable");
+                        continue;
+                    }
+                };
+                let active_request = ActiveRequest {
+                    contact,
+                    request_body: RequestBody::Talk {
+                        protocol: BAN_INTEL_PROTOCOL.to_vec(),
+                        request: message.clone(),
+                    },
+                    query_id: None,
+                    callback: None,
+                };
+                self.send_rpc_request(active_request);
+            }
+        }
+        debug!(
+            reports = reports.len(),
+            "Reported bans to the trusted peers"
+        );
+    }
+
+    /// Records a response of the peer breaking the protocol. Peers committing a severe violation
+    /// are banned and removed from the routing table.
+    fn penalize_response(&mut self, node_address: &NodeAddress, violation: ResponseViolation) {
+        let node_id = node_address.node_id;
+        let ip = node_address.socket_addr.ip();
+        debug!(%node_address, ?violation, "Invalid response");
+        AUDIT_LOG.record(
+            SecurityEventKind::InvalidResponse(violation),
+            Some(ip),
+            Some(node_id),
+            Some(PacketType::Message),
+        );
+        if !violation.is_severe() {
+            return;
+        }
+
+        let duration = self.config.ban_duration;
+        PERMIT_BAN_LIST.write().ban(
+            node_address.clone(),
+            duration.map(|v| self.config.clock.now() + v),
+        );
+        if self.kbuckets.remove(&kbucket::Key::from(node_id)) {
+            self.peers_to_ping.remove(&node_id);
+        }
+        let reason = BanReason::InvalidResponse(violation);
+        AUDIT_LOG.record(
+            SecurityEventKind::NodeBanned { reason, duration },
+            None,
+            Some(node_id),
+            None,
+        );
+        AUDIT_LOG.record(
+            SecurityEventKind::IpBanned { reason, duration },
+            Some(ip),
+            None,
+            None,
+        );
+    }
+
+    /// Merges the ban reports of a peer and bans the targets the trusted peers agree on.
+    fn receive_ban_intel(&mut self, node_address: &NodeAddress, message: &[u8]) {
+        let Some(ban_intel) = self.ban_intel.as_mut() else {
+            return;
+        };
+        let bans = match ban_intel.receive(&node_address.node_id, message) {
+            Ok(bans) => bans,
+            Err(error) => {
+                debug!(%error, %node_address, "Rejected ban reports");
+                return;
+            }
+        };
+        for (target, duration) in bans {
+            let time_to_unban = Some(self.config.clock.now() + duration);
+            let kind = {
+                let mut permit_ban_list = PERMIT_BAN_LIST.write();
+                match target {
+                    // Local bans take precedence.
+                    BanTarget::Ip(ip) if !permit_ban_list.ban_ips().contains_key(&ip) => {
+                        permit_ban_list.ban_ip(ip, time_to_unban);
+                        SecurityEventKind::IpBanned {
+                            reason: BanReason::PeerReport,
+                            duration: Some(duration),
+                        }
+                    }
+                    BanTarget::Node(node_id)
+                        if !permit_ban_list.ban_nodes().contains_key(&node_id) =>
+                    {
+                        permit_ban_list.ban_node(node_id, time_to_unban);
+                        SecurityEventKind::NodeBanned {
+                            reason: BanReason::PeerReport,
+                            duration: Some(duration),
+                        }
+                    }
+                    _ => continue,
+                }
+            };
+            info!(?target, ?duration, "Banned on the reports of trusted peers");
+            match target {
+                BanTarget::Ip(ip) => AUDIT_LOG.record(kind, Some(ip), None, None),
+                BanTarget::Node(node_id) => {
+                    if self.kbuckets.remove(&kbucket::Key::from(node_id)) {
+                        self.peers_to_ping.remove(&node_id);
+                    }
+                    AUDIT_LOG.record(kind, None, Some(node_id), None)
+                }
+            }
+        }
+    }
+
     /// A future that maintains the routing table and inserts nodes when required. This returns the
     /// [`Event::NodeInserted`] variant if a new node has been inserted into the routing table.
-    async fn bucket_maintenance_poll(kbuckets: &Arc<RwLock<KBucketsTable<NodeId, Enr>>>) -> Event {
+    async fn bucket_maintenance_poll(kbuckets: &Arc<KBucketsTable<NodeId, Arc<Enr>>>) -> Event {
         future::poll_fn(move |_cx| {
             // Drain applied pending entries from the routing table.
-            if let Some(entry) = kbuckets.write().take_applied_pending() {
+            if let Some(entry) = kbuckets.take_applied_pending() {
                 let event = Event::NodeInserted {
                     node_id: entry.inserted.into_preimage(),
                     replaced: entry.evicted.map(|n| n.key.into_preimage()),
+                    direction: entry.direction,
                 };
                 return Poll::Ready(event);
             }
@@ -1681,6 +3461,11 @@ enum QueryEvent {
 pub enum QueryKind {
     /// A FindNode query. Searches for peers that are closest to a particular target.
     FindNode { target_node: NodeId },
+    /// A FindNode query that records the peers it contacts, sending the trace when it finishes.
+    TracedFindNode {
+        target_node: NodeId,
+        trace: oneshot::Sender<QueryTrace>,
+    },
     /// A predicate query. Searches for peers that are close to a target but filtered by a specific
     /// predicate and limited by a target peer count.
     Predicate {
@@ -1693,9 +3478,9 @@ pub enum QueryKind {
 /// Reporting the connection status of a node.
 enum ConnectionStatus {
     /// A node has started a new connection with us.
-    Connected(Enr, ConnectionDirection),
+    Connected(Arc<Enr>, ConnectionDirection),
     /// We received a Pong from a new node. Do not have the connection direction.
-    PongReceived(Enr),
+    PongReceived(Arc<Enr>),
     /// The node has disconnected
     Disconnected,
 }
@@ -0,0 +1,345 @@
+//! Exchanges ban reports with operator-configured peers over TALK.
+//!
+//! Each node periodically sends the bans it has made itself to its trusted peers, signed with its
+//! node key. A report from a single peer only greylists its target: the target is banned once
+//! reports from enough distinct trusted peers have accumulated within the report window, and for
+//! no longer than the configured maximum. This lets a cluster of bootnodes respond to an attack
+//! collectively, without any one of them being able to ban peers across the cluster on its own.
+use crate::{lru_time_cache::LruTimeCache, time::Clock, Enr};
+use alloy_rlp::{Bytes, Decodable, Encodable, Error as DecoderError, Header};
+use enr::{CombinedKey, EnrKey, EnrPublicKey, NodeId};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::{TryFrom, TryInto},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The TALK protocol ban reports are exchanged on.
+pub(crate) const BAN_INTEL_PROTOCOL: &[u8] = b"banintel";
+
+/// The maximum number of greylisted targets.
+const GREYLIST_CAPACITY: usize = 10_000;
+
+/// Configures the exchange of ban reports. See [`crate::ConfigBuilder::ban_intel`].
+#[derive(Debug, Clone)]
+pub struct BanIntelConfig {
+    /// The peers ban reports are exchanged with. Reports are only accepted from these peers and
+    /// must be signed with the key of their ENR. Default: none.
+    pub trusted_peers: Vec<Enr>,
+    /// The weight of the reports of a single peer. A target is banned once the reports of distinct
+    /// peers add up to one. Default: 0.5, so that two peers have to agree.
+    pub attenuation: f64,
+    /// The longest a ban based on reports lasts. Permanent bans are shortened to this as well.
+    /// Default: 1 hour.
+    pub max_ban_duration: Duration,
+    /// The time reports about a target are accumulated over. Older reports are rejected.
+    /// Default: 10 minutes.
+    pub report_window: Duration,
+    /// How often new local bans are reported to the trusted peers. Default: 10 seconds.
+    pub share_interval: Duration,
+}
+
+impl Default for BanIntelConfig {
+    fn default() -> Self {
+        BanIntelConfig {
+            trusted_peers: Vec::new(),
+            attenuation: 0.5,
+            max_ban_duration: Duration::from_secs(3600),
+            report_window: Duration::from_secs(600),
+            share_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What a report asks to ban.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum BanTarget {
+    Ip(IpAddr),
+    Node(NodeId),
+}
+
+/// A ban made by the reporting node. A `None` duration is a permanent ban.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BanReport {
+    pub target: BanTarget,
+    pub duration: Option<Duration>,
+}
+
+impl Encodable for BanReport {
+    fn encode(&self, out: &mut dyn alloy_rlp::BufMut) {
+        let mut list = Vec::<u8>::new();
+        match self.target {
+            BanTarget::Ip(IpAddr::V4(ip)) => ip.octets().as_slice().encode(&mut list),
+            BanTarget::Ip(IpAddr::V6(ip)) => ip.octets().as_slice().encode(&mut list),
+            BanTarget::Node(node_id) => node_id.raw().as_slice().encode(&mut list),
+        }
+        // Zero encodes a permanent ban.
+        self.duration
+            .map_or(0, |duration| duration.as_secs().max(1))
+            .encode(&mut list);
+        Header {
+            list: true,
+            payload_length: list.len(),
+        }
+        .encode(out);
+        out.put_slice(&list);
+    }
+}
+
+impl Decodable for BanReport {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecoderError> {
+        let header = Header::decode(buf)?;
+        if !header.list {
+            return Err(DecoderError::UnexpectedString);
+        }
+        let target = Bytes::decode(buf)?;
+        let target = match target.len() {
+            4 => BanTarget::Ip(Ipv4Addr::from(<[u8; 4]>::try_from(&target[..]).unwrap()).into()),
+            16 => BanTarget::Ip(Ipv6Addr::from(<[u8; 16]>::try_from(&target[..]).unwrap()).into()),
+            32 => BanTarget::Node(NodeId::new(&target[..].try_into().unwrap())),
+            _ => return Err(DecoderError::Custom("Invalid ban target")),
+        };
+        let duration = match u64::decode(buf)? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        Ok(BanReport { target, duration })
+    }
+}
+
+/// Encodes and signs a batch of reports issued at the given wall clock time.
+pub(crate) fn encode_reports(
+    key: &CombinedKey,
+    reports: &[BanReport],
+    issued_at: SystemTime,
+) -> Result<Vec<u8>, &'static str> {
+    let issued_at = issued_at
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "System time is before the epoch")?
+        .as_secs();
+    let mut payload = Vec::new();
+    let mut list = Vec::new();
+    issued_at.encode(&mut list);
+    alloy_rlp::encode_list::<_, BanReport>(reports, &mut list);
+    Header {
+        list: true,
+        payload_length: list.len(),
+    }
+    .encode(&mut payload);
+    payload.extend_from_slice(&list);
+
+    let signature = key
+        .sign_v4(&payload)
+        .map_err(|_| "Failed to sign the ban reports")?;
+    let mut message = Vec::new();
+    let mut list = Vec::new();
+    signature.as_slice().encode(&mut list);
+    payload.as_slice().encode(&mut list);
+    Header {
+        list: true,
+        payload_length: list.len(),
+    }
+    .encode(&mut message);
+    message.extend_from_slice(&list);
+    Ok(message)
+}
+
+/// Verifies the signature of a batch of reports and decodes it, returning the time it was issued
+/// at and the reports.
+fn decode_reports(enr: &Enr, message: &[u8]) -> Result<(SystemTime, Vec<BanReport>), DecoderError> {
+    let buf = &mut &message[..];
+    let header = Header::decode(buf)?;
+    if !header.list {
+        return Err(DecoderError::UnexpectedString);
+    }
+    let signature = Bytes::decode(buf)?;
+    let payload = Bytes::decode(buf)?;
+    if !enr.public_key().verify_v4(&payload, &signature) {
+        return Err(DecoderError::Custom("Invalid signature"));
+    }
+
+    let buf = &mut &payload[..];
+    let header = Header::decode(buf)?;
+    if !header.list {
+        return Err(DecoderError::UnexpectedString);
+    }
+    let issued_at = UNIX_EPOCH + Duration::from_secs(u64::decode(buf)?);
+    let reports = Vec::<BanReport>::decode(buf)?;
+    Ok((issued_at, reports))
+}
+
+/// The reports received from the trusted peers and the local bans already reported to them.
+pub(crate) struct BanIntel {
+    config: BanIntelConfig,
+    /// The clock the age of reports is measured by.
+    clock: Arc<dyn Clock>,
+    trusted_peers: HashMap<NodeId, Enr>,
+    /// The peers that reported each target within the report window.
+    greylist: LruTimeCache<BanTarget, HashSet<NodeId>>,
+    /// The local bans that have been reported to the trusted peers.
+    shared: HashSet<BanTarget>,
+    /// The bans made based on reports, which are not reported on.
+    received: HashSet<BanTarget>,
+}
+
+impl BanIntel {
+    pub fn new(config: BanIntelConfig, clock: Arc<dyn Clock>) -> Self {
+        let trusted_peers = config
+            .trusted_peers
+            .iter()
+            .map(|enr| (enr.node_id(), enr.clone()))
+            .collect();
+        BanIntel {
+            greylist: LruTimeCache::with_clock(
+                config.report_window,
+                Some(GREYLIST_CAPACITY),
+                clock.clone(),
+            ),
+            config,
+            clock,
+            trusted_peers,
+            shared: HashSet::new(),
+            received: HashSet::new(),
+        }
+    }
+
+    pub fn config(&self) -> &BanIntelConfig {
+        &self.config
+    }
+
+    /// Returns the reports for the current bans that have not been reported yet.
+    pub fn new_reports(
+        &mut self,
+        bans: impl Iterator<Item = (BanTarget, Option<Duration>)>,
+    ) -> Vec<BanReport> {
+        let mut current = HashSet::new();
+        let mut reports = Vec::new();
+        for (target, duration) in bans {
+            current.insert(target);
+            if !self.received.contains(&target) && self.shared.insert(target) {
+                reports.push(BanReport { target, duration });
+            }
+        }
+        // Forget lifted bans, so that they are reported again if reinstated.
+        self.shared.retain(|target| current.contains(target));
+        self.received.retain(|target| current.contains(target));
+        reports
+    }
+
+    /// Merges a batch of reports from a peer into the greylist. Returns the targets to ban along
+    /// with the duration of their ban.
+    pub fn receive(
+        &mut self,
+        reporter: &NodeId,
+        message: &[u8],
+    ) -> Result<Vec<(BanTarget, Duration)>, DecoderError> {
+        let enr = self
+            .trusted_peers
+            .get(reporter)
+            .ok_or(DecoderError::Custom("Reporter is not trusted"))?;
+        let (issued_at, reports) = decode_reports(enr, message)?;
+        let age = self
+            .clock
+            .system_time()
+            .duration_since(issued_at)
+            .unwrap_or_default();
+        if age > self.config.report_window {
+            return Err(DecoderError::Custom("Reports have expired"));
+        }
+
+        let mut bans = Vec::new();
+        for report in reports {
+            let reporters = match self.greylist.get_mut(&report.target) {
+                Some(reporters) => reporters,
+                None => {
+                    self.greylist.insert(report.target, HashSet::new());
+                    self.greylist
+                        .get_mut(&report.target)
+                        .expect("Just inserted")
+                }
+            };
+            reporters.insert(*reporter);
+            if reporters.len() as f64 * self.config.attenuation >= 1.0 {
+                self.greylist.remove(&report.target);
+                self.received.insert(report.target);
+                let duration = report
+                    .duration
+                    .map_or(self.config.max_ban_duration, |duration| {
+                        duration.min(self.config.max_ban_duration)
+                    });
+                bans.push((report.target, duration));
+            }
+        }
+        Ok(bans)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::ManualClock;
+
+    fn peer() -> (CombinedKey, Enr) {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder().build(&key).unwrap();
+        (key, enr)
+    }
+
+    #[test]
+    fn bans_once_enough_peers_agree() {
+        let (key_a, enr_a) = peer();
+        let (key_b, enr_b) = peer();
+        let (key_c, _) = peer();
+        let clock = ManualClock::default();
+        let config = BanIntelConfig {
+            trusted_peers: vec![enr_a.clone(), enr_b.clone()],
+            ..Default::default()
+        };
+        let report_window = config.report_window;
+        let mut intel = BanIntel::new(config, Arc::new(clock.clone()));
+        let target = BanTarget::Ip([192, 0, 2, 1].into());
+        let reports = [BanReport {
+            target,
+            duration: None,
+        }];
+
+        // A report signed with another key is rejected.
+        let forged = encode_reports(&key_c, &reports, clock.system_time()).unwrap();
+        assert!(intel.receive(&enr_a.node_id(), &forged).is_err());
+
+        // Repeated reports of a single peer only greylist the target.
+        let message = encode_reports(&key_a, &reports, clock.system_time()).unwrap();
+        assert!(intel
+            .receive(&enr_a.node_id(), &message)
+            .unwrap()
+            .is_empty());
+        assert!(intel
+            .receive(&enr_a.node_id(), &message)
+            .unwrap()
+            .is_empty());
+
+        // Reports older than the report window are rejected.
+        let message = encode_reports(&key_b, &reports, clock.system_time()).unwrap();
+        clock.advance(report_window + Duration::from_secs(1));
+        assert!(intel.receive(&enr_b.node_id(), &message).is_err());
+
+        // The greylist expires along with the reports.
+        let message = encode_reports(&key_a, &reports, clock.system_time()).unwrap();
+        assert!(intel
+            .receive(&enr_a.node_id(), &message)
+            .unwrap()
+            .is_empty());
+        let message = encode_reports(&key_b, &reports, clock.system_time()).unwrap();
+        assert_eq!(
+            intel.receive(&enr_b.node_id(), &message).unwrap(),
+            vec![(target, Duration::from_secs(3600))]
+        );
+
+        // The ban is not reported on in turn.
+        assert!(intel
+            .new_reports(std::iter::once((target, Some(Duration::from_secs(3600)))))
+            .is_empty());
+    }
+}
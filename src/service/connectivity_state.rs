@@ -17,17 +17,13 @@
 //!     DURATION_UNTIL_NEXT_CONNECTIVITY_ATTEMPT in the future. This will prevent counting votes until
 //!     this time, which prevents our ENR from being updated.
 
-use crate::metrics::METRICS;
+use crate::time::{self, Instant};
+use crate::{ipmode::to_ipv4_mapped, metrics::METRICS};
 use futures::{
     future::{pending, Either},
     FutureExt,
 };
-use std::{
-    net::SocketAddr,
-    pin::Pin,
-    sync::atomic::Ordering,
-    time::{Duration, Instant},
-};
+use std::{net::SocketAddr, pin::Pin, sync::atomic::Ordering, time::Duration};
 use tokio::time::{sleep, Sleep};
 use tracing::info;
 
@@ -47,34 +43,86 @@ pub enum TimerFailure {
     V6,
 }
 
+/// The external address state of a single IP version. IPv4 and IPv6 are tracked independently, so
+/// losing reachability on one does not revoke the advertisement of the other.
+struct AddressState {
+    /// If we are awaiting for incoming connections, this is the instant that we stop waiting.
+    incoming_wait_time: Option<Pin<Box<Sleep>>>,
+    /// The time that we begin checking connectivity tests.
+    next_connectivity_test: Instant,
+    /// The number of incoming nodes we have seen during our awaiting window.
+    incoming_count: usize,
+}
+
+impl AddressState {
+    fn new() -> Self {
+        AddressState {
+            incoming_wait_time: None,
+            next_connectivity_test: time::now(),
+            incoming_count: 0,
+        }
+    }
+
+    /// Starts waiting for incoming connections on a newly advertised address.
+    fn await_incoming(&mut self, duration_to_wait: Duration) {
+        self.incoming_count = 0;
+        self.incoming_wait_time = Some(Box::pin(sleep(duration_to_wait)));
+    }
+
+    /// Counts an incoming connection. Returns true if this confirms we are contactable.
+    fn incoming_connection(&mut self) -> bool {
+        if self.incoming_wait_time.is_none() {
+            // We are not waiting for any connections
+            return false;
+        }
+        self.incoming_count += 1;
+        if self.incoming_count >= NUMBER_OF_INCOMING_CONNECTIONS_REQUIRED_TO_BE_VALID {
+            self.incoming_wait_time = None;
+            return true;
+        }
+        false
+    }
+
+    /// The address was not confirmed in time. Stop counting votes until the next attempt.
+    fn revoke(&mut self) {
+        self.next_connectivity_test = time::now() + DURATION_UNTIL_NEXT_CONNECTIVITY_ATTEMPT;
+        self.incoming_wait_time = None;
+    }
+}
+
 pub(crate) struct ConnectivityState {
     /// The duration we will wait for incoming connections before deciding if we are contactable or
     /// not. If this is None, we consider ourselves always contactable.
     duration_for_incoming_connections: Option<Duration>,
-    /// If we are awaiting for incoming connections, this is the instant that we stop waiting.
-    ipv4_incoming_wait_time: Option<Pin<Box<Sleep>>>,
-    /// If we are awaiting for incoming connections, this is the instant that we stop waiting.
-    ipv6_incoming_wait_time: Option<Pin<Box<Sleep>>>,
-    /// The time that we begin checking connectivity tests for ipv4.
-    pub ipv4_next_connectivity_test: Instant,
-    /// The time that we begin checking connectivity tests for ipv6.
-    pub ipv6_next_connectivity_test: Instant,
-    /// The number of incoming ipv4 nodes we have seen during our awaiting window.
-    ipv4_incoming_count: usize,
-    /// The number of incoming ipv6 nodes we have seen during our awaiting window.
-    ipv6_incoming_count: usize,
+    /// The state of our IPv4 external address.
+    ipv4: AddressState,
+    /// The state of our IPv6 external address.
+    ipv6: AddressState,
+}
+
+/// Returns whether the socket belongs to the IPv6 stack. IPv4-mapped addresses, as seen by
+/// IPv6 sockets accepting IPv4 traffic, count towards IPv4.
+fn is_ipv6(socket: &SocketAddr) -> bool {
+    match socket {
+        SocketAddr::V4(_) => false,
+        SocketAddr::V6(socket) => to_ipv4_mapped(socket.ip()).is_none(),
+    }
 }
 
 impl ConnectivityState {
     pub fn new(duration_for_incoming_connections: Option<Duration>) -> Self {
         ConnectivityState {
             duration_for_incoming_connections,
-            ipv4_incoming_wait_time: None,
-            ipv6_incoming_wait_time: None,
-            ipv4_next_connectivity_test: Instant::now(),
-            ipv6_next_connectivity_test: Instant::now(),
-            ipv4_incoming_count: 0,
-            ipv6_incoming_count: 0,
+            ipv4: AddressState::new(),
+            ipv6: AddressState::new(),
+        }
+    }
+
+    fn state_mut(&mut self, socket: &SocketAddr) -> &mut AddressState {
+        if is_ipv6(socket) {
+            &mut self.ipv6
+        } else {
+            &mut self.ipv4
         }
     }
 
@@ -89,10 +137,12 @@ impl ConnectivityState {
 
         // If we have failed a connectivity test, then we wait until the next duration window
         // before counting new votes.
-        match socket {
-            SocketAddr::V4(_) => Instant::now() >= self.ipv4_next_connectivity_test,
-            SocketAddr::V6(_) => Instant::now() >= self.ipv6_next_connectivity_test,
-        }
+        let state = if is_ipv6(socket) {
+            &self.ipv6
+        } else {
+            &self.ipv4
+        };
+        time::now() >= state.next_connectivity_test
     }
 
     /// We have updated our external ENR socket. If enabled (i.e duration_for_incoming_connections
@@ -100,16 +150,7 @@ impl ConnectivityState {
     /// verify that we are contactable. If we receive nothing in `duration_for_incoming_connections` then we consider ourselves non-contactable
     pub fn enr_socket_update(&mut self, socket: &SocketAddr) {
         if let Some(duration_to_wait) = self.duration_for_incoming_connections {
-            match socket {
-                SocketAddr::V4(_) => {
-                    self.ipv4_incoming_count = 0;
-                    self.ipv4_incoming_wait_time = Some(Box::pin(sleep(duration_to_wait)))
-                }
-                SocketAddr::V6(_) => {
-                    self.ipv6_incoming_count = 0;
-                    self.ipv6_incoming_wait_time = Some(Box::pin(sleep(duration_to_wait)))
-                }
-            }
+            self.state_mut(socket).await_incoming(duration_to_wait);
         }
     }
 
@@ -117,38 +158,22 @@ impl ConnectivityState {
     // expiry timer and we are done. The ENR will remain advertised and new votes will still count
     // to potentially change the IP address if a legitimate change occurs.
     pub fn received_incoming_connection(&mut self, socket: &SocketAddr) {
-        match socket {
-            SocketAddr::V4(_) => {
-                if self.ipv4_incoming_wait_time.is_none() {
-                    // We are not waiting for any v4 connections
-                    return;
-                }
-                self.ipv4_incoming_count += 1;
-                if self.ipv4_incoming_count >= NUMBER_OF_INCOMING_CONNECTIONS_REQUIRED_TO_BE_VALID {
-                    info!(ip_version = "v4", "We are contactable");
-                    self.ipv4_incoming_wait_time = None;
-                    METRICS.ipv4_contactable.store(true, Ordering::Relaxed);
-                }
-            }
-            SocketAddr::V6(_) => {
-                if self.ipv6_incoming_wait_time.is_none() {
-                    // We are not waiting for any v6 connections
-                    return;
-                }
-                self.ipv6_incoming_count += 1;
-                if self.ipv6_incoming_count >= NUMBER_OF_INCOMING_CONNECTIONS_REQUIRED_TO_BE_VALID {
-                    info!(ip_version = "v6", "We are contactable");
-                    self.ipv6_incoming_wait_time = None;
-                    METRICS.ipv6_contactable.store(true, Ordering::Relaxed);
-                }
-            }
+        if !self.state_mut(socket).incoming_connection() {
+            return;
+        }
+        if is_ipv6(socket) {
+            info!(ip_version = "v6", "We are contactable");
+            METRICS.ipv6_contactable.store(true, Ordering::Relaxed);
+        } else {
+            info!(ip_version = "v4", "We are contactable");
+            METRICS.ipv4_contactable.store(true, Ordering::Relaxed);
         }
     }
 
     pub async fn poll(&mut self) -> TimerFailure {
         let ipv4_fired = match (
-            self.ipv4_incoming_wait_time.as_mut(),
-            self.ipv6_incoming_wait_time.as_mut(),
+            self.ipv4.incoming_wait_time.as_mut(),
+            self.ipv6.incoming_wait_time.as_mut(),
         ) {
             (Some(ipv4_sleep), Some(ipv6_sleep)) => {
                 match futures::future::select(ipv4_sleep, ipv6_sleep).await {
@@ -162,16 +187,12 @@ impl ConnectivityState {
         };
 
         if ipv4_fired {
-            self.ipv4_next_connectivity_test =
-                Instant::now() + DURATION_UNTIL_NEXT_CONNECTIVITY_ATTEMPT;
-            self.ipv4_incoming_wait_time = None;
+            self.ipv4.revoke();
             METRICS.ipv4_contactable.store(false, Ordering::Relaxed);
             TimerFailure::V4
         } else {
             // Ipv6 fired
-            self.ipv6_next_connectivity_test =
-                Instant::now() + DURATION_UNTIL_NEXT_CONNECTIVITY_ATTEMPT;
-            self.ipv6_incoming_wait_time = None;
+            self.ipv6.revoke();
             METRICS.ipv6_contactable.store(false, Ordering::Relaxed);
             TimerFailure::V6
         }
@@ -0,0 +1,354 @@
+//! Verification of a new external address by peers dialing back before it is advertised.
+//!
+//! The votes of peers on our external address tell where our packets appear to come from, not
+//! whether packets sent there reach us. Behind a NAT without hairpinning, or with asymmetric
+//! routing, the address the peers observe may not accept packets from the rest of the network, and
+//! advertising it leaves the node unreachable. With dial-back verification, a new majority address
+//! is only advertised once enough connected peers confirm, over a TALK protocol of this crate,
+//! that they could PING us at it.
+//!
+//! The verification has limits: the peers asked are those we are connected to, so our NAT has a
+//! mapping towards each of them, and those that voted for the candidate ping over the session they
+//! already have with us there. A NAT filtering by remote endpoint lets these pings through even if
+//! it would drop packets of peers we have not contacted, so a confirmed address may still be
+//! unreachable to new peers. The verification catches addresses that are not reachable at all,
+//! such as those of a NAT without hairpinning or of asymmetric routing, rather than proving that
+//! the node accepts unsolicited packets. Peers that did not vote for the candidate are asked first.
+//!
+//! Peers are only dialed back at a public address with the IP they send from, and each at most once
+//! per [`DIAL_BACK_INTERVAL`], so that requests can't make us probe other hosts or addresses.
+use crate::{
+    lru_time_cache::LruTimeCache, node_info::NodeAddress, rpc::RequestBody, service::TalkRequest,
+    time::Clock,
+};
+use enr::NodeId;
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::debug;
+
+/// The TALK protocol dial-backs are requested on.
+pub(crate) const DIAL_BACK_PROTOCOL: &[u8] = b"dialback";
+
+/// The time after dialing back a peer during which its further requests are refused.
+pub(crate) const DIAL_BACK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The maximum number of peers dialed back at once.
+const MAX_DIAL_BACKS: usize = 16;
+
+/// The maximum number of peers recently dialed back that are remembered.
+const RECENT_REQUESTERS_CAPACITY: usize = 1024;
+
+/// Configures the verification of new external addresses. See [`crate::ConfigBuilder::dial_back`].
+#[derive(Debug, Clone)]
+pub struct DialBackConfig {
+    /// The number of connected peers asked to ping us at a new address. Default: 3.
+    pub helpers: usize,
+    /// The number of peers that have to reach us at a new address before it is advertised.
+    /// Default: 2.
+    pub confirmations: usize,
+    /// The time an address that failed verification is not verified again. Default: 5 minutes.
+    pub retry_after: Duration,
+}
+
+impl Default for DialBackConfig {
+    fn default() -> Self {
+        DialBackConfig {
+            helpers: 3,
+            confirmations: 2,
+            retry_after: Duration::from_secs(300),
+        }
+    }
+}
+
+/// The outcome of the verification of an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verified {
+    /// Enough peers reached us at the address.
+    Confirmed,
+    /// Too few peers can still reach us at the address.
+    Rejected,
+    /// The verification awaits more answers, or there is none.
+    Pending,
+}
+
+/// The answers to the dial-back requests for an address.
+#[derive(Debug)]
+struct Verification {
+    asked: usize,
+    answered: usize,
+    confirmed: usize,
+}
+
+/// The dial-backs requested by us and of us.
+pub(crate) struct DialBack {
+    config: DialBackConfig,
+    /// The addresses being verified.
+    verifications: HashMap<SocketAddr, Verification>,
+    /// The addresses that recently failed verification.
+    rejected: LruTimeCache<SocketAddr, ()>,
+    /// The requests of peers being pinged at their new address, by the address pinged.
+    answering: HashMap<NodeAddress, TalkRequest>,
+    /// The peers recently dialed back.
+    recent_requesters: LruTimeCache<NodeId, ()>,
+}
+
+impl DialBack {
+    pub fn new(config: DialBackConfig, clock: Arc<dyn Clock>) -> Self {
+        DialBack {
+            rejected: LruTimeCache::with_clock(config.retry_after, None, clock.clone()),
+            config,
+            verifications: HashMap::new(),
+            answering: HashMap::new(),
+            recent_requesters: LruTimeCache::with_clock(
+                DIAL_BACK_INTERVAL,
+                Some(RECENT_REQUESTERS_CAPACITY),
+                clock,
+            ),
+        }
+    }
+
+    pub fn config(&self) -> &DialBackConfig {
+        &self.config
+    }
+
+    /// Returns whether the address is neither being verified nor recently failed verification.
+    pub fn should_verify(&mut self, candidate: &SocketAddr) -> bool {
+        !self.verifications.contains_key(candidate) && self.rejected.get(candidate).is_none()
+    }
+
+    /// Starts the verification of the address by `asked` peers.
+    pub fn start(&mut self, candidate: SocketAddr, asked: usize) -> Verified {
+        if asked < self.config.confirmations {
+            self.rejected.insert(candidate, ());
+            return Verified::Rejected;
+        }
+        self.verifications.insert(
+            candidate,
+            Verification {
+                asked,
+                answered: 0,
+                confirmed: 0,
+            },
+        );
+        Verified::Pending
+    }
+
+    /// Records whether a peer reached us at the address.
+    pub fn answered(&mut self, candidate: SocketAddr, reached: bool) -> Verified {
+        let Some(verification) = self.verifications.get_mut(&candidate) else {
+            return Verified::Pending;
+        };
+        verification.answered += 1;
+        verification.confirmed += usize::from(reached);
+        let unanswered = verification.asked.saturating_sub(verification.answered);
+        let verified = if verification.confirmed >= self.config.confirmations {
+            Verified::Confirmed
+        } else if verification.confirmed + unanswered < self.config.confirmations {
+            self.rejected.insert(candidate, ());
+            Verified::Rejected
+        } else {
+            return Verified::Pending;
+        };
+        self.verifications.remove(&candidate);
+        verified
+    }
+
+    /// Returns whether the peer sending from `requester` may be dialed back at the candidate: a
+    /// public address with the IP the peer sends from, if the peer hasn't been dialed back
+    /// recently and not too many peers are being dialed back already.
+    pub fn may_dial_back(&mut self, requester: &NodeAddress, candidate: &SocketAddr) -> bool {
+        if candidate.ip() != requester.socket_addr.ip()
+            || !is_global(&candidate.ip())
+            || self.answering.len() >= MAX_DIAL_BACKS
+            || self.recent_requesters.get(&requester.node_id).is_some()
+        {
+            return false;
+        }
+        self.recent_requesters.insert(requester.node_id, ());
+        true
+    }
+
+    /// Holds the request of a peer while it is pinged at `address`. Returns false if the peer is
+    /// already being pinged there, in which case the request is dropped.
+    pub fn answer(&mut self, address: NodeAddress, request: TalkRequest) -> bool {
+        if self.answering.contains_key(&address) {
+            return false;
+        }
+        self.answering.insert(address, request);
+        true
+    }
+
+    /// Answers the request of the peer pinged at `address`. Returns false if the ping wasn't one
+    /// of a dial-back.
+    pub fn pinged(&mut self, address: &NodeAddress, reached: bool) -> bool {
+        let Some(request) = self.answering.remove(address) else {
+            return false;
+        };
+        if let Err(error) = request.respond(vec![u8::from(reached)]) {
+            debug!(%error, %address, "Failed to answer a dial-back request");
+        }
+        true
+    }
+}
+
+/// Whether the IP is publicly routable. Private, shared, loopback, link-local, documentation,
+/// benchmarking, reserved and multicast addresses are not.
+fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_global_v4(&ip),
+            None => is_global_v6(ip),
+        },
+    }
+}
+
+fn is_global_v4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // Shared address space
+        || (a == 100 && (b & 0b1100_0000) == 64)
+        // Benchmarking
+        || (a == 198 && (b & 0b1111_1110) == 18)
+        // Reserved, and "this network"
+        || a >= 240
+        || a == 0)
+}
+
+fn is_global_v6(ip: &Ipv6Addr) -> bool {
+    let [a, b, ..] = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local
+        || (a & 0xfe00) == 0xfc00
+        // Link-local
+        || (a & 0xffc0) == 0xfe80
+        // Documentation
+        || (a == 0x2001 && b == 0xdb8))
+}
+
+/// Encodes the address to be dialed back as its IP octets followed by the port.
+pub(crate) fn encode_candidate(candidate: &SocketAddr) -> Vec<u8> {
+    let mut encoded = match candidate {
+        SocketAddr::V4(socket) => socket.ip().octets().to_vec(),
+        SocketAddr::V6(socket) => socket.ip().octets().to_vec(),
+    };
+    encoded.extend_from_slice(&candidate.port().to_be_bytes());
+    encoded
+}
+
+/// Decodes the address to be dialed back, see [`encode_candidate`].
+pub(crate) fn decode_candidate(encoded: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = encoded.split_at(encoded.len().checked_sub(2)?);
+    let port = u16::from_be_bytes(port.try_into().ok()?);
+    if port == 0 {
+        return None;
+    }
+    match ip.len() {
+        4 => Some(SocketAddr::new(<[u8; 4]>::try_from(ip).ok()?.into(), port)),
+        16 => Some(SocketAddr::new(<[u8; 16]>::try_from(ip).ok()?.into(), port)),
+        _ => None,
+    }
+    .filter(|candidate| !candidate.ip().is_unspecified())
+}
+
+/// The address we asked to be dialed back at, if the request is a dial-back request.
+pub(crate) fn requested_candidate(request_body: &RequestBody) -> Option<SocketAddr> {
+    match request_body {
+        RequestBody::Talk { protocol, request } if protocol == DIAL_BACK_PROTOCOL => {
+            decode_candidate(request)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rpc::RequestId, time::SystemClock};
+
+    #[test]
+    fn confirms_once_enough_peers_reach_the_candidate() {
+        let candidate = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 9000);
+        let v6 = SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), 9000);
+        for address in [candidate, v6] {
+            assert_eq!(decode_candidate(&encode_candidate(&address)), Some(address));
+        }
+        assert_eq!(decode_candidate(&[1, 2, 3]), None);
+
+        let mut dial_back = DialBack::new(DialBackConfig::default(), Arc::new(SystemClock));
+        assert!(dial_back.should_verify(&candidate));
+        assert_eq!(dial_back.start(candidate, 3), Verified::Pending);
+        assert!(!dial_back.should_verify(&candidate));
+        assert_eq!(dial_back.answered(candidate, true), Verified::Pending);
+        assert_eq!(dial_back.answered(candidate, false), Verified::Pending);
+        assert_eq!(dial_back.answered(candidate, true), Verified::Confirmed);
+        assert!(dial_back.should_verify(&candidate));
+
+        // The verification fails as soon as too few peers remain to confirm.
+        assert_eq!(dial_back.start(candidate, 3), Verified::Pending);
+        assert_eq!(dial_back.answered(candidate, false), Verified::Pending);
+        assert_eq!(dial_back.answered(candidate, false), Verified::Rejected);
+        assert_eq!(dial_back.answered(candidate, true), Verified::Pending);
+        assert!(!dial_back.should_verify(&candidate));
+
+        // Too few peers to ask.
+        assert_eq!(dial_back.start(v6, 1), Verified::Rejected);
+        assert!(!dial_back.should_verify(&v6));
+    }
+
+    #[test]
+    fn dial_back_only_at_public_address_of_requester() {
+        let requester = NodeAddress::new("1.2.3.4:9000".parse().unwrap(), NodeId::random());
+        let mut dial_back = DialBack::new(DialBackConfig::default(), Arc::new(SystemClock));
+
+        // Other hosts and non-global addresses are refused.
+        let other_host = "1.2.3.5:9000".parse().unwrap();
+        assert!(!dial_back.may_dial_back(&requester, &other_host));
+        for candidate in ["127.0.0.1:9000", "10.0.0.1:9000", "100.64.0.1:9000"] {
+            let candidate = candidate.parse().unwrap();
+            let requester = NodeAddress::new(candidate, NodeId::random());
+            assert!(!dial_back.may_dial_back(&requester, &candidate));
+        }
+        for ip in ["fd00::1", "fe80::1", "2001:db8::1", "::ffff:192.168.0.1"] {
+            assert!(!is_global(&ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_global(&"2a00::1".parse().unwrap()));
+
+        // Another port of the requester's IP is allowed, once per interval.
+        let candidate = "1.2.3.4:30303".parse().unwrap();
+        assert!(dial_back.may_dial_back(&requester, &candidate));
+        assert!(!dial_back.may_dial_back(&requester, &candidate));
+
+        // Only so many peers are dialed back at once.
+        for port in 1..=MAX_DIAL_BACKS as u16 {
+            let requester = NodeAddress::new(candidate, NodeId::random());
+            assert!(dial_back.may_dial_back(&requester, &candidate));
+            let address =
+                NodeAddress::new(SocketAddr::new(candidate.ip(), port), requester.node_id);
+            let request = TalkRequest {
+                id: RequestId(vec![1]),
+                node_address: requester,
+                protocol: DIAL_BACK_PROTOCOL.to_vec(),
+                body: encode_candidate(&candidate),
+                sender: None,
+                enr: None,
+            };
+            assert!(dial_back.answer(address, request));
+        }
+        let requester = NodeAddress::new(candidate, NodeId::random());
+        assert!(!dial_back.may_dial_back(&requester, &candidate));
+    }
+}
@@ -0,0 +1,342 @@
+//! Heuristics flagging a routing table that may be the target of an eclipse attack.
+//!
+//! An attacker eclipsing a node fills its routing table, and in particular the buckets closest to
+//! it, with nodes under their control. Such nodes are cheap to create but tend to share a few
+//! subnets, replace the honest nodes of the close buckets within a short time and, once in
+//! place, answer lookups with other nodes the attacker has just created. The monitor watches for
+//! each of these over a sliding window and reports an [`EclipseWarning`] when one is out of the
+//! ordinary.
+use crate::{
+    lru_time_cache::LruTimeCache,
+    time::{Clock, Instant},
+    Enr,
+};
+use enr::NodeId;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+/// The number of nodes whose first sighting is remembered.
+const FIRST_SEEN_CAPACITY: usize = 16_384;
+
+/// The time the first sighting of a node is remembered for.
+const FIRST_SEEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Configures the eclipse attack heuristics. See [`crate::ConfigBuilder::eclipse_detection`].
+#[derive(Debug, Clone)]
+pub struct EclipseDetectionConfig {
+    /// The period over which insertions and lookups are judged. A node is considered recently
+    /// seen during the first `window` after we first heard of it. Default: 10 minutes.
+    pub window: Duration,
+    /// The number of insertions or lookup results needed within the window before their
+    /// composition is judged. Default: 10.
+    pub min_sample_size: usize,
+    /// The largest share of the insertions within the window that may come from a single subnet,
+    /// a /24 for IPv4 and a /48 for IPv6. Default: 0.5.
+    pub max_subnet_share: f64,
+    /// The number of closest non-empty buckets watched for churn. Default: 3.
+    pub close_buckets: usize,
+    /// The number of insertions into the close buckets within the window above which they are
+    /// considered churning. As the table fills up on startup, churn is only judged once the
+    /// monitor has run for a window. Default: 32.
+    pub max_close_bucket_insertions: usize,
+    /// The largest share of the nodes returned by a lookup that may have been seen for the first
+    /// time within the window. Every node is new on startup, so lookups are likewise only judged
+    /// once the monitor has run for a window. Default: 0.8.
+    pub max_recent_lookup_share: f64,
+    /// Removes the nodes implicated by a subnet influx or close bucket churn warning from the
+    /// routing table, so that it is refilled from the rest of the network. Default: false.
+    pub reset_table: bool,
+}
+
+impl Default for EclipseDetectionConfig {
+    fn default() -> Self {
+        EclipseDetectionConfig {
+            window: Duration::from_secs(600),
+            min_sample_size: 10,
+            max_subnet_share: 0.5,
+            close_buckets: 3,
+            max_close_bucket_insertions: 32,
+            max_recent_lookup_share: 0.8,
+            reset_table: false,
+        }
+    }
+}
+
+/// A suspicious pattern in the routing table or lookups, reported by
+/// [`crate::Event::EclipseWarning`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum EclipseWarning {
+    /// Most of the nodes recently inserted into the routing table share a subnet.
+    SubnetInflux {
+        /// The network address of the subnet.
+        subnet: IpAddr,
+        /// The insertions from the subnet within the window.
+        insertions: usize,
+        /// All insertions within the window.
+        total: usize,
+    },
+    /// The buckets closest to us have been refilled at an abnormal rate.
+    CloseBucketChurn {
+        /// The insertions into the close buckets within the window.
+        insertions: usize,
+    },
+    /// A lookup returned mostly nodes we had not heard of until recently.
+    RecentLookupPath {
+        /// The returned nodes first seen within the window.
+        recent: usize,
+        /// All returned nodes.
+        total: usize,
+    },
+}
+
+impl EclipseWarning {
+    fn kind(&self) -> WarningKind {
+        match self {
+            EclipseWarning::SubnetInflux { .. } => WarningKind::SubnetInflux,
+            EclipseWarning::CloseBucketChurn { .. } => WarningKind::CloseBucketChurn,
+            EclipseWarning::RecentLookupPath { .. } => WarningKind::RecentLookupPath,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum WarningKind {
+    SubnetInflux,
+    CloseBucketChurn,
+    RecentLookupPath,
+}
+
+/// A node inserted into the routing table.
+struct Insertion {
+    at: Instant,
+    node_id: NodeId,
+    subnet: Option<IpAddr>,
+    /// Whether the node went into one of the close buckets.
+    close: bool,
+}
+
+pub(crate) struct EclipseMonitor {
+    config: EclipseDetectionConfig,
+    /// The clock the window is measured by.
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    /// The insertions within the window, oldest first.
+    insertions: VecDeque<Insertion>,
+    /// The time each node was first seen.
+    first_seen: LruTimeCache<NodeId, Instant>,
+    /// The time each kind of warning was last reported. A warning is reported at most once per
+    /// window.
+    last_warned: HashMap<WarningKind, Instant>,
+}
+
+impl EclipseMonitor {
+    pub fn new(config: EclipseDetectionConfig, clock: Arc<dyn Clock>) -> Self {
+        EclipseMonitor {
+            config,
+            started: clock.now(),
+            insertions: VecDeque::new(),
+            first_seen: LruTimeCache::with_clock(
+                FIRST_SEEN_TTL,
+                Some(FIRST_SEEN_CAPACITY),
+                clock.clone(),
+            ),
+            clock,
+            last_warned: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &EclipseDetectionConfig {
+        &self.config
+    }
+
+    /// Records that we have heard of a node.
+    pub fn seen(&mut self, node_id: NodeId) {
+        if self.first_seen.peek(&node_id).is_none() {
+            self.first_seen.insert(node_id, self.clock.now());
+        }
+    }
+
+    /// Records the insertion of a node into the routing table. `close` is whether it went into one
+    /// of the close buckets.
+    pub fn inserted(&mut self, enr: &Enr, close: bool) -> Option<EclipseWarning> {
+        let node_id = enr.node_id();
+        self.seen(node_id);
+        self.prune();
+        self.insertions.push_back(Insertion {
+            at: self.clock.now(),
+            node_id,
+            subnet: subnet(enr),
+            close,
+        });
+
+        let total = self.insertions.len();
+        if total >= self.config.min_sample_size {
+            let mut per_subnet: HashMap<IpAddr, usize> = HashMap::new();
+            for subnet in self.insertions.iter().filter_map(|i| i.subnet) {
+                *per_subnet.entry(subnet).or_default() += 1;
+            }
+            if let Some((subnet, insertions)) = per_subnet.into_iter().max_by_key(|(_, n)| *n) {
+                if insertions as f64 > self.config.max_subnet_share * total as f64 {
+                    let warning = EclipseWarning::SubnetInflux {
+                        subnet,
+                        insertions,
+                        total,
+                    };
+                    if let Some(warning) = self.warn(warning) {
+                        return Some(warning);
+                    }
+                }
+            }
+        }
+
+        if !self.warmed_up() {
+            return None;
+        }
+        let insertions = self.insertions.iter().filter(|i| i.close).count();
+        if insertions > self.config.max_close_bucket_insertions {
+            return self.warn(EclipseWarning::CloseBucketChurn { insertions });
+        }
+        None
+    }
+
+    /// Judges the nodes returned by a lookup.
+    pub fn lookup_finished(&mut self, nodes: &[Arc<Enr>]) -> Option<EclipseWarning> {
+        if nodes.len() < self.config.min_sample_size || !self.warmed_up() {
+            return None;
+        }
+        let now = self.clock.now();
+        let recent = nodes
+            .iter()
+            .filter(|enr| match self.first_seen.peek(&enr.node_id()) {
+                Some(first_seen) => now.saturating_duration_since(*first_seen) < self.config.window,
+                None => true,
+            })
+            .count();
+        if recent as f64 > self.config.max_recent_lookup_share * nodes.len() as f64 {
+            return self.warn(EclipseWarning::RecentLookupPath {
+                recent,
+                total: nodes.len(),
+            });
+        }
+        None
+    }
+
+    /// Removes and returns the nodes inserted within the window that a warning implicates.
+    pub fn take_implicated(&mut self, warning: &EclipseWarning) -> Vec<NodeId> {
+        let implicated = |insertion: &Insertion| match warning {
+            EclipseWarning::SubnetInflux { subnet, .. } => insertion.subnet == Some(*subnet),
+            EclipseWarning::CloseBucketChurn { .. } => insertion.close,
+            EclipseWarning::RecentLookupPath { .. } => false,
+        };
+        let mut nodes = Vec::new();
+        self.insertions.retain(|insertion| {
+            if implicated(insertion) {
+                nodes.push(insertion.node_id);
+                false
+            } else {
+                true
+            }
+        });
+        nodes
+    }
+
+    /// Returns the warning unless one of its kind was reported within the window.
+    fn warn(&mut self, warning: EclipseWarning) -> Option<EclipseWarning> {
+        let now = self.clock.now();
+        let window = self.config.window;
+        if self
+            .last_warned
+            .get(&warning.kind())
+            .is_some_and(|at| now.saturating_duration_since(*at) < window)
+        {
+            return None;
+        }
+        self.last_warned.insert(warning.kind(), now);
+        Some(warning)
+    }
+
+    /// Whether the monitor has run for a window.
+    fn warmed_up(&self) -> bool {
+        self.clock.now().saturating_duration_since(self.started) >= self.config.window
+    }
+
+    /// Forgets the insertions that have left the window.
+    fn prune(&mut self) {
+        let now = self.clock.now();
+        while self
+            .insertions
+            .front()
+            .is_some_and(|i| now.saturating_duration_since(i.at) >= self.config.window)
+        {
+            self.insertions.pop_front();
+        }
+    }
+}
+
+/// The /24 or /48 subnet the node advertises, preferring IPv4.
+fn subnet(enr: &Enr) -> Option<IpAddr> {
+    if let Some(ip) = enr.ip4() {
+        let [a, b, c, _] = ip.octets();
+        return Some(Ipv4Addr::new(a, b, c, 0).into());
+    }
+    enr.ip6().map(|ip| {
+        let segments = ip.segments();
+        Ipv6Addr::new(segments[0], segments[1], segments[2], 0, 0, 0, 0, 0).into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::ManualClock;
+    use enr::CombinedKey;
+
+    fn enr(ip: Ipv4Addr) -> Enr {
+        let key = CombinedKey::generate_secp256k1();
+        Enr::builder().ip4(ip).udp4(9000).build(&key).unwrap()
+    }
+
+    #[test]
+    fn flags_subnet_influx_once_per_window() {
+        let clock = ManualClock::default();
+        let config = EclipseDetectionConfig::default();
+        let window = config.window;
+        let mut monitor = EclipseMonitor::new(config, Arc::new(clock.clone()));
+        for i in 0..4 {
+            assert_eq!(
+                monitor.inserted(&enr(Ipv4Addr::new(10, i, 0, 1)), false),
+                None
+            );
+        }
+        let mut warnings = Vec::new();
+        for i in 0..8 {
+            warnings.extend(monitor.inserted(&enr(Ipv4Addr::new(192, 0, 2, i)), false));
+        }
+        assert_eq!(
+            warnings,
+            vec![EclipseWarning::SubnetInflux {
+                subnet: Ipv4Addr::new(192, 0, 2, 0).into(),
+                insertions: 6,
+                total: 10,
+            }]
+        );
+
+        let implicated = monitor.take_implicated(&warnings[0]);
+        assert_eq!(implicated.len(), 8);
+        assert_eq!(monitor.insertions.len(), 4);
+
+        // The window is measured by the configured clock.
+        assert!(!monitor.warmed_up());
+        clock.advance(window);
+        assert!(monitor.warmed_up());
+        assert!(monitor
+            .inserted(&enr(Ipv4Addr::new(192, 0, 2, 100)), false)
+            .is_none());
+        assert_eq!(monitor.insertions.len(), 1);
+    }
+}
@@ -1,26 +1,94 @@
+use crate::time::{Clock, Instant};
 use enr::NodeId;
 use fnv::FnvHashMap;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     hash::Hash,
-    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
-    time::{Duration, Instant},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::Duration,
 };
 
+/// The kind of NAT we appear to be behind, derived from the external sockets peers report for us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NatType {
+    /// Not enough votes have been received to classify the NAT.
+    #[default]
+    Unknown,
+    /// Peers observe our local listening socket. We are not behind a NAT.
+    ///
+    /// If we are listening on an unspecified address, only the port can be compared, so a
+    /// port-preserving NAT is also reported as open.
+    Open,
+    /// Peers agree on a single external socket that differs from our listening socket. The NAT
+    /// maps our socket to the same external socket for every destination.
+    EndpointIndependent,
+    /// Peers observe differing external sockets. The NAT assigns a new mapping per destination, so
+    /// the address learned from one peer is not reachable by others.
+    Symmetric,
+}
+
+/// The NAT classification for each IP version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NatStatus {
+    /// The NAT type of the IPv4 stack.
+    pub ipv4: NatType,
+    /// The NAT type of the IPv6 stack.
+    pub ipv6: NatType,
+}
+
+/// A peer's vote for our external socket.
+#[derive(Debug, Clone, Copy)]
+struct Vote<K> {
+    /// The socket the peer observed.
+    socket: K,
+    /// The weight of the vote, given by the quality of the peer.
+    weight: u32,
+    /// The subnet of the peer, see [`voter_subnet`].
+    subnet: IpAddr,
+    /// When the vote expires.
+    expiry: Instant,
+}
+
 /// A collection of IP:Ports for our node reported from external peers.
+///
+/// The socket with the greatest total weight of votes is accepted, provided it has been voted for
+/// by at least `minimum_threshold` peers from at least `min_subnets` distinct subnets. Weighting
+/// the votes by the quality of the voters and requiring votes from several subnets makes it much
+/// harder for Sybil peers to poison the advertised address.
 pub(crate) struct IpVote {
     /// The current collection of IP:Port votes for ipv4.
-    ipv4_votes: HashMap<NodeId, (SocketAddrV4, Instant)>,
+    ipv4_votes: HashMap<NodeId, Vote<SocketAddrV4>>,
     /// The current collection of IP:Port votes for ipv6.
-    ipv6_votes: HashMap<NodeId, (SocketAddrV6, Instant)>,
+    ipv6_votes: HashMap<NodeId, Vote<SocketAddrV6>>,
     /// The minimum number of votes required before an IP/PORT is accepted.
     minimum_threshold: usize,
+    /// The minimum number of distinct voter subnets required before an IP/PORT is accepted.
+    min_subnets: usize,
     /// The time votes remain valid.
     vote_duration: Duration,
+    /// The clock the expiries of votes are measured against.
+    clock: Arc<dyn Clock>,
 }
 
 impl IpVote {
+    #[cfg(test)]
     pub fn new(minimum_threshold: usize, vote_duration: Duration) -> Self {
+        Self::with_clock(
+            minimum_threshold,
+            vote_duration,
+            Arc::new(crate::time::SystemClock),
+        )
+    }
+
+    /// Creates a collection whose votes expire according to the given clock.
+    pub fn with_clock(
+        minimum_threshold: usize,
+        vote_duration: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         // do not allow minimum thresholds less than 2
         if minimum_threshold < 2 {
             panic!("Setting enr_peer_update_min to a value less than 2 will cause issues with discovery with peers behind NAT");
@@ -29,29 +97,75 @@ impl IpVote {
             ipv4_votes: HashMap::new(),
             ipv6_votes: HashMap::new(),
             minimum_threshold,
+            min_subnets: 1,
             vote_duration,
+            clock,
         }
     }
 
+    /// Requires the voters of an accepted socket to be in at least `min_subnets` distinct subnets.
+    pub fn min_subnets(mut self, min_subnets: usize) -> Self {
+        self.min_subnets = min_subnets;
+        self
+    }
+
+    /// Inserts a vote of weight 1 from a voter of an unknown address.
+    #[cfg(test)]
     pub fn insert(&mut self, key: NodeId, socket: impl Into<SocketAddr>) {
+        self.insert_weighted(key, socket, Ipv4Addr::UNSPECIFIED.into(), 1);
+    }
+
+    /// Inserts the vote of the voter at `voter_ip`, replacing any previous vote of the voter.
+    pub fn insert_weighted(
+        &mut self,
+        key: NodeId,
+        socket: impl Into<SocketAddr>,
+        voter_ip: IpAddr,
+        weight: u32,
+    ) {
+        let expiry = self.clock.now() + self.vote_duration;
+        let subnet = voter_subnet(voter_ip);
         match socket.into() {
             SocketAddr::V4(socket) => {
-                self.ipv4_votes
-                    .insert(key, (socket, Instant::now() + self.vote_duration));
+                self.ipv4_votes.insert(
+                    key,
+                    Vote {
+                        socket,
+                        weight,
+                        subnet,
+                        expiry,
+                    },
+                );
             }
             SocketAddr::V6(socket) => {
-                self.ipv6_votes
-                    .insert(key, (socket, Instant::now() + self.vote_duration));
+                self.ipv6_votes.insert(
+                    key,
+                    Vote {
+                        socket,
+                        weight,
+                        subnet,
+                        expiry,
+                    },
+                );
             }
         }
     }
 
+    /// Discards all votes for a single IP version, leaving the other untouched.
+    pub fn clear(&mut self, is_ipv6: bool) {
+        if is_ipv6 {
+            self.ipv6_votes.clear();
+        } else {
+            self.ipv4_votes.clear();
+        }
+    }
+
     /// Returns true if we have more than the minimum number of non-expired votes for a given ip
     /// version.
     pub fn has_minimum_threshold(&mut self) -> (bool, bool) {
-        let instant = Instant::now();
-        self.ipv4_votes.retain(|_, v| v.1 > instant);
-        self.ipv6_votes.retain(|_, v| v.1 > instant);
+        let instant = self.clock.now();
+        self.ipv4_votes.retain(|_, vote| vote.expiry > instant);
+        self.ipv6_votes.retain(|_, vote| vote.expiry > instant);
 
         (
             self.ipv4_votes.len() >= self.minimum_threshold,
@@ -59,58 +173,205 @@ impl IpVote {
         )
     }
 
-    /// Filter the stale votes and return the majority `SocketAddr` if it exists.
-    /// If there are not enough votes to meet the threshold this returns None.
-    fn filter_stale_find_most_frequent<K: Copy + Eq + Hash>(
-        votes: &HashMap<NodeId, (K, Instant)>,
+    /// Filter the stale votes and return the `SocketAddr`, or the part of it selected by
+    /// `candidate`, with the greatest weight of votes if it exists. If no candidate has enough
+    /// votes from enough subnets this returns None.
+    fn filter_stale_find_most_frequent<K: Copy, C: Copy + Eq + Hash>(
+        votes: &HashMap<NodeId, Vote<K>>,
+        candidate: impl Fn(K) -> C,
         minimum_threshold: usize,
-    ) -> (HashMap<NodeId, (K, Instant)>, Option<K>) {
+        min_subnets: usize,
+        now: Instant,
+    ) -> (HashMap<NodeId, Vote<K>>, Option<C>) {
         let mut updated = HashMap::default();
-        let mut counter: FnvHashMap<K, usize> = FnvHashMap::default();
-        let mut max: Option<(K, usize)> = None;
-        let now = Instant::now();
+        // The number of votes, their total weight and the subnets of the voters, per candidate.
+        let mut counter: FnvHashMap<C, (usize, u64, HashSet<IpAddr>)> = FnvHashMap::default();
 
-        for (node_id, (vote, instant)) in votes {
+        for (node_id, vote) in votes {
             // Discard stale votes.
-            if instant <= &now {
+            if vote.expiry <= now {
                 continue;
             }
-            updated.insert(*node_id, (*vote, *instant));
+            updated.insert(*node_id, *vote);
 
-            let count = counter.entry(*vote).or_default();
+            let (count, weight, subnets) = counter.entry(candidate(vote.socket)).or_default();
             *count += 1;
-            let current_max = max.map(|(_v, m)| m).unwrap_or_default();
-            if *count >= current_max && *count >= minimum_threshold {
-                max = Some((*vote, *count));
-            }
+            *weight += u64::from(vote.weight);
+            subnets.insert(vote.subnet);
         }
 
-        (updated, max.map(|m| m.0))
+        let majority = counter
+            .into_iter()
+            .filter(|(_, (count, _, subnets))| {
+                *count >= minimum_threshold && subnets.len() >= min_subnets
+            })
+            .max_by_key(|(_, (count, weight, _))| (*weight, *count))
+            .map(|(socket, _)| socket);
+
+        (updated, majority)
     }
 
     /// Returns the majority `SocketAddr`'s of both IPv4 and IPv6 if they exist. If there are not enough votes to meet the threshold this returns None for each stack.
     pub fn majority(&mut self) -> (Option<SocketAddrV4>, Option<SocketAddrV6>) {
-        let (updated_ipv4_votes, ipv4_majority) = Self::filter_stale_find_most_frequent::<
-            SocketAddrV4,
-        >(
-            &self.ipv4_votes, self.minimum_threshold
+        let now = self.clock.now();
+        let (updated_ipv4_votes, ipv4_majority) = Self::filter_stale_find_most_frequent(
+            &self.ipv4_votes,
+            |socket| socket,
+            self.minimum_threshold,
+            self.min_subnets,
+            now,
         );
         self.ipv4_votes = updated_ipv4_votes;
 
-        let (updated_ipv6_votes, ipv6_majority) = Self::filter_stale_find_most_frequent::<
-            SocketAddrV6,
-        >(
-            &self.ipv6_votes, self.minimum_threshold
+        let (updated_ipv6_votes, ipv6_majority) = Self::filter_stale_find_most_frequent(
+            &self.ipv6_votes,
+            |socket| socket,
+            self.minimum_threshold,
+            self.min_subnets,
+            now,
         );
         self.ipv6_votes = updated_ipv6_votes;
 
         (ipv4_majority, ipv6_majority)
     }
+
+    /// Returns the majority IPs of both IPv4 and IPv6 if they exist, disregarding the reported
+    /// ports. Used when the advertised port is fixed and only the IP is learned from peers.
+    pub fn majority_ip(&mut self) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        let now = self.clock.now();
+        let (updated_ipv4_votes, ipv4_majority) = Self::filter_stale_find_most_frequent(
+            &self.ipv4_votes,
+            |socket| *socket.ip(),
+            self.minimum_threshold,
+            self.min_subnets,
+            now,
+        );
+        self.ipv4_votes = updated_ipv4_votes;
+
+        let (updated_ipv6_votes, ipv6_majority) = Self::filter_stale_find_most_frequent(
+            &self.ipv6_votes,
+            |socket| *socket.ip(),
+            self.minimum_threshold,
+            self.min_subnets,
+            now,
+        );
+        self.ipv6_votes = updated_ipv6_votes;
+
+        (ipv4_majority, ipv6_majority)
+    }
+
+    /// Returns whether the peer's vote is for the socket.
+    pub fn voted_for(&self, key: &NodeId, socket: &SocketAddr) -> bool {
+        match socket {
+            SocketAddr::V4(socket) => self
+                .ipv4_votes
+                .get(key)
+                .is_some_and(|vote| &vote.socket == socket),
+            SocketAddr::V6(socket) => self
+                .ipv6_votes
+                .get(key)
+                .is_some_and(|vote| &vote.socket == socket),
+        }
+    }
+
+    /// Classifies the NAT for each IP version by comparing the non-expired votes against our
+    /// listening sockets.
+    pub fn nat_status(
+        &mut self,
+        listen_ipv4: Option<SocketAddrV4>,
+        listen_ipv6: Option<SocketAddrV6>,
+    ) -> NatStatus {
+        let instant = self.clock.now();
+        self.ipv4_votes.retain(|_, vote| vote.expiry > instant);
+        self.ipv6_votes.retain(|_, vote| vote.expiry > instant);
+
+        NatStatus {
+            ipv4: Self::classify(
+                &self.ipv4_votes,
+                self.minimum_threshold,
+                listen_ipv4.map(SocketAddr::V4),
+            ),
+            ipv6: Self::classify(
+                &self.ipv6_votes,
+                self.minimum_threshold,
+                listen_ipv6.map(SocketAddr::V6),
+            ),
+        }
+    }
+
+    /// Classifies the NAT of a single IP version. A socket observed by more than half of the
+    /// voters is considered a consistent mapping.
+    fn classify<K: Copy + Eq + Hash + Into<SocketAddr>>(
+        votes: &HashMap<NodeId, Vote<K>>,
+        minimum_threshold: usize,
+        listen_socket: Option<SocketAddr>,
+    ) -> NatType {
+        if votes.len() < minimum_threshold {
+            return NatType::Unknown;
+        }
+
+        let mut counter: FnvHashMap<K, usize> = FnvHashMap::default();
+        for vote in votes.values() {
+            *counter.entry(vote.socket).or_default() += 1;
+        }
+        let (most_frequent, count) = match counter.into_iter().max_by_key(|(_, count)| *count) {
+            Some(max) => max,
+            None => return NatType::Unknown,
+        };
+
+        if count * 2 <= votes.len() {
+            return NatType::Symmetric;
+        }
+
+        let observed: SocketAddr = most_frequent.into();
+        let is_listen_socket = listen_socket.is_some_and(|listen| {
+            observed.port() == listen.port()
+                && (listen.ip().is_unspecified() || observed.ip() == listen.ip())
+        });
+        if is_listen_socket {
+            NatType::Open
+        } else {
+            NatType::EndpointIndependent
+        }
+    }
+}
+
+/// The subnet the votes of a voter at the IP are counted in: its /24 for IPv4 and its /48 for
+/// IPv6.
+fn voter_subnet(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).into()
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            Ipv6Addr::new(segments[0], segments[1], segments[2], 0, 0, 0, 0, 0).into()
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Duration, IpVote, NodeId, SocketAddrV4};
+    use super::{Arc, Duration, IpVote, NatType, NodeId, SocketAddrV4, SocketAddrV6};
+    use crate::time::ManualClock;
+
+    #[test]
+    fn test_votes_expire() {
+        let clock = ManualClock::default();
+        let mut votes = IpVote::with_clock(2, Duration::from_secs(10), Arc::new(clock.clone()));
+        let socket = SocketAddrV4::new("127.0.0.1".parse().unwrap(), 1);
+
+        votes.insert(NodeId::random(), socket);
+        clock.advance(Duration::from_secs(5));
+        votes.insert(NodeId::random(), socket);
+        assert_eq!(votes.majority(), (Some(socket), None));
+
+        // The first vote expires, leaving too few votes for a majority.
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(votes.majority(), (None, None));
+        assert_eq!(votes.has_minimum_threshold(), (false, false));
+    }
 
     #[test]
     fn test_three_way_vote_draw() {
@@ -150,6 +411,19 @@ mod tests {
         assert_eq!(votes.majority(), (Some(socket_1), None));
     }
 
+    #[test]
+    fn test_majority_ip_ignores_ports() {
+        let mut votes = IpVote::new(3, Duration::from_secs(10));
+        let ip = "192.0.2.1".parse().unwrap();
+
+        votes.insert(NodeId::random(), SocketAddrV4::new(ip, 1));
+        votes.insert(NodeId::random(), SocketAddrV4::new(ip, 2));
+        votes.insert(NodeId::random(), SocketAddrV4::new(ip, 3));
+
+        assert_eq!(votes.majority(), (None, None));
+        assert_eq!(votes.majority_ip(), (Some(ip), None));
+    }
+
     #[test]
     fn test_below_threshold() {
         let mut votes = IpVote::new(3, Duration::from_secs(10));
@@ -164,4 +438,77 @@ mod tests {
 
         assert_eq!(votes.majority(), (None, None));
     }
+
+    #[test]
+    fn test_weighted_votes_from_distinct_subnets() {
+        let mut votes = IpVote::new(2, Duration::from_secs(10)).min_subnets(2);
+        let honest = SocketAddrV4::new("1.1.1.1".parse().unwrap(), 9000);
+        let poisoned = SocketAddrV4::new("6.6.6.6".parse().unwrap(), 9000);
+
+        // Voters from a single subnet don't carry a vote.
+        for host in 1..=3 {
+            let voter = format!("10.0.0.{}", host).parse().unwrap();
+            votes.insert_weighted(NodeId::random(), poisoned, voter, 1);
+        }
+        assert_eq!(votes.majority(), (None, None));
+        votes.insert_weighted(NodeId::random(), poisoned, "10.9.0.1".parse().unwrap(), 1);
+        assert_eq!(votes.majority(), (Some(poisoned), None));
+
+        // Fewer voters of better quality outweigh them.
+        votes.insert_weighted(NodeId::random(), honest, "10.0.1.1".parse().unwrap(), 3);
+        votes.insert_weighted(NodeId::random(), honest, "10.0.2.1".parse().unwrap(), 3);
+        assert_eq!(votes.majority(), (Some(honest), None));
+    }
+
+    #[test]
+    fn test_nat_classification() {
+        let listen = SocketAddrV4::new("0.0.0.0".parse().unwrap(), 9000);
+        let external = SocketAddrV4::new("1.1.1.1".parse().unwrap(), 9000);
+        let mapped = SocketAddrV4::new("1.1.1.1".parse().unwrap(), 40000);
+
+        let mut votes = IpVote::new(3, Duration::from_secs(10));
+        votes.insert(NodeId::random(), external);
+        votes.insert(NodeId::random(), external);
+        assert_eq!(votes.nat_status(Some(listen), None).ipv4, NatType::Unknown);
+
+        votes.insert(NodeId::random(), external);
+        assert_eq!(votes.nat_status(Some(listen), None).ipv4, NatType::Open);
+
+        let mut votes = IpVote::new(3, Duration::from_secs(10));
+        votes.insert(NodeId::random(), mapped);
+        votes.insert(NodeId::random(), mapped);
+        votes.insert(NodeId::random(), external);
+        assert_eq!(
+            votes.nat_status(Some(listen), None).ipv4,
+            NatType::EndpointIndependent
+        );
+
+        let mut votes = IpVote::new(3, Duration::from_secs(10));
+        for port in 40000..40004 {
+            votes.insert(
+                NodeId::random(),
+                SocketAddrV4::new("1.1.1.1".parse().unwrap(), port),
+            );
+        }
+        assert_eq!(
+            votes.nat_status(Some(listen), None).ipv4,
+            NatType::Symmetric
+        );
+    }
+
+    #[test]
+    fn test_clear_is_per_ip_version() {
+        let mut votes = IpVote::new(2, Duration::from_secs(10));
+        let socket_4 = SocketAddrV4::new("1.1.1.1".parse().unwrap(), 1);
+        let socket_6 = SocketAddrV6::new("2001:db8::1".parse().unwrap(), 1, 0, 0);
+
+        votes.insert(NodeId::random(), socket_4);
+        votes.insert(NodeId::random(), socket_4);
+        votes.insert(NodeId::random(), socket_6);
+        votes.insert(NodeId::random(), socket_6);
+        assert_eq!(votes.majority(), (Some(socket_4), Some(socket_6)));
+
+        votes.clear(true);
+        assert_eq!(votes.majority(), (Some(socket_4), None));
+    }
 }
@@ -0,0 +1,146 @@
+//! Assembly of NODES responses.
+//!
+//! When the requested distances hold more nodes than fit in a response, the
+//! [`NodesSelection`] decides which are returned. The selected ENRs are then packed into as few
+//! packets as their encoded sizes allow.
+use crate::{kbucket::KBucketsTable, packet::MAX_PACKET_SIZE, Enr};
+use enr::NodeId;
+use rand::seq::SliceRandom;
+use std::sync::Arc;
+
+/// The overhead of a NODES response in a regular message packet.
+///
+/// Responses assume that a session is established. Thus, on top of the encoded ENR's the packet
+/// is a regular message, with an IV (16 bytes) and a header of 55 bytes. The NODES message needs
+/// 16 bytes for the request id and the `total` field, the encryption adds a 16 byte HMAC and the
+/// RLP list an extra byte.
+///
+/// A response sent with an auth header could take up to 282 bytes of header instead. As most
+/// responses are regular messages, the packets are packed as tightly as those allow, and a packed
+/// response is dropped in the rare case it has to be sent with an auth header.
+const NODES_RESPONSE_OVERHEAD: usize = 104;
+
+/// Selects the nodes returned to a FINDNODE request when more than
+/// [`crate::Config::max_nodes_response`] nodes match the requested distances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NodesSelection {
+    /// The nodes at the smallest requested distances.
+    #[default]
+    ClosestFirst,
+    /// A uniform sample of the matching nodes, so that repeated requests learn of different
+    /// nodes.
+    RandomSample,
+    /// The most recently connected nodes, as these are the most likely to be reachable with the
+    /// ENR we hold.
+    Freshest,
+}
+
+/// Selects up to `max_nodes` of the nodes at the log2 `distances`.
+pub(crate) fn select_nodes(
+    kbuckets: &KBucketsTable<NodeId, Arc<Enr>>,
+    distances: &[u64],
+    max_nodes: usize,
+    selection: NodesSelection,
+) -> Vec<Arc<Enr>> {
+    match selection {
+        NodesSelection::ClosestFirst => kbuckets
+            .nodes_by_distances(distances, max_nodes)
+            .into_iter()
+            .map(|entry| entry.node.value)
+            .collect(),
+        NodesSelection::RandomSample => {
+            let mut nodes: Vec<Arc<Enr>> = kbuckets
+                .nodes_by_distances(distances, usize::MAX)
+                .into_iter()
+                .map(|entry| entry.node.value)
+                .collect();
+            nodes.shuffle(&mut rand::thread_rng());
+            nodes.truncate(max_nodes);
+            nodes
+        }
+        NodesSelection::Freshest => {
+            // Buckets are ordered from the least to the most recently connected node. Rank the
+            // nodes of each bucket from the end, ties going to the closer bucket.
+            let mut ranked = Vec::new();
+            for distance in distances {
+                let bucket = kbuckets.nodes_by_distances(&[*distance], usize::MAX);
+                ranked.extend(
+                    bucket
+                        .into_iter()
+                        .rev()
+                        .enumerate()
+                        .map(|(rank, entry)| (rank, entry.node.value)),
+                );
+            }
+            ranked.sort_by_key(|(rank, _)| *rank);
+            ranked
+                .into_iter()
+                .take(max_nodes)
+                .map(|(_, enr)| enr)
+                .collect()
+        }
+    }
+}
+
+/// Packs the nodes into responses by their encoded size. Each node goes into the first response
+/// with room for it, so that small ENRs fill the gaps left by large ones.
+pub(crate) fn pack_nodes(nodes: Vec<Arc<Enr>>) -> Vec<Vec<Arc<Enr>>> {
+    let budget = MAX_PACKET_SIZE - NODES_RESPONSE_OVERHEAD;
+    let mut responses: Vec<(usize, Vec<Arc<Enr>>)> = Vec::new();
+    for enr in nodes {
+        let entry_size = alloy_rlp::encode(&enr).len();
+        match responses
+            .iter_mut()
+            .find(|(size, _)| size + entry_size < budget)
+        {
+            Some((size, response)) => {
+                *size += entry_size;
+                response.push(enr);
+            }
+            None => responses.push((entry_size, vec![enr])),
+        }
+    }
+    responses
+        .into_iter()
+        .map(|(_size, response)| response)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::CombinedKey;
+
+    fn enr(padding: usize) -> Arc<Enr> {
+        let key = CombinedKey::generate_secp256k1();
+        let mut builder = Enr::builder();
+        builder.add_value("pad", &vec![0u8; padding].as_slice());
+        Arc::new(builder.build(&key).unwrap())
+    }
+
+    #[test]
+    fn packs_small_enrs_into_gaps() {
+        let large = enr(120);
+        let small = enr(0);
+        let large_size = alloy_rlp::encode(&large).len();
+        let small_size = alloy_rlp::encode(&small).len();
+        let budget = MAX_PACKET_SIZE - NODES_RESPONSE_OVERHEAD;
+        let large_per_response = (budget - 1) / large_size;
+        assert!((budget - 1) % large_size > small_size);
+
+        // Filling two responses with large ENRs leaves room for the small ones in the first.
+        let mut nodes = vec![large.clone(); 2 * large_per_response];
+        nodes.push(small.clone());
+        let responses = pack_nodes(nodes);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].len(), large_per_response + 1);
+        assert_eq!(responses[0].last(), Some(&small));
+        for response in responses {
+            let size: usize = response
+                .iter()
+                .map(|enr| alloy_rlp::encode(enr).len())
+                .sum();
+            assert!(size < budget);
+        }
+    }
+}
@@ -1,6 +1,8 @@
+use super::query_trace::QueryTracer;
 use crate::{kbucket::Key, rpc::RequestBody, Enr};
 use enr::{k256::sha2::digest::generic_array::GenericArray, NodeId};
 use smallvec::SmallVec;
+use std::sync::Arc;
 use tokio::sync::oneshot;
 
 /// Information about a query.
@@ -10,14 +12,20 @@ pub struct QueryInfo {
     pub query_type: QueryType,
 
     /// Temporary ENRs used when trying to reach nodes.
-    pub untrusted_enrs: SmallVec<[Enr; 16]>,
+    pub untrusted_enrs: SmallVec<[Arc<Enr>; 16]>,
+
+    /// The ENRs found that can't be contacted, if they are appended to the results.
+    pub unreachable_enrs: Option<Vec<Arc<Enr>>>,
 
     /// A callback channel for the service that requested the query.
-    pub callback: oneshot::Sender<Vec<Enr>>,
+    pub callback: oneshot::Sender<Vec<Arc<Enr>>>,
 
     /// The number of distances we request for each peer.
     /// NOTE: This must not be larger than 127.
     pub distances_to_request: usize,
+
+    /// Records the peers contacted, if the query is traced.
+    pub trace: Option<QueryTracer>,
 }
 
 /// Additional information about the query.
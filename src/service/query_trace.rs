@@ -0,0 +1,255 @@
+//! Tracing of the peers contacted by a lookup.
+//!
+//! A lookup that is slow or fails is hard to debug from its result alone. A traced lookup, started
+//! with [`crate::Discv5::find_node_traced`], records every peer it asked, which distances it asked
+//! for, what the peer returned and how long it took, as a [`QueryTrace`]. The trace can be
+//! serialized with the `serde` feature, or exported as a DOT graph of which peer led the lookup to
+//! which, to visualize the path the lookup took through the DHT.
+use crate::time::Instant;
+use enr::NodeId;
+use std::{collections::HashMap, fmt::Write, time::Duration};
+use tokio::sync::oneshot;
+
+/// The peers contacted by a lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct QueryTrace {
+    /// The target of the lookup.
+    pub target: NodeId,
+    /// The time the lookup took.
+    pub duration: Duration,
+    /// The peers asked by the lookup, in the order they were asked.
+    pub contacts: Vec<TracedContact>,
+}
+
+/// A peer asked by a lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TracedContact {
+    /// The peer.
+    pub node_id: NodeId,
+    /// The peer that first returned this one, or `None` if the lookup started from it.
+    pub found_by: Option<NodeId>,
+    /// The distances the peer was asked for.
+    pub distances: Vec<u64>,
+    /// When the peer was asked, since the start of the lookup.
+    pub asked_after: Duration,
+    /// How the peer answered.
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub outcome: TracedOutcome,
+}
+
+/// How a peer asked by a lookup answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "outcome", rename_all = "snake_case")
+)]
+pub enum TracedOutcome {
+    /// The lookup finished before the peer answered.
+    Pending,
+    /// The peer returned these nodes, including any the lookup then discarded.
+    Responded {
+        /// The time the peer took to answer.
+        rtt: Duration,
+        /// The nodes returned.
+        nodes: Vec<NodeId>,
+    },
+    /// The request failed.
+    Failed {
+        /// The time until the request failed.
+        after: Duration,
+        /// The reason of the failure.
+        error: String,
+    },
+}
+
+impl QueryTrace {
+    /// The trace as a DOT digraph, with an edge from each peer to the peers it returned that the
+    /// lookup then asked. Peers are labelled with the first bytes of their id and coloured by how
+    /// they answered.
+    pub fn to_dot(&self) -> String {
+        let short = |node_id: &NodeId| hex::encode(&node_id.raw()[..4]);
+        let mut dot = format!(
+            "digraph lookup {{\n  label=\"target {} in {}ms\";\n",
+            short(&self.target),
+            self.duration.as_millis()
+        );
+        for contact in &self.contacts {
+            let (colour, detail) = match &contact.outcome {
+                TracedOutcome::Pending => ("grey", "pending".to_string()),
+                TracedOutcome::Responded { rtt, nodes } => (
+                    "green",
+                    format!("{} nodes in {}ms", nodes.len(), rtt.as_millis()),
+                ),
+                TracedOutcome::Failed { after, .. } => {
+                    ("red", format!("failed after {}ms", after.as_millis()))
+                }
+            };
+            let _ = writeln!(
+                dot,
+                "  \"{}\" [color={}, label=\"{}\\n{}\"];",
+                hex::encode(contact.node_id.raw()),
+                colour,
+                short(&contact.node_id),
+                detail
+            );
+            if let Some(found_by) = contact.found_by {
+                let _ = writeln!(
+                    dot,
+                    "  \"{}\" -> \"{}\";",
+                    hex::encode(found_by.raw()),
+                    hex::encode(contact.node_id.raw())
+                );
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Records the trace of a lookup, and delivers it when the lookup finishes.
+#[derive(Debug)]
+pub(crate) struct QueryTracer {
+    started: Instant,
+    trace: QueryTrace,
+    /// The first peer that returned each node.
+    found_by: HashMap<NodeId, NodeId>,
+    callback: oneshot::Sender<QueryTrace>,
+}
+
+impl QueryTracer {
+    pub fn new(target: NodeId, started: Instant, callback: oneshot::Sender<QueryTrace>) -> Self {
+        QueryTracer {
+            started,
+            trace: QueryTrace {
+                target,
+                duration: Duration::ZERO,
+                contacts: Vec::new(),
+            },
+            found_by: HashMap::new(),
+            callback,
+        }
+    }
+
+    /// The lookup asked the peer for the distances.
+    pub fn asked(&mut self, node_id: NodeId, distances: Vec<u64>, now: Instant) {
+        self.trace.contacts.push(TracedContact {
+            node_id,
+            found_by: self.found_by.get(&node_id).copied(),
+            distances,
+            asked_after: now.saturating_duration_since(self.started),
+            outcome: TracedOutcome::Pending,
+        });
+    }
+
+    /// The peer returned the nodes.
+    pub fn responded(&mut self, node_id: &NodeId, nodes: Vec<NodeId>, now: Instant) {
+        for found in &nodes {
+            self.found_by.entry(*found).or_insert(*node_id);
+        }
+        let elapsed = now.saturating_duration_since(self.started);
+        if let Some(contact) = self.pending(node_id) {
+            let rtt = elapsed.saturating_sub(contact.asked_after);
+            contact.outcome = TracedOutcome::Responded { rtt, nodes };
+        }
+    }
+
+    /// The request to the peer failed.
+    pub fn failed(&mut self, node_id: &NodeId, error: String, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.started);
+        if let Some(contact) = self.pending(node_id) {
+            let after = elapsed.saturating_sub(contact.asked_after);
+            contact.outcome = TracedOutcome::Failed { after, error };
+        }
+    }
+
+    /// Delivers the trace of the finished lookup.
+    pub fn finish(mut self, now: Instant) {
+        self.trace.duration = now.saturating_duration_since(self.started);
+        let _ = self.callback.send(self.trace);
+    }
+
+    /// The latest request to the peer, if it hasn't been answered.
+    fn pending(&mut self, node_id: &NodeId) -> Option<&mut TracedContact> {
+        self.trace
+            .contacts
+            .iter_mut()
+            .rev()
+            .find(|contact| &contact.node_id == node_id)
+            .filter(|contact| contact.outcome == TracedOutcome::Pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_and_exports_a_lookup() {
+        let [target, seed, found] = [NodeId::random(), NodeId::random(), NodeId::random()];
+        let started = Instant::now();
+        let (callback, mut trace) = oneshot::channel();
+        let mut tracer = QueryTracer::new(target, started, callback);
+
+        tracer.asked(seed, vec![256], started);
+        tracer.responded(&seed, vec![found], started + Duration::from_millis(20));
+        tracer.asked(found, vec![255, 256], started + Duration::from_millis(20));
+        tracer.failed(
+            &found,
+            "Timed out".into(),
+            started + Duration::from_millis(70),
+        );
+        tracer.finish(started + Duration::from_millis(80));
+
+        let trace = trace.try_recv().unwrap();
+        assert_eq!(trace.duration, Duration::from_millis(80));
+        assert_eq!(trace.contacts[0].found_by, None);
+        assert_eq!(
+            trace.contacts[0].outcome,
+            TracedOutcome::Responded {
+                rtt: Duration::from_millis(20),
+                nodes: vec![found]
+            }
+        );
+        assert_eq!(trace.contacts[1].found_by, Some(seed));
+        assert_eq!(
+            trace.contacts[1].outcome,
+            TracedOutcome::Failed {
+                after: Duration::from_millis(50),
+                error: "Timed out".into()
+            }
+        );
+
+        let dot = trace.to_dot();
+        assert!(dot.contains(&format!(
+            "\"{}\" -> \"{}\";",
+            hex::encode(seed.raw()),
+            hex::encode(found.raw())
+        )));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializes_outcomes_tagged() {
+        let contact = TracedContact {
+            node_id: NodeId::random(),
+            found_by: None,
+            distances: vec![256],
+            asked_after: Duration::ZERO,
+            outcome: TracedOutcome::Failed {
+                after: Duration::from_millis(50),
+                error: "Timed out".into(),
+            },
+        };
+        let json = serde_json::to_value(&contact).unwrap();
+        assert_eq!(json["outcome"], "failed");
+        assert_eq!(json["error"], "Timed out");
+        assert_eq!(json["distances"], serde_json::json!([256]));
+        assert_eq!(
+            json["node_id"],
+            format!("0x{}", hex::encode(contact.node_id.raw()))
+        );
+    }
+}
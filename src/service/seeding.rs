@@ -0,0 +1,91 @@
+//! The choice of the peers a query starts from.
+//!
+//! By default a query starts from the peers of the routing table closest to its target. Peers that
+//! have gone away since they were last verified waste the requests of the first round, which are
+//! only retried after a timeout. Under [`QuerySeeding::Quality`] the query starts from the peers
+//! among the closest that are connected and answer quickly.
+use std::time::Duration;
+
+/// The number of closest peers per seed the quality seeding chooses from.
+const QUALITY_POOL_FACTOR: usize = 2;
+
+/// Decides which peers of the routing table a query is seeded with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuerySeeding {
+    /// The peers closest to the target.
+    #[default]
+    Distance,
+    /// Among twice as many of the closest peers as are needed, the connected peers with the
+    /// lowest round trip times, then the remaining connected peers and only then disconnected
+    /// peers. Ties go to the closer peer.
+    Quality,
+}
+
+/// What is known of the liveness and latency of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PeerQuality {
+    /// Whether the peer has been verified recently.
+    pub connected: bool,
+    /// The smoothed round trip time of the requests to the peer, if measured.
+    pub rtt: Option<Duration>,
+}
+
+impl QuerySeeding {
+    /// The number of closest peers to choose `num_seeds` seeds from.
+    pub(crate) fn pool_size(&self, num_seeds: usize) -> usize {
+        match self {
+            QuerySeeding::Distance => num_seeds,
+            QuerySeeding::Quality => num_seeds.saturating_mul(QUALITY_POOL_FACTOR),
+        }
+    }
+
+    /// Chooses up to `num_seeds` of the `closest` peers, given in order of their distance to the
+    /// target.
+    pub(crate) fn seed<T>(
+        &self,
+        closest: Vec<T>,
+        num_seeds: usize,
+        quality: impl Fn(&T) -> PeerQuality,
+    ) -> Vec<T> {
+        let mut closest = closest;
+        if *self == QuerySeeding::Quality {
+            // The sort is stable, so that ties keep the order of distance.
+            closest.sort_by_cached_key(|peer| {
+                let quality = quality(peer);
+                (!quality.connected, quality.rtt.is_none(), quality.rtt)
+            });
+        }
+        closest.truncate(num_seeds);
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_live_low_latency_peers() {
+        let quality = |peer: &(bool, Option<u64>)| PeerQuality {
+            connected: peer.0,
+            rtt: peer.1.map(Duration::from_millis),
+        };
+        let closest = vec![
+            (false, None),
+            (true, None),
+            (true, Some(300)),
+            (false, Some(10)),
+            (true, Some(20)),
+            (true, None),
+        ];
+        assert_eq!(QuerySeeding::Quality.pool_size(3), 6);
+        assert_eq!(
+            QuerySeeding::Quality.seed(closest.clone(), 3, quality),
+            vec![(true, Some(20)), (true, Some(300)), (true, None)]
+        );
+        assert_eq!(
+            QuerySeeding::Distance.seed(closest.clone(), 3, quality),
+            closest[..3].to_vec()
+        );
+    }
+}
@@ -9,24 +9,25 @@ use crate::{
     kbucket::{BucketInsertResult, KBucketsTable, NodeStatus},
     node_info::NodeContact,
     packet::{DefaultProtocolId, ProtocolIdentity},
+    peer_store::PeerStore,
     query_pool::{QueryId, QueryPool},
     rpc::RequestId,
     service::{ActiveRequest, Service},
     socket::ListenConfig,
-    ConfigBuilder, Enr,
+    ConfigBuilder, Enr, ExternalAddressPolicy,
 };
 use enr::CombinedKey;
 use parking_lot::RwLock;
 use rand;
 use std::{
     collections::HashMap,
-    net::{Ipv4Addr, Ipv6Addr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4},
     sync::Arc,
     time::Duration,
 };
 use tokio::sync::{
     mpsc,
-    mpsc::{Sender, UnboundedReceiver},
+    mpsc::{Receiver, Sender},
     oneshot,
 };
 
@@ -66,27 +67,31 @@ async fn build_service<P: ProtocolIdentity>(
         .executor(Box::<crate::executor::TokioExecutor>::default())
         .build();
     // build the session service
-    let (_handler_exit, handler_send, handler_recv) =
-        Handler::spawn::<P>(local_enr.clone(), enr_key.clone(), config.clone())
-            .await
-            .unwrap();
+    let (_handler_exit, handler_send, handler_recv) = Handler::spawn::<P>(
+        local_enr.clone(),
+        enr_key.clone(),
+        Default::default(),
+        config.clone(),
+    )
+    .await
+    .unwrap();
 
     let (table_filter, bucket_filter) = if filters {
         (
-            Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Enr>>),
-            Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Enr>>),
+            Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Arc<Enr>>>),
+            Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Arc<Enr>>>),
         )
     } else {
         (None, None)
     };
 
-    let kbuckets = Arc::new(RwLock::new(KBucketsTable::new(
+    let kbuckets = Arc::new(KBucketsTable::new(
         local_enr.read().node_id().into(),
         Duration::from_secs(60),
         config.incoming_bucket_limit,
         table_filter,
         bucket_filter,
-    )));
+    ));
 
     // create the required channels
     let (_discv5_send, discv5_recv) = mpsc::channel(30);
@@ -97,8 +102,9 @@ async fn build_service<P: ProtocolIdentity>(
     Service {
         local_enr,
         enr_key,
+        enr_watch: Default::default(),
         kbuckets,
-        queries: QueryPool::new(config.query_timeout),
+        queries: QueryPool::new(config.query_timeout, config.clock.clone()),
         active_requests: Default::default(),
         active_nodes_responses: HashMap::new(),
         ip_votes: None,
@@ -112,6 +118,41 @@ async fn build_service<P: ProtocolIdentity>(
         config,
         ip_mode: Default::default(),
         connectivity_state,
+        nat_status: Default::default(),
+        nat_keepalive_peers: Default::default(),
+        nat_keepalive: None,
+        enr_store: None,
+        readvertise: None,
+        readvertise_queue: Default::default(),
+        advertised_enr_seq: 0,
+        bootnodes: Default::default(),
+        bootnode_retry: tokio::time::interval(Duration::from_secs(1)),
+        reserved_sessions_refresh: None,
+        reserved_nodes: Default::default(),
+        dial_preferences: LruTimeCache::new(Duration::from_secs(60), None),
+        happy_eyeballs: HashMapDelay::new(Duration::from_millis(250)),
+        peer_store_metadata: Default::default(),
+        peer_store_checkpoint: None,
+        peer_store_checkpointer: None,
+        ban_checkpoint: None,
+        checkpointed_ban_changes: 0,
+        nodes_response_cache: None,
+        reported_discovered: None,
+        eclipse_monitor: None,
+        ban_intel: None,
+        dial_back: None,
+        ban_intel_share: None,
+        topic_table: None,
+        topic_registrations: TopicRegistrations::new(TopicConfig::default()),
+        topic_refresh: tokio::time::interval(TOPIC_REFRESH_INTERVAL),
+        active_topic_queries: HashMap::new(),
+        topic_lookups: FuturesUnordered::new(),
+        self_lookup: None,
+        self_lookup_result: None,
+        peer_rtts: LruTimeCache::new(PEER_RTT_TTL, Some(PEER_RTT_CAPACITY)),
+        successive_failures: HashMap::new(),
+        session_starts: LruTimeCache::new(Duration::from_secs(86400), None),
+        injected_peers: HashMap::new(),
     }
 }
 
@@ -119,7 +160,7 @@ fn build_non_handler_service(
     local_enr: Arc<RwLock<Enr>>,
     enr_key: Arc<RwLock<CombinedKey>>,
     filters: bool,
-) -> (Service, UnboundedReceiver<HandlerIn>, Sender<HandlerOut>) {
+) -> (Service, Receiver<HandlerIn>, Sender<HandlerOut>) {
     let listen_config = ListenConfig::Ipv4 {
         ip: local_enr.read().ip4().unwrap(),
         port: local_enr.read().udp4().unwrap(),
@@ -127,25 +168,25 @@ fn build_non_handler_service(
     let config = ConfigBuilder::new(listen_config).build();
 
     // Fake's the handler with empty channels.
-    let (handler_send, handler_recv_fake) = mpsc::unbounded_channel();
+    let (handler_send, handler_recv_fake) = mpsc::channel(1000);
     let (handler_send_fake, handler_recv) = mpsc::channel(1000);
 
     let (table_filter, bucket_filter) = if filters {
         (
-            Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Enr>>),
-            Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Enr>>),
+            Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Arc<Enr>>>),
+            Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Arc<Enr>>>),
         )
     } else {
         (None, None)
     };
 
-    let kbuckets = Arc::new(RwLock::new(KBucketsTable::new(
+    let kbuckets = Arc::new(KBucketsTable::new(
         local_enr.read().node_id().into(),
         Duration::from_secs(60),
         config.incoming_bucket_limit,
         table_filter,
         bucket_filter,
-    )));
+    ));
 
     let ip_vote = IpVote::new(10, Duration::from_secs(10000));
 
@@ -158,8 +199,9 @@ fn build_non_handler_service(
     let service = Service {
         local_enr,
         enr_key,
+        enr_watch: Default::default(),
         kbuckets,
-        queries: QueryPool::new(config.query_timeout),
+        queries: QueryPool::new(config.query_timeout, config.clock.clone()),
         active_requests: Default::default(),
         active_nodes_responses: HashMap::new(),
         ip_votes: Some(ip_vote),
@@ -173,6 +215,41 @@ fn build_non_handler_service(
         config,
         ip_mode: IpMode::DualStack,
         connectivity_state,
+        nat_status: Default::default(),
+        nat_keepalive_peers: Default::default(),
+        nat_keepalive: None,
+        enr_store: None,
+        readvertise: None,
+        readvertise_queue: Default::default(),
+        advertised_enr_seq: 0,
+        bootnodes: Default::default(),
+        bootnode_retry: tokio::time::interval(Duration::from_secs(1)),
+        reserved_sessions_refresh: None,
+        reserved_nodes: Default::default(),
+        dial_preferences: LruTimeCache::new(Duration::from_secs(60), None),
+        happy_eyeballs: HashMapDelay::new(Duration::from_millis(250)),
+        peer_store_metadata: Default::default(),
+        peer_store_checkpoint: None,
+        peer_store_checkpointer: None,
+        ban_checkpoint: None,
+        checkpointed_ban_changes: 0,
+        nodes_response_cache: None,
+        reported_discovered: None,
+        eclipse_monitor: None,
+        ban_intel: None,
+        dial_back: None,
+        ban_intel_share: None,
+        topic_table: None,
+        topic_registrations: TopicRegistrations::new(TopicConfig::default()),
+        topic_refresh: tokio::time::interval(TOPIC_REFRESH_INTERVAL),
+        active_topic_queries: HashMap::new(),
+        topic_lookups: FuturesUnordered::new(),
+        self_lookup: None,
+        self_lookup_result: None,
+        peer_rtts: LruTimeCache::new(PEER_RTT_TTL, Some(PEER_RTT_CAPACITY)),
+        successive_failures: HashMap::new(),
+        session_starts: LruTimeCache::new(Duration::from_secs(86400), None),
+        injected_peers: HashMap::new(),
     };
     (service, handler_recv_fake, handler_send_fake)
 }
@@ -203,8 +280,8 @@ async fn test_updating_connection_on_ping() {
     .await;
     // Set up service with one disconnected node
     let key = kbucket::Key::from(enr2.node_id());
-    if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
-        match entry.insert(enr2.clone(), disconnected_state()) {
+    if let kbucket::Entry::Absent(entry) = service.kbuckets.entry(&key) {
+        match entry.insert(Arc::new(enr2.clone()), disconnected_state()) {
             BucketInsertResult::Inserted => {}
             BucketInsertResult::Full => {
                 panic!("Can't be full");
@@ -239,7 +316,7 @@ async fn test_updating_connection_on_ping() {
 
     // Handle the ping and expect the disconnected Node to become connected
     service.handle_rpc_response(expected_return_addr, response);
-    let buckets = service.kbuckets.read();
+    let buckets = service.kbuckets;
     let node = buckets.iter_ref().next().unwrap();
     assert!(node.status.is_connected())
 }
@@ -276,25 +353,37 @@ async fn test_connection_direction_on_inject_session_established() {
     let dummy_socket = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 80);
     // Test that the existing connection direction is not updated.
     // Incoming
-    service.inject_session_established(enr2.clone(), &dummy_socket, ConnectionDirection::Incoming);
-    let status = service.kbuckets.read().iter_ref().next().unwrap().status;
+    service.inject_session_established(
+        Arc::new(enr2.clone()),
+        &dummy_socket,
+        ConnectionDirection::Incoming,
+    );
+    let status = service.kbuckets.iter_ref().next().unwrap().status;
     assert!(status.is_connected());
     assert_eq!(ConnectionDirection::Incoming, status.direction);
 
-    service.inject_session_established(enr2.clone(), &dummy_socket, ConnectionDirection::Outgoing);
-    let status = service.kbuckets.read().iter_ref().next().unwrap().status;
+    service.inject_session_established(
+        Arc::new(enr2.clone()),
+        &dummy_socket,
+        ConnectionDirection::Outgoing,
+    );
+    let status = service.kbuckets.iter_ref().next().unwrap().status;
     assert!(status.is_connected());
     assert_eq!(ConnectionDirection::Incoming, status.direction);
 
     // (disconnected) Outgoing
-    let result = service.kbuckets.write().update_node_status(
+    let result = service.kbuckets.update_node_status(
         key,
         ConnectionState::Disconnected,
         Some(ConnectionDirection::Outgoing),
     );
     assert!(matches!(result, UpdateResult::Updated));
-    service.inject_session_established(enr2.clone(), &dummy_socket, ConnectionDirection::Incoming);
-    let status = service.kbuckets.read().iter_ref().next().unwrap().status;
+    service.inject_session_established(
+        Arc::new(enr2.clone()),
+        &dummy_socket,
+        ConnectionDirection::Incoming,
+    );
+    let status = service.kbuckets.iter_ref().next().unwrap().status;
     assert!(status.is_connected());
     assert_eq!(ConnectionDirection::Outgoing, status.direction);
 }
@@ -377,7 +466,7 @@ async fn test_handling_concurrent_responses() {
             id: RequestId(vec![1]),
             body: ResponseBody::Nodes {
                 total: 2,
-                nodes: vec![enrs_for_response.pop().unwrap()],
+                nodes: vec![Arc::new(enrs_for_response.pop().unwrap())],
             },
         },
     );
@@ -394,7 +483,7 @@ async fn test_handling_concurrent_responses() {
             id: RequestId(vec![2]),
             body: ResponseBody::Nodes {
                 total: 1,
-                nodes: vec![enrs_for_response.pop().unwrap()],
+                nodes: vec![Arc::new(enrs_for_response.pop().unwrap())],
             },
         },
     );
@@ -410,7 +499,7 @@ async fn test_handling_concurrent_responses() {
             id: RequestId(vec![1]),
             body: ResponseBody::Nodes {
                 total: 2,
-                nodes: vec![enrs_for_response.pop().unwrap()],
+                nodes: vec![Arc::new(enrs_for_response.pop().unwrap())],
             },
         },
     );
@@ -418,6 +507,99 @@ async fn test_handling_concurrent_responses() {
     assert!(service.active_nodes_responses.is_empty());
 }
 
+#[tokio::test]
+async fn test_invalid_nodes_responses_are_penalized() {
+    init();
+
+    // Seed is chosen such that all nodes are in the 256th distance of the first node.
+    let mut keypairs = generate_deterministic_keypair(5, 1652);
+    let enr_key = keypairs.pop().unwrap();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(10008)
+        .build(&enr_key)
+        .unwrap();
+    let (mut service, _handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.config.max_nodes_response = 2;
+    let mut security_events = AUDIT_LOG.subscribe();
+
+    let node_contact: NodeContact = Enr::builder()
+        .ip4(Ipv4Addr::new(192, 0, 2, 10))
+        .udp4(10009)
+        .build(&keypairs.remove(0))
+        .unwrap()
+        .into();
+    let node_address = node_contact.node_address();
+    let nodes: Vec<Arc<Enr>> = keypairs
+        .iter()
+        .map(|key| Arc::new(Enr::builder().build(key).unwrap()))
+        .collect();
+    let mut respond = |id: u8, distance: u64, nodes: Vec<Arc<Enr>>| {
+        service.active_requests.insert(
+            RequestId(vec![id]),
+            ActiveRequest {
+                contact: node_contact.clone(),
+                request_body: RequestBody::FindNode {
+                    distances: vec![distance],
+                },
+                query_id: None,
+                callback: None,
+            },
+        );
+        service.handle_rpc_response(
+            node_address.clone(),
+            Response {
+                id: RequestId(vec![id]),
+                body: ResponseBody::Nodes { total: 1, nodes },
+            },
+        );
+    };
+    let node_id = node_address.node_id;
+    let mut violations = move || {
+        let mut kinds = Vec::new();
+        while let Ok(event) = security_events.try_recv() {
+            if event.node_id == Some(node_id) {
+                kinds.push(event.kind);
+            }
+        }
+        kinds
+    };
+
+    // Too many nodes are truncated, but don't get the peer banned.
+    respond(1, 256, nodes.clone());
+    assert_eq!(
+        violations(),
+        vec![SecurityEventKind::InvalidResponse(
+            ResponseViolation::ExcessNodes
+        )]
+    );
+    assert!(!PERMIT_BAN_LIST
+        .read()
+        .ban_nodes()
+        .contains_key(&node_contact.node_id()));
+
+    // Nodes at distances that were not requested do.
+    respond(2, 255, nodes);
+    let violation = ResponseViolation::UnrequestedDistance;
+    assert_eq!(
+        violations(),
+        vec![
+            SecurityEventKind::InvalidResponse(violation),
+            SecurityEventKind::NodeBanned {
+                reason: BanReason::InvalidResponse(violation),
+                duration: service.config.ban_duration,
+            },
+        ]
+    );
+    let mut permit_ban_list = PERMIT_BAN_LIST.write();
+    assert!(permit_ban_list.unban_node(&node_contact.node_id()));
+    permit_ban_list.unban_ip(&node_contact.socket_addr().ip());
+}
+
 fn generate_rand_ipv4() -> Ipv4Addr {
     let a: u8 = rand::random();
     let b: u8 = rand::random();
@@ -479,7 +661,7 @@ async fn test_ipv6_update_amongst_ipv4_dominated_network() {
             .unwrap();
 
         let direction = random_connection_direction();
-        service.inject_session_established(enr.clone(), &dummy_socket, direction);
+        service.inject_session_established(Arc::new(enr.clone()), &dummy_socket, direction);
     }
 
     // Attempt to add 10 IPv6 nodes and expect that we attempt to send 10 PING's to IPv6 nodes.
@@ -493,13 +675,13 @@ async fn test_ipv6_update_amongst_ipv4_dominated_network() {
             .unwrap();
 
         let direction = ConnectionDirection::Outgoing;
-        service.inject_session_established(enr.clone(), &dummy_socket, direction);
+        service.inject_session_established(Arc::new(enr.clone()), &dummy_socket, direction);
     }
 
     // Collect all the messages to the handler and count the PING requests for ENR v6 addresses.
     let mut v6_pings = 0;
     while let Ok(event) = handler_recv.try_recv() {
-        if let HandlerIn::Request(contact, request) = event {
+        if let HandlerIn::Request(contact, request, ..) = event {
             if contact.node_address().socket_addr.is_ipv6()
                 && matches!(request.body, RequestBody::Ping { .. })
             {
@@ -511,3 +693,1083 @@ async fn test_ipv6_update_amongst_ipv4_dominated_network() {
     // Should be 10 ipv6 pings
     assert_eq!(v6_pings, 10)
 }
+
+#[tokio::test]
+async fn test_paced_enr_readvertisement() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+
+    let (mut service, mut handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.config.enr_readvertise = Some((2, Duration::from_secs(1)));
+    service.advertised_enr_seq = service.local_enr.read().seq();
+
+    let dummy_socket = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 80);
+    for _ in 0..3 {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip4(generate_rand_ipv4())
+            .udp4(DEFAULT_UDP_PORT)
+            .build(&key)
+            .unwrap();
+        service.inject_session_established(
+            Arc::new(enr),
+            &dummy_socket,
+            ConnectionDirection::Incoming,
+        );
+    }
+    while handler_recv.try_recv().is_ok() {}
+
+    let count_pings = |handler_recv: &mut Receiver<HandlerIn>| {
+        let mut pings = 0;
+        while let Ok(event) = handler_recv.try_recv() {
+            if let HandlerIn::Request(_, request, ..) = event {
+                if matches!(request.body, RequestBody::Ping { .. }) {
+                    pings += 1;
+                }
+            }
+        }
+        pings
+    };
+
+    // Nothing to advertise while the ENR is unchanged.
+    service.send_readvertise_pings();
+    assert_eq!(count_pings(&mut handler_recv), 0);
+
+    // An application update to the ENR is advertised to at most two peers per tick.
+    let enr_key = service.enr_key.clone();
+    service
+        .local_enr
+        .write()
+        .insert("test", &1u8, &enr_key.read())
+        .unwrap();
+    service.send_readvertise_pings();
+    assert_eq!(count_pings(&mut handler_recv), 2);
+    service.send_readvertise_pings();
+    assert_eq!(count_pings(&mut handler_recv), 1);
+    service.send_readvertise_pings();
+    assert_eq!(count_pings(&mut handler_recv), 0);
+}
+
+#[tokio::test]
+async fn test_advertised_port_overrides_observed_port() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+
+    let (mut service, _handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.config.advertised_udp4_port = Some(30303);
+
+    // Peers behind the NAT observe differing source ports, but agree on the IP.
+    let external_ip = Ipv4Addr::new(192, 0, 2, 1);
+    for port in 40000..40010 {
+        let voter = Ipv4Addr::new(198, 51, 100, port as u8);
+        service.handle_ip_vote_from_pong(
+            NodeId::random(),
+            voter.into(),
+            SocketAddr::new(external_ip.into(), port),
+        );
+    }
+
+    assert_eq!(
+        service.local_enr.read().udp4_socket(),
+        Some(SocketAddrV4::new(external_ip, 30303))
+    );
+    // The NAT is classified from the observed sockets, not the advertised one.
+    assert_eq!(service.nat_status.read().ipv4, NatType::Symmetric);
+}
+
+#[tokio::test]
+async fn test_custom_external_address_policy() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+
+    let (mut service, _handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    // Behind an anycast address peers disagree, so only the load balancer's address is accepted.
+    let anycast = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 30303);
+    service.config.external_address_policy =
+        ExternalAddressPolicy::from_callback(move |socket| socket == anycast);
+
+    let egress = SocketAddr::new(Ipv4Addr::new(198, 51, 100, 7).into(), 40000);
+    service.handle_ip_vote_from_pong(NodeId::random(), Ipv4Addr::LOCALHOST.into(), egress);
+    assert_eq!(
+        service.local_enr.read().udp4_socket(),
+        Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, DEFAULT_UDP_PORT))
+    );
+
+    // A single report suffices, without a majority of votes.
+    service.handle_ip_vote_from_pong(NodeId::random(), Ipv4Addr::LOCALHOST.into(), anycast);
+    assert_eq!(
+        service.local_enr.read().udp4_socket().map(SocketAddr::V4),
+        Some(anycast)
+    );
+}
+
+#[tokio::test]
+async fn test_voted_socket_is_advertised_once_dialed_back() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+
+    let (mut service, mut handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    let dial_back_config = DialBackConfig::default();
+    service.config.dial_back = Some(dial_back_config.clone());
+    service.dial_back = Some(DialBack::new(
+        dial_back_config,
+        service.config.clock.clone(),
+    ));
+
+    let dummy_socket = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 80);
+    for _ in 0..3 {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip4(generate_rand_ipv4())
+            .udp4(DEFAULT_UDP_PORT)
+            .build(&key)
+            .unwrap();
+        service.inject_session_established(
+            Arc::new(enr),
+            &dummy_socket,
+            ConnectionDirection::Outgoing,
+        );
+    }
+    while handler_recv.try_recv().is_ok() {}
+
+    // A majority of peers vote for a new socket, which is not advertised before it is verified.
+    let local_socket = service.local_enr.read().udp4_socket();
+    let candidate = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 30303);
+    for voter in 1..=10 {
+        service.handle_ip_vote_from_pong(
+            NodeId::random(),
+            Ipv4Addr::new(198, 51, 100, voter).into(),
+            candidate,
+        );
+    }
+    assert_eq!(service.local_enr.read().udp4_socket(), local_socket);
+
+    let mut dial_backs = Vec::new();
+    while let Ok(event) = handler_recv.try_recv() {
+        if let HandlerIn::Request(contact, request, ..) = event {
+            if let RequestBody::Talk {
+                protocol,
+                request: body,
+            } = &request.body
+            {
+                assert_eq!(protocol, b"dialback");
+                assert_eq!(dial_back::decode_candidate(body), Some(candidate));
+                dial_backs.push((contact.node_address(), request.id.clone()));
+            }
+        }
+    }
+    assert_eq!(dial_backs.len(), 3);
+
+    // Once two of the peers reached us at the socket, it is advertised.
+    for (node_address, id) in dial_backs.into_iter().take(2) {
+        assert_eq!(service.local_enr.read().udp4_socket(), local_socket);
+        service.handle_rpc_response(
+            node_address,
+            Response {
+                id,
+                body: ResponseBody::Talk { response: vec![1] },
+            },
+        );
+    }
+    assert_eq!(
+        service.local_enr.read().udp4_socket().map(SocketAddr::V4),
+        Some(candidate)
+    );
+}
+
+#[tokio::test]
+async fn test_bootnode_mode() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let local_key = kbucket::Key::from(local_enr.node_id());
+
+    let (mut service, mut handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.config.bootnode_mode = true;
+    service.nodes_response_cache = Some(LruTimeCache::new(Duration::from_secs(60), Some(10)));
+
+    // Peers we dialed are inserted without being pinged.
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(generate_rand_ipv4())
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&key)
+        .unwrap();
+    let dummy_socket = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 80);
+    service.inject_session_established(
+        Arc::new(enr.clone()),
+        &dummy_socket,
+        ConnectionDirection::Outgoing,
+    );
+    assert_eq!(service.kbuckets.iter().count(), 1);
+    assert!(handler_recv.try_recv().is_err());
+
+    let distance = local_key
+        .log2_distance(&kbucket::Key::from(enr.node_id()))
+        .unwrap();
+    let mut find_node = |service: &mut Service| {
+        let requester = NodeAddress {
+            socket_addr: dummy_socket,
+            node_id: NodeId::random(),
+        };
+        service.send_nodes_response(requester, RequestId::random(), vec![distance]);
+        match handler_recv.try_recv() {
+            Ok(HandlerIn::Response(_, response)) => match response.body {
+                ResponseBody::Nodes { nodes, .. } => nodes,
+                body => panic!("Unexpected response {}", body),
+            },
+            _ => panic!("No response sent"),
+        }
+    };
+
+    // Responses are served from the cache until it expires, regardless of table changes.
+    assert_eq!(find_node(&mut service), vec![Arc::new(enr.clone())]);
+    service.kbuckets.remove(&kbucket::Key::from(enr.node_id()));
+    assert_eq!(find_node(&mut service), vec![Arc::new(enr)]);
+}
+
+#[tokio::test]
+async fn test_request_fails_when_handler_queue_is_full() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+
+    let (mut service, _handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    let (handler_send, mut handler_recv) = mpsc::channel(1);
+    service.handler_send = handler_send;
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Arc::new(
+        Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(DEFAULT_UDP_PORT + 1)
+            .build(&key)
+            .unwrap(),
+    );
+    let (queued, _queued_recv) = oneshot::channel();
+    service.send_ping(enr.clone(), Some(queued));
+    let (dropped, dropped_recv) = oneshot::channel();
+    service.send_ping(enr, Some(dropped));
+
+    // The second request doesn't fit the queue and fails without waiting for a timeout.
+    assert!(matches!(
+        dropped_recv.await,
+        Ok(Err(RequestError::ChannelFailed(_)))
+    ));
+    assert_eq!(service.active_requests.len(), 1);
+    assert!(handler_recv.try_recv().is_ok());
+}
+
+#[tokio::test]
+async fn test_discovered_peers_are_reported_once_per_record() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let (mut service, _handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.reported_discovered = Some(LruTimeCache::new(Duration::from_secs(60), Some(10)));
+    let (event_send, mut event_recv) = mpsc::channel(10);
+    service.event_stream = Some(event_send);
+
+    let key = CombinedKey::generate_secp256k1();
+    let mut enr = Enr::builder()
+        .ip4(generate_rand_ipv4())
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&key)
+        .unwrap();
+    let source = NodeId::random();
+    let mut discovered = |service: &mut Service, enr: &Enr| {
+        service.discovered(&source, vec![Arc::new(enr.clone())], None);
+        let mut reported = 0;
+        while let Ok(event) = event_recv.try_recv() {
+            if matches!(event, Event::Discovered(_)) {
+                reported += 1;
+            }
+        }
+        reported
+    };
+
+    assert_eq!(discovered(&mut service, &enr), 1);
+    assert_eq!(discovered(&mut service, &enr), 0);
+    enr.insert("test", &1u8, &key).unwrap();
+    assert_eq!(discovered(&mut service, &enr), 1);
+
+    // Without deduplication every discovered ENR is reported.
+    service.reported_discovered = None;
+    assert_eq!(discovered(&mut service, &enr), 1);
+}
+
+#[tokio::test]
+async fn test_unresponsive_peer_is_dropped() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let (mut service, mut handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.config.max_successive_failures = Some(2);
+    let (event_send, mut event_recv) = mpsc::channel(10);
+    service.event_stream = Some(event_send);
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT + 1)
+        .build(&key)
+        .unwrap();
+    let node_id = enr.node_id();
+    let _ = service.kbuckets.insert_or_update(
+        &kbucket::Key::from(node_id),
+        Arc::new(enr.clone()),
+        _connected_state(),
+    );
+
+    service.bootnodes.write().insert(enr.clone());
+    let bootnode_failures =
+        |service: &Service| service.bootnodes.read().health(|_| true)[0].consecutive_failures;
+
+    let fail_ping = |service: &mut Service, id: u8, error: RequestError| {
+        let (callback, _callback_recv) = oneshot::channel();
+        service.active_requests.insert(
+            RequestId(vec![id]),
+            ActiveRequest {
+                contact: enr.clone().into(),
+                request_body: RequestBody::Ping { enr_seq: 1 },
+                query_id: None,
+                callback: Some(CallbackResponse::Pong(callback)),
+            },
+        );
+        service.rpc_failure(RequestId(vec![id]), error);
+    };
+    let is_connected = |service: &Service| {
+        service
+            .kbuckets
+            .iter_ref()
+            .any(|entry| *entry.node.key.preimage() == node_id && entry.status.is_connected())
+    };
+
+    // Requests failing locally, here because the queue to the handler is full, don't count.
+    for id in 1..=3 {
+        fail_ping(
+            &mut service,
+            id,
+            RequestError::ChannelFailed("Handler queue is full".into()),
+        );
+    }
+    assert!(is_connected(&service));
+    assert!(handler_recv.try_recv().is_err());
+    assert!(event_recv.try_recv().is_err());
+    assert_eq!(bootnode_failures(&service), 0);
+
+    fail_ping(&mut service, 4, RequestError::Timeout);
+    assert!(is_connected(&service));
+    assert!(handler_recv.try_recv().is_err());
+    assert_eq!(bootnode_failures(&service), 1);
+
+    fail_ping(&mut service, 5, RequestError::Timeout);
+    assert!(!is_connected(&service));
+    assert!(matches!(
+        handler_recv.try_recv(),
+        Ok(HandlerIn::RemoveSessions(id)) if id == node_id
+    ));
+    assert!(matches!(
+        event_recv.try_recv(),
+        Ok(Event::PeerUnresponsive { node_id: id, failures: 2 }) if id == node_id
+    ));
+}
+
+/// A peer store recording the banned nodes of the snapshots saved.
+#[derive(Default)]
+struct BanRecordingStore {
+    saved: parking_lot::Mutex<Vec<Vec<NodeId>>>,
+}
+
+impl PeerStore for BanRecordingStore {
+    fn load(&self) -> std::io::Result<PeerStoreSnapshot> {
+        Ok(PeerStoreSnapshot::default())
+    }
+
+    fn save(&self, snapshot: &PeerStoreSnapshot) -> std::io::Result<()> {
+        let banned = snapshot.banned_nodes.iter().map(|(node_id, _)| *node_id);
+        self.saved.lock().push(banned.collect());
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_ban_changes_are_checkpointed() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+
+    let (mut service, _handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    let store = Arc::new(BanRecordingStore::default());
+    let node_id = NodeId::random();
+    service.peer_store_checkpointer = Some(PeerStoreCheckpointer::spawn(store.clone()));
+
+    let last_saved_bans = |store: &BanRecordingStore, node_id: &NodeId| {
+        store
+            .saved
+            .lock()
+            .last()
+            .map(|banned| banned.contains(node_id))
+    };
+    let wait_for_save = |banned: bool| {
+        let store = store.clone();
+        async move {
+            for _ in 0..100 {
+                if last_saved_bans(&store, &node_id) == Some(banned) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("The ban of the node was not checkpointed");
+        }
+    };
+
+    // Bans and lifted bans are persisted at the next check.
+    PERMIT_BAN_LIST.write().ban_node(node_id, None);
+    service.checkpoint_changed_bans();
+    wait_for_save(true).await;
+
+    PERMIT_BAN_LIST.write().unban_node(&node_id);
+    service.checkpoint_changed_bans();
+    wait_for_save(false).await;
+}
+
+#[tokio::test]
+async fn test_talk_response_reestablishes_session_only_with_known_enr() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let (mut service, mut handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service
+        .config
+        .reestablish_session_for_talk_responses
+        .insert(b"test".to_vec());
+    let (event_send, mut event_recv) = mpsc::channel(10);
+    service.event_stream = Some(event_send);
+
+    let key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT + 1)
+        .build(&key)
+        .unwrap();
+    let node_address = NodeContact::from(enr.clone()).node_address();
+    let mut respond = |service: &mut Service| {
+        service.handle_rpc_request(
+            node_address.clone(),
+            Request {
+                id: RequestId(vec![1]),
+                body: RequestBody::Talk {
+                    protocol: b"test".to_vec(),
+                    request: vec![],
+                },
+            },
+        );
+        match event_recv.try_recv() {
+            Ok(Event::TalkRequest(request)) => request.respond(vec![1]).unwrap(),
+            _ => panic!("Expected a TALK request"),
+        }
+        handler_recv.try_recv().unwrap()
+    };
+
+    // Without the ENR of the requester, the response can only use the existing session.
+    assert!(matches!(respond(&mut service), HandlerIn::Response(..)));
+
+    let _ = service.kbuckets.insert_or_update(
+        &kbucket::Key::from(enr.node_id()),
+        Arc::new(enr),
+        _connected_state(),
+    );
+    assert!(matches!(
+        respond(&mut service),
+        HandlerIn::ResponseEstablishingSession(..)
+    ));
+}
+
+#[tokio::test]
+async fn test_reserved_nodes_are_sent_to_the_handler_on_change() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let (mut service, mut handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+
+    let enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT + 1)
+        .build(&CombinedKey::generate_secp256k1())
+        .unwrap();
+    let node_id = enr.node_id();
+    let _ = service.kbuckets.insert_or_update(
+        &kbucket::Key::from(node_id),
+        Arc::new(enr),
+        _connected_state(),
+    );
+
+    service.refresh_reserved_sessions();
+    assert!(matches!(
+        handler_recv.try_recv(),
+        Ok(HandlerIn::ReservedNodes(nodes)) if nodes.contains(&node_id)
+    ));
+    // Unchanged reserved nodes are not sent again.
+    service.refresh_reserved_sessions();
+    assert!(handler_recv.try_recv().is_err());
+
+    service.kbuckets.remove(&kbucket::Key::from(node_id));
+    service.refresh_reserved_sessions();
+    assert!(matches!(
+        handler_recv.try_recv(),
+        Ok(HandlerIn::ReservedNodes(nodes)) if !nodes.contains(&node_id)
+    ));
+}
+
+#[tokio::test]
+async fn test_find_node_appends_unreachable_enrs() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let (mut service, mut handler_recv, handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.ip_mode = IpMode::Ip4;
+    service.config.report_unreachable_enrs = true;
+    let (event_send, mut event_recv) = mpsc::channel(10);
+    service.event_stream = Some(event_send);
+    let (_exit_send, exit) = oneshot::channel();
+    service.exit = exit;
+
+    let peer = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT + 1)
+        .build(&CombinedKey::generate_secp256k1())
+        .unwrap();
+    let _ = service.kbuckets.insert_or_update(
+        &kbucket::Key::from(peer.node_id()),
+        Arc::new(peer.clone()),
+        _connected_state(),
+    );
+
+    let (callback, result) = oneshot::channel();
+    service.start_findnode_query(NodeId::random(), callback, None);
+    tokio::spawn(async move { service.start().await });
+
+    let (contact, request) = loop {
+        if let Some(HandlerIn::Request(contact, request, ..)) = handler_recv.recv().await {
+            break (contact, request);
+        }
+    };
+    assert_eq!(contact.node_id(), peer.node_id());
+    let RequestBody::FindNode { distances } = &request.body else {
+        panic!("Expected a FINDNODE request");
+    };
+    // Only advertises an IPv6 socket, which can't be contacted over IPv4. It is at one of the
+    // requested distances, so that the response is valid.
+    let unreachable = loop {
+        let enr = Enr::builder()
+            .ip6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))
+            .udp6(DEFAULT_UDP_PORT + 2)
+            .build(&CombinedKey::generate_secp256k1())
+            .unwrap();
+        let distance = kbucket::Key::from(peer.node_id())
+            .log2_distance(&kbucket::Key::from(enr.node_id()))
+            .unwrap();
+        if distances.contains(&distance) {
+            break enr;
+        }
+    };
+    let response = Response {
+        id: request.id,
+        body: ResponseBody::Nodes {
+            total: 1,
+            nodes: vec![Arc::new(unreachable.clone())],
+        },
+    };
+    handler_send
+        .send(HandlerOut::Response(
+            contact.node_address(),
+            Box::new(response),
+            None,
+        ))
+        .await
+        .unwrap();
+
+    let found = tokio::time::timeout(Duration::from_secs(5), result)
+        .await
+        .unwrap()
+        .unwrap();
+    let found: Vec<NodeId> = found.iter().map(|enr| enr.node_id()).collect();
+    assert_eq!(found, vec![peer.node_id(), unreachable.node_id()]);
+    assert!(matches!(
+        event_recv.try_recv(),
+        Ok(Event::DiscoveredUnreachable { enr, reachability: EnrReachability::OtherIpVersion })
+            if enr.node_id() == unreachable.node_id()
+    ));
+}
+
+#[tokio::test]
+async fn test_happy_eyeballs_follows_the_ip_version_that_worked() {
+    init();
+
+    let stagger = Duration::from_millis(300);
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let (mut service, mut handler_recv, handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.config.dial_policy = DialPolicy::HappyEyeballs { stagger };
+    service.happy_eyeballs = HashMapDelay::new(stagger);
+    let (_exit_send, exit) = oneshot::channel();
+    service.exit = exit;
+    let (discv5_send, discv5_recv) = mpsc::channel(10);
+    service.discv5_recv = discv5_recv;
+    tokio::spawn(async move { service.start().await });
+
+    let dual_stack_peer = |port| {
+        Arc::new(
+            Enr::builder()
+                .ip4(Ipv4Addr::LOCALHOST)
+                .udp4(port)
+                .ip6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))
+                .udp6(port)
+                .build(&CombinedKey::generate_secp256k1())
+                .unwrap(),
+        )
+    };
+    let ping = |enr: &Arc<Enr>| ServiceRequest::Ping(enr.clone(), None);
+    async fn next_request(
+        handler_recv: &mut Receiver<HandlerIn>,
+        wait: Duration,
+    ) -> Option<(NodeContact, Box<Request>)> {
+        loop {
+            match tokio::time::timeout(wait, handler_recv.recv()).await {
+                Ok(Some(HandlerIn::Request(contact, request, ..))) => {
+                    break Some((contact, request))
+                }
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => break None,
+            }
+        }
+    }
+    let wait = stagger * 2;
+
+    // The peer answers over IPv6, so it isn't pinged over IPv4 once the stagger expires.
+    let answering = dual_stack_peer(DEFAULT_UDP_PORT + 1);
+    discv5_send.send(ping(&answering)).await.unwrap();
+    let (contact, request) = next_request(&mut handler_recv, wait).await.unwrap();
+    assert!(contact.socket_addr().is_ipv6());
+    let response = Response {
+        id: request.id,
+        body: ResponseBody::Pong {
+            enr_seq: answering.seq(),
+            ip: Ipv4Addr::LOCALHOST.into(),
+            port: 9000.try_into().unwrap(),
+        },
+    };
+    handler_send
+        .send(HandlerOut::Response(
+            contact.node_address(),
+            Box::new(response),
+            None,
+        ))
+        .await
+        .unwrap();
+    assert!(next_request(&mut handler_recv, wait).await.is_none());
+
+    // A peer that connected to us over IPv4 is dialed over IPv4.
+    let incoming = dual_stack_peer(DEFAULT_UDP_PORT + 2);
+    let socket = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DEFAULT_UDP_PORT + 2);
+    handler_send
+        .send(HandlerOut::Established(
+            incoming.clone(),
+            socket,
+            ConnectionDirection::Incoming,
+        ))
+        .await
+        .unwrap();
+    tokio::time::sleep(stagger).await;
+    discv5_send.send(ping(&incoming)).await.unwrap();
+    let (contact, _) = next_request(&mut handler_recv, wait).await.unwrap();
+    assert_eq!(contact.node_id(), incoming.node_id());
+    assert_eq!(contact.socket_addr(), socket);
+
+    // A peer that doesn't answer over IPv6 is pinged over IPv4 after the stagger.
+    let silent = dual_stack_peer(DEFAULT_UDP_PORT + 3);
+    discv5_send.send(ping(&silent)).await.unwrap();
+    let (contact, _) = next_request(&mut handler_recv, wait).await.unwrap();
+    assert!(contact.socket_addr().is_ipv6());
+    let (contact, request) = next_request(&mut handler_recv, wait).await.unwrap();
+    assert_eq!(contact.node_id(), silent.node_id());
+    assert!(contact.socket_addr().is_ipv4());
+    assert!(matches!(request.body, RequestBody::Ping { .. }));
+}
+
+#[tokio::test]
+async fn test_nat_keepalive_pings_registered_peers_every_interval() {
+    init();
+
+    let interval = Duration::from_millis(100);
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let (mut service, mut handler_recv, _handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.nat_keepalive = Some(tokio::time::interval(interval));
+    let (_exit_send, exit) = oneshot::channel();
+    service.exit = exit;
+
+    let peer = |port| {
+        Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(port)
+            .build(&CombinedKey::generate_secp256k1())
+            .unwrap()
+    };
+    let registered = peer(DEFAULT_UDP_PORT + 1);
+    let unregistered = peer(DEFAULT_UDP_PORT + 2);
+    for enr in [&registered, &unregistered] {
+        let _ = service.kbuckets.insert_or_update(
+            &kbucket::Key::from(enr.node_id()),
+            Arc::new(enr.clone()),
+            _connected_state(),
+        );
+    }
+    // Peers outside of the routing table can't be pinged.
+    let keepalive_peers = service.nat_keepalive_peers.clone();
+    keepalive_peers.write().insert(registered.node_id());
+    keepalive_peers.write().insert(NodeId::random());
+    tokio::spawn(async move { service.start().await });
+
+    let mut pinged = Vec::new();
+    let observe = tokio::time::sleep(interval * 3 + interval / 2);
+    tokio::pin!(observe);
+    loop {
+        tokio::select! {
+            Some(request) = handler_recv.recv() => {
+                if let HandlerIn::Request(contact, request, ..) = request {
+                    assert!(matches!(request.body, RequestBody::Ping { .. }));
+                    pinged.push(contact.node_id());
+                }
+            }
+            _ = &mut observe => break,
+        }
+    }
+    // The first ping is sent right away, then one every interval.
+    assert!(pinged.len() >= 3, "{} keepalive pings", pinged.len());
+    assert!(pinged
+        .iter()
+        .all(|node_id| *node_id == registered.node_id()));
+
+    // Removed peers are no longer pinged.
+    keepalive_peers.write().clear();
+    tokio::time::sleep(interval).await;
+    while handler_recv.try_recv().is_ok() {}
+    assert!(tokio::time::timeout(interval * 2, handler_recv.recv())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_session_events_report_direction_and_table_novelty() {
+    init();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let (mut service, _handler_recv, handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    let (event_send, mut event_recv) = mpsc::channel(10);
+    service.event_stream = Some(event_send);
+    let (_exit_send, exit) = oneshot::channel();
+    service.exit = exit;
+    tokio::spawn(async move { service.start().await });
+
+    let peer = Arc::new(
+        Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(DEFAULT_UDP_PORT + 1)
+            .build(&CombinedKey::generate_secp256k1())
+            .unwrap(),
+    );
+    let socket = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DEFAULT_UDP_PORT + 1);
+    async fn next_event(event_recv: &mut mpsc::Receiver<Event>) -> Event {
+        tokio::time::timeout(Duration::from_secs(5), event_recv.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    // A peer new to the table connects to us and is inserted before the session is reported.
+    handler_send
+        .send(HandlerOut::Established(
+            peer.clone(),
+            socket,
+            ConnectionDirection::Incoming,
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(
+        next_event(&mut event_recv).await,
+        Event::NodeInserted {
+            direction: ConnectionDirection::Incoming,
+            replaced: None,
+            ..
+        }
+    ));
+    assert!(matches!(
+        next_event(&mut event_recv).await,
+        Event::SessionEstablished(enr, established) if enr == peer && established == socket
+    ));
+    assert!(matches!(
+        next_event(&mut event_recv).await,
+        Event::PeerConnected {
+            direction: ConnectionDirection::Incoming,
+            new_to_table: true,
+            ..
+        }
+    ));
+
+    // We establish a new session with the peer, which is in the table by now.
+    handler_send
+        .send(HandlerOut::Established(
+            peer.clone(),
+            socket,
+            ConnectionDirection::Outgoing,
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(
+        next_event(&mut event_recv).await,
+        Event::SessionEstablished(..)
+    ));
+    assert!(matches!(
+        next_event(&mut event_recv).await,
+        Event::PeerConnected {
+            direction: ConnectionDirection::Outgoing,
+            new_to_table: false,
+            ..
+        }
+    ));
+}
+
+#[tokio::test]
+async fn test_self_lookup_runs_every_interval_one_at_a_time() {
+    init();
+
+    async fn next_request(
+        handler_recv: &mut Receiver<HandlerIn>,
+        wait: Duration,
+    ) -> Option<(NodeContact, Request)> {
+        tokio::time::timeout(wait, async {
+            loop {
+                if let Some(HandlerIn::Request(contact, request, ..)) = handler_recv.recv().await {
+                    return (contact, *request);
+                }
+            }
+        })
+        .await
+        .ok()
+    }
+
+    let interval = Duration::from_millis(200);
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let local_id = local_enr.node_id();
+    let (mut service, mut handler_recv, handler_send) = build_non_handler_service(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    );
+    service.ip_mode = IpMode::Ip4;
+    service.self_lookup = Some(tokio::time::interval_at(
+        tokio::time::Instant::now() + interval,
+        interval,
+    ));
+    let (_exit_send, exit) = oneshot::channel();
+    service.exit = exit;
+
+    let peer = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT + 1)
+        .build(&CombinedKey::generate_secp256k1())
+        .unwrap();
+    let _ = service.kbuckets.insert_or_update(
+        &kbucket::Key::from(peer.node_id()),
+        Arc::new(peer.clone()),
+        _connected_state(),
+    );
+    tokio::spawn(async move { service.start().await });
+
+    // The first lookup starts after one interval and targets our own node id.
+    assert!(next_request(&mut handler_recv, interval / 2)
+        .await
+        .is_none());
+    let (contact, request) = next_request(&mut handler_recv, interval * 2)
+        .await
+        .expect("a self-lookup request");
+    assert_eq!(contact.node_id(), peer.node_id());
+    let RequestBody::FindNode { distances } = &request.body else {
+        panic!("Expected a FINDNODE request");
+    };
+    let distance = kbucket::Key::from(peer.node_id())
+        .log2_distance(&kbucket::Key::from(local_id))
+        .unwrap();
+    assert!(distances.contains(&distance));
+
+    // No new lookup starts while the peer hasn't answered.
+    assert!(next_request(&mut handler_recv, interval * 3)
+        .await
+        .is_none());
+
+    // Once the lookup completes, the next interval starts another one.
+    let response = Response {
+        id: request.id,
+        body: ResponseBody::Nodes {
+            total: 1,
+            nodes: Vec::new(),
+        },
+    };
+    handler_send
+        .send(HandlerOut::Response(
+            contact.node_address(),
+            Box::new(response),
+            None,
+        ))
+        .await
+        .unwrap();
+    let (contact, request) = next_request(&mut handler_recv, interval * 2)
+        .await
+        .expect("a second self-lookup request");
+    assert_eq!(contact.node_id(), peer.node_id());
+    assert!(matches!(request.body, RequestBody::FindNode { .. }));
+}
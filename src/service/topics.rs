@@ -0,0 +1,853 @@
+//! Topic advertisement.
+//!
+//! A node advertises itself for a topic by registering with the nodes closest to the hash of the
+//! topic, which hold the advertisements in their topic table and hand them out in answer to topic
+//! queries. A registrar whose table has no room for another advertisement does not turn the
+//! registrant away but issues it a ticket, stating how long to wait for a slot to free up. The
+//! ticket is encrypted with a key only the registrar knows, so that it cannot be forged, and is
+//! presented along with the next registration attempt.
+//!
+//! Registrants and searchers find the registrars of a topic with a lookup of the topic hash. A
+//! registrar favours the registrants that have been waiting longest: a slot that frees up goes to
+//! the holder of the oldest ticket, and a registrant keeps the time it has waited across the
+//! tickets it is issued. A registrar holds only a few advertisements from any one IP address.
+//!
+//! The registrars closest to the hash of a popular topic fill up, so registrants spread their
+//! advertisements over a radius around the hash, and searchers ask registrars within the same
+//! radius. The radius is estimated from the nodes known around the hash, as the distance within
+//! which the configured number of registrars are found. Wait times longer than the target widen
+//! the radius of the topics we advertise, and confirmations narrow it again, so that the
+//! advertisements of a popular topic reach further from the hash than those of a rare one.
+use crate::{
+    kbucket,
+    rpc::TopicHash,
+    time::{Clock, Instant},
+    Enr,
+};
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, KeyInit},
+    Aes128Gcm,
+};
+use alloy_rlp::{Bytes, Decodable, Encodable, Header};
+use delay_map::HashMapDelay;
+use enr::NodeId;
+use futures::{Stream, StreamExt};
+use rand::seq::IteratorRandom;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    convert::{TryFrom, TryInto},
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// The time before an advertisement expires that it is renewed.
+const RENEWAL_MARGIN: Duration = Duration::from_secs(10);
+
+/// The time a registrant has to present a due ticket before it loses its place in line.
+const TICKET_GRACE: Duration = Duration::from_secs(10);
+
+/// The maximum number of advertisements returned for a topic query.
+pub(crate) const MAX_TOPIC_QUERY_RESULTS: usize = 16;
+
+const NONCE_LENGTH: usize = 12;
+
+/// Configures topic advertisement. See [`crate::ConfigBuilder::topics`]. The number of registrars
+/// and the radius estimate also apply to topic queries.
+#[derive(Debug, Clone)]
+pub struct TopicConfig {
+    /// The time an advertisement is held for, and after which our own advertisements are renewed.
+    /// Registrars asking us to wait longer than this are left for others. Default: 15 minutes.
+    pub ad_lifetime: Duration,
+    /// The maximum number of advertisements we hold as a registrar. Default: 5000.
+    pub table_capacity: usize,
+    /// The maximum number of advertisements we hold for a single topic. Default: 100.
+    pub ads_per_topic: usize,
+    /// The maximum number of advertisements we hold from a single IP address, across all topics.
+    /// Default: 10.
+    pub ads_per_ip: usize,
+    /// The number of registrars each of our topics is advertised at. Default: 8.
+    pub registrars: usize,
+    /// The wait time above which a ticket is taken as a sign that the registrars near the topic
+    /// hash are full, widening the radius the advertisements are spread over. Default: 1 minute.
+    pub target_wait: Duration,
+    /// The maximum number of registrars each topic is newly registered with per refresh, which
+    /// paces the placement of advertisements. Default: 4.
+    pub registrations_per_refresh: usize,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        TopicConfig {
+            ad_lifetime: Duration::from_secs(15 * 60),
+            table_capacity: 5000,
+            ads_per_topic: 100,
+            ads_per_ip: 10,
+            registrars: 8,
+            target_wait: Duration::from_secs(60),
+            registrations_per_refresh: 4,
+        }
+    }
+}
+
+/// An advertisement held in the topic table.
+struct Ad {
+    enr: Arc<Enr>,
+    /// The address the registration came from.
+    ip: IpAddr,
+    expires: Instant,
+}
+
+/// A registrant that has been issued a ticket for a topic that is full. Times are relative to the
+/// creation of the topic table.
+struct Waiting {
+    /// The first attempt of the registrant.
+    since: Duration,
+    /// When the ticket last issued to the registrant is due.
+    due: Duration,
+}
+
+/// The answer to a registration attempt.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Registration {
+    /// The registrant is advertised.
+    Confirmed,
+    /// The registrant has to try again with the ticket after the wait time.
+    Ticket {
+        ticket: Vec<u8>,
+        wait_time: Duration,
+    },
+}
+
+/// The contents of a ticket. Times are relative to the creation of the topic table.
+struct Ticket {
+    node_id: NodeId,
+    ip: IpAddr,
+    topic: TopicHash,
+    /// The first attempt of the registrant, carried over from the ticket it presented.
+    waiting_since: Duration,
+    issued_at: Duration,
+    wait_time: Duration,
+}
+
+impl Ticket {
+    fn encrypt(&self, key: &[u8; 16]) -> Vec<u8> {
+        let mut list = Vec::<u8>::new();
+        self.node_id.raw().as_slice().encode(&mut list);
+        match self.ip {
+            IpAddr::V4(ip) => ip.octets().as_slice().encode(&mut list),
+            IpAddr::V6(ip) => ip.octets().as_slice().encode(&mut list),
+        }
+        self.topic.as_bytes().as_slice().encode(&mut list);
+        (self.waiting_since.as_millis() as u64).encode(&mut list);
+        (self.issued_at.as_millis() as u64).encode(&mut list);
+        (self.wait_time.as_millis() as u64).encode(&mut list);
+        let mut plaintext = Vec::new();
+        Header {
+            list: true,
+            payload_length: list.len(),
+        }
+        .encode(&mut plaintext);
+        plaintext.extend_from_slice(&list);
+
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        let aead = Aes128Gcm::new(GenericArray::from_slice(key));
+        let mut ticket = nonce.to_vec();
+        ticket.extend(
+            aead.encrypt(GenericArray::from_slice(&nonce), plaintext.as_slice())
+                .expect("Plaintext is within the AES-GCM limits"),
+        );
+        ticket
+    }
+
+    /// Decrypts a ticket. Returns `None` if it was not issued with the key.
+    fn decrypt(key: &[u8; 16], ticket: &[u8]) -> Option<Self> {
+        if ticket.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = ticket.split_at(NONCE_LENGTH);
+        let aead = Aes128Gcm::new(GenericArray::from_slice(key));
+        let plaintext = aead
+            .decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .ok()?;
+
+        let buf = &mut plaintext.as_slice();
+        let header = Header::decode(buf).ok()?;
+        if !header.list {
+            return None;
+        }
+        let node_id: [u8; 32] = Bytes::decode(buf).ok()?[..].try_into().ok()?;
+        let ip = Bytes::decode(buf).ok()?;
+        let ip = match ip.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(&ip[..]).ok()?),
+            16 => IpAddr::from(<[u8; 16]>::try_from(&ip[..]).ok()?),
+            _ => return None,
+        };
+        let topic: [u8; 32] = Bytes::decode(buf).ok()?[..].try_into().ok()?;
+        let waiting_since = Duration::from_millis(u64::decode(buf).ok()?);
+        let issued_at = Duration::from_millis(u64::decode(buf).ok()?);
+        let wait_time = Duration::from_millis(u64::decode(buf).ok()?);
+        Some(Ticket {
+            node_id: NodeId::new(&node_id),
+            ip,
+            topic: TopicHash::from_raw(topic),
+            waiting_since,
+            issued_at,
+            wait_time,
+        })
+    }
+}
+
+/// The advertisements we hold as a registrar.
+pub(crate) struct TopicTable {
+    config: TopicConfig,
+    /// The advertisements of each topic, oldest first.
+    ads: HashMap<TopicHash, VecDeque<Ad>>,
+    /// The number of advertisements across all topics.
+    len: usize,
+    /// The number of advertisements from each IP address.
+    ips: HashMap<IpAddr, usize>,
+    /// The registrants waiting for a slot of each topic.
+    waiting: HashMap<TopicHash, HashMap<NodeId, Waiting>>,
+    /// The key tickets are encrypted with.
+    ticket_key: [u8; 16],
+    /// The clock advertisements expire and tickets fall due by.
+    clock: Arc<dyn Clock>,
+    created: Instant,
+}
+
+impl TopicTable {
+    pub fn new(config: TopicConfig, clock: Arc<dyn Clock>) -> Self {
+        TopicTable {
+            config,
+            ads: HashMap::new(),
+            len: 0,
+            ips: HashMap::new(),
+            waiting: HashMap::new(),
+            ticket_key: rand::random(),
+            created: clock.now(),
+            clock,
+        }
+    }
+
+    /// The time since the table was created, which the times of tickets are relative to.
+    fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.created)
+    }
+
+    /// Handles an attempt to register `enr` for `topic`, presenting `ticket` unless it is the
+    /// first attempt. `ip` is the address the attempt came from.
+    pub fn register(
+        &mut self,
+        topic: TopicHash,
+        enr: Arc<Enr>,
+        ip: IpAddr,
+        ticket: &[u8],
+    ) -> Registration {
+        self.prune();
+        let node_id = enr.node_id();
+        let now = self.elapsed();
+
+        // Renewing an advertisement replaces it.
+        if let Some(ads) = self.ads.get_mut(&topic) {
+            if let Some(index) = ads.iter().position(|ad| ad.enr.node_id() == node_id) {
+                if let Some(ad) = ads.remove(index) {
+                    self.removed(&ad);
+                }
+                self.insert(topic, enr, ip);
+                return Registration::Confirmed;
+            }
+        }
+
+        // A ticket presented too early is reissued for the rest of its wait time. Invalid and
+        // misappropriated tickets are ignored.
+        let ticket = Ticket::decrypt(&self.ticket_key, ticket)
+            .filter(|ticket| ticket.node_id == node_id && ticket.ip == ip && ticket.topic == topic);
+        let since = ticket.as_ref().map_or(now, |ticket| ticket.waiting_since);
+        if let Some(ticket) = ticket {
+            let due = ticket.issued_at + ticket.wait_time;
+            if now < due {
+                return self.issue_ticket(node_id, ip, topic, since, due - now);
+            }
+        }
+
+        // A registrant that has used up the share of its address waits for one of the
+        // advertisements from it to expire, without a place in line for the topic.
+        let wait_time = self.ip_wait_time(&ip);
+        if !wait_time.is_zero() {
+            return self.issue_ticket(node_id, ip, topic, since, wait_time);
+        }
+
+        let wait_time = self.wait_time(&topic, &node_id, since);
+        if wait_time.is_zero() {
+            if let Some(waiting) = self.waiting.get_mut(&topic) {
+                waiting.remove(&node_id);
+                if waiting.is_empty() {
+                    self.waiting.remove(&topic);
+                }
+            }
+            self.insert(topic, enr, ip);
+            Registration::Confirmed
+        } else {
+            self.wait(topic, node_id, since, now + wait_time);
+            self.issue_ticket(node_id, ip, topic, since, wait_time)
+        }
+    }
+
+    /// Returns a random selection of the nodes advertised for the topic.
+    pub fn ads(&mut self, topic: &TopicHash) -> Vec<Arc<Enr>> {
+        self.prune();
+        self.ads
+            .get(topic)
+            .map(|ads| {
+                ads.iter()
+                    .map(|ad| ad.enr.clone())
+                    .choose_multiple(&mut rand::thread_rng(), MAX_TOPIC_QUERY_RESULTS)
+            })
+            .unwrap_or_default()
+    }
+
+    fn insert(&mut self, topic: TopicHash, enr: Arc<Enr>, ip: IpAddr) {
+        self.ads.entry(topic).or_default().push_back(Ad {
+            enr,
+            ip,
+            expires: self.clock.now() + self.config.ad_lifetime,
+        });
+        self.len += 1;
+        *self.ips.entry(ip).or_default() += 1;
+    }
+
+    /// Accounts for an advertisement taken out of the table.
+    fn removed(&mut self, ad: &Ad) {
+        self.len -= 1;
+        if let Some(count) = self.ips.get_mut(&ad.ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.ips.remove(&ad.ip);
+            }
+        }
+    }
+
+    /// Puts a registrant in line for a slot of the topic, unless as many registrants wait as the
+    /// table holds advertisements.
+    fn wait(&mut self, topic: TopicHash, node_id: NodeId, since: Duration, due: Duration) {
+        let waiting: usize = self.waiting.values().map(HashMap::len).sum();
+        let known = self
+            .waiting
+            .get(&topic)
+            .is_some_and(|waiting| waiting.contains_key(&node_id));
+        if known || waiting < self.config.table_capacity {
+            self.waiting
+                .entry(topic)
+                .or_default()
+                .insert(node_id, Waiting { since, due });
+        }
+    }
+
+    fn issue_ticket(
+        &self,
+        node_id: NodeId,
+        ip: IpAddr,
+        topic: TopicHash,
+        waiting_since: Duration,
+        wait_time: Duration,
+    ) -> Registration {
+        let ticket = Ticket {
+            node_id,
+            ip,
+            topic,
+            waiting_since,
+            issued_at: self.elapsed(),
+            wait_time,
+        };
+        Registration::Ticket {
+            ticket: ticket.encrypt(&self.ticket_key),
+            wait_time,
+        }
+    }
+
+    /// The time until there is room for another advertisement for the topic, for a registrant
+    /// that has been waiting since `since`. Free slots go to the registrants that have been
+    /// waiting longer first.
+    fn wait_time(&self, topic: &TopicHash, node_id: &NodeId, since: Duration) -> Duration {
+        let now = self.clock.now();
+        let until_expiry = |ad: &Ad| ad.expires.saturating_duration_since(now);
+        let topic_ads = self.ads.get(topic);
+        if topic_ads.is_some_and(|ads| ads.len() >= self.config.ads_per_topic) {
+            return topic_ads
+                .and_then(|ads| ads.front())
+                .map(until_expiry)
+                .unwrap_or_default();
+        }
+        if self.len >= self.config.table_capacity {
+            return self
+                .ads
+                .values()
+                .filter_map(|ads| ads.front())
+                .map(until_expiry)
+                .min()
+                .unwrap_or_default();
+        }
+
+        let free = (self.config.ads_per_topic - topic_ads.map_or(0, VecDeque::len))
+            .min(self.config.table_capacity - self.len);
+        let ahead: Vec<Duration> = self
+            .waiting
+            .get(topic)
+            .into_iter()
+            .flatten()
+            .filter(|(id, waiting)| *id != node_id && waiting.since < since)
+            .map(|(_, waiting)| waiting.due + TICKET_GRACE)
+            .collect();
+        if ahead.len() < free {
+            return Duration::ZERO;
+        }
+        // Those ahead have taken the slot or lost their place by the time their ticket lapses.
+        let elapsed = self.elapsed();
+        ahead
+            .into_iter()
+            .min()
+            .map(|lapses| lapses.saturating_sub(elapsed))
+            .unwrap_or_default()
+    }
+
+    /// The time until another advertisement from the IP address may be held.
+    fn ip_wait_time(&self, ip: &IpAddr) -> Duration {
+        if self.ips.get(ip).copied().unwrap_or_default() < self.config.ads_per_ip {
+            return Duration::ZERO;
+        }
+        let now = self.clock.now();
+        self.ads
+            .values()
+            .flatten()
+            .filter(|ad| ad.ip == *ip)
+            .map(|ad| ad.expires.saturating_duration_since(now))
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Removes the expired advertisements and the registrants that lost their place in line.
+    fn prune(&mut self) {
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        self.ads.retain(|_, ads| {
+            while ads.front().is_some_and(|ad| ad.expires <= now) {
+                expired.extend(ads.pop_front());
+            }
+            !ads.is_empty()
+        });
+        for ad in expired {
+            self.removed(&ad);
+        }
+
+        let elapsed = self.elapsed();
+        self.waiting.retain(|_, waiting| {
+            waiting.retain(|_, waiting| waiting.due + TICKET_GRACE > elapsed);
+            !waiting.is_empty()
+        });
+    }
+}
+
+/// The topics we advertise and the state of our registrations with their registrars.
+pub(crate) struct TopicRegistrations {
+    config: TopicConfig,
+    /// The topics with the radius their advertisements are widened to by the tickets of
+    /// registrars, as a log2 distance from the topic hash.
+    topics: HashMap<TopicHash, Option<u64>>,
+    /// The registrars we await the answer of.
+    in_flight: HashMap<(TopicHash, NodeId), Arc<Enr>>,
+    /// The next registration attempt at each registrar, with the ticket to present. The ticket is
+    /// empty when renewing a confirmed registration.
+    scheduled: HashMapDelay<(TopicHash, NodeId), (Arc<Enr>, Vec<u8>)>,
+    /// The topics with a lookup for further registrars under way.
+    lookups: HashSet<TopicHash>,
+}
+
+impl TopicRegistrations {
+    pub fn new(config: TopicConfig) -> Self {
+        TopicRegistrations {
+            config,
+            topics: HashMap::new(),
+            in_flight: HashMap::new(),
+            scheduled: HashMapDelay::default(),
+            lookups: HashSet::new(),
+        }
+    }
+
+    pub fn config(&self) -> &TopicConfig {
+        &self.config
+    }
+
+    /// Starts advertising a topic. Returns false if it is already advertised.
+    pub fn add(&mut self, topic: TopicHash) -> bool {
+        if self.topics.contains_key(&topic) {
+            return false;
+        }
+        self.topics.insert(topic, None);
+        true
+    }
+
+    /// Stops advertising a topic. Existing advertisements are left to expire.
+    pub fn remove(&mut self, topic: &TopicHash) {
+        self.topics.remove(topic);
+        self.lookups.remove(topic);
+        self.in_flight.retain(|(t, _), _| t != topic);
+        let scheduled: Vec<_> = self
+            .scheduled
+            .keys()
+            .filter(|(t, _)| t == topic)
+            .cloned()
+            .collect();
+        for key in scheduled {
+            self.scheduled.remove(&key);
+        }
+    }
+
+    /// Renews the confirmed registrations of the topic right away, so that the registrars hold
+    /// our current ENR.
+    pub fn renew(&mut self, topic: &TopicHash) {
+        let confirmed: Vec<_> = self
+            .scheduled
+            .iter()
+            .filter(|((t, _), (_, ticket))| t == topic && ticket.is_empty())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in confirmed {
+            self.scheduled.update_timeout(&key, Duration::ZERO);
+        }
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &TopicHash> {
+        self.topics.keys()
+    }
+
+    /// The radius the advertisements for the topic are widened to by the tickets of registrars.
+    pub fn radius(&self, topic: &TopicHash) -> Option<u64> {
+        self.topics.get(topic).copied().flatten()
+    }
+
+    /// Whether the node is one of the registrars of the topic.
+    pub fn is_registrar(&self, topic: &TopicHash, node_id: &NodeId) -> bool {
+        let key = (*topic, *node_id);
+        self.in_flight.contains_key(&key) || self.scheduled.contains_key(&key)
+    }
+
+    /// The number of registrars of the topic.
+    pub fn registrar_count(&self, topic: &TopicHash) -> usize {
+        self.in_flight.keys().filter(|(t, _)| t == topic).count()
+            + self.scheduled.keys().filter(|(t, _)| t == topic).count()
+    }
+
+    /// Records the start of a lookup for further registrars of the topic. Returns false if one is
+    /// already under way.
+    pub fn start_lookup(&mut self, topic: TopicHash) -> bool {
+        self.lookups.insert(topic)
+    }
+
+    /// Records the end of a lookup for further registrars of the topic. Returns false if the
+    /// topic is no longer advertised.
+    pub fn lookup_finished(&mut self, topic: &TopicHash) -> bool {
+        self.lookups.remove(topic) && self.topics.contains_key(topic)
+    }
+
+    /// Records a registration attempt sent to a registrar.
+    pub fn sent(&mut self, topic: TopicHash, registrar: Arc<Enr>) {
+        self.in_flight
+            .insert((topic, registrar.node_id()), registrar);
+    }
+
+    /// Records the answer of a registrar. Returns false if the topic is no longer advertised or
+    /// the registrar is dropped.
+    pub fn answered(
+        &mut self,
+        topic: TopicHash,
+        node_id: NodeId,
+        registration: Registration,
+    ) -> bool {
+        let Some(registrar) = self.in_flight.remove(&(topic, node_id)) else {
+            return false;
+        };
+        let Some(radius) = self.topics.get_mut(&topic) else {
+            return false;
+        };
+        let distance = log2_distance(&topic, &node_id);
+        match registration {
+            Registration::Confirmed => {
+                // The registrar had room, so the radius may narrow.
+                if let Some(r) = radius.as_mut() {
+                    if *r > distance {
+                        *r -= 1;
+                    }
+                }
+                // Renew the advertisement just before it expires.
+                self.scheduled.insert_at(
+                    (topic, node_id),
+                    (registrar, Vec::new()),
+                    self.config.ad_lifetime.saturating_sub(RENEWAL_MARGIN),
+                );
+            }
+            Registration::Ticket { ticket, wait_time } => {
+                // The registrar is full, so the advertisements have to reach further.
+                if wait_time > self.config.target_wait {
+                    let widened = (distance + 1).min(256);
+                    *radius = Some(radius.map_or(widened, |r| r.max(widened)));
+                }
+                if wait_time > self.config.ad_lifetime {
+                    return false;
+                }
+                self.scheduled
+                    .insert_at((topic, node_id), (registrar, ticket), wait_time);
+            }
+        }
+        true
+    }
+
+    /// Records a failed registration attempt, so that another registrar takes its place.
+    pub fn failed(&mut self, topic: &TopicHash, node_id: &NodeId) {
+        self.in_flight.remove(&(*topic, *node_id));
+    }
+}
+
+/// Estimates the radius of a topic from the log2 distances of the nodes known around the topic
+/// hash, sorted by distance, as the distance within which `registrars` nodes are found. The radius
+/// is never narrower than the one the topic is `widened` to.
+pub(crate) fn estimate_radius(
+    mut distances: impl Iterator<Item = u64>,
+    registrars: usize,
+    widened: Option<u64>,
+) -> Option<u64> {
+    let estimate = distances.by_ref().take(registrars).last();
+    estimate.max(widened)
+}
+
+/// Picks up to `count` registrars among candidates sorted by their log2 distance to the topic
+/// hash. Without a radius, the closest candidates are picked. Otherwise the picks are spread
+/// evenly over the distances within the radius, starting from the furthest, and any shortfall is
+/// made up with the closest candidates outside the radius.
+pub(crate) fn select_registrars<T>(
+    candidates: impl Iterator<Item = (u64, T)>,
+    radius: Option<u64>,
+    count: usize,
+) -> Vec<T> {
+    let Some(radius) = radius else {
+        return candidates
+            .take(count)
+            .map(|(_, candidate)| candidate)
+            .collect();
+    };
+    let mut within: BTreeMap<u64, VecDeque<T>> = BTreeMap::new();
+    let mut outside = Vec::new();
+    for (distance, candidate) in candidates {
+        if distance <= radius {
+            within.entry(distance).or_default().push_back(candidate);
+        } else if outside.len() < count {
+            outside.push(candidate);
+        }
+    }
+
+    let mut selected = Vec::new();
+    while selected.len() < count && !within.is_empty() {
+        for candidates in within.values_mut().rev() {
+            if selected.len() == count {
+                break;
+            }
+            selected.extend(candidates.pop_front());
+        }
+        within.retain(|_, candidates| !candidates.is_empty());
+    }
+    let shortfall = count - selected.len();
+    selected.extend(outside.into_iter().take(shortfall));
+    selected
+}
+
+/// The log2 distance between a topic hash and a node.
+pub(crate) fn log2_distance(topic: &TopicHash, node_id: &NodeId) -> u64 {
+    kbucket::Key::from(topic.as_node_id())
+        .log2_distance(&kbucket::Key::from(*node_id))
+        .unwrap_or(0)
+}
+
+impl Stream for TopicRegistrations {
+    /// The registration attempts that are due, with the registrar and the ticket to present.
+    type Item = (TopicHash, Arc<Enr>, Vec<u8>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.scheduled.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(((topic, _), (registrar, ticket))))) => {
+                    return Poll::Ready(Some((topic, registrar, ticket)))
+                }
+                Poll::Ready(Some(Err(_))) => continue,
+                // The registrations are never done with, even when none are scheduled.
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::ManualClock;
+    use enr::CombinedKey;
+
+    fn enr() -> Arc<Enr> {
+        let key = CombinedKey::generate_secp256k1();
+        Arc::new(Enr::builder().build(&key).unwrap())
+    }
+
+    #[test]
+    fn full_topic_issues_tickets() {
+        let config = TopicConfig::default();
+        let ads_per_topic = config.ads_per_topic;
+        let ad_lifetime = config.ad_lifetime;
+        let mut table = TopicTable::new(config, Arc::new(ManualClock::default()));
+        let topic = TopicHash::new("topic");
+        for i in 0..ads_per_topic {
+            let ip: IpAddr = [10, 0, 0, i as u8].into();
+            assert_eq!(
+                table.register(topic, enr(), ip, &[]),
+                Registration::Confirmed
+            );
+        }
+        let ip: IpAddr = [192, 0, 2, 1].into();
+        assert_eq!(table.ads(&topic).len(), MAX_TOPIC_QUERY_RESULTS);
+
+        let registrant = enr();
+        let Registration::Ticket { ticket, wait_time } =
+            table.register(topic, registrant.clone(), ip, &[])
+        else {
+            panic!("Expected a ticket");
+        };
+        assert!(wait_time > ad_lifetime - Duration::from_secs(1));
+
+        // Presenting the ticket early reissues it.
+        assert!(matches!(
+            table.register(topic, registrant.clone(), ip, &ticket),
+            Registration::Ticket { .. }
+        ));
+        // The ticket is only valid for the registrant.
+        let decrypted = Ticket::decrypt(&table.ticket_key, &ticket).unwrap();
+        assert_eq!(decrypted.node_id, registrant.node_id());
+        assert!(Ticket::decrypt(&[0; 16], &ticket).is_none());
+    }
+
+    #[test]
+    fn caps_ads_per_ip() {
+        let config = TopicConfig {
+            ads_per_ip: 2,
+            ..Default::default()
+        };
+        let mut table = TopicTable::new(config, Arc::new(ManualClock::default()));
+        let ip: IpAddr = [192, 0, 2, 1].into();
+        for topic in ["a", "b"] {
+            assert_eq!(
+                table.register(TopicHash::new(topic), enr(), ip, &[]),
+                Registration::Confirmed
+            );
+        }
+        // The cap holds across topics, and renewals are not counted twice.
+        let topic = TopicHash::new("c");
+        assert!(matches!(
+            table.register(topic, enr(), ip, &[]),
+            Registration::Ticket { .. }
+        ));
+        let other_ip: IpAddr = [192, 0, 2, 2].into();
+        let registrant = enr();
+        for _ in 0..2 {
+            assert_eq!(
+                table.register(topic, registrant.clone(), other_ip, &[]),
+                Registration::Confirmed
+            );
+        }
+    }
+
+    #[test]
+    fn tickets_grant_priority_by_waiting_time() {
+        let config = TopicConfig {
+            ads_per_topic: 1,
+            ad_lifetime: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let clock = ManualClock::default();
+        let mut table = TopicTable::new(config, Arc::new(clock.clone()));
+        let topic = TopicHash::new("topic");
+        let ip: IpAddr = [192, 0, 2, 1].into();
+        assert_eq!(
+            table.register(topic, enr(), ip, &[]),
+            Registration::Confirmed
+        );
+        let waiting = enr();
+        let Registration::Ticket { ticket, .. } = table.register(topic, waiting.clone(), ip, &[])
+        else {
+            panic!("Expected a ticket");
+        };
+
+        clock.advance(Duration::from_millis(150));
+        // The slot has freed up, but it is held for the registrant that has been waiting.
+        let newcomer = enr();
+        let Registration::Ticket { wait_time, .. } =
+            table.register(topic, newcomer.clone(), ip, &[])
+        else {
+            panic!("Expected a ticket");
+        };
+        assert!(wait_time > Duration::ZERO && wait_time <= TICKET_GRACE);
+        assert_eq!(
+            table.register(topic, waiting, ip, &ticket),
+            Registration::Confirmed
+        );
+    }
+
+    #[test]
+    fn estimates_radius_from_known_nodes() {
+        let distances = [250, 250, 252, 254, 256];
+        assert_eq!(
+            estimate_radius(distances.iter().copied(), 3, None),
+            Some(252)
+        );
+        // Fewer nodes than registrars are known.
+        assert_eq!(
+            estimate_radius(distances.iter().copied(), 8, None),
+            Some(256)
+        );
+        // Tickets widen the radius, but don't narrow it.
+        assert_eq!(
+            estimate_radius(distances.iter().copied(), 3, Some(254)),
+            Some(254)
+        );
+        assert_eq!(
+            estimate_radius(distances.iter().copied(), 3, Some(251)),
+            Some(252)
+        );
+        assert_eq!(estimate_radius(std::iter::empty(), 3, None), None);
+    }
+
+    #[test]
+    fn spreads_registrars_within_radius() {
+        let candidates = [
+            (250, 'a'),
+            (250, 'b'),
+            (252, 'c'),
+            (254, 'd'),
+            (254, 'e'),
+            (256, 'f'),
+        ];
+        // Without a radius the closest are picked.
+        assert_eq!(
+            select_registrars(candidates.iter().copied(), None, 3),
+            vec!['a', 'b', 'c']
+        );
+        // Within the radius, the picks alternate between distances from the furthest.
+        assert_eq!(
+            select_registrars(candidates.iter().copied(), Some(254), 4),
+            vec!['d', 'c', 'a', 'e']
+        );
+        // A shortfall is made up from outside the radius.
+        assert_eq!(
+            select_registrars(candidates.iter().copied(), Some(250), 3),
+            vec!['a', 'b', 'c']
+        );
+    }
+}
@@ -0,0 +1,351 @@
+//! Deterministic simulation of discv5 networks.
+//!
+//! Nodes configured with a [`SimNetwork`] as their transport factory exchange packets in memory
+//! instead of over UDP. With this feature enabled all protocol timers follow tokio's clock, so
+//! running the nodes on a current thread runtime with paused time, as with
+//! `#[tokio::test(start_paused = true)]`, makes timeouts elapse instantly and in a reproducible
+//! order. Scenarios such as churn, partitions and handshake races then run in CI without sleeping
+//! through real timeouts.
+//!
+//! Links can be made lossy, slow, duplicating or reordering with [`LinkConditions`], to exercise
+//! retransmissions and query timeouts. The faults are drawn from a seeded random number generator,
+//! so a scenario plays out the same on every run.
+use crate::socket::{Transport, TransportFactory};
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{io::ReadBuf, sync::mpsc};
+use tracing::trace;
+
+/// A datagram in flight, with the address it was sent from.
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// The faults injected into the packets sent over a link. The default is a perfect link.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkConditions {
+    /// The probability of a packet being dropped.
+    pub loss: f64,
+    /// The minimum time a packet takes to arrive.
+    pub latency: Duration,
+    /// The maximum time added to the latency of a packet, drawn uniformly.
+    pub jitter: Duration,
+    /// The probability of a packet being delivered twice.
+    pub duplication: f64,
+    /// The probability of a packet being held back for up to twice the latency and jitter, so
+    /// that later packets overtake it.
+    pub reordering: f64,
+}
+
+impl LinkConditions {
+    /// The delays after which copies of a packet are delivered. Empty if the packet is lost.
+    fn delays(&self, rng: &mut StdRng) -> Vec<Duration> {
+        if rng.gen_bool(self.loss.clamp(0.0, 1.0)) {
+            return Vec::new();
+        }
+        let copies = if rng.gen_bool(self.duplication.clamp(0.0, 1.0)) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut delay = self.latency + self.jitter.mul_f64(rng.gen::<f64>());
+                if rng.gen_bool(self.reordering.clamp(0.0, 1.0)) {
+                    delay += (self.latency + self.jitter).mul_f64(2.0 * rng.gen::<f64>());
+                }
+                delay
+            })
+            .collect()
+    }
+}
+
+/// An in-memory network connecting the transports bound through it. Clones refer to the same
+/// network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl Default for SimNetwork {
+    fn default() -> Self {
+        Self::with_seed(0)
+    }
+}
+
+struct NetworkState {
+    /// The inbox of each bound address.
+    endpoints: HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>,
+    /// Pairs of addresses between which packets are dropped, stored in both orders.
+    partitions: HashSet<(SocketAddr, SocketAddr)>,
+    /// Addresses whose packets are all dropped.
+    isolated: HashSet<SocketAddr>,
+    /// The conditions of links without conditions of their own.
+    default_conditions: LinkConditions,
+    /// The conditions of individual links, by source and destination.
+    link_conditions: HashMap<(SocketAddr, SocketAddr), LinkConditions>,
+    /// The source of the injected faults.
+    rng: StdRng,
+}
+
+impl SimNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a network whose injected faults are drawn from a generator with the given seed.
+    pub fn with_seed(seed: u64) -> Self {
+        SimNetwork {
+            state: Arc::new(Mutex::new(NetworkState {
+                endpoints: HashMap::new(),
+                partitions: HashSet::new(),
+                isolated: HashSet::new(),
+                default_conditions: LinkConditions::default(),
+                link_conditions: HashMap::new(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// Sets the conditions of all links that have no conditions of their own.
+    pub fn set_default_conditions(&self, conditions: LinkConditions) {
+        self.state.lock().default_conditions = conditions;
+    }
+
+    /// Sets the conditions of the packets sent from `src` to `dst`. The reverse direction is
+    /// unaffected.
+    pub fn set_link_conditions(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        conditions: LinkConditions,
+    ) {
+        self.state
+            .lock()
+            .link_conditions
+            .insert((src, dst), conditions);
+    }
+
+    /// Reverts the link from `src` to `dst` to the default conditions.
+    pub fn clear_link_conditions(&self, src: SocketAddr, dst: SocketAddr) {
+        self.state.lock().link_conditions.remove(&(src, dst));
+    }
+
+    /// Drops all packets between `a` and `b` until the partition is healed.
+    pub fn partition(&self, a: SocketAddr, b: SocketAddr) {
+        let mut state = self.state.lock();
+        state.partitions.insert((a, b));
+        state.partitions.insert((b, a));
+    }
+
+    /// Heals a partition between `a` and `b`.
+    pub fn heal(&self, a: SocketAddr, b: SocketAddr) {
+        let mut state = self.state.lock();
+        state.partitions.remove(&(a, b));
+        state.partitions.remove(&(b, a));
+    }
+
+    /// Drops all packets to and from `addr`, as if the node went offline, until it is
+    /// reconnected.
+    pub fn isolate(&self, addr: SocketAddr) {
+        self.state.lock().isolated.insert(addr);
+    }
+
+    /// Reconnects an isolated address.
+    pub fn reconnect(&self, addr: SocketAddr) {
+        self.state.lock().isolated.remove(&addr);
+    }
+
+    /// Delivers a datagram to the inbox of `dst`, subject to the conditions of the link.
+    fn deliver(&self, src: SocketAddr, dst: SocketAddr, data: Vec<u8>) {
+        let mut state = self.state.lock();
+        if state.isolated.contains(&src)
+            || state.isolated.contains(&dst)
+            || state.partitions.contains(&(src, dst))
+        {
+            trace!(%src, %dst, "Simulated network dropped packet");
+            return;
+        }
+        let Some(inbox) = state.endpoints.get(&dst).cloned() else {
+            return;
+        };
+        let conditions = state
+            .link_conditions
+            .get(&(src, dst))
+            .copied()
+            .unwrap_or(state.default_conditions);
+        let delays = conditions.delays(&mut state.rng);
+        if delays.is_empty() {
+            trace!(%src, %dst, "Simulated network lost packet");
+        }
+        for delay in delays {
+            if delay.is_zero() {
+                let _ = inbox.send((data.clone(), src));
+            } else {
+                let inbox = inbox.clone();
+                let data = data.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = inbox.send((data, src));
+                });
+            }
+        }
+    }
+}
+
+impl TransportFactory for SimNetwork {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Arc<dyn Transport>> {
+        let mut state = self.state.lock();
+        if state
+            .endpoints
+            .get(&addr)
+            .is_some_and(|inbox| !inbox.is_closed())
+        {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (inbox_send, inbox) = mpsc::unbounded_channel();
+        state.endpoints.insert(addr, inbox_send);
+        Ok(Arc::new(SimTransport {
+            local_addr: addr,
+            network: self.clone(),
+            inbox: Mutex::new(inbox),
+        }))
+    }
+}
+
+/// A transport bound to an address of a [`SimNetwork`].
+struct SimTransport {
+    local_addr: SocketAddr,
+    network: SimNetwork,
+    inbox: Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl Transport for SimTransport {
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>> {
+        match self.inbox.lock().poll_recv(cx) {
+            Poll::Ready(Some((data, src))) => {
+                let length = data.len().min(buf.remaining());
+                buf.put_slice(&data[..length]);
+                Poll::Ready(Ok(src))
+            }
+            Poll::Ready(None) => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_send_to(
+        &self,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        self.network.deliver(self.local_addr, target, buf.to_vec());
+        Poll::Ready(Ok(buf.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConfigBuilder, Discv5, Enr, ListenConfig, RequestError};
+    use enr::CombinedKey;
+    use std::{net::Ipv4Addr, time::Duration};
+
+    fn sim_node(network: &SimNetwork, port: u16) -> Discv5 {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(port)
+            .build(&key)
+            .unwrap();
+        let config = ConfigBuilder::new(ListenConfig::Ipv4 {
+            ip: Ipv4Addr::LOCALHOST,
+            port,
+        })
+        .transport_factory(Arc::new(network.clone()))
+        .build();
+        Discv5::new(enr, key, config).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_system_clock_follows_virtual_time() {
+        use crate::time::{Clock, SystemClock};
+
+        // The clock returns standard instants, whether or not the feature is enabled.
+        let started: std::time::Instant = SystemClock.now();
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(SystemClock.now() - started >= Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partition_in_virtual_time() {
+        let network = SimNetwork::new();
+        let mut node_a = sim_node(&network, 9000);
+        let mut node_b = sim_node(&network, 9001);
+        node_a.start().await.unwrap();
+        node_b.start().await.unwrap();
+
+        node_a.send_ping(node_b.local_enr()).await.unwrap();
+
+        network.partition(
+            node_a.local_enr().udp4_socket().unwrap().into(),
+            node_b.local_enr().udp4_socket().unwrap().into(),
+        );
+        let started = tokio::time::Instant::now();
+        assert!(matches!(
+            node_a.send_ping(node_b.local_enr()).await,
+            Err(RequestError::Timeout)
+        ));
+        // The request timed out in virtual time.
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_link_conditions() {
+        let network = SimNetwork::new();
+        let mut node_a = sim_node(&network, 9000);
+        let mut node_b = sim_node(&network, 9001);
+        node_a.start().await.unwrap();
+        node_b.start().await.unwrap();
+        let addr_a = node_a.local_enr().udp4_socket().unwrap().into();
+        let addr_b = node_b.local_enr().udp4_socket().unwrap().into();
+
+        // Duplicated packets don't disrupt the exchange and latency applies on each link.
+        let latency = Duration::from_millis(100);
+        network.set_default_conditions(LinkConditions {
+            latency,
+            duplication: 1.0,
+            ..Default::default()
+        });
+        let started = tokio::time::Instant::now();
+        node_a.send_ping(node_b.local_enr()).await.unwrap();
+        // The handshake takes two round trips.
+        assert!(started.elapsed() >= 4 * latency);
+
+        // A lossy link in one direction is enough to fail requests.
+        network.set_link_conditions(
+            addr_b,
+            addr_a,
+            LinkConditions {
+                loss: 1.0,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            node_a.send_ping(node_b.local_enr()).await,
+            Err(RequestError::Timeout)
+        ));
+        network.clear_link_conditions(addr_b, addr_a);
+        node_a.send_ping(node_b.local_enr()).await.unwrap();
+    }
+}
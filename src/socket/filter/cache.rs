@@ -12,10 +12,8 @@
 //! determined by the `time_window` this can be longer than one second and can be used by metrics
 //! to average results over large values than one second.
 
-use std::{
-    collections::VecDeque,
-    time::{Duration, Instant},
-};
+use crate::time::{self, Instant};
+use std::{collections::VecDeque, time::Duration};
 
 /// The time window that the size of the cache is enforced for. I.e if the size is 5 and
 /// ENFORCED_SIZE_TIME is 1 second, this will allow 5 entries per second. This MUST be less than the
@@ -51,7 +49,7 @@ impl ReceivedPacketCache {
     pub fn reset(&mut self) {
         while let Some(received_at) = self.inner.pop_front() {
             if received_at
-                > Instant::now()
+                > time::now()
                     .checked_sub(Duration::from_secs(self.time_window))
                     .unwrap()
             {
@@ -64,7 +62,7 @@ impl ReceivedPacketCache {
         let mut count = 0;
         for received_at in self.inner.iter().rev() {
             if *received_at
-                > Instant::now()
+                > time::now()
                     .checked_sub(Duration::from_secs(ENFORCED_SIZE_TIME))
                     .unwrap()
             {
@@ -88,7 +86,7 @@ impl ReceivedPacketCache {
             // Reached the target
             false
         } else {
-            self.inner.push_back(Instant::now());
+            self.inner.push_back(time::now());
             self.within_enforced_time += 1;
             true
         }
@@ -1,4 +1,7 @@
 use super::rate_limiter::RateLimiter;
+use crate::{bootnodes::BootnodePermits, time::Clock};
+use parking_lot::RwLock;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct FilterConfig {
@@ -15,4 +18,8 @@ pub struct FilterConfig {
     /// The maximum number of nodes that can be banned by a single IP before that IP gets banned.
     /// The default is 5.
     pub max_bans_per_ip: Option<usize>,
+    /// The clock the expiries of the bans enacted by the filter are measured against.
+    pub clock: Arc<dyn Clock>,
+    /// The bootnodes of the instance, which are let through like those on the permit list.
+    pub bootnode_permits: Arc<RwLock<BootnodePermits>>,
 }
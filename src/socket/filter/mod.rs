@@ -1,15 +1,25 @@
 //! A filter which decides whether to accept/reject incoming UDP packets.
 
-use crate::{discv5::PERMIT_BAN_LIST, metrics::METRICS, node_info::NodeAddress, packet::Packet};
+use crate::time::Clock;
+use crate::{
+    audit::{BanReason, FilterReason, PacketType, SecurityEvent, SecurityEventKind, AUDIT_LOG},
+    bootnodes::BootnodePermits,
+    discv5::PERMIT_BAN_LIST,
+    ipmode::to_ipv4_mapped,
+    metrics::METRICS,
+    node_info::NodeAddress,
+    packet::Packet,
+};
 use cache::ReceivedPacketCache;
 use enr::NodeId;
 use lru::LruCache;
+use parking_lot::RwLock;
 use std::{
-    collections::HashSet,
+    collections::{HashSet, VecDeque},
     net::{IpAddr, SocketAddr},
     num::NonZeroUsize,
-    sync::atomic::Ordering,
-    time::{Duration, Instant},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
 };
 use tracing::{debug, warn};
 
@@ -17,7 +27,7 @@ mod cache;
 mod config;
 pub mod rate_limiter;
 pub use config::FilterConfig;
-use rate_limiter::{LimitKind, RateLimiter};
+use rate_limiter::{LimitKind, RateLimiter, RateLimiterStats};
 
 /// The maximum number of IPs to retain when calculating the number of nodes per IP.
 const KNOWN_ADDRS_SIZE: NonZeroUsize = match NonZeroUsize::new(500) {
@@ -34,6 +44,21 @@ const BANNED_NODES_SIZE: NonZeroUsize = match NonZeroUsize::new(50) {
 /// specified.
 const DEFAULT_PACKETS_PER_SECOND: usize = 20;
 
+/// The number of dropped packets reported in [`FilterStats::recent_drops`].
+const RECENT_DROPS: usize = 32;
+
+/// The state of the filter of unsolicited packets, see [`crate::Discv5::filter_stats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterStats {
+    /// Whether the filter is enabled. A disabled filter only drops packets of banned peers.
+    pub enabled: bool,
+    /// The state of the rate limiter, if configured.
+    pub rate_limiter: Option<RateLimiterStats>,
+    /// The packets most recently dropped by the filter, the most recent first. The event of a
+    /// packet that caused a ban is the ban.
+    pub recent_drops: Vec<SecurityEvent>,
+}
+
 /// The packet filter which decides whether we accept or reject incoming packets.
 pub(crate) struct Filter {
     /// Whether the filter is enabled or not.
@@ -58,6 +83,12 @@ pub(crate) struct Filter {
     /// The maximum number of nodes that can be banned by a single IP before that IP gets banned.
     /// The default is 5.
     pub max_bans_per_ip: Option<usize>,
+    /// The clock the expiries of bans are measured against.
+    clock: Arc<dyn Clock>,
+    /// The bootnodes of the instance, which are let through like those on the permit list.
+    bootnode_permits: Arc<RwLock<BootnodePermits>>,
+    /// The packets most recently dropped, the most recent first.
+    recent_drops: VecDeque<SecurityEvent>,
 }
 
 impl Filter {
@@ -80,18 +111,30 @@ impl Filter {
             ban_duration,
             max_nodes_per_ip: config.max_nodes_per_ip,
             max_bans_per_ip: config.max_bans_per_ip,
+            clock: config.clock,
+            bootnode_permits: config.bootnode_permits,
+            recent_drops: VecDeque::with_capacity(RECENT_DROPS),
         }
     }
 
     /// The first check. This determines if a new UDP packet should be decoded or dropped.
     /// Only unsolicited packets arrive here.
     pub fn initial_pass(&mut self, src: &SocketAddr) -> bool {
-        if PERMIT_BAN_LIST.read().permit_ips.contains(&src.ip()) {
+        if PERMIT_BAN_LIST.read().permit_ips.contains(&src.ip())
+            || self.bootnode_permits.read().ips.contains(&src.ip())
+        {
             return true;
         }
 
-        if PERMIT_BAN_LIST.read().ban_ips.contains_key(&src.ip()) {
+        if PERMIT_BAN_LIST.read().ban_ips().contains_key(&src.ip()) {
             debug!(?src, "Dropped unsolicited packet from banned src");
+            record_filtered(
+                &mut self.recent_drops,
+                FilterReason::BannedIp,
+                src.ip(),
+                None,
+                None,
+            );
             return false;
         }
 
@@ -114,41 +157,89 @@ impl Filter {
         if let Some(rate_limiter) = self.rate_limiter.as_mut() {
             if rate_limiter.allows(&LimitKind::Ip(src.ip())).is_err() {
                 warn!(ip = ?src.ip(), "Banning IP for excessive requests");
-                // Ban the IP address
-                let ban_timeout = self.ban_duration.map(|v| Instant::now() + v);
-                PERMIT_BAN_LIST
-                    .write()
-                    .ban_ips
-                    .insert(src.ip(), ban_timeout);
+                self.ban_ip(src.ip(), BanReason::ExcessiveRequests);
+                return false;
+            }
+
+            // Hosts typically control an entire IPv6 /64 and can rotate addresses within it, so
+            // the prefix is limited as a whole. Addresses are not banned for exceeding it, as
+            // the offending host would simply move on to the next address.
+            let ip_limit = match src.ip() {
+                IpAddr::V4(_) => LimitKind::Ipv4,
+                IpAddr::V6(ip) if to_ipv4_mapped(&ip).is_some() => LimitKind::Ipv4,
+                IpAddr::V6(ip) => {
+                    if rate_limiter.allows(&LimitKind::Ipv6Prefix(ip)).is_err() {
+                        debug!(ip = ?src.ip(), "Dropped unsolicited packet from IPv6 prefix limit");
+                        record_filtered(
+                            &mut self.recent_drops,
+                            FilterReason::Ipv6PrefixLimit,
+                            src.ip(),
+                            None,
+                            None,
+                        );
+                        return false;
+                    }
+                    LimitKind::Ipv6
+                }
+            };
+
+            if rate_limiter.allows(&ip_limit).is_err() {
+                debug!(ip = ?src.ip(), "Dropped unsolicited packet from IP version limit");
+                record_filtered(
+                    &mut self.recent_drops,
+                    FilterReason::IpVersionLimit,
+                    src.ip(),
+                    None,
+                    None,
+                );
                 return false;
             }
 
             if rate_limiter.allows(&LimitKind::Total).is_err() {
                 debug!(ip = ?src.ip(), "Dropped unsolicited packet from RPC limit");
+                record_filtered(
+                    &mut self.recent_drops,
+                    FilterReason::TotalLimit,
+                    src.ip(),
+                    None,
+                    None,
+                );
                 return false;
             }
         }
         true
     }
 
-    pub fn final_pass(&mut self, node_address: &NodeAddress, _packet: &Packet) -> bool {
+    pub fn final_pass(&mut self, node_address: &NodeAddress, packet: &Packet) -> bool {
         if PERMIT_BAN_LIST
             .read()
             .permit_nodes
             .contains(&node_address.node_id)
+            || self
+                .bootnode_permits
+                .read()
+                .nodes
+                .contains(&node_address.node_id)
         {
             return true;
         }
 
         if PERMIT_BAN_LIST
             .read()
-            .ban_nodes
+            .ban_nodes()
             .contains_key(&node_address.node_id)
         {
             debug!(
                 node = %node_address,
                 "Dropped unsolicited packet from banned node_id",
             );
+            record_filtered(
+                &mut self.recent_drops,
+                FilterReason::BannedNode,
+                node_address.socket_addr.ip(),
+                Some(node_address.node_id),
+                Some(packet),
+            );
             return false;
         }
 
@@ -168,11 +259,20 @@ impl Filter {
                 );
 
                 // The node is being banned
-                let ban_timeout = self.ban_duration.map(|v| Instant::now() + v);
+                let ban_timeout = self.ban_duration.map(|v| self.clock.now() + v);
                 PERMIT_BAN_LIST
                     .write()
-                    .ban_nodes
-                    .insert(node_address.node_id, ban_timeout);
+                    .ban_node(node_address.node_id, ban_timeout);
+                record_drop(
+                    &mut self.recent_drops,
+                    SecurityEventKind::NodeBanned {
+                        reason: BanReason::ExcessiveRequests,
+                        duration: self.ban_duration,
+                    },
+                    node_address.socket_addr.ip(),
+                    Some(node_address.node_id),
+                    Some(PacketType::from(&packet.header.kind)),
+                );
 
                 // If we are tracking banned nodes per IP, add to the count. If the count is higher
                 // than our tolerance, ban the IP.
@@ -181,7 +281,7 @@ impl Filter {
                     if let Some(banned_count) = self.banned_nodes.get_mut(&ip) {
                         *banned_count += 1;
                         if *banned_count >= max_bans_per_ip {
-                            PERMIT_BAN_LIST.write().ban_ips.insert(ip, ban_timeout);
+                            self.ban_ip(ip, BanReason::TooManyBannedNodes);
                         }
                     } else {
                         self.banned_nodes.put(ip, 0);
@@ -210,9 +310,7 @@ impl Filter {
 
             if known_nodes >= max_nodes_per_ip {
                 warn!(%ip, "IP has exceeded its node-id limit and is now banned");
-                // The node is being banned
-                let ban_timeout = self.ban_duration.map(|v| Instant::now() + v);
-                PERMIT_BAN_LIST.write().ban_ips.insert(ip, ban_timeout);
+                self.ban_ip(ip, BanReason::TooManyNodes);
                 self.known_addrs.pop(&ip);
                 return false;
             }
@@ -221,9 +319,72 @@ impl Filter {
         true
     }
 
+    /// Bans an IP for the configured ban duration.
+    fn ban_ip(&mut self, ip: IpAddr, reason: BanReason) {
+        let ban_timeout = self.ban_duration.map(|v| self.clock.now() + v);
+        PERMIT_BAN_LIST.write().ban_ip(ip, ban_timeout);
+        record_drop(
+            &mut self.recent_drops,
+            SecurityEventKind::IpBanned {
+                reason,
+                duration: self.ban_duration,
+            },
+            ip,
+            None,
+            None,
+        );
+    }
+
+    /// The state of the filter.
+    pub fn stats(&self) -> FilterStats {
+        FilterStats {
+            enabled: self.enabled,
+            rate_limiter: self.rate_limiter.as_ref().map(RateLimiter::stats),
+            recent_drops: self.recent_drops.iter().cloned().collect(),
+        }
+    }
+
     pub fn prune_limiter(&mut self) {
         if let Some(rate_limiter) = self.rate_limiter.as_mut() {
             rate_limiter.prune();
         }
     }
 }
+
+/// Reports a packet dropped by the filter to the audit log.
+fn record_filtered(
+    recent_drops: &mut VecDeque<SecurityEvent>,
+    reason: FilterReason,
+    ip: IpAddr,
+    node_id: Option<NodeId>,
+    packet: Option<&Packet>,
+) {
+    record_drop(
+        recent_drops,
+        SecurityEventKind::PacketFiltered(reason),
+        ip,
+        node_id,
+        packet.map(|packet| PacketType::from(&packet.header.kind)),
+    );
+}
+
+/// Reports the event of a dropped packet to the audit log, and keeps it among the recent drops.
+fn record_drop(
+    recent_drops: &mut VecDeque<SecurityEvent>,
+    kind: SecurityEventKind,
+    ip: IpAddr,
+    node_id: Option<NodeId>,
+    packet_type: Option<PacketType>,
+) {
+    AUDIT_LOG.record(kind.clone(), Some(ip), node_id, packet_type);
+    if recent_drops.len() == RECENT_DROPS {
+        recent_drops.pop_back();
+    }
+    recent_drops.push_front(SecurityEvent {
+        timestamp: SystemTime::now(),
+        kind,
+        ip: Some(ip),
+        node_id,
+        packet_type,
+    });
+}
@@ -1,10 +1,11 @@
+use crate::time::{self, Instant};
 use enr::NodeId;
 use fnv::FnvHashMap;
 use std::{
     convert::TryInto,
     hash::Hash,
-    net::IpAddr,
-    time::{Duration, Instant},
+    net::{IpAddr, Ipv6Addr},
+    time::Duration,
 };
 
 /// Nanoseconds since a given time.
@@ -13,6 +14,9 @@ use std::{
 //       most <init time> + u64::MAX nanosecs, ~500 years. So it is realistic to assume this is fine.
 type Nanosecs = u64;
 
+/// The number of senders reported in [`RateLimiterStats::top_talkers`].
+const TOP_TALKERS: usize = 10;
+
 /// User-friendly rate limiting parameters of the GCRA.
 ///
 /// A quota of `max_tokens` tokens every `replenish_all_every` units of time means that:
@@ -53,6 +57,16 @@ pub struct Quota {
     max_tokens: u64,
 }
 
+impl Quota {
+    /// Allow `n` tokens to be used every `time_period`.
+    pub fn n_every(n: u64, time_period: Duration) -> Self {
+        Quota {
+            replenish_all_every: time_period,
+            max_tokens: n,
+        }
+    }
+}
+
 /// Manages rate limiting of requests per peer, with differentiated rates per protocol.
 #[derive(Debug, Clone)]
 pub struct RateLimiter {
@@ -67,6 +81,12 @@ pub struct RateLimiter {
     node_rl: Option<Limiter<NodeId>>,
     /// Rate limit for each ip.
     ip_rl: Option<Limiter<IpAddr>>,
+    /// Rate limit for all IPv4 traffic.
+    ipv4_rl: Option<Limiter<()>>,
+    /// Rate limit for all IPv6 traffic.
+    ipv6_rl: Option<Limiter<()>>,
+    /// Rate limit for each IPv6 /64 prefix, keyed by the upper 64 bits of the address.
+    ipv6_prefix_rl: Option<Limiter<u64>>,
 }
 
 /// Error type for non conformant requests
@@ -77,6 +97,38 @@ pub enum RateLimitedErr {
     TooSoon(Duration),
 }
 
+/// The tokens of a rate limit that can be used right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitBudget {
+    /// The tokens left, each of which admits a packet.
+    pub remaining: u64,
+    /// The tokens of a full budget.
+    pub capacity: u64,
+}
+
+/// A sender with a rate limit of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Talker {
+    NodeId(NodeId),
+    Ip(IpAddr),
+    /// An IPv6 /64 prefix, as its first address.
+    Ipv6Prefix(Ipv6Addr),
+}
+
+/// The state of a [`RateLimiter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimiterStats {
+    /// The budget of the total limit.
+    pub total: LimitBudget,
+    /// The budget of the IPv4 limit, if set.
+    pub ipv4: Option<LimitBudget>,
+    /// The budget of the IPv6 limit, if set.
+    pub ipv6: Option<LimitBudget>,
+    /// The senders with the smallest share of their budget left, the most limited first. Only
+    /// senders that used part of their budget are included.
+    pub top_talkers: Vec<(Talker, LimitBudget)>,
+}
+
 pub enum LimitKind {
     /// Request counts towards the total limit.
     Total,
@@ -84,13 +136,23 @@ pub enum LimitKind {
     NodeId(NodeId),
     /// Request counts toward the ip limit.
     Ip(IpAddr),
+    /// Request counts toward the IPv4 limit.
+    Ipv4,
+    /// Request counts toward the IPv6 limit.
+    Ipv6,
+    /// Request counts toward the limit of the /64 prefix of the address.
+    Ipv6Prefix(Ipv6Addr),
 }
 
-/// User-friendly builder of a `RateLimiter`. The user can specify three kinds of rate limits but
-/// must at least set the total quota. The three types are:
+/// User-friendly builder of a `RateLimiter`. The user can specify six kinds of rate limits but
+/// must at least set the total quota. The six types are:
 /// 1. Total Quota - Specifies the total number of inbound requests. This must be set.
 /// 2. Node Quota - Specifies the number of requests per node id.
 /// 3. IP Quota - Specifies the number of requests per IP.
+/// 4. IPv4 Quota - Specifies the total number of inbound requests over IPv4.
+/// 5. IPv6 Quota - Specifies the total number of inbound requests over IPv6.
+/// 6. IPv6 Prefix Quota - Specifies the number of requests per IPv6 /64 prefix. A single host
+///    usually controls a whole /64, so it can evade the IP quota by rotating addresses within it.
 ///
 /// Quotas can be set via the X_one_every() functions to set hard limits as described above. Using
 /// the `X_n_every()` functions allow for bursts.
@@ -102,6 +164,12 @@ pub struct RateLimiterBuilder {
     node_quota: Option<Quota>,
     /// Quota for each IP.
     ip_quota: Option<Quota>,
+    /// Quota for IPv4 RPCs.
+    ipv4_quota: Option<Quota>,
+    /// Quota for IPv6 RPCs.
+    ipv6_quota: Option<Quota>,
+    /// Quota for each IPv6 /64 prefix.
+    ipv6_prefix_quota: Option<Quota>,
 }
 
 #[allow(dead_code)]
@@ -129,6 +197,24 @@ impl RateLimiterBuilder {
         self
     }
 
+    /// Set the IPv4 quota.
+    fn ipv4_quota(mut self, quota: Quota) -> Self {
+        self.ipv4_quota = Some(quota);
+        self
+    }
+
+    /// Set the IPv6 quota.
+    fn ipv6_quota(mut self, quota: Quota) -> Self {
+        self.ipv6_quota = Some(quota);
+        self
+    }
+
+    /// Set the IPv6 /64 prefix quota.
+    fn ipv6_prefix_quota(mut self, quota: Quota) -> Self {
+        self.ipv6_prefix_quota = Some(quota);
+        self
+    }
+
     /// Allow one token every `time_period` to be used for the total RPC limit.
     /// This produces a hard limit.
     pub fn total_one_every(self, time_period: Duration) -> Self {
@@ -180,6 +266,57 @@ impl RateLimiterBuilder {
         })
     }
 
+    /// Allow one token every `time_period` to be used for the RPC limit over IPv4.
+    /// This produces a hard limit.
+    pub fn ipv4_one_every(self, time_period: Duration) -> Self {
+        self.ipv4_quota(Quota {
+            replenish_all_every: time_period,
+            max_tokens: 1,
+        })
+    }
+
+    /// Allow one token every `time_period` to be used for the RPC limit over IPv6.
+    /// This produces a hard limit.
+    pub fn ipv6_one_every(self, time_period: Duration) -> Self {
+        self.ipv6_quota(Quota {
+            replenish_all_every: time_period,
+            max_tokens: 1,
+        })
+    }
+
+    /// Allow one token every `time_period` to be used for the RPC limit per IPv6 /64 prefix.
+    /// This produces a hard limit.
+    pub fn ipv6_prefix_one_every(self, time_period: Duration) -> Self {
+        self.ipv6_prefix_quota(Quota {
+            replenish_all_every: time_period,
+            max_tokens: 1,
+        })
+    }
+
+    /// Allow `n` tokens to be used every `time_period` for the RPC limit over IPv4.
+    pub fn ipv4_n_every(self, n: u64, time_period: Duration) -> Self {
+        self.ipv4_quota(Quota {
+            replenish_all_every: time_period,
+            max_tokens: n,
+        })
+    }
+
+    /// Allow `n` tokens to be used every `time_period` for the RPC limit over IPv6.
+    pub fn ipv6_n_every(self, n: u64, time_period: Duration) -> Self {
+        self.ipv6_quota(Quota {
+            replenish_all_every: time_period,
+            max_tokens: n,
+        })
+    }
+
+    /// Allow `n` tokens to be used every `time_period` for the RPC limit per IPv6 /64 prefix.
+    pub fn ipv6_prefix_n_every(self, n: u64, time_period: Duration) -> Self {
+        self.ipv6_prefix_quota(Quota {
+            replenish_all_every: time_period,
+            max_tokens: n,
+        })
+    }
+
     pub fn build(self) -> Result<RateLimiter, &'static str> {
         // get our quotas
         let total_quota = self
@@ -196,6 +333,18 @@ impl RateLimiterBuilder {
             Some(q) => Some(Limiter::from_quota(q)?),
             None => None,
         };
+        let ipv4_rl = match self.ipv4_quota {
+            Some(q) => Some(Limiter::from_quota(q)?),
+            None => None,
+        };
+        let ipv6_rl = match self.ipv6_quota {
+            Some(q) => Some(Limiter::from_quota(q)?),
+            None => None,
+        };
+        let ipv6_prefix_rl = match self.ipv6_prefix_quota {
+            Some(q) => Some(Limiter::from_quota(q)?),
+            None => None,
+        };
 
         let total_requests_per_second = if total_quota.max_tokens == 1 {
             (1.0 / total_quota.replenish_all_every.as_secs_f32()
@@ -213,7 +362,10 @@ impl RateLimiterBuilder {
             total_rl,
             node_rl,
             ip_rl,
-            init_time: Instant::now(),
+            ipv4_rl,
+            ipv6_rl,
+            ipv6_prefix_rl,
+            init_time: time::now(),
         })
     }
 }
@@ -221,7 +373,7 @@ impl RateLimiterBuilder {
 impl RateLimiter {
     /// Indicates whether the request is allowed based on the configured rate limits.
     pub fn allows(&mut self, request: &LimitKind) -> Result<(), RateLimitedErr> {
-        let time_since_start = self.init_time.elapsed();
+        let time_since_start = time::now().saturating_duration_since(self.init_time);
         let tokens = 1; // Only count each of these as one.
 
         // Check the limits
@@ -241,6 +393,77 @@ impl RateLimiter {
                     Ok(())
                 }
             }
+            LimitKind::Ipv4 => {
+                if let Some(limiter) = self.ipv4_rl.as_mut() {
+                    limiter.allows(time_since_start, &(), tokens)
+                } else {
+                    Ok(())
+                }
+            }
+            LimitKind::Ipv6 => {
+                if let Some(limiter) = self.ipv6_rl.as_mut() {
+                    limiter.allows(time_since_start, &(), tokens)
+                } else {
+                    Ok(())
+                }
+            }
+            LimitKind::Ipv6Prefix(ip_addr) => {
+                if let Some(limiter) = self.ipv6_prefix_rl.as_mut() {
+                    let prefix = (u128::from(*ip_addr) >> 64) as u64;
+                    limiter.allows(time_since_start, &prefix, tokens)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// The budgets left of the limits, and the senders closest to their own limits.
+    pub fn stats(&self) -> RateLimiterStats {
+        let time_since_start = time::now().saturating_duration_since(self.init_time);
+        let mut top_talkers: Vec<(Talker, LimitBudget)> = Vec::new();
+        if let Some(limiter) = self.node_rl.as_ref() {
+            top_talkers.extend(
+                limiter
+                    .used_budgets(time_since_start)
+                    .map(|(node_id, budget)| (Talker::NodeId(node_id), budget)),
+            );
+        }
+        if let Some(limiter) = self.ip_rl.as_ref() {
+            top_talkers.extend(
+                limiter
+                    .used_budgets(time_since_start)
+                    .map(|(ip, budget)| (Talker::Ip(ip), budget)),
+            );
+        }
+        if let Some(limiter) = self.ipv6_prefix_rl.as_ref() {
+            top_talkers.extend(
+                limiter
+                    .used_budgets(time_since_start)
+                    .map(|(prefix, budget)| {
+                        let prefix = Ipv6Addr::from(u128::from(prefix) << 64);
+                        (Talker::Ipv6Prefix(prefix), budget)
+                    }),
+            );
+        }
+        // Compares the shares of the budgets left without rounding.
+        top_talkers.sort_by(|(_, a), (_, b)| {
+            (u128::from(a.remaining) * u128::from(b.capacity))
+                .cmp(&(u128::from(b.remaining) * u128::from(a.capacity)))
+        });
+        top_talkers.truncate(TOP_TALKERS);
+
+        RateLimiterStats {
+            total: self.total_rl.budget(time_since_start, &()),
+            ipv4: self
+                .ipv4_rl
+                .as_ref()
+                .map(|limiter| limiter.budget(time_since_start, &())),
+            ipv6: self
+                .ipv6_rl
+                .as_ref()
+                .map(|limiter| limiter.budget(time_since_start, &())),
+            top_talkers,
         }
     }
 
@@ -251,7 +474,7 @@ impl RateLimiter {
 
     /// Prunes excess entries. Should be called regularly (30 seconds) to remove old entries.
     pub fn prune(&mut self) {
-        let time_since_start = self.init_time.elapsed();
+        let time_since_start = time::now().saturating_duration_since(self.init_time);
         self.total_rl.prune(time_since_start);
         if let Some(v) = self.ip_rl.as_mut() {
             v.prune(time_since_start)
@@ -259,6 +482,15 @@ impl RateLimiter {
         if let Some(v) = self.node_rl.as_mut() {
             v.prune(time_since_start)
         };
+        if let Some(v) = self.ipv4_rl.as_mut() {
+            v.prune(time_since_start)
+        };
+        if let Some(v) = self.ipv6_rl.as_mut() {
+            v.prune(time_since_start)
+        };
+        if let Some(v) = self.ipv6_prefix_rl.as_mut() {
+            v.prune(time_since_start)
+        };
     }
 }
 
@@ -333,6 +565,33 @@ impl<Key: Hash + Eq + Clone> Limiter<Key> {
         }
     }
 
+    /// The budget left to the key. Keys without a bucket have a full budget.
+    pub fn budget(&self, time_since_start: Duration, key: &Key) -> LimitBudget {
+        let now = time_since_start.as_nanos() as u64;
+        self.budget_at(now, self.tat_per_key.get(key))
+    }
+
+    /// The budgets of the keys that used part of theirs.
+    fn used_budgets(
+        &self,
+        time_since_start: Duration,
+    ) -> impl Iterator<Item = (Key, LimitBudget)> + '_ {
+        let now = time_since_start.as_nanos() as u64;
+        self.tat_per_key
+            .iter()
+            .filter(move |(_, tat)| **tat > now)
+            .map(move |(key, tat)| (key.clone(), self.budget_at(now, Some(tat))))
+    }
+
+    fn budget_at(&self, now: Nanosecs, tat: Option<&Nanosecs>) -> LimitBudget {
+        // The bucket is full again at the TAT, replenishing one token every `t` until then.
+        let pending = tat.map_or(0, |tat| tat.saturating_sub(now));
+        LimitBudget {
+            remaining: self.tau.saturating_sub(pending) / self.t,
+            capacity: self.tau / self.t,
+        }
+    }
+
     /// Removes keys for which their bucket is full by `time_limit`
     pub fn prune(&mut self, time_limit: Duration) {
         let lim = &mut (time_limit.as_nanos() as u64);
@@ -343,8 +602,8 @@ impl<Key: Hash + Eq + Clone> Limiter<Key> {
 
 #[cfg(test)]
 mod tests {
-    use super::{Limiter, Quota};
-    use std::time::Duration;
+    use super::{LimitBudget, LimitKind, Limiter, Quota, RateLimiterBuilder, Talker};
+    use std::{net::Ipv6Addr, time::Duration};
 
     #[test]
     fn it_works_a() {
@@ -411,4 +670,67 @@ mod tests {
             .allows(Duration::from_secs_f32(0.4), &key, 1)
             .is_err());
     }
+
+    #[test]
+    fn ipv6_prefix_limit() {
+        let mut limiter = RateLimiterBuilder::new()
+            .total_n_every(100, Duration::from_secs(1))
+            .ip_n_every(2, Duration::from_secs(60))
+            .ipv6_prefix_n_every(2, Duration::from_secs(60))
+            .build()
+            .unwrap();
+
+        // Rotating addresses within a /64 evades the IP limit, but not the prefix limit
+        let prefix_allows = |limiter: &mut super::RateLimiter, ip: &str| {
+            let ip: Ipv6Addr = ip.parse().unwrap();
+            limiter.allows(&LimitKind::Ip(ip.into())).is_ok()
+                && limiter.allows(&LimitKind::Ipv6Prefix(ip)).is_ok()
+        };
+        assert!(prefix_allows(&mut limiter, "2001:db8::1"));
+        assert!(prefix_allows(&mut limiter, "2001:db8::2"));
+        assert!(!prefix_allows(&mut limiter, "2001:db8::3"));
+        assert!(prefix_allows(&mut limiter, "2001:db8:0:1::1"));
+
+        // No IPv4 limit is set
+        for _ in 0..10 {
+            assert!(limiter.allows(&LimitKind::Ipv4).is_ok());
+        }
+    }
+
+    #[test]
+    fn budgets_and_top_talkers() {
+        let mut limiter = Limiter::from_quota(Quota::n_every(4, Duration::from_secs(2))).unwrap();
+        let key = 10;
+        let budget = |remaining| LimitBudget {
+            remaining,
+            capacity: 4,
+        };
+        assert_eq!(limiter.budget(Duration::ZERO, &key), budget(4));
+        assert!(limiter.allows(Duration::ZERO, &key, 3).is_ok());
+        assert_eq!(limiter.budget(Duration::ZERO, &key), budget(1));
+        // A token is replenished every half second.
+        assert_eq!(limiter.budget(Duration::from_millis(600), &key), budget(2));
+        assert_eq!(limiter.budget(Duration::from_secs(2), &key), budget(4));
+
+        let mut limiter = RateLimiterBuilder::new()
+            .total_n_every(100, Duration::from_secs(60))
+            .ip_n_every(4, Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let [quiet, busy] = ["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()];
+        for ip in [quiet, busy, busy, busy] {
+            assert!(limiter.allows(&LimitKind::Ip(ip)).is_ok());
+            assert!(limiter.allows(&LimitKind::Total).is_ok());
+        }
+        let stats = limiter.stats();
+        assert_eq!(stats.total.remaining, 96);
+        assert_eq!(stats.ipv4, None);
+        assert_eq!(
+            stats.top_talkers,
+            vec![
+                (Talker::Ip(busy), budget(1)),
+                (Talker::Ip(quiet), budget(3))
+            ]
+        );
+    }
 }
@@ -5,7 +5,7 @@ use send::*;
 use socket2::{Domain, Protocol, Socket as Socket2, Type};
 use std::{
     collections::HashMap,
-    io::Error,
+    io::{Error, ErrorKind},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::Arc,
     time::Duration,
@@ -18,13 +18,18 @@ use tokio::{
 mod filter;
 mod recv;
 mod send;
+#[cfg(target_os = "linux")]
+mod timestamp;
+mod transport;
 
+pub(crate) use filter::rate_limiter::{Limiter, Quota};
 pub use filter::{
-    rate_limiter::{RateLimiter, RateLimiterBuilder},
-    FilterConfig,
+    rate_limiter::{LimitBudget, RateLimiter, RateLimiterBuilder, RateLimiterStats, Talker},
+    FilterConfig, FilterStats,
 };
 pub use recv::InboundPacket;
 pub use send::OutboundPacket;
+pub use transport::{Transport, TransportFactory};
 
 /// Configuration for the sockets to listen on.
 ///
@@ -45,6 +50,13 @@ pub enum ListenConfig {
         ipv6: Ipv6Addr,
         ipv6_port: u16,
     },
+    /// Sockets opened before discv5 is started, such as those passed by systemd socket activation
+    /// or a supervisor, used instead of binding. At least one socket is set. See
+    /// [`ListenConfig::from_std_sockets`].
+    Sockets {
+        ipv4: Option<Arc<std::net::UdpSocket>>,
+        ipv6: Option<Arc<std::net::UdpSocket>>,
+    },
 }
 
 /// Convenience objects for setting up the recv handler.
@@ -59,14 +71,43 @@ pub struct SocketConfig {
     pub ban_duration: Option<Duration>,
     /// The expected responses reference.
     pub expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
-    /// The local node id used to decrypt messages.
-    pub local_node_id: enr::NodeId,
+    /// The local node ids used to decrypt messages, shared with the handler which updates them
+    /// when the identity is rotated.
+    pub local_node_ids: Arc<RwLock<LocalNodeIds>>,
+    /// Creates the transports in place of UDP sockets, if set.
+    pub transport_factory: Option<Arc<dyn TransportFactory>>,
+    /// The number of decoded packets buffered for the handler.
+    pub inbound_queue_capacity: usize,
+    /// Drops packets from and refuses to send to IPv4 and IPv4-mapped addresses.
+    pub ipv6_only: bool,
+}
+
+/// The node ids inbound packets are addressed to. Packets are decoded for the current identity
+/// and, during the grace period of an identity rotation, for the retired one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalNodeIds {
+    /// The node id of the current identity.
+    pub current: enr::NodeId,
+    /// The node id of the identity retired by the last rotation, while it is still accepted.
+    pub retired: Option<enr::NodeId>,
+}
+
+impl LocalNodeIds {
+    /// The node ids of a node that has not rotated its identity.
+    pub fn new(current: enr::NodeId) -> Self {
+        LocalNodeIds {
+            current,
+            retired: None,
+        }
+    }
 }
 
 /// Creates the UDP socket and handles the exit futures for the send/recv UDP handlers.
 pub struct Socket {
     pub send: mpsc::Sender<OutboundPacket>,
     pub recv: mpsc::Receiver<InboundPacket>,
+    /// Requests the state of the filter from the recv handler.
+    pub filter_stats: mpsc::Sender<oneshot::Sender<FilterStats>>,
     sender_exit: Option<oneshot::Sender<()>>,
     recv_exit: Option<oneshot::Sender<()>>,
 }
@@ -75,15 +116,45 @@ impl Socket {
     /// This creates and binds a new UDP socket.
     // In general this function can be expanded to handle more advanced socket creation.
     async fn new_socket(socket_addr: &SocketAddr) -> Result<UdpSocket, Error> {
-        match socket_addr {
-            SocketAddr::V4(ip4) => UdpSocket::bind(ip4).await,
+        let socket = match socket_addr {
+            SocketAddr::V4(ip4) => UdpSocket::bind(ip4).await?,
             SocketAddr::V6(ip6) => {
                 let socket = Socket2::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
                 socket.set_only_v6(true)?;
                 socket.set_nonblocking(true)?;
                 socket.bind(&SocketAddr::V6(*ip6).into())?;
-                UdpSocket::from_std(socket.into())
+                UdpSocket::from_std(socket.into())?
             }
+        };
+        Ok(Socket::enable_timestamps(socket))
+    }
+
+    /// Takes over a socket opened outside of discv5. The socket is duplicated, so that the
+    /// listen config can be reused.
+    fn adopt_socket(socket: &std::net::UdpSocket) -> Result<Arc<dyn Transport>, Error> {
+        let socket = socket.try_clone()?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+        Ok(Arc::new(Socket::enable_timestamps(socket)))
+    }
+
+    fn enable_timestamps(socket: UdpSocket) -> UdpSocket {
+        // Round trip times fall back to userspace receive times where this is not supported.
+        #[cfg(target_os = "linux")]
+        if let Err(e) = timestamp::enable(&socket) {
+            tracing::debug!(error = %e, "Kernel receive timestamps unavailable");
+        }
+        socket
+    }
+
+    /// Binds a transport to the local address, a UDP socket unless a transport factory is set.
+    async fn bind(
+        transport_factory: Option<&Arc<dyn TransportFactory>>,
+        socket_addr: SocketAddr,
+    ) -> Result<Arc<dyn Transport>, Error> {
+        match transport_factory {
+            Some(transport_factory) => transport_factory.bind(socket_addr),
+            None => Ok(Arc::new(Socket::new_socket(&socket_addr).await?)),
         }
     }
 
@@ -97,22 +168,26 @@ impl Socket {
             listen_config,
             ban_duration,
             expected_responses,
-            local_node_id,
+            local_node_ids,
+            transport_factory,
+            inbound_queue_capacity,
+            ipv6_only,
         } = config;
+        let transport_factory = transport_factory.as_ref();
 
         // For recv socket, intentionally forgetting which socket is the ipv4 and which is the ipv6 one.
         let (first_recv, second_recv, send_ipv4, send_ipv6): (
-            Arc<UdpSocket>,
+            Arc<dyn Transport>,
             Option<_>,
             Option<_>,
             Option<_>,
         ) = match listen_config {
             ListenConfig::Ipv4 { ip, port } => {
-                let ipv4_socket = Arc::new(Socket::new_socket(&(ip, port).into()).await?);
+                let ipv4_socket = Socket::bind(transport_factory, (ip, port).into()).await?;
                 (ipv4_socket.clone(), None, Some(ipv4_socket), None)
             }
             ListenConfig::Ipv6 { ip, port } => {
-                let ipv6_socket = Arc::new(Socket::new_socket(&(ip, port).into()).await?);
+                let ipv6_socket = Socket::bind(transport_factory, (ip, port).into()).await?;
                 (ipv6_socket.clone(), None, None, Some(ipv6_socket))
             }
             ListenConfig::DualStack {
@@ -121,8 +196,8 @@ impl Socket {
                 ipv6,
                 ipv6_port,
             } => {
-                let ipv4_socket = Arc::new(Socket::new_socket(&(ipv4, ipv4_port).into()).await?);
-                let ipv6_socket = Arc::new(Socket::new_socket(&(ipv6, ipv6_port).into()).await?);
+                let ipv4_socket = Socket::bind(transport_factory, (ipv4, ipv4_port).into()).await?;
+                let ipv6_socket = Socket::bind(transport_factory, (ipv6, ipv6_port).into()).await?;
                 (
                     ipv4_socket.clone(),
                     Some(ipv6_socket.clone()),
@@ -130,6 +205,27 @@ impl Socket {
                     Some(ipv6_socket),
                 )
             }
+            ListenConfig::Sockets { ipv4, ipv6 } => {
+                let ipv4_socket = ipv4.as_deref().map(Socket::adopt_socket).transpose()?;
+                let ipv6_socket = ipv6.as_deref().map(Socket::adopt_socket).transpose()?;
+                match (ipv4_socket, ipv6_socket) {
+                    (Some(ipv4_socket), ipv6_socket) => (
+                        ipv4_socket.clone(),
+                        ipv6_socket.clone(),
+                        Some(ipv4_socket),
+                        ipv6_socket,
+                    ),
+                    (None, Some(ipv6_socket)) => {
+                        (ipv6_socket.clone(), None, None, Some(ipv6_socket))
+                    }
+                    (None, None) => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "No socket to listen on",
+                        ))
+                    }
+                }
+            }
         };
 
         // spawn the recv handler
@@ -138,18 +234,22 @@ impl Socket {
             executor: executor.clone(),
             recv: first_recv,
             second_recv,
-            local_node_id,
+            local_node_ids,
             expected_responses,
             ban_duration,
+            inbound_queue_capacity,
+            ipv6_only,
         };
 
-        let (recv, recv_exit) = RecvHandler::spawn::<P>(recv_config);
+        let (recv, filter_stats, recv_exit) = RecvHandler::spawn::<P>(recv_config);
         // spawn the sender handler
-        let (send, sender_exit) = SendHandler::spawn::<P>(executor, send_ipv4, send_ipv6);
+        let (send, sender_exit) =
+            SendHandler::spawn::<P>(executor, send_ipv4, send_ipv6, ipv6_only);
 
         Ok(Socket {
             send,
             recv,
+            filter_stats,
             sender_exit: Some(sender_exit),
             recv_exit: Some(recv_exit),
         })
@@ -192,12 +292,99 @@ impl ListenConfig {
         }
     }
 
+    /// Creates a [`ListenConfig`] from sockets opened outside of discv5, at most one per IP
+    /// version. This lets deployments bind privileged ports or sandbox the process and hand the
+    /// sockets over, instead of having discv5 bind them.
+    pub fn from_std_sockets(
+        sockets: impl IntoIterator<Item = std::net::UdpSocket>,
+    ) -> Result<ListenConfig, Error> {
+        let mut ipv4 = None;
+        let mut ipv6 = None;
+        for socket in sockets {
+            let slot = match socket.local_addr()? {
+                SocketAddr::V4(_) => &mut ipv4,
+                SocketAddr::V6(_) => &mut ipv6,
+            };
+            if slot.replace(Arc::new(socket)).is_some() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "More than one socket per IP version",
+                ));
+            }
+        }
+        if ipv4.is_none() && ipv6.is_none() {
+            return Err(Error::new(ErrorKind::InvalidInput, "No socket given"));
+        }
+        Ok(ListenConfig::Sockets { ipv4, ipv6 })
+    }
+
+    /// Creates a [`ListenConfig`] from the UDP sockets passed by systemd socket activation,
+    /// following the `LISTEN_PID` and `LISTEN_FDS` protocol of `sd_listen_fds`.
+    #[cfg(unix)]
+    pub fn from_systemd() -> Result<ListenConfig, Error> {
+        use std::os::unix::io::FromRawFd;
+        /// The first file descriptor passed by systemd.
+        const SD_LISTEN_FDS_START: i32 = 3;
+
+        let not_activated = || Error::new(ErrorKind::NotFound, "No sockets passed by systemd");
+        let listen_pid: u32 = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse().ok())
+            .ok_or_else(not_activated)?;
+        if listen_pid != std::process::id() {
+            return Err(not_activated());
+        }
+        let listen_fds: i32 = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|fds| fds.parse().ok())
+            .ok_or_else(not_activated)?;
+        // SAFETY: systemd passes the sockets as the descriptors following stderr, and hands their
+        // ownership to this process.
+        let sockets = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + listen_fds)
+            .map(|fd| unsafe { std::net::UdpSocket::from_raw_fd(fd) });
+        ListenConfig::from_std_sockets(sockets)
+    }
+
+    /// The local addresses of the configured sockets.
+    pub(crate) fn socket_addrs(&self) -> (Option<SocketAddrV4>, Option<SocketAddrV6>) {
+        match self {
+            ListenConfig::Ipv4 { ip, port } => (Some(SocketAddrV4::new(*ip, *port)), None),
+            ListenConfig::Ipv6 { ip, port } => (None, Some(SocketAddrV6::new(*ip, *port, 0, 0))),
+            ListenConfig::DualStack {
+                ipv4,
+                ipv4_port,
+                ipv6,
+                ipv6_port,
+            } => (
+                Some(SocketAddrV4::new(*ipv4, *ipv4_port)),
+                Some(SocketAddrV6::new(*ipv6, *ipv6_port, 0, 0)),
+            ),
+            ListenConfig::Sockets { ipv4, ipv6 } => {
+                let local_addr = |socket: &Option<Arc<std::net::UdpSocket>>| {
+                    socket.as_ref().and_then(|socket| socket.local_addr().ok())
+                };
+                let ipv4 = match local_addr(ipv4) {
+                    Some(SocketAddr::V4(addr)) => Some(addr),
+                    _ => None,
+                };
+                let ipv6 = match local_addr(ipv6) {
+                    Some(SocketAddr::V6(addr)) => Some(addr),
+                    _ => None,
+                };
+                (ipv4, ipv6)
+            }
+        }
+    }
+
     // Overrides the ipv4 address and port of ipv4 and dual stack configurations. Ipv6
     // configurations are added ipv4 info making them into dual stack configs.
     /// Sets an ipv4 socket. This will override any past ipv4 configuration and will promote the configuration to dual socket if an ipv6 socket is configured.
+    /// Pre-opened sockets are replaced by the ipv4 socket.
     pub fn with_ipv4(self, ip: Ipv4Addr, port: u16) -> ListenConfig {
         match self {
-            ListenConfig::Ipv4 { .. } => ListenConfig::Ipv4 { ip, port },
+            ListenConfig::Ipv4 { .. } | ListenConfig::Sockets { .. } => {
+                ListenConfig::Ipv4 { ip, port }
+            }
             ListenConfig::Ipv6 {
                 ip: ipv6,
                 port: ipv6_port,
@@ -221,9 +408,12 @@ impl ListenConfig {
     // Overrides the ipv6 address and port of ipv6 and dual stack configurations. Ipv4
     // configurations are added ipv6 info making them into dual stack configs.
     /// Sets an ipv6 socket. This will override any past ipv6 configuration and will promote the configuration to dual socket if an ipv4 socket is configured.
+    /// Pre-opened sockets are replaced by the ipv6 socket.
     pub fn with_ipv6(self, ip: Ipv6Addr, port: u16) -> ListenConfig {
         match self {
-            ListenConfig::Ipv6 { .. } => ListenConfig::Ipv6 { ip, port },
+            ListenConfig::Ipv6 { .. } | ListenConfig::Sockets { .. } => {
+                ListenConfig::Ipv6 { ip, port }
+            }
             ListenConfig::Ipv4 {
                 ip: ipv4,
                 port: ipv4_port,
@@ -2,14 +2,28 @@
 //!
 //! Every UDP packet passes a filter before being processed.
 
-use super::filter::{Filter, FilterConfig};
-use crate::{metrics::METRICS, node_info::NodeAddress, packet::*, Executor};
+use super::{
+    filter::{Filter, FilterConfig, FilterStats},
+    transport::{recv_from, Transport},
+    LocalNodeIds,
+};
+use crate::{
+    audit::{SecurityEventKind, AUDIT_LOG},
+    ipmode::is_ipv4_or_mapped,
+    metrics::METRICS,
+    node_info::NodeAddress,
+    packet::*,
+    time::Instant,
+    Executor,
+};
 use parking_lot::RwLock;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
-use tokio::{
-    net::UdpSocket,
-    sync::{mpsc, oneshot},
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
 };
+use tokio::sync::{mpsc, oneshot};
 
 use tracing::{debug, trace, warn};
 
@@ -23,6 +37,10 @@ pub struct InboundPacket {
     pub message: Vec<u8>,
     /// The authenticated data of the packet.
     pub authenticated_data: Vec<u8>,
+    /// When the packet arrived, as stamped by the kernel where supported.
+    pub received_at: Instant,
+    /// Whether the packet was addressed to the identity retired by the last rotation.
+    pub to_retired_identity: bool,
 }
 
 /// Convenience objects for setting up the recv handler.
@@ -31,61 +49,78 @@ pub struct RecvHandlerConfig {
     /// If the filter is enabled this sets the default timeout for bans enacted by the filter.
     pub ban_duration: Option<Duration>,
     pub executor: Box<dyn Executor>,
-    pub recv: Arc<UdpSocket>,
-    pub second_recv: Option<Arc<UdpSocket>>,
-    pub local_node_id: enr::NodeId,
+    pub recv: Arc<dyn Transport>,
+    pub second_recv: Option<Arc<dyn Transport>>,
+    pub local_node_ids: Arc<RwLock<LocalNodeIds>>,
     pub expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
+    /// The number of decoded packets buffered for the handler.
+    pub inbound_queue_capacity: usize,
+    /// Drops packets from IPv4 and IPv4-mapped addresses.
+    pub ipv6_only: bool,
 }
 
 /// The main task that handles inbound UDP packets.
 pub(crate) struct RecvHandler {
     /// The UDP recv socket.
-    recv: Arc<UdpSocket>,
+    recv: Arc<dyn Transport>,
     /// An option second UDP socket. Used when dialing over both Ipv4 and Ipv6.
-    second_recv: Option<Arc<UdpSocket>>,
+    second_recv: Option<Arc<dyn Transport>>,
     /// Simple hack to alternate reading from the first or the second socket.
     /// The list of waiting responses. These are used to allow incoming packets from sources
     /// that we are expected a response from bypassing the rate-limit filters.
     expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
     /// The packet filter which decides whether to accept or reject inbound packets.
     filter: Filter,
-    /// The local node id used to decrypt headers of messages.
-    node_id: enr::NodeId,
+    /// The local node ids used to decrypt headers of messages.
+    node_ids: Arc<RwLock<LocalNodeIds>>,
     /// The channel to send the packet handler.
     handler: mpsc::Sender<InboundPacket>,
+    /// Requests for the state of the filter.
+    filter_stats: mpsc::Receiver<oneshot::Sender<FilterStats>>,
     /// Exit channel to shutdown the recv handler.
     exit: oneshot::Receiver<()>,
+    /// Drops packets from IPv4 and IPv4-mapped addresses.
+    ipv6_only: bool,
 }
 
 impl RecvHandler {
     /// Spawns the `RecvHandler` on a provided executor.
     pub(crate) fn spawn<P: ProtocolIdentity>(
         config: RecvHandlerConfig,
-    ) -> (mpsc::Receiver<InboundPacket>, oneshot::Sender<()>) {
+    ) -> (
+        mpsc::Receiver<InboundPacket>,
+        mpsc::Sender<oneshot::Sender<FilterStats>>,
+        oneshot::Sender<()>,
+    ) {
         let (exit_sender, exit) = oneshot::channel();
+        let (filter_stats_sender, filter_stats) = mpsc::channel(1);
         let RecvHandlerConfig {
             filter_config,
             ban_duration,
             executor,
             recv,
             second_recv,
-            local_node_id,
+            local_node_ids,
             expected_responses,
+            inbound_queue_capacity,
+            ipv6_only,
         } = config;
 
         let filter_enabled = filter_config.enabled;
 
         // create the channel to send decoded packets to the handler
-        let (handler, handler_recv) = mpsc::channel(30);
+        let (handler, handler_recv) = mpsc::channel(inbound_queue_capacity);
 
         let mut recv_handler = RecvHandler {
             recv,
             second_recv,
             expected_responses,
             filter: Filter::new(filter_config, ban_duration),
-            node_id: local_node_id,
+            node_ids: local_node_ids,
             handler,
+            filter_stats,
             exit,
+            ipv6_only,
         };
 
         // start the handler
@@ -93,7 +128,7 @@ impl RecvHandler {
             debug!("Recv handler starting");
             recv_handler.start::<P>(filter_enabled).await;
         }));
-        (handler_recv, exit_sender)
+        (handler_recv, filter_stats_sender, exit_sender)
     }
 
     /// The main future driving the recv handler. This will shutdown when the exit future is fired.
@@ -109,17 +144,20 @@ impl RecvHandler {
 
         loop {
             tokio::select! {
-                Ok((length, src)) = self.recv.recv_from(&mut first_buffer) => {
+                Ok((length, src, received_at)) = recv_from(&*self.recv, &mut first_buffer) => {
                     METRICS.add_recv_bytes(length);
-                    self.handle_inbound::<P>(src, length, &first_buffer).await;
+                    self.handle_inbound::<P>(src, length, &first_buffer, received_at).await;
                 }
-                Some(Ok((length, src))) = Into::<OptionFuture<_>>::into(self.second_recv.as_ref().map(|second_recv|second_recv.recv_from(&mut second_buffer))), if check_second_recv => {
+                Some(Ok((length, src, received_at))) = Into::<OptionFuture<_>>::into(self.second_recv.as_ref().map(|second_recv| recv_from(&**second_recv, &mut second_buffer))), if check_second_recv => {
                     METRICS.add_recv_bytes(length);
-                    self.handle_inbound::<P>(src, length, &second_buffer).await;
+                    self.handle_inbound::<P>(src, length, &second_buffer, received_at).await;
                 }
                 _ = interval.tick(), if filter_enabled => {
                     self.filter.prune_limiter();
                 },
+                Some(callback) = self.filter_stats.recv() => {
+                    let _ = callback.send(self.filter.stats());
+                }
                 _ = &mut self.exit => {
                     debug!("Recv handler shutdown");
                     return;
@@ -135,6 +173,7 @@ impl RecvHandler {
         mut src_address: SocketAddr,
         length: usize,
         recv_buffer: &[u8; MAX_PACKET_SIZE],
+        received_at: Instant,
     ) {
         // Zero out the flowinfo and scope id of v6 socket addresses.
         //
@@ -171,6 +210,12 @@ impl RecvHandler {
             }
         }
 
+        // Without IPv4, a socket that also accepts IPv4 traffic must not leak it to the handler.
+        if self.ipv6_only && is_ipv4_or_mapped(&src_address) {
+            trace!(?src_address, "Dropped packet from IPv4 source");
+            return;
+        }
+
         // Permit all expected responses
         let permitted = self.expected_responses.read().get(&src_address).is_some();
 
@@ -180,15 +225,29 @@ impl RecvHandler {
             trace!(?src_address, "Packet filtered from source");
             return;
         }
-        // Decodes the packet
-        let (packet, authenticated_data) =
-            match Packet::decode::<P>(&self.node_id, &recv_buffer[..length]) {
-                Ok(p) => p,
-                Err(e) => {
-                    debug!(error = ?e, "Packet decoding failed"); // could not decode the packet, drop it
-                    return;
-                }
-            };
+        // Decodes the packet, for the retired identity if it isn't addressed to the current one.
+        let node_ids = *self.node_ids.read();
+        let decoded = Packet::decode::<P>(&node_ids.current, &recv_buffer[..length])
+            .map(|decoded| (decoded, false))
+            .or_else(|e| match node_ids.retired {
+                Some(retired) => Packet::decode::<P>(&retired, &recv_buffer[..length])
+                    .map(|decoded| (decoded, true))
+                    .map_err(|_| e),
+                None => Err(e),
+            });
+        let ((packet, authenticated_data), to_retired_identity) = match decoded {
+            Ok(p) => p,
+            Err(e) => {
+                debug!(error = ?e, "Packet decoding failed"); // could not decode the packet, drop it
+                AUDIT_LOG.record(
+                    SecurityEventKind::MalformedPacket,
+                    Some(src_address.ip()),
+                    None,
+                    None,
+                );
+                return;
+            }
+        };
 
         // If this is not a challenge packet, we immediately know its src_id and so pass it
         // through the second filter.
@@ -210,12 +269,22 @@ impl RecvHandler {
             header: packet.header,
             message: packet.message,
             authenticated_data,
+            received_at,
+            to_retired_identity,
         };
 
-        // send the filtered decoded packet to the handler.
-        self.handler
-            .send(inbound)
-            .await
-            .unwrap_or_else(|e| warn!(error = %e,"Could not send packet to handler"));
+        // send the filtered decoded packet to the handler. If the handler is falling behind, the
+        // packet is dropped rather than stalling the socket.
+        match self.handler.try_send(inbound) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                trace!(%src_address, "Handler queue full, dropping packet");
+                METRICS
+                    .inbound_packets_dropped
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => warn!(error = %e, "Could not send packet to handler"),
+        }
+        METRICS.set_queue_depth(&METRICS.inbound_queue_depth, &self.handler);
     }
 }
@@ -1,10 +1,11 @@
 //! This is a standalone task that encodes and sends Discv5 UDP packets
-use crate::{metrics::METRICS, node_info::NodeAddress, packet::*, Executor};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{
-    net::UdpSocket,
-    sync::{mpsc, oneshot},
+use super::transport::{send_to, Transport};
+use crate::{
+    ipmode::is_ipv4_or_mapped, metrics::METRICS, node_info::NodeAddress, packet::*, rpc::RequestId,
+    Executor,
 };
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, trace, warn};
 
 pub struct OutboundPacket {
@@ -12,18 +13,22 @@ pub struct OutboundPacket {
     pub node_address: NodeAddress,
     /// The packet to be encoded.
     pub packet: Packet,
+    /// The id of the request the packet carries, if any, for correlating logs.
+    pub request_id: Option<RequestId>,
 }
 
 /// The main task that handles outbound UDP packets.
 pub(crate) struct SendHandler {
     /// The UDP send socket for IPv4.
-    send_ipv4: Option<Arc<UdpSocket>>,
+    send_ipv4: Option<Arc<dyn Transport>>,
     /// The UDP send socket for IPv6.
-    send_ipv6: Option<Arc<UdpSocket>>,
+    send_ipv6: Option<Arc<dyn Transport>>,
     /// The channel to respond to send requests.
     handler_recv: mpsc::Receiver<OutboundPacket>,
     /// Exit channel to shutdown the handler.
     exit: oneshot::Receiver<()>,
+    /// Refuses to send to IPv4 and IPv4-mapped addresses.
+    ipv6_only: bool,
 }
 
 enum Error {
@@ -37,8 +42,9 @@ impl SendHandler {
     /// shutdown the handler.
     pub(crate) fn spawn<P: ProtocolIdentity>(
         executor: Box<dyn Executor>,
-        send_ipv4: Option<Arc<UdpSocket>>,
-        send_ipv6: Option<Arc<UdpSocket>>,
+        send_ipv4: Option<Arc<dyn Transport>>,
+        send_ipv6: Option<Arc<dyn Transport>>,
+        ipv6_only: bool,
     ) -> (mpsc::Sender<OutboundPacket>, oneshot::Sender<()>) {
         let (exit_send, exit) = oneshot::channel();
         let (handler_send, handler_recv) = mpsc::channel(30);
@@ -48,6 +54,7 @@ impl SendHandler {
             send_ipv6,
             handler_recv,
             exit,
+            ipv6_only,
         };
 
         // start the handler
@@ -72,10 +79,11 @@ impl SendHandler {
                         );
                     }
                     let addr = &packet.node_address.socket_addr;
+                    let request_id = packet.request_id.as_ref().map(tracing::field::display);
                     if let Err(e) = self.send(&encoded_packet, addr).await {
                         match e {
                             Error::Io(e) => {
-                                trace!(%addr, error = %e, "Could not send packet.");
+                                trace!(%addr, request_id, error = %e, "Could not send packet.");
                             },
                             Error::SocketMismatch => {
                                 error!(%addr, "Socket mismatch attempting to send a packet.")
@@ -83,6 +91,9 @@ impl SendHandler {
                         }
                     } else {
                         METRICS.add_sent_bytes(encoded_packet.len());
+                        if request_id.is_some() {
+                            trace!(%addr, request_id, "Sent request packet");
+                        }
                     }
                 }
                 _ = &mut self.exit => {
@@ -94,6 +105,9 @@ impl SendHandler {
     }
 
     async fn send(&self, encoded_packet: &[u8], socket_addr: &SocketAddr) -> Result<usize, Error> {
+        if self.ipv6_only && is_ipv4_or_mapped(socket_addr) {
+            return Err(Error::SocketMismatch);
+        }
         let socket = match socket_addr {
             SocketAddr::V4(_) => {
                 if let Some(socket) = self.send_ipv4.as_ref() {
@@ -111,8 +125,7 @@ impl SendHandler {
             }
         };
 
-        socket
-            .send_to(encoded_packet, socket_addr)
+        send_to(&**socket, encoded_packet, *socket_addr)
             .await
             .map_err(Error::Io)
     }
@@ -0,0 +1,140 @@
+//! Kernel receive timestamps of UDP datagrams.
+//!
+//! With `SO_TIMESTAMPING` the kernel stamps each datagram as it arrives, before the receiving task
+//! gets scheduled. Round trip times measured against these timestamps exclude the time a response
+//! waited in the socket buffer, which on a busy host can dwarf the network latency.
+use crate::time::{self, Instant};
+use socket2::SockAddr;
+use std::{
+    convert::TryFrom,
+    io, mem,
+    net::SocketAddr,
+    os::unix::io::AsRawFd,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::UdpSocket;
+
+/// Enables software receive timestamps on the socket.
+pub(crate) fn enable(socket: &UdpSocket) -> io::Result<()> {
+    let flags: libc::c_uint = libc::SOF_TIMESTAMPING_RX_SOFTWARE | libc::SOF_TIMESTAMPING_SOFTWARE;
+    // SAFETY: `flags` outlives the call and its size is passed along.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            &flags as *const libc::c_uint as *const libc::c_void,
+            mem::size_of_val(&flags) as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Receives a datagram from a readable socket, returning its length, the address it was sent
+/// from and the time the kernel received it, if stamped. Bytes of the datagram that don't fit in
+/// `buf` are discarded.
+pub(crate) fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<Instant>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // Room for a `scm_timestamping` control message, aligned for its header.
+    let mut control = [0u64; 16];
+    // SAFETY: All pointers in `msg` point to buffers that outlive the call, and control messages
+    // are only read within the length the kernel reports.
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = (&mut storage as *mut libc::sockaddr_storage).cast();
+        msg.msg_namelen = mem::size_of_val(&storage) as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let len = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let src = SockAddr::new(storage, msg.msg_namelen)
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unexpected address"))?;
+
+        let mut received_at = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPING
+            {
+                // The software timestamp comes first, followed by the deprecated and the hardware
+                // ones.
+                let timestamp =
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                received_at = to_instant(timestamp);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok((len as usize, src, received_at))
+    }
+}
+
+/// Converts a timestamp of the realtime clock to an [`Instant`].
+fn to_instant(timestamp: libc::timespec) -> Option<Instant> {
+    if timestamp.tv_sec == 0 && timestamp.tv_nsec == 0 {
+        return None;
+    }
+    let received = UNIX_EPOCH
+        + Duration::new(
+            u64::try_from(timestamp.tv_sec).ok()?,
+            u32::try_from(timestamp.tv_nsec).ok()?,
+        );
+    let age = SystemTime::now()
+        .duration_since(received)
+        .unwrap_or_default();
+    time::now().checked_sub(age)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stamps_received_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        enable(&receiver).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // The kernel turns timestamping on in the background, so the first datagrams may arrive
+        // unstamped.
+        let mut buf = [0; 2];
+        let mut stamped = None;
+        for _ in 0..50 {
+            let before = Instant::now();
+            sender
+                .send_to(b"ping", receiver.local_addr().unwrap())
+                .await
+                .unwrap();
+            receiver.readable().await.unwrap();
+
+            let (len, src, received_at) = recv_from(&receiver, &mut buf).unwrap();
+            assert_eq!(len, 2);
+            assert_eq!(&buf, b"pi");
+            assert_eq!(src, sender.local_addr().unwrap());
+            if let Some(received_at) = received_at {
+                stamped = Some((before, received_at));
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // Allow for the realtime clock having been read a little apart from the monotonic one.
+        let (before, received_at) = stamped.expect("Timestamping is enabled");
+        assert!(received_at + Duration::from_millis(100) >= before);
+        assert!(received_at <= Instant::now());
+    }
+}
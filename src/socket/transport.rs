@@ -0,0 +1,116 @@
+//! The datagram transport the discv5 sockets send and receive packets over.
+//!
+//! By default the sockets are UDP sockets bound according to the [`super::ListenConfig`]. A
+//! [`TransportFactory`] set in the config replaces them, for instance with the in-memory network
+//! of the `simulation` feature.
+use crate::time::{self, Instant};
+use futures::{future::poll_fn, ready};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{io::ReadBuf, net::UdpSocket};
+
+/// A datagram socket.
+pub trait Transport: Send + Sync + 'static {
+    /// Attempts to receive a single datagram into `buf`, returning the address it was sent from.
+    /// Bytes of the datagram that don't fit in `buf` are discarded.
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>>;
+
+    /// Like [`Transport::poll_recv_from`], but also returns the time the datagram arrived, if the
+    /// transport records it ahead of the call. By default no time is returned and the datagram
+    /// counts as received when it is read.
+    fn poll_recv_from_timestamped(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<(SocketAddr, Option<Instant>)>> {
+        self.poll_recv_from(cx, buf).map_ok(|src| (src, None))
+    }
+
+    /// Attempts to send a single datagram to `target`.
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>>;
+}
+
+/// Creates the transports of a node, one for each of the addresses of its `ListenConfig`.
+pub trait TransportFactory: Send + Sync {
+    /// Binds a transport to the local address.
+    fn bind(&self, addr: SocketAddr) -> io::Result<Arc<dyn Transport>>;
+}
+
+impl Transport for UdpSocket {
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>> {
+        UdpSocket::poll_recv_from(self, cx, buf)
+    }
+
+    /// Reads the kernel receive timestamp, if enabled on the socket.
+    #[cfg(target_os = "linux")]
+    fn poll_recv_from_timestamped(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<(SocketAddr, Option<Instant>)>> {
+        loop {
+            ready!(self.poll_recv_ready(cx))?;
+            match self.try_io(tokio::io::Interest::READABLE, || {
+                super::timestamp::recv_from(self, buf.initialize_unfilled())
+            }) {
+                Ok((len, src, received_at)) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok((src, received_at)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        UdpSocket::poll_send_to(self, cx, buf, target)
+    }
+}
+
+/// Receives a single datagram, returning its length, the address it was sent from and the time it
+/// arrived.
+pub(crate) async fn recv_from(
+    transport: &dyn Transport,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Instant)> {
+    let mut buf = ReadBuf::new(buf);
+    let (src, received_at) =
+        poll_fn(|cx| transport.poll_recv_from_timestamped(cx, &mut buf)).await?;
+    Ok((
+        buf.filled().len(),
+        src,
+        received_at.unwrap_or_else(time::now),
+    ))
+}
+
+/// Sends a single datagram to `target`.
+pub(crate) async fn send_to(
+    transport: &dyn Transport,
+    buf: &[u8],
+    target: SocketAddr,
+) -> io::Result<usize> {
+    poll_fn(|cx| transport.poll_send_to(cx, buf, target)).await
+}
@@ -0,0 +1,122 @@
+//! TALK requests with a timeout, retries and typed failures.
+//!
+//! [`crate::Discv5::talk`] returns a [`Talk`] request, configured with its builder methods and
+//! sent when awaited:
+//!
+//! ```ignore
+//! let response = discv5
+//!     .talk(enr, b"my-protocol".to_vec(), payload)
+//!     .with_timeout(Duration::from_secs(2))
+//!     .with_retries(2)
+//!     .await?;
+//! ```
+//!
+//! The specification has peers answer requests of protocols they don't know with an empty
+//! response, so an empty response is reported as [`TalkError::EmptyResponse`] unless the
+//! protocol has empty responses of its own, see [`Talk::allow_empty_response`].
+use crate::{
+    error::{RequestError, TalkError},
+    node_info::{NodeContact, NonContactable},
+    service::ServiceRequest,
+};
+use std::{
+    future::{Future, IntoFuture},
+    pin::Pin,
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// A TALK request to a peer, sent when awaited.
+#[must_use = "The request is only sent when awaited"]
+pub struct Talk {
+    channel: Option<mpsc::Sender<ServiceRequest>>,
+    contact: Result<NodeContact, NonContactable>,
+    protocol: Vec<u8>,
+    payload: Vec<u8>,
+    timeout: Option<Duration>,
+    retries: usize,
+    allow_empty_response: bool,
+}
+
+impl Talk {
+    pub(crate) fn new(
+        channel: Option<mpsc::Sender<ServiceRequest>>,
+        contact: Result<NodeContact, NonContactable>,
+        protocol: Vec<u8>,
+        payload: Vec<u8>,
+    ) -> Self {
+        Talk {
+            channel,
+            contact,
+            protocol,
+            payload,
+            timeout: None,
+            retries: 0,
+            allow_empty_response: false,
+        }
+    }
+
+    /// Fails an attempt that hasn't been answered within the timeout. The handler sends the
+    /// attempt once and fails it when the timeout elapses, in place of the request timeout and
+    /// retries of the configuration, which apply without a timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retries the request up to `retries` times after failures that may not recur, such as
+    /// timeouts.
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Returns empty responses rather than failing with [`TalkError::EmptyResponse`], for
+    /// protocols with empty responses of their own.
+    pub fn allow_empty_response(mut self) -> Self {
+        self.allow_empty_response = true;
+        self
+    }
+
+    async fn send(self) -> Result<Vec<u8>, TalkError> {
+        let contact = self.contact.map_err(RequestError::from)?;
+        let channel = self.channel.ok_or(RequestError::ServiceNotStarted)?;
+        let mut attempts = 0;
+        loop {
+            let (callback_send, callback_recv) = oneshot::channel();
+            let event = ServiceRequest::Talk(
+                contact.clone(),
+                self.protocol.clone(),
+                self.payload.clone(),
+                self.timeout,
+                callback_send,
+            );
+            channel
+                .send(event)
+                .await
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+
+            let result = match callback_recv.await {
+                Ok(Ok(response)) if response.is_empty() && !self.allow_empty_response => {
+                    Err(TalkError::EmptyResponse)
+                }
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(error)) => Err(TalkError::from(error)),
+                Err(error) => Err(RequestError::ChannelFailed(error.to_string()).into()),
+            };
+            match result {
+                Err(error) if error.is_retryable() && attempts < self.retries => attempts += 1,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl IntoFuture for Talk {
+    type Output = Result<Vec<u8>, TalkError>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.send())
+    }
+}
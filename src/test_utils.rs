@@ -0,0 +1,241 @@
+//! Utilities for testing applications built on discv5.
+//!
+//! [`TestNetwork`] runs a number of discv5 nodes in-process over a [`SimNetwork`], with the first
+//! nodes acting as bootnodes for the rest. Run it on a runtime with paused time, as with
+//! `#[tokio::test(start_paused = true)]`, to have the protocol timers elapse in virtual time.
+//!
+//! NOTE: The permit and ban lists are global to the process and are therefore shared by all nodes
+//! of the network.
+use crate::{simulation::SimNetwork, ConfigBuilder, Discv5, Enr, Error, ListenConfig};
+use enr::{CombinedKey, NodeId};
+use futures::future::join_all;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+/// The UDP port all nodes of a test network listen on. Nodes are told apart by their IP.
+const TEST_NETWORK_PORT: u16 = 9000;
+
+/// The time between rounds of lookups while waiting for a network to converge.
+const CONVERGENCE_ROUND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Builds a [`TestNetwork`].
+pub struct TestNetworkBuilder {
+    nodes: usize,
+    bootnodes: usize,
+    configure: Box<dyn Fn(&mut ConfigBuilder)>,
+}
+
+impl TestNetworkBuilder {
+    /// The number of nodes in the network, including the bootnodes. Default: 8.
+    pub fn nodes(mut self, nodes: usize) -> Self {
+        self.nodes = nodes;
+        self
+    }
+
+    /// The number of nodes every other node is bootstrapped with. Default: 1.
+    pub fn bootnodes(mut self, bootnodes: usize) -> Self {
+        self.bootnodes = bootnodes;
+        self
+    }
+
+    /// Adjusts the config of every node. The listen config and the transport are set by the
+    /// network.
+    pub fn config(mut self, configure: impl Fn(&mut ConfigBuilder) + 'static) -> Self {
+        self.configure = Box::new(configure);
+        self
+    }
+
+    /// Creates and starts the nodes, and adds the bootnodes to the routing table of every other
+    /// node.
+    pub async fn build(self) -> Result<TestNetwork, Error> {
+        assert!(
+            self.bootnodes > 0 && self.bootnodes <= self.nodes,
+            "A test network needs between one and all of its nodes as bootnodes"
+        );
+        let network = SimNetwork::new();
+        let mut nodes = Vec::with_capacity(self.nodes);
+        for index in 0..self.nodes {
+            let ip = TestNetwork::node_ip(index);
+            let key = CombinedKey::generate_secp256k1();
+            let enr = Enr::builder()
+                .ip4(ip)
+                .udp4(TEST_NETWORK_PORT)
+                .build(&key)
+                .map_err(|e| Error::Error(e.to_string()))?;
+            let mut config = ConfigBuilder::new(ListenConfig::Ipv4 {
+                ip,
+                port: TEST_NETWORK_PORT,
+            });
+            (self.configure)(&mut config);
+            config.transport_factory(Arc::new(network.clone()));
+            let mut node = Discv5::new(enr, key, config.build()).map_err(Error::Custom)?;
+            node.start().await?;
+            nodes.push(node);
+        }
+
+        let bootnode_enrs: Vec<Enr> = nodes[..self.bootnodes]
+            .iter()
+            .map(Discv5::local_enr)
+            .collect();
+        for node in &nodes {
+            for enr in &bootnode_enrs {
+                if enr.node_id() != node.local_enr().node_id() {
+                    node.add_enr(enr.clone()).map_err(Error::Custom)?;
+                }
+            }
+        }
+
+        Ok(TestNetwork { network, nodes })
+    }
+}
+
+/// A network of discv5 nodes running in-process over a simulated transport.
+pub struct TestNetwork {
+    network: SimNetwork,
+    nodes: Vec<Discv5>,
+}
+
+impl TestNetwork {
+    pub fn builder() -> TestNetworkBuilder {
+        TestNetworkBuilder {
+            nodes: 8,
+            bootnodes: 1,
+            configure: Box::new(|_| {}),
+        }
+    }
+
+    /// The IP of the node at `index`.
+    fn node_ip(index: usize) -> Ipv4Addr {
+        let index = index as u32 + 1;
+        Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)) + index)
+    }
+
+    /// The simulated network the nodes communicate over, to cut and restore links.
+    pub fn sim(&self) -> &SimNetwork {
+        &self.network
+    }
+
+    /// The nodes of the network, the bootnodes first.
+    pub fn nodes(&self) -> &[Discv5] {
+        &self.nodes
+    }
+
+    /// The node at `index`.
+    pub fn node(&self, index: usize) -> &Discv5 {
+        &self.nodes[index]
+    }
+
+    /// The mutable node at `index`, for instance to shut it down.
+    pub fn node_mut(&mut self, index: usize) -> &mut Discv5 {
+        &mut self.nodes[index]
+    }
+
+    /// The address of the node at `index`.
+    pub fn addr(&self, index: usize) -> SocketAddr {
+        (Self::node_ip(index), TEST_NETWORK_PORT).into()
+    }
+
+    /// The number of entries in the routing table of each node.
+    pub fn table_sizes(&self) -> Vec<usize> {
+        self.nodes
+            .iter()
+            .map(|node| node.table_entries_id().len())
+            .collect()
+    }
+
+    /// Whether the routing table of every node holds at least `min_peers` entries.
+    pub fn is_converged(&self, min_peers: usize) -> bool {
+        self.table_sizes().iter().all(|size| *size >= min_peers)
+    }
+
+    /// Runs a lookup for a random target from every node concurrently.
+    pub async fn lookup_round(&self) {
+        join_all(
+            self.nodes
+                .iter()
+                .map(|node| node.find_node(NodeId::random())),
+        )
+        .await;
+    }
+
+    /// Runs rounds of lookups until every node knows at least `min_peers` other nodes. Returns the
+    /// table sizes as an error if the network has not converged within `timeout`.
+    pub async fn converge(&self, min_peers: usize, timeout: Duration) -> Result<(), Vec<usize>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.is_converged(min_peers) {
+            if tokio::time::Instant::now() >= deadline {
+                return Err(self.table_sizes());
+            }
+            self.lookup_round().await;
+            tokio::time::sleep(CONVERGENCE_ROUND_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Panics unless every node knows at least `min_peers` other nodes.
+    pub fn assert_converged(&self, min_peers: usize) {
+        assert!(
+            self.is_converged(min_peers),
+            "Test network has not converged to {} peers per node. Table sizes: {:?}",
+            min_peers,
+            self.table_sizes()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_network_converges() {
+        let network = TestNetwork::builder().nodes(8).build().await.unwrap();
+        network.converge(7, Duration::from_secs(60)).await.unwrap();
+        network.assert_converged(7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_hooks() {
+        let network = TestNetwork::builder().nodes(2).build().await.unwrap();
+        let (node_a, node_b) = (network.node(0), network.node(1));
+        let node_b_id = node_b.local_enr().node_id();
+        node_a.send_ping(node_b.local_enr()).await.unwrap();
+        assert_eq!(node_a.connected_peers(), 1);
+
+        // Both lost sessions and undecryptable packets are recovered from with a new handshake.
+        let mut events = node_a.event_stream().await.unwrap();
+        node_a.chaos_drop_session(node_b_id).await.unwrap();
+        node_a.send_ping(node_b.local_enr()).await.unwrap();
+        node_a.chaos_corrupt_next_nonce(node_b_id).await.unwrap();
+        node_a.send_ping(node_b.local_enr()).await.unwrap();
+        let mut handshakes = 0;
+        while let Ok(event) = events.try_recv() {
+            if matches!(event, crate::Event::SessionEstablished(enr, _) if enr.node_id() == node_b_id)
+            {
+                handshakes += 1;
+            }
+        }
+        assert_eq!(handshakes, 2);
+
+        assert!(node_a.chaos_expire_entry(&node_b_id));
+        assert_eq!(node_a.connected_peers(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crawl_finds_every_node() {
+        let network = TestNetwork::builder().nodes(8).build().await.unwrap();
+        network.converge(3, Duration::from_secs(60)).await.unwrap();
+
+        let snapshot = network
+            .node(1)
+            .crawl(crate::CrawlConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(snapshot.nodes.len(), 7);
+        assert_eq!(snapshot.count(crate::crawler::Reachability::Reachable), 7);
+        assert_eq!(snapshot.reachable_ratio(), Some(1.0));
+    }
+}
@@ -0,0 +1,160 @@
+//! Hex test vectors of the wire encoding of messages and packets.
+//!
+//! The functions of this module encode protocol messages and packets to hex and decode them back,
+//! so that the encoding can be checked against the vectors of the discv5 specification and of other
+//! implementations. [`MessagePacketVector`] generates the vector of an encrypted message packet from
+//! arbitrary inputs, including a custom [`ProtocolIdentity`], to produce vectors for this
+//! implementation's extensions.
+//!
+//! Handshake packets depend on a freshly generated ephemeral key and can only be decoded.
+use crate::{
+    error::VectorError,
+    handler::crypto::{decrypt_message, encrypt_message},
+    packet::{MessageNonce, Packet, PacketHeader, PacketKind, ProtocolIdentity},
+    rpc::Message,
+};
+use enr::NodeId;
+use std::fmt;
+
+/// Encodes a message to hex.
+pub fn encode_message(message: Message) -> String {
+    hex::encode(message.encode())
+}
+
+/// Decodes a message from hex.
+pub fn decode_message(data: &str) -> Result<Message, VectorError> {
+    Message::decode(&decode_hex(data)?).map_err(|e| VectorError::Message(e.to_string()))
+}
+
+/// Encodes a packet sent to `dst_id` to hex, masking its header.
+pub fn encode_packet<P: ProtocolIdentity>(packet: Packet, dst_id: &NodeId) -> String {
+    hex::encode(packet.encode::<P>(dst_id))
+}
+
+/// Decodes a packet received by `dst_id` from hex. Returns the packet and its authenticated data.
+pub fn decode_packet<P: ProtocolIdentity>(
+    data: &str,
+    dst_id: &NodeId,
+) -> Result<(Packet, Vec<u8>), VectorError> {
+    Packet::decode::<P>(dst_id, &decode_hex(data)?).map_err(VectorError::Packet)
+}
+
+/// Decodes an ordinary message packet received by `dst_id` from hex and decrypts its message with
+/// the session key of the sender.
+pub fn decode_message_packet<P: ProtocolIdentity>(
+    data: &str,
+    dst_id: &NodeId,
+    read_key: &[u8; 16],
+) -> Result<(Packet, Message), VectorError> {
+    let (packet, authenticated_data) = decode_packet::<P>(data, dst_id)?;
+    let message = decrypt_message(
+        read_key,
+        *packet.message_nonce(),
+        &packet.message,
+        &authenticated_data,
+    )
+    .map_err(|e| VectorError::Decryption(e.to_string()))?;
+    let message = Message::decode(&message).map_err(|e| VectorError::Message(e.to_string()))?;
+    Ok((packet, message))
+}
+
+/// The inputs of an ordinary message packet. Displays in the format of the vectors of the
+/// specification.
+#[derive(Debug, Clone)]
+pub struct MessagePacketVector {
+    /// The node sending the packet.
+    pub src_id: NodeId,
+    /// The node receiving the packet, whose id masks the header.
+    pub dst_id: NodeId,
+    /// The IV masking the header.
+    pub masking_iv: u128,
+    /// The nonce the message is encrypted with.
+    pub nonce: MessageNonce,
+    /// The session key the message is encrypted with.
+    pub read_key: [u8; 16],
+    /// The message of the packet.
+    pub message: Message,
+}
+
+impl MessagePacketVector {
+    /// Generates the packet of the vector, encoded to hex.
+    pub fn generate<P: ProtocolIdentity>(&self) -> String {
+        let mut packet = Packet {
+            iv: self.masking_iv,
+            header: PacketHeader {
+                message_nonce: self.nonce,
+                kind: PacketKind::Message {
+                    src_id: self.src_id,
+                },
+            },
+            message: Vec::new(),
+        };
+        packet.message = encrypt_message(
+            &self.read_key,
+            self.nonce,
+            &self.message.clone().encode(),
+            &packet.authenticated_data::<P>(),
+        )
+        .expect("A message of any length can be encrypted");
+        encode_packet::<P>(packet, &self.dst_id)
+    }
+
+    /// Whether `data` is the packet of the vector, by decoding and decrypting it.
+    pub fn verify<P: ProtocolIdentity>(&self, data: &str) -> Result<bool, VectorError> {
+        let (packet, message) = decode_message_packet::<P>(data, &self.dst_id, &self.read_key)?;
+        Ok(packet.iv == self.masking_iv
+            && packet.header.message_nonce == self.nonce
+            && packet.src_id() == Some(self.src_id)
+            && message == self.message)
+    }
+}
+
+impl fmt::Display for MessagePacketVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "src-node-id = 0x{}", hex::encode(self.src_id.raw()))?;
+        writeln!(f, "dest-node-id = 0x{}", hex::encode(self.dst_id.raw()))?;
+        writeln!(f, "masking-iv = 0x{:032x}", self.masking_iv)?;
+        writeln!(f, "nonce = 0x{}", hex::encode(self.nonce))?;
+        writeln!(f, "read-key = 0x{}", hex::encode(self.read_key))?;
+        write!(f, "message = 0x{}", encode_message(self.message.clone()))
+    }
+}
+
+fn decode_hex(data: &str) -> Result<Vec<u8>, VectorError> {
+    let data = data.trim();
+    hex::decode(data.strip_prefix("0x").unwrap_or(data))
+        .map_err(|e| VectorError::InvalidHex(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::DefaultProtocolId,
+        rpc::{Request, RequestBody, RequestId},
+    };
+
+    #[test]
+    fn test_spec_ping_message_packet() {
+        let node_id = |id: &str| NodeId::parse(&hex::decode(id).unwrap()).unwrap();
+        let vector = MessagePacketVector {
+            src_id: node_id("aaaa8419e9f49d0083561b48287df592939a8d19947d8c0ef88f2a4856a69fbb"),
+            dst_id: node_id("bbbb9d047f0488c0b5a93c1c3f2d8bafc7c8ff337024a55434a0d0555de64db9"),
+            masking_iv: 0,
+            nonce: [0xff; 12],
+            read_key: [0; 16],
+            message: Message::Request(Request {
+                id: RequestId(vec![0, 0, 0, 1]),
+                body: RequestBody::Ping { enr_seq: 2 },
+            }),
+        };
+        let expected = "00000000000000000000000000000000088b3d4342774649325f313964a39e55ea96c005ad52be8c7560413a7008f16c9e6d2f43bbea8814a546b7409ce783d34c4f53245d08dab84102ed931f66d1492acb308fa1c6715b9d139b81acbdcc";
+
+        assert_eq!(vector.generate::<DefaultProtocolId>(), expected);
+        assert!(vector.verify::<DefaultProtocolId>(expected).unwrap());
+        assert!(matches!(
+            decode_message_packet::<DefaultProtocolId>(expected, &vector.dst_id, &[1; 16]),
+            Err(VectorError::Decryption(_))
+        ));
+    }
+}
@@ -0,0 +1,84 @@
+//! The clock that protocol timers are measured against.
+//!
+//! By default this is the system clock. With the `simulation` feature it is tokio's clock, which
+//! tests can pause and advance, so that timeouts, ban expiries and vote durations elapse in
+//! virtual time together with the tokio timers driving the service.
+//!
+//! The expiries of sessions, queries, bans and IP votes are read from a [`Clock`], set with
+//! [`crate::ConfigBuilder::clock`]. A [`ManualClock`] lets unit tests of timing logic move time
+//! forward deterministically, without sleeping.
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+pub use std::time::Instant;
+
+/// The current time of the clock of the build, see the module docs. With the `simulation` feature
+/// this is tokio's clock, converted to a [`std::time::Instant`] so that the public types don't
+/// depend on the feature.
+pub(crate) fn now() -> Instant {
+    #[cfg(feature = "simulation")]
+    let now = tokio::time::Instant::now().into_std();
+    #[cfg(not(feature = "simulation"))]
+    let now = Instant::now();
+    now
+}
+
+/// A source of the current time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The wall clock time matching [`Clock::now`]. Expiries persisted across restarts are stored
+    /// as wall clock time. Defaults to the system time.
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// The clock of the build, see the module docs. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        now()
+    }
+}
+
+/// A clock that only moves when advanced. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+    /// The time the clock was created at, to derive the wall clock time from.
+    start: (Instant, SystemTime),
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        let start = (now(), SystemTime::now());
+        ManualClock {
+            now: Arc::new(Mutex::new(start.0)),
+            start,
+        }
+    }
+}
+
+impl ManualClock {
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start.1 + (self.now() - self.start.0)
+    }
+}